@@ -0,0 +1,1041 @@
+//! Audio snippets, and mixing them together.
+
+#[cfg(feature = "druid")]
+use druid::Data;
+use serde::de::{self, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use scribble_curves::time::{self, Diff, Time};
+
+pub const SAMPLE_RATE: u32 = 48000;
+
+/// Each audio snippet is uniquelty identified by one of these ids.
+// This is serialized as part of saving files, so its serialization format needs to remain
+// stable.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "druid", derive(Data))]
+#[serde(transparent)]
+pub struct AudioSnippetId(u64);
+
+/// A buffer of audio data, starting at a particular time.
+///
+/// The actual data is beind a pointer, so this is cheap to clone.
+// This is serialized as part of saving files, so its serialization format needs to remain
+// stable.
+#[derive(Deserialize, Serialize, Clone)]
+#[cfg_attr(feature = "druid", derive(Data))]
+#[serde(from = "SavedAudioSnippet", into = "SavedAudioSnippet")]
+pub struct AudioSnippetData {
+    buf: Arc<Samples>,
+    start_time: Time,
+    /// If this snippet was imported from an audio file that is linked rather than embedded,
+    /// this is the path of that file. Linked snippets don't save their samples; they get
+    /// decoded from the file again when the project is loaded.
+    link: Option<Arc<PathBuf>>,
+}
+
+// This is part of the save file format, so it needs to remain stable. Snippets that aren't
+// linked have the same format that they had before linking was possible.
+#[derive(Deserialize, Serialize)]
+struct SavedAudioSnippet {
+    #[serde(default)]
+    buf: Arc<Samples>,
+    start_time: Time,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    link: Option<PathBuf>,
+}
+
+impl From<AudioSnippetData> for SavedAudioSnippet {
+    fn from(snip: AudioSnippetData) -> SavedAudioSnippet {
+        SavedAudioSnippet {
+            buf: if snip.link.is_some() {
+                Arc::new(Samples::default())
+            } else {
+                snip.buf
+            },
+            start_time: snip.start_time,
+            link: snip.link.map(|path| path.as_ref().clone()),
+        }
+    }
+}
+
+impl From<SavedAudioSnippet> for AudioSnippetData {
+    fn from(saved: SavedAudioSnippet) -> AudioSnippetData {
+        AudioSnippetData {
+            buf: saved.buf,
+            start_time: saved.start_time,
+            link: saved.link.map(Arc::new),
+        }
+    }
+}
+
+/// The samples of an audio snippet.
+///
+/// Freshly recorded audio lives in memory, but long snippets that were loaded from a file get
+/// moved out to a cache file and memory-mapped, so that opening a project with lots of narration
+/// doesn't mean keeping all of it in memory. Snippets that are read from a save file get written
+/// to the cache as they're deserialized, so they're never all in memory at once; decoded audio
+/// files get moved there afterwards (see
+/// [`AudioSnippetsData::spilled`](struct.AudioSnippetsData.html#method.spilled)).
+pub enum Samples {
+    Memory(Vec<i16>),
+    Mapped(memmap::Mmap),
+}
+
+// Snippets shorter than this (in samples) always stay in memory.
+const SPILL_THRESHOLD: usize = 10 * SAMPLE_RATE as usize;
+
+/// A collection of [`AudioSnippetData`](struct.AudioSnippetData.html), each one
+/// identified by an [`AudioSnippetId`](struct.AudioSnippetId.html).
+#[derive(Clone, Default)]
+#[cfg_attr(feature = "druid", derive(Data))]
+pub struct AudioSnippetsData {
+    last_id: u64,
+    snippets: Arc<BTreeMap<AudioSnippetId, AudioSnippetData>>,
+}
+
+// Represents a single snippet within the cursor.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+struct CursorSnippet {
+    id: AudioSnippetId,
+    start: usize,
+    end: usize,
+}
+
+/// A `Cursor` is in charge of taking a bunch of short, possibly overlapping,
+/// audio buffers and presenting them as a single logical sequence of samples. It
+/// does not actually store a reference to the buffers, instead working entirely
+/// with indices.
+///
+/// A `Cursor` can either move forwards or backwards, but not both.
+#[derive(Default, Debug)]
+pub struct Cursor {
+    cur_idx: usize,
+    all_cursors: Vec<CursorSnippet>,
+    next_cursor: usize,
+    active_cursors: Vec<CursorSnippet>,
+    forwards: bool,
+}
+
+// A convenience wrapper around the audio buffer of a snippet. This does two things:
+// - it implicitly does some zero padding, and
+// - it can reverse the order.
+#[derive(Debug)]
+struct Buf<'a> {
+    inner: &'a [i16],
+    offset: usize,
+    len: usize,
+    direction: isize,
+}
+
+impl<'a> std::ops::Index<usize> for Buf<'a> {
+    type Output = i16;
+    fn index(&self, idx: usize) -> &i16 {
+        let dir_idx = if self.direction == 1 {
+            idx
+        } else {
+            self.len - 1 - idx
+        };
+
+        if dir_idx >= self.offset && dir_idx < self.offset + self.inner.len() {
+            &self.inner[dir_idx - self.offset]
+        } else {
+            &0
+        }
+    }
+}
+
+impl CursorSnippet {
+    fn new(id: AudioSnippetId, snip: &AudioSnippetData, sample_rate: u32) -> CursorSnippet {
+        let start = snip.start_time.as_audio_idx(sample_rate);
+        CursorSnippet {
+            id,
+            start,
+            end: start + snip.buf.len(),
+        }
+    }
+
+    /// Gets an audio buffer from this cursor snippet. The length of the audio
+    /// buffer is `amount.abs()`, and indexing the audio buffer from 0 through
+    /// its length corresponds to indexing the snippet from `from` to `from +
+    /// amount`. In particular, if `amount` is negative then iterating forwards
+    /// through the returned buffer actually goes backwards through the audio
+    /// data.
+    ///
+    /// The audio snippet must have a non-trivial overlap with the requested
+    /// range; if not, this panics.
+    fn get_buf<'a>(&mut self, data: &'a AudioSnippetsData, from: usize, amount: isize) -> Buf<'a> {
+        if amount > 0 {
+            debug_assert!(from < self.end);
+            debug_assert!(from + amount as usize > self.start);
+        } else {
+            debug_assert!(from > self.start);
+            debug_assert!(from < self.end + (-amount) as usize);
+        }
+
+        let snip = data.snippet(self.id);
+
+        // The starting and ending indices relative to the buffer (could be
+        // negative or extend past the buffer).
+        let (start, end) = (
+            from as isize - self.start as isize,
+            from as isize - self.start as isize + amount,
+        );
+        let (start, end) = if amount > 0 {
+            (start, end)
+        } else {
+            (end, start)
+        };
+        let offset = (-start).max(0) as usize;
+        let start = start.max(0) as usize;
+        let end = (end as usize).min(snip.buf.len());
+
+        Buf {
+            inner: &snip.buf[start..end],
+            offset,
+            len: amount.abs() as usize,
+            direction: amount.signum(),
+        }
+    }
+
+    /// If we are interested in samples between `from` and `from + amount`, does
+    /// this snippet have anything to contribute?
+    fn is_active(&self, from: usize, amount: isize) -> bool {
+        if amount > 0 {
+            from + amount as usize > self.start
+        } else {
+            from < self.end + (-amount) as usize
+        }
+    }
+
+    /// If the audio cursor is currently at `from`, is this snippet finished
+    /// contributing?
+    fn is_finished(&self, from: usize, forwards: bool) -> bool {
+        if forwards {
+            from >= self.end
+        } else {
+            from <= self.start
+        }
+    }
+
+    /// If the audio cursor is currently at `from`, has this snippet started
+    /// contributing yet?
+    fn is_started(&self, from: usize, forwards: bool) -> bool {
+        if forwards {
+            from >= self.start
+        } else {
+            from < self.end
+        }
+    }
+}
+
+impl Cursor {
+    /// Creates a new cursor.
+    ///
+    /// - `snippets` are the snippets that the new cursor will curse over.
+    /// - `time` gives the initial position of the cursor.
+    /// - `sample_rate` is the sample rate of the audio data (TODO: maybe this
+    ///     should be contained in `AudioSnippetsData`?)
+    /// - `forwards` is true if the audio should be played forwards.
+    ///
+    /// Note that we're currently a bit wasteful when it comes to creating cursors.
+    /// We don't support any kind of seeking, so we just keep creating and
+    /// destroying cursors.
+    pub fn new(
+        snippets: &AudioSnippetsData,
+        time: Time,
+        sample_rate: u32,
+        forwards: bool,
+    ) -> Cursor {
+        let mut cursors = Vec::new();
+        let cur_idx = time.as_audio_idx(sample_rate);
+
+        for (&id, snip) in snippets.snippets.iter() {
+            cursors.push(CursorSnippet::new(id, snip, sample_rate));
+        }
+        // TODO: explain
+        if forwards {
+            cursors.sort_by_key(|c| c.start);
+        } else {
+            cursors.sort_by_key(|c| -(c.end as isize));
+        }
+
+        let mut active = Vec::new();
+        let mut next_cursor = cursors.len();
+        for (c_idx, c) in cursors.iter().enumerate() {
+            if !c.is_started(cur_idx, forwards) {
+                next_cursor = c_idx;
+                break;
+            }
+
+            if !c.is_finished(cur_idx, forwards) {
+                active.push(*c);
+            }
+        }
+
+        Cursor {
+            cur_idx,
+            all_cursors: cursors,
+            next_cursor,
+            active_cursors: active,
+            forwards,
+        }
+    }
+
+    /// Fills the provided buffer with samples from the cursor, and advances the
+    /// cursor past those samples.
+    pub fn mix_to_buffer<B: DerefMut<Target = [i16]>>(
+        &mut self,
+        data: &AudioSnippetsData,
+        mut buf: B,
+    ) {
+        // How many bytes do we need from the input buffers? This is signed: it is negative
+        // if we are playing backwards.
+        let input_amount = (buf.len() as isize) * if self.forwards { 1 } else { -1 };
+
+        while self.next_cursor < self.all_cursors.len() {
+            if self.all_cursors[self.next_cursor].is_active(self.cur_idx, input_amount) {
+                self.active_cursors.push(self.all_cursors[self.next_cursor]);
+                self.next_cursor += 1;
+            } else {
+                break;
+            }
+        }
+
+        // TODO: we do a lot of rounding here. Maybe we should work with floats internally?
+        for c in &mut self.active_cursors {
+            let in_buf = c.get_buf(data, self.cur_idx, input_amount);
+
+            // TODO: we could be more efficient here, because we're potentially copying a bunch of
+            // zeros from in_buf, whereas we could simply skip to the non-zero section. But it's
+            // unlikely to be very expensive, whereas getting the indexing right is fiddly...
+            // Overlapping snippets could overflow, so we clip instead.
+            for (idx, out_sample) in buf.iter_mut().enumerate() {
+                *out_sample = out_sample.saturating_add(in_buf[idx]);
+            }
+        }
+        if self.forwards {
+            self.cur_idx += buf.len()
+        } else {
+            self.cur_idx = self.cur_idx.saturating_sub(buf.len());
+        }
+        let cur_idx = self.cur_idx;
+        let forwards = self.forwards;
+        self.active_cursors
+            .retain(|c| !c.is_finished(cur_idx, forwards));
+    }
+
+    /// Has this cursor finished producing non-zero samples?
+    pub fn is_finished(&self) -> bool {
+        self.active_cursors.is_empty() && self.next_cursor == self.all_cursors.len()
+    }
+}
+
+impl AudioSnippetData {
+    pub fn new(buf: Vec<i16>, start_time: Time) -> AudioSnippetData {
+        AudioSnippetData {
+            buf: Arc::new(Samples::Memory(buf)),
+            start_time,
+            link: None,
+        }
+    }
+
+    /// Creates a snippet from an audio file. If `link` is true, the file gets linked instead of
+    /// embedded.
+    pub fn from_file(
+        path: &Path,
+        start_time: Time,
+        link: bool,
+    ) -> anyhow::Result<AudioSnippetData> {
+        let mut ret = AudioSnippetData::new(crate::audio_file::decode(path)?, start_time);
+        if link {
+            ret.link = Some(Arc::new(path.to_owned()));
+        }
+        Ok(ret)
+    }
+
+    /// A snippet that links to the audio file at `path`, but whose samples haven't been loaded
+    /// yet (see [`AudioSnippetsData::with_links_loaded`]).
+    pub fn unloaded_link(path: PathBuf, start_time: Time) -> AudioSnippetData {
+        AudioSnippetData {
+            buf: Arc::new(Samples::default()),
+            start_time,
+            link: Some(Arc::new(path)),
+        }
+    }
+
+    /// The linked audio file, if there is one.
+    pub fn link(&self) -> Option<&Path> {
+        self.link.as_deref().map(|p| p.as_path())
+    }
+
+    /// Returns `true` if this snippet is linked to an audio file that we couldn't load.
+    pub fn is_missing(&self) -> bool {
+        self.link.is_some() && self.buf.is_empty()
+    }
+
+    pub fn buf(&self) -> &[i16] {
+        &self.buf
+    }
+
+    pub fn start_time(&self) -> Time {
+        self.start_time
+    }
+
+    /// Returns a copy of this snippet that starts `by` later.
+    pub fn shifted(&self, by: Diff) -> AudioSnippetData {
+        AudioSnippetData {
+            start_time: self.start_time + by,
+            ..self.clone()
+        }
+    }
+
+    pub fn end_time(&self) -> Time {
+        let length = time::Diff::from_audio_idx(self.buf().len() as i64, SAMPLE_RATE);
+        self.start_time() + length
+    }
+
+    /// Do these two snippets share their samples? Like `is_same`, this compares by pointer, so
+    /// it's cheap.
+    pub fn has_same_samples(&self, other: &AudioSnippetData) -> bool {
+        Arc::ptr_eq(&self.buf, &other.buf)
+    }
+
+    // Are these two snippets the same? Like `druid::Data::same`, this compares the samples by
+    // pointer, because comparing them by value would be slow.
+    pub(crate) fn is_same(&self, other: &AudioSnippetData) -> bool {
+        Arc::ptr_eq(&self.buf, &other.buf)
+            && self.start_time == other.start_time
+            && self.link == other.link
+    }
+}
+
+impl AudioSnippetsData {
+    /// Returns a copy with `snip` added, together with the id of the new snippet.
+    pub fn with_new_snippet(&self, snip: AudioSnippetData) -> (AudioSnippetsData, AudioSnippetId) {
+        let mut ret = self.clone();
+        ret.last_id += 1;
+        let id = AudioSnippetId(ret.last_id);
+        let mut map = ret.snippets.deref().clone();
+        map.insert(id, snip);
+        ret.snippets = Arc::new(map);
+        (ret, id)
+    }
+
+    pub fn without_snippet(&self, id: AudioSnippetId) -> AudioSnippetsData {
+        let mut ret = self.clone();
+        let mut map = ret.snippets.deref().clone();
+        map.remove(&id);
+        ret.snippets = Arc::new(map);
+        ret
+    }
+
+    /// Removes every snippet except the one with the given id.
+    pub fn with_only_snippet(&self, id: AudioSnippetId) -> AudioSnippetsData {
+        let mut ret = self.clone();
+        let mut map = BTreeMap::new();
+        map.insert(id, self.snippet(id).clone());
+        ret.snippets = Arc::new(map);
+        ret
+    }
+
+    pub fn snippet(&self, id: AudioSnippetId) -> &AudioSnippetData {
+        self.snippets.get(&id).unwrap()
+    }
+
+    /// Returns true if there's a snippet with the id `id`.
+    pub fn contains(&self, id: AudioSnippetId) -> bool {
+        self.snippets.contains_key(&id)
+    }
+
+    /// Returns the snippets that were added or changed since `old`, together with the ids of the
+    /// snippets that were removed.
+    pub fn changes_since(
+        &self,
+        old: &AudioSnippetsData,
+    ) -> (Vec<(AudioSnippetId, AudioSnippetData)>, Vec<AudioSnippetId>) {
+        let changed = self
+            .snippets
+            .iter()
+            .filter(|(id, snip)| old.snippets.get(id).map_or(true, |o| !o.is_same(snip)))
+            .map(|(id, snip)| (*id, snip.clone()))
+            .collect();
+        let removed = old
+            .snippets
+            .keys()
+            .filter(|id| !self.snippets.contains_key(id))
+            .cloned()
+            .collect();
+        (changed, removed)
+    }
+
+    /// Applies changes of the kind returned by `changes_since`.
+    pub fn with_changes(
+        &self,
+        changed: Vec<(AudioSnippetId, AudioSnippetData)>,
+        removed: &[AudioSnippetId],
+    ) -> AudioSnippetsData {
+        let mut ret = self.clone();
+        let mut map = (*ret.snippets).clone();
+        for id in removed {
+            map.remove(id);
+        }
+        for (id, snip) in changed {
+            ret.last_id = ret.last_id.max(id.0);
+            map.insert(id, snip);
+        }
+        ret.snippets = Arc::new(map);
+        ret
+    }
+
+    pub fn snippets(&self) -> impl Iterator<Item = (AudioSnippetId, &AudioSnippetData)> {
+        self.snippets.iter().map(|(k, v)| (*k, v))
+    }
+
+    /// Decodes the audio files of all the linked snippets. If a file can't be decoded, the
+    /// snippet is left empty (see [`AudioSnippetData::is_missing`]).
+    pub fn with_links_loaded(&self) -> AudioSnippetsData {
+        let map = self
+            .snippets
+            .iter()
+            .map(|(&id, snip)| match &snip.link {
+                Some(path) if snip.buf.is_empty() => match crate::audio_file::decode(path) {
+                    Ok(buf) => (
+                        id,
+                        AudioSnippetData {
+                            buf: Arc::new(Samples::Memory(buf)),
+                            ..snip.clone()
+                        },
+                    ),
+                    Err(e) => {
+                        log::warn!("couldn't load linked audio {:?}: {}", path, e);
+                        (id, snip.clone())
+                    }
+                },
+                _ => (id, snip.clone()),
+            })
+            .collect();
+        AudioSnippetsData {
+            last_id: self.last_id,
+            snippets: Arc::new(map),
+        }
+    }
+
+    /// Returns a copy of these snippets in which the paths of all the linked files have been
+    /// replaced by `f(path)`.
+    pub fn with_links_mapped(&self, f: impl Fn(&Path) -> PathBuf) -> AudioSnippetsData {
+        let map = self
+            .snippets
+            .iter()
+            .map(|(&id, snip)| {
+                let snip = AudioSnippetData {
+                    link: snip.link.as_ref().map(|path| Arc::new(f(path))),
+                    ..snip.clone()
+                };
+                (id, snip)
+            })
+            .collect();
+        AudioSnippetsData {
+            last_id: self.last_id,
+            snippets: Arc::new(map),
+        }
+    }
+
+    /// Returns a copy of these snippets in which the linked snippets that haven't been loaded
+    /// yet share the samples of any snippet in `loaded` that links to the same file. This saves
+    /// decoding the same file over and over.
+    pub fn with_links_shared(&self, loaded: &AudioSnippetsData) -> AudioSnippetsData {
+        let bufs: BTreeMap<&Path, &Arc<Samples>> = loaded
+            .snippets
+            .values()
+            .filter(|snip| !snip.buf.is_empty())
+            .filter_map(|snip| snip.link().map(|path| (path, &snip.buf)))
+            .collect();
+        let map = self
+            .snippets
+            .iter()
+            .map(|(&id, snip)| {
+                let shared = snip.link().and_then(|path| bufs.get(path));
+                match shared {
+                    Some(&buf) if snip.buf.is_empty() => (
+                        id,
+                        AudioSnippetData {
+                            buf: Arc::clone(buf),
+                            ..snip.clone()
+                        },
+                    ),
+                    _ => (id, snip.clone()),
+                }
+            })
+            .collect();
+        AudioSnippetsData {
+            last_id: self.last_id,
+            snippets: Arc::new(map),
+        }
+    }
+
+    /// The first linked file that we couldn't load, if any.
+    pub fn first_missing_link(&self) -> Option<&Path> {
+        self.snippets
+            .values()
+            .find(|snip| snip.is_missing())
+            .and_then(|snip| snip.link())
+    }
+
+    /// Points all the snippets that link to `old` to `new` instead, using the samples in `buf`.
+    pub fn with_relinked(&self, old: &Path, new: &Path, buf: Vec<i16>) -> AudioSnippetsData {
+        let buf = Arc::new(Samples::Memory(buf));
+        let new = Arc::new(new.to_owned());
+        let map = self
+            .snippets
+            .iter()
+            .map(|(&id, snip)| {
+                if snip.link() == Some(old) {
+                    let snip = AudioSnippetData {
+                        buf: Arc::clone(&buf),
+                        link: Some(Arc::clone(&new)),
+                        ..snip.clone()
+                    };
+                    (id, snip)
+                } else {
+                    (id, snip.clone())
+                }
+            })
+            .collect();
+        AudioSnippetsData {
+            last_id: self.last_id,
+            snippets: Arc::new(map),
+        }
+    }
+
+    /// Moves the samples of long snippets out of memory and into memory-mapped cache files. If
+    /// that fails for some snippet, its samples just stay in memory.
+    pub fn spilled(&self) -> AudioSnippetsData {
+        let map = self
+            .snippets
+            .iter()
+            .map(|(&id, snip)| match &*snip.buf {
+                Samples::Memory(buf) if buf.len() >= SPILL_THRESHOLD => match spill(buf) {
+                    Ok(samples) => (
+                        id,
+                        AudioSnippetData {
+                            buf: Arc::new(samples),
+                            ..snip.clone()
+                        },
+                    ),
+                    Err(e) => {
+                        log::warn!("failed to move audio to the cache: {}", e);
+                        (id, snip.clone())
+                    }
+                },
+                _ => (id, snip.clone()),
+            })
+            .collect();
+        AudioSnippetsData {
+            last_id: self.last_id,
+            snippets: Arc::new(map),
+        }
+    }
+
+    /// The times at which snippets start or end, in no particular order.
+    pub fn boundaries(&self) -> impl Iterator<Item = Time> + '_ {
+        self.snippets
+            .values()
+            .flat_map(|snip| vec![snip.start_time(), snip.end_time()])
+    }
+
+    pub fn end_time(&self) -> Time {
+        self.snippets
+            .values()
+            .map(|snip| snip.end_time())
+            .max()
+            .unwrap_or(time::ZERO)
+    }
+}
+
+impl Default for Samples {
+    fn default() -> Samples {
+        Samples::Memory(Vec::new())
+    }
+}
+
+impl Deref for Samples {
+    type Target = [i16];
+
+    fn deref(&self) -> &[i16] {
+        match self {
+            Samples::Memory(buf) => buf,
+            // This is safe because the mapping is page-aligned, and it contains native-endian
+            // `i16`s that we wrote ourselves.
+            Samples::Mapped(map) => unsafe {
+                std::slice::from_raw_parts(map.as_ptr() as *const i16, map.len() / 2)
+            },
+        }
+    }
+}
+
+fn audio_cache_dir() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("scribble").join("audio"))
+}
+
+/// Deletes the audio cache files that were left behind by previous runs.
+///
+/// On unix, cache files get deleted as soon as they're mapped (the mapping stays valid), so
+/// there usually isn't anything to do. On windows, files can't be deleted while they're mapped,
+/// so they stick around until we get here (and if another instance of scribble is using them,
+/// deleting them will fail, which is fine).
+pub fn clear_audio_cache() {
+    if let Some(entries) = audio_cache_dir().and_then(|dir| std::fs::read_dir(dir).ok()) {
+        for entry in entries.flatten() {
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+}
+
+fn spill(buf: &[i16]) -> anyhow::Result<Samples> {
+    let mut file = CacheFile::create()?;
+    file.write(buf)?;
+    file.finish()
+}
+
+// A cache file that samples are being written to.
+struct CacheFile {
+    path: PathBuf,
+    write: BufWriter<File>,
+}
+
+impl CacheFile {
+    fn create() -> anyhow::Result<CacheFile> {
+        static NEXT_FILE: AtomicUsize = AtomicUsize::new(0);
+
+        let dir = audio_cache_dir().ok_or_else(|| anyhow::anyhow!("no cache directory found"))?;
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(format!(
+            "{}-{}.pcm",
+            std::process::id(),
+            NEXT_FILE.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        Ok(CacheFile {
+            path,
+            write: BufWriter::new(file),
+        })
+    }
+
+    fn write(&mut self, buf: &[i16]) -> anyhow::Result<()> {
+        for sample in buf {
+            self.write.write_all(&sample.to_ne_bytes())?;
+        }
+        Ok(())
+    }
+
+    // Maps the file into memory. Its contents can't change after this.
+    fn finish(self) -> anyhow::Result<Samples> {
+        let file = self
+            .write
+            .into_inner()
+            .map_err(|e| anyhow::anyhow!("{}", e.error()))?;
+        // This is safe as long as nobody else modifies the file, and nobody else should be
+        // touching our cache files.
+        let map = unsafe { memmap::Mmap::map(&file)? };
+        let _ = std::fs::remove_file(&self.path);
+        Ok(Samples::Mapped(map))
+    }
+}
+
+// The samples are serialized as a plain sequence, regardless of where they're stored.
+impl Serialize for Samples {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        self.deref().serialize(ser)
+    }
+}
+
+impl<'de> Deserialize<'de> for Samples {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Samples, D::Error> {
+        de.deserialize_seq(SamplesVisitor)
+    }
+}
+
+// Reads samples into memory until there are enough of them to spill, and then writes the rest
+// straight to a cache file. That way, a long snippet never has to fit in memory all at once.
+struct SamplesVisitor;
+
+impl<'de> Visitor<'de> for SamplesVisitor {
+    type Value = Samples;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a sequence of samples")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Samples, A::Error> {
+        let mut buf = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(SPILL_THRESHOLD));
+        while buf.len() < SPILL_THRESHOLD {
+            match seq.next_element()? {
+                Some(sample) => buf.push(sample),
+                None => return Ok(Samples::Memory(buf)),
+            }
+        }
+
+        let mut file = match CacheFile::create().and_then(|mut file| {
+            file.write(&buf)?;
+            Ok(file)
+        }) {
+            Ok(file) => file,
+            Err(e) => {
+                log::warn!("failed to move audio to the cache: {}", e);
+                while let Some(sample) = seq.next_element()? {
+                    buf.push(sample);
+                }
+                return Ok(Samples::Memory(buf));
+            }
+        };
+        drop(buf);
+        while let Some(sample) = seq.next_element()? {
+            file.write(&[sample]).map_err(de::Error::custom)?;
+        }
+        file.finish().map_err(de::Error::custom)
+    }
+}
+
+// Here is the serialization for audio. Note that the serialization format needs to remain
+// stable, because it is used for file saving.
+//
+// Specifically, we serialize the audio state as a map id -> snippet data. Any other fields
+// on `AudioSnippetsData` are ignored, and must be reconstituted from the snippet map on
+// deserialization.
+impl Serialize for AudioSnippetsData {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        self.snippets.serialize(ser)
+    }
+}
+
+impl<'de> Deserialize<'de> for AudioSnippetsData {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<AudioSnippetsData, D::Error> {
+        let snips = de.deserialize_map(SnippetMapVisitor)?;
+        let max_id = snips.keys().max().unwrap_or(&AudioSnippetId(0)).0;
+        Ok(AudioSnippetsData {
+            snippets: Arc::new(snips),
+            last_id: max_id,
+        })
+    }
+}
+
+// The ids are map keys, so json writes them as strings. The binary save format has the same
+// structure as the json, so its ids are strings too, but other formats might write them as
+// numbers; we accept both.
+struct SnippetMapVisitor;
+
+impl<'de> Visitor<'de> for SnippetMapVisitor {
+    type Value = BTreeMap<AudioSnippetId, AudioSnippetData>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a map from ids to audio snippets")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut ret = BTreeMap::new();
+        while let Some(key) = map.next_key::<IdKey>()? {
+            ret.insert(key.0, map.next_value()?);
+        }
+        Ok(ret)
+    }
+}
+
+struct IdKey(AudioSnippetId);
+
+impl<'de> Deserialize<'de> for IdKey {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<IdKey, D::Error> {
+        de.deserialize_any(IdKeyVisitor)
+    }
+}
+
+struct IdKeyVisitor;
+
+impl<'de> Visitor<'de> for IdKeyVisitor {
+    type Value = IdKey;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an audio snippet id")
+    }
+
+    fn visit_u64<E: de::Error>(self, id: u64) -> Result<IdKey, E> {
+        Ok(IdKey(AudioSnippetId(id)))
+    }
+
+    fn visit_str<E: de::Error>(self, id: &str) -> Result<IdKey, E> {
+        id.parse()
+            .map(|id| IdKey(AudioSnippetId(id)))
+            .map_err(|_| E::invalid_value(de::Unexpected::Str(id), &self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! snips {
+        ($($time:expr => $buf:expr),*) => {
+            {
+                let mut ret = AudioSnippetsData::default();
+                $(
+                    let buf: &[i16] = $buf;
+                    let time = Time::from_micros($time * 1000000);
+                    ret = ret.with_new_snippet(AudioSnippetData::new(buf.to_owned(), time)).0;
+                )*
+
+                ret
+            }
+        }
+    }
+
+    #[test]
+    fn spilled() {
+        let buf: Vec<i16> = (0..SPILL_THRESHOLD).map(|i| i as i16).collect();
+        let snips = snips!(0 => &buf[..], 1 => &[1, 2, 3]);
+        let spilled = snips.spilled();
+        for ((_, a), (_, b)) in snips.snippets().zip(spilled.snippets()) {
+            assert_eq!(a.buf(), b.buf());
+        }
+    }
+
+    #[test]
+    fn deserialize_long_snippet() {
+        let buf: Vec<i16> = (0..SPILL_THRESHOLD + 3).map(|i| i as i16).collect();
+        let snips = snips!(0 => &buf[..], 1 => &[1, 2, 3]);
+        let json = serde_json::to_string(&snips).unwrap();
+        let read: AudioSnippetsData = serde_json::from_str(&json).unwrap();
+        for ((id_a, a), (id_b, b)) in snips.snippets().zip(read.snippets()) {
+            assert_eq!(id_a, id_b);
+            assert_eq!(a.buf(), b.buf());
+        }
+
+        // So does json that has already been parsed.
+        let read: AudioSnippetsData =
+            serde_json::from_value(serde_json::json!({ "7": { "buf": [1], "start_time": 0 } }))
+                .unwrap();
+        assert!(read.contains(AudioSnippetId(7)));
+    }
+
+    #[test]
+    fn linked_snippets_dont_save_samples() {
+        let mut snip = AudioSnippetData::new(vec![1, 2, 3], time::ZERO);
+        snip.link = Some(Arc::new(PathBuf::from("/music/bed.mp3")));
+        let json = serde_json::to_value(&snip).unwrap();
+        assert_eq!(json["buf"], serde_json::json!([]));
+        assert_eq!(json["link"], "/music/bed.mp3");
+
+        let loaded: AudioSnippetData = serde_json::from_value(json).unwrap();
+        assert!(loaded.is_missing());
+        let (snips, _) = AudioSnippetsData::default().with_new_snippet(loaded);
+        let missing = snips.first_missing_link().unwrap().to_owned();
+        let relinked = snips.with_relinked(&missing, Path::new("/new/bed.mp3"), vec![1, 2, 3]);
+        assert!(relinked.first_missing_link().is_none());
+
+        // Unlinked snippets are saved the same way as always.
+        let json = serde_json::to_value(AudioSnippetData::new(vec![1], time::ZERO)).unwrap();
+        assert!(json.get("link").is_none());
+    }
+
+    #[test]
+    fn forward() {
+        let snips = snips!(0 => &[1, 2, 3, 4, 5]);
+        // a sample rate of 1 is silly, but it lets us get the indices right without any rounding issues.
+        let mut c = Cursor::new(&snips, time::ZERO, 1, true);
+        let mut out = vec![0; 5];
+        c.mix_to_buffer(&snips, &mut out[..]);
+        assert_eq!(out, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn forward_offset() {
+        let snips = snips!(5 => &[1, 2, 3, 4, 5]);
+        let mut c = Cursor::new(&snips, time::ZERO, 1, true);
+        let mut out = vec![0; 15];
+        c.mix_to_buffer(&snips, &mut out[..]);
+        assert_eq!(out, vec![0, 0, 0, 0, 0, 1, 2, 3, 4, 5, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn backward() {
+        let snips = snips!(2 => &[1, 2, 3, 4, 5]);
+        let mut c = Cursor::new(&snips, Time::from_micros(9 * 1000000), 1, false);
+        let mut out = vec![0; 10];
+        c.mix_to_buffer(&snips, &mut out[..]);
+        assert_eq!(out, vec![0, 0, 5, 4, 3, 2, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn backward_already_finished() {
+        let snips = snips!(0 => &[1, 2, 3, 4, 5]);
+        let mut c = Cursor::new(&snips, Time::from_micros(0), 1, false);
+        let mut out = vec![0; 10];
+        c.mix_to_buffer(&snips, &mut out[..]);
+        assert_eq!(out, vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn multiple_snippets() {
+        let snips = snips!(
+            0 => &[1, 2, 3],
+            2 => &[1, 2, 3]
+        );
+        let mut c = Cursor::new(&snips, time::ZERO, 1, true);
+        let mut out = vec![0; 10];
+        c.mix_to_buffer(&snips, &mut out[..]);
+        assert_eq!(out, vec![1, 2, 4, 2, 3, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn multiple_snippets_backwards() {
+        let snips = snips!(
+            0 => &[1, 2, 3],
+            2 => &[1, 2, 3]
+        );
+        let mut c = Cursor::new(&snips, Time::from_micros(10 * 1000000), 1, false);
+        let mut out = vec![0; 10];
+        c.mix_to_buffer(&snips, &mut out[..]);
+        assert_eq!(out, vec![0, 0, 0, 0, 0, 3, 2, 4, 2, 1]);
+    }
+
+    #[test]
+    fn non_overlapping_snippets() {
+        let snips = snips!(
+            0 => &[1, 2, 3],
+            12 => &[1, 2, 3]
+        );
+        let mut c = Cursor::new(&snips, time::ZERO, 1, true);
+        let mut out = vec![0; 10];
+        c.mix_to_buffer(&snips, &mut out[..]);
+        assert_eq!(out, vec![1, 2, 3, 0, 0, 0, 0, 0, 0, 0]);
+
+        let mut out = vec![0; 10];
+        c.mix_to_buffer(&snips, &mut out[..]);
+        assert_eq!(out, vec![0, 0, 1, 2, 3, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn clipping() {
+        let snips = snips!(
+            0 => &[i16::MAX, 1, i16::MIN],
+            0 => &[i16::MAX, 1, -1]
+        );
+        let mut c = Cursor::new(&snips, time::ZERO, 1, true);
+        let mut out = vec![0; 3];
+        c.mix_to_buffer(&snips, &mut out[..]);
+        assert_eq!(out, vec![i16::MAX, 2, i16::MIN]);
+    }
+}
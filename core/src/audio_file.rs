@@ -0,0 +1,105 @@
+//! Decoding audio files (music, sound effects, and so on) so that they can be imported into an
+//! animation. We use gstreamer for this (since the app already depends on it for exporting), so
+//! it needs the `decode` feature.
+
+use anyhow::anyhow;
+#[cfg(feature = "decode")]
+use gst::prelude::*;
+#[cfg(feature = "decode")]
+use gstreamer as gst;
+#[cfg(feature = "decode")]
+use gstreamer_app as gst_app;
+use std::path::Path;
+
+#[cfg(feature = "decode")]
+use crate::audio::SAMPLE_RATE;
+
+/// The file extensions of the audio files that we know how to import.
+pub const AUDIO_EXTENSIONS: &[&str] = &["wav", "mp3", "ogg", "opus", "flac", "m4a"];
+
+/// Does this path look like an audio file that we can import?
+pub fn is_audio_path(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| AUDIO_EXTENSIONS.contains(&e.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+/// Decodes the audio file at `path`, converting it to the format that we use everywhere else
+/// (mono, 16-bit samples at `SAMPLE_RATE`). This blocks until the whole file is decoded.
+#[cfg(feature = "decode")]
+pub fn decode(path: &Path) -> anyhow::Result<Vec<i16>> {
+    gst::init()?;
+    let pipeline = gst::parse_launch(&format!(
+        "filesrc name=src ! decodebin ! audioconvert ! audioresample ! \
+         appsink name=sink sync=false caps=audio/x-raw,format=S16LE,channels=1,rate={}",
+        SAMPLE_RATE
+    ))?
+    .dynamic_cast::<gst::Pipeline>()
+    .map_err(|_| anyhow!("bug: couldn't cast to a pipeline"))?;
+    let src = pipeline
+        .get_by_name("src")
+        .ok_or_else(|| anyhow!("bug: no source"))?;
+    src.set_property(
+        "location",
+        &path
+            .to_str()
+            .ok_or_else(|| anyhow!("this filename is too weird"))?
+            .to_value(),
+    )?;
+    let sink = pipeline
+        .get_by_name("sink")
+        .ok_or_else(|| anyhow!("bug: no sink"))?
+        .dynamic_cast::<gst_app::AppSink>()
+        .map_err(|_| anyhow!("bug: couldn't cast to an AppSink"))?;
+
+    pipeline.set_state(gst::State::Playing)?;
+    let mut ret = Vec::new();
+    // This returns `None` at the end of the stream, or if something went wrong.
+    while let Some(sample) = sink.pull_sample() {
+        let buffer = sample
+            .get_buffer()
+            .ok_or_else(|| anyhow!("no buffer in sample"))?;
+        let map = buffer
+            .map_readable()
+            .ok_or_else(|| anyhow!("couldn't read buffer"))?;
+        ret.extend(
+            map.as_slice()
+                .chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]])),
+        );
+    }
+
+    let error = pipeline
+        .get_bus()
+        .and_then(|bus| bus.pop_filtered(&[gst::MessageType::Error]));
+    pipeline.set_state(gst::State::Null)?;
+    if let Some(msg) = error {
+        if let gst::MessageView::Error(err) = msg.view() {
+            return Err(anyhow!("error decoding {:?}: {}", path, err.get_error()));
+        }
+    }
+    Ok(ret)
+}
+
+/// Without the `decode` feature, we can't decode anything.
+#[cfg(not(feature = "decode"))]
+pub fn decode(path: &Path) -> anyhow::Result<Vec<i16>> {
+    Err(anyhow!(
+        "can't decode {:?}: scribble_core was built without the `decode` feature",
+        path
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audio_paths() {
+        assert!(is_audio_path(Path::new("music.mp3")));
+        assert!(is_audio_path(Path::new("/tmp/Music.FLAC")));
+        assert!(!is_audio_path(Path::new("lecture.scb")));
+        assert!(!is_audio_path(Path::new("noextension")));
+    }
+}
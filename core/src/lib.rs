@@ -0,0 +1,23 @@
+//! The parts of scribble that don't need a GUI: the animation being edited (`ScribbleState`,
+//! which holds the drawings from `scribble_curves` and the images, captions and settings from
+//! `scribble_project`), the undo stack, the audio (storing it, decoding it, and mixing it), and
+//! the file format that projects get saved in. None of this depends on druid, so it can be used
+//! (and tested) without a display.
+//!
+//! There are three features:
+//!
+//! - `druid` makes the data types implement `druid::Data` (and `druid::Lens`), which the
+//!   scribble app needs in order to keep them in its state.
+//! - `decode` decodes audio files with gstreamer (see [`audio_file`]).
+//! - `scribble-file` reads and writes project files (see [`save_file`]).
+//!
+//! Exporting videos stays in the app, because it renders frames with the app's piet backend.
+
+pub mod audio;
+pub mod audio_file;
+pub mod model;
+#[cfg(feature = "scribble-file")]
+pub mod save_file;
+pub mod undo;
+
+pub use scribble_curves::time;
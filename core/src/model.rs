@@ -0,0 +1,359 @@
+//! The animation that is being edited: the drawings, the audio, the images and the captions,
+//! together with the parts of the editing state (like the selection and the mark) that get
+//! restored by undoing. The scribble app keeps one of these in its state, but nothing here
+//! needs a GUI.
+
+#[cfg(feature = "druid")]
+use druid::{Data, Lens};
+use kurbo::{ParamCurve, PathSeg, Point};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use scribble_curves::{Curve, Diff, SnippetData, SnippetId, SnippetsData, Time};
+use scribble_project::captions::CaptionsData;
+use scribble_project::images::ImageSnippetsData;
+use scribble_project::settings::ProjectSettings;
+
+use crate::audio::{AudioSnippetData, AudioSnippetId, AudioSnippetsData};
+#[cfg(feature = "scribble-file")]
+use crate::save_file::{SaveFileData, SAVE_FILE_VERSION};
+
+/// How many deleted snippets we keep around for restoring.
+const MAX_TRASH: usize = 50;
+
+/// A snippet that isn't part of an animation: either it was copied, so that it can be pasted
+/// (into this project or another one), or it was deleted, so that it can be restored.
+#[derive(Clone)]
+#[cfg_attr(feature = "druid", derive(Data))]
+pub enum CopiedSnippet {
+    Draw(SnippetData),
+    Audio(AudioSnippetData),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "druid", derive(Data))]
+pub enum MaybeSnippetId {
+    Draw(SnippetId),
+    Audio(AudioSnippetId),
+    None,
+}
+
+impl MaybeSnippetId {
+    pub fn is_none(&self) -> bool {
+        matches!(self, MaybeSnippetId::None)
+    }
+
+    pub fn as_draw(&self) -> Option<SnippetId> {
+        if let MaybeSnippetId::Draw(id) = self {
+            Some(*id)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_audio(&self) -> Option<AudioSnippetId> {
+        if let MaybeSnippetId::Audio(id) = self {
+            Some(*id)
+        } else {
+            None
+        }
+    }
+}
+
+impl From<SnippetId> for MaybeSnippetId {
+    fn from(id: SnippetId) -> MaybeSnippetId {
+        MaybeSnippetId::Draw(id)
+    }
+}
+
+impl From<AudioSnippetId> for MaybeSnippetId {
+    fn from(id: AudioSnippetId) -> MaybeSnippetId {
+        MaybeSnippetId::Audio(id)
+    }
+}
+
+/// This data contains the state of the drawing.
+#[derive(Clone)]
+#[cfg_attr(feature = "druid", derive(Data, Lens))]
+pub struct ScribbleState {
+    pub new_curve: Option<Arc<Curve>>,
+    pub snippets: SnippetsData,
+    pub audio_snippets: AudioSnippetsData,
+    pub images: ImageSnippetsData,
+    pub captions: CaptionsData,
+    pub selected_snippet: MaybeSnippetId,
+
+    pub mark: Option<Time>,
+
+    pub settings: ProjectSettings,
+
+    /// Snippets that were deleted, most recent first. This isn't saved, so it only lasts as long
+    /// as the project is open.
+    pub trash: Arc<Vec<CopiedSnippet>>,
+}
+
+impl Default for ScribbleState {
+    fn default() -> ScribbleState {
+        ScribbleState {
+            new_curve: None,
+            snippets: SnippetsData::default(),
+            audio_snippets: AudioSnippetsData::default(),
+            images: ImageSnippetsData::default(),
+            captions: CaptionsData::default(),
+            selected_snippet: MaybeSnippetId::None,
+            mark: None,
+            trash: Arc::new(Vec::new()),
+            settings: ProjectSettings {
+                created: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .ok()
+                    .map(|d| d.as_secs()),
+                ..ProjectSettings::default()
+            },
+        }
+    }
+}
+
+impl ScribbleState {
+    /// Returns a copy of the animation for exporting. If `selection_only` is true (and something
+    /// is selected), only the selected snippet is kept.
+    ///
+    /// Any unfinished curve is dropped, since it isn't part of the animation yet.
+    pub fn export_snapshot(&self, selection_only: bool) -> ScribbleState {
+        let mut ret = self.clone();
+        ret.new_curve = None;
+        if selection_only {
+            match self.selected_snippet {
+                MaybeSnippetId::Draw(id) => {
+                    ret.snippets = self.snippets.with_only_snippet(id);
+                    ret.audio_snippets = AudioSnippetsData::default();
+                    ret.images = ImageSnippetsData::default();
+                }
+                MaybeSnippetId::Audio(id) => {
+                    ret.snippets = SnippetsData::default();
+                    ret.audio_snippets = self.audio_snippets.with_only_snippet(id);
+                    ret.images = ImageSnippetsData::default();
+                }
+                // If nothing is selected, we just export everything.
+                MaybeSnippetId::None => {}
+            }
+        }
+        ret
+    }
+
+    /// Deletes a snippet, moving it to the trash.
+    pub fn delete_snippet(&mut self, id: MaybeSnippetId) {
+        let deleted = match id {
+            MaybeSnippetId::Draw(id) => {
+                let snip = self.snippets.snippet(id).clone();
+                self.snippets = self.snippets.without_snippet(id);
+                CopiedSnippet::Draw(snip)
+            }
+            MaybeSnippetId::Audio(id) => {
+                let snip = self.audio_snippets.snippet(id).clone();
+                self.audio_snippets = self.audio_snippets.without_snippet(id);
+                CopiedSnippet::Audio(snip)
+            }
+            MaybeSnippetId::None => return,
+        };
+        if self.selected_snippet == id {
+            self.selected_snippet = MaybeSnippetId::None;
+        }
+        let trash = Arc::make_mut(&mut self.trash);
+        trash.insert(0, deleted);
+        trash.truncate(MAX_TRASH);
+    }
+
+    /// Takes the `idx`th snippet out of the trash and puts it back where it was.
+    pub fn restore_snippet(&mut self, idx: usize) {
+        if idx >= self.trash.len() {
+            log::error!("there's no deleted snippet {}", idx);
+            return;
+        }
+        match Arc::make_mut(&mut self.trash).remove(idx) {
+            CopiedSnippet::Draw(snip) => {
+                let (snippets, id) = self.snippets.with_new_snippet(snip);
+                self.snippets = snippets;
+                self.selected_snippet = MaybeSnippetId::Draw(id);
+            }
+            CopiedSnippet::Audio(snip) => {
+                let (audio_snippets, id) = self.audio_snippets.with_new_snippet(snip);
+                self.audio_snippets = audio_snippets;
+                self.selected_snippet = MaybeSnippetId::Audio(id);
+            }
+        }
+    }
+
+    /// The time at which the last snippet finishes drawing or talking.
+    pub fn end_time(&self) -> Time {
+        self.snippets
+            .last_draw_time()
+            .max(self.audio_snippets.end_time())
+    }
+
+    /// The first time after `time` at which a snippet starts, ends, or has a lerp point.
+    pub fn next_boundary(&self, time: Time) -> Option<Time> {
+        self.snippets
+            .boundaries()
+            .chain(self.audio_snippets.boundaries())
+            .filter(|&t| t > time)
+            .min()
+    }
+
+    /// The last time before `time` at which a snippet starts, ends, or has a lerp point.
+    pub fn previous_boundary(&self, time: Time) -> Option<Time> {
+        self.snippets
+            .boundaries()
+            .chain(self.audio_snippets.boundaries())
+            .filter(|&t| t < time)
+            .max()
+    }
+
+    /// The time closest to `time` at which a snippet starts, ends, or has a lerp point.
+    pub fn nearest_boundary(&self, time: Time) -> Option<Time> {
+        self.snippets
+            .boundaries()
+            .chain(self.audio_snippets.boundaries())
+            .min_by_key(|&t| (t - time).as_micros().abs())
+    }
+
+    /// A drawing that is visible at `time` and passes within `radius` of `pos`, if there is one.
+    pub fn snippet_near(&self, pos: Point, time: Time, radius: f64) -> Option<SnippetId> {
+        // How many points to check along each segment of the drawing.
+        const SAMPLES: usize = 8;
+        self.snippets
+            .snippets()
+            .filter(|(_, snip)| snip.visible_at(time))
+            .find(|(_, snip)| {
+                let path = snip.path_at(time);
+                let near = |seg: PathSeg| {
+                    (0..=SAMPLES)
+                        .any(|i| seg.eval(i as f64 / SAMPLES as f64).distance(pos) <= radius)
+                };
+                path.segments().any(near)
+            })
+            .map(|(id, _)| id)
+    }
+
+    /// Describes the snippets in the trash, for showing in the restore dialog.
+    pub fn trash_labels(&self) -> Vec<String> {
+        let secs = |t: Time| t.as_micros() as f64 / 1e6;
+        self.trash
+            .iter()
+            .map(|snip| match snip {
+                CopiedSnippet::Draw(s) => format!("Drawing at {:.1}s", secs(s.start_time())),
+                CopiedSnippet::Audio(s) => format!("Audio at {:.1}s", secs(s.start_time())),
+            })
+            .collect()
+    }
+
+    /// Returns a copy of this animation with all the snippets of `other` added to it, `offset`
+    /// later than they were in `other`. The imported snippets get new ids, and `other`'s
+    /// settings are ignored.
+    pub fn with_imported(&self, other: &ScribbleState, offset: Diff) -> ScribbleState {
+        let mut ret = self.clone();
+        for (_, snip) in other.snippets.snippets() {
+            ret.snippets = ret.snippets.with_new_snippet(snip.shifted(offset)).0;
+        }
+        for (_, snip) in other.audio_snippets.snippets() {
+            ret.audio_snippets = ret.audio_snippets.with_new_snippet(snip.shifted(offset)).0;
+        }
+        for snip in other.images.snippets() {
+            ret.images = ret.images.with_new_snippet(snip.shifted(offset));
+        }
+        ret
+    }
+}
+
+#[cfg(feature = "scribble-file")]
+impl ScribbleState {
+    pub fn from_save_file(data: SaveFileData) -> ScribbleState {
+        ScribbleState {
+            snippets: data.snippets,
+            audio_snippets: data.audio_snippets,
+            settings: data.settings,
+            images: data.images,
+            captions: data.captions,
+            ..Default::default()
+        }
+    }
+
+    pub fn to_save_file(&self) -> SaveFileData {
+        SaveFileData {
+            version: SAVE_FILE_VERSION,
+            snippets: self.snippets.clone(),
+            audio_snippets: self.audio_snippets.clone(),
+            settings: self.settings.clone(),
+            images: self.images.clone(),
+            captions: self.captions.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use piet::Color;
+    use scribble_curves::{Effects, LineStyle};
+
+    #[test]
+    fn near() {
+        let mut scribble = ScribbleState::default();
+        let style = LineStyle {
+            color: Color::rgb8(255, 0, 0),
+            thickness: 0.01,
+        };
+        let mut curve = Curve::new();
+        curve.move_to(
+            Point::new(0.0, 0.0),
+            Time::from_micros(0),
+            style,
+            Effects::default(),
+        );
+        curve.line_to(Point::new(1.0, 0.0), Time::from_micros(1_000_000));
+        let (snippets, id) = scribble.snippets.with_new_snippet(SnippetData::new(curve));
+        scribble.snippets = snippets;
+
+        let secs = |s| Time::from_micros(s * 1_000_000);
+        let near = |x, y, time| scribble.snippet_near(Point::new(x, y), time, 0.05);
+        assert_eq!(near(0.5, 0.01, secs(2)), Some(id));
+        assert_eq!(near(0.5, 0.1, secs(2)), None);
+        assert_eq!(near(1.02, 0.0, secs(2)), Some(id));
+        // The end of the line hasn't been drawn yet.
+        assert_eq!(near(0.9, 0.0, Time::from_micros(100_000)), None);
+    }
+
+    #[test]
+    fn trash() {
+        let mut scribble = ScribbleState::default();
+        for secs in 1..=3 {
+            let snip = AudioSnippetData::new(vec![0; 100], Time::from_micros(secs * 1_000_000));
+            scribble.audio_snippets = scribble.audio_snippets.with_new_snippet(snip).0;
+        }
+        let ids: Vec<_> = scribble
+            .audio_snippets
+            .snippets()
+            .map(|(id, _)| id)
+            .collect();
+        scribble.selected_snippet = ids[0].into();
+        scribble.delete_snippet(ids[0].into());
+        scribble.delete_snippet(ids[2].into());
+        assert_eq!(scribble.selected_snippet, MaybeSnippetId::None);
+        assert_eq!(scribble.audio_snippets.snippets().count(), 1);
+        assert_eq!(
+            scribble.trash_labels(),
+            vec!["Audio at 3.0s".to_owned(), "Audio at 1.0s".to_owned()]
+        );
+
+        scribble.restore_snippet(1);
+        assert_eq!(scribble.trash_labels(), vec!["Audio at 3.0s".to_owned()]);
+        assert!(scribble.selected_snippet.as_audio().is_some());
+        let starts: Vec<_> = scribble
+            .audio_snippets
+            .snippets()
+            .map(|(_, s)| s.start_time().as_micros())
+            .collect();
+        assert!(starts.contains(&1_000_000));
+        assert_eq!(starts.len(), 2);
+    }
+}
@@ -0,0 +1,796 @@
+//! The scribble file format.
+//!
+//! A scribble project is a single file, which is either gzip-compressed json or
+//! zstd-compressed CBOR (see [`SaveFormat`]). The two are told apart by their first bytes, and
+//! they have exactly the same structure: the CBOR format is just the json data model written
+//! in binary. The rest of this documentation describes the json.
+//!
+//! The top level is an object with these fields:
+//!
+//! - `version`: the version of the format, currently [`SAVE_FILE_VERSION`]. Readers must
+//!   refuse files with a newer version, and should upgrade files with an older version (which
+//!   is what [`SaveFileData::from_json_value`] does).
+//! - `snippets`: the drawings, as an object mapping ids to snippets.
+//! - `audio_snippets`: the narration and music, as an object mapping ids to audio snippets.
+//! - `settings`: the project settings.
+//! - `images`: the images on the canvas, as an object mapping ids to image snippets.
+//! - `captions` (since version 3): a list of captions, sorted by their start times. Each one is
+//!   an object with `start` and `end` times, and the `text` to show.
+//!
+//! Ids are unsigned integers, written as strings because they're object keys. Unless otherwise
+//! mentioned, times are integers counting microseconds since the start of the animation,
+//! durations are integers counting microseconds, and colors are integers holding the color in
+//! RGBA order (so `0xff0000ff` is opaque red). Positions are in image coordinates, where the
+//! canvas is 1.0 wide; in some places they are written as integers in units of 1/10000.
+//!
+//! A drawing snippet has these fields:
+//!
+//! - `curve`: a list of segments. Each segment is an object with
+//!   - `elements`: a list of `[x, y]` pairs in units of 1/10000. The first is where the
+//!     segment starts, and each following triple is a cubic Bézier curve (two control points,
+//!     then an end point).
+//!   - `times`: the times at which the segment's points were drawn. There is one time for
+//!     the start, and one for each curve.
+//!   - `style`: an object with a `color` and a `thickness` (a float, in image coordinates).
+//!   - `effects`: a list of effects, each of which is an object with a single key. The only
+//!     effect so far is `{"Fade": {"pause": <duration>, "fade": <duration>}}`.
+//! - `lerp`: an object with two lists of times of the same length, `original_values` and
+//!   `lerped_values`. The snippet's drawing times get mapped piecewise linearly from the first
+//!   list to the second.
+//! - `end`: the time at which the snippet disappears, or `null` if it stays forever.
+//! - `reversed` (optional, default `false`): whether the snippet un-draws itself.
+//! - `pen` (optional): the path that the pen took, as an object with lists `points` (`[x, y]`
+//!   pairs, in units of 1/10000) and `times`, of the same length.
+//!
+//! An audio snippet has these fields:
+//!
+//! - `buf`: the samples, as a list of 16-bit integers. The audio is mono, at 48kHz. This is
+//!   empty for linked snippets.
+//! - `start_time`: when the snippet starts playing.
+//! - `link` (optional, since version 2): the path of a linked audio file, relative to the
+//!   project file (or absolute, if it's on a different drive). The samples are decoded from this
+//!   file when the project is loaded.
+//!
+//! An image snippet has these fields:
+//!
+//! - `encoded`: the bytes of the original image file (PNG, JPEG, etc.), as a list of integers.
+//! - `rect`: `[x0, y0, x1, y1]`, the rectangle that the image covers (as floats, in image
+//!   coordinates).
+//! - `start_time`, and `end_time` (which is `null` if the image stays forever).
+//! - `fade_in`: how long the image takes to fade in.
+//!
+//! The settings are an object in which every field is optional:
+//!
+//! - `aspect_ratio`: one of `"Standard"` (4:3), `"Wide"` (16:9) or `"Vertical"` (9:16).
+//! - `background`: the color of the canvas.
+//! - `watermark`: an object with `encoded` (the bytes of an image file, or `null`), `corner`
+//!   (one of `"TopLeft"`, `"TopRight"`, `"BottomLeft"` or `"BottomRight"`), `scale` and
+//!   `opacity`.
+//! - `title` and `author`: strings.
+//! - `created`: when the project was created, in seconds since the Unix epoch, or `null`.
+//! - `frame_rate`: one of `"Fps24"`, `"Fps30"` or `"Fps60"`.
+//! - `recording_speed`: one of `"Paused"`, `"Slower"`, `"Slow"` or `"Normal"`.
+//! - `recording_setup`: `null`, or an object with `fade` (a boolean), `pen_color` and
+//!   `line_thickness`.
+//!
+//! Readers should ignore fields that they don't know about. Rust programs can read and write
+//! projects with [`SaveFileData`], which needs this crate's `scribble-file` feature. Other tools
+//! can use scribble's `convert` subcommand, which turns projects into plain json and back, so
+//! that they don't have to deal with the compression.
+
+#[cfg(feature = "druid")]
+use druid::Data;
+use serde::de::{self, Deserializer, MapAccess, Visitor};
+use serde::ser::{SerializeMap, Serializer};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Component, Path, PathBuf};
+
+use scribble_curves::SnippetsData;
+use scribble_project::captions::CaptionsData;
+use scribble_project::images::ImageSnippetsData;
+use scribble_project::settings::ProjectSettings;
+
+use crate::audio::AudioSnippetsData;
+
+/// The version of the save file format that we write. Whenever the serialization format of
+/// `SaveFileData` changes, this needs to be bumped and a migration needs to be added to
+/// `MIGRATIONS`.
+pub const SAVE_FILE_VERSION: u64 = 3;
+
+// The migrations for upgrading old save files, which get applied to the json before we try to
+// deserialize it. `MIGRATIONS[n]` upgrades a file from version `n` to version `n + 1`.
+//
+// When loading from a file, the audio snippets get deserialized directly instead of going through
+// json (see `RawSaveFile`), so the migrations don't see them. A change to the format of the audio
+// snippets has to be something that they can read by themselves, like a new optional field.
+const MIGRATIONS: &[fn(&mut serde_json::Value) -> anyhow::Result<()>] =
+    &[migrate_v0, migrate_v1, migrate_v2];
+
+// Version 0 files might be missing the project settings and the images, because they were added
+// without bumping the version.
+fn migrate_v0(value: &mut serde_json::Value) -> anyhow::Result<()> {
+    let fields = value
+        .as_object_mut()
+        .ok_or_else(|| anyhow::anyhow!("the save file is corrupt"))?;
+    if !fields.contains_key("settings") {
+        fields.insert(
+            "settings".to_owned(),
+            serde_json::to_value(ProjectSettings::default())?,
+        );
+    }
+    if !fields.contains_key("images") {
+        fields.insert(
+            "images".to_owned(),
+            serde_json::to_value(ImageSnippetsData::default())?,
+        );
+    }
+    Ok(())
+}
+
+// Version 2 added linked audio. Older files don't have any, so there's nothing to change; the
+// version bump is so that older versions of scribble refuse to open files with linked audio,
+// instead of opening them without it.
+fn migrate_v1(_value: &mut serde_json::Value) -> anyhow::Result<()> {
+    Ok(())
+}
+
+// Version 3 added captions.
+fn migrate_v2(value: &mut serde_json::Value) -> anyhow::Result<()> {
+    let fields = value
+        .as_object_mut()
+        .ok_or_else(|| anyhow::anyhow!("the save file is corrupt"))?;
+    if !fields.contains_key("captions") {
+        fields.insert(
+            "captions".to_owned(),
+            serde_json::to_value(CaptionsData::default())?,
+        );
+    }
+    Ok(())
+}
+
+// The first bytes of a zstd-compressed file. (Gzip files start with different bytes, so we can
+// use this to tell the two save formats apart.)
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// The formats that we can save projects in. When loading, we detect the format automatically.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "druid", derive(Data))]
+pub enum SaveFormat {
+    /// json, compressed with gzip.
+    Json,
+    /// CBOR, compressed with zstd. This is smaller and much faster to save and load than `Json`.
+    Binary,
+}
+
+/// Our save file format is simply to serialize this struct as json, compressed
+/// with gzip. Alternatively, it can be serialized as CBOR (with the same structure as
+/// the json) and compressed with zstd; see `SaveFormat`. The module documentation describes
+/// the json in detail.
+///
+/// In particular, it's very important that the serializion format of this struct
+/// doesn't change unexpectedly. If it does change, see `SAVE_FILE_VERSION`.
+#[derive(Deserialize, Serialize)]
+pub struct SaveFileData {
+    /// The version of the save file format. When loading, older versions get upgraded to
+    /// `SAVE_FILE_VERSION`.
+    pub version: u64,
+
+    pub snippets: SnippetsData,
+    pub audio_snippets: AudioSnippetsData,
+    pub settings: ProjectSettings,
+    pub images: ImageSnippetsData,
+    pub captions: CaptionsData,
+}
+
+impl Default for SaveFileData {
+    fn default() -> SaveFileData {
+        SaveFileData {
+            version: SAVE_FILE_VERSION,
+            snippets: SnippetsData::default(),
+            audio_snippets: AudioSnippetsData::default(),
+            settings: ProjectSettings::default(),
+            images: ImageSnippetsData::default(),
+            captions: CaptionsData::default(),
+        }
+    }
+}
+
+// When saving, we report progress after compressing each chunk of this size.
+const SAVE_CHUNK_SIZE: usize = 1 << 20;
+
+impl SaveFileData {
+    pub fn load_from_path<P: AsRef<Path>>(path: P) -> anyhow::Result<SaveFileData> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        Ok(SaveFileData::load_from(file)?.with_links_resolved(path))
+    }
+
+    /// Linked files are saved with paths relative to the project file, so that a project (together
+    /// with the files it links to) can be moved or copied to another machine. Given the path that
+    /// this was loaded from, this turns those paths back into real ones.
+    pub fn with_links_resolved(mut self, project_path: &Path) -> SaveFileData {
+        let dir = project_path.parent().unwrap_or_else(|| Path::new(""));
+        self.audio_snippets = self.audio_snippets.with_links_mapped(|link| dir.join(link));
+        self
+    }
+
+    /// The opposite of `with_links_resolved`. Links that can't be made relative (for example,
+    /// because they're on a different drive) stay as they are.
+    pub fn with_links_relative_to(&self, project_path: &Path) -> SaveFileData {
+        let dir = project_path.parent().unwrap_or_else(|| Path::new(""));
+        let relative = |link: &Path| relative_path(link, dir).unwrap_or_else(|| link.to_owned());
+        SaveFileData {
+            version: self.version,
+            snippets: self.snippets.clone(),
+            audio_snippets: self.audio_snippets.with_links_mapped(relative),
+            settings: self.settings.clone(),
+            images: self.images.clone(),
+            captions: self.captions.clone(),
+        }
+    }
+
+    pub fn load_from<R: std::io::Read>(read: R) -> anyhow::Result<SaveFileData> {
+        let mut read = BufReader::new(read);
+        let raw: RawSaveFile = if read.fill_buf()?.starts_with(ZSTD_MAGIC) {
+            serde_cbor::from_reader(zstd::Decoder::with_buffer(read)?)?
+        } else {
+            serde_json::from_reader(flate2::read::GzDecoder::new(read))?
+        };
+
+        let mut value = serde_json::Value::Object(raw.fields);
+        upgrade(&mut value)?;
+        // The real audio snippets get put back after deserializing the rest. If they're missing,
+        // we leave them out so that deserializing complains.
+        if raw.audio_snippets.is_some() {
+            value["audio_snippets"] = serde_json::json!({});
+        }
+        let mut ret: SaveFileData = serde_json::from_value(value)?;
+        if let Some(audio_snippets) = raw.audio_snippets {
+            ret.audio_snippets = audio_snippets;
+        }
+        Ok(ret)
+    }
+
+    /// Reads a project from its json representation (as described in the module
+    /// documentation), upgrading it if it was saved by an older version of scribble.
+    pub fn from_json_value(mut value: serde_json::Value) -> anyhow::Result<SaveFileData> {
+        upgrade(&mut value)?;
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// The json representation of this project, as described in the module documentation.
+    pub fn to_json_value(&self) -> anyhow::Result<serde_json::Value> {
+        Ok(serde_json::to_value(self)?)
+    }
+
+    /// Decodes the linked audio files (which aren't stored in the save file itself), and moves
+    /// the long ones into memory-mapped cache files. (The audio that is stored in the save file
+    /// went to the cache while it was being loaded.) This can be slow, so it shouldn't happen on
+    /// the UI thread.
+    pub fn with_audio_loaded(mut self) -> SaveFileData {
+        self.audio_snippets = self.audio_snippets.with_links_loaded().spilled();
+        self
+    }
+
+    pub fn save_to_path<P: AsRef<Path>>(&self, path: P, format: SaveFormat) -> anyhow::Result<()> {
+        self.save_to_path_with_progress(path, format, |_| {})
+    }
+
+    /// Like `save_to_path`, but calls `progress` every so often with the fraction of the work
+    /// that has been done.
+    pub fn save_to_path_with_progress<P: AsRef<Path>>(
+        &self,
+        path: P,
+        format: SaveFormat,
+        progress: impl FnMut(f64),
+    ) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let tmp_file_name = format!(
+            "{}.savefile",
+            path.file_name()
+                .and_then(|s| s.to_str())
+                .unwrap_or("untitled")
+        );
+        let tmp_path = path.with_file_name(tmp_file_name);
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let tmp_file = File::create(&tmp_path)?;
+        self.with_links_relative_to(path)
+            .save_to_with_progress(tmp_file, format, progress)?;
+        std::fs::rename(tmp_path, path)?;
+
+        Ok(())
+    }
+
+    pub fn save_to<W: std::io::Write>(&self, write: W, format: SaveFormat) -> anyhow::Result<()> {
+        self.save_to_with_progress(write, format, |_| {})
+    }
+
+    fn save_to_with_progress<W: std::io::Write>(
+        &self,
+        write: W,
+        format: SaveFormat,
+        mut progress: impl FnMut(f64),
+    ) -> anyhow::Result<()> {
+        // Serializing is quick compared to compressing, so we serialize everything up front and
+        // then report progress as we compress it.
+        match format {
+            SaveFormat::Json => {
+                let uncompressed = serde_json::to_vec(self)?;
+                let mut compress =
+                    flate2::write::GzEncoder::new(write, flate2::Compression::new(7));
+                write_in_chunks(&mut compress, &uncompressed, &mut progress)?;
+                compress.finish()?;
+            }
+            SaveFormat::Binary => {
+                // We go through json's data model so that the binary format has exactly the same
+                // structure as the json one (in particular, map keys are always strings). That
+                // way, the migrations work on both.
+                let value = self.to_json_value()?;
+                let fields = value
+                    .as_object()
+                    .ok_or_else(|| anyhow::anyhow!("a project should be a json object"))?;
+                let uncompressed = serde_cbor::to_vec(&VersionFirst(fields))?;
+                let mut compress = zstd::Encoder::new(write, 3)?;
+                write_in_chunks(&mut compress, &uncompressed, &mut progress)?;
+                compress.finish()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Checks the version of a save file's json, and upgrades it to the current version.
+fn upgrade(value: &mut serde_json::Value) -> anyhow::Result<()> {
+    let version = value
+        .get("version")
+        .and_then(|v| v.as_u64())
+        .ok_or_else(|| anyhow::anyhow!("this doesn't look like a scribble file"))?;
+    check_version(version)?;
+    for migrate in &MIGRATIONS[version as usize..] {
+        migrate(value)?;
+    }
+    value["version"] = SAVE_FILE_VERSION.into();
+    Ok(())
+}
+
+fn check_version(version: u64) -> anyhow::Result<()> {
+    if version > SAVE_FILE_VERSION {
+        return Err(anyhow::anyhow!(
+            "this file was saved by a newer version of scribble (file format version {}, \
+             but this version only understands up to {}); please upgrade scribble",
+            version,
+            SAVE_FILE_VERSION
+        ));
+    }
+    Ok(())
+}
+
+// Json objects sort their keys, but readers need to see the version before the audio (see
+// `RawSaveFile`), so this serializes a save file's fields with the version first.
+struct VersionFirst<'a>(&'a serde_json::Map<String, serde_json::Value>);
+
+impl<'a> Serialize for VersionFirst<'a> {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        let mut map = ser.serialize_map(Some(self.0.len()))?;
+        if let Some(version) = self.0.get("version") {
+            map.serialize_entry("version", version)?;
+        }
+        for (key, value) in self.0.iter().filter(|(key, _)| *key != "version") {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+}
+
+// A save file as it gets read from disk. The audio is usually most of a project, so instead of
+// reading it into json (which takes several times as much memory as the samples themselves) we
+// deserialize it straight into `AudioSnippetsData`, which writes long snippets to the cache as it
+// reads them. Everything else stays as json, so that it can be migrated.
+struct RawSaveFile {
+    fields: serde_json::Map<String, serde_json::Value>,
+    audio_snippets: Option<AudioSnippetsData>,
+}
+
+impl<'de> Deserialize<'de> for RawSaveFile {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<RawSaveFile, D::Error> {
+        de.deserialize_map(RawSaveFileVisitor)
+    }
+}
+
+struct RawSaveFileVisitor;
+
+impl<'de> Visitor<'de> for RawSaveFileVisitor {
+    type Value = RawSaveFile;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a scribble file")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<RawSaveFile, A::Error> {
+        let mut ret = RawSaveFile {
+            fields: serde_json::Map::new(),
+            audio_snippets: None,
+        };
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "audio_snippets" {
+                ret.audio_snippets = Some(map.next_value()?);
+            } else {
+                let value: serde_json::Value = map.next_value()?;
+                // We write the version first, so this stops us from trying to read audio in a
+                // format that we don't understand. (Binary files written by older versions of
+                // scribble have the audio first, but they're old enough for us to read.)
+                if let Some(version) = value.as_u64().filter(|_| key == "version") {
+                    check_version(version).map_err(de::Error::custom)?;
+                }
+                ret.fields.insert(key, value);
+            }
+        }
+        Ok(ret)
+    }
+}
+
+// The path that leads from the directory `base` to `path`, if both are absolute and there is
+// such a path.
+fn relative_path(path: &Path, base: &Path) -> Option<PathBuf> {
+    if !path.is_absolute() || !base.is_absolute() {
+        return None;
+    }
+    let path: Vec<Component> = path.components().collect();
+    let base: Vec<Component> = base.components().collect();
+    // On windows, there's no relative path between different drives.
+    if path.first() != base.first() {
+        return None;
+    }
+    let common = path.iter().zip(&base).take_while(|(a, b)| a == b).count();
+    let mut ret = PathBuf::new();
+    for _ in common..base.len() {
+        ret.push("..");
+    }
+    for c in &path[common..] {
+        ret.push(c);
+    }
+    Some(ret)
+}
+
+fn write_in_chunks<W: std::io::Write>(
+    write: &mut W,
+    data: &[u8],
+    progress: &mut impl FnMut(f64),
+) -> std::io::Result<()> {
+    let chunks = data.chunks(SAVE_CHUNK_SIZE);
+    let num_chunks = chunks.len();
+    for (i, chunk) in chunks.enumerate() {
+        write.write_all(chunk)?;
+        progress((i + 1) as f64 / num_chunks as f64);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    use scribble_project::settings::AspectRatio;
+
+    fn png() -> Vec<u8> {
+        let img = image::RgbaImage::from_pixel(2, 2, image::Rgba([0, 0, 255, 255]));
+        let mut buf = Vec::new();
+        image::DynamicImage::ImageRgba8(img)
+            .write_to(&mut buf, image::ImageOutputFormat::Png)
+            .unwrap();
+        buf
+    }
+
+    // A project that uses every field described in the module documentation.
+    fn everything() -> serde_json::Value {
+        json!({
+            "version": SAVE_FILE_VERSION,
+            "snippets": {
+                "1": {
+                    "curve": [{
+                        "elements": [[0, 0], [1000, 0], [2000, 1000], [2000, 2000]],
+                        "times": [0, 100000],
+                        "style": { "color": 0xff0000ffu32, "thickness": 0.004 },
+                        "effects": [{ "Fade": { "pause": 250000, "fade": 500000 } }],
+                    }],
+                    "lerp": {
+                        "original_values": [0, 100000],
+                        "lerped_values": [1000000, 1050000],
+                    },
+                    "end": 3000000,
+                    "reversed": true,
+                    "pen": { "points": [[0, 0], [2000, 2000]], "times": [0, 100000] },
+                },
+                "3": {
+                    "curve": [],
+                    "lerp": { "original_values": [0, 0], "lerped_values": [0, 0] },
+                    "end": null,
+                    "reversed": false,
+                    "pen": { "points": [], "times": [] },
+                },
+            },
+            "audio_snippets": {
+                "1": { "buf": [0, 100, -100, 32767, -32768], "start_time": 500000 },
+                "2": { "buf": [], "start_time": 0, "link": "music/intro.mp3" },
+            },
+            "settings": {
+                "aspect_ratio": "Vertical",
+                "background": 0x000000ffu32,
+                "watermark": {
+                    "encoded": png(),
+                    "corner": "TopLeft",
+                    "scale": 0.25,
+                    "opacity": 0.5,
+                },
+                "title": "Fractions",
+                "author": "Me",
+                "created": 1600000000u64,
+                "frame_rate": "Fps60",
+                "recording_speed": "Slower",
+                "recording_setup": {
+                    "fade": true,
+                    "pen_color": 0x00ff00ffu32,
+                    "line_thickness": 0.008,
+                },
+            },
+            "images": {
+                "5": {
+                    "encoded": png(),
+                    "rect": [0.25, 0.125, 0.75, 0.625],
+                    "start_time": 2000000,
+                    "end_time": null,
+                    "fade_in": 250000,
+                },
+            },
+            "captions": [
+                { "start": 0, "end": 1500000, "text": "Let's add some fractions." },
+                { "start": 2000000, "end": 3000000, "text": "First,\nthe denominators." },
+            ],
+        })
+    }
+
+    #[test]
+    fn schema() {
+        let value = everything();
+        let data = SaveFileData::from_json_value(value.clone()).unwrap();
+        assert_eq!(data.snippets.snippets().count(), 2);
+        assert_eq!(data.audio_snippets.snippets().count(), 2);
+        assert_eq!(data.images.snippets().count(), 1);
+        assert_eq!(data.captions.captions().len(), 2);
+        assert_eq!(data.settings.title, "Fractions");
+        assert_eq!(data.settings.aspect_ratio, AspectRatio::Vertical);
+
+        // Everything that we read gets written back exactly as it was.
+        assert_eq!(data.to_json_value().unwrap(), value);
+    }
+
+    #[test]
+    fn round_trip() {
+        let value = everything();
+        for &format in &[SaveFormat::Json, SaveFormat::Binary] {
+            let data = SaveFileData::from_json_value(value.clone()).unwrap();
+            let mut written = Vec::new();
+            data.save_to(&mut written, format).unwrap();
+            assert_eq!(
+                written.starts_with(ZSTD_MAGIC),
+                format == SaveFormat::Binary
+            );
+
+            let read_again = SaveFileData::load_from(&written[..]).unwrap();
+            assert_eq!(read_again.to_json_value().unwrap(), value);
+        }
+
+        // An empty project survives too.
+        let empty = SaveFileData::default();
+        let mut written = Vec::new();
+        empty.save_to(&mut written, SaveFormat::Binary).unwrap();
+        let read_again = SaveFileData::load_from(&written[..]).unwrap();
+        assert_eq!(
+            read_again.to_json_value().unwrap(),
+            empty.to_json_value().unwrap()
+        );
+    }
+
+    #[test]
+    fn optional_fields() {
+        let mut value = everything();
+        value["version"] = 0.into();
+        value.as_object_mut().unwrap().remove("settings");
+        value.as_object_mut().unwrap().remove("images");
+        value.as_object_mut().unwrap().remove("captions");
+        let snippet = value["snippets"]["1"].as_object_mut().unwrap();
+        snippet.remove("reversed");
+        snippet.remove("pen");
+        value["audio_snippets"]["3"] = json!({ "start_time": 0, "link": "a.mp3" });
+        value["unknown_field"] = json!("is ignored");
+
+        let data = SaveFileData::from_json_value(value).unwrap();
+        assert_eq!(data.version, SAVE_FILE_VERSION);
+        assert_eq!(data.settings, ProjectSettings::default());
+        assert_eq!(data.images.snippets().count(), 0);
+        assert!(data.captions.is_empty());
+        let written = data.to_json_value().unwrap();
+        assert_eq!(written["snippets"]["1"]["reversed"], json!(false));
+        assert_eq!(written["audio_snippets"]["3"]["buf"], json!([]));
+
+        let err = SaveFileData::from_json_value(json!({ "snippets": {} }))
+            .err()
+            .unwrap();
+        assert!(err
+            .to_string()
+            .contains("doesn't look like a scribble file"));
+    }
+
+    #[test]
+    fn load_audio_without_json() {
+        let value = everything();
+        for &format in &[SaveFormat::Json, SaveFormat::Binary] {
+            let mut written = Vec::new();
+            SaveFileData::from_json_value(value.clone())
+                .unwrap()
+                .save_to(&mut written, format)
+                .unwrap();
+            let read_again = SaveFileData::load_from(&written[..]).unwrap();
+            assert_eq!(read_again.audio_snippets.snippets().count(), 2);
+            assert_eq!(read_again.to_json_value().unwrap(), value);
+        }
+
+        // A file without audio is still an error, like it was when it went through json.
+        let mut no_audio = value;
+        no_audio.as_object_mut().unwrap().remove("audio_snippets");
+        let mut written = Vec::new();
+        let mut compress = flate2::write::GzEncoder::new(&mut written, Default::default());
+        serde_json::to_writer(&mut compress, &no_audio).unwrap();
+        compress.finish().unwrap();
+        assert!(SaveFileData::load_from(&written[..]).is_err());
+    }
+
+    #[test]
+    fn linked_audio_round_trip() {
+        let mut value = everything();
+        value["version"] = 1.into();
+        value["audio_snippets"].as_object_mut().unwrap().remove("2");
+        let data = SaveFileData::from_json_value(value).unwrap();
+        assert_eq!(data.version, SAVE_FILE_VERSION);
+
+        let value = everything();
+        for &format in &[SaveFormat::Json, SaveFormat::Binary] {
+            let mut written = Vec::new();
+            SaveFileData::from_json_value(value.clone())
+                .unwrap()
+                .save_to(&mut written, format)
+                .unwrap();
+            let read_again = SaveFileData::load_from(&written[..]).unwrap();
+            let linked: Vec<_> = read_again
+                .audio_snippets
+                .snippets()
+                .filter_map(|(_, snip)| snip.link())
+                .collect();
+            assert_eq!(linked, vec![Path::new("music/intro.mp3")]);
+            assert_eq!(
+                read_again.to_json_value().unwrap()["version"],
+                json!(SAVE_FILE_VERSION)
+            );
+        }
+    }
+
+    #[test]
+    fn captions_round_trip() {
+        let mut value = everything();
+        value["version"] = 2.into();
+        value.as_object_mut().unwrap().remove("captions");
+        let data = SaveFileData::from_json_value(value).unwrap();
+        assert!(data.captions.is_empty());
+        assert_eq!(data.to_json_value().unwrap()["captions"], json!([]));
+
+        // Files that claim to be new enough need their captions.
+        let mut value = everything();
+        value.as_object_mut().unwrap().remove("captions");
+        assert!(SaveFileData::from_json_value(value).is_err());
+
+        let value = everything();
+        for &format in &[SaveFormat::Json, SaveFormat::Binary] {
+            let mut written = Vec::new();
+            SaveFileData::from_json_value(value.clone())
+                .unwrap()
+                .save_to(&mut written, format)
+                .unwrap();
+            let read_again = SaveFileData::load_from(&written[..]).unwrap();
+            assert_eq!(read_again.captions.captions().len(), 2);
+            assert_eq!(
+                read_again.to_json_value().unwrap()["captions"],
+                value["captions"]
+            );
+        }
+    }
+
+    #[test]
+    fn relative_paths() {
+        let rel = |path: &str, base: &str| relative_path(Path::new(path), Path::new(base));
+        assert_eq!(
+            rel("/home/me/lectures/audio/intro.mp3", "/home/me/lectures"),
+            Some(PathBuf::from("audio/intro.mp3"))
+        );
+        assert_eq!(
+            rel("/home/me/music/intro.mp3", "/home/me/lectures/week1"),
+            Some(PathBuf::from("../../music/intro.mp3"))
+        );
+        assert_eq!(rel("intro.mp3", "/home/me"), None);
+
+        // Links from older save files are absolute, and they resolve to themselves.
+        let link = Path::new("/home/me/music/intro.mp3");
+        assert_eq!(Path::new("/home/me/lectures").join(link), link);
+    }
+
+    #[test]
+    fn save_load() {
+        // TODO: this file is a bit too big. It makes the tests slow.
+        let data = include_bytes!("../../scribble/sample/test.scb");
+
+        // Check that we can read our sample file.
+        let save_data = SaveFileData::load_from(&data[..]).unwrap();
+        // The sample file is older than the project settings, so it should get the defaults.
+        assert_eq!(save_data.settings, ProjectSettings::default());
+
+        let mut written = Vec::new();
+        save_data.save_to(&mut written, SaveFormat::Json).unwrap();
+
+        // We don't check that save -> load is the identity, because it's too
+        // fragile (e.g., compression settings could change). We also don't check
+        // that load -> save is the identity (for now), because implementing
+        // PartialEq is a pain.
+        let read_again = SaveFileData::load_from(&written[..]).unwrap();
+        assert_eq!(read_again.version, SAVE_FILE_VERSION);
+
+        // We do check that if something was written using the current version
+        // of scribble, then save -> load is the identity.
+        let mut written_again = Vec::new();
+        read_again
+            .save_to(&mut written_again, SaveFormat::Json)
+            .unwrap();
+        assert_eq!(written, written_again);
+    }
+
+    #[test]
+    fn binary_save_load() {
+        let data = include_bytes!("../../scribble/sample/test.scb");
+        let save_data = SaveFileData::load_from(&data[..]).unwrap();
+        let mut json = Vec::new();
+        save_data.save_to(&mut json, SaveFormat::Json).unwrap();
+
+        let mut binary = Vec::new();
+        save_data.save_to(&mut binary, SaveFormat::Binary).unwrap();
+        assert!(binary.starts_with(ZSTD_MAGIC));
+
+        // Converting to binary and back doesn't change anything.
+        let read_again = SaveFileData::load_from(&binary[..]).unwrap();
+        let mut json_again = Vec::new();
+        read_again
+            .save_to(&mut json_again, SaveFormat::Json)
+            .unwrap();
+        assert_eq!(json, json_again);
+    }
+
+    #[test]
+    fn save_file_versions() {
+        // Every old version needs a migration.
+        assert_eq!(MIGRATIONS.len() as u64, SAVE_FILE_VERSION);
+
+        let mut save_data = SaveFileData::default();
+        save_data.version = SAVE_FILE_VERSION + 1;
+        for &format in &[SaveFormat::Json, SaveFormat::Binary] {
+            let mut written = Vec::new();
+            save_data.save_to(&mut written, format).unwrap();
+            let err = SaveFileData::load_from(&written[..]).err().unwrap();
+            assert!(err.to_string().contains("newer version"));
+        }
+    }
+}
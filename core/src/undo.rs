@@ -0,0 +1,759 @@
+// Our general undo philosophy follows the data split between the app's `AppState` and
+// `ScribbleState`: the latter contains the state of the actual animation being
+// created, and the changes to that are the ones that we want to support
+// undoing. Therefore, our undo stack is essentially just a stack of
+// `ScribbleState`s, and we execute undoing and redoing by pushing and popping
+// these states. (This is less wasteful than it seems at first glance, because
+// most of the actual data in `ScribbleState` is behind shared pointers.)
+//
+// The maps of snippets aren't shared between states, though: changing one
+// snippet copies the whole map. So most states are stored as the changes from
+// the state below them (the same kind of changes that the autosave journal
+// records), and reconstructed when they're needed. Every so often (and always
+// at the bottom of the stack) we store a whole state, so that reconstructing
+// never has to go back too far.
+//
+// The selection and the mark are part of `ScribbleState`, so they come along
+// for free. Some of the application state is also relevant to the bigger undo
+// picture, though: if we undo back into the middle of drawing a snippet, we
+// need to resume drawing from where that state was saved; and the selected
+// palette color should go back to what it was. These bits are stored alongside
+// the `ScribbleState` in an `UndoState`, and `AppState` knows how to capture and
+// restore them.
+//
+// Some edits are made up of several smaller ones (for example, everything that
+// happens during a single drag). These can be wrapped in an undo group, and then
+// all the states that get pushed while the group is open are undone in one step.
+//
+// Undoing a few steps and then making a new edit would normally throw away the
+// states that could have been redone. Instead, we keep them as a branch, which
+// hangs off the state that they were undone to. Switching to a branch swaps it
+// with the states that came after the branch point on the current line (which
+// then become a branch themselves), so the history is really a tree.
+
+use piet::Color;
+use scribble_curves::{Curve, SnippetData, SnippetId, Time};
+use scribble_project::captions::CaptionsData;
+use scribble_project::images::{ImageSnippetData, ImageSnippetId};
+use scribble_project::settings::ProjectSettings;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::audio::{AudioSnippetData, AudioSnippetId};
+use crate::model::{CopiedSnippet, MaybeSnippetId, ScribbleState};
+
+const MAX_UNDO_STACK: usize = 128;
+
+// The most states in a row that can be stored as changes, before we store a whole one.
+const MAX_CHANGES_IN_A_ROW: usize = 32;
+
+/// For edits that tend to come in quick bursts (like nudging the mark around), this is how soon
+/// another edit of the same kind needs to come in order to be merged into the same undo step.
+pub const COALESCE_WINDOW: Duration = Duration::from_millis(750);
+
+// How many abandoned branches we keep around.
+const MAX_BRANCHES: usize = 16;
+
+/// How many undo states get saved along with a project (if the user wants the undo history
+/// saved at all).
+pub const MAX_SAVED_UNDO: usize = 20;
+
+/// Everything that gets restored by undoing or redoing.
+#[derive(Clone)]
+pub struct UndoState {
+    pub scribble: ScribbleState,
+
+    /// If this state was saved in the middle of drawing a snippet, this is the time that we
+    /// should resume drawing from.
+    pub recording_time: Option<Time>,
+
+    /// The palette's selected color. This is `None` for the initial state, which gets created
+    /// before there's a palette.
+    pub color: Option<Color>,
+
+    /// A description of the edit that led to this state, like "Delete Snippet". This is `None`
+    /// for states that weren't created by an edit (like the initial state).
+    pub description: Option<&'static str>,
+}
+
+impl UndoState {
+    pub fn new(scribble: ScribbleState) -> UndoState {
+        UndoState {
+            scribble,
+            recording_time: None,
+            color: None,
+            description: None,
+        }
+    }
+}
+
+// The changes between two animations. Only the snippets that changed are
+// stored; the rest of the animation is small (or behind shared pointers), so
+// it's stored whole.
+struct Changes {
+    snippets: (Vec<(SnippetId, SnippetData)>, Vec<SnippetId>),
+    audio: (Vec<(AudioSnippetId, AudioSnippetData)>, Vec<AudioSnippetId>),
+    images: (Vec<(ImageSnippetId, ImageSnippetData)>, Vec<ImageSnippetId>),
+    captions: CaptionsData,
+    new_curve: Option<Arc<Curve>>,
+    selected_snippet: MaybeSnippetId,
+    mark: Option<Time>,
+    settings: ProjectSettings,
+    trash: Arc<Vec<CopiedSnippet>>,
+}
+
+impl Changes {
+    fn new(old: &ScribbleState, new: &ScribbleState) -> Changes {
+        Changes {
+            snippets: new.snippets.changes_since(&old.snippets),
+            audio: new.audio_snippets.changes_since(&old.audio_snippets),
+            images: new.images.changes_since(&old.images),
+            captions: new.captions.clone(),
+            new_curve: new.new_curve.clone(),
+            selected_snippet: new.selected_snippet,
+            mark: new.mark,
+            settings: new.settings.clone(),
+            trash: new.trash.clone(),
+        }
+    }
+
+    fn apply(&self, old: &ScribbleState) -> ScribbleState {
+        // If a map didn't change, we keep sharing it.
+        let (changed, removed) = &self.snippets;
+        let snippets = if changed.is_empty() && removed.is_empty() {
+            old.snippets.clone()
+        } else {
+            old.snippets.with_changes(changed.clone(), removed)
+        };
+        let (changed, removed) = &self.audio;
+        let audio_snippets = if changed.is_empty() && removed.is_empty() {
+            old.audio_snippets.clone()
+        } else {
+            old.audio_snippets.with_changes(changed.clone(), removed)
+        };
+        let (changed, removed) = &self.images;
+        let images = if changed.is_empty() && removed.is_empty() {
+            old.images.clone()
+        } else {
+            old.images.with_changes(changed.clone(), removed)
+        };
+        ScribbleState {
+            new_curve: self.new_curve.clone(),
+            snippets,
+            audio_snippets,
+            images,
+            captions: self.captions.clone(),
+            selected_snippet: self.selected_snippet,
+            mark: self.mark,
+            settings: self.settings.clone(),
+            trash: self.trash.clone(),
+        }
+    }
+}
+
+enum Stored {
+    Whole(ScribbleState),
+    // The changes that turn the state below this one (or, for the oldest state
+    // in a branch, the state that the branch split off from) into this one.
+    Changes(Box<Changes>),
+}
+
+struct UndoData {
+    scribble: Stored,
+
+    // The rest of the `UndoState`.
+    recording_time: Option<Time>,
+    color: Option<Color>,
+    description: Option<&'static str>,
+
+    // A unique (within this stack) id, so that branches can find the state
+    // that they split off from.
+    id: u64,
+
+    // If an undo state is transient, we delete it next time a non-transient
+    // state is pushed. This is used for undoing in the middle of a snippet: we
+    // store a (transient) undo state for every segment that gets drawn, and
+    // then when the snippet is done being drawn we delete all the little
+    // transient undo states and replace it with just one for the whole snippet.
+    transient: bool,
+
+    // When this state was pushed (or last had another state merged into it).
+    pushed: Instant,
+}
+
+// Some states that were undone, and then abandoned by making a different edit.
+#[derive(Debug)]
+struct Branch {
+    // The id of the state that this branch split off from.
+    parent: u64,
+    // The states in the branch, newest first.
+    states: Vec<UndoData>,
+    abandoned: Instant,
+}
+
+/// A description of an abandoned branch, for choosing which one to switch to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BranchSummary {
+    /// How many undo steps the branch has.
+    pub edits: usize,
+    /// When the branch was abandoned.
+    pub abandoned: Instant,
+}
+
+#[derive(Debug)]
+pub struct UndoStack {
+    stack: VecDeque<UndoData>,
+    current_state: usize,
+    next_id: u64,
+
+    // The abandoned branches, most recently abandoned first. The states that
+    // they split off from are always on the stack: when a state drops off the
+    // stack, its branches get dropped too.
+    branches: Vec<Branch>,
+
+    // How many undo groups are currently open (they can be nested, but only the
+    // outermost one matters), and whether a state has been pushed since the
+    // outermost one began.
+    group_depth: usize,
+    group_pushed: bool,
+
+    // The id and animation of the state that was most recently pushed or
+    // restored, because that's the one that the next state will be compared to.
+    cache: Option<(u64, ScribbleState)>,
+}
+
+impl std::fmt::Debug for UndoData {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("UndoData")
+            .field("id", &self.id)
+            .field("whole", &matches!(self.scribble, Stored::Whole(_)))
+            .field("transient", &self.transient)
+            .finish()
+    }
+}
+
+impl UndoStack {
+    pub fn new(initial_state: ScribbleState) -> UndoStack {
+        let mut stack = VecDeque::new();
+        stack.push_front(UndoData {
+            scribble: Stored::Whole(initial_state),
+            recording_time: None,
+            color: None,
+            description: None,
+            id: 0,
+            transient: false,
+            pushed: Instant::now(),
+        });
+        UndoStack {
+            stack,
+            current_state: 0,
+            next_id: 1,
+            branches: Vec::new(),
+            group_depth: 0,
+            group_pushed: false,
+            cache: None,
+        }
+    }
+
+    /// Creates an undo stack whose current state is `current`, and which can undo back through
+    /// `history` (most recent first). This is for restoring a saved undo history.
+    pub fn with_history(current: ScribbleState, mut history: Vec<ScribbleState>) -> UndoStack {
+        history.truncate(MAX_UNDO_STACK - 1);
+        let mut older = history.into_iter().rev();
+        let mut ret = match older.next() {
+            Some(oldest) => UndoStack::new(oldest),
+            None => return UndoStack::new(current),
+        };
+        for scribble in older.chain(std::iter::once(current)) {
+            ret.push(UndoState::new(scribble));
+        }
+        ret
+    }
+
+    /// The states that we can undo back to, most recent first (and at most `max` of them). This
+    /// is for saving the undo history, so transient states are skipped.
+    pub fn history(&self, max: usize) -> Vec<ScribbleState> {
+        (self.current_state + 1..self.stack.len())
+            .filter(|&idx| !self.stack[idx].transient)
+            .take(max)
+            .map(|idx| self.scribble_at(idx))
+            .collect()
+    }
+
+    // Reconstructs the animation of the `idx`th state on the stack.
+    fn scribble_at(&self, idx: usize) -> ScribbleState {
+        // Look down the stack for a whole state to start from. There's always one at the bottom.
+        let mut start = idx;
+        let mut scribble = loop {
+            let data = &self.stack[start];
+            match (&self.cache, &data.scribble) {
+                (Some((id, cached)), _) if *id == data.id => break cached.clone(),
+                (_, Stored::Whole(scribble)) => break scribble.clone(),
+                (_, Stored::Changes(_)) => start += 1,
+            }
+        };
+        for data in self.stack.range(idx..start).rev() {
+            if let Stored::Changes(changes) = &data.scribble {
+                scribble = changes.apply(&scribble);
+            }
+        }
+        scribble
+    }
+
+    // Reconstructs the `idx`th state on the stack, and remembers it for comparing to the next
+    // state that gets pushed.
+    fn state_at(&mut self, idx: usize) -> UndoState {
+        let scribble = self.scribble_at(idx);
+        let data = &self.stack[idx];
+        self.cache = Some((data.id, scribble.clone()));
+        UndoState {
+            scribble,
+            recording_time: data.recording_time,
+            color: data.color.clone(),
+            description: data.description,
+        }
+    }
+
+    // Drops states off the bottom of the stack until it isn't too big.
+    fn trim(&mut self) {
+        while self.stack.len() > MAX_UNDO_STACK {
+            // The new bottom state needs to be whole, since there won't be anything below it.
+            let idx = self.stack.len() - 2;
+            if let Stored::Changes(_) = self.stack[idx].scribble {
+                self.stack[idx].scribble = Stored::Whole(self.scribble_at(idx));
+            }
+            self.stack.pop_back();
+        }
+    }
+
+    fn new_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    // Saves some states (newest first) that split off from `parent` as a branch.
+    fn add_branch(&mut self, parent: u64, states: Vec<UndoData>) {
+        // Transient states are only useful in the middle of drawing a snippet, and we
+        // won't be in the middle of that one anymore.
+        let states: Vec<_> = states.into_iter().filter(|s| !s.transient).collect();
+        if !states.is_empty() {
+            self.branches.insert(
+                0,
+                Branch {
+                    parent,
+                    states,
+                    abandoned: Instant::now(),
+                },
+            );
+            self.branches.truncate(MAX_BRANCHES);
+        }
+    }
+
+    // Drops the branches whose branch points are no longer on the stack.
+    fn prune_branches(&mut self) {
+        let stack = &self.stack;
+        self.branches
+            .retain(|b| stack.iter().any(|state| state.id == b.parent));
+    }
+
+    fn do_push(&mut self, state: UndoState, transient: bool) {
+        // In case the current state is not the newest one, the newer ones get moved off the stack
+        // into a new branch.
+        let abandoned: Vec<_> = self.stack.drain(0..self.current_state).collect();
+        if let Some(parent) = self.stack.front().map(|s| s.id) {
+            self.add_branch(parent, abandoned);
+        }
+
+        // In case the top of the stack is transient and this one isn't, remove all the transient ones.
+        if !transient {
+            let last_permanent = self
+                .stack
+                .iter()
+                .position(|s| !s.transient)
+                .unwrap_or(self.stack.len());
+            self.stack.drain(..last_permanent);
+
+            // If we already pushed a state in this group, this one replaces it.
+            if self.group_depth > 0 {
+                if self.group_pushed {
+                    self.stack.pop_front();
+                }
+                self.group_pushed = true;
+            }
+        }
+
+        let changes_in_a_row = self
+            .stack
+            .iter()
+            .position(|s| matches!(s.scribble, Stored::Whole(_)))
+            .unwrap_or(MAX_CHANGES_IN_A_ROW);
+        let scribble = if self.stack.is_empty() || changes_in_a_row >= MAX_CHANGES_IN_A_ROW {
+            Stored::Whole(state.scribble.clone())
+        } else {
+            let old = self.scribble_at(0);
+            Stored::Changes(Box::new(Changes::new(&old, &state.scribble)))
+        };
+        let id = self.new_id();
+        self.stack.push_front(UndoData {
+            scribble,
+            recording_time: state.recording_time,
+            color: state.color,
+            description: state.description,
+            id,
+            transient,
+            pushed: Instant::now(),
+        });
+        self.cache = Some((id, state.scribble));
+        self.trim();
+        self.current_state = 0;
+        self.prune_branches();
+    }
+    pub fn push(&mut self, state: UndoState) {
+        self.do_push(state, false);
+    }
+
+    pub fn push_transient(&mut self, state: UndoState) {
+        self.do_push(state, true);
+    }
+
+    /// Pushes a state, unless the newest state came from an edit with the same description
+    /// less than `window` ago; in that case, this state replaces it, so that undoing reverts
+    /// the whole burst of edits at once.
+    pub fn push_coalescing(&mut self, state: UndoState, window: Duration) {
+        let coalesce = match self.stack.front() {
+            Some(top) => {
+                self.current_state == 0
+                    && self.stack.len() > 1
+                    && self.group_depth == 0
+                    && !top.transient
+                    && top.description.is_some()
+                    && top.description == state.description
+                    && top.pushed.elapsed() < window
+            }
+            None => false,
+        };
+        if coalesce {
+            self.stack.pop_front();
+        }
+        self.do_push(state, false);
+    }
+
+    /// Starts an undo group: until the matching call to `end_group`, all the
+    /// pushed states will be undone together.
+    pub fn begin_group(&mut self) {
+        if self.group_depth == 0 {
+            self.group_pushed = false;
+        }
+        self.group_depth += 1;
+    }
+
+    pub fn end_group(&mut self) {
+        if self.group_depth == 0 {
+            log::warn!("ending an undo group, but none was started");
+            return;
+        }
+        self.group_depth -= 1;
+    }
+
+    pub fn undo(&mut self) -> Option<UndoState> {
+        // Undoing in the middle of a group means that the next push starts a new step
+        // (otherwise it would replace the state that we just went back to).
+        self.group_pushed = false;
+        if self.current_state + 1 < self.stack.len() {
+            self.current_state += 1;
+            Some(self.state_at(self.current_state))
+        } else {
+            None
+        }
+    }
+
+    pub fn redo(&mut self) -> Option<UndoState> {
+        self.group_pushed = false;
+        if self.current_state > 0 {
+            self.current_state -= 1;
+            Some(self.state_at(self.current_state))
+        } else {
+            None
+        }
+    }
+
+    /// The abandoned branches that we can switch to, most recently abandoned first.
+    pub fn branches(&self) -> Vec<BranchSummary> {
+        self.branches
+            .iter()
+            .map(|b| BranchSummary {
+                edits: b.states.len(),
+                abandoned: b.abandoned,
+            })
+            .collect()
+    }
+
+    /// Switches to the `idx`th abandoned branch, returning its newest state. The states on the
+    /// current line that came after the branch point become a branch in its place.
+    pub fn switch_branch(&mut self, idx: usize) -> Option<UndoState> {
+        if idx >= self.branches.len() {
+            return None;
+        }
+        let branch = self.branches.remove(idx);
+        let branch_point = self.stack.iter().position(|s| s.id == branch.parent)?;
+        let current_line: Vec<_> = self.stack.drain(0..branch_point).collect();
+        for state in branch.states.into_iter().rev() {
+            self.stack.push_front(state);
+        }
+        self.trim();
+        self.add_branch(branch.parent, current_line);
+        self.prune_branches();
+        self.current_state = 0;
+        self.group_pushed = false;
+        Some(self.state_at(0))
+    }
+
+    /// The description of the edit that undoing would undo, if there is one.
+    pub fn undo_description(&self) -> Option<&'static str> {
+        if self.can_undo() {
+            self.stack[self.current_state].description
+        } else {
+            None
+        }
+    }
+
+    /// The description of the edit that redoing would redo, if there is one.
+    pub fn redo_description(&self) -> Option<&'static str> {
+        if self.can_redo() {
+            self.stack[self.current_state - 1].description
+        } else {
+            None
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.current_state + 1 < self.stack.len()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        self.current_state > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scribble_curves::Diff;
+
+    fn scribble(mark: i64) -> ScribbleState {
+        ScribbleState {
+            mark: Some(Time::from_micros(mark)),
+            ..Default::default()
+        }
+    }
+
+    fn state(mark: i64) -> UndoState {
+        UndoState::new(scribble(mark))
+    }
+
+    fn mark(state: Option<UndoState>) -> Option<i64> {
+        state.and_then(|s| s.scribble.mark).map(|t| t.as_micros())
+    }
+
+    #[test]
+    fn groups() {
+        let mut undo = UndoStack::new(scribble(0));
+        undo.push(state(1));
+        undo.begin_group();
+        undo.push(state(2));
+        undo.begin_group();
+        undo.push(state(3));
+        undo.end_group();
+        undo.push_transient(state(4));
+        undo.push(state(5));
+        undo.end_group();
+        undo.push(state(6));
+
+        assert_eq!(mark(undo.undo()), Some(5));
+        assert_eq!(mark(undo.undo()), Some(1));
+        assert_eq!(mark(undo.redo()), Some(5));
+        assert_eq!(mark(undo.undo()), Some(1));
+        assert_eq!(mark(undo.undo()), Some(0));
+        assert!(!undo.can_undo());
+    }
+
+    #[test]
+    fn history() {
+        let mut undo = UndoStack::new(scribble(0));
+        undo.push(state(1));
+        undo.push(state(2));
+        undo.push_transient(state(3));
+        undo.push(state(4));
+        undo.undo();
+        let marks = |states: Vec<ScribbleState>| {
+            states
+                .into_iter()
+                .map(|s| s.mark.unwrap().as_micros())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(marks(undo.history(10)), vec![1, 0]);
+        assert_eq!(marks(undo.history(1)), vec![1]);
+
+        let mut restored = UndoStack::with_history(scribble(2), undo.history(10));
+        assert!(!restored.can_redo());
+        assert_eq!(mark(restored.undo()), Some(1));
+        assert_eq!(mark(restored.undo()), Some(0));
+        assert!(!restored.can_undo());
+    }
+
+    #[test]
+    fn branches() {
+        let mut undo = UndoStack::new(scribble(0));
+        undo.push(state(1));
+        undo.push(state(2));
+        undo.push(state(3));
+        undo.undo();
+        undo.undo();
+        undo.push(state(4));
+        assert!(!undo.can_redo());
+        assert_eq!(undo.branches().len(), 1);
+        assert_eq!(undo.branches()[0].edits, 2);
+
+        // Switching lands on the newest state of the branch, and the line we were on becomes a
+        // branch instead.
+        assert_eq!(mark(undo.switch_branch(0)), Some(3));
+        assert_eq!(undo.branches().len(), 1);
+        assert_eq!(undo.branches()[0].edits, 1);
+        assert_eq!(mark(undo.undo()), Some(2));
+        assert_eq!(mark(undo.undo()), Some(1));
+        assert_eq!(mark(undo.switch_branch(0)), Some(4));
+        assert_eq!(mark(undo.undo()), Some(1));
+        assert_eq!(mark(undo.undo()), Some(0));
+        assert!(undo.switch_branch(1).is_none());
+    }
+
+    #[test]
+    fn descriptions() {
+        let mut undo = UndoStack::new(scribble(0));
+        let described = |mark, description| UndoState {
+            description: Some(description),
+            ..state(mark)
+        };
+        undo.push(described(1, "Add Snippet"));
+        undo.push(described(2, "Delete Snippet"));
+        assert_eq!(undo.undo_description(), Some("Delete Snippet"));
+        assert_eq!(undo.redo_description(), None);
+        undo.undo();
+        assert_eq!(undo.undo_description(), Some("Add Snippet"));
+        assert_eq!(undo.redo_description(), Some("Delete Snippet"));
+        undo.undo();
+        assert_eq!(undo.undo_description(), None);
+    }
+
+    #[test]
+    fn coalescing() {
+        let mut undo = UndoStack::new(scribble(0));
+        let described = |mark, description| UndoState {
+            description: Some(description),
+            ..state(mark)
+        };
+        let long = Duration::from_secs(1000);
+        undo.push_coalescing(described(1, "Set Mark"), long);
+        undo.push_coalescing(described(2, "Set Mark"), long);
+        undo.push_coalescing(described(3, "Set Mark"), long);
+        undo.push_coalescing(described(4, "Change Speed"), long);
+        undo.push_coalescing(described(5, "Change Speed"), Duration::from_secs(0));
+
+        assert_eq!(mark(undo.undo()), Some(4));
+        assert_eq!(mark(undo.undo()), Some(3));
+        assert_eq!(mark(undo.undo()), Some(0));
+        assert!(!undo.can_undo());
+
+        // Nothing gets merged into a state that we've undone back to.
+        undo.redo();
+        undo.push_coalescing(described(6, "Set Mark"), long);
+        assert_eq!(mark(undo.undo()), Some(3));
+    }
+
+    // Makes a sequence of edits, each one adding, moving, or removing an audio snippet.
+    fn edits(count: usize) -> Vec<ScribbleState> {
+        let mut ret = vec![ScribbleState::default()];
+        for i in 0..count {
+            let mut s = ret.last().unwrap().clone();
+            let ids: Vec<_> = s.audio_snippets.snippets().map(|(id, _)| id).collect();
+            match (i % 3, ids.first()) {
+                (1, Some(&id)) => {
+                    let snip = s.audio_snippets.snippet(id).shifted(Diff::from_micros(10));
+                    s.audio_snippets = s.audio_snippets.with_changes(vec![(id, snip)], &[]);
+                }
+                (2, Some(&id)) if ids.len() > 2 => {
+                    s.audio_snippets = s.audio_snippets.without_snippet(id);
+                }
+                _ => {
+                    let snip = AudioSnippetData::new(vec![i as i16], Time::from_micros(i as i64));
+                    s.audio_snippets = s.audio_snippets.with_new_snippet(snip).0;
+                }
+            }
+            s.mark = Some(Time::from_micros(i as i64));
+            ret.push(s);
+        }
+        ret
+    }
+
+    fn assert_same(a: &ScribbleState, b: &ScribbleState) {
+        let a_snips: Vec<_> = a.audio_snippets.snippets().collect();
+        let b_snips: Vec<_> = b.audio_snippets.snippets().collect();
+        assert_eq!(a_snips.len(), b_snips.len());
+        for ((a_id, a_snip), (b_id, b_snip)) in a_snips.into_iter().zip(b_snips) {
+            assert_eq!(a_id, b_id);
+            assert!(a_snip.is_same(b_snip));
+        }
+        assert_eq!(a.mark, b.mark);
+    }
+
+    #[test]
+    fn changes_reconstruct_states() {
+        // Enough edits that the oldest ones fall off the stack.
+        let states = edits(MAX_UNDO_STACK + MAX_CHANGES_IN_A_ROW + 5);
+        let mut undo = UndoStack::new(states[0].clone());
+        for s in &states[1..] {
+            undo.push(UndoState::new(s.clone()));
+        }
+
+        let mut expected = states.iter().rev();
+        expected.next();
+        while let Some(s) = undo.undo() {
+            assert_same(&s.scribble, expected.next().unwrap());
+        }
+        assert_eq!(states.len() - expected.len(), MAX_UNDO_STACK);
+
+        let mut expected = states[states.len() - MAX_UNDO_STACK + 1..].iter();
+        while let Some(s) = undo.redo() {
+            assert_same(&s.scribble, expected.next().unwrap());
+        }
+        assert!(expected.next().is_none());
+    }
+
+    #[test]
+    fn changes_in_branches() {
+        let states = edits(10);
+        let mut undo = UndoStack::new(states[0].clone());
+        for s in &states[1..6] {
+            undo.push(UndoState::new(s.clone()));
+        }
+        undo.undo();
+        undo.undo();
+        undo.undo();
+        for s in &states[6..] {
+            undo.push(UndoState::new(s.clone()));
+        }
+
+        // The branch is reconstructed from the state it split off from.
+        assert_same(&undo.switch_branch(0).unwrap().scribble, &states[5]);
+        assert_same(&undo.undo().unwrap().scribble, &states[4]);
+        assert_same(&undo.switch_branch(0).unwrap().scribble, &states[10]);
+        assert_same(&undo.undo().unwrap().scribble, &states[9]);
+
+        let history = undo.history(100);
+        assert_eq!(history.len(), 6);
+        assert_same(&history[0], &states[8]);
+        assert_same(&history[3], &states[2]);
+        assert_same(&history[5], &states[0]);
+    }
+}
@@ -1,13 +1,13 @@
-use druid::kurbo::{BezPath, ParamCurve, PathEl, PathSeg, Point};
-use druid::piet::{LineCap, LineJoin, StrokeStyle};
-use druid::{Color, RenderContext};
+use kurbo::{Affine, BezPath, ParamCurve, PathEl, PathSeg, Point};
+use piet::{Color, LineCap, LineJoin, RenderContext, StrokeStyle};
 use serde::ser::SerializeSeq;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use crate::effect::Effects;
-use crate::time::Time;
+use crate::time::{Diff, Time};
 
-mod serde_color {
+/// Serialization for colors, for use with `#[serde(with = "serde_color")]`.
+pub mod serde_color {
     use super::*;
 
     pub fn serialize<S: Serializer>(c: &Color, ser: S) -> Result<S::Ok, S::Error> {
@@ -60,7 +60,8 @@ pub struct Curve {
 
     // The path can consist of many different "segments" (continuous parts between
     // pen lifts). This contains the first index of each segment, which will always
-    // point to a `MoveTo`.
+    // point to a `MoveTo`. The segments are ordered by their starting times, but they
+    // may overlap in time (for example, when drawing in mirror mode).
     seg_boundaries: Vec<usize>,
 
     // This has the same length as `seg_boundaries`.
@@ -95,8 +96,8 @@ impl Curve {
         if t.is_empty() {
             return;
         }
-        if let Some(&last_time) = self.times.last() {
-            assert!(last_time <= t[0]);
+        if let Some(&last_start) = self.seg_boundaries.last() {
+            assert!(self.times[last_start] <= t[0]);
         }
 
         self.seg_boundaries.push(self.times.len());
@@ -108,6 +109,16 @@ impl Curve {
         self.path = BezPath::from_vec(elts);
     }
 
+    /// How long this curve keeps changing after it's finished drawing, because it's fading out.
+    pub fn fade_duration(&self) -> Diff {
+        self.seg_data
+            .iter()
+            .filter_map(|data| data.effects.fade())
+            .map(|fade| fade.pause + fade.fade)
+            .max()
+            .unwrap_or(Diff::from_micros(0))
+    }
+
     pub fn segments<'a>(&'a self) -> impl Iterator<Item = Segment<'a>> + 'a {
         self.seg_boundaries
             .iter()
@@ -127,6 +138,33 @@ impl Curve {
             })
     }
 
+    /// Returns a copy of this curve, with every segment drawn in the color `color`.
+    pub fn with_color(&self, color: &Color) -> Curve {
+        let mut ret = self.clone();
+        for data in &mut ret.seg_data {
+            data.style.color = color.clone();
+        }
+        ret
+    }
+
+    /// Returns a copy of this curve, transformed by `transform`. The line thicknesses are
+    /// scaled along with the curve.
+    pub fn transformed(&self, transform: Affine) -> Curve {
+        let mut ret = self.clone();
+        ret.path = BezPath::from_vec(
+            self.path
+                .elements()
+                .iter()
+                .map(|&el| transform * el)
+                .collect(),
+        );
+        let scale = transform.determinant().abs().sqrt();
+        for data in &mut ret.seg_data {
+            data.style.thickness *= scale;
+        }
+        ret
+    }
+
     // TODO: test this. Maybe add a check_consistent function to check the invariants of `Curve`
     pub fn smoothed(&self, distance_threshold: f64, angle_threshold: f64) -> Curve {
         let mut ret = Curve::new();
@@ -146,10 +184,9 @@ impl Curve {
                 })
                 .collect();
 
-            let point_indices = crate::simplify::simplify(&points, distance_threshold);
+            let (curve, point_indices) =
+                crate::fit::fit(&points, distance_threshold, angle_threshold);
             let times: Vec<Time> = point_indices.iter().map(|&i| seg.times[i]).collect();
-            let points: Vec<Point> = point_indices.iter().map(|&i| points[i]).collect();
-            let curve = crate::smooth::smooth(&points, 0.4, angle_threshold);
 
             let seg_data = SegmentData {
                 style: seg.style.clone(),
@@ -200,7 +237,8 @@ impl Curve {
 
                     if t_idx == 0 {
                         // If we only contain the first element of the curve, it's a MoveTo and doesn't
-                        // need to be drawn anyway.
+                        // need to be drawn anyway. The segments are sorted by starting time, so
+                        // none of the later segments have started either.
                         break;
                     }
 
@@ -225,10 +263,9 @@ impl Curve {
                         PathSeg::Line(x) => c.line_to(x.p1),
                     }
 
+                    // Note that we don't stop here: segments can overlap in time, so there might
+                    // be other segments that are still being drawn.
                     ctx.stroke_styled(&c, &seg.style.color, seg.style.thickness, &stroke_style);
-
-                    // We've already rendered the segment spanning the ending time, so we're done.
-                    break;
                 }
             }
         }
@@ -375,6 +412,89 @@ pub mod tests {
         assert_eq!(c.segments().count(), 2);
     }
 
+    #[test]
+    fn overlapping_segments() {
+        let mut c = Curve::new();
+        let style = LineStyle {
+            color: Color::WHITE,
+            thickness: 1.0,
+        };
+
+        let mut path = BezPath::new();
+        path.move_to((0.0, 0.0));
+        path.curve_to((0.0, 1.0), (1.0, 1.0), (1.0, 0.0));
+        let times = vec![Time::from_micros(1), Time::from_micros(10)];
+        c.append_segment(path.clone(), times.clone(), style.clone().into());
+        // The second segment starts before the first one ends.
+        c.append_segment(path, times, style.into());
+
+        assert_eq!(c.segments().count(), 2);
+    }
+
+    #[test]
+    fn recolor() {
+        let mut c = Curve::new();
+        let style = LineStyle {
+            color: Color::WHITE,
+            thickness: 1.0,
+        };
+        c.move_to(
+            Point::new(0.0, 0.0),
+            Time::from_micros(1),
+            style.clone(),
+            Effects::default(),
+        );
+        c.line_to(Point::new(1.0, 1.0), Time::from_micros(2));
+        c.move_to(
+            Point::new(4.0, 0.0),
+            Time::from_micros(6),
+            style,
+            Effects::default(),
+        );
+        c.line_to(Point::new(1.0, 1.0), Time::from_micros(7));
+
+        let recolored = c.with_color(&Color::BLACK);
+        for seg in recolored.segments() {
+            assert_eq!(seg.style.color.as_rgba_u32(), Color::BLACK.as_rgba_u32());
+            assert_eq!(seg.style.thickness, 1.0);
+        }
+        assert_eq!(recolored.times, c.times);
+    }
+
+    #[test]
+    fn transform() {
+        let mut c = Curve::new();
+        let style = LineStyle {
+            color: Color::WHITE,
+            thickness: 1.0,
+        };
+        c.move_to(
+            Point::new(0.0, 0.0),
+            Time::from_micros(1),
+            style,
+            Effects::default(),
+        );
+        c.line_to(Point::new(1.0, 1.0), Time::from_micros(2));
+
+        let moved = c.transformed(Affine::translate((1.0, 2.0)));
+        assert_eq!(
+            moved.path.elements(),
+            &[
+                PathEl::MoveTo(Point::new(1.0, 2.0)),
+                PathEl::LineTo(Point::new(2.0, 3.0))
+            ]
+        );
+        assert_eq!(moved.segments().next().unwrap().style.thickness, 1.0);
+
+        let scaled = c.transformed(Affine::scale(2.0));
+        assert_eq!(
+            scaled.path.elements()[1],
+            PathEl::LineTo(Point::new(2.0, 2.0))
+        );
+        assert_eq!(scaled.segments().next().unwrap().style.thickness, 2.0);
+        assert_eq!(scaled.times, c.times);
+    }
+
     #[test]
     fn serialize_curve() {
         let mut c = Curve::new();
@@ -2,6 +2,7 @@
 //!
 //! (Or at least, it does in principle. There's only one effect right now.)
 
+#[cfg(feature = "druid")]
 use druid::Data;
 use serde::de::{Deserializer, SeqAccess, Visitor};
 use serde::ser::{SerializeSeq, Serializer};
@@ -12,7 +13,8 @@ use crate::time::Diff;
 /// A fade effect.
 ///
 /// When a segment is finished, it will start fading out.
-#[derive(Clone, Data, Debug, Eq, Serialize, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Eq, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "druid", derive(Data))]
 pub struct FadeEffect {
     /// After the segment finishes, it will remain at full opacity for this duration.
     /// Then it will start fading out.
@@ -24,13 +26,15 @@ pub struct FadeEffect {
 
 // TODO: how do we deserialize an "open" enum? We'd like to be able to read files
 // with unrecognized effects.
-#[derive(Clone, Data, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "druid", derive(Data))]
 pub enum Effect {
     Fade(FadeEffect),
 }
 
 /// A collection of effects.
-#[derive(Clone, Data, Debug, Default, Eq, PartialEq)]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "druid", derive(Data))]
 pub struct Effects {
     fade: Option<FadeEffect>,
 }
@@ -0,0 +1,292 @@
+//! Fitting cubic Bézier curves to recorded polylines.
+//!
+//! The raw input from the mouse (or tablet) consists of lots of closely-spaced points. We first
+//! reduce them using the Ramer-Douglas-Peucker algorithm (see [`simplify`](crate::simplify)) and
+//! then fit a small number of cubic segments through what's left, using the algorithm from
+//! Philip Schneider's "An Algorithm for Automatically Fitting Digitized Curves" (Graphics Gems,
+//! 1990).
+
+use kurbo::{BezPath, CubicBez, ParamCurve, Point, Vec2};
+
+/// Fits a curve consisting of cubic segments to a polyline.
+///
+/// Returns the fitted path, together with the indices (into `points`) of the endpoints of all of
+/// the path's elements. That is, the returned path starts with a `MoveTo` to
+/// `points[indices[0]]`, and its `i`th `CurveTo` ends at `points[indices[i]]`. In particular, the
+/// returned vector has the same length as the path, which makes it easy to preserve timing
+/// information.
+///
+/// `distance_threshold` controls both the initial simplification and the accuracy of the fitted
+/// curve: the fitted curve will pass within roughly `distance_threshold` of all the points that
+/// survived simplification. Points at which the polyline turns through an angle sharper than
+/// `angle_threshold` radians (measured as in [`smooth`](crate::smooth::smooth)) are preserved as
+/// corners.
+pub fn fit(
+    points: &[Point],
+    distance_threshold: f64,
+    angle_threshold: f64,
+) -> (BezPath, Vec<usize>) {
+    let mut path = BezPath::new();
+    let mut indices = Vec::new();
+    if points.is_empty() {
+        return (path, indices);
+    }
+
+    let simplified = crate::simplify::simplify(points, distance_threshold);
+    let pts: Vec<Point> = simplified.iter().map(|&i| points[i]).collect();
+    path.move_to(pts[0]);
+    indices.push(simplified[0]);
+    if pts.len() == 1 {
+        return (path, indices);
+    }
+
+    // Split the simplified polyline at the corners, and fit each of the smooth pieces separately.
+    let mut corners = vec![0];
+    for i in 1..(pts.len() - 1) {
+        let d_prev = pts[i - 1] - pts[i];
+        let d_next = pts[i + 1] - pts[i];
+        let angle = (d_prev.dot(d_next) / (d_prev.hypot() * d_next.hypot())).acos();
+        if angle < angle_threshold {
+            corners.push(i);
+        }
+    }
+    corners.push(pts.len() - 1);
+
+    let mut cubics = Vec::new();
+    for w in corners.windows(2) {
+        let (first, last) = (w[0], w[1]);
+        let first_tangent = unit(pts[first + 1] - pts[first]);
+        let last_tangent = unit(pts[last - 1] - pts[last]);
+        fit_range(
+            &pts,
+            first,
+            last,
+            first_tangent,
+            last_tangent,
+            distance_threshold * distance_threshold,
+            &mut cubics,
+        );
+    }
+
+    for (c, end_idx) in cubics {
+        path.curve_to(c.p1, c.p2, c.p3);
+        indices.push(simplified[end_idx]);
+    }
+    (path, indices)
+}
+
+fn unit(v: Vec2) -> Vec2 {
+    let len = v.hypot();
+    if len < 1e-12 {
+        Vec2::new(0.0, 0.0)
+    } else {
+        v / len
+    }
+}
+
+// Fits cubics to `pts[first..=last]`, pushing them (along with the index of their last point)
+// onto `out`. The tangents point "inwards", i.e., `first_tangent` points from `pts[first]` towards
+// the rest of the curve and `last_tangent` points from `pts[last]` backwards.
+fn fit_range(
+    pts: &[Point],
+    first: usize,
+    last: usize,
+    first_tangent: Vec2,
+    last_tangent: Vec2,
+    sq_tolerance: f64,
+    out: &mut Vec<(CubicBez, usize)>,
+) {
+    let p0 = pts[first];
+    let p3 = pts[last];
+    if last - first == 1 {
+        let dist = p0.distance(p3) / 3.0;
+        let c = CubicBez::new(p0, p0 + first_tangent * dist, p3 + last_tangent * dist, p3);
+        out.push((c, last));
+        return;
+    }
+
+    let range = &pts[first..=last];
+    let params = chord_length_params(range);
+    let c = generate_bezier(range, &params, first_tangent, last_tangent);
+    let (max_sq_err, split) = max_error(range, &params, &c);
+    if max_sq_err <= sq_tolerance {
+        out.push((c, last));
+        return;
+    }
+
+    // The fit wasn't good enough, so split at the point of maximum error and try again on
+    // both halves.
+    let split = first + split;
+    let mut center_tangent = unit(pts[split - 1] - pts[split + 1]);
+    if center_tangent.hypot() == 0.0 {
+        center_tangent = unit(pts[split - 1] - pts[split]);
+    }
+    fit_range(
+        pts,
+        first,
+        split,
+        first_tangent,
+        center_tangent,
+        sq_tolerance,
+        out,
+    );
+    fit_range(
+        pts,
+        split,
+        last,
+        -center_tangent,
+        last_tangent,
+        sq_tolerance,
+        out,
+    );
+}
+
+// Assigns a parameter in [0, 1] to each point, proportional to the distance along the polyline.
+fn chord_length_params(pts: &[Point]) -> Vec<f64> {
+    let mut ret = Vec::with_capacity(pts.len());
+    let mut total = 0.0;
+    ret.push(0.0);
+    for w in pts.windows(2) {
+        total += w[0].distance(w[1]);
+        ret.push(total);
+    }
+    if total > 0.0 {
+        for u in &mut ret {
+            *u /= total;
+        }
+    }
+    ret
+}
+
+// Finds the least-squares cubic with the given endpoints and tangent directions.
+fn generate_bezier(pts: &[Point], params: &[f64], t1: Vec2, t2: Vec2) -> CubicBez {
+    let p0 = pts[0];
+    let p3 = pts[pts.len() - 1];
+
+    let mut c = [[0.0, 0.0], [0.0, 0.0]];
+    let mut x = [0.0, 0.0];
+    for (p, &u) in pts.iter().zip(params) {
+        let v = 1.0 - u;
+        let b0 = v * v * v;
+        let b1 = 3.0 * u * v * v;
+        let b2 = 3.0 * u * u * v;
+        let b3 = u * u * u;
+        let a1 = t1 * b1;
+        let a2 = t2 * b2;
+
+        c[0][0] += a1.dot(a1);
+        c[0][1] += a1.dot(a2);
+        c[1][1] += a2.dot(a2);
+
+        let tmp = p.to_vec2() - (p0.to_vec2() * (b0 + b1) + p3.to_vec2() * (b2 + b3));
+        x[0] += a1.dot(tmp);
+        x[1] += a2.dot(tmp);
+    }
+    c[1][0] = c[0][1];
+
+    let det = c[0][0] * c[1][1] - c[0][1] * c[1][0];
+    let seg_len = p0.distance(p3);
+    let (alpha1, alpha2) = if det.abs() > 1e-12 {
+        (
+            (x[0] * c[1][1] - x[1] * c[0][1]) / det,
+            (c[0][0] * x[1] - c[1][0] * x[0]) / det,
+        )
+    } else {
+        (0.0, 0.0)
+    };
+
+    // If the least-squares solution is degenerate (or points the wrong way), fall back to the
+    // heuristic of putting the control points at a third of the chord length.
+    let eps = 1e-6 * seg_len;
+    let (alpha1, alpha2) = if alpha1 < eps || alpha2 < eps {
+        (seg_len / 3.0, seg_len / 3.0)
+    } else {
+        (alpha1, alpha2)
+    };
+
+    CubicBez::new(p0, p0 + t1 * alpha1, p3 + t2 * alpha2, p3)
+}
+
+// Returns the maximum squared distance between a point and its image on the fitted curve,
+// together with the index of the point achieving that maximum. The endpoints are not considered,
+// since they are interpolated exactly.
+fn max_error(pts: &[Point], params: &[f64], c: &CubicBez) -> (f64, usize) {
+    let mut max_sq_err = 0.0;
+    let mut split = pts.len() / 2;
+    for i in 1..(pts.len() - 1) {
+        let d = c.eval(params[i]) - pts[i];
+        let sq_err = d.dot(d);
+        if sq_err > max_sq_err {
+            max_sq_err = sq_err;
+            split = i;
+        }
+    }
+    (max_sq_err, split)
+}
+
+#[cfg(test)]
+mod tests {
+    use kurbo::PathEl;
+
+    use super::*;
+
+    #[test]
+    fn straight_line() {
+        let points: Vec<Point> = (0..100)
+            .map(|i| Point::new(i as f64 / 100.0, 0.0))
+            .collect();
+        let (path, indices) = fit(&points, 0.001, std::f64::consts::PI / 4.0);
+        assert_eq!(path.elements().len(), 2);
+        assert_eq!(indices, vec![0, 99]);
+    }
+
+    #[test]
+    fn circular_arc() {
+        let points: Vec<Point> = (0..=200)
+            .map(|i| {
+                let theta = i as f64 / 200.0 * std::f64::consts::PI;
+                Point::new(theta.cos(), theta.sin())
+            })
+            .collect();
+        let tol = 0.001;
+        let (path, indices) = fit(&points, tol, std::f64::consts::PI / 4.0);
+
+        assert_eq!(path.elements().len(), indices.len());
+        assert_eq!(indices[0], 0);
+        assert_eq!(*indices.last().unwrap(), 200);
+        // A half circle needs a handful of cubics, but many fewer than the number of points.
+        assert!(path.elements().len() < 20);
+
+        // The endpoints of the segments should be exactly the original points.
+        for (el, &idx) in path.elements().iter().zip(&indices) {
+            let p = match el {
+                PathEl::MoveTo(p) => *p,
+                PathEl::CurveTo(_, _, p) => *p,
+                _ => panic!("unexpected element"),
+            };
+            assert_eq!(p, points[idx]);
+        }
+    }
+
+    #[test]
+    fn corner() {
+        let mut points: Vec<Point> = (0..50).map(|i| Point::new(i as f64 / 50.0, 0.0)).collect();
+        points.extend((0..50).map(|i| Point::new(1.0, i as f64 / 50.0)));
+        let (_, indices) = fit(&points, 0.001, std::f64::consts::PI / 4.0);
+        // The corner at (1, 0) should be preserved as a knot.
+        assert!(indices
+            .iter()
+            .any(|&i| points[i].x == 1.0 && points[i].y == 0.0));
+    }
+
+    #[test]
+    fn tiny_inputs() {
+        let (path, indices) = fit(&[], 0.001, 1.0);
+        assert!(path.elements().is_empty());
+        assert!(indices.is_empty());
+
+        let (path, indices) = fit(&[Point::new(1.0, 1.0)], 0.001, 1.0);
+        assert_eq!(path.elements().len(), 1);
+        assert_eq!(indices, vec![0]);
+    }
+}
@@ -32,6 +32,16 @@ impl Lerp {
         *self.lerped_values.last().unwrap()
     }
 
+    /// The first time in the snippet's local (i.e., un-lerped) coordinates.
+    pub fn original_first(&self) -> Time {
+        *self.original_values.first().unwrap()
+    }
+
+    /// The last time in the snippet's local (i.e., un-lerped) coordinates.
+    pub fn original_last(&self) -> Time {
+        *self.original_values.last().unwrap()
+    }
+
     pub fn times(&self) -> &[Time] {
         &self.lerped_values
     }
@@ -114,6 +124,29 @@ impl Lerp {
         ret.add_lerp(time_from, time_to);
         ret
     }
+
+    /// Returns a new lerp that runs `factor` times as fast as this one, but starts at the
+    /// same time.
+    pub fn with_speed_factor(&self, factor: f64) -> Lerp {
+        assert!(factor > 0.0);
+        let start = self.first();
+        let lerped = self
+            .lerped_values
+            .iter()
+            .map(|&t| start + scale_diff(t - start, 1.0 / factor))
+            .collect();
+        Lerp::new(self.original_values.clone(), lerped)
+    }
+
+    /// Returns a new lerp that is the same as this one, but `by` later.
+    pub fn shifted(&self, by: Diff) -> Lerp {
+        let lerped = self.lerped_values.iter().map(|&t| t + by).collect();
+        Lerp::new(self.original_values.clone(), lerped)
+    }
+}
+
+fn scale_diff(d: Diff, factor: f64) -> Diff {
+    Diff::from_micros((d.as_micros() as f64 * factor).round() as i64)
 }
 
 enum LerpResult {
@@ -230,6 +263,18 @@ mod tests {
         assert_eq!(out.lerped_values, tvec![0, 150, 200]);
     }
 
+    #[test]
+    fn speed_factor() {
+        let lerp = Lerp::new(tvec![0, 50, 100], tvec![100, 200, 300]);
+
+        let out = lerp.with_speed_factor(2.0);
+        assert_eq!(out.original_values, tvec![0, 50, 100]);
+        assert_eq!(out.lerped_values, tvec![100, 150, 200]);
+
+        let out = lerp.with_speed_factor(0.5);
+        assert_eq!(out.lerped_values, tvec![100, 300, 500]);
+    }
+
     #[test]
     fn unlerp() {
         let lerp = Lerp::new(tvec![1, 101], tvec![201, 301]);
@@ -1,12 +1,16 @@
-use druid::kurbo::PathEl;
-use druid::{Data, RenderContext};
+#[cfg(feature = "druid")]
+use druid::Data;
+use kurbo::{Affine, BezPath, PathEl, Point};
+use piet::{Color, RenderContext};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::collections::BTreeMap;
 use std::sync::Arc;
 
 pub mod curve;
 pub mod effect;
+pub mod fit;
 pub mod lerp;
+pub mod pen;
 pub mod simplify;
 pub mod smooth;
 pub mod span_cursor;
@@ -15,14 +19,17 @@ pub mod time;
 pub use crate::curve::{Curve, LineStyle, SegmentData};
 pub use crate::effect::{Effect, Effects, FadeEffect};
 pub use crate::lerp::Lerp;
+pub use crate::pen::PenPath;
 pub use crate::time::{Diff, Time};
 
 /// Snippets are identified by unique ids.
-#[derive(Deserialize, Serialize, Clone, Copy, Data, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "druid", derive(Data))]
 #[serde(transparent)]
 pub struct SnippetId(u64);
 
-#[derive(Deserialize, Serialize, Data, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[cfg_attr(feature = "druid", derive(Data))]
 pub struct SnippetData {
     pub curve: Arc<Curve>,
     pub lerp: Arc<Lerp>,
@@ -30,9 +37,19 @@ pub struct SnippetData {
     /// Controls whether the snippet ever ends. If `None`, it means that the snippet will remain
     /// forever; if `Some(t)` it means that the snippet will disappear at time `t`.
     pub end: Option<Time>,
+
+    /// If true, the snippet plays backwards: it starts out fully drawn, and then un-draws itself.
+    #[serde(default)]
+    pub reversed: bool,
+
+    /// The path that the pen took while this snippet was being recorded. This might be empty
+    /// (for example, in files that were saved before we recorded it).
+    #[serde(default)]
+    pub pen: Arc<PenPath>,
 }
 
-#[derive(Clone, Data, Default)]
+#[derive(Clone, Default)]
+#[cfg_attr(feature = "druid", derive(Data))]
 pub struct SnippetsData {
     last_id: u64,
     snippets: Arc<BTreeMap<SnippetId, SnippetData>>,
@@ -44,12 +61,59 @@ impl SnippetData {
     // TODO: this panics if the curve is empty
     pub fn new(curve: Curve) -> SnippetData {
         let start = *curve.times.first().unwrap();
-        let end = *curve.times.last().unwrap();
+        // Segments can overlap in time, so the last time isn't necessarily the latest one.
+        let end = *curve.times.iter().max().unwrap();
         let lerp = Lerp::identity(start, end);
         SnippetData {
             curve: Arc::new(curve),
             lerp: Arc::new(lerp),
             end: None,
+            reversed: false,
+            pen: Arc::new(PenPath::new()),
+        }
+    }
+
+    // Are these two snippets the same? Like `druid::Data::same`, this compares the curves by
+    // pointer, because comparing them by value would be slow.
+    fn is_same(&self, other: &SnippetData) -> bool {
+        Arc::ptr_eq(&self.curve, &other.curve)
+            && Arc::ptr_eq(&self.lerp, &other.lerp)
+            && self.end == other.end
+            && self.reversed == other.reversed
+            && Arc::ptr_eq(&self.pen, &other.pen)
+    }
+
+    pub fn with_pen_path(mut self, pen: PenPath) -> SnippetData {
+        self.pen = Arc::new(pen);
+        self
+    }
+
+    /// Returns a copy of this snippet that starts (and ends) `by` later.
+    pub fn shifted(&self, by: Diff) -> SnippetData {
+        let mut ret = self.clone();
+        ret.lerp = Arc::new(self.lerp.shifted(by));
+        ret.end = self.end.map(|end| end + by);
+        ret
+    }
+
+    /// The position of the pen at the given time, if this snippet was being recorded then.
+    pub fn pen_position_at(&self, time: Time) -> Option<Point> {
+        // The pen path doesn't make much sense for a snippet that's being un-drawn.
+        if self.reversed || !self.visible_at(time) {
+            return None;
+        }
+        self.pen.position_at(self.lerp.unlerp_extended(time))
+    }
+
+    // Converts a time in the curve's local time coordinates to the time that we should actually
+    // draw, taking into account whether the snippet is reversed.
+    fn directed_local_time(&self, local_time: Time) -> Time {
+        if self.reversed {
+            let first = self.lerp.original_first();
+            let last = self.lerp.original_last();
+            first + (last - local_time)
+        } else {
+            local_time
         }
     }
 
@@ -61,41 +125,79 @@ impl SnippetData {
         }
     }
 
-    pub fn path_at(&self, time: Time) -> &[PathEl] {
+    /// Returns true if this snippet looks the same at `time` as it does at every later time, until
+    /// it disappears: it's finished drawing, and it's finished fading. Reversed snippets don't
+    /// count, because they're busy un-drawing themselves.
+    pub fn is_settled_at(&self, time: Time) -> bool {
+        !self.reversed
+            && self.visible_at(time)
+            && time >= self.last_draw_time() + self.curve.fade_duration()
+    }
+
+    /// The part of the path that has been drawn by `time`.
+    ///
+    /// The segments of a curve can overlap in time (when drawing in mirror mode, say), so each
+    /// segment gets cut off separately.
+    pub fn path_at(&self, time: Time) -> BezPath {
+        let mut ret = BezPath::new();
         if !self.visible_at(time) {
-            return &[];
+            return ret;
         }
 
-        // TODO: maybe there can be a better API that just gets idx directly?
-        let local_time = self.lerp.unlerp_clamped(time);
-        let idx = match self.curve.times.binary_search(&local_time) {
-            Ok(i) => i + 1,
-            Err(i) => i,
-        };
-        &self.curve.path.elements()[..idx]
+        let local_time = self.directed_local_time(self.lerp.unlerp_clamped(time));
+        for seg in self.curve.segments() {
+            let idx = match seg.times.binary_search(&local_time) {
+                Ok(i) => i + 1,
+                Err(i) => i,
+            };
+            for el in &seg.elements[..idx] {
+                ret.push(*el);
+            }
+        }
+        ret
     }
 
-    pub fn path_between(&self, start: Time, end: Time) -> &[PathEl] {
+    /// The parts of the path that were drawn between `start` and `end`.
+    pub fn path_between(&self, start: Time, end: Time) -> BezPath {
+        let mut ret = BezPath::new();
         if let Some(my_end) = self.end {
             if start > my_end {
-                return &[];
+                return ret;
             }
         }
         if end < self.start_time() {
-            return &[];
+            return ret;
         }
 
-        let local_start = self.lerp.unlerp_clamped(start);
-        let local_end = self.lerp.unlerp_clamped(end);
-        let start_idx = match self.curve.times.binary_search(&local_start) {
-            Ok(i) => i,
-            Err(i) => i,
-        };
-        let end_idx = match self.curve.times.binary_search(&local_end) {
-            Ok(i) => i + 1,
-            Err(i) => i,
-        };
-        &self.curve.path.elements()[start_idx..end_idx]
+        let mut local_start = self.directed_local_time(self.lerp.unlerp_clamped(start));
+        let mut local_end = self.directed_local_time(self.lerp.unlerp_clamped(end));
+        if self.reversed {
+            std::mem::swap(&mut local_start, &mut local_end);
+        }
+        for seg in self.curve.segments() {
+            let start_idx = match seg.times.binary_search(&local_start) {
+                Ok(i) => i,
+                Err(i) => i,
+            };
+            let end_idx = match seg.times.binary_search(&local_end) {
+                Ok(i) => i + 1,
+                Err(i) => i,
+            };
+            if start_idx >= end_idx {
+                continue;
+            }
+            // If we start in the middle of the segment, start from where the previous element
+            // ended.
+            if start_idx > 0 {
+                if let Some(p) = end_point(&seg.elements[start_idx - 1]) {
+                    ret.move_to(p);
+                }
+            }
+            for el in &seg.elements[start_idx..end_idx] {
+                ret.push(*el);
+            }
+        }
+        ret
     }
 
     pub fn start_time(&self) -> Time {
@@ -116,11 +218,21 @@ impl SnippetData {
         if !self.visible_at(time) {
             return;
         }
-        let local_time = self.lerp.unlerp_extended(time);
+        let local_time = self.directed_local_time(self.lerp.unlerp_extended(time));
         self.curve.render(ctx, local_time);
     }
 }
 
+// Where the pen is after drawing this element.
+fn end_point(el: &PathEl) -> Option<Point> {
+    match *el {
+        PathEl::MoveTo(p) | PathEl::LineTo(p) | PathEl::QuadTo(_, p) | PathEl::CurveTo(_, _, p) => {
+            Some(p)
+        }
+        PathEl::ClosePath => None,
+    }
+}
+
 impl SnippetsData {
     pub fn with_new_snippet(&self, snip: SnippetData) -> (SnippetsData, SnippetId) {
         let mut ret = self.clone();
@@ -149,12 +261,55 @@ impl SnippetsData {
         ret
     }
 
+    /// Removes every snippet except the one with the given id.
+    pub fn with_only_snippet(&self, id: SnippetId) -> SnippetsData {
+        let mut ret = self.clone();
+        let mut map = BTreeMap::new();
+        map.insert(id, self.snippet(id).clone());
+        ret.snippets = Arc::new(map);
+        ret
+    }
+
     pub fn with_new_lerp(&self, id: SnippetId, lerp_from: Time, lerp_to: Time) -> SnippetsData {
         let mut snip = self.snippet(id).clone();
         snip.lerp = Arc::new(snip.lerp.with_new_lerp(lerp_from, lerp_to));
         self.with_replacement_snippet(id, snip)
     }
 
+    pub fn with_recolored_snippet(&self, id: SnippetId, color: &Color) -> SnippetsData {
+        let mut snip = self.snippet(id).clone();
+        snip.curve = Arc::new(snip.curve.with_color(color));
+        self.with_replacement_snippet(id, snip)
+    }
+
+    /// Moves (or scales, or otherwise transforms) all of the strokes in the snippet with the
+    /// given id.
+    pub fn with_transformed_snippet(&self, id: SnippetId, transform: Affine) -> SnippetsData {
+        let mut snip = self.snippet(id).clone();
+        snip.curve = Arc::new(snip.curve.transformed(transform));
+        snip.pen = Arc::new(snip.pen.transformed(transform));
+        self.with_replacement_snippet(id, snip)
+    }
+
+    /// Speeds up (or slows down, if `factor < 1.0`) the snippet with the given id, keeping its
+    /// starting time fixed.
+    pub fn with_speed_factor(&self, id: SnippetId, factor: f64) -> SnippetsData {
+        let mut snip = self.snippet(id).clone();
+        let start = snip.start_time();
+        snip.lerp = Arc::new(snip.lerp.with_speed_factor(factor));
+        snip.end = snip.end.map(|end| {
+            start + Diff::from_micros(((end - start).as_micros() as f64 / factor).round() as i64)
+        });
+        self.with_replacement_snippet(id, snip)
+    }
+
+    /// Toggles whether the snippet with the given id plays backwards.
+    pub fn with_reversed_snippet(&self, id: SnippetId) -> SnippetsData {
+        let mut snip = self.snippet(id).clone();
+        snip.reversed = !snip.reversed;
+        self.with_replacement_snippet(id, snip)
+    }
+
     pub fn with_truncated_snippet(&self, id: SnippetId, time: Time) -> SnippetsData {
         let mut snip = self.snippet(id).clone();
         snip.end = Some(time);
@@ -165,10 +320,64 @@ impl SnippetsData {
         self.snippets.get(&id).unwrap()
     }
 
+    /// Returns true if there's a snippet with the id `id`.
+    pub fn contains(&self, id: SnippetId) -> bool {
+        self.snippets.contains_key(&id)
+    }
+
+    /// Returns the snippets that were added or changed since `old`, together with the ids of the
+    /// snippets that were removed.
+    pub fn changes_since(
+        &self,
+        old: &SnippetsData,
+    ) -> (Vec<(SnippetId, SnippetData)>, Vec<SnippetId>) {
+        let changed = self
+            .snippets
+            .iter()
+            .filter(|(id, snip)| old.snippets.get(id).map_or(true, |o| !o.is_same(snip)))
+            .map(|(id, snip)| (*id, snip.clone()))
+            .collect();
+        let removed = old
+            .snippets
+            .keys()
+            .filter(|id| !self.snippets.contains_key(id))
+            .cloned()
+            .collect();
+        (changed, removed)
+    }
+
+    /// Applies changes of the kind returned by `changes_since`.
+    pub fn with_changes(
+        &self,
+        changed: Vec<(SnippetId, SnippetData)>,
+        removed: &[SnippetId],
+    ) -> SnippetsData {
+        let mut ret = self.clone();
+        let mut map = (*ret.snippets).clone();
+        for id in removed {
+            map.remove(id);
+        }
+        for (id, snip) in changed {
+            ret.last_id = ret.last_id.max(id.0);
+            map.insert(id, snip);
+        }
+        ret.snippets = Arc::new(map);
+        ret
+    }
+
     pub fn snippets(&self) -> impl Iterator<Item = (SnippetId, &SnippetData)> {
         self.snippets.iter().map(|(k, v)| (*k, v))
     }
 
+    /// The times at which snippets start, stop drawing, or disappear, together with their lerp
+    /// points, in no particular order.
+    pub fn boundaries(&self) -> impl Iterator<Item = Time> + '_ {
+        // The lerp times include the start and the last draw time.
+        self.snippets
+            .values()
+            .flat_map(|snip| snip.lerp.times().iter().copied().chain(snip.end_time()))
+    }
+
     pub fn last_draw_time(&self) -> Time {
         self.snippets
             .values()
@@ -216,6 +425,16 @@ impl SnippetsData {
             snip.render(ctx, new_time);
         }
     }
+
+    /// Draws the position of the pen at the given time (if any snippet was being recorded at
+    /// that time).
+    pub fn render_pen(&self, ctx: &mut impl RenderContext, time: Time) {
+        for snip in self.snippets.values() {
+            if let Some(p) = snip.pen_position_at(time) {
+                pen::render_pen(ctx, p);
+            }
+        }
+    }
 }
 
 // The serialization of SnippetsData is part of our save file format, and so it needs
@@ -236,3 +455,136 @@ impl<'de> Deserialize<'de> for SnippetsData {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use kurbo::{Point, Rect, Shape};
+
+    use super::*;
+
+    #[test]
+    fn reversed_path() {
+        let style = LineStyle {
+            color: Color::WHITE,
+            thickness: 1.0,
+        };
+        let mut curve = Curve::new();
+        curve.move_to(
+            Point::new(0.0, 0.0),
+            Time::from_micros(0),
+            style,
+            Effects::default(),
+        );
+        curve.line_to(Point::new(1.0, 0.0), Time::from_micros(10));
+        curve.line_to(Point::new(2.0, 0.0), Time::from_micros(20));
+        let snip = SnippetData::new(curve);
+        assert_eq!(snip.path_at(Time::from_micros(0)).elements().len(), 1);
+        assert_eq!(snip.path_at(Time::from_micros(20)).elements().len(), 3);
+
+        let (snips, id) = SnippetsData::default().with_new_snippet(snip);
+        let snips = snips.with_reversed_snippet(id);
+        let snip = snips.snippet(id);
+        assert_eq!(snip.path_at(Time::from_micros(0)).elements().len(), 3);
+        assert_eq!(snip.path_at(Time::from_micros(10)).elements().len(), 2);
+        assert_eq!(snip.path_at(Time::from_micros(20)).elements().len(), 1);
+        // The path starts in the middle of the curve, so it gets a `MoveTo` at the front.
+        let between = snip.path_between(Time::from_micros(0), Time::from_micros(10));
+        assert_eq!(between.elements().len(), 3);
+        assert_eq!(between.elements()[0], PathEl::MoveTo(Point::new(0.0, 0.0)));
+    }
+
+    #[test]
+    fn overlapping_path() {
+        // Two mirrored segments that are drawn at the same time.
+        let style = LineStyle {
+            color: Color::WHITE,
+            thickness: 1.0,
+        };
+        let times = vec![
+            Time::from_micros(0),
+            Time::from_micros(10),
+            Time::from_micros(20),
+        ];
+        let mut curve = Curve::new();
+        for &dir in &[1.0, -1.0] {
+            let mut path = BezPath::new();
+            path.move_to((0.0, 0.0));
+            path.line_to((dir, 0.0));
+            path.line_to((2.0 * dir, 0.0));
+            curve.append_segment(path, times.clone(), style.clone().into());
+        }
+        let snip = SnippetData::new(curve);
+
+        let path = snip.path_at(Time::from_micros(10));
+        assert_eq!(path.elements().len(), 4);
+        assert_eq!(path.bounding_box(), Rect::new(-1.0, 0.0, 1.0, 0.0));
+        assert_eq!(snip.path_at(Time::from_micros(20)).elements().len(), 6);
+
+        let path = snip.path_between(Time::from_micros(10), Time::from_micros(20));
+        assert_eq!(path.elements().len(), 6);
+        assert_eq!(path.bounding_box(), Rect::new(-2.0, 0.0, 2.0, 0.0));
+    }
+
+    #[test]
+    fn settled() {
+        let style = LineStyle {
+            color: Color::WHITE,
+            thickness: 1.0,
+        };
+        let mut effects = Effects::default();
+        effects.add(Effect::Fade(FadeEffect {
+            pause: Diff::from_micros(5),
+            fade: Diff::from_micros(5),
+        }));
+        let mut curve = Curve::new();
+        curve.move_to(Point::new(0.0, 0.0), Time::from_micros(0), style, effects);
+        curve.line_to(Point::new(1.0, 0.0), Time::from_micros(10));
+        let mut snip = SnippetData::new(curve);
+        assert!(!snip.is_settled_at(Time::from_micros(10)));
+        assert!(!snip.is_settled_at(Time::from_micros(19)));
+        assert!(snip.is_settled_at(Time::from_micros(20)));
+
+        snip.end = Some(Time::from_micros(30));
+        assert!(snip.is_settled_at(Time::from_micros(30)));
+        assert!(!snip.is_settled_at(Time::from_micros(31)));
+
+        snip.reversed = true;
+        assert!(!snip.is_settled_at(Time::from_micros(25)));
+    }
+
+    #[test]
+    fn changes() {
+        let style = LineStyle {
+            color: Color::WHITE,
+            thickness: 1.0,
+        };
+        let mut curve = Curve::new();
+        curve.move_to(
+            Point::new(0.0, 0.0),
+            Time::from_micros(0),
+            style,
+            Effects::default(),
+        );
+        curve.line_to(Point::new(1.0, 0.0), Time::from_micros(10));
+        let snip = SnippetData::new(curve);
+
+        let (old, id1) = SnippetsData::default().with_new_snippet(snip.clone());
+        let (old, id2) = old.with_new_snippet(snip.clone());
+        let (new, id3) = old.without_snippet(id1).with_new_snippet(snip);
+        let new = new.with_reversed_snippet(id2);
+
+        let (changed, removed) = new.changes_since(&old);
+        assert_eq!(
+            changed.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            vec![id2, id3]
+        );
+        assert_eq!(removed, vec![id1]);
+
+        let applied = old.with_changes(changed, &removed);
+        assert_eq!(applied.last_id, new.last_id);
+        let ids = |s: &SnippetsData| s.snippets().map(|(id, _)| id).collect::<Vec<_>>();
+        assert_eq!(ids(&applied), ids(&new));
+        assert!(applied.snippet(id2).reversed);
+        assert!(new.changes_since(&applied).0.is_empty());
+    }
+}
@@ -0,0 +1,162 @@
+//! Recording the position of the pen, including when it isn't touching the paper.
+
+use kurbo::{Affine, Circle, Point};
+use piet::{Color, RenderContext};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::time::Time;
+
+/// The radius of the dot that we draw to show the pen position, in image coordinates.
+const PEN_RADIUS: f64 = 0.005;
+// This should be visible on both light and dark backgrounds.
+const PEN_COLOR: Color = Color::rgba8(0x88, 0x88, 0x88, 0xaa);
+
+/// The path that the pen took while a snippet was being recorded. Unlike a [`Curve`], this
+/// includes the parts in between strokes, so that the viewer can see where the presenter is
+/// about to draw.
+///
+/// [`Curve`]: crate::curve::Curve
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PenPath {
+    points: Vec<Point>,
+    times: Vec<Time>,
+}
+
+impl PenPath {
+    pub fn new() -> PenPath {
+        PenPath::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.times.is_empty()
+    }
+
+    /// Records the pen position `p` at time `t`. If there are already recorded positions after
+    /// time `t` (which can happen if the user undoes part of a recording), they are discarded.
+    pub fn push(&mut self, p: Point, t: Time) {
+        let idx = match self.times.binary_search(&t) {
+            Ok(i) => i + 1,
+            Err(i) => i,
+        };
+        self.points.truncate(idx);
+        self.times.truncate(idx);
+        self.points.push(p);
+        self.times.push(t);
+    }
+
+    /// Returns the position of the pen at time `t`, or `None` if `t` is outside the range of
+    /// recorded times.
+    pub fn position_at(&self, t: Time) -> Option<Point> {
+        let (&first, &last) = (self.times.first()?, self.times.last()?);
+        if t < first || t > last {
+            return None;
+        }
+        let idx = match self.times.binary_search(&t) {
+            Ok(i) => return Some(self.points[i]),
+            Err(i) => i,
+        };
+
+        // We already checked that `t` is strictly between the first and last times.
+        let (prev_t, next_t) = (self.times[idx - 1], self.times[idx]);
+        let ratio = (t - prev_t).as_micros() as f64 / (next_t - prev_t).as_micros() as f64;
+        Some(self.points[idx - 1].lerp(self.points[idx], ratio))
+    }
+
+    pub fn transformed(&self, transform: Affine) -> PenPath {
+        PenPath {
+            points: self.points.iter().map(|&p| transform * p).collect(),
+            times: self.times.clone(),
+        }
+    }
+}
+
+/// Draws a dot representing the pen, at the position `p` (in image coordinates).
+pub fn render_pen(ctx: &mut impl RenderContext, p: Point) {
+    ctx.fill(Circle::new(p, PEN_RADIUS), &PEN_COLOR);
+}
+
+// Points are stored in the same fixed-point format that we use for curves.
+#[derive(Deserialize, Serialize)]
+struct SavedPenPath {
+    points: Vec<(i32, i32)>,
+    times: Vec<Time>,
+}
+
+impl Serialize for PenPath {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        let coord = |x: f64| {
+            (x * 10_000.0)
+                .round()
+                .max(i32::MIN as f64)
+                .min(i32::MAX as f64) as i32
+        };
+        SavedPenPath {
+            points: self
+                .points
+                .iter()
+                .map(|p| (coord(p.x), coord(p.y)))
+                .collect(),
+            times: self.times.clone(),
+        }
+        .serialize(ser)
+    }
+}
+
+impl<'a> Deserialize<'a> for PenPath {
+    fn deserialize<D: Deserializer<'a>>(de: D) -> Result<PenPath, D::Error> {
+        let saved = SavedPenPath::deserialize(de)?;
+        if saved.points.len() != saved.times.len() {
+            return Err(serde::de::Error::custom(
+                "pen path has different numbers of points and times",
+            ));
+        }
+        Ok(PenPath {
+            points: saved
+                .points
+                .into_iter()
+                .map(|(x, y)| Point::new(x as f64 / 10_000.0, y as f64 / 10_000.0))
+                .collect(),
+            times: saved.times,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn position() {
+        let mut pen = PenPath::new();
+        assert_eq!(pen.position_at(Time::from_micros(0)), None);
+
+        pen.push(Point::new(0.0, 0.0), Time::from_micros(10));
+        pen.push(Point::new(1.0, 0.0), Time::from_micros(20));
+        pen.push(Point::new(1.0, 2.0), Time::from_micros(30));
+        assert_eq!(pen.position_at(Time::from_micros(5)), None);
+        assert_eq!(
+            pen.position_at(Time::from_micros(10)),
+            Some(Point::new(0.0, 0.0))
+        );
+        assert_eq!(
+            pen.position_at(Time::from_micros(15)),
+            Some(Point::new(0.5, 0.0))
+        );
+        assert_eq!(
+            pen.position_at(Time::from_micros(25)),
+            Some(Point::new(1.0, 1.0))
+        );
+        assert_eq!(pen.position_at(Time::from_micros(31)), None);
+    }
+
+    #[test]
+    fn serialize() {
+        let mut pen = PenPath::new();
+        pen.push(Point::new(0.25, 0.5), Time::from_micros(10));
+        pen.push(Point::new(1.0, 0.0), Time::from_micros(20));
+
+        let ser = serde_json::to_string(&pen).unwrap();
+        let de: PenPath = serde_json::from_str(&ser).unwrap();
+        assert_eq!(pen, de);
+    }
+}
@@ -1,4 +1,4 @@
-use druid::kurbo::{Line, Point};
+use kurbo::{Line, Point};
 
 // Squared distance from the point `p` to the line *segment* `line.
 fn sq_distance(p: Point, line: Line) -> f64 {
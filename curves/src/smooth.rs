@@ -1,4 +1,4 @@
-use druid::kurbo::{BezPath, Point};
+use kurbo::{BezPath, Point};
 
 /// Turns a polyline into a (mostly) smooth curve through the same points.
 /// The returned curve will consist only of cubic segments.
@@ -72,7 +72,7 @@ pub fn smooth(points: &[Point], tangent_factor: f64, angle_threshold: f64) -> Be
 
 #[cfg(test)]
 mod tests {
-    use druid::kurbo::PathSeg;
+    use kurbo::PathSeg;
 
     use super::*;
 
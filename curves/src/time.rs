@@ -1,3 +1,4 @@
+#[cfg(feature = "druid")]
 use druid::Data;
 use serde::{Deserialize, Serialize};
 use std::convert::TryFrom;
@@ -5,18 +6,21 @@ use std::convert::TryFrom;
 /// The clock of a scribble.
 // This is measured in microseconds from the beginning. We enforce that the value is non-negative,
 // but arithmetic is more convenient with signed types.
-#[derive(Copy, Clone, Data, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Deserialize, Serialize)]
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Deserialize, Serialize)]
+#[cfg_attr(feature = "druid", derive(Data))]
 #[serde(transparent)]
 pub struct Time(i64);
 
 /// The difference between two [`Time`]s. Unlike `std::time::Duration`, this
 /// can be negative.
-#[derive(Copy, Clone, Data, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Deserialize, Serialize)]
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Deserialize, Serialize)]
+#[cfg_attr(feature = "druid", derive(Data))]
 #[serde(transparent)]
 pub struct Diff(i64);
 
 /// An interval of times.
-#[derive(Copy, Clone, Data, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Deserialize, Serialize)]
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Deserialize, Serialize)]
+#[cfg_attr(feature = "druid", derive(Data))]
 pub struct TimeSpan {
     start: Time,
     end: Time,
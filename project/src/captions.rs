@@ -0,0 +1,168 @@
+//! Timed captions, which are stored in the project and can be exported as subtitles.
+
+#[cfg(feature = "druid")]
+use druid::Data;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use scribble_curves::{Diff, Time};
+
+/// No caption is shown for less time than this, when we get to decide how long it's shown.
+pub const MIN_CAPTION_DURATION: Diff = Diff::from_micros(1_000_000);
+
+/// A single caption, shown from `start` until (but not including) `end`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[cfg_attr(feature = "druid", derive(Data))]
+pub struct Caption {
+    pub start: Time,
+    pub end: Time,
+    pub text: String,
+}
+
+/// All the captions in a project, sorted by their start times.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[cfg_attr(feature = "druid", derive(Data))]
+#[serde(transparent)]
+pub struct CaptionsData {
+    captions: Arc<Vec<Caption>>,
+}
+
+impl CaptionsData {
+    pub fn new(mut captions: Vec<Caption>) -> CaptionsData {
+        captions.sort_by_key(|c| c.start);
+        CaptionsData {
+            captions: Arc::new(captions),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.captions.is_empty()
+    }
+
+    pub fn captions(&self) -> &[Caption] {
+        &self.captions
+    }
+
+    /// The caption that's showing at `time`, if there is one. If captions overlap, the one
+    /// that started most recently wins.
+    pub fn at(&self, time: Time) -> Option<&Caption> {
+        self.captions
+            .iter()
+            .rev()
+            .find(|c| c.start <= time && time < c.end)
+    }
+
+    /// Replaces the captions that overlap any of `spans` with `new`.
+    pub fn with_replaced(&self, spans: &[(Time, Time)], new: Vec<Caption>) -> CaptionsData {
+        let overlaps = |c: &Caption| spans.iter().any(|&(s, e)| c.start < e && s < c.end);
+        let kept = self.captions.iter().filter(|c| !overlaps(c)).cloned();
+        CaptionsData::new(kept.chain(new).collect())
+    }
+
+    /// Changes the text of the `idx`th caption.
+    pub fn set_text(&mut self, idx: usize, text: String) {
+        // Lenses put the text back even if it didn't change, and we don't want to unshare the
+        // captions for nothing.
+        if self.captions.get(idx).map_or(false, |c| c.text != text) {
+            Arc::make_mut(&mut self.captions)[idx].text = text;
+        }
+    }
+
+    /// Moves the start of the `idx`th caption to `time` (and its end too, if the caption would
+    /// otherwise end before it starts).
+    pub fn set_start(&mut self, idx: usize, time: Time) {
+        if idx < self.captions.len() {
+            let captions = Arc::make_mut(&mut self.captions);
+            captions[idx].start = time;
+            if captions[idx].end <= time {
+                captions[idx].end = time + MIN_CAPTION_DURATION;
+            }
+            captions.sort_by_key(|c| c.start);
+        }
+    }
+
+    /// Moves the end of the `idx`th caption to `time`, unless that's before it starts.
+    pub fn set_end(&mut self, idx: usize, time: Time) {
+        match self.captions.get(idx) {
+            Some(c) if c.start < time => Arc::make_mut(&mut self.captions)[idx].end = time,
+            Some(_) => log::warn!("a caption can't end before it starts"),
+            None => {}
+        }
+    }
+
+    /// Deletes the `idx`th caption.
+    pub fn remove(&mut self, idx: usize) {
+        if idx < self.captions.len() {
+            Arc::make_mut(&mut self.captions).remove(idx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn t(secs: f64) -> Time {
+        Time::from_micros((secs * 1e6).round() as i64)
+    }
+
+    fn caption(start: f64, end: f64, text: &str) -> Caption {
+        Caption {
+            start: t(start),
+            end: t(end),
+            text: text.to_owned(),
+        }
+    }
+
+    #[test]
+    fn caption_at() {
+        let captions = CaptionsData::new(vec![caption(1.0, 3.0, "A"), caption(2.0, 4.0, "B")]);
+        assert_eq!(captions.at(t(0.5)), None);
+        assert_eq!(captions.at(t(1.5)).unwrap().text, "A");
+        assert_eq!(captions.at(t(2.5)).unwrap().text, "B");
+        assert_eq!(captions.at(t(4.0)), None);
+    }
+
+    #[test]
+    fn edit() {
+        let mut captions = CaptionsData::new(vec![caption(1.0, 2.0, "A"), caption(3.0, 4.0, "B")]);
+        let before = captions.clone();
+        captions.set_text(0, "A".to_owned());
+        assert!(Arc::ptr_eq(&captions.captions, &before.captions));
+        captions.set_text(0, "C".to_owned());
+        assert_eq!(captions.captions()[0].text, "C");
+
+        // Moving the start past the end moves the end too, and keeps the captions sorted.
+        captions.set_start(0, t(5.0));
+        assert_eq!(
+            captions.captions(),
+            &[caption(3.0, 4.0, "B"), caption(5.0, 6.0, "C")][..]
+        );
+        captions.set_end(1, t(4.5));
+        assert_eq!(captions.captions()[1].end, t(6.0));
+        captions.set_end(1, t(7.0));
+        assert_eq!(captions.captions()[1].end, t(7.0));
+
+        captions.remove(0);
+        captions.remove(5);
+        assert_eq!(captions.captions(), &[caption(5.0, 7.0, "C")][..]);
+    }
+
+    #[test]
+    fn replace() {
+        let captions = CaptionsData::new(vec![
+            caption(1.0, 2.0, "A"),
+            caption(3.0, 4.0, "B"),
+            caption(5.0, 6.0, "C"),
+        ]);
+        let replaced = captions.with_replaced(&[(t(2.0), t(3.5))], vec![caption(2.5, 3.0, "D")]);
+        assert_eq!(
+            replaced.captions(),
+            &[
+                caption(1.0, 2.0, "A"),
+                caption(2.5, 3.0, "D"),
+                caption(5.0, 6.0, "C")
+            ][..]
+        );
+    }
+}
@@ -0,0 +1,419 @@
+//! Still images (e.g. pasted screenshots or imported photos) that appear on the canvas at a
+//! particular time.
+
+#[cfg(feature = "druid")]
+use druid::Data;
+use kurbo::Rect;
+use piet::{Color, ImageFormat, InterpolationMode, RenderContext};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+use scribble_curves::time::{Diff, Time};
+
+use crate::settings::DRAWING_WIDTH;
+
+/// Image snippets are identified by unique ids.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "druid", derive(Data))]
+#[serde(transparent)]
+pub struct ImageSnippetId(u64);
+
+// A decoded image, in (non-premultiplied) RGBA format.
+#[derive(Debug)]
+struct Pixels {
+    width: usize,
+    height: usize,
+    rgba: Vec<u8>,
+}
+
+/// An image that gets placed on the canvas at some time.
+///
+/// We keep around the original (encoded) image file, which is what gets saved. The decoded
+/// pixels are behind a pointer, so this is cheap to clone.
+#[derive(Clone, Deserialize, Serialize)]
+#[cfg_attr(feature = "druid", derive(Data))]
+#[serde(try_from = "SavedImageSnippet", into = "SavedImageSnippet")]
+pub struct ImageSnippetData {
+    encoded: Arc<Vec<u8>>,
+    pixels: Arc<Pixels>,
+
+    /// Where the image goes, in image coordinates.
+    pub rect: Rect,
+    pub start_time: Time,
+    /// The time at which the image disappears, if any.
+    pub end_time: Option<Time>,
+    /// How long the image takes to fade in. If this is zero, it appears immediately.
+    pub fade_in: Diff,
+}
+
+/// A collection of [`ImageSnippetData`], each one identified by an [`ImageSnippetId`].
+#[derive(Clone, Default)]
+#[cfg_attr(feature = "druid", derive(Data))]
+pub struct ImageSnippetsData {
+    last_id: u64,
+    snippets: Arc<BTreeMap<ImageSnippetId, ImageSnippetData>>,
+}
+
+fn decode(encoded: &[u8]) -> anyhow::Result<Pixels> {
+    let img = image::load_from_memory(encoded)?.to_rgba();
+    Ok(Pixels {
+        width: img.width() as usize,
+        height: img.height() as usize,
+        rgba: img.into_raw(),
+    })
+}
+
+impl ImageSnippetData {
+    /// Creates a new image snippet from the contents of an image file (in any format that the
+    /// `image` crate understands).
+    ///
+    /// The image will be centered in a canvas of height `drawing_height` (and width
+    /// `DRAWING_WIDTH`), and it will be scaled to take up at most half of the canvas in each
+    /// direction.
+    pub fn from_encoded(
+        encoded: Vec<u8>,
+        start_time: Time,
+        drawing_height: f64,
+    ) -> anyhow::Result<ImageSnippetData> {
+        let pixels = decode(&encoded)?;
+        if pixels.width == 0 || pixels.height == 0 {
+            return Err(anyhow::anyhow!("the image is empty"));
+        }
+
+        let drawing_width = DRAWING_WIDTH;
+        let scale = (drawing_width / 2.0 / pixels.width as f64)
+            .min(drawing_height / 2.0 / pixels.height as f64);
+        let rect = Rect::from_center_size(
+            (drawing_width / 2.0, drawing_height / 2.0),
+            (pixels.width as f64 * scale, pixels.height as f64 * scale),
+        );
+        Ok(ImageSnippetData {
+            encoded: Arc::new(encoded),
+            pixels: Arc::new(pixels),
+            rect,
+            start_time,
+            end_time: None,
+            fade_in: Diff::from_micros(0),
+        })
+    }
+
+    /// Returns a copy of this snippet that's as big as possible while still fitting in the
+    /// canvas (which has height `drawing_height`), so that it can be used as a background.
+    pub fn as_background(&self, drawing_height: f64) -> ImageSnippetData {
+        let drawing_width = DRAWING_WIDTH;
+        let size = self.rect.size();
+        let scale = (drawing_width / size.width).min(drawing_height / size.height);
+        let mut ret = self.clone();
+        ret.rect = Rect::from_center_size(
+            (drawing_width / 2.0, drawing_height / 2.0),
+            (size.width * scale, size.height * scale),
+        );
+        ret
+    }
+
+    /// The contents of the original image file.
+    pub fn encoded(&self) -> &[u8] {
+        &self.encoded
+    }
+
+    // Are these two snippets the same? Like `druid::Data::same`, this compares the images by
+    // pointer, because comparing them by value would be slow.
+    fn is_same(&self, other: &ImageSnippetData) -> bool {
+        Arc::ptr_eq(&self.encoded, &other.encoded)
+            && Arc::ptr_eq(&self.pixels, &other.pixels)
+            && self.rect == other.rect
+            && self.start_time == other.start_time
+            && self.end_time == other.end_time
+            && self.fade_in == other.fade_in
+    }
+
+    /// Returns a copy of this snippet that appears (and disappears) `by` later.
+    pub fn shifted(&self, by: Diff) -> ImageSnippetData {
+        let mut ret = self.clone();
+        ret.start_time = self.start_time + by;
+        ret.end_time = self.end_time.map(|end| end + by);
+        ret
+    }
+
+    pub fn visible_at(&self, time: Time) -> bool {
+        self.start_time <= time && self.end_time.map(|end| time <= end).unwrap_or(true)
+    }
+
+    fn make_image<R: RenderContext>(&self, ctx: &mut R) -> Result<R::Image, piet::Error> {
+        let pixels = &self.pixels;
+        ctx.make_image(
+            pixels.width,
+            pixels.height,
+            &pixels.rgba,
+            ImageFormat::RgbaSeparate,
+        )
+    }
+
+    // Draws this image (assuming that the render context is in image coordinates), given its
+    // pixels as an image for the render context.
+    //
+    // Fading in is done by drawing the background color on top of the image, so the
+    // `background` should be the color of the canvas.
+    fn render<R: RenderContext>(
+        &self,
+        ctx: &mut R,
+        image: &R::Image,
+        time: Time,
+        background: &Color,
+    ) {
+        ctx.draw_image(image, self.rect, InterpolationMode::Bilinear);
+
+        let fade_in = self.fade_in.as_micros();
+        let elapsed = (time - self.start_time).as_micros();
+        if elapsed < fade_in {
+            let alpha = 1.0 - elapsed as f64 / fade_in as f64;
+            ctx.fill(self.rect, &background.clone().with_alpha(alpha));
+        }
+    }
+}
+
+impl ImageSnippetsData {
+    pub fn snippets(&self) -> impl Iterator<Item = &ImageSnippetData> {
+        self.snippets.values()
+    }
+
+    /// Returns true if there's a snippet with the id `id`.
+    pub fn contains(&self, id: ImageSnippetId) -> bool {
+        self.snippets.contains_key(&id)
+    }
+
+    pub fn with_new_snippet(&self, snip: ImageSnippetData) -> ImageSnippetsData {
+        let mut ret = self.clone();
+        ret.last_id += 1;
+        let id = ImageSnippetId(ret.last_id);
+        let mut map = (*ret.snippets).clone();
+        map.insert(id, snip);
+        ret.snippets = Arc::new(map);
+        ret
+    }
+
+    /// Returns the snippets that were added or changed since `old`, together with the ids of the
+    /// snippets that were removed.
+    pub fn changes_since(
+        &self,
+        old: &ImageSnippetsData,
+    ) -> (Vec<(ImageSnippetId, ImageSnippetData)>, Vec<ImageSnippetId>) {
+        let changed = self
+            .snippets
+            .iter()
+            .filter(|(id, snip)| old.snippets.get(id).map_or(true, |o| !o.is_same(snip)))
+            .map(|(id, snip)| (*id, snip.clone()))
+            .collect();
+        let removed = old
+            .snippets
+            .keys()
+            .filter(|id| !self.snippets.contains_key(id))
+            .cloned()
+            .collect();
+        (changed, removed)
+    }
+
+    /// Applies changes of the kind returned by `changes_since`.
+    pub fn with_changes(
+        &self,
+        changed: Vec<(ImageSnippetId, ImageSnippetData)>,
+        removed: &[ImageSnippetId],
+    ) -> ImageSnippetsData {
+        let mut ret = self.clone();
+        let mut map = (*ret.snippets).clone();
+        for id in removed {
+            map.remove(id);
+        }
+        for (id, snip) in changed {
+            ret.last_id = ret.last_id.max(id.0);
+            map.insert(id, snip);
+        }
+        ret.snippets = Arc::new(map);
+        ret
+    }
+
+    /// Makes all the images that are visible at `time` disappear at that time.
+    pub fn with_visible_snippets_ended(&self, time: Time) -> ImageSnippetsData {
+        let mut ret = self.clone();
+        let mut map = (*ret.snippets).clone();
+        for snip in map.values_mut() {
+            if snip.visible_at(time) {
+                snip.end_time = Some(time);
+            }
+        }
+        ret.snippets = Arc::new(map);
+        ret
+    }
+
+    /// Draws the images that are visible at `time` (assuming that the render context is in image
+    /// coordinates). Each image only gets uploaded to the render context the first time that it's
+    /// drawn; after that, it comes from `cache`.
+    ///
+    /// Fading in is done by drawing the background color on top of the image, so the
+    /// `background` should be the color of the canvas.
+    pub fn render<R: RenderContext>(
+        &self,
+        ctx: &mut R,
+        cache: &mut ImageCache<R::Image>,
+        time: Time,
+        background: &Color,
+    ) {
+        cache.images.retain(|id, (pixels, _)| {
+            self.snippets
+                .get(id)
+                .map_or(false, |snip| Arc::ptr_eq(pixels, &snip.pixels))
+        });
+        for (id, snip) in self.snippets.iter() {
+            if !snip.visible_at(time) {
+                continue;
+            }
+            if !cache.images.contains_key(id) {
+                match snip.make_image(ctx) {
+                    Ok(image) => {
+                        cache.images.insert(*id, (Arc::clone(&snip.pixels), image));
+                    }
+                    Err(e) => {
+                        log::error!("failed to create image: {}", e);
+                        continue;
+                    }
+                }
+            }
+            snip.render(ctx, &cache.images[id].1, time, background);
+        }
+    }
+}
+
+/// The images that have been uploaded to a render context, so that they don't need to be
+/// uploaded again on every frame. `I` is the render context's image type.
+pub struct ImageCache<I> {
+    // Each image remembers the pixels that it was made from, so that it can be dropped when its
+    // snippet changes or goes away.
+    images: BTreeMap<ImageSnippetId, (Arc<Pixels>, I)>,
+}
+
+impl<I> Default for ImageCache<I> {
+    fn default() -> ImageCache<I> {
+        ImageCache {
+            images: BTreeMap::new(),
+        }
+    }
+}
+
+// This is part of the save file format, so it needs to remain stable.
+#[derive(Deserialize, Serialize)]
+struct SavedImageSnippet {
+    encoded: Vec<u8>,
+    rect: (f64, f64, f64, f64),
+    start_time: Time,
+    end_time: Option<Time>,
+    fade_in: Diff,
+}
+
+impl From<ImageSnippetData> for SavedImageSnippet {
+    fn from(snip: ImageSnippetData) -> SavedImageSnippet {
+        SavedImageSnippet {
+            encoded: snip.encoded.as_ref().clone(),
+            rect: (snip.rect.x0, snip.rect.y0, snip.rect.x1, snip.rect.y1),
+            start_time: snip.start_time,
+            end_time: snip.end_time,
+            fade_in: snip.fade_in,
+        }
+    }
+}
+
+impl TryFrom<SavedImageSnippet> for ImageSnippetData {
+    type Error = String;
+
+    fn try_from(saved: SavedImageSnippet) -> Result<ImageSnippetData, String> {
+        let pixels = decode(&saved.encoded).map_err(|e| e.to_string())?;
+        let (x0, y0, x1, y1) = saved.rect;
+        Ok(ImageSnippetData {
+            encoded: Arc::new(saved.encoded),
+            pixels: Arc::new(pixels),
+            rect: Rect::new(x0, y0, x1, y1),
+            start_time: saved.start_time,
+            end_time: saved.end_time,
+            fade_in: saved.fade_in,
+        })
+    }
+}
+
+impl Serialize for ImageSnippetsData {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        self.snippets.serialize(ser)
+    }
+}
+
+impl<'de> Deserialize<'de> for ImageSnippetsData {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<ImageSnippetsData, D::Error> {
+        let snips: BTreeMap<ImageSnippetId, ImageSnippetData> = Deserialize::deserialize(de)?;
+        let max_id = snips.keys().max().unwrap_or(&ImageSnippetId(0)).0;
+        Ok(ImageSnippetsData {
+            snippets: Arc::new(snips),
+            last_id: max_id,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let img = image::RgbaImage::from_pixel(width, height, image::Rgba([255, 0, 0, 255]));
+        let mut buf = Vec::new();
+        image::DynamicImage::ImageRgba8(img)
+            .write_to(&mut buf, image::ImageOutputFormat::Png)
+            .unwrap();
+        buf
+    }
+
+    #[test]
+    fn placement() {
+        let snip = ImageSnippetData::from_encoded(png(4, 2), Time::from_micros(10), 0.75).unwrap();
+        // The image is wider than the canvas, so its width is limited to half the canvas.
+        assert_eq!(snip.rect, Rect::new(0.25, 0.25, 0.75, 0.5));
+        assert!(!snip.visible_at(Time::from_micros(0)));
+        assert!(snip.visible_at(Time::from_micros(1000)));
+    }
+
+    #[test]
+    fn background_placement() {
+        let time = Time::from_micros(10);
+        let wide = ImageSnippetData::from_encoded(png(4, 2), time, 0.75).unwrap();
+        assert_eq!(
+            wide.as_background(0.75).rect,
+            Rect::new(0.0, 0.125, 1.0, 0.625)
+        );
+        let tall = ImageSnippetData::from_encoded(png(1, 2), time, 0.75).unwrap();
+        assert_eq!(
+            tall.as_background(0.75).rect,
+            Rect::new(0.3125, 0.0, 0.6875, 0.75)
+        );
+    }
+
+    #[test]
+    fn end_visible() {
+        let snip = ImageSnippetData::from_encoded(png(1, 1), Time::from_micros(10), 0.75).unwrap();
+        let images = ImageSnippetsData::default()
+            .with_new_snippet(snip)
+            .with_visible_snippets_ended(Time::from_micros(20));
+        let snip = images.snippets.values().next().unwrap();
+        assert_eq!(snip.end_time, Some(Time::from_micros(20)));
+        assert!(!snip.visible_at(Time::from_micros(21)));
+    }
+
+    #[test]
+    fn serialize() {
+        let snip = ImageSnippetData::from_encoded(png(3, 3), Time::from_micros(10), 0.75).unwrap();
+        let images = ImageSnippetsData::default().with_new_snippet(snip);
+        let ser = serde_json::to_string(&images).unwrap();
+        let de: ImageSnippetsData = serde_json::from_str(&ser).unwrap();
+        assert_eq!(de.last_id, 1);
+        let snip = de.snippets.values().next().unwrap();
+        assert_eq!(snip.pixels.width, 3);
+        assert_eq!(snip.start_time, Time::from_micros(10));
+    }
+}
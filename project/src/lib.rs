@@ -0,0 +1,9 @@
+//! A scribble project: the images, captions and settings that go along with the drawings (from
+//! `scribble_curves`). None of this depends on druid; the `druid` feature makes the types
+//! implement `druid::Data` (and the settings `druid::Lens`), which the scribble app needs.
+//! The file that a project gets saved in is in `scribble_core`, together with the audio.
+
+pub mod captions;
+pub mod images;
+pub mod settings;
+pub mod watermark;
@@ -0,0 +1,241 @@
+//! The settings that belong to a project (as opposed to the user's preferences), and so get
+//! saved along with the animation.
+
+#[cfg(feature = "druid")]
+use druid::{Data, Lens};
+use piet::Color;
+use serde::{Deserialize, Serialize};
+
+use crate::watermark::Watermark;
+
+/// The drawing coordinates are chosen so that the width of the image is always 1.0. The height
+/// depends on the aspect ratio in the project settings.
+pub const DRAWING_WIDTH: f64 = 1.0;
+
+/// The shape of the canvas.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "druid", derive(Data))]
+pub enum AspectRatio {
+    /// 4:3
+    Standard,
+    /// 16:9
+    Wide,
+    /// 9:16, for phone screens.
+    Vertical,
+}
+
+impl Default for AspectRatio {
+    fn default() -> AspectRatio {
+        AspectRatio::Standard
+    }
+}
+
+impl AspectRatio {
+    pub const ALL: [AspectRatio; 3] = [
+        AspectRatio::Standard,
+        AspectRatio::Wide,
+        AspectRatio::Vertical,
+    ];
+
+    /// The width of the canvas, divided by its height.
+    pub fn ratio(&self) -> f64 {
+        match self {
+            AspectRatio::Standard => 4.0 / 3.0,
+            AspectRatio::Wide => 16.0 / 9.0,
+            AspectRatio::Vertical => 9.0 / 16.0,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AspectRatio::Standard => "4:3",
+            AspectRatio::Wide => "16:9",
+            AspectRatio::Vertical => "9:16 (vertical)",
+        }
+    }
+}
+
+/// Settings that belong to a project (as opposed to the user's preferences), and so get saved
+/// along with the animation.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "druid", derive(Data, Lens))]
+#[serde(default)]
+pub struct ProjectSettings {
+    pub aspect_ratio: AspectRatio,
+
+    /// The color of the canvas.
+    #[serde(with = "scribble_curves::curve::serde_color")]
+    pub background: Color,
+
+    /// An image to draw on top of the exported video.
+    pub watermark: Watermark,
+
+    /// The title of the project, which gets written into the metadata of exported files.
+    pub title: String,
+
+    /// The author of the project, which gets written into the metadata of exported files.
+    pub author: String,
+
+    /// When the project was created, in seconds since the Unix epoch. This is `None` for
+    /// projects that were created before we kept track.
+    pub created: Option<u64>,
+
+    /// The frame rate that this project gets exported at, unless it's changed in the export
+    /// dialog.
+    pub frame_rate: FrameRate,
+
+    /// The recording speed that gets selected when this project is opened. This gets updated
+    /// whenever something is drawn, so it's the one that was used most recently.
+    pub recording_speed: RecordingSpeed,
+
+    /// The rest of the recording controls, as they were the last time something was drawn.
+    /// This is `None` for projects that were last drawn in before we kept track.
+    pub recording_setup: Option<RecordingSetup>,
+}
+
+/// The recording controls (apart from the speed, which is in `ProjectSettings`) that get
+/// restored when a project is opened.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "druid", derive(Data))]
+pub struct RecordingSetup {
+    pub fade: bool,
+    #[serde(with = "scribble_curves::curve::serde_color")]
+    pub pen_color: Color,
+    pub line_thickness: f64,
+}
+
+// piet::Color doesn't implement PartialEq, so we can't derive this.
+impl PartialEq for RecordingSetup {
+    fn eq(&self, other: &RecordingSetup) -> bool {
+        self.fade == other.fade
+            && self.pen_color.as_rgba_u32() == other.pen_color.as_rgba_u32()
+            && self.line_thickness == other.line_thickness
+    }
+}
+
+impl Default for ProjectSettings {
+    fn default() -> ProjectSettings {
+        ProjectSettings {
+            aspect_ratio: AspectRatio::default(),
+            background: Color::WHITE,
+            watermark: Watermark::default(),
+            title: String::new(),
+            author: String::new(),
+            created: None,
+            frame_rate: FrameRate::Fps30,
+            recording_speed: RecordingSpeed::Slow,
+            recording_setup: None,
+        }
+    }
+}
+
+// piet::Color doesn't implement PartialEq, so we can't derive this.
+impl PartialEq for ProjectSettings {
+    fn eq(&self, other: &ProjectSettings) -> bool {
+        self.aspect_ratio == other.aspect_ratio
+            && self.background.as_rgba_u32() == other.background.as_rgba_u32()
+            && self.watermark == other.watermark
+            && self.title == other.title
+            && self.author == other.author
+            && self.created == other.created
+            && self.frame_rate == other.frame_rate
+            && self.recording_speed == other.recording_speed
+            && self.recording_setup == other.recording_setup
+    }
+}
+
+impl ProjectSettings {
+    /// The height of the drawing, in image coordinates (in which the width is always
+    /// `DRAWING_WIDTH`).
+    pub fn drawing_height(&self) -> f64 {
+        DRAWING_WIDTH / self.aspect_ratio.ratio()
+    }
+}
+
+/// The frame rate of exported videos.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "druid", derive(Data))]
+pub enum FrameRate {
+    Fps24,
+    Fps30,
+    Fps60,
+}
+
+impl FrameRate {
+    pub fn fps(&self) -> u32 {
+        match self {
+            FrameRate::Fps24 => 24,
+            FrameRate::Fps30 => 30,
+            FrameRate::Fps60 => 60,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "druid", derive(Data))]
+pub enum RecordingSpeed {
+    Paused,
+    Slower,
+    Slow,
+    Normal,
+}
+
+impl RecordingSpeed {
+    /// The speed after this one, in the order that the buttons are shown (wrapping around at
+    /// the end).
+    pub fn next(&self) -> RecordingSpeed {
+        match self {
+            RecordingSpeed::Paused => RecordingSpeed::Slower,
+            RecordingSpeed::Slower => RecordingSpeed::Slow,
+            RecordingSpeed::Slow => RecordingSpeed::Normal,
+            RecordingSpeed::Normal => RecordingSpeed::Paused,
+        }
+    }
+
+    pub fn factor(&self) -> f64 {
+        match self {
+            RecordingSpeed::Paused => 0.0,
+            RecordingSpeed::Slower => 1.0 / 8.0,
+            RecordingSpeed::Slow => 1.0 / 3.0,
+            RecordingSpeed::Normal => 1.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_recording_speed() {
+        let mut speed = RecordingSpeed::Slow;
+        let mut seen = Vec::new();
+        for _ in 0..4 {
+            speed = speed.next();
+            seen.push(speed);
+        }
+        assert_eq!(speed, RecordingSpeed::Slow);
+        assert_eq!(seen[0], RecordingSpeed::Normal);
+        assert_eq!(seen[1], RecordingSpeed::Paused);
+    }
+
+    #[test]
+    fn recording_setup() {
+        // Settings from before we remembered the recording setup still load.
+        let settings: ProjectSettings = serde_json::from_str("{}").unwrap();
+        assert_eq!(settings.recording_setup, None);
+
+        let setup = RecordingSetup {
+            fade: true,
+            pen_color: Color::rgb8(255, 214, 0),
+            line_thickness: 0.008,
+        };
+        let settings = ProjectSettings {
+            recording_setup: Some(setup.clone()),
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&settings).unwrap();
+        let loaded: ProjectSettings = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.recording_setup, Some(setup));
+    }
+}
@@ -0,0 +1,172 @@
+//! A watermark is an image (e.g. a channel logo) that gets drawn on top of every exported frame.
+
+#[cfg(feature = "druid")]
+use druid::{Data, Lens};
+use kurbo::Rect;
+use piet::{ImageFormat, InterpolationMode, RenderContext};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// The gap between the watermark and the edge of the frame, as a fraction of the frame's width.
+const MARGIN: f64 = 0.02;
+
+/// Which corner of the frame the watermark goes in.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "druid", derive(Data))]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+#[derive(Clone, Deserialize, PartialEq, Serialize)]
+#[cfg_attr(feature = "druid", derive(Data, Lens))]
+#[serde(default)]
+pub struct Watermark {
+    /// The contents of the image file, or `None` if there is no watermark.
+    pub encoded: Option<Arc<Vec<u8>>>,
+    pub corner: Corner,
+    /// The width of the watermark, as a fraction of the frame's width.
+    pub scale: f64,
+    /// Between 0.0 (invisible) and 1.0 (opaque).
+    pub opacity: f64,
+}
+
+impl Default for Watermark {
+    fn default() -> Watermark {
+        Watermark {
+            encoded: None,
+            corner: Corner::BottomRight,
+            scale: 0.15,
+            opacity: 0.8,
+        }
+    }
+}
+
+// We don't want the whole image file showing up in debug output.
+impl std::fmt::Debug for Watermark {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Watermark")
+            .field("encoded", &self.encoded.as_ref().map(|e| e.len()))
+            .field("corner", &self.corner)
+            .field("scale", &self.scale)
+            .field("opacity", &self.opacity)
+            .finish()
+    }
+}
+
+impl Watermark {
+    /// Decodes the watermark image. Returns `None` if there is no watermark.
+    pub fn image(&self) -> anyhow::Result<Option<WatermarkImage>> {
+        let encoded = match &self.encoded {
+            Some(e) => e,
+            None => return Ok(None),
+        };
+        let img = image::load_from_memory(encoded)?.to_rgba();
+        if img.width() == 0 || img.height() == 0 {
+            return Err(anyhow::anyhow!("the watermark image is empty"));
+        }
+
+        let opacity = self.opacity.max(0.0).min(1.0);
+        let width = img.width() as usize;
+        let height = img.height() as usize;
+        let mut rgba = img.into_raw();
+        for alpha in rgba.iter_mut().skip(3).step_by(4) {
+            *alpha = (*alpha as f64 * opacity).round() as u8;
+        }
+        Ok(Some(WatermarkImage {
+            width,
+            height,
+            rgba,
+            corner: self.corner,
+            scale: self.scale,
+        }))
+    }
+}
+
+/// A decoded watermark, ready to be drawn. The opacity has already been applied to the pixels.
+pub struct WatermarkImage {
+    width: usize,
+    height: usize,
+    rgba: Vec<u8>,
+    corner: Corner,
+    scale: f64,
+}
+
+impl WatermarkImage {
+    /// Where the watermark goes in a frame of the given size (in pixels).
+    pub fn rect(&self, frame_width: f64, frame_height: f64) -> Rect {
+        let width = frame_width * self.scale;
+        let height = width * self.height as f64 / self.width as f64;
+        let margin = frame_width * MARGIN;
+        let x0 = match self.corner {
+            Corner::TopLeft | Corner::BottomLeft => margin,
+            Corner::TopRight | Corner::BottomRight => frame_width - margin - width,
+        };
+        let y0 = match self.corner {
+            Corner::TopLeft | Corner::TopRight => margin,
+            Corner::BottomLeft | Corner::BottomRight => frame_height - margin - height,
+        };
+        Rect::from_origin_size((x0, y0), (width, height))
+    }
+
+    /// Draws the watermark, assuming that the render context is in pixel coordinates.
+    pub fn render(&self, ctx: &mut impl RenderContext, frame_width: f64, frame_height: f64) {
+        let image = match ctx.make_image(
+            self.width,
+            self.height,
+            &self.rgba,
+            ImageFormat::RgbaSeparate,
+        ) {
+            Ok(image) => image,
+            Err(e) => {
+                log::error!("failed to create watermark image: {}", e);
+                return;
+            }
+        };
+        ctx.draw_image(
+            &image,
+            self.rect(frame_width, frame_height),
+            InterpolationMode::Bilinear,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watermark(corner: Corner) -> Watermark {
+        let img = image::RgbaImage::from_pixel(20, 10, image::Rgba([255, 0, 0, 200]));
+        let mut buf = Vec::new();
+        image::DynamicImage::ImageRgba8(img)
+            .write_to(&mut buf, image::ImageOutputFormat::Png)
+            .unwrap();
+        Watermark {
+            encoded: Some(Arc::new(buf)),
+            corner,
+            scale: 0.2,
+            opacity: 0.5,
+        }
+    }
+
+    #[test]
+    fn placement() {
+        let img = watermark(Corner::TopLeft).image().unwrap().unwrap();
+        assert_eq!(img.rect(1000.0, 500.0), Rect::new(20.0, 20.0, 220.0, 120.0));
+
+        let img = watermark(Corner::BottomRight).image().unwrap().unwrap();
+        assert_eq!(
+            img.rect(1000.0, 500.0),
+            Rect::new(780.0, 380.0, 980.0, 480.0)
+        );
+    }
+
+    #[test]
+    fn opacity() {
+        let img = watermark(Corner::TopLeft).image().unwrap().unwrap();
+        assert_eq!(&img.rgba[0..4], &[255, 0, 0, 100]);
+        assert!(Watermark::default().image().unwrap().is_none());
+    }
+}
@@ -1,10 +1,35 @@
 use druid::{AppDelegate, Command, DelegateCtx, Env, FileInfo, Target, WindowId};
+use std::sync::Arc;
 
 use crate::cmd;
-use crate::data::{AppState, SaveFileData};
+use crate::controllers::Controllers;
+use crate::data::{AppState, Dialog};
+use crate::global_hotkeys::GlobalHotkeys;
+use crate::remote::RemoteControl;
+use crate::remote_pen::PenServer;
 
-#[derive(Debug, Default)]
-pub struct Delegate;
+pub struct Delegate {
+    global_hotkeys: GlobalHotkeys,
+    controllers: Controllers,
+    remote: RemoteControl,
+    remote_pen: PenServer,
+}
+
+impl Delegate {
+    pub fn new(
+        global_hotkeys: GlobalHotkeys,
+        controllers: Controllers,
+        remote: RemoteControl,
+        remote_pen: PenServer,
+    ) -> Delegate {
+        Delegate {
+            global_hotkeys,
+            controllers,
+            remote,
+            remote_pen,
+        }
+    }
+}
 
 impl AppDelegate<AppState> for Delegate {
     fn command(
@@ -15,7 +40,39 @@ impl AppDelegate<AppState> for Delegate {
         data: &mut AppState,
         _env: &Env,
     ) -> bool {
+        // The global hotkeys, the controllers and the remote control (and pen) servers aren't part
+        // of the app's data, so they don't notice the new preferences by themselves.
+        if let Some(prefs) = cmd::SET_PREFERENCES.get(cmd) {
+            self.global_hotkeys.update(prefs);
+            self.controllers.update(prefs);
+            self.remote.update(prefs);
+            self.remote_pen.update(prefs);
+        }
+
         match cmd.selector {
+            // Before doing anything that would throw away the current project, check whether
+            // there's anything that needs saving.
+            cmd::CLOSE_TAB | druid::commands::CLOSE_WINDOW | druid::commands::QUIT_APP
+                if data.is_dirty() =>
+            {
+                data.unsaved_action = Some((cmd.clone(), target));
+                data.dialog = Some(Dialog::UnsavedChanges);
+                false
+            }
+            // Closing the window also throws away the projects in the other tabs. We ask about
+            // them one at a time, by switching to them.
+            druid::commands::CLOSE_WINDOW | druid::commands::QUIT_APP
+                if crate::tabs::dirty_tab(data).is_some() =>
+            {
+                if crate::tabs::can_switch(data) {
+                    if let Some(idx) = crate::tabs::dirty_tab(data) {
+                        crate::tabs::switch_to(data, idx);
+                    }
+                    data.unsaved_action = Some((cmd.clone(), target));
+                    data.dialog = Some(Dialog::UnsavedChanges);
+                }
+                false
+            }
             druid::commands::SAVE_FILE => {
                 let path = if let Ok(info) = cmd.get_object::<FileInfo>() {
                     info.path().to_owned()
@@ -30,29 +87,23 @@ impl AppDelegate<AppState> for Delegate {
                 // exporting, and we decide which to do based on the file
                 // extension.
                 match path.extension().and_then(|e| e.to_str()) {
-                    Some("mp4") => {
-                        let export = cmd::ExportCmd {
-                            snippets: data.scribble.snippets.clone(),
-                            audio_snippets: data.scribble.audio_snippets.clone(),
-                            filename: path.to_owned(),
-                        };
-                        ctx.submit_command(Command::new(cmd::EXPORT, export), None);
+                    Some("mp4") | Some("webm") | Some("gif") | Some("png") | Some("svg")
+                    | Some("ivf") | Some("opus") | Some("mp3") | Some("pdf") | Some("html")
+                    | Some("htm") => {
+                        let export = data.export_cmd(path.to_owned());
+                        ctx.submit_command(cmd::EXPORT.with(export), None);
+                    }
+                    Some("gpl") | Some("hex") => {
+                        ctx.submit_command(cmd::SAVE_PALETTE.with(path), None);
                     }
                     Some("scb") => {
-                        data.save_path = Some(path.clone());
-                        if let Err(e) = data.scribble.to_save_file().save_to_path(&path) {
-                            log::error!("error saving: '{}'", e);
-                        }
+                        ctx.submit_command(cmd::SAVE_PROJECT.with(path), None);
                     }
                     _ => {
                         log::error!("unknown extension! Trying to save anyway");
-                        data.save_path = Some(path.clone());
-                        if let Err(e) = data.scribble.to_save_file().save_to_path(&path) {
-                            log::error!("error saving: '{}'", e);
-                        }
+                        ctx.submit_command(cmd::SAVE_PROJECT.with(path), None);
                     }
                 }
-                ctx.submit_command(cmd::REBUILD_MENUS, target);
                 false
             }
             druid::commands::OPEN_FILE => {
@@ -62,16 +113,44 @@ impl AppDelegate<AppState> for Delegate {
                     log::error!("no open file info, not opening");
                     return false;
                 };
-                match SaveFileData::load_from_path(info.path()) {
-                    Ok(save_data) => {
-                        *data = AppState::from_save_file(save_data);
-                        data.save_path = Some(info.path().to_owned());
-                    }
-                    Err(e) => {
-                        log::error!("error loading: '{}'", e);
+                if crate::images::is_image_path(info.path()) {
+                    match std::fs::read(info.path()) {
+                        // The export dialog uses the open panel for choosing a watermark.
+                        Ok(bytes) if data.dialog == Some(Dialog::Export) => {
+                            ctx.submit_command(cmd::SET_WATERMARK.with(Some(bytes)), None)
+                        }
+                        Ok(bytes) => ctx.submit_command(cmd::ADD_IMAGE.with(bytes), None),
+                        Err(e) => log::error!("error reading image: '{}'", e),
                     }
+                    return false;
+                }
+                if crate::whiteboard::is_whiteboard_path(info.path()) {
+                    ctx.submit_command(cmd::IMPORT_DRAWING.with(info.path().to_owned()), None);
+                    return false;
+                }
+                if crate::captions::is_caption_path(info.path()) {
+                    ctx.submit_command(cmd::IMPORT_CAPTIONS.with(info.path().to_owned()), None);
+                    return false;
                 }
-                ctx.submit_command(cmd::REBUILD_MENUS, target);
+                if crate::palette_file::is_palette_path(info.path()) {
+                    ctx.submit_command(cmd::LOAD_PALETTE.with(info.path().to_owned()), None);
+                    return false;
+                }
+                if scribble_core::audio_file::is_audio_path(info.path()) {
+                    // The relink dialog uses the open panel for finding missing audio files.
+                    let selector = if data.dialog == Some(Dialog::RelinkAudio) {
+                        cmd::RELINK_AUDIO
+                    } else {
+                        cmd::ADD_AUDIO_FILE
+                    };
+                    ctx.submit_command(selector.with(info.path().to_owned()), None);
+                    return false;
+                }
+                if data.dialog == Some(Dialog::ImportProject) {
+                    data.import_path = Some(Arc::new(info.path().to_owned()));
+                    return false;
+                }
+                ctx.submit_command(cmd::OPEN_PROJECT.with(info.path().to_owned()), None);
                 false
             }
             cmd::REBUILD_MENUS => {
@@ -88,10 +167,15 @@ impl AppDelegate<AppState> for Delegate {
     fn window_removed(
         &mut self,
         _id: WindowId,
-        _data: &mut AppState,
+        data: &mut AppState,
         _env: &Env,
         _ctx: &mut DelegateCtx,
     ) {
         log::info!("window removed");
+        // We're shutting down normally, so there's nothing to recover.
+        crate::autosave::remove(data.save_path.as_deref());
+        for tab in data.tabs.iter() {
+            crate::autosave::remove(tab.save_path().map(|p| p.as_path()));
+        }
     }
 }
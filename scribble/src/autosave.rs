@@ -0,0 +1,524 @@
+//! Periodically saving the animation to a separate file, so that a crash doesn't lose everything
+//! since the last time the user saved.
+//!
+//! The autosaves live in the user's local data directory, with one file per project. They get
+//! deleted whenever the project is saved (and when scribble exits normally), so if there's an
+//! autosave lying around that's newer than its project then scribble probably crashed.
+//!
+//! Between autosaves, every edit gets appended to a journal. To recover the animation, we take
+//! the autosave (or the project, if it's newer) and replay the journal on top of it.
+//!
+//! All the writing happens on the same background thread that saves and loads projects, so that
+//! big projects don't stall the UI.
+
+use druid::Data;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use scribble_core::save_file::{SaveFileData, SaveFormat};
+use scribble_core::undo::UndoStack;
+use scribble_curves::{SnippetData, SnippetId, Time};
+
+use crate::audio::{AudioSnippetData, AudioSnippetId, AudioSnippetsData};
+use crate::data::{AppState, ProjectSettings, ScribbleState};
+use crate::images::{ImageSnippetData, ImageSnippetId};
+use crate::project_io::ProjectIo;
+
+// The extensions of the journal files (which go next to the autosave file). When we autosave, we
+// move the journal to the old journal, and we delete the old journal once the autosave has been
+// written.
+const JOURNAL: &str = "journal";
+const OLD_JOURNAL: &str = "journal.old";
+
+// The extension of the directory (also next to the autosave file) that holds the samples of the
+// audio in the journal. They're too big to go in the journal itself, so each new recording gets a
+// file of its own there, which stays until the autosave includes it.
+const JOURNAL_AUDIO: &str = "journal-audio";
+
+// The name of the autosave file for the project that gets saved at `save_path`. Projects that
+// have never been saved share a single autosave file.
+fn file_name(save_path: Option<&Path>) -> String {
+    match save_path {
+        Some(path) => {
+            let name: String = path
+                .to_string_lossy()
+                .chars()
+                .map(|c| if c.is_alphanumeric() { c } else { '_' })
+                .collect();
+            format!("{}.scb", name)
+        }
+        None => "untitled.scb".to_owned(),
+    }
+}
+
+/// The path of the autosave file for the project that gets saved at `save_path`, if we could
+/// figure out where the data directory is.
+pub fn autosave_path(save_path: Option<&Path>) -> Option<PathBuf> {
+    dirs::data_local_dir().map(|dir| {
+        dir.join("scribble")
+            .join("autosave")
+            .join(file_name(save_path))
+    })
+}
+
+// Returns `true` if there is an autosave for the project at `save_path` that is newer than the
+// project itself.
+fn has_newer_autosave(save_path: Option<&Path>) -> bool {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let autosave_time = match autosave_path(save_path).and_then(|p| modified(&p)) {
+        Some(t) => t,
+        None => return false,
+    };
+    match save_path.and_then(modified) {
+        Some(project_time) => autosave_time > project_time,
+        None => true,
+    }
+}
+
+/// Returns `true` if the project at `save_path` has unsaved changes that we can recover.
+pub fn has_recovery_data(save_path: Option<&Path>) -> bool {
+    let has_journal = |ext: &str| {
+        autosave_path(save_path)
+            .and_then(|p| std::fs::metadata(p.with_extension(ext)).ok())
+            .map_or(false, |m| m.len() > 0)
+    };
+    has_newer_autosave(save_path) || has_journal(JOURNAL) || has_journal(OLD_JOURNAL)
+}
+
+/// Reconstructs the project at `save_path`, including all the changes that were made since it
+/// was last saved.
+pub fn recover(save_path: Option<&Path>) -> anyhow::Result<ScribbleState> {
+    let autosave =
+        autosave_path(save_path).ok_or_else(|| anyhow::anyhow!("no data directory found"))?;
+    let base = if has_newer_autosave(save_path) {
+        Some(autosave.clone())
+    } else {
+        save_path.map(|p| p.to_owned())
+    };
+    let mut state = match base {
+        Some(path) => ScribbleState::from_save_file(SaveFileData::load_from_path(path)?),
+        None => ScribbleState::default(),
+    };
+    replay(&autosave, OLD_JOURNAL, &mut state)?;
+    replay(&autosave, JOURNAL, &mut state)?;
+    // Neither the journal nor the saved files contain the samples of linked audio.
+    state.audio_snippets = state.audio_snippets.with_links_loaded().spilled();
+    Ok(state)
+}
+
+/// Deletes the autosave (and the journal) for the project at `save_path`, if there is one.
+pub fn remove(save_path: Option<&Path>) {
+    if let Some(path) = autosave_path(save_path) {
+        for path in &[
+            path.clone(),
+            path.with_extension(JOURNAL),
+            path.with_extension(OLD_JOURNAL),
+        ] {
+            if path.exists() {
+                if let Err(e) = std::fs::remove_file(path) {
+                    log::error!("error removing autosave {:?}: {}", path, e);
+                }
+            }
+        }
+        let audio_dir = path.with_extension(JOURNAL_AUDIO);
+        if audio_dir.exists() {
+            if let Err(e) = std::fs::remove_dir_all(&audio_dir) {
+                log::error!("error removing autosave {:?}: {}", audio_dir, e);
+            }
+        }
+    }
+}
+
+/// One edit to the animation, as recorded in the journal.
+///
+/// Rather than recording how to change the animation, we record what the changed parts look like
+/// after the change. This means that replaying an edit that the autosave already includes
+/// doesn't do any harm.
+#[derive(Deserialize, Serialize)]
+struct Entry {
+    snippets: (Vec<(SnippetId, SnippetData)>, Vec<SnippetId>),
+    audio: (Vec<(AudioSnippetId, JournaledAudio)>, Vec<AudioSnippetId>),
+    images: (Vec<(ImageSnippetId, ImageSnippetData)>, Vec<ImageSnippetId>),
+    settings: Option<ProjectSettings>,
+}
+
+/// An audio snippet, as recorded in the journal.
+#[derive(Deserialize, Serialize)]
+struct JournaledAudio {
+    start_time: Time,
+    link: Option<PathBuf>,
+    /// The file (in the journal's audio directory) with the samples, if they're new in this
+    /// edit. Otherwise the snippet keeps the samples that it had before (or, if it's linked,
+    /// they get decoded from the linked file), so that moving a long recording around doesn't
+    /// mean writing it all out again.
+    samples: Option<String>,
+}
+
+impl Entry {
+    // Also returns the snippets whose samples need to be written to the journal's audio
+    // directory, together with the names of their files.
+    fn new(old: &ScribbleState, new: &ScribbleState) -> (Entry, Vec<(String, AudioSnippetData)>) {
+        let mut new_samples = Vec::new();
+        let (changed, removed) = new.audio_snippets.changes_since(&old.audio_snippets);
+        let changed = changed
+            .into_iter()
+            .map(|(id, snip)| {
+                let same_samples = old.audio_snippets.contains(id)
+                    && old.audio_snippets.snippet(id).has_same_samples(&snip);
+                let samples = if snip.link().is_some() || same_samples {
+                    None
+                } else {
+                    let name = samples_file_name();
+                    new_samples.push((name.clone(), snip.clone()));
+                    Some(name)
+                };
+                let audio = JournaledAudio {
+                    start_time: snip.start_time(),
+                    link: snip.link().map(|path| path.to_owned()),
+                    samples,
+                };
+                (id, audio)
+            })
+            .collect();
+
+        let entry = Entry {
+            snippets: new.snippets.changes_since(&old.snippets),
+            audio: (changed, removed),
+            images: new.images.changes_since(&old.images),
+            settings: if new.settings.same(&old.settings) {
+                None
+            } else {
+                Some(new.settings.clone())
+            },
+        };
+        (entry, new_samples)
+    }
+
+    fn apply(self, state: &mut ScribbleState, audio_dir: &Path) {
+        let (changed, removed) = self.snippets;
+        state.snippets = state.snippets.with_changes(changed, &removed);
+        let (changed, removed) = self.audio;
+        let changed = changed
+            .into_iter()
+            .filter_map(|(id, audio)| {
+                audio
+                    .into_snippet(id, &state.audio_snippets, audio_dir)
+                    .map(|snip| (id, snip))
+            })
+            .collect();
+        state.audio_snippets = state.audio_snippets.with_changes(changed, &removed);
+        let (changed, removed) = self.images;
+        state.images = state.images.with_changes(changed, &removed);
+        if let Some(settings) = self.settings {
+            state.settings = settings;
+        }
+    }
+}
+
+impl JournaledAudio {
+    // Turns this back into a snippet, given the snippets as they were before this edit.
+    fn into_snippet(
+        self,
+        id: AudioSnippetId,
+        old: &AudioSnippetsData,
+        audio_dir: &Path,
+    ) -> Option<AudioSnippetData> {
+        if let Some(link) = self.link {
+            return Some(AudioSnippetData::unloaded_link(link, self.start_time));
+        }
+        match self.samples {
+            Some(name) => match read_samples(&audio_dir.join(&name)) {
+                Ok(buf) => Some(AudioSnippetData::new(buf, self.start_time)),
+                Err(e) => {
+                    log::warn!("couldn't read the journaled audio {:?}: {}", name, e);
+                    None
+                }
+            },
+            None if old.contains(id) => {
+                let snip = old.snippet(id);
+                Some(snip.shifted(self.start_time - snip.start_time()))
+            }
+            None => {
+                log::warn!("no samples for the journaled audio {:?}", id);
+                None
+            }
+        }
+    }
+}
+
+// A name for a new file in the journal's audio directory. A recovered journal keeps getting
+// appended to, so these need to be different from the ones that earlier runs used.
+fn samples_file_name() -> String {
+    static NEXT_FILE: AtomicUsize = AtomicUsize::new(0);
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!("{}-{}.pcm", now, NEXT_FILE.fetch_add(1, Ordering::Relaxed))
+}
+
+fn write_samples(path: &Path, buf: &[i16]) -> anyhow::Result<()> {
+    let mut write = BufWriter::new(File::create(path)?);
+    for sample in buf {
+        write.write_all(&sample.to_le_bytes())?;
+    }
+    write.flush()?;
+    Ok(())
+}
+
+fn read_samples(path: &Path) -> anyhow::Result<Vec<i16>> {
+    let bytes = std::fs::read(path)?;
+    Ok(bytes
+        .chunks_exact(2)
+        .map(|b| i16::from_le_bytes([b[0], b[1]]))
+        .collect())
+}
+
+// Appends `entry` to the journal of the autosave at `path`. The new samples get written first,
+// so that the journal never refers to samples that aren't there.
+fn append_entry(
+    path: &Path,
+    entry: &Entry,
+    samples: &[(String, AudioSnippetData)],
+) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    if !samples.is_empty() {
+        let audio_dir = path.with_extension(JOURNAL_AUDIO);
+        std::fs::create_dir_all(&audio_dir)?;
+        for (name, snip) in samples {
+            write_samples(&audio_dir.join(name), snip.buf())?;
+        }
+    }
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    let journal = path.with_extension(JOURNAL);
+    let mut file = OpenOptions::new().create(true).append(true).open(journal)?;
+    file.write_all(&line)?;
+    Ok(())
+}
+
+// Replays the journal with extension `ext` of the autosave at `autosave`.
+fn replay(autosave: &Path, ext: &str, state: &mut ScribbleState) -> anyhow::Result<()> {
+    let path = autosave.with_extension(ext);
+    let audio_dir = autosave.with_extension(JOURNAL_AUDIO);
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for line in BufReader::new(file).lines() {
+        match serde_json::from_str::<Entry>(&line?) {
+            Ok(entry) => entry.apply(state, &audio_dir),
+            // If we crashed while writing an entry, the last line will be incomplete.
+            Err(e) => {
+                log::warn!("stopping at a bad journal entry in {:?}: {}", path, e);
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Keeps track of when we last autosaved, and what we saved.
+pub struct Autosave {
+    last_save: Instant,
+    // The autosave file that we're currently writing to. If this changes (because a different
+    // project was opened, or because this one was saved under a different name), we start
+    // over.
+    path: Option<PathBuf>,
+    // The animation, as of the last autosave.
+    saved: Option<ScribbleState>,
+    // The animation, as of the last journal entry.
+    journaled: Option<ScribbleState>,
+    // The undo stack of the project that we're autosaving. Every open project has its own, so
+    // if this changes then a different tab has been switched to (even if it has the same
+    // autosave file, like two untitled projects), and we start over.
+    undo: Option<Arc<RefCell<UndoStack>>>,
+}
+
+impl Autosave {
+    pub fn new() -> Autosave {
+        Autosave {
+            last_save: Instant::now(),
+            path: None,
+            saved: None,
+            journaled: None,
+            undo: None,
+        }
+    }
+
+    /// Writes any new edits to the journal, and saves the animation if the autosave interval has
+    /// passed and the animation has changed since the last autosave. The writing happens on
+    /// `io`'s background thread.
+    pub fn tick(&mut self, data: &AppState, io: &ProjectIo) {
+        if data.prefs.autosave_secs <= 0.0 {
+            return;
+        }
+        let path = match autosave_path(data.save_path.as_deref()) {
+            Some(path) => path,
+            None => return,
+        };
+        let same_project = self
+            .undo
+            .as_ref()
+            .map_or(false, |undo| Arc::ptr_eq(undo, &data.undo));
+        if self.path.as_ref() != Some(&path) || !same_project {
+            self.path = Some(path);
+            self.undo = Some(Arc::clone(&data.undo));
+            self.saved = Some(data.scribble.clone());
+            self.journaled = Some(data.scribble.clone());
+            self.last_save = Instant::now();
+            return;
+        }
+
+        // While something is being recorded, the animation is changing all the time. We only
+        // want to journal the finished edits.
+        if data.action.is_idle() {
+            self.write_journal(&path, &data.scribble, io);
+        }
+        if self.last_save.elapsed().as_secs_f64() >= data.prefs.autosave_secs {
+            self.last_save = Instant::now();
+            self.write_autosave(&path, data, io);
+        }
+    }
+
+    fn write_journal(&mut self, path: &Path, scribble: &ScribbleState, io: &ProjectIo) {
+        match &self.journaled {
+            Some(journaled) if journaled.same(scribble) => return,
+            Some(journaled) => {
+                // Working out what changed is cheap (everything is behind `Arc`s), but writing it
+                // out isn't.
+                let (entry, samples) = Entry::new(journaled, scribble);
+                let path = path.to_owned();
+                io.run_in_background(move || {
+                    if let Err(e) = append_entry(&path, &entry, &samples) {
+                        log::error!("error writing to the journal: {}", e);
+                    }
+                });
+            }
+            None => {}
+        }
+        self.journaled = Some(scribble.clone());
+    }
+
+    fn write_autosave(&mut self, path: &Path, data: &AppState, io: &ProjectIo) {
+        if self
+            .saved
+            .as_ref()
+            .map_or(false, |s| s.same(&data.scribble))
+        {
+            return;
+        }
+        self.saved = Some(data.scribble.clone());
+
+        let path = path.to_owned();
+        let save_file = data.scribble.to_save_file();
+        let format = data.prefs.save_format;
+        io.run_in_background(move || write_autosave(&path, save_file, format));
+    }
+}
+
+fn write_autosave(path: &Path, save_file: SaveFileData, format: SaveFormat) {
+    // Everything in the journal so far is about to be included in the autosave, but we can't
+    // delete it until the autosave has been written. (If the old journal is still there, the
+    // previous autosave failed; in that case we just leave the journal alone, since replaying it
+    // twice does no harm.)
+    let journal = path.with_extension(JOURNAL);
+    let old_journal = path.with_extension(OLD_JOURNAL);
+    let mut old_samples = Vec::new();
+    if journal.exists() && !old_journal.exists() {
+        match std::fs::rename(&journal, &old_journal) {
+            Ok(()) => {
+                // The journal's samples are about to be included in the autosave too.
+                if let Ok(entries) = std::fs::read_dir(path.with_extension(JOURNAL_AUDIO)) {
+                    old_samples = entries.flatten().map(|e| e.path()).collect();
+                }
+            }
+            Err(e) => log::error!("error moving the journal: {}", e),
+        }
+    }
+
+    match save_file.save_to_path(path, format) {
+        Ok(()) => {
+            if old_journal.exists() {
+                if let Err(e) = std::fs::remove_file(&old_journal) {
+                    log::error!("error removing the old journal: {}", e);
+                }
+            }
+            for samples in &old_samples {
+                if let Err(e) = std::fs::remove_file(samples) {
+                    log::error!("error removing the old journal audio {:?}: {}", samples, e);
+                }
+            }
+        }
+        Err(e) => log::error!("error autosaving to {:?}: {}", path, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scribble_curves::{time, Diff};
+
+    #[test]
+    fn names() {
+        assert_eq!(file_name(None), "untitled.scb");
+        assert_eq!(
+            file_name(Some(Path::new("/home/me/lecture 1.scb"))),
+            "_home_me_lecture_1_scb.scb"
+        );
+    }
+
+    #[test]
+    fn journal() {
+        let old = ScribbleState::default();
+        let mut new = old.clone();
+        new.audio_snippets = AudioSnippetsData::default()
+            .with_new_snippet(AudioSnippetData::new(vec![1, 2, 3], time::ZERO))
+            .0;
+        new.settings.title = "Journaled".to_owned();
+        // Moving the snippet afterwards doesn't write its samples again.
+        let (id, snip) = new.audio_snippets.snippets().next().unwrap();
+        let mut moved = new.clone();
+        moved.audio_snippets = new
+            .audio_snippets
+            .with_changes(vec![(id, snip.shifted(Diff::from_micros(1000)))], &[]);
+        let (move_entry, move_samples) = Entry::new(&new, &moved);
+        assert!(move_samples.is_empty());
+
+        let dir = std::env::temp_dir().join(format!("scribble-journal-{}", std::process::id()));
+        let path = dir.join("autosave.scb");
+        let (entry, samples) = Entry::new(&old, &new);
+        assert_eq!(samples.len(), 1);
+        append_entry(&path, &entry, &samples).unwrap();
+        // Replaying the same entry twice is the same as replaying it once.
+        let (entry, samples) = Entry::new(&old, &new);
+        append_entry(&path, &entry, &samples).unwrap();
+        append_entry(&path, &move_entry, &move_samples).unwrap();
+        // A partially-written entry gets ignored.
+        OpenOptions::new()
+            .append(true)
+            .open(path.with_extension(JOURNAL))
+            .unwrap()
+            .write_all(b"{\"snippets\":")
+            .unwrap();
+
+        let mut replayed = old;
+        replay(&path, JOURNAL, &mut replayed).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(replayed.settings.title, "Journaled");
+        let snips: Vec<_> = replayed.audio_snippets.snippets().collect();
+        assert_eq!(snips.len(), 1);
+        assert_eq!(snips[0].1.buf(), &[1, 2, 3][..]);
+        assert_eq!(snips[0].1.start_time(), Time::from_micros(1000));
+    }
+}
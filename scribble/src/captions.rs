@@ -0,0 +1,356 @@
+//! Timed captions, which are stored in the project and can be exported as subtitles.
+//!
+//! Captions can be imported from SRT or WebVTT subtitles (which already say when each caption
+//! appears), or from a plain-text script. A script has no times in it, so we line it up with
+//! the narration: each non-empty line becomes a caption, and the captions share out the parts of
+//! the audio where someone is talking in proportion to how long they are.
+
+use anyhow::anyhow;
+use std::path::Path;
+
+use scribble_curves::{time, Diff, Time};
+use scribble_project::captions::MIN_CAPTION_DURATION;
+
+use crate::audio::AudioSnippetsData;
+use crate::time_remap::TimeRemap;
+
+pub use scribble_project::captions::{Caption, CaptionsData};
+
+/// When there's no narration to line a script up with, captions stay up long enough to be read
+/// at this many characters per second.
+const READING_SPEED: f64 = 15.0;
+
+/// Script lines longer than this get split at sentence breaks, if there are any.
+const MAX_CAPTION_CHARS: usize = 84;
+
+/// Does this path look like something that we can import captions from?
+pub fn is_caption_path(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| matches!(e.to_lowercase().as_str(), "srt" | "vtt" | "txt"))
+        .unwrap_or(false)
+}
+
+/// Writes the captions as SRT subtitles for a video whose times are mapped by `remap`. Captions
+/// that aren't in the exported part of the animation are left out.
+pub fn to_srt(captions: &CaptionsData, remap: &TimeRemap) -> String {
+    let mut ret = String::new();
+    let mapped = captions
+        .captions()
+        .iter()
+        .map(|c| (remap.out_time(c.start), remap.out_time(c.end), &c.text))
+        .filter(|(start, end, _)| start < end);
+    for (idx, (start, end, text)) in mapped.enumerate() {
+        ret.push_str(&format!(
+            "{}\n{} --> {}\n{}\n\n",
+            idx + 1,
+            format_timestamp(start),
+            format_timestamp(end),
+            text
+        ));
+    }
+    ret
+}
+
+/// Loads captions from a subtitle file or a script. Scripts get lined up with the narration in
+/// `audio`; if there isn't any, they start at `start` and go at reading speed.
+pub fn load(path: &Path, audio: &AudioSnippetsData, start: Time) -> anyhow::Result<CaptionsData> {
+    let text = std::fs::read_to_string(path)?;
+    let is_subtitles = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| matches!(e.to_lowercase().as_str(), "srt" | "vtt"))
+        .unwrap_or(false);
+    let captions = if is_subtitles {
+        parse_subtitles(&text)?
+    } else {
+        align(&script_lines(&text), &speech_spans(audio), start)
+    };
+    if captions.is_empty() {
+        return Err(anyhow!("there are no captions in {:?}", path));
+    }
+    Ok(CaptionsData::new(captions))
+}
+
+/// Parses SRT or WebVTT subtitles. Formatting tags and cue settings are kept as they are.
+pub fn parse_subtitles(text: &str) -> anyhow::Result<Vec<Caption>> {
+    let mut ret = Vec::new();
+    let mut lines = text.lines().map(|l| l.trim_end()).peekable();
+    while let Some(line) = lines.next() {
+        let (start, end) = match line.split_once("-->") {
+            Some(times) => times,
+            // Cue numbers, the WEBVTT header, notes, and blank lines.
+            None => continue,
+        };
+        let start = parse_timestamp(start)
+            .ok_or_else(|| anyhow!("bad start time in subtitle line \"{}\"", line))?;
+        // WebVTT can have cue settings after the end time.
+        let end = end.split_whitespace().next().and_then(parse_timestamp);
+        let end = end.ok_or_else(|| anyhow!("bad end time in subtitle line \"{}\"", line))?;
+
+        let mut text = Vec::new();
+        while let Some(line) = lines.peek() {
+            if line.trim().is_empty() {
+                break;
+            }
+            text.push(lines.next().unwrap().trim());
+        }
+        ret.push(Caption {
+            start,
+            end,
+            text: text.join("\n"),
+        });
+    }
+    Ok(ret)
+}
+
+// Parses a timestamp like "01:02:03,456" (SRT) or "02:03.456" (WebVTT).
+fn parse_timestamp(s: &str) -> Option<Time> {
+    let s = s.trim().replace(',', ".");
+    let mut parts = s.rsplit(':');
+    let secs: f64 = parts.next()?.parse().ok()?;
+    let mins: u64 = parts.next()?.parse().ok()?;
+    let hours: u64 = parts.next().map(|h| h.parse().ok()).unwrap_or(Some(0))?;
+    if parts.next().is_some() || secs < 0.0 {
+        return None;
+    }
+    let micros = (hours * 3600 + mins * 60) as f64 * 1e6 + secs * 1e6;
+    Some(Time::from_micros(micros.round() as i64))
+}
+
+fn format_timestamp(t: Time) -> String {
+    let ms = t.as_micros().max(0) / 1000;
+    format!(
+        "{:02}:{:02}:{:02},{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+/// Splits a script into captions: one for each non-empty line, except that long lines get split
+/// into sentences.
+pub fn script_lines(text: &str) -> Vec<String> {
+    let mut ret = Vec::new();
+    for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        if line.chars().count() <= MAX_CAPTION_CHARS {
+            ret.push(line.to_owned());
+            continue;
+        }
+        let mut sentence = String::new();
+        for word in line.split_whitespace() {
+            if !sentence.is_empty() {
+                sentence.push(' ');
+            }
+            sentence.push_str(word);
+            if word.ends_with(&['.', '?', '!'][..]) {
+                ret.push(std::mem::take(&mut sentence));
+            }
+        }
+        if !sentence.is_empty() {
+            ret.push(sentence);
+        }
+    }
+    ret
+}
+
+/// The parts of the animation in which someone is talking, sorted and non-overlapping.
+pub fn speech_spans(audio: &AudioSnippetsData) -> Vec<(Time, Time)> {
+    let mut spans = Vec::new();
+    for (_, snip) in audio.snippets() {
+        let mut talk_start = snip.start_time();
+        for (silence_start, silence_end) in crate::time_remap::find_silences(snip.buf()) {
+            let silence_start = snip.start_time() + (silence_start - time::ZERO);
+            spans.push((talk_start, silence_start));
+            talk_start = snip.start_time() + (silence_end - time::ZERO);
+        }
+        spans.push((talk_start, snip.end_time()));
+    }
+    spans.retain(|(start, end)| start < end);
+    spans.sort();
+
+    let mut ret: Vec<(Time, Time)> = Vec::new();
+    for (start, end) in spans {
+        match ret.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => ret.push((start, end)),
+        }
+    }
+    ret
+}
+
+/// Lines up some captions with the times at which someone is talking. Each caption gets a share
+/// of the talking time that's proportional to its length. If no one is talking, the captions go
+/// one after the other, starting at `start`.
+pub fn align(lines: &[String], speech: &[(Time, Time)], start: Time) -> Vec<Caption> {
+    let weights: Vec<f64> = lines.iter().map(|l| l.chars().count() as f64).collect();
+    let total_weight: f64 = weights.iter().sum();
+    let total_speech: i64 = speech.iter().map(|(s, e)| (*e - *s).as_micros()).sum();
+
+    let mut ret = Vec::new();
+    if total_speech == 0 || total_weight == 0.0 {
+        let mut time = start;
+        for line in lines {
+            let secs = line.chars().count() as f64 / READING_SPEED;
+            let len = Diff::from_micros((secs * 1e6).round() as i64).max(MIN_CAPTION_DURATION);
+            ret.push(Caption {
+                start: time,
+                end: time + len,
+                text: line.clone(),
+            });
+            time += len;
+        }
+        return ret;
+    }
+
+    // Turns an amount of talking time (counted from the start of the first span) into a time
+    // in the animation. Captions that start at the end of a span actually start at the beginning
+    // of the next one, so that they don't show during the pause.
+    let at = |mut offset: i64, is_start: bool| {
+        for &(s, e) in speech {
+            let len = (e - s).as_micros();
+            if offset < len || (offset == len && !is_start) {
+                return s + Diff::from_micros(offset);
+            }
+            offset -= len;
+        }
+        speech.last().unwrap().1
+    };
+    let mut done = 0.0;
+    for (line, weight) in lines.iter().zip(&weights) {
+        let from = (done / total_weight * total_speech as f64).round() as i64;
+        done += weight;
+        let to = (done / total_weight * total_speech as f64).round() as i64;
+        ret.push(Caption {
+            start: at(from, true),
+            end: at(to, false),
+            text: line.clone(),
+        });
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn t(secs: f64) -> Time {
+        Time::from_micros((secs * 1e6).round() as i64)
+    }
+
+    fn caption(start: f64, end: f64, text: &str) -> Caption {
+        Caption {
+            start: t(start),
+            end: t(end),
+            text: text.to_owned(),
+        }
+    }
+
+    #[test]
+    fn srt() {
+        let srt = "1\n00:00:01,000 --> 00:00:02,500\nHello\nthere\n\n\
+                   2\n00:01:00,250 --> 01:00:00,000\nBye\n";
+        assert_eq!(
+            parse_subtitles(srt).unwrap(),
+            vec![
+                caption(1.0, 2.5, "Hello\nthere"),
+                caption(60.25, 3600.0, "Bye")
+            ]
+        );
+        assert!(parse_subtitles("1\n00:00:xx,000 --> 00:00:02,000\nHi\n").is_err());
+    }
+
+    #[test]
+    fn vtt() {
+        let vtt = "WEBVTT\n\nNOTE a comment\n\n00:01.000 --> 00:02.000 align:start\nHi\n";
+        assert_eq!(parse_subtitles(vtt).unwrap(), vec![caption(1.0, 2.0, "Hi")]);
+    }
+
+    #[test]
+    fn srt_round_trip() {
+        let captions = CaptionsData::new(vec![
+            caption(3725.5, 3726.0, "Later"),
+            caption(1.0, 2.5, "Hello\nthere"),
+        ]);
+        let remap = TimeRemap::new(t(0.0), t(4000.0), 1.0, &[], 1.0);
+        let srt = to_srt(&captions, &remap);
+        assert!(
+            srt.starts_with("1\n00:00:01,000 --> 00:00:02,500\nHello\nthere\n\n2\n01:02:05,500")
+        );
+        assert_eq!(parse_subtitles(&srt).unwrap(), captions.captions());
+    }
+
+    #[test]
+    fn srt_remapped() {
+        let captions = CaptionsData::new(vec![caption(1.0, 2.0, "Cut"), caption(4.0, 6.0, "Kept")]);
+        // Export from 3s onwards, at double speed.
+        let remap = TimeRemap::new(t(3.0), t(10.0), 2.0, &[], 1.0);
+        assert_eq!(
+            parse_subtitles(&to_srt(&captions, &remap)).unwrap(),
+            vec![caption(0.5, 1.5, "Kept")]
+        );
+    }
+
+    #[test]
+    fn split_script() {
+        let long = "This sentence is short. But this one goes on for quite a while, so that \
+                    the whole line is too long";
+        let script = format!("  First line\n\n{}\n", long);
+        assert_eq!(
+            script_lines(&script),
+            vec![
+                "First line".to_owned(),
+                "This sentence is short.".to_owned(),
+                "But this one goes on for quite a while, so that the whole line is too long"
+                    .to_owned(),
+            ]
+        );
+    }
+
+    #[test]
+    fn align_to_speech() {
+        let lines = vec!["aaaa".to_owned(), "bbbb".to_owned(), "cccccccc".to_owned()];
+        let speech = [(t(1.0), t(3.0)), (t(5.0), t(7.0))];
+        assert_eq!(
+            align(&lines, &speech, t(0.0)),
+            vec![
+                caption(1.0, 2.0, "aaaa"),
+                caption(2.0, 3.0, "bbbb"),
+                caption(5.0, 7.0, "cccccccc"),
+            ]
+        );
+    }
+
+    #[test]
+    fn align_without_speech() {
+        let lines = vec!["a".to_owned(), "b".repeat(30)];
+        assert_eq!(
+            align(&lines, &[], t(10.0)),
+            vec![
+                caption(10.0, 11.0, "a"),
+                caption(11.0, 13.0, &"b".repeat(30))
+            ]
+        );
+    }
+
+    #[test]
+    fn speech_in_audio() {
+        use crate::audio::{AudioSnippetData, SAMPLE_RATE};
+
+        // Half a second of talking, two seconds of silence, and another half second of talking.
+        let loud = vec![10000i16; SAMPLE_RATE as usize / 2];
+        let quiet = vec![0i16; SAMPLE_RATE as usize * 2];
+        let buf = [&loud[..], &quiet[..], &loud[..]].concat();
+        let audio = AudioSnippetsData::default()
+            .with_new_snippet(AudioSnippetData::new(buf, t(1.0)))
+            .0
+            // This overlaps with the end of the first snippet.
+            .with_new_snippet(AudioSnippetData::new(loud.clone(), t(3.75)))
+            .0;
+        assert_eq!(
+            speech_spans(&audio),
+            vec![(t(1.0), t(1.65)), (t(3.35), t(4.25))]
+        );
+    }
+}
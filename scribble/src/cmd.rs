@@ -1,9 +1,75 @@
-use druid::Selector;
+use druid::{Affine, Color, Command, Selector};
+use std::any::Any;
+use std::marker::PhantomData;
 use std::path::PathBuf;
 
-use scribble_curves::SnippetsData;
+use scribble_core::save_file::SaveFormat;
+use scribble_curves::time::TimeSpan;
+use scribble_curves::{SnippetData, Time};
 
-use crate::audio::AudioSnippetsData;
+use crate::audio::AudioSnippetData;
+use crate::collab::CollabRole;
+use crate::data::{AspectRatio, Dialog, Jump, MirrorMode, ScribbleState, SegmentInProgress};
+use crate::encode::ExportSettings;
+use crate::midi_sync::SyncEvent;
+use crate::prefs::Preferences;
+use crate::remote::RemoteRequest;
+use crate::remote_pen::PenEvent;
+
+/// A [`Selector`] for commands whose argument is a `T`.
+///
+/// Druid doesn't know the types of command arguments, so if the code sending a command and the
+/// code handling it disagree, we only find out at run time. Making the command with
+/// [`TypedSelector::with`] and reading it with [`TypedSelector::get`] means that the compiler
+/// checks that they agree. (Selectors for commands with no argument are just plain `Selector`s.)
+pub struct TypedSelector<T> {
+    selector: Selector,
+    marker: PhantomData<fn() -> T>,
+}
+
+// These can't be derived, because the derived versions would require `T: Copy`.
+impl<T> Clone for TypedSelector<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for TypedSelector<T> {}
+
+impl<T> TypedSelector<T> {
+    pub const fn new(name: &'static str) -> TypedSelector<T> {
+        TypedSelector {
+            selector: Selector::new(name),
+            marker: PhantomData,
+        }
+    }
+
+    pub const fn selector(&self) -> Selector {
+        self.selector
+    }
+}
+
+impl<T: Any> TypedSelector<T> {
+    /// Makes a command with this selector and the given argument.
+    pub fn with(self, arg: T) -> Command {
+        Command::new(self.selector, arg)
+    }
+
+    /// If `cmd` has this selector, returns its argument.
+    pub fn get(self, cmd: &Command) -> Option<&T> {
+        if cmd.selector != self.selector {
+            return None;
+        }
+        match cmd.get_object::<T>() {
+            Ok(arg) => Some(arg),
+            Err(e) => {
+                // This can only happen if someone bypassed `with` to make the command.
+                log::error!("bad argument for {:?}: {}", self.selector, e);
+                None
+            }
+        }
+    }
+}
 
 /// Starts recording a drawing. There is no argument.
 pub const DRAW: Selector = Selector::new("scribble.draw");
@@ -14,6 +80,31 @@ pub const TALK: Selector = Selector::new("scribble.talk");
 /// Starts playing. There is no argument.
 pub const PLAY: Selector = Selector::new("scribble.play");
 
+/// Starts recording a drawing if nothing is going on, and otherwise stops whatever is going on.
+/// There is no argument.
+pub const TOGGLE_DRAW: Selector = Selector::new("scribble.toggle-draw");
+
+/// Starts recording audio if nothing is going on, and otherwise stops whatever is going on.
+/// There is no argument.
+pub const TOGGLE_TALK: Selector = Selector::new("scribble.toggle-talk");
+
+/// Sent by the MIDI and foot pedal threads when a control gets pressed. The argument is the
+/// name of the control (see `crate::controllers`).
+pub const CONTROLLER_INPUT: TypedSelector<String> = TypedSelector::new("scribble.controller-input");
+
+/// Sent by the MIDI threads when another program tells us about its playback (see
+/// `crate::midi_sync`). The argument is a [`SyncEvent`].
+pub const MIDI_SYNC: TypedSelector<SyncEvent> = TypedSelector::new("scribble.midi-sync");
+
+/// Sent by the remote control threads when a client asks for something. The argument is a
+/// [`RemoteRequest`], which should get answered exactly once.
+pub const REMOTE_REQUEST: TypedSelector<RemoteRequest> =
+    TypedSelector::new("scribble.remote-request");
+
+/// Sent by the remote pen threads when a pen on another device does something (see
+/// `crate::remote_pen`). The argument is a [`PenEvent`].
+pub const REMOTE_PEN: TypedSelector<PenEvent> = TypedSelector::new("scribble.remote-pen");
+
 /*
 /// Pauses an animation. There is no argument.
 pub const PAUSE: Selector = Selector::new("scribble.pause");
@@ -23,45 +114,257 @@ pub const PAUSE: Selector = Selector::new("scribble.pause");
 pub const STOP: Selector = Selector::new("scribble.stop");
 
 /// Adds a new snippet. The argument is a [`SnippetData`].
-pub const ADD_SNIPPET: Selector = Selector::new("scribble.add-snippet");
+pub const ADD_SNIPPET: TypedSelector<SnippetData> = TypedSelector::new("scribble.add-snippet");
 
-/// Deletes a snipppet. The argument is an optional [`SnippetId`] or
-/// [`AudioSnippetId`]. If there is no argument, the currently selected snippet
-/// is deleted.
+/// Deletes the currently selected snippet. There is no argument.
 pub const DELETE_SNIPPET: Selector = Selector::new("scribble.delete-snippet");
 
+/// Puts a deleted snippet back. The argument is a `usize`, the snippet's index in the trash.
+pub const RESTORE_SNIPPET: TypedSelector<usize> = TypedSelector::new("scribble.restore-snippet");
+
 /// Adds a new audio snippet. The argument is an [`AudioSnippetData`].
-pub const ADD_AUDIO_SNIPPET: Selector = Selector::new("scribble.add-audio-snippet");
+pub const ADD_AUDIO_SNIPPET: TypedSelector<AudioSnippetData> =
+    TypedSelector::new("scribble.add-audio-snippet");
 
 /// Truncates the currently selected snippet at the current time. There is no
 /// argument.
 pub const TRUNCATE_SNIPPET: Selector = Selector::new("scribble.truncate-snippet");
 
+/// Changes the color of the selected snippet to the currently selected palette color. There is
+/// no argument.
+pub const RECOLOR_SNIPPET: Selector = Selector::new("scribble.recolor-snippet");
+
+/// Moves or scales the selected snippet. The argument is a `druid::Affine`, in image
+/// coordinates.
+pub const TRANSFORM_SNIPPET: TypedSelector<Affine> =
+    TypedSelector::new("scribble.transform-snippet");
+
+/// Speeds up or slows down the selected snippet. The argument is an `f64`; for example, 2.0
+/// makes the snippet play twice as fast.
+pub const CHANGE_SPEED: TypedSelector<f64> = TypedSelector::new("scribble.change-speed");
+
+/// Toggles whether the selected snippet plays backwards (i.e., un-draws itself). There is no
+/// argument.
+pub const REVERSE_SNIPPET: Selector = Selector::new("scribble.reverse-snippet");
+
+/// Does the most recent snippet edit (truncating, recoloring, moving, etc.) again, this time to
+/// the selected snippet. There is no argument.
+pub const REPEAT_LAST_EDIT: Selector = Selector::new("scribble.repeat-last-edit");
+
+/// Adds an image to the canvas at the current time. The argument is a `Vec<u8>` containing the
+/// contents of an image file.
+pub const ADD_IMAGE: TypedSelector<Vec<u8>> = TypedSelector::new("scribble.add-image");
+
+/// Sets the watermark that gets drawn on exported videos. The argument is an
+/// `Option<Vec<u8>>` containing the contents of an image file; if it is `None`, the
+/// watermark is removed.
+pub const SET_WATERMARK: TypedSelector<Option<Vec<u8>>> =
+    TypedSelector::new("scribble.set-watermark");
+
+/// Adds the image on the clipboard (if there is one) to the canvas. There is no argument.
+pub const PASTE_IMAGE: Selector = Selector::new("scribble.paste-image");
+
+/// Makes all the images that are currently visible disappear at the current time. There is no
+/// argument.
+pub const END_IMAGES: Selector = Selector::new("scribble.end-images");
+
+/// Changes whether new images fade in. The argument is a `bool`.
+pub const SET_FADE_IN_IMAGES: TypedSelector<bool> =
+    TypedSelector::new("scribble.set-fade-in-images");
+
 /// Adds a lerp to the selected snippet, lerping the current time to the marked time.
 pub const LERP_SNIPPET: Selector = Selector::new("scribble.lerp-snippet");
 
-/// Changes the current mark time. The argument is an optional [`Time`]. If it is
-/// not present, the current time will be used instead.
+/// Sets the mark to the current time. There is no argument.
 pub const SET_MARK: Selector = Selector::new("scribble.set-mark");
 
 /// Changes the current animation time. The argument is a [`Time`].
-pub const WARP_TIME_TO: Selector = Selector::new("scribble.warp-time-to");
+pub const WARP_TIME_TO: TypedSelector<Time> = TypedSelector::new("scribble.warp-time-to");
+
+/// Moves the cursor to somewhere interesting, like the mark or the start of the next snippet.
+/// The argument is a [`Jump`].
+pub const JUMP: TypedSelector<Jump> = TypedSelector::new("scribble.jump");
 
 /// Changes the pen color. The argument is a [`Color`].
-pub const CHOOSE_COLOR: Selector = Selector::new("scribble.choose-color");
+pub const CHOOSE_COLOR: TypedSelector<Color> = TypedSelector::new("scribble.choose-color");
+
+/// Changes whether newly drawn snippets fade out. The argument is a `bool`.
+pub const SET_FADE: TypedSelector<bool> = TypedSelector::new("scribble.set-fade");
+
+/// Switches to the next speed for the animation time while drawing.
+pub const NEXT_RECORDING_SPEED: Selector = Selector::new("scribble.next-recording-speed");
+
+/// Changes the mirror drawing mode. The argument is a [`MirrorMode`].
+pub const SET_MIRROR: TypedSelector<MirrorMode> = TypedSelector::new("scribble.set-mirror");
+
+/// Changes the plugin drawing tool. The argument is the tool's name, or an empty string for the
+/// plain pen.
+pub const SET_DRAWING_TOOL: TypedSelector<String> = TypedSelector::new("scribble.set-drawing-tool");
+
+/// Changes whether the coordinate and measure overlay is shown. The argument is a `bool`.
+pub const SET_SHOW_MEASURE: TypedSelector<bool> = TypedSelector::new("scribble.set-show-measure");
+
+/// Resets the canvas view to be unzoomed and unpanned. There is no argument.
+pub const RESET_ZOOM: Selector = Selector::new("scribble.reset-zoom");
+
+/// Changes the shape of the canvas. The argument is an [`AspectRatio`].
+pub const SET_ASPECT_RATIO: TypedSelector<AspectRatio> =
+    TypedSelector::new("scribble.set-aspect-ratio");
+
+/// Changes the background color of the canvas. The argument is a `Color`.
+pub const SET_BACKGROUND: TypedSelector<Color> = TypedSelector::new("scribble.set-background");
+
+/// Changes whether the pen position is shown during playback. The argument is a `bool`.
+pub const SET_SHOW_PEN: TypedSelector<bool> = TypedSelector::new("scribble.set-show-pen");
+
+/// Changes whether the captions are shown on the canvas. The argument is a `bool`.
+pub const SET_SHOW_CAPTIONS: TypedSelector<bool> = TypedSelector::new("scribble.set-show-captions");
+
+/// Starts or stops publishing the canvas to `Preferences::live_output_target`. The argument is a
+/// `bool`.
+pub const SET_LIVE_OUTPUT: TypedSelector<bool> = TypedSelector::new("scribble.set-live-output");
+
+/// Starts hosting or joining a collaboration on the current project (see `crate::collab`), or
+/// stops collaborating if the argument is `None`.
+pub const SET_COLLAB: TypedSelector<Option<CollabRole>> = TypedSelector::new("scribble.set-collab");
 
 /// Exports the current animation as a video. The argument is an [`ExportCmd`].
-pub const EXPORT: Selector = Selector::new("scribble.export");
+pub const EXPORT: TypedSelector<ExportCmd> = TypedSelector::new("scribble.export");
 
 /// Appends a new segment to the currently-drawing snippet. The argument is a [`SegmentInProgress`].
-pub const APPEND_NEW_SEGMENT: Selector = Selector::new("scribble.append-new-segment");
+pub const APPEND_NEW_SEGMENT: TypedSelector<SegmentInProgress> =
+    TypedSelector::new("scribble.append-new-segment");
+
+/// Opens a dialog. The argument is a [`Dialog`].
+pub const SHOW_DIALOG: TypedSelector<Dialog> = TypedSelector::new("scribble.show-dialog");
+
+/// Closes the currently open dialog. There is no argument.
+pub const CLOSE_DIALOG: Selector = Selector::new("scribble.close-dialog");
+
+/// Replaces the animation with the autosaved version of the current project. There is no
+/// argument.
+pub const RESTORE_AUTOSAVE: Selector = Selector::new("scribble.restore-autosave");
+
+/// Replaces the preferences (and saves them). The argument is a [`Preferences`].
+pub const SET_PREFERENCES: TypedSelector<Preferences> =
+    TypedSelector::new("scribble.set-preferences");
+
+/// Changes the format that projects are saved in. The argument is a [`SaveFormat`].
+pub const SET_SAVE_FORMAT: TypedSelector<SaveFormat> =
+    TypedSelector::new("scribble.set-save-format");
+
+/// Saves the project. The argument is a `PathBuf`.
+pub const SAVE_PROJECT: TypedSelector<PathBuf> = TypedSelector::new("scribble.save-project");
+
+/// Opens a saved project. The argument is a `PathBuf`.
+pub const OPEN_PROJECT: TypedSelector<PathBuf> = TypedSelector::new("scribble.open-project");
+
+/// Adds the snippets from another saved project to the current one, starting at the cursor
+/// (plus `AppState::import_offset`). The argument is a `PathBuf`.
+pub const IMPORT_PROJECT: TypedSelector<PathBuf> = TypedSelector::new("scribble.import-project");
+
+/// Adds an Excalidraw or tldraw drawing to the animation at the cursor, as a single snippet. The
+/// argument is a `PathBuf`.
+pub const IMPORT_DRAWING: TypedSelector<PathBuf> = TypedSelector::new("scribble.import-drawing");
+
+/// Replaces the project's captions with ones loaded from a subtitle file or a script (see
+/// `crate::captions::load`). The argument is a `PathBuf`.
+pub const IMPORT_CAPTIONS: TypedSelector<PathBuf> = TypedSelector::new("scribble.import-captions");
+
+/// Turns the selected audio snippet (or all of them, if no audio snippet is selected) into
+/// captions, replacing any captions that were there. This happens in the background.
+pub const TRANSCRIBE: Selector = Selector::new("scribble.transcribe");
+
+/// Adds an audio file to the animation at the cursor, either linked or embedded depending on
+/// `Preferences::link_audio`. The argument is a `PathBuf`.
+pub const ADD_AUDIO_FILE: TypedSelector<PathBuf> = TypedSelector::new("scribble.add-audio-file");
+
+/// Opens some files that were dropped onto the window: projects get opened, audio gets added at
+/// the cursor, and images become backgrounds. The argument is a `Vec<PathBuf>`.
+///
+/// druid doesn't tell widgets about dropped files yet, so for now these come from the remote
+/// control (see `crate::remote`), which file-manager scripts can use.
+pub const DROP_FILES: TypedSelector<Vec<PathBuf>> = TypedSelector::new("scribble.drop-files");
+
+/// Replaces a missing linked audio file with another one. The argument is a `PathBuf`; the file
+/// that it replaces is the first missing one.
+pub const RELINK_AUDIO: TypedSelector<PathBuf> = TypedSelector::new("scribble.relink-audio");
+
+/// Opens a new tab with an empty project. There is no argument.
+pub const NEW_TAB: Selector = Selector::new("scribble.new-tab");
+
+/// Closes the current tab. There is no argument.
+pub const CLOSE_TAB: Selector = Selector::new("scribble.close-tab");
+
+/// Switches to another tab. The argument is the index of the tab, as a `usize`.
+pub const SWITCH_TAB: TypedSelector<usize> = TypedSelector::new("scribble.switch-tab");
+
+/// Copies the selected snippet, so that it can be pasted into this project or another one.
+/// There is no argument.
+pub const COPY_SNIPPET: Selector = Selector::new("scribble.copy-snippet");
+
+/// Pastes the most recently copied snippet at the cursor. There is no argument.
+pub const PASTE_SNIPPET: Selector = Selector::new("scribble.paste-snippet");
+
+/// Replaces the palette with the colors from a palette file. The argument is a `PathBuf`.
+pub const LOAD_PALETTE: TypedSelector<PathBuf> = TypedSelector::new("scribble.load-palette");
+
+/// Saves the palette to a palette file. The argument is a `PathBuf`.
+pub const SAVE_PALETTE: TypedSelector<PathBuf> = TypedSelector::new("scribble.save-palette");
+
+/// Changes whether imported audio files are linked or embedded. The argument is a `bool`.
+pub const SET_LINK_AUDIO: TypedSelector<bool> = TypedSelector::new("scribble.set-link-audio");
+
+/// Starts an undo group: everything that changes until the matching `END_UNDO_GROUP` will be
+/// undone in a single step. There is no argument.
+pub const BEGIN_UNDO_GROUP: Selector = Selector::new("scribble.begin-undo-group");
+
+/// Ends an undo group. There is no argument.
+pub const END_UNDO_GROUP: Selector = Selector::new("scribble.end-undo-group");
+
+/// Switches to an abandoned branch of the undo history. The argument is a `usize`, the index of
+/// the branch (see `UndoStack::branches`).
+pub const SWITCH_BRANCH: TypedSelector<usize> = TypedSelector::new("scribble.switch-branch");
+
+/// Saves the project, and then goes ahead with whatever the "unsaved changes" dialog held up.
+/// There is no argument.
+pub const SAVE_CHANGES: Selector = Selector::new("scribble.save-changes");
+
+/// Throws away the unsaved changes, and goes ahead with whatever the "unsaved changes" dialog
+/// held up. There is no argument.
+pub const DISCARD_CHANGES: Selector = Selector::new("scribble.discard-changes");
 
 /// Recreate the menus. There is no argument.
 pub const REBUILD_MENUS: Selector = Selector::new("scribble.rebuild-menus");
 
 #[derive(Clone)]
 pub struct ExportCmd {
-    pub snippets: SnippetsData,
-    pub audio_snippets: AudioSnippetsData,
+    /// A snapshot of the animation, taken when the export was requested. Since all the
+    /// snippets are behind `Arc`s this is cheap, and it means that the user can keep editing
+    /// (or playing back) the animation while the export runs.
+    pub scribble: ScribbleState,
     pub filename: PathBuf,
+    pub export: ExportSettings,
+    /// If true, the exported video will show the position of the pen.
+    pub show_pen: bool,
+    /// The time of the timeline's cursor when the export was requested.
+    pub cursor: Time,
+    /// If set, only this part of the animation is exported.
+    pub range: Option<TimeSpan>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typed_selectors() {
+        let switch = SWITCH_TAB.with(3);
+        assert_eq!(SWITCH_TAB.get(&switch), Some(&3));
+        assert_eq!(SWITCH_BRANCH.get(&switch), None);
+
+        // A command with the right selector but the wrong argument is ignored, not a panic.
+        let bad = Command::new(SWITCH_TAB.selector, "3");
+        assert_eq!(SWITCH_TAB.get(&bad), None);
+    }
 }
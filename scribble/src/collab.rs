@@ -0,0 +1,1179 @@
+//! Collaborating on an animation over the network, so that (for example) a teacher and a TA can
+//! build one together.
+//!
+//! One scribble hosts, listening on `Preferences::collab_port` (on localhost, unless
+//! `Preferences::collab_interface` says otherwise), and the others join it at
+//! `Preferences::collab_address`. A guest starts by sending the collaboration password, and the
+//! host hangs up on guests that get it wrong. Then the host sends the whole animation, and after
+//! that everyone sends their edits as they make them, as lines of JSON.
+//!
+//! An edit says what the snippets that were added, changed or removed look like afterwards,
+//! rather than how they changed (like the entries in the autosave journal). Retiming or
+//! recoloring a snippet just replaces it, so two edits to the same snippet never need to be
+//! merged: the later one wins. The host decides what "later" means. It applies its own edits and
+//! the ones that arrive from guests in some order, numbers them, and sends every one of them to
+//! every guest, including the guest that made it. Each guest keeps the animation as of the last
+//! numbered edit, and shows its own edits that the host hasn't sent back yet on top of that. So
+//! once the edits stop, everyone ends up with the host's animation.
+//!
+//! The host fits each guest edit into its own animation first. Changes to snippets that someone
+//! else has removed in the meantime get dropped, so a removal beats a concurrent change. And
+//! since everyone picks the ids of their new snippets themselves, two people can pick the same
+//! one. When a guest adds a snippet under an id that the host already has, the host gives the
+//! snippet a new id (and keeps track of it, so that the guest's later edits to that snippet go to
+//! the right place).
+//!
+//! Only the snippets (of drawing, audio and images) and the project settings are shared:
+//! everyone has their own selection, mark and undo history. (But undoing someone else's edit
+//! undoes it for everyone.) Audio that is linked rather than embedded gets sent as a path, so it
+//! only plays for people who have the same file. The strokes that people are in the middle of
+//! drawing aren't part of the animation yet, but they get sent along a few times a second so
+//! that everyone can watch.
+
+use druid::Data;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use scribble_core::save_file::SaveFileData;
+use scribble_core::undo::{UndoStack, COALESCE_WINDOW};
+use scribble_curves::{Curve, SnippetData, SnippetId, SnippetsData};
+
+use crate::audio::{AudioSnippetData, AudioSnippetId, AudioSnippetsData};
+use crate::data::{AppState, MaybeSnippetId, ProjectSettings, ScribbleState};
+use crate::images::{ImageSnippetData, ImageSnippetId, ImageSnippetsData};
+
+/// How often the listening thread checks whether it should stop.
+const ACCEPT_POLL: Duration = Duration::from_millis(200);
+
+/// How long a guest waits for the host to answer when joining.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The longest `Hello` that the host will read.
+const MAX_HELLO_LEN: u64 = 4096;
+
+/// How often we send the strokes that we're drawing.
+const STROKE_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Data, Debug, PartialEq)]
+pub enum CollabRole {
+    Host,
+    Guest,
+}
+
+/// The three kinds of snippet collections, which all get shared in the same way.
+trait SnippetMap: Clone {
+    type Id: Copy + Ord;
+    type Snippet: Clone;
+
+    fn changes_since(&self, old: &Self) -> (Vec<(Self::Id, Self::Snippet)>, Vec<Self::Id>);
+    fn with_changes(&self, changed: Vec<(Self::Id, Self::Snippet)>, removed: &[Self::Id]) -> Self;
+    fn contains(&self, id: Self::Id) -> bool;
+    /// Adds a snippet under a new id, returning that id.
+    fn with_new(&self, snip: Self::Snippet) -> (Self, Self::Id);
+}
+
+macro_rules! impl_snippet_map {
+    ($map:ty, $id:ty, $snip:ty, $add:expr) => {
+        impl SnippetMap for $map {
+            type Id = $id;
+            type Snippet = $snip;
+
+            fn changes_since(&self, old: &Self) -> (Vec<($id, $snip)>, Vec<$id>) {
+                <$map>::changes_since(self, old)
+            }
+
+            fn with_changes(&self, changed: Vec<($id, $snip)>, removed: &[$id]) -> Self {
+                <$map>::with_changes(self, changed, removed)
+            }
+
+            fn contains(&self, id: $id) -> bool {
+                <$map>::contains(self, id)
+            }
+
+            fn with_new(&self, snip: $snip) -> (Self, $id) {
+                let add: fn(&$map, $snip) -> $map = $add;
+                let ret = add(self, snip);
+                let (mut added, _) = ret.changes_since(self);
+                (ret, added.pop().unwrap().0)
+            }
+        }
+    };
+}
+
+impl_snippet_map!(SnippetsData, SnippetId, SnippetData, |snips, snip| snips
+    .with_new_snippet(snip)
+    .0);
+impl_snippet_map!(
+    AudioSnippetsData,
+    AudioSnippetId,
+    AudioSnippetData,
+    |snips, snip| snips.with_new_snippet(snip).0
+);
+impl_snippet_map!(
+    ImageSnippetsData,
+    ImageSnippetId,
+    ImageSnippetData,
+    |snips, snip| snips.with_new_snippet(snip)
+);
+
+/// The edits to one kind of snippet.
+#[derive(Clone, Deserialize, Serialize)]
+struct MapEdit<Id, T> {
+    /// The snippets that are new to whoever sent the edit.
+    added: Vec<(Id, T)>,
+    changed: Vec<(Id, T)>,
+    removed: Vec<Id>,
+}
+
+impl<Id, T> MapEdit<Id, T> {
+    fn empty() -> MapEdit<Id, T> {
+        MapEdit {
+            added: Vec::new(),
+            changed: Vec::new(),
+            removed: Vec::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+}
+
+fn map_edit<M: SnippetMap>(old: &M, new: &M) -> MapEdit<M::Id, M::Snippet> {
+    let (changed, removed) = new.changes_since(old);
+    let (added, changed) = changed.into_iter().partition(|(id, _)| !old.contains(*id));
+    MapEdit {
+        added,
+        changed,
+        removed,
+    }
+}
+
+fn apply_map_edit<M: SnippetMap>(map: &M, edit: &MapEdit<M::Id, M::Snippet>) -> M {
+    if edit.is_empty() {
+        return map.clone();
+    }
+    let changed = edit.added.iter().chain(&edit.changed).cloned().collect();
+    map.with_changes(changed, &edit.removed)
+}
+
+// Gives new names to the snippets that a guest refers to by ids that the host has renamed.
+fn rename_map_edit<Id: Copy + PartialEq, T>(
+    edit: MapEdit<Id, T>,
+    renames: &[(Id, Id)],
+) -> MapEdit<Id, T> {
+    let rename = |id: Id| {
+        renames
+            .iter()
+            .find(|(old, _)| *old == id)
+            .map(|(_, new)| *new)
+            .unwrap_or(id)
+    };
+    MapEdit {
+        added: edit.added,
+        changed: edit
+            .changed
+            .into_iter()
+            .map(|(id, snip)| (rename(id), snip))
+            .collect(),
+        removed: edit.removed.into_iter().map(rename).collect(),
+    }
+}
+
+// Fits an edit from a guest into the host's `map`. Added snippets whose ids are already taken get
+// new ids, and changes to snippets that someone else has removed in the meantime get dropped (so
+// a removal beats a concurrent change). Returns the edit as it should be applied, and the ids
+// that were renamed.
+fn rebase_map_edit<M: SnippetMap>(
+    map: &M,
+    edit: MapEdit<M::Id, M::Snippet>,
+) -> (MapEdit<M::Id, M::Snippet>, Vec<(M::Id, M::Id)>) {
+    let mut map = map.clone();
+    let mut renames = Vec::new();
+    let mut added = Vec::new();
+    for (id, snip) in edit.added {
+        let id = if map.contains(id) {
+            let (new_map, new_id) = map.with_new(snip.clone());
+            map = new_map;
+            renames.push((id, new_id));
+            new_id
+        } else {
+            map = map.with_changes(vec![(id, snip.clone())], &[]);
+            id
+        };
+        added.push((id, snip));
+    }
+    let rebased = MapEdit {
+        added,
+        changed: edit
+            .changed
+            .into_iter()
+            .filter(|(id, _)| map.contains(*id))
+            .collect(),
+        removed: edit
+            .removed
+            .into_iter()
+            .filter(|id| map.contains(*id))
+            .collect(),
+    };
+    (rebased, renames)
+}
+
+/// The ids that the host gave to snippets that a guest added under ids that were already taken.
+struct Renames {
+    snippets: Vec<(SnippetId, SnippetId)>,
+    audio: Vec<(AudioSnippetId, AudioSnippetId)>,
+    images: Vec<(ImageSnippetId, ImageSnippetId)>,
+}
+
+impl Renames {
+    fn is_empty(&self) -> bool {
+        self.snippets.is_empty() && self.audio.is_empty() && self.images.is_empty()
+    }
+}
+
+/// Some edits to the animation.
+#[derive(Clone, Deserialize, Serialize)]
+struct Edit {
+    snippets: MapEdit<SnippetId, SnippetData>,
+    audio: MapEdit<AudioSnippetId, AudioSnippetData>,
+    images: MapEdit<ImageSnippetId, ImageSnippetData>,
+    settings: Option<ProjectSettings>,
+}
+
+// Someone else might have deleted the selected snippet.
+fn fix_selection(state: &mut ScribbleState) {
+    let selection_exists = match state.selected_snippet {
+        MaybeSnippetId::Draw(id) => state.snippets.contains(id),
+        MaybeSnippetId::Audio(id) => state.audio_snippets.contains(id),
+        MaybeSnippetId::None => true,
+    };
+    if !selection_exists {
+        state.selected_snippet = MaybeSnippetId::None;
+    }
+}
+
+impl Edit {
+    fn new(old: &ScribbleState, new: &ScribbleState) -> Edit {
+        Edit {
+            snippets: map_edit(&old.snippets, &new.snippets),
+            audio: map_edit(&old.audio_snippets, &new.audio_snippets),
+            images: map_edit(&old.images, &new.images),
+            settings: if new.settings.same(&old.settings) {
+                None
+            } else {
+                Some(new.settings.clone())
+            },
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.snippets.is_empty()
+            && self.audio.is_empty()
+            && self.images.is_empty()
+            && self.settings.is_none()
+    }
+
+    fn apply(&self, state: &mut ScribbleState) {
+        state.snippets = apply_map_edit(&state.snippets, &self.snippets);
+        state.audio_snippets = apply_map_edit(&state.audio_snippets, &self.audio);
+        state.images = apply_map_edit(&state.images, &self.images);
+        if let Some(settings) = &self.settings {
+            state.settings = settings.clone();
+        }
+
+        // Linked audio arrives without its samples.
+        if !self.audio.is_empty() {
+            state.audio_snippets = state.audio_snippets.with_links_loaded();
+        }
+        fix_selection(state);
+    }
+
+    fn renamed(self, renames: &Renames) -> Edit {
+        Edit {
+            snippets: rename_map_edit(self.snippets, &renames.snippets),
+            audio: rename_map_edit(self.audio, &renames.audio),
+            images: rename_map_edit(self.images, &renames.images),
+            settings: self.settings,
+        }
+    }
+
+    // See `rebase_map_edit`.
+    fn rebase(self, state: &ScribbleState) -> (Edit, Renames) {
+        let (snippets, snippet_renames) = rebase_map_edit(&state.snippets, self.snippets);
+        let (audio, audio_renames) = rebase_map_edit(&state.audio_snippets, self.audio);
+        let (images, image_renames) = rebase_map_edit(&state.images, self.images);
+        let edit = Edit {
+            snippets,
+            audio,
+            images,
+            settings: self.settings,
+        };
+        let renames = Renames {
+            snippets: snippet_renames,
+            audio: audio_renames,
+            images: image_renames,
+        };
+        (edit, renames)
+    }
+}
+
+/// The first line that a guest sends. The host doesn't tell it anything until it has checked this.
+#[derive(Deserialize, Serialize)]
+struct Hello {
+    password: String,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "message", rename_all = "kebab-case")]
+enum Message {
+    /// The whole animation, which the host sends to guests when they join. `you` is the id that
+    /// the host knows the guest by, and `seq` is the number of the last sequenced edit that's
+    /// included in the animation.
+    Welcome {
+        project: SaveFileData,
+        you: u64,
+        seq: u64,
+    },
+    /// An edit that a guest made, on its way to the host. `seq` counts the guest's edits, and
+    /// `base` is the last sequenced edit that the guest had applied when it made this one.
+    Edit { edit: Edit, seq: u64, base: u64 },
+    /// An edit in the host's order, which goes to everyone (including whoever made it). `from` is
+    /// the guest that made it (or zero, for the host), and `ack` is that guest's `seq` for it.
+    Sequenced {
+        edit: Edit,
+        seq: u64,
+        from: u64,
+        ack: u64,
+    },
+    /// The strokes that someone is drawing right now (or nothing, if they've stopped). When the
+    /// host passes these on, it fills in `from` so that the guests can tell people apart.
+    Strokes { from: u64, curves: Vec<Curve> },
+}
+
+/// The host's side of the edits: it puts everyone's edits in order.
+#[derive(Default)]
+struct Sequencer {
+    // The number of edits sequenced so far.
+    seq: u64,
+    // For each guest, the renames that it might not have heard about yet, and the sequence
+    // numbers of the edits that did the renaming.
+    renames: BTreeMap<u64, Vec<(u64, Renames)>>,
+}
+
+impl Sequencer {
+    /// Sequences an edit that the host made (and has already applied).
+    fn local(&mut self, edit: Edit) -> Message {
+        self.seq += 1;
+        Message::Sequenced {
+            edit,
+            seq: self.seq,
+            from: 0,
+            ack: 0,
+        }
+    }
+
+    /// Fits an edit from a guest into the host's animation, applies it, and sequences it.
+    fn accept(
+        &mut self,
+        state: &mut ScribbleState,
+        from: u64,
+        edit: Edit,
+        seq: u64,
+        base: u64,
+    ) -> Message {
+        let renames = self.renames.entry(from).or_default();
+        // The guest has heard about the renames up to `base`, and uses the new ids since then.
+        renames.retain(|(renamed_at, _)| *renamed_at > base);
+        let edit = renames.iter().fold(edit, |edit, (_, r)| edit.renamed(r));
+        let (edit, new_renames) = edit.rebase(state);
+        edit.apply(state);
+        self.seq += 1;
+        if !new_renames.is_empty() {
+            renames.push((self.seq, new_renames));
+        }
+        Message::Sequenced {
+            edit,
+            seq: self.seq,
+            from,
+            ack: seq,
+        }
+    }
+
+    fn forget(&mut self, guest: u64) {
+        self.renames.remove(&guest);
+    }
+}
+
+/// A guest's side of the edits: the host's edits are the truth, and the guest's own edits get
+/// applied on top of them until the host has sequenced them.
+struct Follower {
+    // The id that the host knows us by.
+    me: u64,
+    // The animation as of the last sequenced edit (only the shared parts matter).
+    confirmed: ScribbleState,
+    // The number of the last sequenced edit.
+    base: u64,
+    // The number of edits that we've sent.
+    sent: u64,
+    // The edits that we've sent but the host hasn't sequenced yet, with their numbers.
+    pending: VecDeque<(u64, Edit)>,
+}
+
+impl Follower {
+    fn new(me: u64, confirmed: ScribbleState, base: u64) -> Follower {
+        Follower {
+            me,
+            confirmed,
+            base,
+            sent: 0,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Makes the message for sending one of our edits to the host.
+    fn propose(&mut self, edit: Edit) -> Message {
+        self.sent += 1;
+        self.pending.push_back((self.sent, edit.clone()));
+        Message::Edit {
+            edit,
+            seq: self.sent,
+            base: self.base,
+        }
+    }
+
+    fn receive(&mut self, edit: &Edit, seq: u64, from: u64, ack: u64) {
+        edit.apply(&mut self.confirmed);
+        self.base = seq;
+        if from == self.me {
+            self.pending.retain(|(s, _)| *s > ack);
+        }
+    }
+
+    /// Replaces the shared parts of `state` with the sequenced animation, plus our own edits that
+    /// haven't been sequenced yet.
+    fn rebuild(&self, state: &mut ScribbleState) {
+        state.snippets = self.confirmed.snippets.clone();
+        state.audio_snippets = self.confirmed.audio_snippets.clone();
+        state.images = self.confirmed.images.clone();
+        state.settings = self.confirmed.settings.clone();
+        for (_, edit) in &self.pending {
+            edit.apply(state);
+        }
+        fix_selection(state);
+    }
+}
+
+enum Event {
+    Joined(Peer),
+    Received(u64, Message),
+    Left(u64),
+}
+
+/// Someone on the other end of a connection: for the host, a guest, and for a guest, the host.
+struct Peer {
+    id: u64,
+    // Lines to send, which get written by a background thread.
+    outgoing: Sender<Arc<String>>,
+    // Shutting this down stops the background threads.
+    stream: TcpStream,
+}
+
+impl Peer {
+    /// Starts talking to someone. Their messages (and the news that they've left) get sent to
+    /// `events`.
+    fn new(stream: TcpStream, id: u64, events: Sender<Event>) -> std::io::Result<Peer> {
+        stream.set_nonblocking(false)?;
+        stream.set_nodelay(true)?;
+        let reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream.try_clone()?;
+        let (outgoing, lines) = channel::<Arc<String>>();
+
+        std::thread::spawn(move || {
+            for line in reader.lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(e) => {
+                        log::warn!("lost the connection to collaborator {}: {}", id, e);
+                        break;
+                    }
+                };
+                match serde_json::from_str(&line) {
+                    Ok(msg) => {
+                        if events.send(Event::Received(id, msg)).is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => log::error!("bad message from collaborator {}: {}", id, e),
+                }
+            }
+            let _ = events.send(Event::Left(id));
+        });
+        std::thread::spawn(move || {
+            for line in lines {
+                if let Err(e) = writer
+                    .write_all(line.as_bytes())
+                    .and_then(|_| writer.flush())
+                {
+                    log::warn!("failed to send to collaborator {}: {}", id, e);
+                    return;
+                }
+            }
+        });
+        Ok(Peer {
+            id,
+            outgoing,
+            stream,
+        })
+    }
+
+    fn send(&self, line: &Arc<String>) {
+        // If this fails, the connection is gone and the reading thread will tell us about it.
+        let _ = self.outgoing.send(Arc::clone(line));
+    }
+}
+
+impl Drop for Peer {
+    fn drop(&mut self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+fn to_line(msg: &Message) -> Option<Arc<String>> {
+    match serde_json::to_string(msg) {
+        Ok(mut line) => {
+            line.push('\n');
+            Some(Arc::new(line))
+        }
+        Err(e) => {
+            log::error!("failed to serialize a message for collaborators: {}", e);
+            None
+        }
+    }
+}
+
+// Compares passwords without leaking (through how long it takes) how much of them matched.
+fn same_password(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Reads the `Hello` that someone sends when they connect, and checks its password. (Remote pens
+/// log in the same way as guests do.)
+pub fn authenticate(stream: &TcpStream, password: &str) -> anyhow::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+    // This doesn't go through a `BufReader`, because that could read past the end of the line.
+    let mut line = Vec::new();
+    for byte in stream.take(MAX_HELLO_LEN).bytes() {
+        match byte? {
+            b'\n' => break,
+            b => line.push(b),
+        }
+    }
+    let hello: Hello = serde_json::from_slice(&line)?;
+    if !same_password(&hello.password, password) {
+        return Err(anyhow::anyhow!("wrong password"));
+    }
+    stream.set_read_timeout(None)?;
+    Ok(())
+}
+
+// The listener is non-blocking, so that we notice when we're asked to stop. Guests get checked
+// on their own threads, so that a slow one doesn't hold up the others.
+fn listen(listener: TcpListener, password: String, events: Sender<Event>, stop: Arc<AtomicBool>) {
+    if let Err(e) = listener.set_nonblocking(true) {
+        log::error!("failed to set up collaboration: {}", e);
+        return;
+    }
+    let password = Arc::new(password);
+    let mut next_id = 1;
+    while !stop.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, addr)) => {
+                let id = next_id;
+                next_id += 1;
+                let password = Arc::clone(&password);
+                let events = events.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = authenticate(&stream, &password) {
+                        log::warn!("turned away a collaborator from {}: {}", addr, e);
+                        return;
+                    }
+                    log::info!("collaborator {} joined from {}", id, addr);
+                    match Peer::new(stream, id, events.clone()) {
+                        Ok(peer) => {
+                            let _ = events.send(Event::Joined(peer));
+                        }
+                        Err(e) => log::error!("failed to talk to collaborator {}: {}", id, e),
+                    }
+                });
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                std::thread::sleep(ACCEPT_POLL);
+            }
+            Err(e) => {
+                log::error!("collaboration error: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+// Who puts the edits in order: the host does, and the guests follow along.
+enum Side {
+    Host(Sequencer),
+    // The guest gets its `Follower` once the host has sent the animation.
+    Guest(Option<Follower>),
+}
+
+pub struct Collab {
+    side: Side,
+    events: Receiver<Event>,
+    // For the host, the guests. For a guest, just the host.
+    peers: Vec<Peer>,
+    // The host's listening thread.
+    listener: Option<(Arc<AtomicBool>, JoinHandle<()>)>,
+    // The animation as we last sent it (or received it), so that we can tell what we've changed
+    // since then.
+    synced: ScribbleState,
+    // The undo stack of the project that we're collaborating on. If this changes, a different
+    // tab has been switched to.
+    undo: Arc<RefCell<UndoStack>>,
+    // The strokes that everyone else is drawing, by who is drawing them.
+    strokes: BTreeMap<u64, Vec<Curve>>,
+    // When we last sent our own strokes, and how many points they had.
+    strokes_sent: (Instant, usize),
+}
+
+// Do these two have the same shared parts?
+fn same_shared(a: &ScribbleState, b: &ScribbleState) -> bool {
+    a.snippets.same(&b.snippets)
+        && a.audio_snippets.same(&b.audio_snippets)
+        && a.images.same(&b.images)
+        && a.settings.same(&b.settings)
+}
+
+impl Collab {
+    /// Starts hosting (on `Preferences::collab_port`) or joining (the host at
+    /// `Preferences::collab_address`) a collaboration on the current project.
+    pub fn start(role: CollabRole, data: &AppState) -> anyhow::Result<Collab> {
+        let password = data.prefs.collab_password.clone();
+        if password.is_empty() {
+            return Err(anyhow::anyhow!(
+                "there's no collaboration password in the preferences"
+            ));
+        }
+        let (tx, rx) = channel();
+        let mut ret = Collab {
+            side: match role {
+                CollabRole::Host => Side::Host(Sequencer::default()),
+                CollabRole::Guest => Side::Guest(None),
+            },
+            events: rx,
+            peers: Vec::new(),
+            listener: None,
+            synced: data.scribble.clone(),
+            undo: Arc::clone(&data.undo),
+            strokes: BTreeMap::new(),
+            strokes_sent: (Instant::now(), 0),
+        };
+        match role {
+            CollabRole::Host => {
+                let port = data.prefs.collab_port;
+                let interface = data.prefs.collab_interface.trim();
+                let ip: IpAddr = if interface.is_empty() {
+                    Ipv4Addr::LOCALHOST.into()
+                } else {
+                    interface
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("{:?} isn't an IP address", interface))?
+                };
+                let listener = TcpListener::bind((ip, port))?;
+                log::info!("hosting a collaboration on {}:{}", ip, port);
+                let stop = Arc::new(AtomicBool::new(false));
+                let thread_stop = Arc::clone(&stop);
+                let handle =
+                    std::thread::spawn(move || listen(listener, password, tx, thread_stop));
+                ret.listener = Some((stop, handle));
+            }
+            CollabRole::Guest => {
+                let address = data.prefs.collab_address.trim();
+                let addr = address
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("no address found for {}", address))?;
+                let mut stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+                let mut hello = serde_json::to_string(&Hello { password })?;
+                hello.push('\n');
+                stream.write_all(hello.as_bytes())?;
+                log::info!("joined a collaboration at {}", addr);
+                ret.peers.push(Peer::new(stream, 0, tx)?);
+            }
+        }
+        Ok(ret)
+    }
+
+    pub fn role(&self) -> CollabRole {
+        match self.side {
+            Side::Host(_) => CollabRole::Host,
+            Side::Guest(_) => CollabRole::Guest,
+        }
+    }
+
+    /// Sends our edits (and strokes) to the others, and applies theirs. Returns the things that
+    /// happened that the user should hear about, like people joining, or an error if the
+    /// collaboration is over.
+    pub fn update(&mut self, data: &mut AppState) -> anyhow::Result<Vec<String>> {
+        if !Arc::ptr_eq(&self.undo, &data.undo) {
+            return Err(anyhow::anyhow!(
+                "Stopped collaborating, because a different project was opened"
+            ));
+        }
+
+        // Our own edits go out first, so that the host can sequence them before anything that
+        // comes in, and a guest has them pending before it rebuilds its animation.
+        if !matches!(self.side, Side::Guest(None)) {
+            self.send_edits(data);
+            self.send_strokes(data);
+        }
+
+        let mut notices = Vec::new();
+        let mut edited = false;
+        let mut strokes_changed = false;
+        while let Ok(event) = self.events.try_recv() {
+            match event {
+                Event::Joined(peer) => {
+                    let seq = match &self.side {
+                        Side::Host(sequencer) => sequencer.seq,
+                        Side::Guest(_) => {
+                            log::error!("a guest got a connection from collaborator {}", peer.id);
+                            continue;
+                        }
+                    };
+                    let welcome = Message::Welcome {
+                        project: self.synced.to_save_file(),
+                        you: peer.id,
+                        seq,
+                    };
+                    if let Some(line) = to_line(&welcome) {
+                        peer.send(&line);
+                    }
+                    self.peers.push(peer);
+                    notices.push(format!(
+                        "A collaborator joined ({} connected)",
+                        self.peers.len()
+                    ));
+                }
+                Event::Left(id) => {
+                    let sequencer = match &mut self.side {
+                        Side::Host(sequencer) => sequencer,
+                        Side::Guest(None) => {
+                            return Err(anyhow::anyhow!(
+                                "The host turned us away (is the collaboration password right?)"
+                            ))
+                        }
+                        Side::Guest(Some(_)) => {
+                            return Err(anyhow::anyhow!("The host stopped collaborating"))
+                        }
+                    };
+                    sequencer.forget(id);
+                    self.peers.retain(|p| p.id != id);
+                    if self.strokes.remove(&id).is_some() {
+                        strokes_changed = true;
+                        let msg = Message::Strokes {
+                            from: id,
+                            curves: Vec::new(),
+                        };
+                        self.broadcast(&msg, None);
+                    }
+                    notices.push(format!(
+                        "A collaborator left ({} connected)",
+                        self.peers.len()
+                    ));
+                }
+                Event::Received(from, Message::Welcome { project, you, seq }) => {
+                    if let Side::Guest(follower) = &mut self.side {
+                        data.scribble = ScribbleState::from_save_file(project);
+                        data.scribble.audio_snippets =
+                            data.scribble.audio_snippets.with_links_loaded();
+                        data.push_undo("Join Collaboration");
+                        self.synced = data.scribble.clone();
+                        *follower = Some(Follower::new(you, data.scribble.clone(), seq));
+                    } else {
+                        log::error!("collaborator {} tried to send a whole animation", from);
+                    }
+                }
+                Event::Received(from, Message::Edit { edit, seq, base }) => {
+                    if let Side::Host(sequencer) = &mut self.side {
+                        let msg = sequencer.accept(&mut data.scribble, from, edit, seq, base);
+                        self.synced = data.scribble.clone();
+                        self.broadcast(&msg, None);
+                        edited = true;
+                    } else {
+                        log::error!("the host sent an edit without sequencing it");
+                    }
+                }
+                Event::Received(
+                    _,
+                    Message::Sequenced {
+                        edit,
+                        seq,
+                        from,
+                        ack,
+                    },
+                ) => {
+                    if let Side::Guest(Some(follower)) = &mut self.side {
+                        follower.receive(&edit, seq, from, ack);
+                        follower.rebuild(&mut data.scribble);
+                        edited |= !same_shared(&self.synced, &data.scribble);
+                        self.synced = data.scribble.clone();
+                    } else {
+                        log::error!("got a sequenced edit from collaborator {}", from);
+                    }
+                }
+                Event::Received(
+                    from,
+                    Message::Strokes {
+                        from: origin,
+                        curves,
+                    },
+                ) => {
+                    let origin = match self.side {
+                        Side::Host(_) => {
+                            let msg = Message::Strokes {
+                                from,
+                                curves: curves.clone(),
+                            };
+                            self.broadcast(&msg, Some(from));
+                            from
+                        }
+                        Side::Guest(_) => origin,
+                    };
+                    if curves.is_empty() {
+                        self.strokes.remove(&origin);
+                    } else {
+                        self.strokes.insert(origin, curves);
+                    }
+                    strokes_changed = true;
+                }
+            }
+        }
+
+        if edited {
+            data.push_coalescing_undo("Collaborator's Edit", COALESCE_WINDOW);
+        }
+        if strokes_changed {
+            data.peer_strokes = Arc::new(self.strokes.values().flatten().cloned().collect());
+        }
+        Ok(notices)
+    }
+
+    fn send_edits(&mut self, data: &AppState) {
+        if same_shared(&self.synced, &data.scribble) {
+            return;
+        }
+        let edit = Edit::new(&self.synced, &data.scribble);
+        self.synced = data.scribble.clone();
+        if edit.is_empty() {
+            return;
+        }
+        let msg = match &mut self.side {
+            Side::Host(sequencer) => sequencer.local(edit),
+            Side::Guest(Some(follower)) => follower.propose(edit),
+            Side::Guest(None) => return,
+        };
+        self.broadcast(&msg, None);
+    }
+
+    // The whole of each stroke gets sent every time, which is simple, and fine for strokes of
+    // ordinary length.
+    fn send_strokes(&mut self, data: &AppState) {
+        let (last_sent, last_len) = self.strokes_sent;
+        if last_sent.elapsed() < STROKE_INTERVAL {
+            return;
+        }
+        let curves: Vec<Curve> = data
+            .scribble
+            .new_curve
+            .as_deref()
+            .cloned()
+            .into_iter()
+            .chain(data.new_snippet_as_curve())
+            .filter(|c| !c.times.is_empty())
+            .collect();
+        let len = curves.iter().map(|c| c.times.len()).sum();
+        if len == last_len {
+            return;
+        }
+        self.strokes_sent = (Instant::now(), len);
+        self.broadcast(&Message::Strokes { from: 0, curves }, None);
+    }
+
+    // Sends a message to all the peers, except for the one with id `except`.
+    fn broadcast(&self, msg: &Message, except: Option<u64>) {
+        if self.peers.iter().all(|p| Some(p.id) == except) {
+            return;
+        }
+        if let Some(line) = to_line(msg) {
+            for peer in self.peers.iter().filter(|p| Some(p.id) != except) {
+                peer.send(&line);
+            }
+        }
+    }
+}
+
+impl Drop for Collab {
+    fn drop(&mut self) {
+        if let Some((stop, handle)) = self.listener.take() {
+            stop.store(true, Ordering::SeqCst);
+            if handle.join().is_err() {
+                log::error!("the collaboration thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scribble_curves::time;
+
+    #[test]
+    fn passwords() {
+        assert!(same_password("swordfish", "swordfish"));
+        assert!(!same_password("swordfish", "swordfisH"));
+        assert!(!same_password("sword", "swordfish"));
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        for (line, ok) in &[
+            ("{\"password\": \"swordfish\"}\n", true),
+            ("{\"password\": \"trout\"}\n", false),
+            ("{\"message\": \"edit\"}\n", false),
+        ] {
+            let mut guest = TcpStream::connect(addr).unwrap();
+            guest.write_all(line.as_bytes()).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            assert_eq!(authenticate(&stream, "swordfish").is_ok(), *ok);
+        }
+    }
+
+    fn audio(sample: i16) -> AudioSnippetData {
+        AudioSnippetData::new(vec![sample], time::ZERO)
+    }
+
+    fn samples(state: &ScribbleState) -> Vec<(AudioSnippetId, i16)> {
+        state
+            .audio_snippets
+            .snippets()
+            .map(|(id, snip)| (id, snip.buf()[0]))
+            .collect()
+    }
+
+    // Sends an edit through JSON, like it would go over the network.
+    fn send(edit: &Edit) -> Edit {
+        serde_json::from_str(&serde_json::to_string(edit).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn edits() {
+        let old = ScribbleState::default();
+        let mut new = old.clone();
+        new.audio_snippets = new.audio_snippets.with_new_snippet(audio(1)).0;
+        new.settings.title = "Together".to_owned();
+        let edit = Edit::new(&old, &new);
+        assert_eq!(edit.audio.added.len(), 1);
+
+        let mut other = old.clone();
+        send(&edit).apply(&mut other);
+        assert_eq!(samples(&other), samples(&new));
+        assert_eq!(other.settings.title, "Together");
+        assert!(Edit::new(&new, &other).is_empty());
+
+        // Removing a snippet also clears the selection, if that snippet was selected.
+        let id = samples(&other)[0].0;
+        other.selected_snippet = MaybeSnippetId::Audio(id);
+        let removed = ScribbleState {
+            audio_snippets: new.audio_snippets.without_snippet(id),
+            ..new.clone()
+        };
+        let edit = Edit::new(&new, &removed);
+        assert!(edit.audio.added.is_empty());
+        assert_eq!(edit.audio.removed, vec![id]);
+        edit.apply(&mut other);
+        assert!(samples(&other).is_empty());
+        assert_eq!(other.selected_snippet, MaybeSnippetId::None);
+    }
+
+    // Sends a message through JSON, like it would go over the network.
+    fn send_msg(msg: &Message) -> Message {
+        serde_json::from_str(&serde_json::to_string(msg).unwrap()).unwrap()
+    }
+
+    // A host and a guest who have both just started with the same (empty) animation.
+    fn collaborators() -> (ScribbleState, Sequencer, ScribbleState, Follower) {
+        let start = ScribbleState::default();
+        let follower = Follower::new(1, start.clone(), 0);
+        (start.clone(), Sequencer::default(), start, follower)
+    }
+
+    // The guest gets a message from the host.
+    fn guest_receive(guest: &mut ScribbleState, follower: &mut Follower, msg: &Message) {
+        match send_msg(msg) {
+            Message::Sequenced {
+                edit,
+                seq,
+                from,
+                ack,
+            } => {
+                follower.receive(&edit, seq, from, ack);
+                follower.rebuild(guest);
+            }
+            _ => panic!("expected a sequenced edit"),
+        }
+    }
+
+    // The host gets a message from the guest.
+    fn host_receive(host: &mut ScribbleState, sequencer: &mut Sequencer, msg: &Message) -> Message {
+        match send_msg(msg) {
+            Message::Edit { edit, seq, base } => sequencer.accept(host, 1, edit, seq, base),
+            _ => panic!("expected an edit"),
+        }
+    }
+
+    #[test]
+    fn clashing_ids() {
+        // The host and the guest both add a snippet at the same time, and they get the same id.
+        let (mut host, mut sequencer, mut guest, mut follower) = collaborators();
+        let old = host.clone();
+        host.audio_snippets = host.audio_snippets.with_new_snippet(audio(1)).0;
+        let host_msg = sequencer.local(Edit::new(&old, &host));
+        let old = guest.clone();
+        guest.audio_snippets = guest.audio_snippets.with_new_snippet(audio(2)).0;
+        let guest_msg = follower.propose(Edit::new(&old, &guest));
+
+        // The guest gets the host's edit first, but keeps its own snippet until the host has
+        // sequenced it.
+        guest_receive(&mut guest, &mut follower, &host_msg);
+        assert_eq!(samples(&guest).len(), 1);
+        assert_eq!(samples(&guest)[0].1, 2);
+
+        // Then the host gets the guest's edit, gives its snippet a new id, and sends it back.
+        let echo = host_receive(&mut host, &mut sequencer, &guest_msg);
+        guest_receive(&mut guest, &mut follower, &echo);
+        assert_eq!(samples(&host).len(), 2);
+        assert_eq!(samples(&guest), samples(&host));
+        assert!(follower.pending.is_empty());
+
+        // Edits to existing snippets don't get new ids.
+        let mut retimed = guest.clone();
+        let (id, _) = samples(&guest)[1];
+        retimed.audio_snippets = retimed
+            .audio_snippets
+            .with_changes(vec![(id, audio(3))], &[]);
+        let msg = follower.propose(Edit::new(&guest, &retimed));
+        let echo = host_receive(&mut host, &mut sequencer, &msg);
+        guest_receive(&mut guest, &mut follower, &echo);
+        assert_eq!(samples(&host), samples(&retimed));
+        assert_eq!(samples(&guest), samples(&host));
+    }
+
+    #[test]
+    fn renamed_before_acknowledged() {
+        // The guest adds a snippet under a clashing id, and then changes it before hearing back.
+        let (mut host, mut sequencer, mut guest, mut follower) = collaborators();
+        let old = host.clone();
+        host.audio_snippets = host.audio_snippets.with_new_snippet(audio(1)).0;
+        let host_msg = sequencer.local(Edit::new(&old, &host));
+        let old = guest.clone();
+        guest.audio_snippets = guest.audio_snippets.with_new_snippet(audio(2)).0;
+        let added = follower.propose(Edit::new(&old, &guest));
+        let old = guest.clone();
+        let (id, _) = samples(&guest)[0];
+        guest.audio_snippets = guest.audio_snippets.with_changes(vec![(id, audio(3))], &[]);
+        let changed = follower.propose(Edit::new(&old, &guest));
+
+        // The host applies the guest's change to the renamed snippet, not to its own.
+        let echo_added = host_receive(&mut host, &mut sequencer, &added);
+        let echo_changed = host_receive(&mut host, &mut sequencer, &changed);
+        let mut host_samples: Vec<i16> = samples(&host).into_iter().map(|(_, s)| s).collect();
+        host_samples.sort();
+        assert_eq!(host_samples, vec![1, 3]);
+
+        for msg in &[host_msg, echo_added, echo_changed] {
+            guest_receive(&mut guest, &mut follower, msg);
+        }
+        assert_eq!(samples(&guest), samples(&host));
+        assert!(follower.pending.is_empty());
+    }
+
+    #[test]
+    fn concurrent_changes_converge() {
+        let (mut host, mut sequencer, mut guest, mut follower) = collaborators();
+        let old = host.clone();
+        host.audio_snippets = host.audio_snippets.with_new_snippet(audio(1)).0;
+        let msg = sequencer.local(Edit::new(&old, &host));
+        guest_receive(&mut guest, &mut follower, &msg);
+        let (id, _) = samples(&host)[0];
+
+        // They both change the same snippet at the same time.
+        let old = host.clone();
+        host.audio_snippets = host.audio_snippets.with_changes(vec![(id, audio(10))], &[]);
+        let host_msg = sequencer.local(Edit::new(&old, &host));
+        let old = guest.clone();
+        guest.audio_snippets = guest
+            .audio_snippets
+            .with_changes(vec![(id, audio(20))], &[]);
+        let guest_msg = follower.propose(Edit::new(&old, &guest));
+
+        // The guest's own change stays on top until the host has sequenced it, and the host
+        // sequenced it last, so it wins everywhere.
+        guest_receive(&mut guest, &mut follower, &host_msg);
+        assert_eq!(samples(&guest), vec![(id, 20)]);
+        let echo = host_receive(&mut host, &mut sequencer, &guest_msg);
+        guest_receive(&mut guest, &mut follower, &echo);
+        assert_eq!(samples(&host), vec![(id, 20)]);
+        assert_eq!(samples(&guest), samples(&host));
+    }
+
+    #[test]
+    fn delete_beats_change() {
+        let (mut host, mut sequencer, mut guest, mut follower) = collaborators();
+        let old = host.clone();
+        host.audio_snippets = host.audio_snippets.with_new_snippet(audio(1)).0;
+        let msg = sequencer.local(Edit::new(&old, &host));
+        guest_receive(&mut guest, &mut follower, &msg);
+        let (id, _) = samples(&host)[0];
+
+        // The host deletes the snippet while the guest changes it.
+        let old = host.clone();
+        host.audio_snippets = host.audio_snippets.without_snippet(id);
+        let host_msg = sequencer.local(Edit::new(&old, &host));
+        let old = guest.clone();
+        guest.audio_snippets = guest
+            .audio_snippets
+            .with_changes(vec![(id, audio(20))], &[]);
+        guest.selected_snippet = MaybeSnippetId::Audio(id);
+        let guest_msg = follower.propose(Edit::new(&old, &guest));
+
+        let echo = host_receive(&mut host, &mut sequencer, &guest_msg);
+        assert!(samples(&host).is_empty());
+        guest_receive(&mut guest, &mut follower, &host_msg);
+        guest_receive(&mut guest, &mut follower, &echo);
+        assert!(samples(&guest).is_empty());
+        assert_eq!(guest.selected_snippet, MaybeSnippetId::None);
+    }
+}
@@ -0,0 +1,500 @@
+//! The commands that can be run from the command palette, together with their names and
+//! shortcuts, and the fuzzy search for finding them.
+
+use druid::Command;
+
+use crate::cmd;
+use crate::collab::CollabRole;
+use crate::data::{AppState, CurrentAction, Dialog, Jump, MaybeSnippetId};
+use crate::prefs::Keymap;
+
+/// A command, as it is listed in the command palette.
+pub struct PaletteCommand {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// The name of this command's keyboard shortcut in the preferences, if it has one.
+    pub action: Option<&'static str>,
+    pub command: Command,
+}
+
+impl PaletteCommand {
+    fn new(name: &'static str, description: &'static str, command: impl Into<Command>) -> Self {
+        PaletteCommand {
+            name,
+            description,
+            action: None,
+            command: command.into(),
+        }
+    }
+
+    fn bound(mut self, action: &'static str) -> Self {
+        self.action = Some(action);
+        self
+    }
+
+    /// The text that the palette shows for this command: its name, and its shortcut if it
+    /// has one.
+    pub fn label(&self, data: &AppState) -> String {
+        let shortcut = self
+            .action
+            .map(|a| data.prefs.keybinding(a))
+            .unwrap_or_default();
+        if shortcut.is_empty() {
+            format!("{} — {}", self.name, self.description)
+        } else {
+            format!("{} ({}) — {}", self.name, shortcut, self.description)
+        }
+    }
+}
+
+/// All the commands that can be run from the palette. The toggles depend on the current state,
+/// which is why this needs `data`.
+pub fn commands(data: &AppState) -> Vec<PaletteCommand> {
+    let dialog = |d: Dialog| cmd::SHOW_DIALOG.with(d);
+    let mut ret = vec![
+        PaletteCommand::new("Draw", "Start recording a drawing", cmd::DRAW).bound("draw"),
+        PaletteCommand::new("Talk", "Start recording audio", cmd::TALK).bound("talk"),
+        PaletteCommand::new("Play", "Play the animation", cmd::PLAY).bound("play"),
+        PaletteCommand::new("Stop", "Stop playing or recording", cmd::STOP).bound("stop"),
+        PaletteCommand::new("Undo", "Undo the last edit", druid::commands::UNDO),
+        PaletteCommand::new("Redo", "Redo the last undone edit", druid::commands::REDO),
+        PaletteCommand::new(
+            "Next recording speed",
+            "Switch to the next speed for drawing",
+            cmd::NEXT_RECORDING_SPEED,
+        )
+        .bound("next-recording-speed"),
+        PaletteCommand::new(
+            "Fade out new snippets",
+            "Toggle whether newly drawn snippets fade out",
+            cmd::SET_FADE.with(!data.fade_enabled),
+        )
+        .bound("fade"),
+        PaletteCommand::new("Set mark", "Mark the current time", cmd::SET_MARK).bound("mark"),
+        PaletteCommand::new(
+            "Jump to mark",
+            "Move the cursor to the mark",
+            cmd::JUMP.with(Jump::Mark),
+        )
+        .bound("jump-to-mark"),
+        PaletteCommand::new(
+            "Jump to start",
+            "Move the cursor to the start of the animation",
+            cmd::JUMP.with(Jump::Start),
+        )
+        .bound("jump-to-start"),
+        PaletteCommand::new(
+            "Jump to end",
+            "Move the cursor to where the last snippet finishes",
+            cmd::JUMP.with(Jump::End),
+        )
+        .bound("jump-to-end"),
+        PaletteCommand::new(
+            "Next snippet boundary",
+            "Move the cursor to where the next snippet starts or ends",
+            cmd::JUMP.with(Jump::NextBoundary),
+        )
+        .bound("next-boundary"),
+        PaletteCommand::new(
+            "Previous snippet boundary",
+            "Move the cursor to where the previous snippet starts or ends",
+            cmd::JUMP.with(Jump::PreviousBoundary),
+        )
+        .bound("previous-boundary"),
+        PaletteCommand::new(
+            "Snap to snippet boundary",
+            "Move the cursor to the nearest snippet start, end or lerp point",
+            cmd::JUMP.with(Jump::NearestBoundary),
+        )
+        .bound("snap-to-boundary"),
+        PaletteCommand::new(
+            "Warp snippet",
+            "Warp the selected snippet so that the current time moves to the mark",
+            cmd::LERP_SNIPPET,
+        )
+        .bound("warp"),
+        PaletteCommand::new(
+            "Truncate snippet",
+            "Cut off the selected snippet at the current time",
+            cmd::TRUNCATE_SNIPPET,
+        )
+        .bound("truncate"),
+        PaletteCommand::new(
+            "Recolor snippet",
+            "Give the selected snippet the pen color",
+            cmd::RECOLOR_SNIPPET,
+        )
+        .bound("recolor"),
+        PaletteCommand::new(
+            "Repeat last edit",
+            "Do the last snippet edit again, to the selected snippet",
+            cmd::REPEAT_LAST_EDIT,
+        )
+        .bound("repeat-last-edit"),
+        PaletteCommand::new(
+            "Change snippet speed",
+            "Speed up or slow down the selected snippet",
+            dialog(Dialog::ChangeSpeed),
+        ),
+        PaletteCommand::new(
+            "Reverse snippet",
+            "Make the selected snippet play backwards",
+            cmd::REVERSE_SNIPPET,
+        ),
+        PaletteCommand::new(
+            "Delete snippet",
+            "Delete the selected snippet",
+            cmd::DELETE_SNIPPET,
+        )
+        .bound("delete"),
+        PaletteCommand::new(
+            "Restore deleted snippet",
+            "Bring back a deleted snippet",
+            dialog(Dialog::RestoreSnippet),
+        ),
+        PaletteCommand::new(
+            "Copy snippet",
+            "Copy the selected snippet",
+            cmd::COPY_SNIPPET,
+        )
+        .bound("copy-snippet"),
+        PaletteCommand::new(
+            "Paste snippet",
+            "Paste the copied snippet at the cursor",
+            cmd::PASTE_SNIPPET,
+        )
+        .bound("paste-snippet"),
+        PaletteCommand::new(
+            "Paste image",
+            "Add the image on the clipboard",
+            cmd::PASTE_IMAGE,
+        )
+        .bound("paste-image"),
+        PaletteCommand::new(
+            "Hide images",
+            "Make the visible images disappear at the current time",
+            cmd::END_IMAGES,
+        ),
+        PaletteCommand::new(
+            "Fade in new images",
+            "Toggle whether newly added images fade in",
+            cmd::SET_FADE_IN_IMAGES.with(!data.fade_in_images),
+        ),
+        PaletteCommand::new(
+            "Show pen position",
+            "Toggle showing the pen during playback",
+            cmd::SET_SHOW_PEN.with(!data.show_pen),
+        ),
+        PaletteCommand::new(
+            "Show captions",
+            "Toggle showing the captions on the canvas",
+            cmd::SET_SHOW_CAPTIONS.with(!data.show_captions),
+        ),
+        PaletteCommand::new(
+            "Edit captions",
+            "List the captions, to fix their text or timing",
+            dialog(Dialog::Captions),
+        ),
+        PaletteCommand::new(
+            "Transcribe narration",
+            "Turn the selected audio snippet (or all of them) into captions",
+            cmd::TRANSCRIBE,
+        ),
+        PaletteCommand::new(
+            "Live output",
+            "Toggle publishing the canvas to a virtual camera",
+            cmd::SET_LIVE_OUTPUT.with(!data.live_output),
+        ),
+        PaletteCommand::new(
+            "Host collaboration",
+            "Toggle letting other scribbles join in editing this project",
+            cmd::SET_COLLAB.with(match data.collab {
+                Some(CollabRole::Host) => None,
+                _ => Some(CollabRole::Host),
+            }),
+        ),
+        PaletteCommand::new(
+            "Join collaboration",
+            "Toggle editing a project together with another scribble",
+            cmd::SET_COLLAB.with(match data.collab {
+                Some(CollabRole::Guest) => None,
+                _ => Some(CollabRole::Guest),
+            }),
+        ),
+        PaletteCommand::new(
+            "Measure",
+            "Toggle the coordinate and measure overlay",
+            cmd::SET_SHOW_MEASURE.with(!data.show_measure),
+        ),
+        PaletteCommand::new("Reset zoom", "Unzoom and unpan the canvas", cmd::RESET_ZOOM)
+            .bound("reset-zoom"),
+        PaletteCommand::new(
+            "History branches",
+            "Switch to an abandoned branch of the undo history",
+            dialog(Dialog::HistoryBranches),
+        ),
+        PaletteCommand::new(
+            "Export",
+            "Export the animation as a video",
+            dialog(Dialog::Export),
+        )
+        .bound("export"),
+        PaletteCommand::new(
+            "Project properties",
+            "Edit the project's settings",
+            dialog(Dialog::ProjectProperties),
+        ),
+        PaletteCommand::new(
+            "Preferences",
+            "Edit the preferences",
+            dialog(Dialog::Preferences),
+        ),
+        PaletteCommand::new(
+            "New tab",
+            "Open an empty project in a new tab",
+            cmd::NEW_TAB,
+        )
+        .bound("new-tab"),
+        PaletteCommand::new("Close tab", "Close the current project", cmd::CLOSE_TAB)
+            .bound("close-tab"),
+    ];
+
+    // Choosing the current plugin tool again switches back to the plain pen.
+    for tool in crate::plugins::registry().tools() {
+        let name = if data.drawing_tool == tool.name() {
+            String::new()
+        } else {
+            tool.name().to_owned()
+        };
+        ret.push(PaletteCommand::new(
+            tool.name(),
+            "Toggle drawing with this plugin tool",
+            cmd::SET_DRAWING_TOOL.with(name),
+        ));
+    }
+    ret
+}
+
+/// The actions that can be bound to buttons (on MIDI controllers, foot pedals and mice), on top
+/// of the ones in `DEFAULT_KEYBINDINGS`.
+pub const BUTTON_ACTIONS: &[&str] = &["toggle-draw", "toggle-talk", "undo", "redo", "next-color"];
+
+/// The command that the keyboard shortcut for `action` runs (where `action` is one of the actions
+/// in `DEFAULT_KEYBINDINGS` or [`BUTTON_ACTIONS`]), or `None` if there isn't one right now. This
+/// is for running shortcuts that don't go through the menus.
+pub fn command_for(action: &str, data: &AppState) -> Option<Command> {
+    match action {
+        "toggle-draw" => return Some(cmd::TOGGLE_DRAW.into()),
+        "toggle-talk" => return Some(cmd::TOGGLE_TALK.into()),
+        "undo" => return Some(druid::commands::UNDO.into()),
+        "redo" => return Some(druid::commands::REDO.into()),
+        _ => {}
+    }
+    if action == "play-pause" {
+        return match data.action {
+            CurrentAction::Idle => Some(cmd::PLAY.into()),
+            CurrentAction::Playing
+            | CurrentAction::Recording(_)
+            | CurrentAction::WaitingToRecord(_)
+            | CurrentAction::RecordingAudio(_) => Some(cmd::STOP.into()),
+            CurrentAction::Scanning(_) => None,
+        };
+    }
+    if action.starts_with("color-") {
+        let idx = action["color-".len()..].parse::<usize>().ok()?;
+        let color = data.palette.colors().get(idx.checked_sub(1)?)?;
+        return Some(cmd::CHOOSE_COLOR.with(color.clone()));
+    }
+    // Cycles through the palette, which is handy on a pen button.
+    if action == "next-color" {
+        let colors = data.palette.colors();
+        let selected = data.palette.selected_color().as_rgba_u32();
+        let idx = colors
+            .iter()
+            .position(|c| c.as_rgba_u32() == selected)
+            .map_or(0, |i| (i + 1) % colors.len());
+        return colors.get(idx).map(|c| cmd::CHOOSE_COLOR.with(c.clone()));
+    }
+    commands(data)
+        .into_iter()
+        .find(|c| c.action == Some(action))
+        .map(|c| c.command)
+}
+
+/// A few shortcuts that are useful for what's going on right now (like "Space: stop · S: next
+/// recording speed" while drawing), for showing in the status bar.
+pub fn shortcut_hints(data: &AppState) -> String {
+    let actions: &[&str] = match data.action {
+        CurrentAction::WaitingToRecord(_) | CurrentAction::Recording(_) => {
+            &["play-pause", "next-recording-speed", "fade"]
+        }
+        CurrentAction::RecordingAudio(_) => &["play-pause"],
+        CurrentAction::Playing => &["play-pause", "mark", "jump-to-mark"],
+        CurrentAction::Scanning(_) => &[],
+        CurrentAction::Idle => match data.scribble.selected_snippet {
+            MaybeSnippetId::Draw(_) => &["truncate", "warp", "recolor", "repeat-last-edit"],
+            _ => &["draw", "talk", "play-pause", "command-palette"],
+        },
+    };
+    let commands = commands(data);
+    let hint = |action: &str| {
+        let key = hint_key(action, data);
+        if key.is_empty() {
+            return None;
+        }
+        let name = match action {
+            "play-pause" if data.action.is_idle() => "Play",
+            "play-pause" => "Stop",
+            // The palette doesn't list itself.
+            "command-palette" => "Command palette",
+            _ => commands.iter().find(|c| c.action == Some(action))?.name,
+        };
+        Some(format!("{}: {}", key, name.to_lowercase()))
+    };
+    let hints: Vec<String> = actions.iter().filter_map(|a| hint(a)).collect();
+    hints.join(" · ")
+}
+
+// The shortcut to show for `action`. When the modal keymap is in normal mode, that's the
+// normal-mode shortcut (if the action has one).
+fn hint_key(action: &str, data: &AppState) -> String {
+    let normal_mode = data.action.is_idle() || data.action == CurrentAction::Playing;
+    if data.prefs.keymap == Keymap::Modal && normal_mode {
+        let key = data.prefs.keybinding(&format!("normal-{}", action));
+        if !key.is_empty() {
+            return key;
+        }
+    }
+    data.prefs.keybinding(action)
+}
+
+/// The commands whose names match `query`, best matches first.
+pub fn matching(query: &str, data: &AppState) -> Vec<PaletteCommand> {
+    let mut scored: Vec<_> = commands(data)
+        .into_iter()
+        .filter_map(|c| fuzzy_score(query, c.name).map(|score| (score, c)))
+        .collect();
+    // The sort is stable, so equally good matches stay in the order they're listed in.
+    scored.sort_by_key(|(score, _)| *score);
+    scored.into_iter().map(|(_, c)| c).collect()
+}
+
+/// Scores how well `query` matches `name`, or returns `None` if it doesn't match at all. The
+/// query matches if its letters appear in the name in the same order (ignoring case and
+/// spaces). Lower scores are better: each jump to the start of another word costs one, and
+/// jumping into the middle of a word costs more the further it goes.
+pub fn fuzzy_score(query: &str, name: &str) -> Option<usize> {
+    let query: Vec<char> = query
+        .to_lowercase()
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    let name: Vec<char> = name.to_lowercase().chars().collect();
+    let cost = |pos: usize, found: usize| {
+        if found == pos {
+            0
+        } else if name[found - 1] == ' ' {
+            1
+        } else {
+            found - pos + 1
+        }
+    };
+
+    // best[pos] is the best score for matching the rest of the query, starting from `pos` in
+    // the name. We fill it in from the end of the query backwards.
+    let mut best = vec![Some(0); name.len() + 1];
+    for &q in query.iter().rev() {
+        let mut next = vec![None; name.len() + 1];
+        for pos in 0..=name.len() {
+            next[pos] = (pos..name.len())
+                .filter(|&found| name[found] == q)
+                .filter_map(|found| best[found + 1].map(|rest| cost(pos, found) + rest))
+                .min();
+        }
+        best = next;
+    }
+    best[0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prefs::Preferences;
+
+    #[test]
+    fn fuzzy() {
+        assert_eq!(fuzzy_score("", "Draw"), Some(0));
+        assert_eq!(fuzzy_score("draw", "Draw"), Some(0));
+        assert_eq!(
+            fuzzy_score("DS", "Delete snippet"),
+            fuzzy_score("ds", "Delete snippet")
+        );
+        assert!(fuzzy_score("wd", "Draw").is_none());
+        assert!(fuzzy_score("drawx", "Draw").is_none());
+
+        // Matching the starts of words beats matching in the middle of them.
+        assert_eq!(fuzzy_score("rs", "Reverse snippet"), Some(1));
+        assert_eq!(fuzzy_score("rs", "Reset zoom"), Some(2));
+        assert_eq!(fuzzy_score("ps", "Paste image"), Some(2));
+    }
+
+    #[test]
+    fn best_matches_first() {
+        let data = AppState::with_prefs(Preferences::default());
+        let names = |query| {
+            matching(query, &data)
+                .into_iter()
+                .map(|c| c.name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names("")[0], "Draw");
+        assert_eq!(names("").len(), commands(&data).len());
+        assert_eq!(names("delete")[0], "Delete snippet");
+        assert_eq!(names("paste sn")[0], "Paste snippet");
+        assert_eq!(names("ps")[0], "Paste snippet");
+        assert!(names("qqq").is_empty());
+    }
+
+    #[test]
+    fn commands_for_actions() {
+        let data = AppState::with_prefs(Preferences::default());
+        let selector = |action| command_for(action, &data).map(|c| c.selector);
+        assert_eq!(selector("play-pause"), Some(cmd::PLAY));
+        assert_eq!(selector("mark"), Some(cmd::SET_MARK));
+        assert_eq!(selector("color-1"), Some(cmd::CHOOSE_COLOR.selector()));
+        assert_eq!(selector("color-0"), None);
+        let next = command_for("next-color", &data).unwrap();
+        let colors = data.palette.colors();
+        assert_eq!(
+            cmd::CHOOSE_COLOR.get(&next).map(|c| c.as_rgba_u32()),
+            Some(colors[1 % colors.len()].as_rgba_u32())
+        );
+        assert_eq!(selector("toggle-talk"), Some(cmd::TOGGLE_TALK));
+        assert_eq!(selector("undo"), Some(druid::commands::UNDO));
+        assert_eq!(selector("scan-forward"), None);
+    }
+
+    #[test]
+    fn hints() {
+        let mut data = AppState::with_prefs(Preferences::default());
+        assert_eq!(
+            shortcut_hints(&data),
+            "Cmd+D: draw · Cmd+T: talk · Space: play · Cmd+Shift+P: command palette"
+        );
+        data.prefs.keymap = Keymap::Modal;
+        assert!(shortcut_hints(&data).starts_with("Cmd+D: draw · A: talk"));
+
+        data.action = CurrentAction::Recording(1.0);
+        assert_eq!(
+            shortcut_hints(&data),
+            "Space: stop · S: next recording speed · F: fade out new snippets"
+        );
+        // Actions without shortcuts get left out.
+        data.prefs
+            .set_keybinding("next-recording-speed", String::new());
+        assert_eq!(
+            shortcut_hints(&data),
+            "Space: stop · F: fade out new snippets"
+        );
+    }
+}
@@ -0,0 +1,283 @@
+//! Controls for MIDI controllers and USB foot pedals, so that recording can be started and
+//! stopped without putting down the pen.
+//!
+//! The inputs are read on background threads, which tell the UI thread about them by sending a
+//! `cmd::CONTROLLER_INPUT` with the name of the control that was pressed: "midi-note-N" or
+//! "midi-cc-N" for MIDI notes and control changes (from any channel), and "pedal-N" for the Nth
+//! button of the foot pedal. `Preferences::controller_bindings` says what each control does.
+//!
+//! The MIDI inputs also carry timecode and transport messages from other programs, which get
+//! passed on as `cmd::MIDI_SYNC` (see `crate::midi_sync`).
+
+use druid::ExtEventSink;
+use midir::{Ignore, MidiInput, MidiInputConnection};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use crate::cmd;
+use crate::midi_sync::{SyncDecoder, SyncEvent};
+use crate::prefs::Preferences;
+
+/// How long the pedal thread waits for input before checking whether it should stop.
+const PEDAL_TIMEOUT_MS: i32 = 200;
+
+/// A control change counts as pressed once its value reaches this (which is what sustain pedals
+/// do).
+const CC_PRESSED: u8 = 64;
+
+pub struct Controllers {
+    sink: ExtEventSink,
+    // We need to keep these alive for as long as we want to hear from the MIDI devices.
+    _midi: Vec<MidiInputConnection<MidiState>>,
+    pedal_device: String,
+    pedal: Option<(Arc<AtomicBool>, JoinHandle<()>)>,
+}
+
+impl Controllers {
+    /// Starts listening to all the MIDI devices that are connected right now. The pedal doesn't
+    /// get opened until the first `update`.
+    pub fn new(sink: ExtEventSink) -> Controllers {
+        Controllers {
+            _midi: connect_midi(&sink),
+            sink,
+            pedal_device: String::new(),
+            pedal: None,
+        }
+    }
+
+    /// Switches to the foot pedal from `prefs`, if it's different from the one we're using.
+    pub fn update(&mut self, prefs: &Preferences) {
+        if prefs.pedal_device == self.pedal_device {
+            return;
+        }
+        self.pedal_device = prefs.pedal_device.clone();
+        self.stop_pedal();
+
+        if self.pedal_device.is_empty() {
+            return;
+        }
+        let (vendor, product) = match parse_usb_id(&self.pedal_device) {
+            Some(id) => id,
+            None => {
+                log::error!("bad foot pedal id \"{}\"", self.pedal_device);
+                return;
+            }
+        };
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+        let sink = self.sink.clone();
+        let handle = std::thread::spawn(move || read_pedal(vendor, product, sink, thread_stop));
+        self.pedal = Some((stop, handle));
+    }
+
+    fn stop_pedal(&mut self) {
+        if let Some((stop, handle)) = self.pedal.take() {
+            stop.store(true, Ordering::SeqCst);
+            if handle.join().is_err() {
+                log::error!("the foot pedal thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for Controllers {
+    fn drop(&mut self) {
+        self.stop_pedal();
+    }
+}
+
+// What we remember about each MIDI device: which control changes are held down, and the
+// timecode that's partly arrived.
+struct MidiState {
+    held: [bool; 128],
+    sync: SyncDecoder,
+}
+
+fn send(sink: &ExtEventSink, control: String) {
+    if let Err(e) = sink.submit_command(cmd::CONTROLLER_INPUT.selector(), Box::new(control), None) {
+        log::error!("failed to send controller input: {}", e);
+    }
+}
+
+fn send_sync(sink: &ExtEventSink, event: SyncEvent) {
+    if let Err(e) = sink.submit_command(cmd::MIDI_SYNC.selector(), Box::new(event), None) {
+        log::error!("failed to send MIDI sync: {}", e);
+    }
+}
+
+fn connect_midi(sink: &ExtEventSink) -> Vec<MidiInputConnection<MidiState>> {
+    // Connecting uses up the `MidiInput`, so we need a new one for each port.
+    let new_input = || match MidiInput::new("scribble") {
+        Ok(input) => Some(input),
+        Err(e) => {
+            log::error!("failed to open MIDI input: {}", e);
+            None
+        }
+    };
+    let ports = match new_input() {
+        Some(input) => input.ports(),
+        None => return Vec::new(),
+    };
+
+    let mut ret = Vec::new();
+    for port in &ports {
+        let mut input = match new_input() {
+            Some(input) => input,
+            None => break,
+        };
+        // Timecode comes in system exclusive and timing messages, so we need those.
+        input.ignore(Ignore::ActiveSense);
+        let name = input.port_name(port).unwrap_or_default();
+        let sink = sink.clone();
+        let callback = move |stamp: u64, message: &[u8], state: &mut MidiState| {
+            if let Some(control) = midi_control(message, &mut state.held) {
+                send(&sink, control);
+            }
+            if let Some(event) = state.sync.decode(stamp, message) {
+                send_sync(&sink, event);
+            }
+        };
+        let state = MidiState {
+            held: [false; 128],
+            sync: SyncDecoder::default(),
+        };
+        match input.connect(port, "scribble-controls", callback, state) {
+            Ok(conn) => {
+                log::info!("listening to MIDI device {}", name);
+                ret.push(conn);
+            }
+            Err(e) => log::error!("failed to connect to MIDI device {}: {}", name, e),
+        }
+    }
+    ret
+}
+
+/// The name of the control that was pressed by a MIDI message, if any. `held` remembers which
+/// control changes are currently pressed, so that we only report them when they go down.
+fn midi_control(message: &[u8], held: &mut [bool; 128]) -> Option<String> {
+    match *message {
+        [status, note, velocity] if status & 0xF0 == 0x90 && velocity > 0 => {
+            Some(format!("midi-note-{}", note))
+        }
+        [status, cc, value] if status & 0xF0 == 0xB0 => {
+            let was_held = std::mem::replace(&mut held[cc as usize & 0x7F], value >= CC_PRESSED);
+            if value >= CC_PRESSED && !was_held {
+                Some(format!("midi-cc-{}", cc))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Parses a USB id like "05f3:00ff" (the vendor and product ids, in hex) into its two parts.
+fn parse_usb_id(s: &str) -> Option<(u16, u16)> {
+    let mut parts = s.trim().split(':');
+    let vendor = u16::from_str_radix(parts.next()?, 16).ok()?;
+    let product = u16::from_str_radix(parts.next()?, 16).ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((vendor, product))
+}
+
+/// The pedals that are down in `new` but weren't in `old`. Pedals report their state as a bit
+/// mask, so the first bit of the first byte is "pedal-1", and so on.
+fn pressed_pedals(old: &[u8], new: &[u8]) -> Vec<String> {
+    let mut ret = Vec::new();
+    for (i, &byte) in new.iter().enumerate() {
+        let pressed = byte & !old.get(i).copied().unwrap_or(0);
+        for bit in 0..8 {
+            if pressed & (1 << bit) != 0 {
+                ret.push(format!("pedal-{}", i * 8 + bit + 1));
+            }
+        }
+    }
+    ret
+}
+
+fn read_pedal(vendor: u16, product: u16, sink: ExtEventSink, stop: Arc<AtomicBool>) {
+    let device = match hidapi::HidApi::new().and_then(|api| api.open(vendor, product)) {
+        Ok(device) => device,
+        Err(e) => {
+            log::error!(
+                "failed to open foot pedal {:04x}:{:04x}: {}",
+                vendor,
+                product,
+                e
+            );
+            return;
+        }
+    };
+
+    let mut old = Vec::new();
+    let mut buf = [0u8; 64];
+    while !stop.load(Ordering::SeqCst) {
+        match device.read_timeout(&mut buf, PEDAL_TIMEOUT_MS) {
+            // Nothing happened before the timeout.
+            Ok(0) => {}
+            Ok(len) => {
+                for pedal in pressed_pedals(&old, &buf[..len]) {
+                    send(&sink, pedal);
+                }
+                old = buf[..len].to_vec();
+            }
+            Err(e) => {
+                log::error!("failed to read from the foot pedal: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_palette::command_for;
+    use crate::data::AppState;
+
+    #[test]
+    fn midi() {
+        let mut held = [false; 128];
+        let mut control = |msg: &[u8]| midi_control(msg, &mut held);
+        assert_eq!(control(&[0x90, 36, 100]), Some("midi-note-36".to_owned()));
+        assert_eq!(control(&[0x93, 36, 100]), Some("midi-note-36".to_owned()));
+        // A note-on with zero velocity is really a note-off.
+        assert_eq!(control(&[0x90, 36, 0]), None);
+        assert_eq!(control(&[0x80, 36, 100]), None);
+
+        assert_eq!(control(&[0xB0, 64, 127]), Some("midi-cc-64".to_owned()));
+        // It's still held, so it doesn't count again until it's been let go.
+        assert_eq!(control(&[0xB0, 64, 100]), None);
+        assert_eq!(control(&[0xB0, 64, 0]), None);
+        assert_eq!(control(&[0xB0, 64, 127]), Some("midi-cc-64".to_owned()));
+        assert_eq!(control(&[0xF8]), None);
+    }
+
+    #[test]
+    fn pedals() {
+        assert_eq!(pressed_pedals(&[], &[0b101]), vec!["pedal-1", "pedal-3"]);
+        assert_eq!(pressed_pedals(&[0b001], &[0b011]), vec!["pedal-2"]);
+        assert!(pressed_pedals(&[0b011], &[0b001]).is_empty());
+        assert_eq!(pressed_pedals(&[0, 0], &[0, 1]), vec!["pedal-9"]);
+    }
+
+    #[test]
+    fn usb_ids() {
+        assert_eq!(parse_usb_id("05f3:00ff"), Some((0x05f3, 0x00ff)));
+        assert_eq!(parse_usb_id(" 0C45:7403 "), Some((0x0c45, 0x7403)));
+        assert_eq!(parse_usb_id("05f3"), None);
+        assert_eq!(parse_usb_id("05f3:00ff:1"), None);
+        assert_eq!(parse_usb_id("pedal"), None);
+    }
+
+    #[test]
+    fn default_bindings() {
+        let data = AppState::with_prefs(Preferences::default());
+        for action in data.prefs.controller_bindings.values() {
+            assert!(command_for(action, &data).is_some(), "{}", action);
+        }
+    }
+}
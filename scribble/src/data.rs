@@ -1,19 +1,31 @@
 use druid::kurbo::BezPath;
-use druid::{Data, Lens, Point};
-use serde::{Deserialize, Serialize};
+use druid::{Command, Data, Lens, Point, Target, Vec2};
 use std::cell::RefCell;
-use std::fs::File;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
+use scribble_core::save_file::SaveFileData;
+use scribble_core::undo::{UndoStack, UndoState};
 use scribble_curves::{
-    time, Curve, Effect, Effects, FadeEffect, LineStyle, SegmentData, SnippetData, SnippetId,
-    SnippetsData, Time,
+    time, Curve, Diff, Effect, Effects, FadeEffect, LineStyle, PenPath, SegmentData, SnippetData,
+    Time,
 };
 
-use crate::audio::{AudioSnippetData, AudioSnippetId, AudioSnippetsData, AudioState};
-use crate::undo::UndoStack;
+pub use scribble_core::model::{CopiedSnippet, MaybeSnippetId, ScribbleState};
+pub use scribble_project::settings::{
+    AspectRatio, ProjectSettings, RecordingSetup, RecordingSpeed,
+};
+
+use crate::audio::{AudioSnippetData, AudioState};
+use crate::captions::CaptionsData;
+use crate::collab::CollabRole;
+use crate::encode::ExportSettings;
+use crate::export_queue::ExportJobs;
+use crate::plugins::DrawingTool;
+use crate::prefs::Preferences;
+use crate::project_io::FileOp;
+use crate::tabs::Tab;
 use crate::widgets::ToggleButtonState;
 
 /// While drawing, this stores one continuous poly-line (from pen-down to
@@ -38,127 +50,143 @@ impl SegmentInProgress {
         self.len += 1;
     }
 
-    /// Returns a simplified and smoothed version of this polyline.
+    /// Returns a copy of this polyline, reflected according to `mirror`. If `mirror` is off,
+    /// returns `None`.
+    pub fn reflected(&self, mirror: MirrorMode) -> Option<SegmentInProgress> {
+        let points = self
+            .points
+            .borrow()
+            .iter()
+            .map(|&p| mirror.reflect(p))
+            .collect::<Option<Vec<_>>>()?;
+        Some(SegmentInProgress {
+            points: Arc::new(RefCell::new(points)),
+            times: Arc::new(RefCell::new(self.times.borrow().clone())),
+            len: self.len,
+        })
+    }
+
+    /// Runs this polyline through a drawing tool, returning the polylines that it turns into.
+    pub fn with_tool(&self, tool: &dyn DrawingTool) -> Vec<SegmentInProgress> {
+        tool.apply(&self.points.borrow(), &self.times.borrow())
+            .into_iter()
+            .filter(|(points, times)| !points.is_empty() && points.len() == times.len())
+            .map(|(points, times)| SegmentInProgress {
+                len: points.len(),
+                points: Arc::new(RefCell::new(points)),
+                times: Arc::new(RefCell::new(times)),
+            })
+            .collect()
+    }
+
+    /// Returns a simplified and smoothed version of this polyline, fitted with cubic segments.
     ///
-    /// `distance_threshold` controls the simplification: higher values will result in
-    /// a curve with fewer points. `angle_threshold` affects the presence of angles in
+    /// `distance_threshold` controls the simplification and fitting: higher values will
+    /// result in a curve with fewer points. `angle_threshold` affects the presence of angles in
     /// the returned curve: higher values will result in more smooth parts and fewer
     /// angular parts.
     pub fn to_curve(&self, distance_threshold: f64, angle_threshold: f64) -> (BezPath, Vec<Time>) {
         let points = self.points.borrow();
         let times = self.times.borrow();
-        let point_indices = scribble_curves::simplify::simplify(&points, distance_threshold);
+        let (path, point_indices) =
+            scribble_curves::fit::fit(&points, distance_threshold, angle_threshold);
         let times: Vec<Time> = point_indices.iter().map(|&i| times[i]).collect();
-        let points: Vec<Point> = point_indices.iter().map(|&i| points[i]).collect();
-        let path = scribble_curves::smooth::smooth(&points, 0.4, angle_threshold);
         (path, times)
     }
 }
 
-/// Our save file format is simply to serialize this struct as json, compressed
-/// with gzip.
-///
-/// In particular, it's very important that the serializion format of this struct
-/// doesn't change unexpectedly.
-#[derive(Deserialize, Serialize)]
-pub struct SaveFileData {
-    /// This is currently always set to zero, but it's here in case we need to make
-    /// changes.
-    pub version: u64,
-
-    pub snippets: SnippetsData,
-    pub audio_snippets: AudioSnippetsData,
+/// Places in the animation that the cursor can jump to.
+#[derive(Clone, Copy, Data, Debug, PartialEq)]
+pub enum Jump {
+    Mark,
+    Start,
+    End,
+    NextBoundary,
+    PreviousBoundary,
+    NearestBoundary,
 }
 
-impl SaveFileData {
-    pub fn load_from_path<P: AsRef<Path>>(path: P) -> anyhow::Result<SaveFileData> {
-        let file = File::open(path.as_ref())?;
-        SaveFileData::load_from(file)
-    }
-
-    pub fn load_from<R: std::io::Read>(read: R) -> anyhow::Result<SaveFileData> {
-        let decompress = flate2::read::GzDecoder::new(read);
-        Ok(serde_json::from_reader(decompress)?)
-    }
-
-    pub fn save_to_path<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
-        let path = path.as_ref();
-        let tmp_file_name = format!(
-            "{}.savefile",
-            path.file_name()
-                .and_then(|s| s.to_str())
-                .unwrap_or("untitled")
-        );
-        let tmp_path = path.with_file_name(tmp_file_name);
-
-        if let Some(parent) = path.parent() {
-            std::fs::create_dir_all(parent)?;
+impl Jump {
+    /// The time to jump to from `time`, if there's anywhere to go.
+    pub fn target(&self, scribble: &ScribbleState, time: Time) -> Option<Time> {
+        match self {
+            Jump::Mark => scribble.mark,
+            Jump::Start => Some(time::ZERO),
+            Jump::End => Some(scribble.end_time()),
+            Jump::NextBoundary => scribble.next_boundary(time),
+            Jump::PreviousBoundary => scribble.previous_boundary(time),
+            Jump::NearestBoundary => scribble.nearest_boundary(time),
         }
-
-        let tmp_file = File::create(&tmp_path)?;
-        self.save_to(tmp_file)?;
-        std::fs::rename(tmp_path, path)?;
-
-        Ok(())
-    }
-
-    pub fn save_to<W: std::io::Write>(&self, write: W) -> anyhow::Result<()> {
-        let compress = flate2::write::GzEncoder::new(write, flate2::Compression::new(7));
-        serde_json::to_writer(compress, self)?;
-        Ok(())
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Data)]
-pub enum MaybeSnippetId {
-    Draw(SnippetId),
-    Audio(AudioSnippetId),
-    None,
+/// In mirror mode, everything that gets drawn is also reflected across an axis.
+#[derive(Clone, Copy, Data, Debug, PartialEq)]
+pub enum MirrorMode {
+    Off,
+    /// Reflect across the vertical line with this x coordinate.
+    Vertical(f64),
+    /// Reflect across the horizontal line with this y coordinate.
+    Horizontal(f64),
 }
 
-impl MaybeSnippetId {
-    pub fn is_none(&self) -> bool {
-        matches!(self, MaybeSnippetId::None)
+impl Default for MirrorMode {
+    fn default() -> MirrorMode {
+        MirrorMode::Off
     }
+}
 
-    pub fn as_draw(&self) -> Option<SnippetId> {
-        if let MaybeSnippetId::Draw(id) = self {
-            Some(*id)
-        } else {
-            None
-        }
+impl MirrorMode {
+    pub fn is_off(&self) -> bool {
+        *self == MirrorMode::Off
     }
 
-    pub fn as_audio(&self) -> Option<AudioSnippetId> {
-        if let MaybeSnippetId::Audio(id) = self {
-            Some(*id)
-        } else {
-            None
+    /// Reflects a point across the axis, returning `None` if mirror mode is off.
+    pub fn reflect(&self, p: Point) -> Option<Point> {
+        match *self {
+            MirrorMode::Off => None,
+            MirrorMode::Vertical(x) => Some(Point::new(2.0 * x - p.x, p.y)),
+            MirrorMode::Horizontal(y) => Some(Point::new(p.x, 2.0 * y - p.y)),
         }
     }
-}
 
-impl From<SnippetId> for MaybeSnippetId {
-    fn from(id: SnippetId) -> MaybeSnippetId {
-        MaybeSnippetId::Draw(id)
+    /// Returns the same kind of mirror, but with the axis moved to pass through `p`.
+    pub fn with_axis_through(&self, p: Point) -> MirrorMode {
+        match *self {
+            MirrorMode::Off => MirrorMode::Off,
+            MirrorMode::Vertical(_) => MirrorMode::Vertical(p.x),
+            MirrorMode::Horizontal(_) => MirrorMode::Horizontal(p.y),
+        }
     }
 }
 
-impl From<AudioSnippetId> for MaybeSnippetId {
-    fn from(id: AudioSnippetId) -> MaybeSnippetId {
-        MaybeSnippetId::Audio(id)
-    }
-}
-
-/// This data contains the state of the drawing.
-#[derive(Clone, Data, Lens)]
-pub struct ScribbleState {
-    pub new_curve: Option<Arc<Curve>>,
-    pub snippets: SnippetsData,
-    pub audio_snippets: AudioSnippetsData,
-    pub selected_snippet: MaybeSnippetId,
-
-    pub mark: Option<Time>,
+/// The dialogs that can be shown on top of the drawing.
+#[derive(Clone, Copy, Data, Debug, Eq, PartialEq)]
+pub enum Dialog {
+    /// Changes the speed of the selected snippet.
+    ChangeSpeed,
+    /// Chooses the settings for exporting a video.
+    Export,
+    /// Offers to restore an autosave that is newer than the project.
+    RestoreAutosave,
+    /// Edits the project settings.
+    ProjectProperties,
+    /// Chooses a project to import into this one.
+    ImportProject,
+    /// Asks what to do with unsaved changes before closing a project.
+    UnsavedChanges,
+    /// Asks where to find linked audio files that have gone missing.
+    RelinkAudio,
+    /// Edits the preferences.
+    Preferences,
+    /// Lists the deleted snippets, so that they can be restored.
+    RestoreSnippet,
+    /// Lists the abandoned branches of the undo history, so that they can be switched to.
+    HistoryBranches,
+    /// Searches for a command to run.
+    CommandPalette,
+    /// Lists the captions, so that they can be edited.
+    Captions,
 }
 
 /// This data contains the state of the entire app.
@@ -171,6 +199,9 @@ pub struct AppState {
 
     pub undo: Arc<RefCell<UndoStack>>,
 
+    /// The animation as of the last time it was saved (or loaded).
+    pub saved: ScribbleState,
+
     #[lens(name = "time_lens")]
     time: Time,
 
@@ -186,6 +217,44 @@ pub struct AppState {
     /// When true, the "fade out" toggle button is pressed down.
     pub fade_enabled: bool,
 
+    /// If mirror mode is on, new segments are also drawn reflected across an axis.
+    pub mirror: MirrorMode,
+
+    /// The name of the plugin drawing tool that new segments go through (see `crate::plugins`).
+    /// If this is empty (or there's no such tool), segments are drawn as they are.
+    pub drawing_tool: String,
+
+    /// The path taken by the pen during the current recording.
+    #[data(ignore)]
+    pub pen_path: PenPath,
+
+    /// If true, newly added images fade in instead of appearing immediately.
+    pub fade_in_images: bool,
+
+    /// If true, we show the mouse coordinates and allow measuring distances and angles.
+    pub show_measure: bool,
+
+    /// How much the canvas is zoomed in. This only affects the view, not the animation.
+    pub canvas_zoom: f64,
+
+    /// How far the canvas is panned, in image coordinates.
+    pub canvas_pan: Vec2,
+
+    /// If true, we draw a dot showing the pen position during playback (and in exported videos).
+    pub show_pen: bool,
+
+    /// If true, the project's captions are shown at the bottom of the canvas.
+    pub show_captions: bool,
+
+    /// If true, we're publishing the canvas as a live video feed (see `crate::live_output`).
+    pub live_output: bool,
+
+    /// Whether (and how) we're collaborating with other scribbles (see `crate::collab`).
+    pub collab: Option<CollabRole>,
+
+    /// The strokes that collaborators are in the middle of drawing.
+    pub peer_strokes: Arc<Vec<Curve>>,
+
     // This is a bit of an odd one out, since it's specifically for input handling in the
     // drawing-pane widget. If there get to be more of these, maybe they should get split out.
     pub mouse_down: bool,
@@ -198,50 +267,241 @@ pub struct AppState {
 
     pub encoding_status: Option<crate::encode::EncodingStatus>,
 
+    /// All the exports that have been queued up.
+    pub export_jobs: ExportJobs,
+
+    /// The save or load that is currently running, if any.
+    pub file_op: Option<FileOp>,
+
+    /// True while narration is being turned into captions (see `crate::transcribe`).
+    pub transcribing: bool,
+
+    /// A short message about something that just happened (like a file being dropped), which
+    /// gets shown in the status bar for a few seconds.
+    pub toast: Option<Arc<String>>,
+
+    /// The dialog that is currently open, if any.
+    pub dialog: Option<Dialog>,
+
+    /// The speed factor in the "change speed" dialog.
+    pub speed_factor: f64,
+
+    /// The project chosen in the "import project" dialog.
+    pub import_path: Option<Arc<PathBuf>>,
+
+    /// How long after the cursor the imported project should start, in seconds.
+    pub import_offset: f64,
+
+    /// The settings that will be used the next time we export a video.
+    pub export: ExportSettings,
+
+    /// The name to use when saving the current export settings as a preset.
+    pub preset_name: String,
+
+    /// What's been typed into the command palette.
+    pub command_query: String,
+
+    pub prefs: Preferences,
+
+    /// The preferences being edited in the preferences dialog. They only replace `prefs` once
+    /// the dialog is accepted.
+    pub edited_prefs: Preferences,
+
+    /// The captions being edited in the captions dialog. They only replace the project's
+    /// captions once the dialog is accepted.
+    pub edited_captions: CaptionsData,
+
     #[data(ignore)]
     pub save_path: Option<PathBuf>,
+
+    /// A command (with its target) that was held up by the "unsaved changes" dialog.
+    #[data(ignore)]
+    pub unsaved_action: Option<(Command, Target)>,
+
+    /// A command (with its target) to run once the current save finishes.
+    #[data(ignore)]
+    pub after_save: Option<(Command, Target)>,
+
+    /// The projects that are open in the other tabs.
+    pub tabs: Arc<Vec<Tab>>,
+
+    /// The position of the current tab, among all the tabs.
+    pub current_tab: usize,
+
+    /// The snippet that was most recently copied. This is shared between all the tabs, so it
+    /// can be used for moving snippets between projects.
+    pub snippet_clipboard: Option<CopiedSnippet>,
 }
 
 impl Default for AppState {
     fn default() -> AppState {
+        AppState::with_prefs(Preferences::default())
+    }
+}
+
+impl AppState {
+    /// A fresh state with the given preferences. This doesn't touch the audio devices (see
+    /// [`AppState::init_audio`]), and so it's safe to use in tests.
+    pub fn with_prefs(prefs: Preferences) -> AppState {
+        let scribble = ScribbleState::default();
         AppState {
-            scribble: ScribbleState::default(),
+            scribble: scribble.clone(),
             new_segment: None,
             action: CurrentAction::Idle,
             recording_speed: RecordingSpeed::Slow,
-            undo: Arc::new(RefCell::new(UndoStack::new(ScribbleState::default()))),
+            undo: Arc::new(RefCell::new(UndoStack::new(scribble.clone()))),
+            saved: scribble,
 
             time_snapshot: (Instant::now(), time::ZERO),
             time: time::ZERO,
             fade_enabled: false,
+            mirror: MirrorMode::Off,
+            drawing_tool: String::new(),
+            pen_path: PenPath::new(),
+            fade_in_images: prefs.fade_in_images,
+            show_measure: false,
+            canvas_zoom: 1.0,
+            canvas_pan: Vec2::ZERO,
+            show_pen: prefs.show_pen,
+            show_captions: false,
+            live_output: false,
+            collab: None,
+            peer_strokes: Arc::new(Vec::new()),
             mouse_down: false,
             line_thickness: 0.004,
-            audio: Arc::new(RefCell::new(AudioState::init())),
+            audio: Arc::new(RefCell::new(AudioState::silent())),
             palette: crate::widgets::PaletteData::default(),
             encoding_status: None,
+            export_jobs: ExportJobs::default(),
+            file_op: None,
+            transcribing: false,
+            toast: None,
+            dialog: None,
+            speed_factor: 2.0,
+            import_path: None,
+            import_offset: 0.0,
+            export: prefs.default_export(),
+            preset_name: String::new(),
+            command_query: String::new(),
+            edited_prefs: prefs.clone(),
+            edited_captions: CaptionsData::default(),
+            prefs,
 
             save_path: None,
+            unsaved_action: None,
+            after_save: None,
+            tabs: Arc::new(Vec::new()),
+            current_tab: 0,
+            snippet_clipboard: None,
         }
     }
-}
 
-impl Default for ScribbleState {
-    fn default() -> ScribbleState {
-        ScribbleState {
-            new_curve: None,
-            snippets: SnippetsData::default(),
-            audio_snippets: AudioSnippetsData::default(),
-            selected_snippet: MaybeSnippetId::None,
-            mark: None,
-        }
+    /// Opens the audio devices that the preferences ask for.
+    pub fn init_audio(&mut self) {
+        let mut audio = AudioState::init();
+        audio.set_devices(&self.prefs.input_device, &self.prefs.output_device);
+        self.audio = Arc::new(RefCell::new(audio));
     }
-}
 
-impl AppState {
     pub fn from_save_file(data: SaveFileData) -> AppState {
-        AppState {
-            scribble: ScribbleState::from_save_file(data),
+        let scribble = ScribbleState::from_save_file(data);
+        let mut ret = AppState {
+            saved: scribble.clone(),
+            scribble,
             ..Default::default()
+        };
+        ret.restore_project_defaults();
+        ret
+    }
+
+    /// Sets up the recording and export controls the way that the current project wants them.
+    pub fn restore_project_defaults(&mut self) {
+        let settings = &self.scribble.settings;
+        self.recording_speed = settings.recording_speed;
+        self.export.frame_rate = settings.frame_rate;
+        if let Some(setup) = &settings.recording_setup {
+            self.fade_enabled = setup.fade;
+            self.palette.select(&setup.pen_color);
+            self.line_thickness = setup.line_thickness;
+        }
+    }
+
+    // Remembers the current recording controls in the project settings, so that they can be
+    // restored when the project is opened again.
+    fn remember_recording_setup(&mut self) {
+        let settings = &mut self.scribble.settings;
+        settings.recording_speed = self.recording_speed;
+        settings.recording_setup = Some(RecordingSetup {
+            fade: self.fade_enabled,
+            pen_color: self.palette.selected_color().clone(),
+            line_thickness: self.line_thickness,
+        });
+    }
+
+    /// Returns true if the animation has changed since it was last saved. Changes to things that
+    /// don't get saved (like the selection) don't count.
+    pub fn is_dirty(&self) -> bool {
+        crate::tabs::is_dirty(&self.scribble, &self.saved)
+    }
+
+    /// The title of the main window: the name of the project, with a "*" if it has unsaved
+    /// changes.
+    pub fn window_title(&self) -> String {
+        let name = crate::tabs::project_name(self.save_path.as_ref(), self.is_dirty());
+        format!("{} - Scribble", name)
+    }
+
+    /// Copies the selected snippet to the snippet clipboard.
+    pub fn copy_snippet(&mut self) {
+        let copied = match self.scribble.selected_snippet {
+            MaybeSnippetId::Draw(id) => {
+                CopiedSnippet::Draw(self.scribble.snippets.snippet(id).clone())
+            }
+            MaybeSnippetId::Audio(id) => {
+                CopiedSnippet::Audio(self.scribble.audio_snippets.snippet(id).clone())
+            }
+            MaybeSnippetId::None => return,
+        };
+        self.snippet_clipboard = Some(copied);
+    }
+
+    /// Adds the snippet on the snippet clipboard (if there is one) to the animation, starting at
+    /// the cursor. The new snippet gets selected.
+    pub fn paste_snippet(&mut self) {
+        let time = self.time();
+        match &self.snippet_clipboard {
+            Some(CopiedSnippet::Draw(snip)) => {
+                let snip = snip.shifted(time - snip.start_time());
+                let (snippets, id) = self.scribble.snippets.with_new_snippet(snip);
+                self.scribble.snippets = snippets;
+                self.scribble.selected_snippet = MaybeSnippetId::Draw(id);
+            }
+            Some(CopiedSnippet::Audio(snip)) => {
+                let snip = snip.shifted(time - snip.start_time());
+                let (audio_snippets, id) = self.scribble.audio_snippets.with_new_snippet(snip);
+                self.scribble.audio_snippets = audio_snippets;
+                self.scribble.selected_snippet = MaybeSnippetId::Audio(id);
+            }
+            None => {}
+        }
+    }
+
+    /// Returns true if a project is being loaded. Any changes made in the meantime would be
+    /// thrown away once it finishes.
+    pub fn is_loading(&self) -> bool {
+        self.file_op.as_ref().map_or(false, |op| !op.saving)
+    }
+
+    /// Creates a command for exporting the current animation to `filename`, using the current
+    /// export settings.
+    pub fn export_cmd(&self, filename: PathBuf) -> crate::cmd::ExportCmd {
+        crate::cmd::ExportCmd {
+            scribble: self.scribble.export_snapshot(self.export.selection_only),
+            filename,
+            export: self.export.clone(),
+            show_pen: self.show_pen,
+            cursor: self.time(),
+            range: None,
         }
     }
 
@@ -296,6 +556,73 @@ impl AppState {
         self.take_time_snapshot();
     }
 
+    /// Captures the current state, for putting on the undo stack. The description is of the edit
+    /// that led to this state (for example, "Delete Snippet").
+    pub fn undo_state(&self, description: &'static str) -> UndoState {
+        let recording = matches!(
+            self.action,
+            CurrentAction::Recording(_) | CurrentAction::WaitingToRecord(_)
+        );
+        UndoState {
+            scribble: self.scribble.clone(),
+            recording_time: if recording && self.scribble.new_curve.is_some() {
+                Some(self.time)
+            } else {
+                None
+            },
+            color: Some(self.palette.selected_color().clone()),
+            description: Some(description),
+        }
+    }
+
+    /// Pushes the current state onto the undo stack. The description shows up in the undo and
+    /// redo menu items.
+    pub fn push_undo(&self, description: &'static str) {
+        self.undo.borrow_mut().push(self.undo_state(description));
+    }
+
+    /// Pushes the current state onto the undo stack, merging it with the previous state if that
+    /// came from the same kind of edit less than `window` ago.
+    pub fn push_coalescing_undo(&self, description: &'static str, window: Duration) {
+        self.undo
+            .borrow_mut()
+            .push_coalescing(self.undo_state(description), window);
+    }
+
+    /// Pushes the current state onto the undo stack, to be replaced by the next non-transient
+    /// state. This is for the segments of a snippet that's being drawn.
+    pub fn push_transient_undo(&self) {
+        self.undo
+            .borrow_mut()
+            .push_transient(self.undo_state("Draw Segment"));
+    }
+
+    /// Restores a state from the undo stack, and makes sure that whatever we're doing still makes
+    /// sense with it.
+    pub fn restore_undo_state(&mut self, state: UndoState) {
+        let was_drawing = self.scribble.new_curve.is_some();
+        self.scribble = state.scribble;
+        if let Some(color) = state.color {
+            self.palette.select(&color);
+        }
+
+        if let Some(time) = state.recording_time {
+            // We went back to the middle of drawing a snippet, so get ready to continue it.
+            self.warp_time_to(time);
+            self.ensure_recording();
+        } else if was_drawing && self.scribble.new_curve.is_none() {
+            // We went forward past the end of a snippet that was being drawn (so it's already
+            // been added), or back to before it started. Either way, we're done with it.
+            self.new_segment = None;
+            if matches!(
+                self.action,
+                CurrentAction::Recording(_) | CurrentAction::WaitingToRecord(_)
+            ) {
+                let _ = self.stop_recording();
+            }
+        }
+    }
+
     /// Puts us into the `WaitingToRecord` state, after first cleaning up any
     /// other states that need to be cleaned up. This is useful for handling
     /// mid-drawing undos.
@@ -345,16 +672,26 @@ impl AppState {
             thickness: self.line_thickness,
         };
         let seg_data = SegmentData { effects, style };
-        let (path, times) = seg.to_curve(0.0005, std::f64::consts::PI / 4.0);
-        if let Some(curve) = self.scribble.new_curve.as_ref() {
-            let mut curve_clone = curve.as_ref().clone();
-            curve_clone.append_segment(path, times, seg_data);
-            self.scribble.new_curve = Some(Arc::new(curve_clone));
-        } else {
-            let mut curve = Curve::new();
-            curve.append_segment(path, times, seg_data);
-            self.scribble.new_curve = Some(Arc::new(curve));
+        let mut curve = self
+            .scribble
+            .new_curve
+            .as_ref()
+            .map(|c| c.as_ref().clone())
+            .unwrap_or_else(Curve::new);
+
+        let segs = match crate::plugins::registry().tool(&self.drawing_tool) {
+            Some(tool) => seg.with_tool(tool),
+            None => vec![seg],
+        };
+        for seg in segs {
+            let (path, times) = seg.to_curve(0.0005, std::f64::consts::PI / 4.0);
+            curve.append_segment(path, times, seg_data.clone());
+            if let Some(reflected) = seg.reflected(self.mirror) {
+                let (path, times) = reflected.to_curve(0.0005, std::f64::consts::PI / 4.0);
+                curve.append_segment(path, times, seg_data.clone());
+            }
         }
+        self.scribble.new_curve = Some(Arc::new(curve));
     }
 
     /// Stops recording drawing, returning the snippet that we just finished recording (if it was
@@ -373,10 +710,14 @@ impl AppState {
         }
         self.action = CurrentAction::Idle;
         self.take_time_snapshot();
+        if self.scribble.new_curve.is_some() {
+            self.remember_recording_setup();
+        }
+        let pen = std::mem::take(&mut self.pen_path);
         self.scribble
             .new_curve
             .take()
-            .map(|arc_curve| SnippetData::new(arc_curve.as_ref().clone()))
+            .map(|arc_curve| SnippetData::new(arc_curve.as_ref().clone()).with_pen_path(pen))
     }
 
     pub fn start_playing(&mut self) {
@@ -476,6 +817,12 @@ impl AppState {
         }
     }
 
+    /// Records the position of the pen, whether or not it's currently drawing.
+    pub fn record_pen_position(&mut self, p: Point, t: Time) {
+        assert!(self.action.is_recording());
+        self.pen_path.push(p, t);
+    }
+
     pub fn finish_cur_segment(&mut self) -> Option<SegmentInProgress> {
         assert!(self.action.is_recording());
         self.new_segment.take()
@@ -485,22 +832,25 @@ impl AppState {
     pub fn new_snippet_as_curve(&self) -> Option<Curve> {
         if let Some(ref new_snippet) = self.new_segment {
             let mut ret = Curve::new();
-            for (i, (p, t)) in new_snippet
-                .points
-                .borrow()
-                .iter()
-                .zip(new_snippet.times.borrow().iter())
-                .enumerate()
-            {
-                if i == 0 {
-                    let style = LineStyle {
-                        color: self.palette.selected_color().clone(),
-                        thickness: self.line_thickness,
-                    };
-                    let effects = self.selected_effects();
-                    ret.move_to(*p, *t, style, effects);
-                } else {
-                    ret.line_to(*p, *t);
+            let reflected = new_snippet.reflected(self.mirror);
+            for seg in std::iter::once(new_snippet).chain(reflected.as_ref()) {
+                for (i, (p, t)) in seg
+                    .points
+                    .borrow()
+                    .iter()
+                    .zip(seg.times.borrow().iter())
+                    .enumerate()
+                {
+                    if i == 0 {
+                        let style = LineStyle {
+                            color: self.palette.selected_color().clone(),
+                            thickness: self.line_thickness,
+                        };
+                        let effects = self.selected_effects();
+                        ret.move_to(*p, *t, style, effects);
+                    } else {
+                        ret.line_to(*p, *t);
+                    }
                 }
             }
             Some(ret)
@@ -510,24 +860,6 @@ impl AppState {
     }
 }
 
-impl ScribbleState {
-    pub fn from_save_file(data: SaveFileData) -> ScribbleState {
-        ScribbleState {
-            snippets: data.snippets,
-            audio_snippets: data.audio_snippets,
-            ..Default::default()
-        }
-    }
-
-    pub fn to_save_file(&self) -> SaveFileData {
-        SaveFileData {
-            version: 0,
-            snippets: self.snippets.clone(),
-            audio_snippets: self.audio_snippets.clone(),
-        }
-    }
-}
-
 #[derive(Clone, Copy, Data, Debug, PartialEq)]
 pub enum CurrentAction {
     /// They started an animation (e.g. by pressing the "video" button), but
@@ -613,50 +945,70 @@ impl CurrentAction {
     }
 }
 
-#[derive(Clone, Copy, Data, PartialEq, Eq)]
-pub enum RecordingSpeed {
-    Paused,
-    Slower,
-    Slow,
-    Normal,
-}
-
-impl RecordingSpeed {
-    pub fn factor(&self) -> f64 {
-        match self {
-            RecordingSpeed::Paused => 0.0,
-            RecordingSpeed::Slower => 1.0 / 8.0,
-            RecordingSpeed::Slow => 1.0 / 3.0,
-            RecordingSpeed::Normal => 1.0,
-        }
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
-    fn save_load() {
-        // TODO: this file is a bit too big. It makes the tests slow.
-        let data = include_bytes!("../sample/test.scb");
-
-        // Check that we can read our sample file.
-        let save_data = SaveFileData::load_from(&data[..]).unwrap();
-
-        let mut written = Vec::new();
-        save_data.save_to(&mut written).unwrap();
+    fn jumps() {
+        let mut scribble = ScribbleState::default();
+        for secs in &[1, 3] {
+            let snip = AudioSnippetData::new(vec![0; 100], Time::from_micros(secs * 1_000_000));
+            scribble.audio_snippets = scribble.audio_snippets.with_new_snippet(snip).0;
+        }
+        let ends: Vec<_> = scribble
+            .audio_snippets
+            .snippets()
+            .map(|(_, s)| s.end_time())
+            .collect();
+        let secs = |s| Time::from_micros(s * 1_000_000);
+        let target = |jump: Jump, time| jump.target(&scribble, time);
+
+        assert_eq!(target(Jump::NextBoundary, time::ZERO), Some(secs(1)));
+        assert_eq!(target(Jump::NextBoundary, secs(1)), Some(ends[0]));
+        assert_eq!(target(Jump::NextBoundary, ends[0]), Some(secs(3)));
+        assert_eq!(target(Jump::NextBoundary, ends[1]), None);
+        assert_eq!(target(Jump::PreviousBoundary, secs(3)), Some(ends[0]));
+        assert_eq!(target(Jump::PreviousBoundary, secs(1)), None);
+        assert_eq!(target(Jump::NearestBoundary, time::ZERO), Some(secs(1)));
+        assert_eq!(target(Jump::NearestBoundary, secs(3)), Some(secs(3)));
+        assert_eq!(target(Jump::NearestBoundary, secs(100)), Some(ends[1]));
+        assert_eq!(target(Jump::End, time::ZERO), Some(ends[1]));
+        assert_eq!(target(Jump::Start, secs(2)), Some(time::ZERO));
+        assert_eq!(target(Jump::Mark, secs(2)), None);
+    }
 
-        // We don't check that save -> load is the identity, because it's too
-        // fragile (e.g., compression settings could change). We also don't check
-        // that load -> save is the identity (for now), because implementing
-        // PartialEq is a pain.
-        let read_again = SaveFileData::load_from(&written[..]).unwrap();
+    #[test]
+    fn mirror() {
+        let p = Point::new(0.25, 0.5);
+        assert_eq!(MirrorMode::Off.reflect(p), None);
+        assert_eq!(
+            MirrorMode::Vertical(0.5).reflect(p),
+            Some(Point::new(0.75, 0.5))
+        );
+        assert_eq!(
+            MirrorMode::Horizontal(0.25).reflect(p),
+            Some(Point::new(0.25, 0.0))
+        );
+    }
 
-        // We do check that if something was written using the current version
-        // of scribble, then save -> load is the identity.
-        let mut written_again = Vec::new();
-        read_again.save_to(&mut written_again).unwrap();
-        assert_eq!(written, written_again);
+    #[test]
+    fn import() {
+        let data = include_bytes!("../sample/test.scb");
+        let sample = ScribbleState::from_save_file(SaveFileData::load_from(&data[..]).unwrap());
+        let offset = Diff::from_micros(1_000_000);
+        let imported = sample.with_imported(&sample, offset);
+
+        // Everything gets imported with a new id, so nothing gets overwritten.
+        assert_eq!(
+            imported.snippets.snippets().count(),
+            2 * sample.snippets.snippets().count()
+        );
+        assert_eq!(
+            imported.audio_snippets.snippets().count(),
+            2 * sample.audio_snippets.snippets().count()
+        );
+        let start = |s: &ScribbleState| s.snippets.snippets().map(|(_, s)| s.start_time()).max();
+        assert_eq!(start(&imported), start(&sample).map(|t| t + offset));
     }
 }
@@ -1,23 +1,417 @@
 use anyhow::anyhow;
 use druid::piet::{Device, ImageFormat, RenderContext};
-use druid::{Affine, Data};
+use druid::{Affine, Color, Data, FileSpec, Lens};
 use gst::prelude::*;
 use gstreamer as gst;
 use gstreamer_app as gst_app;
 use gstreamer_audio as gst_audio;
 use gstreamer_video as gst_video;
-use std::path::Path;
-use std::sync::mpsc::Sender;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use scribble_curves::time::TimeSpan;
 use scribble_curves::{time, SnippetsData, Time};
+use scribble_project::watermark::WatermarkImage;
+
+pub use scribble_project::settings::FrameRate;
 
 use crate::audio::{AudioSnippetsData, Cursor, SAMPLE_RATE};
+use crate::data::{AspectRatio, ProjectSettings, ScribbleState};
+use crate::images::{ImageCache, ImageSnippetsData};
+use crate::time_remap::TimeRemap;
+
+/// The kinds of files that we can export to.
+#[derive(Clone, Copy, Data, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum ExportFormat {
+    Video,
+    WebM,
+    Gif,
+    PngSequence,
+    Svg,
+    /// AV1 video in an IVF file, encoded without gstreamer.
+    Ivf,
+    /// Just the (mixed) audio, as Opus or MP3.
+    Audio,
+    /// A PDF with a page for each key frame (see `crate::pdf`), for printing handouts.
+    Pdf,
+    /// A web page with the animated SVG and a small player (see `crate::html`).
+    Html,
+}
+
+impl ExportFormat {
+    pub fn file_spec(&self) -> FileSpec {
+        match self {
+            ExportFormat::Video => FileSpec::new("mp4 video", &["mp4"]),
+            ExportFormat::WebM => FileSpec::new("WebM video", &["webm"]),
+            ExportFormat::Gif => FileSpec::new("Animated GIF", &["gif"]),
+            ExportFormat::PngSequence => FileSpec::new("PNG image sequence", &["png"]),
+            ExportFormat::Svg => FileSpec::new("Animated SVG", &["svg"]),
+            ExportFormat::Ivf => FileSpec::new("AV1 video (no audio)", &["ivf"]),
+            ExportFormat::Audio => FileSpec::new("Narration audio", &["opus", "mp3"]),
+            ExportFormat::Pdf => FileSpec::new("PDF handout", &["pdf"]),
+            ExportFormat::Html => FileSpec::new("Web page", &["html"]),
+        }
+    }
+
+    /// Figures out the export format from a file's extension.
+    pub fn from_path(path: &Path) -> Option<ExportFormat> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("mp4") => Some(ExportFormat::Video),
+            Some("webm") => Some(ExportFormat::WebM),
+            Some("gif") => Some(ExportFormat::Gif),
+            Some("png") => Some(ExportFormat::PngSequence),
+            Some("svg") => Some(ExportFormat::Svg),
+            Some("ivf") => Some(ExportFormat::Ivf),
+            Some("opus") | Some("mp3") => Some(ExportFormat::Audio),
+            Some("pdf") => Some(ExportFormat::Pdf),
+            Some("html") | Some("htm") => Some(ExportFormat::Html),
+            _ => None,
+        }
+    }
+}
+
+/// The video codecs that can be used for WebM export.
+#[derive(Clone, Copy, Data, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum VideoCodec {
+    Vp9,
+    Av1,
+}
+
+/// How the video encoder decides how many bits to spend.
+#[derive(Clone, Copy, Data, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum RateControl {
+    /// Aim for a particular average bitrate.
+    Bitrate,
+    /// Aim for a constant quality (like x264's CRF), letting the bitrate vary as needed.
+    Quality,
+}
+
+// Which pass of a two-pass encode we're doing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Pass {
+    First,
+    Last,
+}
+
+/// The resolution of exported videos, in terms of the length of the shorter side.
+#[derive(Clone, Copy, Data, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Resolution {
+    P720,
+    P1080,
+    P2160,
+    Custom,
+}
+
+/// Whether (and when) to save a still image alongside an export, for use as a thumbnail.
+#[derive(Clone, Copy, Data, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Poster {
+    Off,
+    /// The last frame of the animation.
+    End,
+    /// Wherever the timeline's cursor was when the export was requested.
+    Cursor,
+}
+
+/// The user-configurable options for exporting a video.
+#[derive(Clone, Data, Debug, Deserialize, Lens, PartialEq, Serialize)]
+#[serde(default)]
+pub struct ExportSettings {
+    /// The format that the export dialog offers to save as. (The format that actually gets
+    /// written is determined by the file extension.)
+    pub format: ExportFormat,
+
+    pub resolution: Resolution,
 
-const FPS: f64 = 30.0;
-// Note that the aspect ratio here needs to match the aspect ratio
-// of the drawing, which is currently fixed at 4:3 in widgets/drawing_pane.rs.
-const WIDTH: i32 = 800;
-const HEIGHT: i32 = 600;
+    /// The length (in pixels) of the shorter side of the video, if `resolution` is `Custom`.
+    pub custom_resolution: f64,
+
+    pub frame_rate: FrameRate,
+
+    /// The codec to use when exporting WebM. (Other video formats always use VP9.)
+    pub codec: VideoCodec,
+
+    pub rate_control: RateControl,
+
+    /// The target video bitrate (in kilobits per second), if `rate_control` is `Bitrate`.
+    pub bitrate_kbps: f64,
+
+    /// The quality level, from 0 (best) to 63 (worst), if `rate_control` is `Quality`.
+    pub quality: f64,
+
+    /// If true, only the selected snippet is exported (and everything else is hidden).
+    pub selection_only: bool,
+
+    /// How many times faster than normal the exported animation plays.
+    pub speed: f64,
+
+    /// If true, the parts where nothing is being said play even faster, at `silence_speed`
+    /// times the normal speed.
+    pub skip_silence: bool,
+
+    pub silence_speed: f64,
+
+    /// If true, we analyze the whole video in a first pass before encoding it. This takes
+    /// twice as long, but spends the bits more wisely. Only VP9 supports this.
+    pub two_pass: bool,
+
+    /// Extra properties for the gstreamer video encoder, as space-separated `name=value` pairs
+    /// (e.g. "cpu-used=4 threads=8"). These override the ones that we set ourselves.
+    pub encoder_args: String,
+
+    /// If this isn't `Off`, we also save a PNG (with the same name as the exported file, but a
+    /// different extension) showing a single frame.
+    pub poster: Poster,
+
+    /// If true (and the project has captions), we also save the captions as SRT subtitles, with
+    /// the same name as the exported file but a different extension.
+    pub captions: bool,
+
+    /// The frame rate of exported GIFs. GIF frame delays are measured in hundredths of a
+    /// second, so the actual frame rate might be slightly different.
+    pub gif_fps: f64,
+
+    /// The number of times that an exported GIF repeats after playing once. Zero means that it
+    /// repeats forever.
+    pub gif_repeats: f64,
+}
+
+impl Default for ExportSettings {
+    fn default() -> ExportSettings {
+        ExportSettings {
+            format: ExportFormat::Video,
+            resolution: Resolution::P720,
+            custom_resolution: 480.0,
+            frame_rate: FrameRate::Fps30,
+            codec: VideoCodec::Vp9,
+            // The encoders' default bitrates are far too low for thin strokes, so by default we
+            // ask for a fixed quality instead.
+            rate_control: RateControl::Quality,
+            bitrate_kbps: 2000.0,
+            quality: 24.0,
+            two_pass: false,
+            encoder_args: String::new(),
+            selection_only: false,
+            speed: 1.0,
+            skip_silence: false,
+            silence_speed: 4.0,
+            poster: Poster::Off,
+            captions: true,
+            gif_fps: 15.0,
+            gif_repeats: 0.0,
+        }
+    }
+}
+
+impl ExportSettings {
+    /// The width and height (in pixels) of the exported video.
+    pub fn video_size(&self, aspect_ratio: AspectRatio) -> (u32, u32) {
+        let short = match self.resolution {
+            Resolution::P720 => 720.0,
+            Resolution::P1080 => 1080.0,
+            Resolution::P2160 => 2160.0,
+            Resolution::Custom => self.custom_resolution.max(16.0),
+        };
+        let ratio = aspect_ratio.ratio();
+        let long = short * ratio.max(1.0 / ratio);
+
+        // Video encoders tend to require even dimensions.
+        let even = |x: f64| ((x / 2.0).round() * 2.0) as u32;
+        if ratio >= 1.0 {
+            (even(long), even(short))
+        } else {
+            (even(short), even(long))
+        }
+    }
+
+    /// The video codec that we'll use for exporting in the given format.
+    fn video_codec(&self, format: ExportFormat) -> VideoCodec {
+        if format == ExportFormat::WebM {
+            self.codec
+        } else {
+            VideoCodec::Vp9
+        }
+    }
+
+    /// The delay (in hundredths of a second) between frames of an exported GIF.
+    pub fn gif_delay(&self) -> u16 {
+        (100.0 / self.gif_fps).round().max(1.0) as u16
+    }
+}
+
+// The speed/quality trade-off for GIF color quantization: 1 is the slowest (and best), 30 is the
+// fastest.
+const GIF_QUANTIZATION_SPEED: i32 = 10;
+
+// The name of the program that made the exported files, for their metadata.
+pub const SCRIBBLE_VERSION: &str = concat!("scribble ", env!("CARGO_PKG_VERSION"));
+
+// The number of rendered frames that can be waiting for the encoder. Each one is a whole RGBA
+// frame, so this shouldn't be too big.
+const RENDER_AHEAD: usize = 8;
+
+// The speed preset for the built-in AV1 encoder, from 0 (slowest) to 10 (fastest).
+const RAV1E_SPEED: usize = 6;
+
+/// Renders frames of the animation to RGBA pixels.
+pub struct FrameRenderer {
+    device: Device,
+    anim: SnippetsData,
+    images: ImageSnippetsData,
+    background: Color,
+    watermark: Option<WatermarkImage>,
+    show_pen: bool,
+    width: u32,
+    height: u32,
+}
+
+impl FrameRenderer {
+    pub fn new(
+        anim: SnippetsData,
+        images: ImageSnippetsData,
+        settings: &ProjectSettings,
+        show_pen: bool,
+        width: u32,
+        height: u32,
+    ) -> anyhow::Result<FrameRenderer> {
+        Ok(FrameRenderer {
+            device: Device::new().map_err(|_| anyhow!("couldn't open Device"))?,
+            anim,
+            images,
+            background: settings.background.clone(),
+            watermark: settings.watermark.image()?,
+            show_pen,
+            width,
+            height,
+        })
+    }
+
+    /// Replaces the things being rendered, while keeping the background and watermark.
+    pub fn set_contents(&mut self, anim: SnippetsData, images: ImageSnippetsData, show_pen: bool) {
+        self.anim = anim;
+        self.images = images;
+        self.show_pen = show_pen;
+    }
+
+    /// Returns the frame at the given time, in RGBA format with premultiplied alpha. (Since the
+    /// background is opaque, premultiplication doesn't actually make a difference.)
+    pub fn render(&mut self, time: Time) -> anyhow::Result<Vec<u8>> {
+        let FrameRenderer {
+            device,
+            anim,
+            images,
+            background,
+            watermark,
+            show_pen,
+            width,
+            height,
+        } = self;
+
+        // Create a cairo surface and render to it.
+        let mut bitmap = device
+            .bitmap_target(*width as usize, *height as usize, 1.0)
+            .map_err(|_| anyhow!("couldn't create bitmap"))?;
+        {
+            let mut ctx = bitmap.render_context();
+            ctx.clear(background.clone());
+            ctx.with_save(|ctx| {
+                // scribble's internal coordinates are always with respect to a drawing width of 1.0.
+                ctx.transform(Affine::scale(*width as f64));
+                // The renderers get moved between threads, so they can't keep the images around.
+                images.render(ctx, &mut ImageCache::default(), time, background);
+                for (_, snip) in anim.snippets() {
+                    snip.render(ctx, time);
+                }
+                if *show_pen {
+                    anim.render_pen(ctx, time);
+                }
+                Ok(())
+                // FIXME: piet's errors are not Send + Sync, so we'll need to wrap them or something.
+            })
+            .map_err(|_| anyhow!("error saving ctx"))?;
+            if let Some(watermark) = watermark {
+                watermark.render(&mut ctx, *width as f64, *height as f64);
+            }
+            ctx.finish()
+                .map_err(|_| anyhow!("error finishing render"))?;
+        }
+
+        // Note that piet-cairo currently only supports RgbaPremul.
+        bitmap
+            .into_raw_pixels(ImageFormat::RgbaPremul)
+            .map_err(|_| anyhow!("couldn't get pixels"))
+    }
+}
+
+// Frames that are rendered in the background, so that rendering the next frames overlaps with
+// encoding the current one. Rendering a frame only depends on the (immutable) snapshot of the
+// animation, so we render several frames at once on the rayon thread pool. The frames still
+// arrive in order.
+struct RenderedFrames {
+    frames: Receiver<anyhow::Result<Vec<u8>>>,
+}
+
+impl RenderedFrames {
+    // Starts rendering `scribble` at each of the given times.
+    fn new(
+        scribble: ScribbleState,
+        show_pen: bool,
+        width: u32,
+        height: u32,
+        times: Vec<Time>,
+    ) -> RenderedFrames {
+        let (tx, rx) = sync_channel(RENDER_AHEAD);
+        std::thread::spawn(move || {
+            // Each rendering thread gets its own renderer, and renders one frame of each chunk.
+            let renderers = (0..rayon::current_num_threads())
+                .map(|_| {
+                    FrameRenderer::new(
+                        scribble.snippets.clone(),
+                        scribble.images.clone(),
+                        &scribble.settings,
+                        show_pen,
+                        width,
+                        height,
+                    )
+                })
+                .collect::<anyhow::Result<Vec<_>>>();
+            let mut renderers = match renderers {
+                Ok(r) => r,
+                Err(e) => {
+                    let _ = tx.send(Err(e));
+                    return;
+                }
+            };
+
+            for chunk_times in times.chunks(renderers.len()) {
+                let chunk: Vec<_> = renderers
+                    .par_iter_mut()
+                    .zip(chunk_times)
+                    .map(|(renderer, &time)| renderer.render(time))
+                    .collect();
+                for frame in chunk {
+                    let failed = frame.is_err();
+                    // If the receiver hung up then the encoding failed, so there's no point
+                    // going on.
+                    if tx.send(frame).is_err() || failed {
+                        return;
+                    }
+                }
+            }
+        });
+        RenderedFrames { frames: rx }
+    }
+
+    fn next_frame(&self) -> anyhow::Result<Vec<u8>> {
+        self.frames
+            .recv()
+            .unwrap_or_else(|_| Err(anyhow!("the rendering thread stopped")))
+    }
+}
 
 // We make a custom error here because the default display for gst::message::Error isn't very
 // helpful in narrowing down the problem.
@@ -42,32 +436,182 @@ impl<'a> From<gst::message::Error<'a>> for PipelineError {
     }
 }
 
+// Creates the gstreamer element for encoding audio in the given format. WebM is usually paired
+// with Opus, but we stick with Vorbis for our other video format.
+fn make_audio_encoder(format: ExportFormat) -> Result<gst::Element, anyhow::Error> {
+    let name = match format {
+        ExportFormat::WebM => "opusenc",
+        _ => "vorbisenc",
+    };
+    gst::ElementFactory::make(name, Some("audio-encode")).map_err(|_| {
+        anyhow!(
+            "couldn't create {}; is the gstreamer plugin installed?",
+            name
+        )
+    })
+}
+
+// The file where vp9enc stores its statistics between the passes of a two-pass encode.
+fn multipass_cache_path() -> PathBuf {
+    std::env::temp_dir().join(format!("scribble-{}.multipass", std::process::id()))
+}
+
+// Creates the gstreamer element for encoding video in the given format.
+fn make_video_encoder(
+    format: ExportFormat,
+    export: &ExportSettings,
+    pass: Option<Pass>,
+) -> Result<gst::Element, anyhow::Error> {
+    let codec = export.video_codec(format);
+    let name = match codec {
+        VideoCodec::Vp9 => "vp9enc",
+        VideoCodec::Av1 => "av1enc",
+    };
+    let encoder = gst::ElementFactory::make(name, Some("encode")).map_err(|_| {
+        anyhow!(
+            "couldn't create {}; is the gstreamer plugin installed?",
+            name
+        )
+    })?;
+
+    // The two encoders have the same rate control properties, but they don't quite agree on
+    // the types and units.
+    match export.rate_control {
+        RateControl::Bitrate => {
+            let kbps = export.bitrate_kbps.max(1.0);
+            encoder.set_property_from_str("end-usage", "vbr");
+            match codec {
+                // vp9enc measures the bitrate in bits per second.
+                VideoCodec::Vp9 => {
+                    encoder.set_property("target-bitrate", &((kbps * 1000.0) as i32).to_value())?
+                }
+                VideoCodec::Av1 => {
+                    encoder.set_property("target-bitrate", &(kbps as u32).to_value())?
+                }
+            }
+        }
+        RateControl::Quality => {
+            let level = export.quality.round().max(0.0).min(63.0);
+            encoder.set_property_from_str("end-usage", "q");
+            match codec {
+                VideoCodec::Vp9 => encoder.set_property("cq-level", &(level as i32).to_value())?,
+                VideoCodec::Av1 => encoder.set_property("cq-level", &(level as u32).to_value())?,
+            }
+        }
+    }
+
+    if let Some(pass) = pass {
+        let mode = match pass {
+            Pass::First => "first-pass",
+            Pass::Last => "last-pass",
+        };
+        encoder.set_property_from_str("multipass-mode", mode);
+        let cache = multipass_cache_path();
+        let cache = cache
+            .to_str()
+            .ok_or(anyhow!("the temporary directory's name is too weird"))?;
+        encoder.set_property("multipass-cache-file", &cache.to_value())?;
+    }
+
+    for (prop, value) in parse_encoder_args(&export.encoder_args)? {
+        if encoder.find_property(prop).is_none() {
+            return Err(anyhow!(
+                "{} doesn't have a property called '{}'",
+                name,
+                prop
+            ));
+        }
+        encoder.set_property_from_str(prop, value);
+    }
+    Ok(encoder)
+}
+
+/// Parses the extra encoder properties in `ExportSettings::encoder_args`.
+pub fn parse_encoder_args(args: &str) -> Result<Vec<(&str, &str)>, anyhow::Error> {
+    args.split_whitespace()
+        .map(|arg| {
+            let mut parts = arg.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(prop), Some(value)) if !prop.is_empty() && !value.is_empty() => {
+                    Ok((prop, value))
+                }
+                _ => Err(anyhow!(
+                    "encoder properties should look like name=value, not '{}'",
+                    arg
+                )),
+            }
+        })
+        .collect()
+}
+
+// Writes the project's metadata into the container, so that exported videos can be traced back
+// to the project that they came from.
+fn set_metadata_tags(mux: &gst::Element, settings: &ProjectSettings) -> anyhow::Result<()> {
+    let tags = mux
+        .dynamic_cast_ref::<gst::TagSetter>()
+        .ok_or_else(|| anyhow!("bug: {} doesn't support tags", mux.get_name()))?;
+    let mode = gst::TagMergeMode::Replace;
+    if !settings.title.is_empty() {
+        tags.add::<gst::tags::Title>(&settings.title.as_str(), mode);
+    }
+    if !settings.author.is_empty() {
+        tags.add::<gst::tags::Artist>(&settings.author.as_str(), mode);
+    }
+    if let Some(created) = settings.created {
+        let date = gst::DateTime::new_from_unix_epoch_utc(created as i64);
+        tags.add::<gst::tags::DateTime>(&date, mode);
+    }
+    tags.add::<gst::tags::ApplicationName>(&SCRIBBLE_VERSION, mode);
+    Ok(())
+}
+
 fn create_pipeline(
-    anim: SnippetsData,
-    audio: AudioSnippetsData,
-    frame_count: u32,
+    scribble: &ScribbleState,
+    remap: &TimeRemap,
     path: &Path,
+    export: &ExportSettings,
+    show_pen: bool,
+    pass: Option<Pass>,
     progress: Sender<EncodingStatus>,
 ) -> Result<gst::Pipeline, anyhow::Error> {
+    let settings = &scribble.settings;
     let pipeline = gst::Pipeline::new(None);
     let v_src = gst::ElementFactory::make("appsrc", Some("source"))?;
     let v_convert = gst::ElementFactory::make("videoconvert", Some("convert"))?;
-    let v_encode = gst::ElementFactory::make("vp9enc", Some("encode"))?;
+    let format = ExportFormat::from_path(path).unwrap_or(ExportFormat::Video);
+    let v_encode = make_video_encoder(format, export, pass)?;
     let v_queue1 = gst::ElementFactory::make("queue", Some("queue1"))?;
     let v_queue2 = gst::ElementFactory::make("queue", Some("queue2"))?;
     let a_src = gst::ElementFactory::make("appsrc", Some("audio-source"))?;
     let a_convert = gst::ElementFactory::make("audioconvert", Some("audio-convert"))?;
-    let a_encode = gst::ElementFactory::make("vorbisenc", Some("audio-encode"))?;
+    let a_resample = gst::ElementFactory::make("audioresample", Some("audio-resample"))?;
+    let a_encode = make_audio_encoder(format)?;
     let a_queue1 = gst::ElementFactory::make("queue", Some("audio-queue1"))?;
     let a_queue2 = gst::ElementFactory::make("queue", Some("audio-queue2"))?;
     let mux = gst::ElementFactory::make("webmmux", Some("mux"))?;
+    set_metadata_tags(&mux, settings)?;
     let sink = gst::ElementFactory::make("filesink", Some("sink"))?;
 
     pipeline.add_many(&[&v_src, &v_convert, &v_encode, &v_queue1, &v_queue2])?;
-    pipeline.add_many(&[&a_src, &a_convert, &a_encode, &a_queue1, &a_queue2])?;
+    pipeline.add_many(&[
+        &a_src,
+        &a_convert,
+        &a_resample,
+        &a_encode,
+        &a_queue1,
+        &a_queue2,
+    ])?;
     pipeline.add_many(&[&mux, &sink])?;
     gst::Element::link_many(&[&v_src, &v_queue1, &v_convert, &v_encode, &v_queue2, &mux])?;
-    gst::Element::link_many(&[&a_src, &a_queue1, &a_convert, &a_encode, &a_queue2, &mux])?;
+    gst::Element::link_many(&[
+        &a_src,
+        &a_queue1,
+        &a_convert,
+        &a_resample,
+        &a_encode,
+        &a_queue2,
+        &mux,
+    ])?;
     gst::Element::link(&mux, &sink)?;
 
     // TODO: allow weirder filenames
@@ -79,10 +623,15 @@ fn create_pipeline(
             .to_value(),
     )?;
 
-    let video_info =
-        gst_video::VideoInfo::new(gst_video::VideoFormat::Rgba, WIDTH as u32, HEIGHT as u32)
-            .fps(gst::Fraction::new(FPS as i32, 1))
-            .build()?;
+    // Note that we scale the whole drawing (including the line widths) to the output size, so
+    // the video looks the same at every resolution.
+    let (width, height) = export.video_size(settings.aspect_ratio);
+    let fps = export.frame_rate.fps();
+    let frame_times = remap.frame_times(fps as f64);
+    let frame_count = frame_times.len() as u32;
+    let video_info = gst_video::VideoInfo::new(gst_video::VideoFormat::Rgba, width, height)
+        .fps(gst::Fraction::new(fps as i32, 1))
+        .build()?;
 
     let v_src = v_src
         .dynamic_cast::<gst_app::AppSrc>()
@@ -90,51 +639,21 @@ fn create_pipeline(
     v_src.set_caps(Some(&video_info.to_caps()?));
     v_src.set_property_format(gst::Format::Time); // FIXME: what does this mean?
 
-    let a_src = a_src
-        .dynamic_cast::<gst_app::AppSrc>()
-        .map_err(|_| anyhow!("bug: couldn't cast a_src to an AppSrc"))?;
-    let audio_info =
-        gst_audio::AudioInfo::new(gst_audio::AudioFormat::S16le, SAMPLE_RATE as u32, 1).build()?;
-    a_src.set_caps(Some(&audio_info.to_caps()?));
-    a_src.set_property_format(gst::Format::Time); // FIXME: needed?
-
     // This will be called every time the video source requests data.
     let mut frame_counter = 0;
-    let mut device = Device::new().map_err(|_| anyhow!("couldn't open Device"))?;
+    let frames = RenderedFrames::new(scribble.clone(), show_pen, width, height, frame_times);
+    let progress = ProgressTracker::new(frame_count, progress);
     let mut need_data_inner = move |src: &gst_app::AppSrc| -> anyhow::Result<()> {
-        // We track encoding progress by the fraction of video frames that we've rendered.  This
+        // We track encoding progress by the number of video frames that we've rendered.  This
         // isn't perfect (what with gstreamer's buffering, etc.), but it's probably good enough.
-        let _ = progress.send(EncodingStatus::Encoding(
-            frame_counter as f64 / frame_count as f64,
-        ));
+        progress.report(frame_counter);
         if frame_counter == frame_count {
             let _ = src.end_of_stream();
             return Ok(());
         }
 
-        let time = Time::from_video_frame(frame_counter, FPS);
-
-        // Create a cairo surface and render to it.
-
-        let mut bitmap = device
-            .bitmap_target(WIDTH as usize, HEIGHT as usize, 1.0)
-            .map_err(|_| anyhow!("couldn't create bitmap"))?;
-        {
-            let mut ctx = bitmap.render_context();
-            ctx.clear(druid::Color::WHITE);
-            ctx.with_save(|ctx| {
-                // scribble's internal coordinates are always with respect to a drawing width of 1.0.
-                ctx.transform(Affine::scale(WIDTH as f64));
-                for (_, snip) in anim.snippets() {
-                    snip.render(ctx, time);
-                }
-                Ok(())
-                // FIXME: piet's errors are not Send + Sync, so we'll need to wrap them or something.
-            })
-            .map_err(|_| anyhow!("error saving ctx"))?;
-            ctx.finish()
-                .map_err(|_| anyhow!("error finishing render"))?;
-        }
+        let time = Time::from_video_frame(frame_counter, fps as f64);
+        let pixels = frames.next_frame()?;
 
         // Create a gst buffer and copy the cairo surface over to it. (TODO: it would be nice to render
         // directly into this buffer, but cairo doesn't seem to safely support rendering into borrowed
@@ -146,13 +665,9 @@ fn create_pipeline(
                 .ok_or(anyhow!("failed to get mutable buffer"))?;
             // Presentation time stamp (i.e. when should this frame be displayed).
             gst_buffer_ref.set_pts(time.as_gst_clock_time());
+            gst_buffer_ref.set_duration(gst::ClockTime::from_nseconds(1_000_000_000 / fps as u64));
 
             let mut data = gst_buffer_ref.map_writable()?;
-            // Note that piet-cairo currently only supports RgbaPremul. It shouldn't
-            // make a difference, because we start with an opaque background.
-            let pixels = bitmap
-                .into_raw_pixels(ImageFormat::RgbaPremul)
-                .map_err(|_| anyhow!("couldn't get pixels"))?;
             data.as_mut_slice().copy_from_slice(&pixels[..]);
         }
 
@@ -168,39 +683,64 @@ fn create_pipeline(
         }
     };
 
+    // We mix the audio offline, over exactly the same time range as the video (padding with
+    // silence if the audio finishes early).
+    feed_audio(&a_src, scribble.audio_snippets.clone(), remap.out_end())?;
+    v_src.set_callbacks(gst_app::AppSrcCallbacks::new().need_data(need_data).build());
+
+    Ok(pipeline)
+}
+// Sets up an appsrc to produce the mixed audio, from the beginning until `end` (padding with
+// silence if the audio finishes early).
+fn feed_audio(a_src: &gst::Element, audio: AudioSnippetsData, end: Time) -> anyhow::Result<()> {
+    let a_src = a_src
+        .clone()
+        .dynamic_cast::<gst_app::AppSrc>()
+        .map_err(|_| anyhow!("bug: couldn't cast a_src to an AppSrc"))?;
+    let audio_info =
+        gst_audio::AudioInfo::new(gst_audio::AudioFormat::S16le, SAMPLE_RATE as u32, 1).build()?;
+    a_src.set_caps(Some(&audio_info.to_caps()?));
+    a_src.set_property_format(gst::Format::Time); // FIXME: needed?
+
     let mut cursor = Cursor::new(&audio, time::ZERO, crate::audio::SAMPLE_RATE, true);
-    let mut time_us = 0i64;
-    let mut need_audio_data_inner =
-        move |src: &gst_app::AppSrc, size_hint: u32| -> anyhow::Result<()> {
-            if cursor.is_finished() {
-                let _ = src.end_of_stream();
-                return Ok(());
-            }
+    let total_samples = end.as_audio_idx(SAMPLE_RATE);
+    let mut samples_written = 0usize;
+    let mut need_audio_data_inner = move |src: &gst_app::AppSrc,
+                                          size_hint: u32|
+          -> anyhow::Result<()> {
+        if samples_written >= total_samples {
+            let _ = src.end_of_stream();
+            return Ok(());
+        }
 
-            // I'm not sure if this is necessary, but there isn't much documentation on `size_hint` in
-            // gstreamer, so just to be sure let's make sure it isn't too small.
-            let size = size_hint.max(1024);
-
-            // gstreamer buffers seem to only ever hand out [u8], but we prefer to work with
-            // [i16]s. Here, we're doing an extra copy to handle endian-ness and avoid unsafe.
-            let mut buf = vec![0i16; size as usize / 2];
-            cursor.mix_to_buffer(&audio, &mut buf[..]);
-
-            let mut gst_buffer = gst::Buffer::with_size(size as usize)?;
-            {
-                let gst_buffer_ref = gst_buffer
-                    .get_mut()
-                    .ok_or(anyhow!("couldn't get mut buffer"))?;
-                gst_buffer_ref.set_pts(time_us as u64 * gst::USECOND);
-                time_us += (size as i64 / 2 * 1000000) / SAMPLE_RATE as i64;
-                let mut data = gst_buffer_ref.map_writable()?;
-                for (idx, bytes) in data.as_mut_slice().chunks_mut(2).enumerate() {
-                    bytes.copy_from_slice(&buf[idx].to_le_bytes());
-                }
+        // I'm not sure if this is necessary, but there isn't much documentation on `size_hint` in
+        // gstreamer, so just to be sure let's make sure it isn't too small.
+        let len = (size_hint.max(1024) as usize / 2).min(total_samples - samples_written);
+
+        // gstreamer buffers seem to only ever hand out [u8], but we prefer to work with
+        // [i16]s. Here, we're doing an extra copy to handle endian-ness and avoid unsafe.
+        let mut buf = vec![0i16; len];
+        cursor.mix_to_buffer(&audio, &mut buf[..]);
+
+        let mut gst_buffer = gst::Buffer::with_size(len * 2)?;
+        {
+            let gst_buffer_ref = gst_buffer
+                .get_mut()
+                .ok_or(anyhow!("couldn't get mut buffer"))?;
+            let sample_nanos = |idx: usize| idx as u64 * 1_000_000_000 / SAMPLE_RATE as u64;
+            gst_buffer_ref.set_pts(gst::ClockTime::from_nseconds(sample_nanos(samples_written)));
+            gst_buffer_ref.set_duration(gst::ClockTime::from_nseconds(
+                sample_nanos(samples_written + len) - sample_nanos(samples_written),
+            ));
+            samples_written += len;
+            let mut data = gst_buffer_ref.map_writable()?;
+            for (idx, bytes) in data.as_mut_slice().chunks_mut(2).enumerate() {
+                bytes.copy_from_slice(&buf[idx].to_le_bytes());
             }
-            let _ = src.push_buffer(gst_buffer);
-            Ok(())
-        };
+        }
+        let _ = src.push_buffer(gst_buffer);
+        Ok(())
+    };
 
     let need_audio_data = move |src: &gst_app::AppSrc, size_hint: u32| {
         if let Err(e) = need_audio_data_inner(src, size_hint) {
@@ -208,13 +748,63 @@ fn create_pipeline(
         }
     };
 
-    v_src.set_callbacks(gst_app::AppSrcCallbacks::new().need_data(need_data).build());
     a_src.set_callbacks(
         gst_app::AppSrcCallbacks::new()
             .need_data(need_audio_data)
             .build(),
     );
+    Ok(())
+}
+
+// Creates a pipeline that writes just the mixed audio, as Opus (in an Ogg container) or MP3
+// depending on the file extension.
+fn create_audio_pipeline(
+    cmd: &crate::cmd::ExportCmd,
+    end: Time,
+) -> Result<gst::Pipeline, anyhow::Error> {
+    let path = &cmd.filename;
+    let mp3 = path.extension().and_then(|e| e.to_str()) == Some("mp3");
+    let make = |factory: &str, name: &str| {
+        gst::ElementFactory::make(factory, Some(name)).map_err(|_| {
+            anyhow!(
+                "couldn't create {}; is the gstreamer plugin installed?",
+                factory
+            )
+        })
+    };
+    let pipeline = gst::Pipeline::new(None);
+    let src = make("appsrc", "audio-source")?;
+    let convert = make("audioconvert", "audio-convert")?;
+    let resample = make("audioresample", "audio-resample")?;
+    let sink = make("filesink", "sink")?;
+    let mut elements = vec![src.clone(), convert, resample];
+    if mp3 {
+        let encode = make("lamemp3enc", "audio-encode")?;
+        // lamemp3enc doesn't write tags itself, so we need id3v2mux for the metadata.
+        let tag = make("id3v2mux", "tag")?;
+        set_metadata_tags(&tag, &cmd.scribble.settings)?;
+        elements.push(encode);
+        elements.push(tag);
+    } else {
+        let encode = make("opusenc", "audio-encode")?;
+        let mux = make("oggmux", "mux")?;
+        set_metadata_tags(&encode, &cmd.scribble.settings)?;
+        elements.push(encode);
+        elements.push(mux);
+    }
+    elements.push(sink.clone());
 
+    let elements = elements.iter().collect::<Vec<_>>();
+    pipeline.add_many(&elements)?;
+    gst::Element::link_many(&elements)?;
+    sink.set_property(
+        "location",
+        &path
+            .to_str()
+            .ok_or(anyhow!("this filename is too weird"))?
+            .to_value(),
+    )?;
+    feed_audio(&src, cmd.scribble.audio_snippets.clone(), end)?;
     Ok(pipeline)
 }
 
@@ -244,11 +834,95 @@ fn main_loop(pipeline: gst::Pipeline) -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+/// A snapshot of how far along an export is.
+#[derive(Clone, Data, Debug, PartialEq)]
+pub struct EncodingProgress {
+    pub frames_rendered: u32,
+    pub frame_count: u32,
+    /// The average number of frames rendered per second so far.
+    pub fps: f64,
+    /// The estimated number of seconds until we're done, or `None` if it's too early to tell.
+    pub remaining_secs: Option<f64>,
+}
+
+impl EncodingProgress {
+    fn new(frames_rendered: u32, frame_count: u32, elapsed_secs: f64) -> EncodingProgress {
+        let fps = if elapsed_secs > 0.0 {
+            frames_rendered as f64 / elapsed_secs
+        } else {
+            0.0
+        };
+        let remaining_secs = if fps > 0.0 {
+            Some(frame_count.saturating_sub(frames_rendered) as f64 / fps)
+        } else {
+            None
+        };
+        EncodingProgress {
+            frames_rendered,
+            frame_count,
+            fps,
+            remaining_secs,
+        }
+    }
+
+    /// The progress, from 0.0 at the beginning to 1.0 at the end.
+    pub fn fraction(&self) -> f64 {
+        if self.frame_count == 0 {
+            1.0
+        } else {
+            self.frames_rendered as f64 / self.frame_count as f64
+        }
+    }
+}
+
+impl std::fmt::Display for EncodingProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "frame {}/{}, {:.1} fps",
+            self.frames_rendered, self.frame_count, self.fps
+        )?;
+        if let Some(secs) = self.remaining_secs {
+            let secs = secs.round() as u64;
+            write!(f, ", {}:{:02} left", secs / 60, secs % 60)?;
+        }
+        Ok(())
+    }
+}
+
+// Sends progress reports, keeping track of how long we've been going so that we can estimate
+// how much longer it will take.
+struct ProgressTracker {
+    start: Instant,
+    frame_count: u32,
+    sender: Sender<EncodingStatus>,
+}
+
+impl ProgressTracker {
+    fn new(frame_count: u32, sender: Sender<EncodingStatus>) -> ProgressTracker {
+        ProgressTracker {
+            start: Instant::now(),
+            frame_count,
+            sender,
+        }
+    }
+
+    fn report(&self, frames_rendered: u32) {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let _ = self
+            .sender
+            .send(EncodingStatus::Encoding(EncodingProgress::new(
+                frames_rendered,
+                self.frame_count,
+                elapsed,
+            )));
+    }
+}
+
 #[derive(Clone, Data, Debug)]
 pub enum EncodingStatus {
-    /// We are still encoding, and the parameter is the progress (0.0 at the beginning, 1.0 at the
-    /// end).
-    Encoding(f64),
+    /// We are still encoding.
+    Encoding(EncodingProgress),
 
     /// We finished encoding successfully.
     Finished,
@@ -257,23 +931,274 @@ pub enum EncodingStatus {
     Error(String),
 }
 
+// Exports last until a little after everything has finished.
+pub fn export_end_time(scribble: &ScribbleState) -> Time {
+    scribble
+        .snippets
+        .last_draw_time()
+        .max(scribble.audio_snippets.end_time())
+        + time::Diff::from_micros(200000)
+}
+
+// The part of the animation that gets exported: either the range that was asked for, or
+// everything.
+fn export_range(cmd: &crate::cmd::ExportCmd) -> TimeSpan {
+    cmd.range
+        .unwrap_or_else(|| TimeSpan::new(time::ZERO, export_end_time(&cmd.scribble)))
+}
+
 pub fn do_encode_blocking(
+    mut cmd: crate::cmd::ExportCmd,
+    progress: Sender<EncodingStatus>,
+) -> Result<(), anyhow::Error> {
+    let range = export_range(&cmd);
+    let end_time = range.end();
+
+    // From here on, the audio is already remapped; only the frames still need to be.
+    let (remap, audio) =
+        crate::time_remap::for_export(&cmd.scribble.audio_snippets, &cmd.export, range);
+    cmd.scribble.audio_snippets = audio;
+
+    // The poster is a still of the original animation, so it doesn't care about the remapping.
+    let poster_time = match cmd.export.poster {
+        Poster::Off => None,
+        Poster::End => Some(end_time),
+        Poster::Cursor => Some(cmd.cursor),
+    };
+    if let Some(time) = poster_time {
+        write_poster(&cmd, time)?;
+    }
+    if cmd.export.captions && !cmd.scribble.captions.is_empty() {
+        std::fs::write(
+            cmd.filename.with_extension("srt"),
+            crate::captions::to_srt(&cmd.scribble.captions, &remap),
+        )?;
+    }
+
+    if let Some(backend) = crate::plugins::registry().exporter_for(&cmd.filename) {
+        return backend
+            .export(&cmd, &remap, &progress)
+            .map_err(|e| anyhow!("{} export failed: {}", backend.name(), e));
+    }
+
+    match ExportFormat::from_path(&cmd.filename) {
+        Some(ExportFormat::Gif) => return encode_gif(cmd, &remap, progress),
+        Some(ExportFormat::PngSequence) => return encode_png_sequence(cmd, &remap, progress),
+        Some(ExportFormat::Svg) | Some(ExportFormat::Html) if !remap.is_identity() => {
+            return Err(anyhow!(
+                "SVG and web page export don't support changing the speed or exporting part of the animation"
+            ));
+        }
+        Some(ExportFormat::Svg) => return crate::svg::write_svg(&cmd, end_time),
+        Some(ExportFormat::Html) => return crate::html::write_html(&cmd, end_time),
+        Some(ExportFormat::Ivf) => return encode_ivf(cmd, &remap, progress),
+        Some(ExportFormat::Pdf) => return encode_pdf(cmd, range, &remap, progress),
+        _ => {}
+    }
+
+    // If gstreamer (or one of the plugins we need) is missing, let the user know that there's
+    // another option.
+    let gst_error = |e: anyhow::Error| {
+        anyhow!(
+            "{} (exporting to .ivf doesn't need GStreamer, but it has no audio)",
+            e
+        )
+    };
+    gst::init().map_err(|e| gst_error(e.into()))?;
+    let format = ExportFormat::from_path(&cmd.filename).unwrap_or(ExportFormat::Video);
+    if format == ExportFormat::Audio {
+        return main_loop(create_audio_pipeline(&cmd, remap.out_end())?);
+    }
+    let pipeline = |pass: Option<Pass>| {
+        create_pipeline(
+            &cmd.scribble,
+            &remap,
+            &cmd.filename,
+            &cmd.export,
+            cmd.show_pen,
+            pass,
+            progress.clone(),
+        )
+        .map_err(gst_error)
+    };
+    if cmd.export.two_pass && cmd.export.video_codec(format) == VideoCodec::Vp9 {
+        // The first pass writes a useless file (that gets overwritten by the second pass), but
+        // the statistics that it collects are what we actually want.
+        main_loop(pipeline(Some(Pass::First))?)?;
+        let result = main_loop(pipeline(Some(Pass::Last))?);
+        let _ = std::fs::remove_file(multipass_cache_path());
+        result
+    } else {
+        main_loop(pipeline(None)?)
+    }
+}
+
+// GIFs don't have audio, so we don't need gstreamer for them: we just render the frames and hand
+// them to the gif crate, which takes care of reducing each frame to a 256-color palette.
+fn encode_gif(
     cmd: crate::cmd::ExportCmd,
+    remap: &TimeRemap,
     progress: Sender<EncodingStatus>,
 ) -> Result<(), anyhow::Error> {
-    let end_time = cmd
-        .snippets
-        .last_draw_time()
-        .max(cmd.audio_snippets.end_time())
-        + time::Diff::from_micros(200000);
-    let num_frames = end_time.as_video_frame(FPS);
-    main_loop(create_pipeline(
-        cmd.snippets,
-        cmd.audio_snippets,
-        num_frames as u32,
-        &cmd.filename,
-        progress,
-    )?)
+    let (width, height) = cmd.export.video_size(cmd.scribble.settings.aspect_ratio);
+    if width > u16::MAX as u32 || height > u16::MAX as u32 {
+        return Err(anyhow!("{}x{} is too large for a GIF", width, height));
+    }
+    let delay = cmd.export.gif_delay();
+    let fps = 100.0 / delay as f64;
+    let frame_times = remap.frame_times(fps);
+    let num_frames = frame_times.len() as u32;
+
+    let file = std::io::BufWriter::new(std::fs::File::create(&cmd.filename)?);
+    let mut encoder = gif::Encoder::new(file, width as u16, height as u16, &[])?;
+    let repeat = match cmd.export.gif_repeats.round() as u16 {
+        0 => gif::Repeat::Infinite,
+        n => gif::Repeat::Finite(n),
+    };
+    encoder.set_repeat(repeat)?;
+
+    let frames = RenderedFrames::new(cmd.scribble, cmd.show_pen, width, height, frame_times);
+    let progress = ProgressTracker::new(num_frames, progress);
+    for frame_idx in 0..num_frames {
+        progress.report(frame_idx);
+        let mut pixels = frames.next_frame()?;
+        let mut frame = gif::Frame::from_rgba_speed(
+            width as u16,
+            height as u16,
+            &mut pixels,
+            GIF_QUANTIZATION_SPEED,
+        );
+        frame.delay = delay;
+        encoder.write_frame(&frame)?;
+    }
+    Ok(())
+}
+
+// When exporting an image sequence to `path` (e.g. "dir/anim.png"), this is the directory
+// (e.g. "dir/anim") that the frames go in.
+fn png_sequence_dir(path: &Path) -> PathBuf {
+    path.with_extension("")
+}
+
+// Writes each frame to a numbered PNG file. There's no audio, and the frame rate isn't recorded
+// anywhere, so whoever assembles the frames will need to know it.
+fn encode_png_sequence(
+    cmd: crate::cmd::ExportCmd,
+    remap: &TimeRemap,
+    progress: Sender<EncodingStatus>,
+) -> Result<(), anyhow::Error> {
+    let (width, height) = cmd.export.video_size(cmd.scribble.settings.aspect_ratio);
+    let frame_times = remap.frame_times(cmd.export.frame_rate.fps() as f64);
+    let num_frames = frame_times.len() as u32;
+    let dir = png_sequence_dir(&cmd.filename);
+    std::fs::create_dir_all(&dir)?;
+
+    let frames = RenderedFrames::new(cmd.scribble, cmd.show_pen, width, height, frame_times);
+    let progress = ProgressTracker::new(num_frames, progress);
+    for frame_idx in 0..num_frames {
+        progress.report(frame_idx);
+        let pixels = frames.next_frame()?;
+        image::save_buffer(
+            dir.join(format!("frame_{:05}.png", frame_idx)),
+            &pixels,
+            width,
+            height,
+            image::ColorType::Rgba8,
+        )?;
+    }
+    Ok(())
+}
+
+// The handout shows stills of the original animation (like the poster does), but the pages are
+// labelled with the times that they appear in the exported video.
+fn encode_pdf(
+    cmd: crate::cmd::ExportCmd,
+    range: TimeSpan,
+    remap: &TimeRemap,
+    progress: Sender<EncodingStatus>,
+) -> Result<(), anyhow::Error> {
+    let times = crate::pdf::key_frame_times(&cmd.scribble, range);
+    if times.is_empty() {
+        return Err(anyhow!("there's nothing to put in the handout"));
+    }
+    let (width, height) = cmd.export.video_size(cmd.scribble.settings.aspect_ratio);
+    let mut renderer = FrameRenderer::new(
+        cmd.scribble.snippets.clone(),
+        cmd.scribble.images.clone(),
+        &cmd.scribble.settings,
+        cmd.show_pen,
+        width,
+        height,
+    )?;
+    let file = std::io::BufWriter::new(std::fs::File::create(&cmd.filename)?);
+    let mut pdf = crate::pdf::PdfWriter::new(file)?;
+    let progress = ProgressTracker::new(times.len() as u32, progress);
+    for (idx, &time) in times.iter().enumerate() {
+        progress.report(idx as u32);
+        let pixels = renderer.render(time)?;
+        let label = crate::pdf::page_label(idx + 1, remap.out_time(time));
+        pdf.add_page(width, height, &pixels, &label)?;
+    }
+    pdf.finish()?;
+    Ok(())
+}
+
+/// Writes the frames of the animation to `out` as raw video, pausing between frames so that they
+/// arrive in real time. This is for feeding scribble's output live into something else (like
+/// OBS, or a v4l2 loopback device).
+///
+/// The format is as simple as possible: there's no header, and each frame is just
+/// `width * height * 4` bytes of RGBA (8 bits per channel, rows from top to bottom, no padding
+/// between rows). The size and frame rate are the ones given by `cmd.export`. There's no audio.
+pub fn stream_frames(cmd: crate::cmd::ExportCmd, out: &mut dyn Write) -> anyhow::Result<()> {
+    let range = export_range(&cmd);
+    let (remap, _) =
+        crate::time_remap::for_export(&cmd.scribble.audio_snippets, &cmd.export, range);
+    let (width, height) = cmd.export.video_size(cmd.scribble.settings.aspect_ratio);
+    let fps = cmd.export.frame_rate.fps() as f64;
+    let frame_times = remap.frame_times(fps);
+    let num_frames = frame_times.len();
+
+    let frames = RenderedFrames::new(cmd.scribble, cmd.show_pen, width, height, frame_times);
+    let start = Instant::now();
+    for frame_idx in 0..num_frames {
+        let pixels = frames.next_frame()?;
+        let due = Duration::from_secs_f64(frame_idx as f64 / fps);
+        if let Some(wait) = due.checked_sub(start.elapsed()) {
+            std::thread::sleep(wait);
+        }
+        out.write_all(&pixels)?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+// The file that the poster frame goes in. For PNG sequences, this doesn't clash with any of the
+// frames, because they go in a directory.
+fn poster_path(path: &Path) -> PathBuf {
+    path.with_extension("png")
+}
+
+// Saves a single frame of the animation as a PNG, at the same size as the exported video.
+fn write_poster(cmd: &crate::cmd::ExportCmd, time: Time) -> Result<(), anyhow::Error> {
+    let (width, height) = cmd.export.video_size(cmd.scribble.settings.aspect_ratio);
+    let pixels = FrameRenderer::new(
+        cmd.scribble.snippets.clone(),
+        cmd.scribble.images.clone(),
+        &cmd.scribble.settings,
+        cmd.show_pen,
+        width,
+        height,
+    )?
+    .render(time)?;
+    image::save_buffer(
+        poster_path(&cmd.filename),
+        &pixels,
+        width,
+        height,
+        image::ColorType::Rgba8,
+    )?;
+    Ok(())
 }
 
 pub fn encode_blocking(cmd: crate::cmd::ExportCmd, progress: Sender<EncodingStatus>) {
@@ -284,3 +1209,261 @@ pub fn encode_blocking(cmd: crate::cmd::ExportCmd, progress: Sender<EncodingStat
         let _ = progress.send(EncodingStatus::Finished);
     }
 }
+
+// A minimal writer for IVF files, which is the simplest container that AV1 decoders understand.
+struct IvfWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> IvfWriter<W> {
+    fn new(
+        mut out: W,
+        width: u16,
+        height: u16,
+        fps: u32,
+        frame_count: u32,
+    ) -> std::io::Result<IvfWriter<W>> {
+        out.write_all(b"DKIF")?;
+        out.write_all(&0u16.to_le_bytes())?; // version
+        out.write_all(&32u16.to_le_bytes())?; // header size
+        out.write_all(b"AV01")?;
+        out.write_all(&width.to_le_bytes())?;
+        out.write_all(&height.to_le_bytes())?;
+        // The time base is 1/fps, so the timestamps are just frame numbers.
+        out.write_all(&fps.to_le_bytes())?;
+        out.write_all(&1u32.to_le_bytes())?;
+        out.write_all(&frame_count.to_le_bytes())?;
+        out.write_all(&0u32.to_le_bytes())?; // unused
+        Ok(IvfWriter { out })
+    }
+
+    fn write_frame(&mut self, data: &[u8], frame_idx: u64) -> std::io::Result<()> {
+        self.out.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.out.write_all(&frame_idx.to_le_bytes())?;
+        self.out.write_all(data)
+    }
+}
+
+// Converts RGBA pixels (ignoring alpha) to planar YUV 4:2:0, using the BT.709 coefficients in
+// limited range. The width and height must be even, which they are for our videos.
+fn rgba_to_yuv420(rgba: &[u8], width: usize, height: usize) -> [Vec<u8>; 3] {
+    let pixel = |x: usize, y: usize| {
+        let i = 4 * (y * width + x);
+        (rgba[i] as f64, rgba[i + 1] as f64, rgba[i + 2] as f64)
+    };
+    let mut y_plane = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let (r, g, b) = pixel(x, y);
+            let luma = 16.0 + 219.0 * (0.2126 * r + 0.7152 * g + 0.0722 * b) / 255.0;
+            y_plane.push(luma.round() as u8);
+        }
+    }
+
+    let mut u_plane = Vec::with_capacity(width * height / 4);
+    let mut v_plane = Vec::with_capacity(width * height / 4);
+    for y in (0..height).step_by(2) {
+        for x in (0..width).step_by(2) {
+            let block = [
+                pixel(x, y),
+                pixel(x + 1, y),
+                pixel(x, y + 1),
+                pixel(x + 1, y + 1),
+            ];
+            let r = block.iter().map(|p| p.0).sum::<f64>() / 4.0;
+            let g = block.iter().map(|p| p.1).sum::<f64>() / 4.0;
+            let b = block.iter().map(|p| p.2).sum::<f64>() / 4.0;
+            let u = 128.0 + 224.0 * (-0.1146 * r - 0.3854 * g + 0.5 * b) / 255.0;
+            let v = 128.0 + 224.0 * (0.5 * r - 0.4542 * g - 0.0458 * b) / 255.0;
+            u_plane.push(u.round() as u8);
+            v_plane.push(v.round() as u8);
+        }
+    }
+    [y_plane, u_plane, v_plane]
+}
+
+// Writes out all the packets that rav1e has ready. Returns true if the encoder is finished.
+fn write_av1_packets<W: Write>(
+    ctx: &mut rav1e::Context<u8>,
+    out: &mut IvfWriter<W>,
+) -> Result<bool, anyhow::Error> {
+    loop {
+        match ctx.receive_packet() {
+            Ok(packet) => out.write_frame(&packet.data, packet.input_frameno)?,
+            Err(rav1e::EncoderStatus::Encoded) => {}
+            Err(rav1e::EncoderStatus::NeedMoreData) => return Ok(false),
+            Err(rav1e::EncoderStatus::LimitReached) => return Ok(true),
+            Err(e) => return Err(anyhow!("AV1 encoding failed: {:?}", e)),
+        }
+    }
+}
+
+// Encodes the video with rav1e, which is written in Rust. This means that it works even if
+// gstreamer (or its plugins) aren't installed, but it's slower than the gstreamer encoders and
+// the IVF container doesn't support audio.
+fn encode_ivf(
+    cmd: crate::cmd::ExportCmd,
+    remap: &TimeRemap,
+    progress: Sender<EncodingStatus>,
+) -> Result<(), anyhow::Error> {
+    let (width, height) = cmd.export.video_size(cmd.scribble.settings.aspect_ratio);
+    if width > u16::MAX as u32 || height > u16::MAX as u32 {
+        return Err(anyhow!("{}x{} is too large for an IVF file", width, height));
+    }
+    let fps = cmd.export.frame_rate.fps();
+    let frame_times = remap.frame_times(fps as f64);
+    let num_frames = frame_times.len() as u32;
+
+    let mut enc = rav1e::EncoderConfig::with_speed_preset(RAV1E_SPEED);
+    enc.width = width as usize;
+    enc.height = height as usize;
+    enc.time_base = rav1e::Rational::new(1, fps as u64);
+    match cmd.export.rate_control {
+        RateControl::Bitrate => enc.bitrate = (cmd.export.bitrate_kbps * 1000.0) as i32,
+        // rav1e's quantizer goes up to 255 instead of 63, but otherwise it means the same thing.
+        RateControl::Quality => enc.quantizer = (cmd.export.quality * 255.0 / 63.0) as usize,
+    }
+    let cfg = rav1e::Config { enc, threads: 0 };
+    let mut ctx: rav1e::Context<u8> = cfg
+        .new_context()
+        .map_err(|e| anyhow!("invalid AV1 encoder settings: {:?}", e))?;
+
+    let file = std::io::BufWriter::new(std::fs::File::create(&cmd.filename)?);
+    let mut out = IvfWriter::new(file, width as u16, height as u16, fps, num_frames)?;
+    let frames = RenderedFrames::new(cmd.scribble, cmd.show_pen, width, height, frame_times);
+    let progress = ProgressTracker::new(num_frames, progress);
+    for frame_idx in 0..num_frames {
+        progress.report(frame_idx);
+        let pixels = frames.next_frame()?;
+        let planes = rgba_to_yuv420(&pixels, width as usize, height as usize);
+        let mut frame = ctx.new_frame();
+        for (i, plane) in planes.iter().enumerate() {
+            let stride = if i == 0 { width } else { width / 2 };
+            frame.planes[i].copy_from_raw_u8(plane, stride as usize, 1);
+        }
+        ctx.send_frame(Arc::new(frame))
+            .map_err(|e| anyhow!("AV1 encoding failed: {:?}", e))?;
+        write_av1_packets(&mut ctx, &mut out)?;
+    }
+
+    ctx.flush();
+    while !write_av1_packets(&mut ctx, &mut out)? {}
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn video_size() {
+        let mut export = ExportSettings::default();
+        assert_eq!(export.video_size(AspectRatio::Wide), (1280, 720));
+        assert_eq!(export.video_size(AspectRatio::Standard), (960, 720));
+        assert_eq!(export.video_size(AspectRatio::Vertical), (720, 1280));
+
+        export.resolution = Resolution::P2160;
+        assert_eq!(export.video_size(AspectRatio::Wide), (3840, 2160));
+
+        export.resolution = Resolution::Custom;
+        export.custom_resolution = 101.0;
+        assert_eq!(export.video_size(AspectRatio::Wide), (180, 102));
+    }
+
+    #[test]
+    fn export_format() {
+        assert_eq!(
+            ExportFormat::from_path(Path::new("a/b.gif")),
+            Some(ExportFormat::Gif)
+        );
+        assert_eq!(
+            ExportFormat::from_path(Path::new("b.mp4")),
+            Some(ExportFormat::Video)
+        );
+        assert_eq!(
+            ExportFormat::from_path(Path::new("b.webm")),
+            Some(ExportFormat::WebM)
+        );
+        assert_eq!(
+            ExportFormat::from_path(Path::new("b.ivf")),
+            Some(ExportFormat::Ivf)
+        );
+        assert_eq!(
+            ExportFormat::from_path(Path::new("b.opus")),
+            Some(ExportFormat::Audio)
+        );
+        assert_eq!(
+            ExportFormat::from_path(Path::new("b.mp3")),
+            Some(ExportFormat::Audio)
+        );
+        assert_eq!(
+            ExportFormat::from_path(Path::new("b.pdf")),
+            Some(ExportFormat::Pdf)
+        );
+        assert_eq!(
+            ExportFormat::from_path(Path::new("b.htm")),
+            Some(ExportFormat::Html)
+        );
+        assert_eq!(ExportFormat::from_path(Path::new("b.scb")), None);
+        assert_eq!(png_sequence_dir(Path::new("a/b.png")), PathBuf::from("a/b"));
+    }
+
+    #[test]
+    fn gif_delay() {
+        let mut export = ExportSettings::default();
+        export.gif_fps = 15.0;
+        assert_eq!(export.gif_delay(), 7);
+        export.gif_fps = 1000.0;
+        assert_eq!(export.gif_delay(), 1);
+    }
+
+    #[test]
+    fn progress() {
+        let p = EncodingProgress::new(10, 40, 2.0);
+        assert_eq!(p.fps, 5.0);
+        assert_eq!(p.remaining_secs, Some(6.0));
+        assert_eq!(p.fraction(), 0.25);
+        assert_eq!(p.to_string(), "frame 10/40, 5.0 fps, 0:06 left");
+
+        let p = EncodingProgress::new(0, 40, 0.0);
+        assert_eq!(p.remaining_secs, None);
+        assert_eq!(p.to_string(), "frame 0/40, 0.0 fps");
+    }
+
+    #[test]
+    fn encoder_args() {
+        assert!(parse_encoder_args("  ").unwrap().is_empty());
+        assert_eq!(
+            parse_encoder_args("cpu-used=4  threads=8").unwrap(),
+            vec![("cpu-used", "4"), ("threads", "8")]
+        );
+        assert_eq!(
+            parse_encoder_args("tune=a=b").unwrap(),
+            vec![("tune", "a=b")]
+        );
+        assert!(parse_encoder_args("cpu-used").is_err());
+        assert!(parse_encoder_args("=4").is_err());
+        assert!(parse_encoder_args("cpu-used=").is_err());
+    }
+
+    #[test]
+    fn yuv() {
+        let mut rgba = vec![255; 4 * 4];
+        rgba.extend(vec![0, 0, 0, 255].repeat(4));
+        let [y, u, v] = rgba_to_yuv420(&rgba, 4, 2);
+        assert_eq!(y, vec![235, 235, 235, 235, 16, 16, 16, 16]);
+        assert_eq!(u, vec![128, 128]);
+        assert_eq!(v, vec![128, 128]);
+    }
+
+    #[test]
+    fn ivf_header() {
+        let mut buf = Vec::new();
+        let mut ivf = IvfWriter::new(&mut buf, 640, 480, 30, 1).unwrap();
+        ivf.write_frame(&[1, 2, 3], 0).unwrap();
+        assert_eq!(buf.len(), 32 + 12 + 3);
+        assert_eq!(&buf[0..4], b"DKIF");
+        assert_eq!(&buf[8..12], b"AV01");
+        assert_eq!(&buf[32..36], &3u32.to_le_bytes());
+    }
+}
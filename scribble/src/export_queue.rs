@@ -0,0 +1,137 @@
+//! Exports are queued up and run one at a time on a background thread.
+
+use druid::{Data, Lens};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+
+use crate::cmd::ExportCmd;
+use crate::encode::{EncodingProgress, EncodingStatus};
+
+/// The state of a single export job.
+#[derive(Clone, Data, Debug, PartialEq)]
+pub enum JobStatus {
+    Pending,
+    Running(EncodingProgress),
+    Finished,
+    Failed(String),
+}
+
+#[derive(Clone, Data, Debug, PartialEq)]
+pub struct ExportJob {
+    /// A short description of the job (currently, the name of the file being written).
+    pub name: String,
+    pub status: JobStatus,
+}
+
+impl ExportJob {
+    pub fn is_done(&self) -> bool {
+        matches!(self.status, JobStatus::Finished | JobStatus::Failed(_))
+    }
+
+    pub fn description(&self) -> String {
+        match &self.status {
+            JobStatus::Pending => format!("{}: waiting", self.name),
+            JobStatus::Running(p) => format!("{}: {}", self.name, p),
+            JobStatus::Finished => format!("{}: finished", self.name),
+            JobStatus::Failed(e) => format!("{}: failed ({})", self.name, e),
+        }
+    }
+}
+
+/// The list of export jobs that we show to the user, in the order that they were queued.
+#[derive(Clone, Data, Debug, Default, Lens)]
+pub struct ExportJobs {
+    pub jobs: Arc<Vec<ExportJob>>,
+}
+
+impl ExportJobs {
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+
+    pub fn with_new_job(&self, name: String) -> ExportJobs {
+        let mut jobs = (*self.jobs).clone();
+        jobs.push(ExportJob {
+            name,
+            status: JobStatus::Pending,
+        });
+        ExportJobs {
+            jobs: Arc::new(jobs),
+        }
+    }
+
+    /// Applies a status update from the encoder. Since jobs run one at a time in the order that
+    /// they were queued, the update belongs to the first job that isn't done yet.
+    pub fn with_status(&self, status: EncodingStatus) -> ExportJobs {
+        let mut jobs = (*self.jobs).clone();
+        if let Some(job) = jobs.iter_mut().find(|job| !job.is_done()) {
+            job.status = match status {
+                EncodingStatus::Encoding(p) => JobStatus::Running(p),
+                EncodingStatus::Finished => JobStatus::Finished,
+                EncodingStatus::Error(e) => JobStatus::Failed(e),
+            };
+        } else {
+            log::warn!("got an encoding status update, but there was no job running");
+        }
+        ExportJobs {
+            jobs: Arc::new(jobs),
+        }
+    }
+
+    pub fn without_done_jobs(&self) -> ExportJobs {
+        ExportJobs {
+            jobs: Arc::new(self.jobs.iter().filter(|j| !j.is_done()).cloned().collect()),
+        }
+    }
+}
+
+/// The handle to the background thread that runs the exports.
+pub struct ExportQueue {
+    jobs: Sender<ExportCmd>,
+
+    /// Status updates from the encoder. These are for the job that is currently running; every
+    /// job ends with either `EncodingStatus::Finished` or `EncodingStatus::Error`.
+    pub status: Receiver<EncodingStatus>,
+}
+
+impl ExportQueue {
+    pub fn new() -> ExportQueue {
+        let (jobs_tx, jobs_rx) = channel::<ExportCmd>();
+        let (status_tx, status_rx) = channel();
+        std::thread::spawn(move || {
+            for cmd in jobs_rx {
+                crate::encode::encode_blocking(cmd, status_tx.clone());
+            }
+        });
+        ExportQueue {
+            jobs: jobs_tx,
+            status: status_rx,
+        }
+    }
+
+    pub fn push(&self, cmd: ExportCmd) {
+        if self.jobs.send(cmd).is_err() {
+            log::error!("the export thread has stopped");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_goes_to_first_unfinished_job() {
+        let jobs = ExportJobs::default()
+            .with_new_job("a".to_owned())
+            .with_new_job("b".to_owned())
+            .with_status(EncodingStatus::Finished)
+            .with_status(EncodingStatus::Error("oops".to_owned()));
+        assert_eq!(jobs.jobs[0].status, JobStatus::Finished);
+        assert_eq!(jobs.jobs[1].status, JobStatus::Failed("oops".to_owned()));
+
+        let jobs = jobs.with_new_job("c".to_owned()).without_done_jobs();
+        assert_eq!(jobs.jobs.len(), 1);
+        assert_eq!(jobs.jobs[0].status, JobStatus::Pending);
+    }
+}
@@ -0,0 +1,84 @@
+//! System-wide keyboard shortcuts, which work even when some other window has the focus (for
+//! example, for narrating over something in a browser without switching windows).
+
+use druid::{ExtEventSink, Selector};
+use livesplit_hotkey::{Hook, KeyCode};
+
+use crate::cmd;
+use crate::prefs::Preferences;
+
+pub struct GlobalHotkeys {
+    sink: ExtEventSink,
+    // This is `None` if we couldn't hook into the system's keyboard events.
+    hook: Option<Hook>,
+    // The keys (as they're written in the preferences) that we registered last time.
+    keys: Vec<String>,
+    registered: Vec<KeyCode>,
+}
+
+impl GlobalHotkeys {
+    /// Starts listening for global hotkeys. None get registered until the first `update`.
+    pub fn new(sink: ExtEventSink) -> GlobalHotkeys {
+        let hook = match Hook::new() {
+            Ok(hook) => Some(hook),
+            Err(e) => {
+                log::error!("failed to set up global hotkeys: {:?}", e);
+                None
+            }
+        };
+        GlobalHotkeys {
+            sink,
+            hook,
+            keys: Vec::new(),
+            registered: Vec::new(),
+        }
+    }
+
+    /// Registers the global hotkeys from `prefs`, replacing the ones that were registered before.
+    pub fn update(&mut self, prefs: &Preferences) {
+        let wanted = [
+            (&prefs.global_draw_key, cmd::TOGGLE_DRAW),
+            (&prefs.global_talk_key, cmd::TOGGLE_TALK),
+        ];
+        let keys: Vec<String> = wanted.iter().map(|(key, _)| (*key).clone()).collect();
+        if keys == self.keys {
+            return;
+        }
+        self.keys = keys;
+
+        let hook = match &self.hook {
+            Some(hook) => hook,
+            None => return,
+        };
+        for key in self.registered.drain(..) {
+            if let Err(e) = hook.unregister(key) {
+                log::error!("failed to unregister global hotkey {:?}: {:?}", key, e);
+            }
+        }
+        for &(name, selector) in &wanted {
+            if name.is_empty() {
+                continue;
+            }
+            let key = match name.parse::<KeyCode>() {
+                Ok(key) => key,
+                Err(_) => {
+                    log::error!("unknown key for a global hotkey: \"{}\"", name);
+                    continue;
+                }
+            };
+            match hook.register(key, toggle(self.sink.clone(), selector)) {
+                Ok(()) => self.registered.push(key),
+                Err(e) => log::error!("failed to register global hotkey {}: {:?}", name, e),
+            }
+        }
+    }
+}
+
+// The hotkeys get called on the hook's thread, so they send their commands through the sink.
+fn toggle(sink: ExtEventSink, selector: Selector) -> impl FnMut() + Send + 'static {
+    move || {
+        if let Err(e) = sink.submit_command(selector, Box::new(()), None) {
+            log::error!("failed to send a global hotkey: {}", e);
+        }
+    }
+}
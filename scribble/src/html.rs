@@ -0,0 +1,258 @@
+//! Exporting to a self-contained web page, so that animations can be embedded in a website and
+//! stay sharp at any size.
+//!
+//! The page contains the animated SVG from `crate::svg`, the narration (as a WAV file in a data
+//! URL), and a few lines of JavaScript for playing, pausing and seeking. SVG animations can be
+//! paused and moved to any time, so the player doesn't need to know how to draw anything. While
+//! playing, the narration sets the pace and the drawing follows it.
+
+use scribble_curves::{time, Time};
+
+use crate::audio::{AudioSnippetsData, Cursor, SAMPLE_RATE};
+
+/// The sample rate of the narration in the web page. This is plenty for speech, and it makes the
+/// page a third of the size that it would be at our usual rate.
+const WEB_SAMPLE_RATE: u32 = 16000;
+
+const PLAYER: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="generator" content="{{generator}}">
+<title>{{title}}</title>
+<style>
+body { margin: 0; font-family: sans-serif; }
+#scribble svg { display: block; width: 100%; height: auto; }
+#controls { display: flex; align-items: center; gap: 0.5em; padding: 0.5em; }
+#seek { flex: 1; }
+</style>
+</head>
+<body>
+<div id="scribble">
+{{svg}}
+</div>
+<div id="controls">
+<button id="play">Play</button>
+<input id="seek" type="range" min="0" max="{{duration}}" step="0.01" value="0">
+<span id="clock"></span>
+</div>
+{{audio}}
+<script>
+(function () {
+  var svg = document.querySelector("#scribble svg");
+  var audio = document.getElementById("narration");
+  var button = document.getElementById("play");
+  var seek = document.getElementById("seek");
+  var clock = document.getElementById("clock");
+  var duration = {{duration}};
+  var playing = false;
+
+  function format(t) {
+    var s = Math.floor(t);
+    return Math.floor(s / 60) + ":" + ("0" + (s % 60)).slice(-2);
+  }
+  function show(t) {
+    seek.value = t;
+    clock.textContent = format(t) + " / " + format(duration);
+  }
+  function pause() {
+    playing = false;
+    svg.pauseAnimations();
+    if (audio) audio.pause();
+    button.textContent = "Play";
+  }
+  function tick() {
+    if (!playing) return;
+    var t = svg.getCurrentTime();
+    if (audio && !audio.paused && !audio.ended && Math.abs(audio.currentTime - t) > 0.1) {
+      t = audio.currentTime;
+      svg.setCurrentTime(t);
+    }
+    if (t >= duration) {
+      t = duration;
+      pause();
+    }
+    show(t);
+    requestAnimationFrame(tick);
+  }
+  function play() {
+    if (svg.getCurrentTime() >= duration) svg.setCurrentTime(0);
+    playing = true;
+    if (audio) {
+      audio.currentTime = svg.getCurrentTime();
+      audio.play();
+    }
+    svg.unpauseAnimations();
+    button.textContent = "Pause";
+    requestAnimationFrame(tick);
+  }
+
+  button.addEventListener("click", function () {
+    if (playing) pause(); else play();
+  });
+  seek.addEventListener("input", function () {
+    var t = parseFloat(seek.value);
+    svg.setCurrentTime(t);
+    if (audio) audio.currentTime = t;
+    show(t);
+  });
+  svg.pauseAnimations();
+  svg.setCurrentTime(0);
+  show(0);
+})();
+</script>
+</body>
+</html>
+"##;
+
+// Fills in the `{{name}}`s in a template. Unlike calling `str::replace` for each one, this doesn't
+// look for names inside the values that it fills in (which might contain anything).
+fn fill(template: &str, values: &[(&str, &str)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after.find("}}").expect("unterminated template variable");
+        let name = &after[..end];
+        let value = values
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| *v)
+            .expect("unknown template variable");
+        out.push_str(value);
+        rest = &after[end + 2..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Encodes mono 16-bit audio as a WAV file.
+fn wav(samples: &[i16], sample_rate: u32) -> Vec<u8> {
+    let data_len = samples.len() as u32 * 2;
+    let mut out = Vec::with_capacity(44 + data_len as usize);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(36 + data_len).to_le_bytes());
+    out.extend_from_slice(b"WAVEfmt ");
+    out.extend_from_slice(&16u32.to_le_bytes());
+    // Uncompressed, with one channel.
+    out.extend_from_slice(&1u16.to_le_bytes());
+    out.extend_from_slice(&1u16.to_le_bytes());
+    out.extend_from_slice(&sample_rate.to_le_bytes());
+    // Bytes per second, bytes per sample, and bits per sample.
+    out.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+    out.extend_from_slice(&2u16.to_le_bytes());
+    out.extend_from_slice(&16u16.to_le_bytes());
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&data_len.to_le_bytes());
+    for s in samples {
+        out.extend_from_slice(&s.to_le_bytes());
+    }
+    out
+}
+
+/// Mixes the narration from the beginning until `duration`, at `WEB_SAMPLE_RATE`. If there isn't
+/// any, returns `None`.
+fn narration(audio: &AudioSnippetsData, duration: Time) -> Option<Vec<i16>> {
+    audio.snippets().next()?;
+    let mut buf = vec![0i16; duration.as_audio_idx(SAMPLE_RATE)];
+    Cursor::new(audio, time::ZERO, SAMPLE_RATE, true).mix_to_buffer(audio, &mut buf[..]);
+    // Averaging each group of samples is a (crude) low-pass filter.
+    let ratio = (SAMPLE_RATE / WEB_SAMPLE_RATE) as usize;
+    Some(
+        buf.chunks(ratio)
+            .map(|c| (c.iter().map(|&x| x as i32).sum::<i32>() / c.len() as i32) as i16)
+            .collect(),
+    )
+}
+
+/// Makes a web page that plays the animation in `svg` (as made by `crate::svg::to_svg`), along
+/// with the narration in `wav` (if there is any).
+pub fn to_html(svg: &str, wav: Option<&[u8]>, title: &str, duration: Time) -> String {
+    let audio = match wav {
+        Some(wav) => format!(
+            "<audio id=\"narration\" preload=\"auto\" src=\"data:audio/wav;base64,{}\"></audio>",
+            base64::encode(wav)
+        ),
+        None => String::new(),
+    };
+    let title = if title.is_empty() { "scribble" } else { title };
+    fill(
+        PLAYER,
+        &[
+            ("generator", crate::encode::SCRIBBLE_VERSION),
+            ("title", &crate::svg::escape(title)),
+            ("svg", svg),
+            (
+                "duration",
+                &format!("{:.3}", duration.as_micros() as f64 / 1e6),
+            ),
+            ("audio", &audio),
+        ],
+    )
+}
+
+/// Writes the animation in `cmd` to a web page.
+pub fn write_html(cmd: &crate::cmd::ExportCmd, duration: Time) -> anyhow::Result<()> {
+    let (width, height) = cmd.export.video_size(cmd.scribble.settings.aspect_ratio);
+    let svg = crate::svg::to_svg(
+        &cmd.scribble.snippets,
+        &cmd.scribble.images,
+        &cmd.scribble.settings,
+        width,
+        height,
+        duration,
+    );
+    let audio = narration(&cmd.scribble.audio_snippets, duration)
+        .map(|samples| wav(&samples, WEB_SAMPLE_RATE));
+    let html = to_html(
+        &svg,
+        audio.as_deref(),
+        &cmd.scribble.settings.title,
+        duration,
+    );
+    std::fs::write(&cmd.filename, html)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fill_template() {
+        let filled = fill("a{{x}}b{{y}}c", &[("x", "{{y}}"), ("y", "1")]);
+        assert_eq!(filled, "a{{y}}b1c");
+    }
+
+    #[test]
+    fn wav_header() {
+        let bytes = wav(&[1, -1], 16000);
+        assert_eq!(bytes.len(), 48);
+        assert_eq!(&bytes[..4], b"RIFF");
+        assert_eq!(&bytes[4..8], &40u32.to_le_bytes());
+        assert_eq!(&bytes[24..28], &16000u32.to_le_bytes());
+        assert_eq!(&bytes[40..44], &4u32.to_le_bytes());
+        assert_eq!(&bytes[44..], &[1, 0, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn page() {
+        let svg = "<svg><desc>{{audio}}</desc></svg>";
+        let html = to_html(svg, None, "Fish & chips", Time::from_micros(2_500_000));
+        assert!(html.contains("<title>Fish &amp; chips</title>"));
+        assert!(html.contains(svg));
+        assert!(html.contains("var duration = 2.500;"));
+        assert!(!html.contains("<audio"));
+
+        let html = to_html(svg, Some(&[1, 2, 3]), "", Time::from_micros(0));
+        assert!(html.contains("<title>scribble</title>"));
+        assert!(html.contains("src=\"data:audio/wav;base64,AQID\""));
+    }
+
+    #[test]
+    fn no_narration() {
+        let duration = Time::from_micros(1_000_000);
+        assert!(narration(&AudioSnippetsData::default(), duration).is_none());
+    }
+}
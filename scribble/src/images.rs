@@ -0,0 +1,85 @@
+//! Still images (e.g. pasted screenshots or imported photos) that appear on the canvas at a
+//! particular time.
+
+use std::path::{Path, PathBuf};
+
+pub use scribble_project::images::{
+    ImageCache, ImageSnippetData, ImageSnippetId, ImageSnippetsData,
+};
+
+/// The file extensions of images that we know how to import.
+pub const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "bmp"];
+
+/// Does this path look like an image that we can import?
+pub fn is_image_path(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| IMAGE_EXTENSIONS.contains(&e.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+/// Finds the path of an image file in some text from the clipboard. When a file gets copied in a
+/// file manager, the clipboard has its URI (possibly in a list, like "text/uri-list"); some file
+/// managers can also copy the plain (possibly quoted) path.
+pub fn image_path_from_clipboard(text: &str) -> Option<PathBuf> {
+    text.lines()
+        .map(str::trim)
+        // Comments are allowed in URI lists.
+        .filter(|line| !line.starts_with('#'))
+        .map(|line| line.trim_matches('"'))
+        .filter_map(|line| match line.strip_prefix("file://") {
+            Some(uri) => percent_decode(uri),
+            None => Some(line.to_owned()),
+        })
+        .map(|path| {
+            // Windows file URIs look like "file:///C:/pictures/a.png".
+            let is_drive = path.len() > 2 && path.as_bytes()[2] == b':';
+            match path.strip_prefix('/') {
+                Some(rest) if is_drive => PathBuf::from(rest),
+                _ => PathBuf::from(path),
+            }
+        })
+        .find(|path| path.is_absolute() && is_image_path(path))
+}
+
+// Decodes the "%20"-style escapes in a URI.
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clipboard_paths() {
+        let path = |text: &str| image_path_from_clipboard(text);
+        assert_eq!(
+            path("file:///home/me/Screenshot%20from%202020.png\r\n"),
+            Some(PathBuf::from("/home/me/Screenshot from 2020.png"))
+        );
+        assert_eq!(
+            path("# copied\nfile:///home/me/notes.txt\nfile:///home/me/a.JPG"),
+            Some(PathBuf::from("/home/me/a.JPG"))
+        );
+        assert_eq!(
+            path("\"/home/me/a.gif\""),
+            Some(PathBuf::from("/home/me/a.gif"))
+        );
+        assert_eq!(path("a.png"), None);
+        assert_eq!(path("file:///home/me/bad%zz.png"), None);
+        assert_eq!(path("some text"), None);
+    }
+}
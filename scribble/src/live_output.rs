@@ -0,0 +1,211 @@
+//! Publishing the canvas live, so that scribble can be used as a whiteboard in a video call.
+//!
+//! While live output is on, a background thread writes frames of whatever the drawing pane is
+//! showing to `Preferences::live_output_target` (typically a v4l2loopback device, or a named
+//! pipe that OBS reads from). The frames are in the same raw RGBA format as `--stream-to` (see
+//! `encode::stream_frames`), and they keep coming at a steady rate even when nothing changes,
+//! because virtual cameras expect that. Recording carries on as usual in the meantime.
+//!
+//! The target gets opened on the background thread too, because opening a named pipe blocks
+//! until something opens the other end. If opening or writing fails, the error comes back
+//! through [`LiveOutput::failure`].
+
+use druid::Data;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use scribble_curves::{SnippetData, SnippetsData, Time};
+
+use crate::data::{AppState, ProjectSettings};
+use crate::encode::FrameRenderer;
+use crate::images::ImageSnippetsData;
+
+/// Everything that goes into a frame of live output.
+#[derive(Clone, Data)]
+struct LiveFrame {
+    snippets: SnippetsData,
+    images: ImageSnippetsData,
+    settings: ProjectSettings,
+    time: Time,
+    show_pen: bool,
+}
+
+impl LiveFrame {
+    fn new(data: &AppState) -> LiveFrame {
+        // The stroke that's being drawn right now isn't a snippet yet, but it should be visible.
+        let mut snippets = data.scribble.snippets.clone();
+        let in_progress = data
+            .new_snippet_as_curve()
+            .into_iter()
+            .chain(data.scribble.new_curve.as_deref().cloned());
+        for curve in in_progress {
+            if !curve.times.is_empty() {
+                snippets = snippets.with_new_snippet(SnippetData::new(curve)).0;
+            }
+        }
+        LiveFrame {
+            snippets,
+            images: data.scribble.images.clone(),
+            settings: data.scribble.settings.clone(),
+            time: data.time(),
+            show_pen: data.show_pen,
+        }
+    }
+}
+
+pub struct LiveOutput {
+    target: PathBuf,
+    last: Option<LiveFrame>,
+    // The most recent frame that the output thread hasn't rendered yet.
+    pending: Arc<Mutex<Option<LiveFrame>>>,
+    stop: Arc<AtomicBool>,
+    // Whether the output thread has opened the target yet.
+    opened: Arc<AtomicBool>,
+    failed: Receiver<String>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl LiveOutput {
+    /// Starts writing frames to `target`, at the size and frame rate of the current export
+    /// settings. The size stays the same until live output is stopped, even if the canvas
+    /// changes shape.
+    pub fn start(target: &Path, data: &AppState) -> LiveOutput {
+        let (width, height) = data.export.video_size(data.scribble.settings.aspect_ratio);
+        let frame_time = Duration::from_secs_f64(1.0 / data.export.frame_rate.fps() as f64);
+        log::info!(
+            "publishing {}x{} RGBA frames to {:?}",
+            width,
+            height,
+            target
+        );
+
+        let pending = Arc::new(Mutex::new(Some(LiveFrame::new(data))));
+        let stop = Arc::new(AtomicBool::new(false));
+        let opened = Arc::new(AtomicBool::new(false));
+        let (fail_tx, failed) = channel();
+        let thread_target = target.to_owned();
+        let thread_pending = Arc::clone(&pending);
+        let thread_stop = Arc::clone(&stop);
+        let thread_opened = Arc::clone(&opened);
+        let thread = std::thread::spawn(move || {
+            let result = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .open(&thread_target)
+                .map_err(anyhow::Error::from)
+                .and_then(|out| {
+                    thread_opened.store(true, Ordering::SeqCst);
+                    publish(
+                        out,
+                        thread_pending,
+                        thread_stop,
+                        (width, height),
+                        frame_time,
+                    )
+                });
+            if let Err(e) = result {
+                log::error!("live output to {:?} stopped: {}", thread_target, e);
+                let _ = fail_tx.send(e.to_string());
+            }
+        });
+        LiveOutput {
+            target: target.to_owned(),
+            last: None,
+            pending,
+            stop,
+            opened,
+            failed,
+            thread: Some(thread),
+        }
+    }
+
+    /// If the output thread has given up (because it couldn't open the target, say), this is
+    /// why.
+    pub fn failure(&self) -> Option<String> {
+        self.failed.try_recv().ok()
+    }
+
+    /// The file or device that we're writing to.
+    pub fn target(&self) -> &Path {
+        &self.target
+    }
+
+    /// Tells the output thread what the canvas looks like now. This is cheap if nothing changed.
+    pub fn update(&mut self, data: &AppState) {
+        let frame = LiveFrame::new(data);
+        if self.last.as_ref().map_or(false, |last| last.same(&frame)) {
+            return;
+        }
+        *self.pending.lock().unwrap() = Some(frame.clone());
+        self.last = Some(frame);
+    }
+}
+
+impl Drop for LiveOutput {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // A thread that's still waiting for a named pipe to be opened at the other end might
+        // wait forever, so we let it finish by itself (it stops as soon as the pipe opens).
+        if !self.opened.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                log::error!("the live output thread panicked");
+            }
+        }
+    }
+}
+
+// Writes a frame every `frame_time`, re-rendering whenever there's a new `pending` frame and
+// repeating the previous one otherwise.
+fn publish(
+    mut out: impl Write,
+    pending: Arc<Mutex<Option<LiveFrame>>>,
+    stop: Arc<AtomicBool>,
+    (width, height): (u32, u32),
+    frame_time: Duration,
+) -> anyhow::Result<()> {
+    let mut renderer: Option<(ProjectSettings, FrameRenderer)> = None;
+    let mut pixels = vec![0; width as usize * height as usize * 4];
+    let mut next_frame = Instant::now();
+    while !stop.load(Ordering::SeqCst) {
+        let frame = pending.lock().unwrap().take();
+        if let Some(frame) = frame {
+            // The background and the watermark are baked into the renderer.
+            let stale = renderer
+                .as_ref()
+                .map_or(true, |(settings, _)| !settings.same(&frame.settings));
+            if stale {
+                let r = FrameRenderer::new(
+                    frame.snippets.clone(),
+                    frame.images.clone(),
+                    &frame.settings,
+                    frame.show_pen,
+                    width,
+                    height,
+                )?;
+                renderer = Some((frame.settings.clone(), r));
+            }
+            let (_, r) = renderer.as_mut().unwrap();
+            r.set_contents(frame.snippets, frame.images, frame.show_pen);
+            pixels = r.render(frame.time)?;
+        }
+        out.write_all(&pixels)?;
+        out.flush()?;
+
+        next_frame += frame_time;
+        match next_frame.checked_duration_since(Instant::now()) {
+            Some(wait) => std::thread::sleep(wait),
+            // If we fell behind (say, because a reader was slow), don't try to catch up.
+            None => next_frame = Instant::now(),
+        }
+    }
+    Ok(())
+}
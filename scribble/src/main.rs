@@ -1,16 +1,44 @@
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches, SubCommand};
 use druid::theme;
-use druid::{AppLauncher, Color, Key, LocalizedString, WindowDesc};
+use druid::{AppLauncher, Color, Key, WindowDesc};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
+use scribble_core::save_file::{SaveFileData, SaveFormat};
+
 mod app_delegate;
 mod audio;
+mod autosave;
+mod captions;
 mod cmd;
+mod collab;
+mod command_palette;
+mod controllers;
 mod data;
 mod encode;
+mod export_queue;
+mod global_hotkeys;
+mod html;
+mod images;
+mod live_output;
 mod menus;
+mod midi_sync;
+mod modal;
+mod palette_file;
+mod pdf;
+mod plugins;
+mod prefs;
+mod project_io;
+mod remote;
+mod remote_pen;
 mod snippet_layout;
-mod undo;
+mod svg;
+mod tabs;
+mod time_remap;
+mod touch;
+mod transcribe;
+mod whiteboard;
 mod widgets;
 
 const BUTTON_BACKGROUND_DISABLED: Key<Color> = Key::new("button_background_disabled");
@@ -23,7 +51,9 @@ const BUTTON_ICON_IDLE: Key<Color> = Key::new("scribble-radio-button-icon-idle")
 pub const FRAME_TIME: Duration = Duration::from_millis(16);
 pub const TEXT_SIZE_SMALL: Key<f64> = Key::new("text_size_small");
 
-use data::AppState;
+use cmd::ExportCmd;
+use data::{AppState, ScribbleState};
+use encode::ExportSettings;
 use widgets::Root;
 
 const MAJOR: u32 = pkg_version::pkg_version_major!();
@@ -33,18 +63,22 @@ const PATCH: u32 = pkg_version::pkg_version_patch!();
 fn main() {
     env_logger::init();
 
+    // We only need gstreamer for exporting, and some export formats work without it.
     if let Err(e) = gstreamer::init() {
-        log::error!("failed to init gstreamer: {}", e);
-        return;
+        log::warn!("failed to init gstreamer: {}", e);
     }
 
-    let matches = App::new("scribble")
+    let app = App::new("scribble")
         .version(format!("{}.{}.{}", MAJOR, MINOR, PATCH).as_str())
         .author("Joe Neeman <joeneeman@gmail.com>")
         .arg(
             Arg::with_name("FILE")
-                .help("The file to open")
-                .takes_value(true),
+                .help(
+                    "The projects to open (each in its own tab). When exporting or streaming, \
+                     only the first one is used.",
+                )
+                .takes_value(true)
+                .multiple(true),
         )
         .arg(
             Arg::with_name("export-to")
@@ -52,32 +86,255 @@ fn main() {
                 .long("export-to")
                 .takes_value(true),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("stream-to")
+                .help(
+                    "Stream raw RGBA frames (in real time) to a file, named pipe, or device \
+                     instead of opening the animation. Use '-' for stdout.",
+                )
+                .long("stream-to")
+                .takes_value(true)
+                .conflicts_with("export-to"),
+        )
+        .arg(
+            Arg::with_name("show-pen")
+                .help("When exporting, show the position of the pen")
+                .long("show-pen"),
+        )
+        .subcommand(
+            SubCommand::with_name("render")
+                .about("Renders a project to a video without opening a window")
+                .arg(
+                    Arg::with_name("PROJECT")
+                        .help("The project to render")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .help("The file to write (the format comes from the extension)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("resolution")
+                        .help("The length (in pixels) of the shorter side of the video")
+                        .long("resolution")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("fps")
+                        .help("The frame rate of the video: 24, 30 or 60")
+                        .long("fps")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("start")
+                        .help("Where to start rendering, in seconds")
+                        .long("start")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("end")
+                        .help("Where to stop rendering, in seconds")
+                        .long("end")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("show-pen")
+                        .help("Show the position of the pen")
+                        .long("show-pen"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("render-all")
+                .about(
+                    "Renders every project in a directory (and its subdirectories) without \
+                     opening a window",
+                )
+                .arg(
+                    Arg::with_name("DIR")
+                        .help("The directory to look for projects in")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("preset")
+                        .help(
+                            "The name of the export preset to use. If this isn't given, each \
+                             project uses its own export settings.",
+                        )
+                        .long("preset")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("extension")
+                        .help(
+                            "The extension (and so the format) of the rendered files. It \
+                             defaults to the one for the preset's format.",
+                        )
+                        .long("extension")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("out-dir")
+                        .help(
+                            "Where to put the rendered files. By default, each one goes next to \
+                             its project.",
+                        )
+                        .long("out-dir")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("show-pen")
+                        .help("Show the position of the pen")
+                        .long("show-pen"),
+                ),
+        );
+    let app = app.subcommand(
+        SubCommand::with_name("convert")
+            .about("Converts a project to plain json (see the file format docs), or back again")
+            .arg(
+                Arg::with_name("INPUT")
+                    .help("The file to read: a project, or a json file")
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("OUTPUT")
+                    .help("The file to write. If it ends in .json, it gets written as plain json.")
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("binary")
+                    .help("When writing a project, use the binary format")
+                    .long("binary"),
+            ),
+    );
+    #[cfg(unix)]
+    let app = app.arg(
+        Arg::with_name("remote-socket")
+            .help(
+                "Listen for remote control requests on a Unix socket at this path (for \
+                 automated tests)",
+            )
+            .long("remote-socket")
+            .takes_value(true),
+    );
+    let matches = app.get_matches();
 
-    let initial_state = if let Some(path) = matches.value_of("FILE") {
-        match crate::data::SaveFileData::load_from_path(path) {
-            Ok(save_file) => AppState::from_save_file(save_file),
+    audio::clear_audio_cache();
+    if let Some(matches) = matches.subcommand_matches("render") {
+        if let Err(e) = render(matches) {
+            eprintln!("Rendering error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("render-all") {
+        if !render_all(matches) {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("convert") {
+        if let Err(e) = convert(matches) {
+            eprintln!("Conversion error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let paths: Vec<PathBuf> = matches
+        .values_of_os("FILE")
+        .map(|paths| paths.map(open_path).collect())
+        .unwrap_or_default();
+
+    let output = matches
+        .value_of("export-to")
+        .map(|path| (path, false))
+        .or_else(|| matches.value_of("stream-to").map(|target| (target, true)));
+    if let Some((output, streaming)) = output {
+        let path = match paths.first() {
+            Some(path) => path,
+            None => {
+                log::error!("There's no project to export");
+                return;
+            }
+        };
+        let mut export = match load_for_export(path) {
+            Ok(export) => export,
             Err(e) => {
-                log::error!("Error opening save file: {}", e);
+                log::error!("{}", e);
                 return;
             }
+        };
+        export.filename = output.into();
+        export.show_pen = matches.is_present("show-pen");
+        if streaming {
+            stream(export, output);
+        } else {
+            encode(export);
         }
-    } else {
-        AppState::default()
-    };
-
-    if let Some(output_path) = matches.value_of("export-to") {
-        encode(initial_state, output_path);
         return;
     }
 
+    // When we get launched by the file manager (because the user double-clicked on a project),
+    // the project comes in as a command line argument. In that case a project that doesn't load
+    // shouldn't stop the editor from starting, so we just skip it.
+    let mut initial_state = AppState::with_prefs(prefs::Preferences::load());
+    initial_state.init_audio();
+    for path in paths {
+        let status = match SaveFileData::load_from_path(&path) {
+            Ok(save_file) => {
+                let save_file = save_file.with_audio_loaded();
+                let history = if initial_state.prefs.save_undo_history {
+                    project_io::load_history(&path, &save_file).unwrap_or_else(|e| {
+                        log::warn!("couldn't load the undo history for {:?}: {}", path, e);
+                        Vec::new()
+                    })
+                } else {
+                    Vec::new()
+                };
+                project_io::Status::Loaded(path, Box::new(save_file), history)
+            }
+            Err(e) => project_io::Status::Failed(format!("error loading {:?}: {}", path, e)),
+        };
+        project_io::apply_status(&mut initial_state, status);
+    }
+    if initial_state.save_path.is_none()
+        && autosave::has_recovery_data(initial_state.save_path.as_deref())
+    {
+        initial_state.dialog = Some(data::Dialog::RestoreAutosave);
+    }
+
     let main_window = WindowDesc::new(|| Root::new())
-        .title(LocalizedString::new("Scribble"))
+        .title(|data: &AppState, _env: &druid::Env| data.window_title())
         .menu(menus::make_menu(&initial_state))
         .window_size((400.0, 400.0));
 
-    AppLauncher::with_window(main_window)
-        .delegate(app_delegate::Delegate::default())
+    let launcher = AppLauncher::with_window(main_window);
+    touch::start();
+    let mut global_hotkeys = global_hotkeys::GlobalHotkeys::new(launcher.get_external_handle());
+    global_hotkeys.update(&initial_state.prefs);
+    let mut controllers = controllers::Controllers::new(launcher.get_external_handle());
+    controllers.update(&initial_state.prefs);
+    let mut remote = remote::RemoteControl::new(launcher.get_external_handle());
+    remote.update(&initial_state.prefs);
+    #[cfg(unix)]
+    {
+        if let Some(path) = matches.value_of_os("remote-socket") {
+            if let Err(e) = remote.listen_on_socket(PathBuf::from(path)) {
+                log::error!("failed to listen on {:?}: {}", path, e);
+            }
+        }
+    }
+    let mut remote_pen = remote_pen::PenServer::new(launcher.get_external_handle());
+    remote_pen.update(&initial_state.prefs);
+
+    launcher
+        .delegate(app_delegate::Delegate::new(
+            global_hotkeys,
+            controllers,
+            remote,
+            remote_pen,
+        ))
         .configure_env(|e, _| {
             e.set(theme::BUTTON_LIGHT, Color::rgb8(0x70, 0x70, 0x70));
             e.set(BUTTON_BACKGROUND_DISABLED, Color::rgb8(0x55, 0x55, 0x55));
@@ -93,12 +350,15 @@ fn main() {
         .expect("failed to launch");
 }
 
-fn encode(data: AppState, path: &str) {
-    let export = cmd::ExportCmd {
-        snippets: data.scribble.snippets,
-        audio_snippets: data.scribble.audio_snippets,
-        filename: path.into(),
-    };
+// File managers usually give us absolute paths, but paths typed on the command line are relative
+// to the working directory. We want absolute ones, so that the autosave and the "recent files"
+// menu can find the project again.
+fn open_path(path: &OsStr) -> PathBuf {
+    let path = Path::new(path);
+    path.canonicalize().unwrap_or_else(|_| path.to_owned())
+}
+
+fn encode(export: ExportCmd) {
     let (tx, rx) = std::sync::mpsc::channel();
     std::thread::spawn(move || crate::encode::encode_blocking(export, tx));
 
@@ -106,9 +366,295 @@ fn encode(data: AppState, path: &str) {
         use crate::encode::EncodingStatus;
         match msg {
             // TODO: nicer display
-            EncodingStatus::Encoding(pct) => eprintln!("{}", pct),
+            EncodingStatus::Encoding(progress) => eprintln!("{}", progress),
             EncodingStatus::Error(s) => eprintln!("Encoding error: {}", s),
             EncodingStatus::Finished => eprintln!("Finished!"),
         }
     }
 }
+
+// Renders a project without starting the GUI, so that videos can be built on machines without a
+// display.
+fn render(matches: &ArgMatches) -> anyhow::Result<()> {
+    let project = open_path(matches.value_of_os("PROJECT").unwrap());
+    let output = matches.value_of_os("OUTPUT").unwrap();
+    let mut export = load_for_export(&project)?;
+    export.filename = output.into();
+    export.show_pen = matches.is_present("show-pen");
+    if let Some(res) = matches.value_of("resolution") {
+        export.export.resolution = parse_resolution(res, &mut export.export.custom_resolution)?;
+    }
+    if let Some(fps) = matches.value_of("fps") {
+        export.export.frame_rate = parse_frame_rate(fps)?;
+    }
+
+    let start = matches.value_of("start").map(parse_seconds).transpose()?;
+    let end = matches.value_of("end").map(parse_seconds).transpose()?;
+    if start.is_some() || end.is_some() {
+        let start = start.unwrap_or(scribble_curves::time::ZERO);
+        let end = end.unwrap_or_else(|| crate::encode::export_end_time(&export.scribble));
+        if end <= start {
+            anyhow::bail!("the end of the range must come after its start");
+        }
+        export.range = Some(scribble_curves::time::TimeSpan::new(start, end));
+    }
+    run_export(export)
+}
+
+// Converts between projects and their plain json representation (as documented in
+// `scribble_core::save_file`), so that other tools can read and write projects without dealing
+// with compression.
+fn convert(matches: &ArgMatches) -> anyhow::Result<()> {
+    let input = Path::new(matches.value_of_os("INPUT").unwrap());
+    let output = Path::new(matches.value_of_os("OUTPUT").unwrap());
+    let is_json = |path: &Path| path.extension().map_or(false, |ext| ext == "json");
+
+    let data = if is_json(input) {
+        let value = serde_json::from_reader(std::io::BufReader::new(std::fs::File::open(input)?))?;
+        SaveFileData::from_json_value(value)?.with_links_resolved(input)
+    } else {
+        SaveFileData::load_from_path(input)?
+    };
+
+    if is_json(output) {
+        let value = data.with_links_relative_to(output).to_json_value()?;
+        let file = std::io::BufWriter::new(std::fs::File::create(output)?);
+        serde_json::to_writer_pretty(file, &value)?;
+    } else {
+        let format = if matches.is_present("binary") {
+            SaveFormat::Binary
+        } else {
+            SaveFormat::Json
+        };
+        data.save_to_path(output, format)?;
+    }
+    Ok(())
+}
+
+// Renders all the projects in a directory, carrying on past the ones that fail. Returns true if
+// they all succeeded.
+fn render_all(matches: &ArgMatches) -> bool {
+    let dir = Path::new(matches.value_of_os("DIR").unwrap());
+    let out_dir = matches.value_of_os("out-dir").map(Path::new);
+    let preset = match matches.value_of("preset") {
+        Some(name) => {
+            let prefs = prefs::Preferences::load();
+            match prefs.export_presets.iter().find(|p| p.name == name) {
+                Some(preset) => Some(preset.settings.clone()),
+                None => {
+                    eprintln!("There's no export preset named {:?}", name);
+                    return false;
+                }
+            }
+        }
+        None => None,
+    };
+
+    let mut projects = Vec::new();
+    if let Err(e) = find_projects(dir, &mut projects) {
+        eprintln!("Error looking for projects in {:?}: {}", dir, e);
+        return false;
+    }
+    projects.sort();
+
+    let mut failures = Vec::new();
+    for project in &projects {
+        let result = load_for_export(project).and_then(|mut export| {
+            if let Some(settings) = &preset {
+                export.export = settings.clone();
+            }
+            export.show_pen = matches.is_present("show-pen");
+            let extension = matches
+                .value_of("extension")
+                .unwrap_or(export.export.format.file_spec().extensions[0]);
+            export.filename = batch_output_path(project, dir, out_dir, extension);
+            if let Some(parent) = export.filename.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            eprintln!("Rendering {:?} to {:?}", project, export.filename);
+            run_export(export)
+        });
+        if let Err(e) = result {
+            eprintln!("Failed to render {:?}: {}", project, e);
+            failures.push((project, e));
+        }
+    }
+
+    eprintln!(
+        "Rendered {} of {} projects",
+        projects.len() - failures.len(),
+        projects.len()
+    );
+    for (project, e) in &failures {
+        eprintln!("  failed: {:?} ({})", project, e);
+    }
+    failures.is_empty()
+}
+
+// Recursively collects all the projects in `dir`.
+fn find_projects(dir: &Path, out: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_projects(&path, out)?;
+        } else if path.extension() == Some(OsStr::new("scb")) {
+            out.push(path);
+        }
+    }
+    Ok(())
+}
+
+// Where to put the rendered version of `project` (which was found in `dir`). If there's an output
+// directory, the layout of the subdirectories gets copied into it.
+fn batch_output_path(project: &Path, dir: &Path, out_dir: Option<&Path>, ext: &str) -> PathBuf {
+    let output = project.with_extension(ext);
+    match (out_dir, output.strip_prefix(dir)) {
+        (Some(out_dir), Ok(rel)) => out_dir.join(rel),
+        _ => output,
+    }
+}
+
+// Loads a project for exporting without a window. This doesn't go through `AppState`, because
+// making one loads the preferences and opens the audio devices. So the export settings are the
+// defaults (apart from the project's frame rate), and the caller fills in the output file.
+fn load_for_export(project: &Path) -> anyhow::Result<ExportCmd> {
+    let save_file = SaveFileData::load_from_path(project)
+        .map_err(|e| anyhow::anyhow!("error opening {:?}: {}", project, e))?;
+    let scribble = ScribbleState::from_save_file(save_file.with_audio_loaded());
+    let export = ExportSettings {
+        frame_rate: scribble.settings.frame_rate,
+        ..ExportSettings::default()
+    };
+    Ok(ExportCmd {
+        scribble,
+        filename: PathBuf::new(),
+        export,
+        show_pen: false,
+        cursor: scribble_curves::time::ZERO,
+        range: None,
+    })
+}
+
+// Runs an export on a background thread, printing its progress until it's done.
+fn run_export(export: ExportCmd) -> anyhow::Result<()> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || crate::encode::encode_blocking(export, tx));
+    for msg in rx.iter() {
+        use crate::encode::EncodingStatus;
+        match msg {
+            EncodingStatus::Encoding(progress) => eprintln!("{}", progress),
+            EncodingStatus::Error(s) => anyhow::bail!(s),
+            EncodingStatus::Finished => eprintln!("Finished!"),
+        }
+    }
+    Ok(())
+}
+
+// Resolutions can be given as "1080" or "1080p". The standard ones get their own setting, and
+// anything else is a custom resolution.
+fn parse_resolution(s: &str, custom: &mut f64) -> anyhow::Result<encode::Resolution> {
+    let short: u32 = s
+        .trim_end_matches('p')
+        .parse()
+        .map_err(|_| anyhow::anyhow!("invalid resolution {:?}", s))?;
+    Ok(match short {
+        720 => encode::Resolution::P720,
+        1080 => encode::Resolution::P1080,
+        2160 => encode::Resolution::P2160,
+        _ => {
+            *custom = short as f64;
+            encode::Resolution::Custom
+        }
+    })
+}
+
+fn parse_frame_rate(s: &str) -> anyhow::Result<encode::FrameRate> {
+    match s {
+        "24" => Ok(encode::FrameRate::Fps24),
+        "30" => Ok(encode::FrameRate::Fps30),
+        "60" => Ok(encode::FrameRate::Fps60),
+        _ => Err(anyhow::anyhow!(
+            "unsupported frame rate {:?} (try 24, 30 or 60)",
+            s
+        )),
+    }
+}
+
+fn parse_seconds(s: &str) -> anyhow::Result<scribble_curves::Time> {
+    match s.parse::<f64>() {
+        Ok(secs) if secs >= 0.0 && secs.is_finite() => Ok(scribble_curves::Time::from_micros(
+            (secs * 1e6).round() as i64,
+        )),
+        _ => Err(anyhow::anyhow!("invalid time {:?}", s)),
+    }
+}
+
+fn stream(export: ExportCmd, target: &str) {
+    let (width, height) = export
+        .export
+        .video_size(export.scribble.settings.aspect_ratio);
+    eprintln!(
+        "Streaming {}x{} RGBA frames at {} fps",
+        width,
+        height,
+        export.export.frame_rate.fps()
+    );
+
+    let result = if target == "-" {
+        crate::encode::stream_frames(export, &mut std::io::stdout().lock())
+    } else {
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .open(target)
+            .map_err(|e| e.into())
+            .and_then(|mut file| crate::encode::stream_frames(export, &mut file))
+    };
+    if let Err(e) = result {
+        eprintln!("Streaming error: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_flags() {
+        let mut custom = 480.0;
+        assert_eq!(
+            parse_resolution("1080p", &mut custom).unwrap(),
+            encode::Resolution::P1080
+        );
+        assert_eq!(custom, 480.0);
+        assert_eq!(
+            parse_resolution("600", &mut custom).unwrap(),
+            encode::Resolution::Custom
+        );
+        assert_eq!(custom, 600.0);
+        assert!(parse_resolution("big", &mut custom).is_err());
+
+        assert_eq!(parse_frame_rate("60").unwrap(), encode::FrameRate::Fps60);
+        assert!(parse_frame_rate("25").is_err());
+
+        assert_eq!(
+            parse_seconds("1.5").unwrap(),
+            scribble_curves::Time::from_micros(1_500_000)
+        );
+        assert!(parse_seconds("-1").is_err());
+    }
+
+    #[test]
+    fn batch_output() {
+        let dir = Path::new("course");
+        let project = Path::new("course/week1/intro.scb");
+        assert_eq!(
+            batch_output_path(project, dir, None, "webm"),
+            Path::new("course/week1/intro.webm")
+        );
+        assert_eq!(
+            batch_output_path(project, dir, Some(Path::new("out")), "mp4"),
+            Path::new("out/week1/intro.mp4")
+        );
+    }
+}
@@ -1,20 +1,189 @@
 use druid::commands;
 use druid::platform_menus;
 use druid::{
-    Command, FileDialogOptions, FileSpec, KeyCode, LocalizedString, MenuDesc, MenuItem, SysMods,
+    Color, Command, FileDialogOptions, FileSpec, LocalizedString, MenuDesc, MenuItem, SysMods, Vec2,
 };
 
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use scribble_core::save_file::SaveFormat;
+use scribble_curves::Time;
+
 use crate::cmd;
-use crate::data::CurrentAction;
-use crate::widgets::ToggleButtonState;
+use crate::collab::CollabRole;
+use crate::data::{AspectRatio, CurrentAction, Dialog, Jump, MaybeSnippetId, MirrorMode};
+use crate::encode::ExportFormat;
+use crate::prefs::{Hotkey, HotkeyKey, Keymap};
+use crate::widgets::{ToggleButtonState, DRAWING_WIDTH};
+
+pub const SCRIBBLE_FILE_TYPE: FileSpec = FileSpec::new("Scribble animation", &["scb"]);
+const IMAGE_FILE_TYPE: FileSpec = FileSpec::new("Image", crate::images::IMAGE_EXTENSIONS);
+const GPL_FILE_TYPE: FileSpec = FileSpec::new("GIMP palette", &["gpl"]);
+const HEX_FILE_TYPE: FileSpec = FileSpec::new("Hex palette", &["hex"]);
+const EXCALIDRAW_FILE_TYPE: FileSpec = FileSpec::new("Excalidraw drawing", &["excalidraw"]);
+const TLDRAW_FILE_TYPE: FileSpec = FileSpec::new("tldraw drawing", &["tldr"]);
+const SUBTITLE_FILE_TYPE: FileSpec = FileSpec::new("Subtitles", &["srt", "vtt"]);
+const SCRIPT_FILE_TYPE: FileSpec = FileSpec::new("Script", &["txt"]);
+pub const AUDIO_FILE_TYPE: FileSpec =
+    FileSpec::new("Audio", scribble_core::audio_file::AUDIO_EXTENSIONS);
 
-const SCRIBBLE_FILE_TYPE: FileSpec = FileSpec::new("Scribble animation", &["scb"]);
-const EXPORT_FILE_TYPE: FileSpec = FileSpec::new("mp4 video", &["mp4"]);
+// The background colors that can be chosen from the menu (in addition to the currently selected
+// palette color).
+const BACKGROUND_COLORS: [(&str, Color); 4] = [
+    ("Paper", Color::WHITE),
+    ("Cream", Color::rgb8(0xf7, 0xf1, 0xe1)),
+    ("Blackboard", Color::rgb8(0x2b, 0x3a, 0x33)),
+    ("Black", Color::BLACK),
+];
 
 use crate::data::AppState;
 
+/// Everything that the menus show, or that decides what their items do. Rebuilding the menus
+/// isn't free, so we only do it when this changes. (Whenever the menus start to depend on
+/// something new, it needs to be added here.)
+#[derive(Clone, PartialEq)]
+pub struct MenuState {
+    action: CurrentAction,
+    busy: bool,
+    save_path: Option<PathBuf>,
+    recent_files: Arc<Vec<PathBuf>>,
+    keybindings: Arc<BTreeMap<String, String>>,
+    keymap: Keymap,
+    save_format: SaveFormat,
+    link_audio: bool,
+    undo_description: Option<&'static str>,
+    redo_description: Option<&'static str>,
+    can_undo: bool,
+    can_redo: bool,
+    has_branches: bool,
+    fade_enabled: bool,
+    palette: Vec<u32>,
+    selected_color: u32,
+    mark: Option<Time>,
+    selected_snippet: MaybeSnippetId,
+    has_trash: bool,
+    has_clipboard: bool,
+    mirror: MirrorMode,
+    aspect_ratio: AspectRatio,
+    background: u32,
+    fade_in_images: bool,
+    show_measure: bool,
+    show_pen: bool,
+    show_captions: bool,
+    has_captions: bool,
+    transcribing: bool,
+    has_whisper_model: bool,
+    live_output: bool,
+    has_live_output_target: bool,
+    collab: Option<CollabRole>,
+    has_collab_port: bool,
+    has_collab_address: bool,
+    zoomed: bool,
+    current_tab: usize,
+    tab_names: Vec<String>,
+}
+
+impl MenuState {
+    pub fn new(data: &AppState) -> MenuState {
+        let undo = data.undo.borrow();
+        MenuState {
+            action: data.action,
+            busy: data.file_op.is_some(),
+            save_path: data.save_path.clone(),
+            recent_files: data.prefs.recent_files.clone(),
+            keybindings: data.prefs.keybindings.clone(),
+            keymap: data.prefs.keymap,
+            save_format: data.prefs.save_format,
+            link_audio: data.prefs.link_audio,
+            undo_description: undo.undo_description(),
+            redo_description: undo.redo_description(),
+            can_undo: undo.can_undo(),
+            can_redo: undo.can_redo(),
+            has_branches: !undo.branches().is_empty(),
+            fade_enabled: data.fade_enabled,
+            palette: data
+                .palette
+                .colors()
+                .iter()
+                .map(|c| c.as_rgba_u32())
+                .collect(),
+            selected_color: data.palette.selected_color().as_rgba_u32(),
+            mark: data.scribble.mark,
+            selected_snippet: data.scribble.selected_snippet,
+            has_trash: !data.scribble.trash.is_empty(),
+            has_clipboard: data.snippet_clipboard.is_some(),
+            mirror: data.mirror,
+            aspect_ratio: data.scribble.settings.aspect_ratio,
+            background: data.scribble.settings.background.as_rgba_u32(),
+            fade_in_images: data.fade_in_images,
+            show_measure: data.show_measure,
+            show_pen: data.show_pen,
+            show_captions: data.show_captions,
+            has_captions: !data.scribble.captions.is_empty(),
+            transcribing: data.transcribing,
+            has_whisper_model: !data.prefs.whisper_model.is_empty(),
+            live_output: data.live_output,
+            has_live_output_target: !data.prefs.live_output_target.is_empty(),
+            collab: data.collab,
+            has_collab_port: data.prefs.collab_port != 0,
+            has_collab_address: !data.prefs.collab_address.is_empty(),
+            zoomed: data.canvas_zoom != 1.0 || data.canvas_pan != Vec2::ZERO,
+            current_tab: data.current_tab,
+            tab_names: crate::tabs::tab_names(data),
+        }
+    }
+}
+
+// Menu labels have to be `&'static str`s, but the names of recent files are only known at
+// runtime. So we leak them, but only once for each distinct label (and there aren't many).
+fn static_label(label: String) -> &'static str {
+    thread_local! {
+        static LABELS: RefCell<HashSet<&'static str>> = RefCell::new(HashSet::new());
+    }
+    LABELS.with(|labels| {
+        let mut labels = labels.borrow_mut();
+        if let Some(l) = labels.get(label.as_str()) {
+            return *l;
+        }
+        let l: &'static str = Box::leak(label.into_boxed_str());
+        labels.insert(l);
+        l
+    })
+}
+
+trait Bound {
+    /// Gives this menu item the keyboard shortcut that the preferences assign to `action`. In the
+    /// modal keymap, the keys without Cmd belong to normal mode, so the menus don't get those.
+    fn bound(self, data: &AppState, action: &str) -> Self;
+}
+
+impl Bound for MenuItem<AppState> {
+    fn bound(self, data: &AppState, action: &str) -> MenuItem<AppState> {
+        let hotkey = data.prefs.hotkey(action).filter(|h| {
+            data.prefs.keymap == Keymap::Standard
+                || !matches!(h.mods, SysMods::None | SysMods::Shift)
+        });
+        match hotkey {
+            Some(Hotkey {
+                mods,
+                key: HotkeyKey::Code(code),
+            }) => self.hotkey(mods, code),
+            Some(Hotkey {
+                mods,
+                key: HotkeyKey::Text(text),
+            }) => self.hotkey(mods, static_label(text)),
+            None => self,
+        }
+    }
+}
+
 fn file_menu(data: &AppState) -> MenuDesc<AppState> {
     let has_path = data.save_path.is_some();
+    // Only one save or load can run at a time.
+    let busy = data.file_op.is_some();
 
     let open = MenuItem::new(
         LocalizedString::new("common-menu-file-open"),
@@ -23,7 +192,36 @@ fn file_menu(data: &AppState) -> MenuDesc<AppState> {
             FileDialogOptions::new().allowed_types(vec![SCRIBBLE_FILE_TYPE]),
         ),
     )
-    .hotkey(SysMods::Cmd, "o");
+    .bound(data, "open")
+    .disabled_if(|| busy);
+
+    let mut recent = MenuDesc::new(
+        LocalizedString::new("scribble-menu-file-recent").with_placeholder("Open recent"),
+    );
+    for path in data.prefs.recent_files.iter() {
+        let item = MenuItem::new(
+            LocalizedString::new("scribble-menu-file-recent-item")
+                .with_placeholder(static_label(path.display().to_string())),
+            cmd::OPEN_PROJECT.with(path.clone()),
+        )
+        .disabled_if(|| busy);
+        recent = recent.append(item);
+    }
+
+    // The most recent project that isn't the one that's currently open.
+    let last_project = data
+        .prefs
+        .recent_files
+        .iter()
+        .find(|p| Some(*p) != data.save_path.as_ref());
+    // If there isn't one, the item is disabled and so the path doesn't matter.
+    let reopen_path = last_project.cloned().unwrap_or_default();
+    let reopen = MenuItem::new(
+        LocalizedString::new("scribble-menu-file-reopen").with_placeholder("Reopen last project"),
+        cmd::OPEN_PROJECT.with(reopen_path),
+    )
+    .bound(data, "reopen")
+    .disabled_if(|| busy || last_project.is_none());
 
     let save_as_command = Command::new(
         commands::SHOW_SAVE_PANEL,
@@ -35,115 +233,607 @@ fn file_menu(data: &AppState) -> MenuDesc<AppState> {
         save_as_command.clone()
     };
     let save = MenuItem::new(LocalizedString::new("common-menu-file-save"), save_command)
-        .hotkey(SysMods::Cmd, "s");
+        .bound(data, "save")
+        .disabled_if(|| busy);
 
     let save_as = MenuItem::new(
         LocalizedString::new("common-menu-file-save-as"),
         save_as_command,
+    )
+    .disabled_if(|| busy);
+
+    // Changing the format doesn't change the current file, only how it gets saved next time.
+    let binary_format = data.prefs.save_format == SaveFormat::Binary;
+    let save_format = MenuItem::new(
+        LocalizedString::new("scribble-menu-file-binary-format")
+            .with_placeholder("Save in compact binary format"),
+        cmd::SET_SAVE_FORMAT.with(if binary_format {
+            SaveFormat::Json
+        } else {
+            SaveFormat::Binary
+        }),
+    )
+    .selected_if(|| binary_format);
+
+    let properties = MenuItem::new(
+        LocalizedString::new("scribble-menu-file-properties")
+            .with_placeholder("Project properties..."),
+        cmd::SHOW_DIALOG.with(Dialog::ProjectProperties),
     );
 
-    // Note that we're reusing the SHOW_SAVE_PANEL command for exporting. There doesn't appear to
-    // be another way to get the system file dialog.
+    let preferences = MenuItem::new(
+        LocalizedString::new("scribble-menu-file-preferences").with_placeholder("Preferences..."),
+        cmd::SHOW_DIALOG.with(Dialog::Preferences),
+    );
+
+    let command_palette = MenuItem::new(
+        LocalizedString::new("scribble-menu-file-command-palette")
+            .with_placeholder("Command palette..."),
+        cmd::SHOW_DIALOG.with(Dialog::CommandPalette),
+    )
+    .bound(data, "command-palette");
+
+    // This opens our export dialog, which then opens the system file dialog.
     let export = MenuItem::new(
-        LocalizedString::new("scribble-menu-file-export").with_placeholder("Export"),
+        LocalizedString::new("scribble-menu-file-export").with_placeholder("Export..."),
+        cmd::SHOW_DIALOG.with(Dialog::Export),
+    )
+    .bound(data, "export");
+
+    // Exporting just the audio doesn't have any settings worth asking about, so this goes
+    // straight to the system file dialog.
+    let export_audio = MenuItem::new(
+        LocalizedString::new("scribble-menu-file-export-audio")
+            .with_placeholder("Export narration audio..."),
         Command::new(
             commands::SHOW_SAVE_PANEL,
-            FileDialogOptions::new().allowed_types(vec![EXPORT_FILE_TYPE]),
+            FileDialogOptions::new().allowed_types(vec![ExportFormat::Audio.file_spec()]),
+        ),
+    );
+
+    // Like exporting, importing reuses a system file dialog. The app delegate decides what to do
+    // with the file based on its extension.
+    let import_image = MenuItem::new(
+        LocalizedString::new("scribble-menu-file-import-image").with_placeholder("Import image..."),
+        Command::new(
+            commands::SHOW_OPEN_PANEL,
+            FileDialogOptions::new().allowed_types(vec![IMAGE_FILE_TYPE]),
+        ),
+    );
+
+    let import_audio = MenuItem::new(
+        LocalizedString::new("scribble-menu-file-import-audio").with_placeholder("Import audio..."),
+        Command::new(
+            commands::SHOW_OPEN_PANEL,
+            FileDialogOptions::new().allowed_types(vec![AUDIO_FILE_TYPE]),
         ),
+    );
+
+    let import_drawing = MenuItem::new(
+        LocalizedString::new("scribble-menu-file-import-drawing")
+            .with_placeholder("Import drawing..."),
+        Command::new(
+            commands::SHOW_OPEN_PANEL,
+            FileDialogOptions::new().allowed_types(vec![EXCALIDRAW_FILE_TYPE, TLDRAW_FILE_TYPE]),
+        ),
+    );
+
+    let import_captions = MenuItem::new(
+        LocalizedString::new("scribble-menu-file-import-captions")
+            .with_placeholder("Import captions..."),
+        Command::new(
+            commands::SHOW_OPEN_PANEL,
+            FileDialogOptions::new().allowed_types(vec![SUBTITLE_FILE_TYPE, SCRIPT_FILE_TYPE]),
+        ),
+    );
+
+    let link_audio = data.prefs.link_audio;
+    let link_audio_item = MenuItem::new(
+        LocalizedString::new("scribble-menu-file-link-audio")
+            .with_placeholder("Link imported audio files"),
+        cmd::SET_LINK_AUDIO.with(!link_audio),
     )
-    .hotkey(SysMods::Cmd, "e");
+    .selected_if(|| link_audio);
+
+    let load_palette = MenuItem::new(
+        LocalizedString::new("scribble-menu-file-load-palette").with_placeholder("Load palette..."),
+        Command::new(
+            commands::SHOW_OPEN_PANEL,
+            FileDialogOptions::new().allowed_types(vec![GPL_FILE_TYPE, HEX_FILE_TYPE]),
+        ),
+    );
+
+    let save_palette = MenuItem::new(
+        LocalizedString::new("scribble-menu-file-save-palette").with_placeholder("Save palette..."),
+        Command::new(
+            commands::SHOW_SAVE_PANEL,
+            FileDialogOptions::new().allowed_types(vec![GPL_FILE_TYPE, HEX_FILE_TYPE]),
+        ),
+    );
+
+    // This opens our import dialog, which then opens the system file dialog.
+    let import_project = MenuItem::new(
+        LocalizedString::new("scribble-menu-file-import-project")
+            .with_placeholder("Import project..."),
+        cmd::SHOW_DIALOG.with(Dialog::ImportProject),
+    );
 
     MenuDesc::new(LocalizedString::new("common-menu-file-menu"))
         .append(open)
+        .append(recent)
+        .append(reopen)
         .append(save)
         .append(save_as)
+        .append(save_format)
+        .append(properties)
+        .append(preferences)
+        .append(command_palette)
         .append(export)
+        .append(export_audio)
+        .append(import_image)
+        .append(import_audio)
+        .append(link_audio_item)
+        .append(import_drawing)
+        .append(import_captions)
+        .append(import_project)
+        .append(load_palette)
+        .append(save_palette)
         .append_separator()
         .append(platform_menus::win::file::exit())
 }
 
 fn edit_menu(data: &AppState) -> MenuDesc<AppState> {
-    let undo = platform_menus::common::undo().disabled_if(|| !data.undo.borrow().can_undo());
-    let redo = platform_menus::common::redo().disabled_if(|| !data.undo.borrow().can_redo());
+    // The undo and redo items say what they would undo or redo. (The menus get rebuilt after
+    // every command, so they stay up to date.)
+    let undo_stack = data.undo.borrow();
+    let undo_label = match undo_stack.undo_description() {
+        Some(desc) => static_label(format!("Undo {}", desc)),
+        None => "Undo",
+    };
+    let undo = MenuItem::new(
+        LocalizedString::new("scribble-menu-edit-undo").with_placeholder(undo_label),
+        druid::commands::UNDO,
+    )
+    .hotkey(SysMods::Cmd, "z")
+    .disabled_if(|| !undo_stack.can_undo());
+    let redo_label = match undo_stack.redo_description() {
+        Some(desc) => static_label(format!("Redo {}", desc)),
+        None => "Redo",
+    };
+    let redo = MenuItem::new(
+        LocalizedString::new("scribble-menu-edit-redo").with_placeholder(redo_label),
+        druid::commands::REDO,
+    )
+    .hotkey(SysMods::CmdShift, "Z")
+    .disabled_if(|| !undo_stack.can_redo());
+    let branches = MenuItem::new(
+        LocalizedString::new("scribble-menu-edit-branches").with_placeholder("History branches..."),
+        cmd::SHOW_DIALOG.with(Dialog::HistoryBranches),
+    )
+    .disabled_if(|| undo_stack.branches().is_empty());
 
     let draw = MenuItem::new(
         LocalizedString::new("scribble-menu-edit-draw").with_placeholder("Draw"),
         cmd::DRAW,
     )
-    .hotkey(SysMods::Cmd, "d")
+    .bound(data, "draw")
     .disabled_if(|| data.action.rec_toggle() != ToggleButtonState::ToggledOff);
 
     let talk = MenuItem::new(
         LocalizedString::new("scribble-menu-edit-talk").with_placeholder("Talk"),
         cmd::TALK,
     )
-    .hotkey(SysMods::Cmd, "t")
+    .bound(data, "talk")
     .disabled_if(|| data.action.rec_audio_toggle() != ToggleButtonState::ToggledOff);
 
     let play = MenuItem::new(
         LocalizedString::new("scribble-menu-edit-play").with_placeholder("Play"),
         cmd::PLAY,
     )
-    .hotkey(SysMods::Cmd, "p")
+    .bound(data, "play")
     .disabled_if(|| data.action.play_toggle() != ToggleButtonState::ToggledOff);
 
     let stop = MenuItem::new(
         LocalizedString::new("scribble-menu-edit-stop").with_placeholder("Stop"),
         cmd::STOP,
     )
-    .hotkey(SysMods::None, KeyCode::Space)
+    .bound(data, "stop")
     .disabled_if(|| {
         !matches!(data.action,
             CurrentAction::Playing
-            | CurrentAction::Recording(_)
-            | CurrentAction::WaitingToRecord(_)
-            | CurrentAction::RecordingAudio(_))
+                | CurrentAction::Recording(_)
+                | CurrentAction::WaitingToRecord(_)
+                | CurrentAction::RecordingAudio(_)
+        )
     });
 
+    let fade = MenuItem::new(
+        LocalizedString::new("scribble-menu-edit-fade").with_placeholder("Fade out new snippets"),
+        cmd::SET_FADE.with(!data.fade_enabled),
+    )
+    .bound(data, "fade")
+    .selected_if(|| data.fade_enabled);
+
+    // The speed buttons change the speed without sending a command, so the menus don't
+    // necessarily know the current speed.
+    let next_recording_speed = MenuItem::new(
+        LocalizedString::new("scribble-menu-edit-next-recording-speed")
+            .with_placeholder("Next recording speed"),
+        cmd::NEXT_RECORDING_SPEED,
+    )
+    .bound(data, "next-recording-speed");
+
+    // The first nine palette colors can be chosen with the number keys.
+    let selected_color = data.palette.selected_color().as_rgba_u32();
+    let mut pen_color = MenuDesc::new(
+        LocalizedString::new("scribble-menu-edit-pen-color").with_placeholder("Pen color"),
+    );
+    for (i, color) in data.palette.colors().iter().take(9).enumerate() {
+        pen_color = pen_color.append(
+            MenuItem::new(
+                LocalizedString::new("scribble-menu-edit-pen-color-item")
+                    .with_placeholder(static_label(format!("Color {}", i + 1))),
+                cmd::CHOOSE_COLOR.with(color.clone()),
+            )
+            .bound(data, &format!("color-{}", i + 1))
+            .selected_if(|| color.as_rgba_u32() == selected_color),
+        );
+    }
+
     let mark = MenuItem::new(
         LocalizedString::new("scribble-menu-edit-mark").with_placeholder("Set mark"),
         cmd::SET_MARK,
     )
-    .hotkey(SysMods::None, KeyCode::KeyM);
+    .bound(data, "mark");
+
+    let jumps = [
+        (
+            "scribble-menu-edit-jump-mark",
+            "Mark",
+            Jump::Mark,
+            "jump-to-mark",
+        ),
+        (
+            "scribble-menu-edit-jump-start",
+            "Start",
+            Jump::Start,
+            "jump-to-start",
+        ),
+        (
+            "scribble-menu-edit-jump-end",
+            "End",
+            Jump::End,
+            "jump-to-end",
+        ),
+        (
+            "scribble-menu-edit-jump-next",
+            "Next snippet boundary",
+            Jump::NextBoundary,
+            "next-boundary",
+        ),
+        (
+            "scribble-menu-edit-jump-previous",
+            "Previous snippet boundary",
+            Jump::PreviousBoundary,
+            "previous-boundary",
+        ),
+        (
+            "scribble-menu-edit-jump-nearest",
+            "Nearest snippet boundary",
+            Jump::NearestBoundary,
+            "snap-to-boundary",
+        ),
+    ];
+    let mut jump =
+        MenuDesc::new(LocalizedString::new("scribble-menu-edit-jump").with_placeholder("Jump to"));
+    for &(key, label, target, action) in &jumps {
+        jump = jump.append(
+            MenuItem::new(
+                LocalizedString::new(key).with_placeholder(label),
+                cmd::JUMP.with(target),
+            )
+            .bound(data, action)
+            .disabled_if(|| target == Jump::Mark && data.scribble.mark.is_none()),
+        );
+    }
 
     let warp = MenuItem::new(
         LocalizedString::new("scribble-menu-edit-warp").with_placeholder("Warp snippet"),
         cmd::LERP_SNIPPET,
     )
-    .hotkey(SysMods::None, KeyCode::KeyW)
+    .bound(data, "warp")
     .disabled_if(|| data.scribble.mark.is_none());
 
     let trunc = MenuItem::new(
         LocalizedString::new("scribble-menu-edit-truncate").with_placeholder("Truncate snippet"),
         cmd::TRUNCATE_SNIPPET,
     )
-    .hotkey(SysMods::None, KeyCode::KeyT)
+    .bound(data, "truncate")
     .disabled_if(|| data.scribble.selected_snippet.is_none());
 
+    let speed = MenuItem::new(
+        LocalizedString::new("scribble-menu-edit-speed").with_placeholder("Change speed..."),
+        cmd::SHOW_DIALOG.with(Dialog::ChangeSpeed),
+    )
+    .disabled_if(|| data.scribble.selected_snippet.as_draw().is_none());
+
+    let reverse = MenuItem::new(
+        LocalizedString::new("scribble-menu-edit-reverse").with_placeholder("Reverse snippet"),
+        cmd::REVERSE_SNIPPET,
+    )
+    .disabled_if(|| data.scribble.selected_snippet.as_draw().is_none());
+
+    let recolor = MenuItem::new(
+        LocalizedString::new("scribble-menu-edit-recolor").with_placeholder("Recolor snippet"),
+        cmd::RECOLOR_SNIPPET,
+    )
+    .bound(data, "recolor")
+    .disabled_if(|| data.scribble.selected_snippet.as_draw().is_none());
+
+    let repeat = MenuItem::new(
+        LocalizedString::new("scribble-menu-edit-repeat").with_placeholder("Repeat last edit"),
+        cmd::REPEAT_LAST_EDIT,
+    )
+    .bound(data, "repeat-last-edit")
+    .disabled_if(|| data.scribble.selected_snippet.as_draw().is_none());
+
     let delete = MenuItem::new(
         LocalizedString::new("scribble-menu-edit-delete").with_placeholder("Delete selected"),
         cmd::DELETE_SNIPPET,
     )
-    .hotkey(SysMods::None, KeyCode::Delete)
+    .bound(data, "delete")
     .disabled_if(|| data.scribble.selected_snippet.is_none());
 
+    let restore = MenuItem::new(
+        LocalizedString::new("scribble-menu-edit-restore")
+            .with_placeholder("Restore deleted snippet..."),
+        cmd::SHOW_DIALOG.with(Dialog::RestoreSnippet),
+    )
+    .disabled_if(|| data.scribble.trash.is_empty());
+
+    let mirror_off = MenuItem::new(
+        LocalizedString::new("scribble-menu-edit-mirror-off").with_placeholder("Off"),
+        cmd::SET_MIRROR.with(MirrorMode::Off),
+    )
+    .selected_if(|| data.mirror.is_off());
+
+    let mirror_vertical = MenuItem::new(
+        LocalizedString::new("scribble-menu-edit-mirror-vertical").with_placeholder("Left-right"),
+        cmd::SET_MIRROR.with(MirrorMode::Vertical(DRAWING_WIDTH / 2.0)),
+    )
+    .selected_if(|| matches!(data.mirror, MirrorMode::Vertical(_)));
+
+    let mirror_horizontal = MenuItem::new(
+        LocalizedString::new("scribble-menu-edit-mirror-horizontal").with_placeholder("Top-bottom"),
+        cmd::SET_MIRROR.with(MirrorMode::Horizontal(
+            data.scribble.settings.drawing_height() / 2.0,
+        )),
+    )
+    .selected_if(|| matches!(data.mirror, MirrorMode::Horizontal(_)));
+
+    let mirror =
+        MenuDesc::new(LocalizedString::new("scribble-menu-edit-mirror").with_placeholder("Mirror"))
+            .append(mirror_off)
+            .append(mirror_vertical)
+            .append(mirror_horizontal);
+
+    let mut aspect_ratio = MenuDesc::new(
+        LocalizedString::new("scribble-menu-edit-aspect-ratio").with_placeholder("Canvas shape"),
+    );
+    for &ratio in &AspectRatio::ALL {
+        aspect_ratio = aspect_ratio.append(
+            MenuItem::new(
+                LocalizedString::new("scribble-menu-edit-aspect-ratio-item")
+                    .with_placeholder(ratio.name()),
+                cmd::SET_ASPECT_RATIO.with(ratio),
+            )
+            .selected_if(|| data.scribble.settings.aspect_ratio == ratio),
+        );
+    }
+
+    let current_background = data.scribble.settings.background.as_rgba_u32();
+    let mut background = MenuDesc::new(
+        LocalizedString::new("scribble-menu-edit-background").with_placeholder("Background"),
+    );
+    for (name, color) in &BACKGROUND_COLORS {
+        background = background.append(
+            MenuItem::new(
+                LocalizedString::new("scribble-menu-edit-background-item").with_placeholder(*name),
+                cmd::SET_BACKGROUND.with(color.clone()),
+            )
+            .selected_if(|| color.as_rgba_u32() == current_background),
+        );
+    }
+    background = background.append_separator().append(MenuItem::new(
+        LocalizedString::new("scribble-menu-edit-background-palette")
+            .with_placeholder("Use selected color"),
+        cmd::SET_BACKGROUND.with(data.palette.selected_color().clone()),
+    ));
+
+    let paste_image = MenuItem::new(
+        LocalizedString::new("scribble-menu-edit-paste-image").with_placeholder("Paste image"),
+        cmd::PASTE_IMAGE,
+    )
+    .bound(data, "paste-image");
+
+    let copy_snippet = MenuItem::new(
+        LocalizedString::new("scribble-menu-edit-copy-snippet").with_placeholder("Copy snippet"),
+        cmd::COPY_SNIPPET,
+    )
+    .bound(data, "copy-snippet")
+    .disabled_if(|| data.scribble.selected_snippet == MaybeSnippetId::None);
+
+    // Copied snippets can be pasted into any of the open projects.
+    let paste_snippet = MenuItem::new(
+        LocalizedString::new("scribble-menu-edit-paste-snippet").with_placeholder("Paste snippet"),
+        cmd::PASTE_SNIPPET,
+    )
+    .bound(data, "paste-snippet")
+    .disabled_if(|| data.snippet_clipboard.is_none());
+
+    let end_images = MenuItem::new(
+        LocalizedString::new("scribble-menu-edit-end-images").with_placeholder("Hide images"),
+        cmd::END_IMAGES,
+    );
+
+    let fade_in_images = MenuItem::new(
+        LocalizedString::new("scribble-menu-edit-fade-in-images")
+            .with_placeholder("Fade in new images"),
+        cmd::SET_FADE_IN_IMAGES.with(!data.fade_in_images),
+    )
+    .selected_if(|| data.fade_in_images);
+
+    let show_measure = MenuItem::new(
+        LocalizedString::new("scribble-menu-edit-show-measure").with_placeholder("Measure"),
+        cmd::SET_SHOW_MEASURE.with(!data.show_measure),
+    )
+    .selected_if(|| data.show_measure);
+
+    let reset_zoom = MenuItem::new(
+        LocalizedString::new("scribble-menu-edit-reset-zoom").with_placeholder("Reset zoom"),
+        cmd::RESET_ZOOM,
+    )
+    .bound(data, "reset-zoom")
+    .disabled_if(|| data.canvas_zoom == 1.0 && data.canvas_pan == Vec2::ZERO);
+
+    let show_pen = MenuItem::new(
+        LocalizedString::new("scribble-menu-edit-show-pen").with_placeholder("Show pen position"),
+        cmd::SET_SHOW_PEN.with(!data.show_pen),
+    )
+    .selected_if(|| data.show_pen);
+
+    let show_captions = MenuItem::new(
+        LocalizedString::new("scribble-menu-edit-show-captions").with_placeholder("Show captions"),
+        cmd::SET_SHOW_CAPTIONS.with(!data.show_captions),
+    )
+    .selected_if(|| data.show_captions)
+    .disabled_if(|| data.scribble.captions.is_empty());
+
+    let edit_captions = MenuItem::new(
+        LocalizedString::new("scribble-menu-edit-captions").with_placeholder("Edit captions..."),
+        cmd::SHOW_DIALOG.with(Dialog::Captions),
+    );
+
+    let transcribe = MenuItem::new(
+        LocalizedString::new("scribble-menu-edit-transcribe")
+            .with_placeholder("Transcribe narration"),
+        cmd::TRANSCRIBE,
+    )
+    .disabled_if(|| data.transcribing || data.prefs.whisper_model.is_empty());
+
+    let live_output = MenuItem::new(
+        LocalizedString::new("scribble-menu-edit-live-output").with_placeholder("Live output"),
+        cmd::SET_LIVE_OUTPUT.with(!data.live_output),
+    )
+    .selected_if(|| data.live_output)
+    .disabled_if(|| !data.live_output && data.prefs.live_output_target.is_empty());
+
+    let hosting = data.collab == Some(CollabRole::Host);
+    let host_collab = MenuItem::new(
+        LocalizedString::new("scribble-menu-edit-host-collab")
+            .with_placeholder("Host collaboration"),
+        cmd::SET_COLLAB.with(if hosting {
+            None
+        } else {
+            Some(CollabRole::Host)
+        }),
+    )
+    .selected_if(|| hosting)
+    .disabled_if(|| !hosting && data.prefs.collab_port == 0);
+
+    let joined = data.collab == Some(CollabRole::Guest);
+    let join_collab = MenuItem::new(
+        LocalizedString::new("scribble-menu-edit-join-collab")
+            .with_placeholder("Join collaboration"),
+        cmd::SET_COLLAB.with(if joined {
+            None
+        } else {
+            Some(CollabRole::Guest)
+        }),
+    )
+    .selected_if(|| joined)
+    .disabled_if(|| !joined && data.prefs.collab_address.is_empty());
+
     MenuDesc::new(LocalizedString::new("common-menu-edit-menu"))
         .append(undo)
         .append(redo)
+        .append(branches)
         .append_separator()
         .append(draw)
         .append(talk)
         .append(play)
         .append(stop)
+        .append(fade)
+        .append(next_recording_speed)
+        .append(pen_color)
+        .append(mirror)
+        .append(show_pen)
+        .append(show_captions)
+        .append(edit_captions)
+        .append(transcribe)
+        .append(live_output)
+        .append(host_collab)
+        .append(join_collab)
+        .append(show_measure)
+        .append(reset_zoom)
+        .append(aspect_ratio)
+        .append(background)
         .append_separator()
         .append(mark)
+        .append(jump)
         .append(warp)
         .append(trunc)
+        .append(speed)
+        .append(reverse)
+        .append(recolor)
+        .append(repeat)
         .append(delete)
+        .append(restore)
+        .append_separator()
+        .append(copy_snippet)
+        .append(paste_snippet)
+        .append(paste_image)
+        .append(end_images)
+        .append(fade_in_images)
+}
+
+fn tabs_menu(data: &AppState) -> MenuDesc<AppState> {
+    let can_switch = crate::tabs::can_switch(data);
+
+    let new_tab = MenuItem::new(
+        LocalizedString::new("scribble-menu-tabs-new").with_placeholder("New tab"),
+        cmd::NEW_TAB,
+    )
+    .bound(data, "new-tab")
+    .disabled_if(|| !can_switch);
+
+    let close_tab = MenuItem::new(
+        LocalizedString::new("scribble-menu-tabs-close").with_placeholder("Close tab"),
+        cmd::CLOSE_TAB,
+    )
+    .bound(data, "close-tab")
+    .disabled_if(|| !can_switch);
+
+    let mut menu =
+        MenuDesc::new(LocalizedString::new("scribble-menu-tabs").with_placeholder("Tabs"))
+            .append(new_tab)
+            .append(close_tab)
+            .append_separator();
+    for (idx, name) in crate::tabs::tab_names(data).into_iter().enumerate() {
+        let item = MenuItem::new(
+            LocalizedString::new("scribble-menu-tabs-item").with_placeholder(static_label(name)),
+            cmd::SWITCH_TAB.with(idx),
+        )
+        .selected_if(|| idx == data.current_tab)
+        .disabled_if(|| !can_switch);
+        menu = menu.append(item);
+    }
+    menu
 }
 
 pub fn make_menu(data: &AppState) -> MenuDesc<AppState> {
     MenuDesc::empty()
         .append(file_menu(data))
         .append(edit_menu(data))
+        .append(tabs_menu(data))
 }
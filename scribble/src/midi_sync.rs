@@ -0,0 +1,369 @@
+//! Synchronizing playback with other programs (like a DAW, or a video playback rig) over MIDI.
+//!
+//! When `Preferences::follow_midi_sync` is on, scribble plays along with MIDI timecode (MTC), and
+//! with the transport messages that come with MIDI clock (start, continue, stop and song
+//! position). These arrive on the same MIDI inputs as the controllers (see `crate::controllers`),
+//! which decode them with a [`SyncDecoder`] and send them to the UI thread as `cmd::MIDI_SYNC`.
+//!
+//! When `Preferences::midi_sync_output` names a MIDI output, scribble sends MTC (at 30 fps) and
+//! transport messages to it whenever it plays or jumps, so that other programs can follow it.
+//!
+//! LTC (timecode sent as audio) isn't supported, but most things that send LTC can also send MTC.
+
+use midir::{MidiOutput, MidiOutputConnection};
+use std::time::Instant;
+
+use scribble_curves::time::{Diff, Time};
+
+use crate::data::{AppState, CurrentAction};
+
+/// The frame rates of MTC, indexed by the rate code in the messages. Drop-frame timecode (the
+/// third one) is labelled so that it keeps up with the clock, so we treat it like 30 fps.
+const MTC_RATES: [i64; 4] = [24, 25, 30, 30];
+
+/// The MTC rate code that we send (30 fps, non-drop).
+const OUTPUT_RATE: u8 = 3;
+
+/// If we're following timecode and it drifts this far from our own clock, we jump to catch up.
+const MAX_DRIFT: Diff = Diff::from_micros(100_000);
+
+/// If timecode stops arriving for this long, the other program has stopped.
+const TIMECODE_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// If we fall this many quarter-frames behind while sending timecode, we skip ahead instead of
+/// catching up.
+const MAX_QUARTER_FRAME_BURST: i64 = 8;
+
+/// Something that another program told us about its playback.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncEvent {
+    /// Start playing from the beginning.
+    Start,
+    /// Start playing from the current time.
+    Continue,
+    Stop,
+    /// Jump to a time (without starting or stopping).
+    Locate(Time),
+    /// The current time while the other program is playing. This arrives many times a second.
+    Timecode(Time),
+}
+
+/// Turns MIDI messages from one device into [`SyncEvent`]s.
+#[derive(Default)]
+pub struct SyncDecoder {
+    // The nibbles of the current MTC quarter-frame sequence, and a bit mask of which ones we've
+    // seen.
+    quarter_frames: [u8; 8],
+    received: u8,
+    // The time (in microseconds, according to the MIDI driver) of the last MIDI clock tick, and
+    // our estimate of the time between ticks.
+    last_clock: Option<u64>,
+    clock_interval: Option<u64>,
+}
+
+impl SyncDecoder {
+    /// Decodes a MIDI message that arrived at `stamp` (in microseconds).
+    pub fn decode(&mut self, stamp: u64, message: &[u8]) -> Option<SyncEvent> {
+        match *message {
+            [0xFA] => Some(SyncEvent::Start),
+            [0xFB] => Some(SyncEvent::Continue),
+            [0xFC] => Some(SyncEvent::Stop),
+            [0xF8] => {
+                self.clock_tick(stamp);
+                None
+            }
+            // The song position is in sixteenth notes, each of which is 6 clock ticks.
+            [0xF2, lsb, msb] => {
+                let sixteenths = (lsb as u64 & 0x7F) | ((msb as u64 & 0x7F) << 7);
+                let interval = self.clock_interval?;
+                Some(SyncEvent::Locate(Time::from_micros(
+                    (sixteenths * 6 * interval) as i64,
+                )))
+            }
+            [0xF1, data] => self.quarter_frame(data),
+            [0xF0, 0x7F, _, 0x01, 0x01, hours, mins, secs, frames, 0xF7] => Some(
+                SyncEvent::Locate(mtc_time(hours & 0x1F, mins, secs, frames, hours >> 5)),
+            ),
+            _ => None,
+        }
+    }
+
+    fn clock_tick(&mut self, stamp: u64) {
+        if let Some(last) = self.last_clock {
+            let interval = stamp.saturating_sub(last);
+            // A long gap means that the clock stopped for a while, not that the tempo changed.
+            if interval < 1_000_000 {
+                // Ticks are jittery, so we smooth them out.
+                self.clock_interval = Some(match self.clock_interval {
+                    Some(old) => (old * 7 + interval) / 8,
+                    None => interval,
+                });
+            }
+        }
+        self.last_clock = Some(stamp);
+    }
+
+    fn quarter_frame(&mut self, data: u8) -> Option<SyncEvent> {
+        let piece = (data >> 4) & 7;
+        if piece == 0 {
+            self.received = 0;
+        }
+        self.quarter_frames[piece as usize] = data & 0xF;
+        self.received |= 1 << piece;
+        if piece != 7 || self.received != 0xFF {
+            return None;
+        }
+
+        let q = &self.quarter_frames;
+        let frames = q[0] | ((q[1] & 1) << 4);
+        let secs = q[2] | ((q[3] & 3) << 4);
+        let mins = q[4] | ((q[5] & 3) << 4);
+        let hours = q[6] | ((q[7] & 1) << 4);
+        let rate = (q[7] >> 1) & 3;
+        // The sequence started with the time of its first quarter-frame, and it takes two frames
+        // to send.
+        let frame = Diff::from_micros(1_000_000 / MTC_RATES[rate as usize]);
+        Some(SyncEvent::Timecode(
+            mtc_time(hours, mins, secs, frames, rate) + frame + frame,
+        ))
+    }
+}
+
+fn mtc_time(hours: u8, mins: u8, secs: u8, frames: u8, rate: u8) -> Time {
+    let secs = (hours as i64 * 60 + mins as i64) * 60 + secs as i64;
+    let fps = MTC_RATES[rate as usize & 3];
+    Time::from_micros(secs * 1_000_000 + frames as i64 * 1_000_000 / fps)
+}
+
+// The hours, minutes, seconds and frames of a frame number (at our output rate).
+fn timecode(frame: i64) -> [u8; 4] {
+    let fps = MTC_RATES[OUTPUT_RATE as usize];
+    let frame = frame.max(0);
+    [
+        ((frame / (fps * 3600)) % 24) as u8,
+        ((frame / (fps * 60)) % 60) as u8,
+        ((frame / fps) % 60) as u8,
+        (frame % fps) as u8,
+    ]
+}
+
+fn frame_at(time: Time) -> i64 {
+    time.as_micros() * MTC_RATES[OUTPUT_RATE as usize] / 1_000_000
+}
+
+/// The MTC message that tells followers to jump to `time`.
+pub fn full_frame(time: Time) -> [u8; 10] {
+    let [hours, mins, secs, frames] = timecode(frame_at(time));
+    [
+        0xF0,
+        0x7F,
+        0x7F,
+        0x01,
+        0x01,
+        (OUTPUT_RATE << 5) | hours,
+        mins,
+        secs,
+        frames,
+        0xF7,
+    ]
+}
+
+/// The `idx`th MTC quarter-frame message since time zero. Each group of eight messages sends the
+/// timecode of the frame when the group started, and takes two frames to send.
+pub fn quarter_frame(idx: i64) -> [u8; 2] {
+    let [hours, mins, secs, frames] = timecode(idx / 8 * 2);
+    let piece = (idx % 8) as u8;
+    let nibble = match piece {
+        0 => frames & 0xF,
+        1 => frames >> 4,
+        2 => secs & 0xF,
+        3 => secs >> 4,
+        4 => mins & 0xF,
+        5 => mins >> 4,
+        6 => hours & 0xF,
+        _ => (OUTPUT_RATE << 1) | (hours >> 4),
+    };
+    [0xF1, (piece << 4) | nibble]
+}
+
+// The index of the quarter-frame that's due at `time`.
+fn quarter_frame_at(time: Time) -> i64 {
+    time.as_micros() * MTC_RATES[OUTPUT_RATE as usize] * 4 / 1_000_000
+}
+
+/// Makes our playback follow another program's.
+pub fn follow(data: &mut AppState, event: SyncEvent) {
+    match event {
+        SyncEvent::Start => chase(data, Some(scribble_curves::time::ZERO), true),
+        SyncEvent::Continue => chase(data, None, true),
+        SyncEvent::Stop => chase(data, None, false),
+        SyncEvent::Locate(time) => {
+            let playing = data.action == CurrentAction::Playing;
+            chase(data, Some(time), playing);
+        }
+        SyncEvent::Timecode(time) => {
+            let drift = (data.time() - time).as_micros().abs();
+            let playing = data.action == CurrentAction::Playing;
+            if !playing || drift > MAX_DRIFT.as_micros() {
+                chase(data, Some(time), true);
+            }
+        }
+    }
+}
+
+// Jumps to `time` (if there is one), and then plays or stops. We don't interrupt recordings.
+fn chase(data: &mut AppState, time: Option<Time>, play: bool) {
+    let playing = match data.action {
+        CurrentAction::Idle => false,
+        CurrentAction::Playing => true,
+        _ => return,
+    };
+    if playing && (time.is_some() || !play) {
+        data.stop_playing();
+    }
+    if let Some(time) = time {
+        data.warp_time_to(time);
+    }
+    if play && (time.is_some() || !playing) {
+        data.start_playing();
+    }
+}
+
+/// Sends MTC and transport messages to a MIDI output, so that other programs can follow us.
+pub struct SyncOutput {
+    conn: MidiOutputConnection,
+    playing: bool,
+    time: Option<Time>,
+    // The next quarter-frame to send, while playing.
+    next_quarter_frame: i64,
+}
+
+impl SyncOutput {
+    /// Connects to the first MIDI output whose name contains `port`.
+    pub fn connect(port: &str) -> anyhow::Result<SyncOutput> {
+        let output = MidiOutput::new("scribble")?;
+        let found = output.ports().into_iter().find(|p| {
+            output
+                .port_name(p)
+                .map_or(false, |name| name.contains(port))
+        });
+        let found = found.ok_or_else(|| anyhow::anyhow!("no MIDI output called \"{}\"", port))?;
+        let conn = output
+            .connect(&found, "scribble-sync")
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+        log::info!("sending MIDI timecode to {}", port);
+        Ok(SyncOutput {
+            conn,
+            playing: false,
+            time: None,
+            next_quarter_frame: 0,
+        })
+    }
+
+    fn send(&mut self, message: &[u8]) {
+        if let Err(e) = self.conn.send(message) {
+            log::error!("failed to send MIDI timecode: {}", e);
+        }
+    }
+
+    fn locate(&mut self, time: Time) {
+        self.send(&full_frame(time));
+        self.next_quarter_frame = quarter_frame_at(time);
+    }
+
+    /// Tells the followers what we're doing now. This should be called on every frame.
+    pub fn update(&mut self, data: &AppState) {
+        let time = data.time();
+        let playing = data.action == CurrentAction::Playing;
+        if playing && !self.playing {
+            self.locate(time);
+            self.send(&[0xFB]);
+        } else if !playing && self.playing {
+            self.send(&[0xFC]);
+            self.locate(time);
+        } else if !playing && self.time != Some(time) {
+            self.locate(time);
+        }
+
+        if playing {
+            let due = quarter_frame_at(time);
+            if due - self.next_quarter_frame > MAX_QUARTER_FRAME_BURST {
+                self.locate(time);
+            }
+            while self.next_quarter_frame <= due {
+                let msg = quarter_frame(self.next_quarter_frame);
+                self.send(&msg);
+                self.next_quarter_frame += 1;
+            }
+        }
+        self.playing = playing;
+        self.time = Some(time);
+    }
+}
+
+/// Whether timecode has stopped arriving, if we were getting it.
+pub fn timecode_stopped(last_timecode: Option<Instant>) -> bool {
+    last_timecode.map_or(false, |t| t.elapsed() > TIMECODE_TIMEOUT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transport() {
+        let mut dec = SyncDecoder::default();
+        assert_eq!(dec.decode(0, &[0xFA]), Some(SyncEvent::Start));
+        assert_eq!(dec.decode(0, &[0xFC]), Some(SyncEvent::Stop));
+        // Until we've heard the clock, we don't know how long a sixteenth note is.
+        assert_eq!(dec.decode(0, &[0xF2, 4, 0]), None);
+
+        // 120 bpm is 48 ticks a second.
+        for i in 0..10 {
+            assert_eq!(dec.decode(i * 20_833, &[0xF8]), None);
+        }
+        // Eight sixteenth notes is one second.
+        match dec.decode(0, &[0xF2, 8, 0]) {
+            Some(SyncEvent::Locate(t)) => assert!((t.as_micros() - 1_000_000).abs() < 100),
+            e => panic!("unexpected {:?}", e),
+        }
+        // Notes and so on aren't sync messages.
+        assert_eq!(dec.decode(0, &[0x90, 36, 100]), None);
+    }
+
+    #[test]
+    fn full_frame_round_trip() {
+        let mut dec = SyncDecoder::default();
+        let time = Time::from_micros(3_723_500_000);
+        assert_eq!(
+            full_frame(time),
+            [0xF0, 0x7F, 0x7F, 0x01, 0x01, 0x61, 2, 3, 15, 0xF7]
+        );
+        assert_eq!(
+            dec.decode(0, &full_frame(time)),
+            Some(SyncEvent::Locate(time))
+        );
+    }
+
+    #[test]
+    fn quarter_frame_round_trip() {
+        let mut dec = SyncDecoder::default();
+        // The group that starts at frame 100 (3 seconds and 10 frames).
+        let start = 100 / 2 * 8;
+        for idx in start..start + 7 {
+            assert_eq!(dec.decode(0, &quarter_frame(idx)), None);
+        }
+        // The time is two frames after the start of the group.
+        match dec.decode(0, &quarter_frame(start + 7)) {
+            Some(SyncEvent::Timecode(t)) => assert!((t.as_micros() - 3_400_000).abs() < 10),
+            e => panic!("unexpected {:?}", e),
+        }
+
+        // Starting in the middle of a group doesn't give a time until the next whole group.
+        let mut dec = SyncDecoder::default();
+        for idx in start + 4..start + 8 {
+            assert_eq!(dec.decode(0, &quarter_frame(idx)), None);
+        }
+        assert_eq!(quarter_frame_at(Time::from_micros(3_400_000)), 408);
+    }
+}
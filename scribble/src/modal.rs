@@ -0,0 +1,178 @@
+//! The vim-style modal keymap (see `Keymap::Modal`).
+//!
+//! We're in insert mode while something is being recorded, and in normal mode the rest of the
+//! time. In normal mode, the keys without modifiers run the actions in `MODAL_KEYBINDINGS`
+//! instead of their usual ones, and typing a number before a movement (like "5l") repeats it. In
+//! insert mode, the usual shortcuts work (so that the pen color can still be changed while
+//! drawing), and "normal-mode" stops recording.
+
+use druid::{EventCtx, KeyEvent};
+
+use scribble_curves::{Diff, Time};
+
+use crate::cmd;
+use crate::command_palette::command_for;
+use crate::data::{AppState, CurrentAction, Jump};
+use crate::prefs::{DEFAULT_KEYBINDINGS, MODAL_KEYBINDINGS};
+
+/// How far "normal-left" and "normal-right" move the cursor.
+const NUDGE: Diff = Diff::from_micros(100_000);
+
+/// Counts bigger than this are probably typos, and moving that far isn't useful anyway.
+const MAX_COUNT: u32 = 9999;
+
+/// The state of the modal keymap that isn't in the `AppState`: the count that has been typed so
+/// far.
+#[derive(Default)]
+pub struct ModalKeys {
+    count: u32,
+}
+
+impl ModalKeys {
+    /// Handles a key press, returning true if it was one of ours.
+    pub fn key_down(&mut self, ctx: &mut EventCtx, ev: &KeyEvent, data: &AppState) -> bool {
+        let prefs = &data.prefs;
+        if is_insert_mode(data.action) {
+            if prefs.is_shortcut_for(ev, "normal-mode") {
+                ctx.submit_command(cmd::STOP, None);
+                return true;
+            }
+            // The menus don't have the shortcuts without modifiers in the modal keymap, so we run
+            // them ourselves.
+            for (action, _) in DEFAULT_KEYBINDINGS {
+                if prefs.is_shortcut_for(ev, action) {
+                    if let Some(command) = command_for(action, data) {
+                        ctx.submit_command(command, None);
+                        return true;
+                    }
+                }
+            }
+            return false;
+        }
+
+        if let Some(digit) = digit(ev) {
+            // A zero on its own isn't a count, so it's free to be a shortcut.
+            if digit > 0 || self.count > 0 {
+                self.count = (self.count * 10 + digit).min(MAX_COUNT);
+                return true;
+            }
+        }
+        let count = std::mem::take(&mut self.count).max(1);
+        for (action, _) in MODAL_KEYBINDINGS {
+            if prefs.is_shortcut_for(ev, action) {
+                run(ctx, action, count, data);
+                return true;
+            }
+        }
+        false
+    }
+}
+
+fn is_insert_mode(action: CurrentAction) -> bool {
+    matches!(
+        action,
+        CurrentAction::WaitingToRecord(_)
+            | CurrentAction::Recording(_)
+            | CurrentAction::RecordingAudio(_)
+    )
+}
+
+/// If `ev` is a digit typed without any modifiers, returns it.
+fn digit(ev: &KeyEvent) -> Option<u32> {
+    if ev.mods.shift || ev.mods.ctrl || ev.mods.alt || ev.mods.meta {
+        return None;
+    }
+    let mut chars = ev.unmod_text()?.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => c.to_digit(10),
+        _ => None,
+    }
+}
+
+/// Where the cursor ends up after `count` repetitions of a movement.
+fn moved(action: &str, count: u32, data: &AppState) -> Option<Time> {
+    let nudge = Diff::from_micros(NUDGE.as_micros() * count as i64);
+    let jump = match action {
+        "normal-left" => return Some(data.time() - nudge),
+        "normal-right" => return Some(data.time() + nudge),
+        "normal-next-boundary" => Jump::NextBoundary,
+        "normal-previous-boundary" => Jump::PreviousBoundary,
+        _ => return None,
+    };
+    let mut time = data.time();
+    for _ in 0..count {
+        match jump.target(&data.scribble, time) {
+            Some(t) => time = t,
+            None => break,
+        }
+    }
+    Some(time)
+}
+
+fn run(ctx: &mut EventCtx, action: &str, count: u32, data: &AppState) {
+    if let Some(time) = moved(action, count, data) {
+        ctx.submit_command(cmd::WARP_TIME_TO.with(time), None);
+        return;
+    }
+    match action {
+        "normal-insert" => ctx.submit_command(cmd::DRAW, None),
+        "normal-undo" => ctx.submit_command(druid::commands::UNDO, None),
+        "normal-redo" => ctx.submit_command(druid::commands::REDO, None),
+        // We're already in normal mode, and the count has already been forgotten.
+        "normal-mode" => {}
+        _ => {
+            let standard = &action["normal-".len()..];
+            if let Some(command) = command_for(standard, data) {
+                ctx.submit_command(command, None);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::AudioSnippetData;
+
+    #[test]
+    fn movements() {
+        let mut data = AppState::with_prefs(Preferences::default());
+        let secs = |s| Time::from_micros(s * 1_000_000);
+        for &s in &[1, 3, 5] {
+            let snip = AudioSnippetData::new(vec![0; 100], secs(s));
+            data.scribble.audio_snippets = data.scribble.audio_snippets.with_new_snippet(snip).0;
+        }
+
+        let micros = Time::from_micros;
+        assert_eq!(moved("normal-right", 1, &data), Some(micros(100_000)));
+        assert_eq!(moved("normal-right", 12, &data), Some(micros(1_200_000)));
+        // The cursor can't go before the start.
+        assert_eq!(moved("normal-left", 3, &data), Some(micros(0)));
+        assert_eq!(moved("normal-next-boundary", 1, &data), Some(secs(1)));
+        // Each audio snippet has a start and an end, so this skips over the first snippet.
+        assert_eq!(moved("normal-next-boundary", 3, &data), Some(secs(3)));
+        assert_eq!(moved("normal-previous-boundary", 2, &data), Some(micros(0)));
+        assert_eq!(moved("normal-mark", 1, &data), None);
+    }
+
+    #[test]
+    fn every_normal_action_does_something() {
+        let data = AppState::with_prefs(Preferences::default());
+        for (action, _) in MODAL_KEYBINDINGS {
+            let special = [
+                "normal-left",
+                "normal-right",
+                "normal-next-boundary",
+                "normal-previous-boundary",
+                "normal-insert",
+                "normal-undo",
+                "normal-redo",
+                "normal-mode",
+            ];
+            if !special.contains(action) {
+                let standard = &action["normal-".len()..];
+                assert!(command_for(standard, &data).is_some(), "{}", action);
+            }
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! Reading and writing palettes, so that a color scheme can be shared between projects (and
+//! with other programs). We support GIMP palettes (`.gpl`, which are understood by most drawing
+//! programs) and plain lists of hex colors (`.hex`, as used by lospec.com).
+
+use anyhow::anyhow;
+use druid::Color;
+use std::path::Path;
+
+/// Does this path look like a palette file that we can load?
+pub fn is_palette_path(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| matches!(e.to_lowercase().as_str(), "gpl" | "hex"))
+        .unwrap_or(false)
+}
+
+/// Loads the colors from a palette file. The format is determined by the extension.
+pub fn load(path: &Path) -> anyhow::Result<Vec<Color>> {
+    let contents = std::fs::read_to_string(path)?;
+    let colors = if is_gpl(path) {
+        parse_gpl(&contents)?
+    } else {
+        parse_hex(&contents)?
+    };
+    if colors.is_empty() {
+        return Err(anyhow!("there are no colors in {:?}", path));
+    }
+    Ok(colors)
+}
+
+/// Saves some colors to a palette file. The format is determined by the extension.
+pub fn save(path: &Path, colors: &[Color]) -> anyhow::Result<()> {
+    let contents = if is_gpl(path) {
+        let name = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        to_gpl(&name, colors)
+    } else {
+        to_hex(colors)
+    };
+    std::fs::write(path, contents)?;
+    Ok(())
+}
+
+fn is_gpl(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.eq_ignore_ascii_case("gpl"))
+        .unwrap_or(false)
+}
+
+fn rgb(color: &Color) -> (u8, u8, u8) {
+    let rgba = color.as_rgba_u32();
+    ((rgba >> 24) as u8, (rgba >> 16) as u8, (rgba >> 8) as u8)
+}
+
+fn parse_gpl(s: &str) -> anyhow::Result<Vec<Color>> {
+    let mut lines = s.lines();
+    if lines.next().map(|l| l.trim()) != Some("GIMP Palette") {
+        return Err(anyhow!("this isn't a GIMP palette"));
+    }
+    let mut ret = Vec::new();
+    for line in lines {
+        let line = line.trim();
+        if line.is_empty()
+            || line.starts_with('#')
+            || line.starts_with("Name:")
+            || line.starts_with("Columns:")
+        {
+            continue;
+        }
+        // Each color is three numbers, optionally followed by a name (which we ignore).
+        let channels = line
+            .split_whitespace()
+            .take(3)
+            .map(|c| c.parse::<u8>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| anyhow!("invalid color \"{}\"", line))?;
+        match channels[..] {
+            [r, g, b] => ret.push(Color::rgb8(r, g, b)),
+            _ => return Err(anyhow!("invalid color \"{}\"", line)),
+        }
+    }
+    Ok(ret)
+}
+
+fn to_gpl(name: &str, colors: &[Color]) -> String {
+    let mut ret = format!(
+        "GIMP Palette\nName: {}\nColumns: {}\n#\n",
+        name,
+        colors.len()
+    );
+    for c in colors {
+        let (r, g, b) = rgb(c);
+        ret.push_str(&format!(
+            "{:3} {:3} {:3}\t#{:02x}{:02x}{:02x}\n",
+            r, g, b, r, g, b
+        ));
+    }
+    ret
+}
+
+fn parse_hex(s: &str) -> anyhow::Result<Vec<Color>> {
+    s.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let hex = line.trim_start_matches('#');
+            match u32::from_str_radix(hex, 16) {
+                Ok(rgb) if hex.len() == 6 => Ok(Color::from_rgba32_u32((rgb << 8) | 0xff)),
+                _ => Err(anyhow!("invalid color \"{}\"", line)),
+            }
+        })
+        .collect()
+}
+
+fn to_hex(colors: &[Color]) -> String {
+    colors
+        .iter()
+        .map(|c| {
+            let (r, g, b) = rgb(c);
+            format!("{:02x}{:02x}{:02x}\n", r, g, b)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let colors = vec![Color::rgb8(51, 63, 72), Color::rgb8(255, 214, 0)];
+        let rgba = |cs: &[Color]| cs.iter().map(|c| c.as_rgba_u32()).collect::<Vec<_>>();
+        assert_eq!(
+            rgba(&parse_gpl(&to_gpl("test", &colors)).unwrap()),
+            rgba(&colors)
+        );
+        assert_eq!(rgba(&parse_hex(&to_hex(&colors)).unwrap()), rgba(&colors));
+    }
+
+    #[test]
+    fn gimp() {
+        let gpl = "GIMP Palette\nName: Test\nColumns: 2\n# a comment\n\n  0 128 255\tBlue-ish\n255 255 255\n";
+        let colors = parse_gpl(gpl).unwrap();
+        assert_eq!(colors.len(), 2);
+        assert_eq!(colors[0].as_rgba_u32(), 0x0080ffff);
+        assert!(parse_gpl("0 0 0\n").is_err());
+        assert!(parse_gpl("GIMP Palette\n0 0\n").is_err());
+        assert!(parse_gpl("GIMP Palette\n0 0 300\n").is_err());
+    }
+
+    #[test]
+    fn hex() {
+        let colors = parse_hex("#ff0000\n00ff00\n\n").unwrap();
+        assert_eq!(colors.len(), 2);
+        assert_eq!(colors[1].as_rgba_u32(), 0x00ff00ff);
+        assert!(parse_hex("ff00").is_err());
+    }
+
+    #[test]
+    fn paths() {
+        assert!(is_palette_path(Path::new("colors.GPL")));
+        assert!(is_palette_path(Path::new("colors.hex")));
+        assert!(!is_palette_path(Path::new("colors.png")));
+    }
+}
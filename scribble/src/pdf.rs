@@ -0,0 +1,343 @@
+//! Exporting key frames of the animation as a PDF, for printing handouts.
+//!
+//! The key frames are the moments just before some of the drawing disappears (which is usually
+//! the end of a scene), the mark (if there is one), and the end of the animation. Each one gets a
+//! page of its own, labelled with the time at which it appears in the exported video. We write
+//! the PDF ourselves: it only needs images and a line of text per page, and storing the frames
+//! as zlib-compressed RGB keeps thin strokes sharp without making the file huge.
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+use scribble_curves::time::{Diff, TimeSpan};
+use scribble_curves::Time;
+
+use crate::data::ScribbleState;
+
+/// Scene boundaries that are closer together than this count as one (for example, when the
+/// canvas gets cleared with a fade, the strokes end one after another).
+const SCENE_GAP: Diff = Diff::from_micros(1_000_000);
+
+// The size of an A4 page, in points. The pages are landscape if the frames are.
+const PAGE_LONG: f64 = 842.0;
+const PAGE_SHORT: f64 = 595.0;
+const MARGIN: f64 = 36.0;
+const LABEL_SIZE: f64 = 10.0;
+
+// The first few objects in the file are always the same ones; the pages come after them.
+const CATALOG_ID: usize = 1;
+const PAGES_ID: usize = 2;
+const FONT_ID: usize = 3;
+
+/// The times (in the original animation) that get a page of the handout.
+pub fn key_frame_times(scribble: &ScribbleState, range: TimeSpan) -> Vec<Time> {
+    let snippet_ends = scribble
+        .snippets
+        .snippets()
+        .filter_map(|(_, s)| s.end_time());
+    let image_ends = scribble.images.snippets().filter_map(|s| s.end_time);
+    let mut ends: Vec<Time> = snippet_ends
+        .chain(image_ends)
+        .filter(|&t| range.start() <= t && t < range.end())
+        .collect();
+    ends.sort();
+
+    // Things stay visible until their end time, so the first end of each scene is the last
+    // moment that the whole scene is visible.
+    let mut ret = Vec::new();
+    let mut prev: Option<Time> = None;
+    for t in ends {
+        if prev.map_or(true, |p| t - p >= SCENE_GAP) {
+            ret.push(t);
+        }
+        prev = Some(t);
+    }
+    if let Some(mark) = scribble.mark {
+        if range.start() <= mark && mark <= range.end() {
+            ret.push(mark);
+        }
+    }
+    if anything_visible(scribble, range.end()) {
+        ret.push(range.end());
+    }
+    ret.sort();
+    ret.dedup();
+    ret
+}
+
+fn anything_visible(scribble: &ScribbleState, time: Time) -> bool {
+    scribble
+        .snippets
+        .snippets()
+        .any(|(_, s)| s.visible_at(time) && s.start_time() < time)
+        || scribble.images.snippets().any(|s| s.visible_at(time))
+}
+
+/// The label at the bottom of a page, like "3 - 1:05.20".
+pub fn page_label(page: usize, time: Time) -> String {
+    let cents = time.as_micros().max(0) / 10_000;
+    format!(
+        "{} - {}:{:02}.{:02}",
+        page,
+        cents / 6000,
+        (cents / 100) % 60,
+        cents % 100
+    )
+}
+
+/// Writes a PDF, one page at a time.
+pub struct PdfWriter<W: Write> {
+    out: W,
+    // The number of bytes written so far.
+    pos: usize,
+    // The position of each object (indexed by object id, starting from 1).
+    offsets: Vec<usize>,
+    page_ids: Vec<usize>,
+}
+
+impl<W: Write> PdfWriter<W> {
+    pub fn new(out: W) -> io::Result<PdfWriter<W>> {
+        let mut ret = PdfWriter {
+            out,
+            pos: 0,
+            offsets: vec![0; FONT_ID],
+            page_ids: Vec::new(),
+        };
+        // The comment with high bytes tells other programs that the file is binary.
+        ret.write(b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n")?;
+        ret.object(
+            FONT_ID,
+            b"<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica >>",
+        )?;
+        Ok(ret)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.out.write_all(bytes)?;
+        self.pos += bytes.len();
+        Ok(())
+    }
+
+    fn next_id(&mut self) -> usize {
+        self.offsets.push(0);
+        self.offsets.len()
+    }
+
+    fn object(&mut self, id: usize, body: &[u8]) -> io::Result<()> {
+        self.offsets[id - 1] = self.pos;
+        self.write(format!("{} 0 obj\n", id).as_bytes())?;
+        self.write(body)?;
+        self.write(b"\nendobj\n")
+    }
+
+    fn stream(&mut self, id: usize, dict: &str, data: &[u8]) -> io::Result<()> {
+        let mut body = format!("<< {} /Length {} >>\nstream\n", dict, data.len()).into_bytes();
+        body.extend_from_slice(data);
+        body.extend_from_slice(b"\nendstream");
+        self.object(id, &body)
+    }
+
+    /// Adds a page showing a frame (as RGBA, with an opaque background), with a label below it.
+    /// The label should be ASCII.
+    pub fn add_page(
+        &mut self,
+        width: u32,
+        height: u32,
+        rgba: &[u8],
+        label: &str,
+    ) -> io::Result<()> {
+        let (page_w, page_h) = if width >= height {
+            (PAGE_LONG, PAGE_SHORT)
+        } else {
+            (PAGE_SHORT, PAGE_LONG)
+        };
+        let label_space = 2.0 * LABEL_SIZE;
+        let avail_w = page_w - 2.0 * MARGIN;
+        let avail_h = page_h - 2.0 * MARGIN - label_space;
+        let scale = (avail_w / width as f64).min(avail_h / height as f64);
+        let (img_w, img_h) = (width as f64 * scale, height as f64 * scale);
+        let img_x = (page_w - img_w) / 2.0;
+        let img_y = MARGIN + label_space + (avail_h - img_h) / 2.0;
+
+        let rgb: Vec<u8> = rgba
+            .chunks(4)
+            .flat_map(|px| px[..3].iter().copied())
+            .collect();
+        let mut compressed = ZlibEncoder::new(Vec::new(), Compression::default());
+        compressed.write_all(&rgb)?;
+        let compressed = compressed.finish()?;
+
+        let mut contents = String::new();
+        let _ = writeln!(
+            contents,
+            "q {:.2} 0 0 {:.2} {:.2} {:.2} cm /Im Do Q",
+            img_w, img_h, img_x, img_y
+        );
+        let _ = writeln!(
+            contents,
+            "BT /F1 {} Tf {} {} Td ({}) Tj ET",
+            LABEL_SIZE,
+            MARGIN,
+            MARGIN,
+            escape(label)
+        );
+
+        let page_id = self.next_id();
+        let contents_id = self.next_id();
+        let image_id = self.next_id();
+        let image_dict = format!(
+            "/Type /XObject /Subtype /Image /Width {} /Height {} /ColorSpace /DeviceRGB \
+             /BitsPerComponent 8 /Filter /FlateDecode",
+            width, height
+        );
+        self.stream(image_id, &image_dict, &compressed)?;
+        self.stream(contents_id, "", contents.as_bytes())?;
+        let page = format!(
+            "<< /Type /Page /Parent {} 0 R /MediaBox [0 0 {} {}] \
+             /Resources << /Font << /F1 {} 0 R >> /XObject << /Im {} 0 R >> >> \
+             /Contents {} 0 R >>",
+            PAGES_ID, page_w, page_h, FONT_ID, image_id, contents_id
+        );
+        self.object(page_id, page.as_bytes())?;
+        self.page_ids.push(page_id);
+        Ok(())
+    }
+
+    /// Writes the parts of the PDF that come after the pages, and returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        let kids: Vec<String> = self
+            .page_ids
+            .iter()
+            .map(|id| format!("{} 0 R", id))
+            .collect();
+        let pages = format!(
+            "<< /Type /Pages /Kids [{}] /Count {} >>",
+            kids.join(" "),
+            kids.len()
+        );
+        self.object(PAGES_ID, pages.as_bytes())?;
+        let catalog = format!("<< /Type /Catalog /Pages {} 0 R >>", PAGES_ID);
+        self.object(CATALOG_ID, catalog.as_bytes())?;
+
+        let xref_pos = self.pos;
+        let mut xref = format!("xref\n0 {}\n0000000000 65535 f \n", self.offsets.len() + 1);
+        for offset in &self.offsets {
+            let _ = write!(xref, "{:010} 00000 n \n", offset);
+        }
+        let _ = write!(
+            xref,
+            "trailer\n<< /Size {} /Root {} 0 R >>\nstartxref\n{}\n%%EOF\n",
+            self.offsets.len() + 1,
+            CATALOG_ID,
+            xref_pos
+        );
+        self.write(xref.as_bytes())?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+// Escapes the characters that are special in PDF strings.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('(', "\\(")
+        .replace(')', "\\)")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use druid::kurbo::Point;
+    use scribble_curves::{Curve, Effects, LineStyle, SnippetData};
+
+    fn snippet(start: i64, end: Option<i64>) -> SnippetData {
+        let style = LineStyle {
+            color: druid::Color::BLACK,
+            thickness: 0.01,
+        };
+        let mut curve = Curve::new();
+        curve.move_to(
+            Point::new(0.0, 0.0),
+            Time::from_micros(start),
+            style,
+            Effects::default(),
+        );
+        curve.line_to(Point::new(1.0, 1.0), Time::from_micros(start + 100_000));
+        let mut snip = SnippetData::new(curve);
+        snip.end = end.map(Time::from_micros);
+        snip
+    }
+
+    #[test]
+    fn key_frames() {
+        let mut scribble = ScribbleState::default();
+        let snips = [
+            snippet(0, Some(2_000_000)),
+            // This one gets cleared at almost the same time, so it's the same scene.
+            snippet(500_000, Some(2_500_000)),
+            snippet(3_000_000, Some(5_000_000)),
+            snippet(6_000_000, None),
+        ];
+        for snip in snips.iter().cloned() {
+            scribble.snippets = scribble.snippets.with_new_snippet(snip).0;
+        }
+        scribble.mark = Some(Time::from_micros(4_000_000));
+        let range = TimeSpan::new(Time::from_micros(0), Time::from_micros(7_000_000));
+        let times: Vec<i64> = key_frame_times(&scribble, range)
+            .iter()
+            .map(|t| t.as_micros())
+            .collect();
+        assert_eq!(times, vec![2_000_000, 4_000_000, 5_000_000, 7_000_000]);
+
+        // If everything's gone by the end, the last page would be blank.
+        let range = TimeSpan::new(Time::from_micros(0), Time::from_micros(5_500_000));
+        scribble.mark = None;
+        let times: Vec<i64> = key_frame_times(&scribble, range)
+            .iter()
+            .map(|t| t.as_micros())
+            .collect();
+        assert_eq!(times, vec![2_000_000, 5_000_000]);
+    }
+
+    #[test]
+    fn labels() {
+        assert_eq!(page_label(3, Time::from_micros(65_200_000)), "3 - 1:05.20");
+        assert_eq!(escape("a (b) \\c"), "a \\(b\\) \\\\c");
+    }
+
+    #[test]
+    fn write_pdf() {
+        let mut pdf = PdfWriter::new(Vec::new()).unwrap();
+        pdf.add_page(4, 2, &[255; 4 * 4 * 2], "1 - 0:01.00")
+            .unwrap();
+        pdf.add_page(2, 4, &[0; 2 * 4 * 4], "2 - 0:02.00").unwrap();
+        let bytes = pdf.finish().unwrap();
+        // The images are binary, so the offsets into `text` aren't the same as the offsets into
+        // `bytes`. But the cross-reference table is at the end, and it's ASCII.
+        let text = String::from_utf8_lossy(&bytes);
+        assert!(bytes.starts_with(b"%PDF-1.4\n"));
+        assert!(text.contains("/Count 2"));
+        assert!(text.contains("/MediaBox [0 0 842 595]"));
+        assert!(text.contains("/MediaBox [0 0 595 842]"));
+        assert!(text.contains("(1 - 0:01.00) Tj"));
+        assert!(text.ends_with("%%EOF\n"));
+
+        // Every entry in the cross-reference table points at the start of its object.
+        let xref_pos: usize = text.lines().rev().nth(1).unwrap().parse().unwrap();
+        let xref = std::str::from_utf8(&bytes[xref_pos..]).unwrap();
+        assert!(xref.starts_with("xref\n"));
+        let offsets: Vec<usize> = xref
+            .lines()
+            .skip(3)
+            .take_while(|line| line.ends_with(" n "))
+            .map(|line| line[..10].parse().unwrap())
+            .collect();
+        assert_eq!(offsets.len(), 3 + 2 * 3);
+        for (i, offset) in offsets.iter().enumerate() {
+            let header = format!("{} 0 obj\n", i + 1);
+            assert!(bytes[*offset..].starts_with(header.as_bytes()));
+        }
+    }
+}
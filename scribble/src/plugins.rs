@@ -0,0 +1,199 @@
+//! Extension points for code that isn't part of scribble itself.
+//!
+//! There are three kinds of plugins: drawing tools (which change the strokes that the user
+//! draws), export backends (which write animations in formats that scribble doesn't know
+//! about), and audio processors (which clean up newly recorded narration). Plugins are compiled
+//! in: a plugin crate gets added as an optional dependency (behind a cargo feature), and
+//! [`builtin_plugins`] registers it when that feature is enabled. This means that extending
+//! scribble doesn't need any changes to the UI code.
+
+use druid::Point;
+use once_cell::sync::OnceCell;
+use std::path::Path;
+use std::sync::mpsc::Sender;
+
+use scribble_curves::Time;
+
+use crate::cmd::ExportCmd;
+use crate::encode::EncodingStatus;
+use crate::time_remap::TimeRemap;
+
+/// A tool that changes the strokes that get drawn.
+pub trait DrawingTool: Send + Sync {
+    /// The name of this tool, as shown in the command palette. It must be unique.
+    fn name(&self) -> &'static str;
+
+    /// Takes a stroke that the user just finished drawing (as a list of points in image
+    /// coordinates, and the times at which they were drawn), and returns the strokes that should
+    /// actually be added to the animation. Each returned stroke must have as many times as
+    /// points.
+    fn apply(&self, points: &[Point], times: &[Time]) -> Vec<(Vec<Point>, Vec<Time>)>;
+}
+
+/// A way of exporting animations.
+pub trait ExportBackend: Send + Sync {
+    /// The name of this export format, for error messages.
+    fn name(&self) -> &'static str;
+
+    /// The file extensions (without the dot) that this backend handles. If one of these clashes
+    /// with a built-in format, the backend wins.
+    fn extensions(&self) -> &[&'static str];
+
+    /// Writes `cmd.scribble` to `cmd.filename`.
+    ///
+    /// By the time this gets called, the audio in `cmd.scribble` has already been sped up (if the
+    /// export settings asked for that), and `remap` says which time of the animation to show in
+    /// each moment of the exported video. The backend can report its progress on `progress`, but
+    /// it shouldn't send `Finished` or `Error`: those get sent according to the return value.
+    fn export(
+        &self,
+        cmd: &ExportCmd,
+        remap: &TimeRemap,
+        progress: &Sender<EncodingStatus>,
+    ) -> anyhow::Result<()>;
+}
+
+/// Something that processes audio as soon as it's been recorded.
+pub trait AudioProcessor: Send + Sync {
+    /// The name of this processor, for logging.
+    fn name(&self) -> &'static str;
+
+    /// Modifies the recorded audio (which is mono, at `crate::audio::SAMPLE_RATE`). This runs
+    /// after scribble's own noise removal.
+    fn process(&self, buf: &mut Vec<i16>);
+}
+
+/// All the plugins that we know about.
+#[derive(Default)]
+pub struct Registry {
+    tools: Vec<Box<dyn DrawingTool>>,
+    exporters: Vec<Box<dyn ExportBackend>>,
+    audio_processors: Vec<Box<dyn AudioProcessor>>,
+}
+
+impl Registry {
+    pub fn register_tool(&mut self, tool: impl DrawingTool + 'static) {
+        self.tools.push(Box::new(tool));
+    }
+
+    pub fn register_exporter(&mut self, exporter: impl ExportBackend + 'static) {
+        self.exporters.push(Box::new(exporter));
+    }
+
+    /// Adds an audio processor. Processors run in the order that they were registered.
+    pub fn register_audio_processor(&mut self, processor: impl AudioProcessor + 'static) {
+        self.audio_processors.push(Box::new(processor));
+    }
+
+    pub fn tools(&self) -> impl Iterator<Item = &dyn DrawingTool> {
+        self.tools.iter().map(|t| t.as_ref())
+    }
+
+    /// The drawing tool with the given name, if there is one.
+    pub fn tool(&self, name: &str) -> Option<&dyn DrawingTool> {
+        self.tools().find(|t| t.name() == name)
+    }
+
+    /// The export backend that handles files like `path`, if there is one.
+    pub fn exporter_for(&self, path: &Path) -> Option<&dyn ExportBackend> {
+        let ext = path.extension()?.to_str()?.to_lowercase();
+        self.exporters
+            .iter()
+            .find(|e| e.extensions().iter().any(|&x| x == ext))
+            .map(|e| e.as_ref())
+    }
+
+    /// Runs all the audio processors on some newly recorded audio.
+    pub fn process_recording(&self, buf: &mut Vec<i16>) {
+        for processor in &self.audio_processors {
+            log::debug!("running audio processor {}", processor.name());
+            processor.process(buf);
+        }
+    }
+}
+
+static REGISTRY: OnceCell<Registry> = OnceCell::new();
+
+/// The plugins that were compiled in.
+pub fn registry() -> &'static Registry {
+    REGISTRY.get_or_init(builtin_plugins)
+}
+
+// Plugins get registered here, each behind its own feature.
+fn builtin_plugins() -> Registry {
+    #[allow(unused_mut)]
+    let mut ret = Registry::default();
+    #[cfg(feature = "example-plugins")]
+    ret.register_tool(StraightLine);
+    ret
+}
+
+/// An example drawing tool, which replaces each stroke by a straight line between its ends.
+#[cfg(any(test, feature = "example-plugins"))]
+pub struct StraightLine;
+
+#[cfg(any(test, feature = "example-plugins"))]
+impl DrawingTool for StraightLine {
+    fn name(&self) -> &'static str {
+        "Straight line"
+    }
+
+    fn apply(&self, points: &[Point], times: &[Time]) -> Vec<(Vec<Point>, Vec<Time>)> {
+        match (points.first(), points.last()) {
+            (Some(&start), Some(&end)) if points.len() > 1 => {
+                vec![(vec![start, end], vec![times[0], times[times.len() - 1]])]
+            }
+            _ => vec![(points.to_vec(), times.to_vec())],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Gain;
+
+    impl AudioProcessor for Gain {
+        fn name(&self) -> &'static str {
+            "Gain"
+        }
+
+        fn process(&self, buf: &mut Vec<i16>) {
+            for x in buf.iter_mut() {
+                *x = x.saturating_mul(2);
+            }
+        }
+    }
+
+    #[test]
+    fn register_and_look_up() {
+        let mut reg = Registry::default();
+        reg.register_tool(StraightLine);
+        reg.register_audio_processor(Gain);
+        reg.register_audio_processor(Gain);
+
+        assert!(reg.tool("Straight line").is_some());
+        assert!(reg.tool("Pen").is_none());
+        assert!(reg.exporter_for(Path::new("a.webm")).is_none());
+
+        let mut buf = vec![1, -2, 20000];
+        reg.process_recording(&mut buf);
+        assert_eq!(buf, vec![4, -8, i16::MAX]);
+    }
+
+    #[test]
+    fn straight_line() {
+        let t = Time::from_micros;
+        let points = [
+            Point::new(0.0, 0.0),
+            Point::new(1.0, 5.0),
+            Point::new(2.0, 0.0),
+        ];
+        let times = [t(0), t(10), t(20)];
+        assert_eq!(
+            StraightLine.apply(&points, &times),
+            vec![(vec![points[0], points[2]], vec![t(0), t(20)])]
+        );
+    }
+}
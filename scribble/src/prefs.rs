@@ -0,0 +1,963 @@
+//! User preferences, which (unlike project settings) are shared between all projects. They are
+//! stored as TOML in the user's config directory.
+
+use druid::{Data, KeyCode, KeyEvent, KeyModifiers, Lens, MouseButton, MouseEvent, SysMods};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use scribble_core::save_file::SaveFormat;
+
+use crate::command_palette::BUTTON_ACTIONS;
+use crate::encode::{ExportFormat, ExportSettings, FrameRate, RateControl, Resolution, VideoCodec};
+
+/// The number of projects that we remember in the "Open recent" menu.
+const MAX_RECENT_FILES: usize = 10;
+
+/// The sets of keyboard shortcuts to choose from.
+#[derive(Clone, Copy, Data, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Keymap {
+    /// Every shortcut does the same thing all the time.
+    Standard,
+    /// Vim-style: when nothing is being recorded, the keys without modifiers are the ones in
+    /// [`MODAL_KEYBINDINGS`] (see `crate::modal`).
+    Modal,
+}
+
+/// A named bundle of export settings.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ExportPreset {
+    pub name: String,
+    pub settings: ExportSettings,
+}
+
+#[derive(Clone, Data, Debug, Deserialize, Lens, PartialEq, Serialize)]
+#[serde(default)]
+// TOML needs all the plain values to come before any tables, so the keybindings and the export
+// presets go last.
+pub struct Preferences {
+    /// How often (in seconds) to autosave the current project, if it has changed. Zero turns off
+    /// autosaving.
+    pub autosave_secs: f64,
+
+    /// The format to use when saving projects.
+    pub save_format: SaveFormat,
+
+    /// How many previous versions of a project to keep when it gets overwritten. They're stored
+    /// next to the project, as `project.scb.bak1` (the most recent) to `project.scb.bakN`.
+    pub backups: u32,
+
+    /// Whether imported audio files get linked (so that the project refers to the file) instead
+    /// of embedded (so that the project contains a copy of the audio).
+    pub link_audio: bool,
+
+    /// Whether imported Excalidraw and tldraw drawings get drawn stroke by stroke (instead of
+    /// appearing all at once).
+    pub draw_on_imported_drawings: bool,
+
+    /// Whether to save the undo history next to each project (as `project.scb.undo`), so that
+    /// the last session's edits can still be undone after reopening it.
+    pub save_undo_history: bool,
+
+    /// The name of the audio device to record from. If this is empty (or if there's no device
+    /// with this name), we use the system's default.
+    pub input_device: String,
+
+    /// The name of the audio device to play to. If this is empty (or if there's no device with
+    /// this name), we use the system's default.
+    pub output_device: String,
+
+    /// The name of the export preset to start with. If this is empty, we start with the
+    /// built-in export settings.
+    pub default_export_preset: String,
+
+    /// Whether newly added images fade in, when scribble starts.
+    pub fade_in_images: bool,
+
+    /// Whether to show the pen position during playback, when scribble starts.
+    pub show_pen: bool,
+
+    /// Whether dragging one finger on a touchscreen pans the canvas (instead of drawing). Two
+    /// fingers always pan.
+    pub touch_pans: bool,
+
+    /// Which set of keyboard shortcuts to use.
+    pub keymap: Keymap,
+
+    /// A key (like "F9") that starts recording a drawing, or stops recording, even when scribble
+    /// isn't the focused window. The names of the keys are the operating system's, and modifiers
+    /// aren't supported. Empty means that there isn't one.
+    pub global_draw_key: String,
+
+    /// Like `global_draw_key`, but for recording audio.
+    pub global_talk_key: String,
+
+    /// The USB id (like "05f3:00ff") of a foot pedal to listen to. Empty means that there isn't
+    /// one.
+    pub pedal_device: String,
+
+    /// The port (on localhost) to listen on for remote control (see `crate::remote`). Zero
+    /// turns off remote control.
+    pub remote_control_port: u16,
+
+    /// The port (on every network interface) to listen on for pens on other devices (see
+    /// `crate::remote_pen`). Zero means that we don't listen.
+    pub pen_server_port: u16,
+
+    /// The password that pens on other devices need to send before they can draw. Empty means
+    /// that we don't listen for them.
+    pub pen_server_password: String,
+
+    /// Where to publish the canvas when live output is turned on: a v4l2loopback device (like
+    /// "/dev/video10"), or a named pipe. Empty means that there isn't one.
+    pub live_output_target: String,
+
+    /// The port to listen on when hosting a collaboration (see `crate::collab`). Zero means that
+    /// we can't host.
+    pub collab_port: u16,
+
+    /// The address (like "192.168.1.20:7878") of the scribble to join when joining a
+    /// collaboration. Empty means that there isn't one.
+    pub collab_address: String,
+
+    /// The IP address of the network interface (like "192.168.1.20", or "0.0.0.0" for all of
+    /// them) to host collaborations on. Empty means localhost, so that only other copies of
+    /// scribble on the same computer can join.
+    pub collab_interface: String,
+
+    /// The password that guests need in order to join a collaboration. Empty means that we can't
+    /// host or join one.
+    pub collab_password: String,
+
+    /// Whether playback follows the MIDI timecode and transport messages that other programs
+    /// send (see `crate::midi_sync`).
+    pub follow_midi_sync: bool,
+
+    /// The name (or part of the name) of a MIDI output to send timecode to. Empty means that we
+    /// don't send any.
+    pub midi_sync_output: String,
+
+    /// The whisper.cpp model file (like "ggml-base.en.bin") to use for turning narration into
+    /// captions. Empty means that there isn't one.
+    pub whisper_model: String,
+
+    /// The language of the narration, as a code like "en". Empty means that whisper should guess.
+    pub transcription_language: String,
+
+    /// The projects that were most recently opened or saved, most recent first.
+    pub recent_files: Arc<Vec<PathBuf>>,
+
+    /// What the buttons on MIDI controllers and foot pedals do (see `crate::controllers`). The
+    /// keys are the names of the controls, like "pedal-1" or "midi-note-36", and the values are
+    /// the names of the actions.
+    pub controller_bindings: Arc<BTreeMap<String, String>>,
+
+    /// What the mouse buttons do, on top of the left button's drawing and selecting and the middle
+    /// button's panning (which get overridden if they're bound here). The keys are buttons like
+    /// "Right", "Back" or "Shift+Middle" (see [`MouseBinding`]), and the values are the names of
+    /// the actions. Besides the usual actions, there's "erase", which deletes the drawings that
+    /// get dragged over.
+    ///
+    /// Tablet drivers report the buttons on the barrel of a pen as the right and middle buttons,
+    /// so these bindings are also the pen bindings. We can't tell which end of the pen is
+    /// touching the tablet, so erasing with the eraser tip needs the driver to report the
+    /// eraser as a right click (or as some other button that's bound to "erase").
+    pub mouse_bindings: Arc<BTreeMap<String, String>>,
+
+    /// Keyboard shortcuts that are different from the default ones (see [`DEFAULT_KEYBINDINGS`]
+    /// and [`MODAL_KEYBINDINGS`]).
+    /// The keys are the names of the actions, and the values are shortcuts like "Cmd+Shift+O".
+    /// An empty value means that the action doesn't have a shortcut.
+    pub keybindings: Arc<BTreeMap<String, String>>,
+
+    pub export_presets: Arc<Vec<ExportPreset>>,
+}
+
+impl Default for Preferences {
+    fn default() -> Preferences {
+        let youtube = ExportSettings {
+            format: ExportFormat::WebM,
+            resolution: Resolution::P1080,
+            frame_rate: FrameRate::Fps60,
+            codec: VideoCodec::Vp9,
+            rate_control: RateControl::Bitrate,
+            bitrate_kbps: 12000.0,
+            two_pass: true,
+            ..ExportSettings::default()
+        };
+        let small_gif = ExportSettings {
+            format: ExportFormat::Gif,
+            resolution: Resolution::Custom,
+            custom_resolution: 240.0,
+            gif_fps: 10.0,
+            ..ExportSettings::default()
+        };
+        // For checking the pacing and the audio sync: a quarter of 1080p, with vp9enc's fastest
+        // settings.
+        let draft = ExportSettings {
+            format: ExportFormat::WebM,
+            resolution: Resolution::Custom,
+            custom_resolution: 270.0,
+            frame_rate: FrameRate::Fps24,
+            codec: VideoCodec::Vp9,
+            rate_control: RateControl::Quality,
+            quality: 40.0,
+            encoder_args: "deadline=1 cpu-used=8".to_owned(),
+            ..ExportSettings::default()
+        };
+        let archive = ExportSettings {
+            format: ExportFormat::WebM,
+            resolution: Resolution::P2160,
+            frame_rate: FrameRate::Fps60,
+            codec: VideoCodec::Vp9,
+            rate_control: RateControl::Quality,
+            quality: 4.0,
+            ..ExportSettings::default()
+        };
+        Preferences {
+            export_presets: Arc::new(vec![
+                ExportPreset {
+                    name: "YouTube 1080p60".to_owned(),
+                    settings: youtube,
+                },
+                ExportPreset {
+                    name: "Small GIF".to_owned(),
+                    settings: small_gif,
+                },
+                ExportPreset {
+                    name: "Draft".to_owned(),
+                    settings: draft,
+                },
+                ExportPreset {
+                    name: "High quality archive".to_owned(),
+                    settings: archive,
+                },
+            ]),
+            autosave_secs: 60.0,
+            save_format: SaveFormat::Json,
+            backups: 3,
+            link_audio: false,
+            draw_on_imported_drawings: true,
+            save_undo_history: false,
+            input_device: String::new(),
+            output_device: String::new(),
+            default_export_preset: String::new(),
+            fade_in_images: false,
+            show_pen: false,
+            touch_pans: false,
+            keymap: Keymap::Standard,
+            global_draw_key: String::new(),
+            global_talk_key: String::new(),
+            pedal_device: String::new(),
+            remote_control_port: 0,
+            pen_server_port: 0,
+            pen_server_password: String::new(),
+            live_output_target: String::new(),
+            collab_port: 0,
+            collab_address: String::new(),
+            collab_interface: String::new(),
+            collab_password: String::new(),
+            follow_midi_sync: false,
+            midi_sync_output: String::new(),
+            whisper_model: String::new(),
+            transcription_language: String::new(),
+            recent_files: Arc::new(Vec::new()),
+            controller_bindings: Arc::new(default_controller_bindings()),
+            mouse_bindings: Arc::new(default_mouse_bindings()),
+            keybindings: Arc::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl Preferences {
+    /// The path of the preferences file, if we could figure out where the config directory is.
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("scribble").join("prefs.toml"))
+    }
+
+    /// Loads the preferences from the config directory. If there aren't any saved preferences
+    /// (or if they fail to load), returns the default preferences.
+    pub fn load() -> Preferences {
+        let path = match Preferences::path() {
+            Some(path) => path,
+            None => return Preferences::default(),
+        };
+        if !path.exists() {
+            return Preferences::default();
+        }
+        match std::fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|s| Ok(toml::from_str(&s)?))
+        {
+            Ok(prefs) => {
+                for conflict in prefs.keybinding_problems() {
+                    log::warn!("{}", conflict);
+                }
+                prefs
+            }
+            Err(e) => {
+                log::error!("error loading preferences from {:?}: {}", path, e);
+                Preferences::default()
+            }
+        }
+    }
+
+    /// Saves the preferences to the config directory.
+    pub fn save(&self) -> anyhow::Result<()> {
+        let path =
+            Preferences::path().ok_or_else(|| anyhow::anyhow!("no config directory found"))?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&path, toml::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// Returns a copy of these preferences with an additional export preset. If there is already
+    /// a preset with the same name, it is replaced.
+    pub fn with_export_preset(&self, name: String, settings: ExportSettings) -> Preferences {
+        let mut presets = (*self.export_presets).clone();
+        let preset = ExportPreset { name, settings };
+        if let Some(existing) = presets.iter_mut().find(|p| p.name == preset.name) {
+            *existing = preset;
+        } else {
+            presets.push(preset);
+        }
+        let mut ret = self.clone();
+        ret.export_presets = Arc::new(presets);
+        ret
+    }
+
+    /// The export settings to start with: the ones from the default preset, if there is one.
+    pub fn default_export(&self) -> ExportSettings {
+        self.export_presets
+            .iter()
+            .find(|p| p.name == self.default_export_preset)
+            .map(|p| p.settings.clone())
+            .unwrap_or_default()
+    }
+
+    /// The keyboard shortcut for `action` (which should be one of the actions in
+    /// [`DEFAULT_KEYBINDINGS`] or [`MODAL_KEYBINDINGS`]).
+    pub fn hotkey(&self, action: &str) -> Option<Hotkey> {
+        let default = || default_keybinding(action).and_then(|key| Hotkey::parse(key).ok());
+        match self.keybindings.get(action) {
+            Some(key) if key.is_empty() => None,
+            Some(key) => match Hotkey::parse(key) {
+                Ok(hotkey) => Some(hotkey),
+                Err(e) => {
+                    log::error!("bad keybinding for {}: {}", action, e);
+                    default()
+                }
+            },
+            None => default(),
+        }
+    }
+
+    /// Is `ev` the key for `action`? This ignores the modifiers, which is what we want for actions
+    /// that last as long as the key is held down.
+    pub fn is_key_for(&self, ev: &KeyEvent, action: &str) -> bool {
+        self.hotkey(action)
+            .map(|h| h.matches_key(ev))
+            .unwrap_or(false)
+    }
+
+    /// Is `ev` the shortcut for `action`, including the modifiers?
+    pub fn is_shortcut_for(&self, ev: &KeyEvent, action: &str) -> bool {
+        self.hotkey(action).map(|h| h.matches(ev)).unwrap_or(false)
+    }
+
+    /// The shortcut for `action` as it would be written in the preferences file, taking the
+    /// default into account. This is empty if the action doesn't have a shortcut.
+    pub fn keybinding(&self, action: &str) -> String {
+        match self.keybindings.get(action) {
+            Some(key) => key.clone(),
+            None => default_keybinding(action).unwrap_or_default().to_owned(),
+        }
+    }
+
+    /// Changes the shortcut for `action`. Only shortcuts that differ from the default get stored.
+    pub fn set_keybinding(&mut self, action: &str, key: String) {
+        let is_default = default_keybinding(action) == Some(key.as_str());
+        let bindings = Arc::make_mut(&mut self.keybindings);
+        if is_default {
+            bindings.remove(action);
+        } else {
+            bindings.insert(action.to_owned(), key);
+        }
+    }
+
+    /// Describes the keybindings that can't be parsed, and the shortcuts that are assigned to
+    /// more than one action. (The normal-mode shortcuts are only compared with each other, since
+    /// they're allowed to reuse keys from the standard ones.) This also checks the controller and
+    /// mouse bindings.
+    pub fn keybinding_problems(&self) -> Vec<String> {
+        let mut ret = Vec::new();
+        for &table in &[DEFAULT_KEYBINDINGS, MODAL_KEYBINDINGS] {
+            let mut bound: Vec<(&str, Hotkey)> = Vec::new();
+            for (action, _) in table {
+                let key = self.keybinding(action);
+                if key.is_empty() {
+                    continue;
+                }
+                match Hotkey::parse(&key) {
+                    Ok(hotkey) => {
+                        if let Some((other, _)) = bound.iter().find(|(_, h)| *h == hotkey) {
+                            ret.push(format!("{} is used for both {} and {}", key, other, action));
+                        }
+                        bound.push((action, hotkey));
+                    }
+                    Err(e) => ret.push(format!("bad keybinding for {}: {}", action, e)),
+                }
+            }
+        }
+        for action in self.keybindings.keys() {
+            if default_keybinding(action).is_none() {
+                ret.push(format!("there is no action called {}", action));
+            }
+        }
+        for (control, action) in self.controller_bindings.iter() {
+            if !is_button_action(action) {
+                ret.push(format!(
+                    "{} is bound to {}, which isn't an action",
+                    control, action
+                ));
+            }
+        }
+        let mut bound: Vec<MouseBinding> = Vec::new();
+        for (button, action) in self.mouse_bindings.iter() {
+            match MouseBinding::parse(button) {
+                Ok(b) if b.is_plain_left() => ret.push(format!(
+                    "the left button is for drawing, not for {}",
+                    action
+                )),
+                Ok(b) if bound.contains(&b) => {
+                    ret.push(format!("{} is bound more than once", button))
+                }
+                Ok(b) => bound.push(b),
+                Err(e) => ret.push(format!("bad mouse binding for {}: {}", action, e)),
+            }
+            if !is_button_action(action) && action != "erase" {
+                ret.push(format!(
+                    "{} is bound to {}, which isn't an action",
+                    button, action
+                ));
+            }
+        }
+        ret
+    }
+
+    /// The action that `button` (which shouldn't have any modifiers) is bound to, as it would be
+    /// written in the preferences file. This is empty if the button isn't bound.
+    pub fn mouse_binding(&self, button: &str) -> String {
+        let target = match MouseBinding::parse(button) {
+            Ok(target) => target,
+            Err(_) => return String::new(),
+        };
+        self.mouse_bindings
+            .iter()
+            .find(|(b, _)| MouseBinding::parse(b).ok() == Some(target))
+            .map(|(_, action)| action.clone())
+            .unwrap_or_default()
+    }
+
+    /// Binds `button` to `action`, replacing any other binding for it. An empty action unbinds
+    /// the button. Fails (without changing anything) if `button` can't be parsed.
+    pub fn set_mouse_binding(&mut self, button: &str, action: String) -> anyhow::Result<()> {
+        let target = MouseBinding::parse(button).map_err(|e| anyhow::anyhow!(e))?;
+        let bindings = Arc::make_mut(&mut self.mouse_bindings);
+        bindings.retain(|b, _| MouseBinding::parse(b).ok() != Some(target));
+        let action = action.trim();
+        if !action.is_empty() {
+            bindings.insert(button.to_owned(), action.to_owned());
+        }
+        Ok(())
+    }
+
+    /// The action that the mouse button in `ev` is bound to, if any.
+    pub fn mouse_action(&self, ev: &MouseEvent) -> Option<&str> {
+        self.mouse_bindings
+            .iter()
+            .find(|(button, _)| {
+                MouseBinding::parse(button)
+                    .map(|b| b.matches(ev.button, &ev.mods))
+                    .unwrap_or(false)
+            })
+            .map(|(_, action)| action.as_str())
+    }
+
+    /// Returns a copy of these preferences with `path` at the top of the recently used files.
+    pub fn with_recent_file(&self, path: &Path) -> Preferences {
+        let mut recent = vec![path.to_owned()];
+        recent.extend(self.recent_files.iter().filter(|p| *p != path).cloned());
+        recent.truncate(MAX_RECENT_FILES);
+        let mut ret = self.clone();
+        ret.recent_files = Arc::new(recent);
+        ret
+    }
+}
+
+/// The actions that have keyboard shortcuts, and their default shortcuts (empty if they don't
+/// have one by default).
+pub const DEFAULT_KEYBINDINGS: &[(&str, &str)] = &[
+    ("open", "Cmd+O"),
+    ("reopen", "Cmd+Shift+O"),
+    ("save", "Cmd+S"),
+    ("export", "Cmd+E"),
+    ("draw", "Cmd+D"),
+    ("talk", "Cmd+T"),
+    ("play", "Cmd+P"),
+    ("command-palette", "Cmd+Shift+P"),
+    ("play-pause", "Space"),
+    ("fade", "F"),
+    ("next-recording-speed", "S"),
+    ("color-1", "1"),
+    ("color-2", "2"),
+    ("color-3", "3"),
+    ("color-4", "4"),
+    ("color-5", "5"),
+    ("color-6", "6"),
+    ("color-7", "7"),
+    ("color-8", "8"),
+    ("color-9", "9"),
+    ("stop", ""),
+    ("mark", "M"),
+    ("jump-to-mark", "J"),
+    ("jump-to-start", "Home"),
+    ("jump-to-end", "End"),
+    ("next-boundary", "PageDown"),
+    ("previous-boundary", "PageUp"),
+    ("snap-to-boundary", "B"),
+    ("warp", "W"),
+    ("truncate", "T"),
+    ("recolor", "C"),
+    ("repeat-last-edit", "."),
+    ("delete", "Delete"),
+    ("copy-snippet", "Cmd+C"),
+    ("paste-snippet", "Cmd+Shift+V"),
+    ("paste-image", "Cmd+V"),
+    ("reset-zoom", "Cmd+0"),
+    ("new-tab", "Cmd+N"),
+    ("close-tab", "Cmd+W"),
+    // Scanning lasts as long as the key is held down, and holding shift makes it go faster.
+    ("scan-forward", "Right"),
+    ("scan-backward", "Left"),
+];
+
+/// The normal-mode shortcuts of the modal keymap (see [`Keymap::Modal`]). Most of them do the
+/// same thing as the standard action that they're named after (so "normal-delete" does the same
+/// thing as "delete"); the exceptions are handled in `crate::modal`. Typing a number before a
+/// movement repeats it, so these shouldn't use the digits (except that "0" is fine, as long as
+/// it isn't part of a number).
+pub const MODAL_KEYBINDINGS: &[(&str, &str)] = &[
+    ("normal-left", "H"),
+    ("normal-right", "L"),
+    ("normal-next-boundary", "J"),
+    ("normal-previous-boundary", "K"),
+    ("normal-jump-to-start", "0"),
+    ("normal-jump-to-end", "Shift+G"),
+    ("normal-jump-to-mark", "'"),
+    ("normal-snap-to-boundary", "B"),
+    ("normal-mark", "M"),
+    ("normal-insert", "I"),
+    ("normal-talk", "A"),
+    ("normal-play-pause", "Space"),
+    ("normal-warp", "W"),
+    ("normal-truncate", "T"),
+    ("normal-recolor", "C"),
+    ("normal-delete", "X"),
+    ("normal-copy-snippet", "Y"),
+    ("normal-paste-snippet", "P"),
+    ("normal-repeat-last-edit", "."),
+    ("normal-fade", "F"),
+    ("normal-next-recording-speed", "S"),
+    ("normal-undo", "U"),
+    ("normal-redo", "Cmd+R"),
+    // Leaves insert mode (i.e. stops recording), or forgets a half-typed count.
+    ("normal-mode", "Escape"),
+];
+
+/// What the extra mouse buttons do, unless the preferences say otherwise.
+fn default_mouse_bindings() -> BTreeMap<String, String> {
+    [("Right", "erase"), ("Back", "undo"), ("Forward", "redo")]
+        .iter()
+        .map(|&(button, action)| (button.to_owned(), action.to_owned()))
+        .collect()
+}
+
+/// What the first three buttons of a foot pedal do, unless the preferences say otherwise.
+fn default_controller_bindings() -> BTreeMap<String, String> {
+    [
+        ("pedal-1", "toggle-draw"),
+        ("pedal-2", "undo"),
+        ("pedal-3", "next-recording-speed"),
+    ]
+    .iter()
+    .map(|&(control, action)| (control.to_owned(), action.to_owned()))
+    .collect()
+}
+
+/// Can `action` be bound to a button on a controller or a mouse?
+fn is_button_action(action: &str) -> bool {
+    default_keybinding(action).is_some() || BUTTON_ACTIONS.contains(&action)
+}
+
+/// The default shortcut for `action`, or `None` if there's no such action.
+fn default_keybinding(action: &str) -> Option<&'static str> {
+    DEFAULT_KEYBINDINGS
+        .iter()
+        .chain(MODAL_KEYBINDINGS)
+        .find(|(a, _)| *a == action)
+        .map(|(_, key)| *key)
+}
+
+/// A keyboard shortcut.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hotkey {
+    pub mods: SysMods,
+    pub key: HotkeyKey,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum HotkeyKey {
+    /// A key that gets matched by the text that it produces.
+    Text(String),
+    /// A key that gets matched by its key code.
+    Code(KeyCode),
+}
+
+const LETTER_CODES: [KeyCode; 26] = [
+    KeyCode::KeyA,
+    KeyCode::KeyB,
+    KeyCode::KeyC,
+    KeyCode::KeyD,
+    KeyCode::KeyE,
+    KeyCode::KeyF,
+    KeyCode::KeyG,
+    KeyCode::KeyH,
+    KeyCode::KeyI,
+    KeyCode::KeyJ,
+    KeyCode::KeyK,
+    KeyCode::KeyL,
+    KeyCode::KeyM,
+    KeyCode::KeyN,
+    KeyCode::KeyO,
+    KeyCode::KeyP,
+    KeyCode::KeyQ,
+    KeyCode::KeyR,
+    KeyCode::KeyS,
+    KeyCode::KeyT,
+    KeyCode::KeyU,
+    KeyCode::KeyV,
+    KeyCode::KeyW,
+    KeyCode::KeyX,
+    KeyCode::KeyY,
+    KeyCode::KeyZ,
+];
+
+impl Hotkey {
+    /// Parses a shortcut like "Cmd+Shift+O" or "Space". "Cmd" means the command key on macOS,
+    /// and the control key everywhere else ("Ctrl" is accepted too). The modifiers can be any
+    /// combination of "Cmd" and "Shift", or "Alt" together with "Cmd".
+    pub fn parse(s: &str) -> Result<Hotkey, String> {
+        let ((cmd, shift, alt), key) = split_modifiers(s)?;
+        if key.is_empty() {
+            return Err(format!("no key in \"{}\"", s));
+        }
+        let mods = match (alt, cmd, shift) {
+            (false, false, false) => SysMods::None,
+            (false, false, true) => SysMods::Shift,
+            (false, true, false) => SysMods::Cmd,
+            (false, true, true) => SysMods::CmdShift,
+            (true, true, false) => SysMods::AltCmd,
+            (true, true, true) => SysMods::AltCmdShift,
+            (true, false, _) => return Err("\"Alt\" only works together with \"Cmd\"".to_owned()),
+        };
+
+        let mut chars = key.chars();
+        let key = match (chars.next(), chars.next()) {
+            // Plain letters are matched by their key codes, so that they work regardless of the
+            // keyboard layout's case.
+            (Some(c), None) if c.is_ascii_alphabetic() && mods == SysMods::None => {
+                let idx = (c.to_ascii_lowercase() as u8 - b'a') as usize;
+                HotkeyKey::Code(LETTER_CODES[idx])
+            }
+            (Some(c), None) => HotkeyKey::Text(c.to_lowercase().to_string()),
+            _ => HotkeyKey::Code(match key.to_lowercase().as_str() {
+                "space" => KeyCode::Space,
+                "delete" => KeyCode::Delete,
+                "backspace" => KeyCode::Backspace,
+                "escape" | "esc" => KeyCode::Escape,
+                "enter" | "return" => KeyCode::Return,
+                "tab" => KeyCode::Tab,
+                "home" => KeyCode::Home,
+                "end" => KeyCode::End,
+                "pageup" => KeyCode::PageUp,
+                "pagedown" => KeyCode::PageDown,
+                "left" => KeyCode::ArrowLeft,
+                "right" => KeyCode::ArrowRight,
+                "up" => KeyCode::ArrowUp,
+                "down" => KeyCode::ArrowDown,
+                _ => return Err(format!("unknown key \"{}\"", key)),
+            }),
+        };
+        Ok(Hotkey { mods, key })
+    }
+
+    /// Is this the shortcut that was pressed?
+    pub fn matches(&self, ev: &KeyEvent) -> bool {
+        let (cmd, shift, alt) = held_modifiers(&ev.mods);
+        let mods = match self.mods {
+            SysMods::None => (false, false, false),
+            SysMods::Shift => (false, false, true),
+            SysMods::Cmd => (false, true, false),
+            SysMods::CmdShift => (false, true, true),
+            SysMods::AltCmd => (true, true, false),
+            SysMods::AltCmdShift => (true, true, true),
+        };
+        self.matches_key(ev) && mods == (alt, cmd, shift)
+    }
+
+    /// Is this the key that was pressed? This ignores the modifiers.
+    pub fn matches_key(&self, ev: &KeyEvent) -> bool {
+        match &self.key {
+            HotkeyKey::Code(code) => ev.key_code == *code,
+            HotkeyKey::Text(text) => ev
+                .unmod_text()
+                .map(|t| t.to_lowercase() == *text)
+                .unwrap_or(false),
+        }
+    }
+}
+
+/// A mouse button, possibly together with some modifiers, like "Shift+Right". The buttons are
+/// "Left", "Right", "Middle", "Back" and "Forward", and the modifiers are the same as the ones
+/// for [`Hotkey`] (except that any combination of them is allowed).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MouseBinding {
+    mods: Modifiers,
+    button: MouseButton,
+}
+
+impl MouseBinding {
+    pub fn parse(s: &str) -> Result<MouseBinding, String> {
+        let (mods, button) = split_modifiers(s)?;
+        let button = match button.to_lowercase().as_str() {
+            "left" => MouseButton::Left,
+            "right" => MouseButton::Right,
+            "middle" => MouseButton::Middle,
+            "back" => MouseButton::X1,
+            "forward" => MouseButton::X2,
+            "" => return Err(format!("no button in \"{}\"", s)),
+            _ => return Err(format!("unknown mouse button \"{}\"", button)),
+        };
+        Ok(MouseBinding { mods, button })
+    }
+
+    /// Is this the button that was pressed, with exactly these modifiers?
+    pub fn matches(&self, button: MouseButton, mods: &KeyModifiers) -> bool {
+        self.button == button && self.mods == held_modifiers(mods)
+    }
+
+    /// Is this the left button without any modifiers? That one is always for drawing.
+    fn is_plain_left(&self) -> bool {
+        self.button == MouseButton::Left && self.mods == (false, false, false)
+    }
+}
+
+/// Which of Cmd, Shift and Alt (in that order) are part of a shortcut.
+type Modifiers = (bool, bool, bool);
+
+/// Splits a shortcut like "Cmd+Shift+O" into its modifiers and its last part (which is empty if
+/// there isn't one).
+fn split_modifiers(s: &str) -> Result<(Modifiers, &str), String> {
+    let mut parts: Vec<&str> = s.split('+').map(|p| p.trim()).collect();
+    let last = parts.pop().unwrap_or_default();
+    let (mut cmd, mut shift, mut alt) = (false, false, false);
+    for m in parts {
+        match m.to_lowercase().as_str() {
+            "cmd" | "ctrl" => cmd = true,
+            "shift" => shift = true,
+            "alt" => alt = true,
+            _ => return Err(format!("unknown modifier \"{}\"", m)),
+        }
+    }
+    Ok(((cmd, shift, alt), last))
+}
+
+/// Which of Cmd, Shift and Alt are held down. "Cmd" is the command key on macOS, and the control
+/// key everywhere else.
+fn held_modifiers(mods: &KeyModifiers) -> Modifiers {
+    let cmd = if cfg!(target_os = "macos") {
+        mods.meta
+    } else {
+        mods.ctrl
+    };
+    (cmd, mods.shift, mods.alt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let prefs = Preferences::default();
+        let ser = toml::to_string(&prefs).unwrap();
+        let de: Preferences = toml::from_str(&ser).unwrap();
+        assert_eq!(prefs, de);
+    }
+
+    #[test]
+    fn hotkeys() {
+        let parsed = |s| Hotkey::parse(s).unwrap();
+        assert_eq!(
+            parsed("Cmd+Shift+o"),
+            Hotkey {
+                mods: SysMods::CmdShift,
+                key: HotkeyKey::Text("o".to_owned())
+            }
+        );
+        assert_eq!(parsed("ctrl + 0").mods, SysMods::Cmd);
+        assert_eq!(parsed("M").key, HotkeyKey::Code(KeyCode::KeyM));
+        assert_eq!(parsed("Space").key, HotkeyKey::Code(KeyCode::Space));
+        assert_eq!(parsed("Left").key, HotkeyKey::Code(KeyCode::ArrowLeft));
+        assert!(Hotkey::parse("Alt+X").is_err());
+        assert!(Hotkey::parse("Hyper+X").is_err());
+        assert!(Hotkey::parse("Cmd+").is_err());
+        for (_, key) in DEFAULT_KEYBINDINGS
+            .iter()
+            .filter(|(_, key)| !key.is_empty())
+        {
+            assert!(Hotkey::parse(key).is_ok(), "{}", key);
+        }
+
+        let mut prefs = Preferences::default();
+        let mut bindings = BTreeMap::new();
+        bindings.insert("draw".to_owned(), "Cmd+R".to_owned());
+        bindings.insert("play".to_owned(), String::new());
+        bindings.insert("talk".to_owned(), "nonsense+T".to_owned());
+        prefs.keybindings = Arc::new(bindings);
+        assert_eq!(prefs.hotkey("draw"), Hotkey::parse("Cmd+R").ok());
+        assert_eq!(prefs.hotkey("play"), None);
+        assert_eq!(prefs.hotkey("talk"), Hotkey::parse("Cmd+T").ok());
+        assert_eq!(prefs.hotkey("save"), Hotkey::parse("Cmd+S").ok());
+
+        // Make sure that the keybindings table doesn't get in the way of serializing.
+        let de: Preferences = toml::from_str(&toml::to_string(&prefs).unwrap()).unwrap();
+        assert_eq!(prefs, de);
+    }
+
+    #[test]
+    fn keybinding_problems() {
+        let mut prefs = Preferences::default();
+        assert!(prefs.keybinding_problems().is_empty());
+
+        prefs.set_keybinding("draw", "Cmd+S".to_owned());
+        prefs.set_keybinding("talk", "nonsense+T".to_owned());
+        prefs.set_keybinding("play", String::new());
+        prefs.set_keybinding("mark", "M".to_owned());
+        assert_eq!(prefs.keybinding("draw"), "Cmd+S");
+        assert_eq!(prefs.keybinding("play"), "");
+        assert!(!prefs.keybindings.contains_key("mark"));
+        assert_eq!(prefs.keybinding_problems().len(), 2);
+
+        prefs.set_keybinding("draw", "Cmd+D".to_owned());
+        prefs.set_keybinding("talk", "Cmd+T".to_owned());
+        assert_eq!(prefs.keybindings.len(), 1);
+        assert!(prefs.keybinding_problems().is_empty());
+
+        // The normal-mode shortcuts can reuse the standard keys, but not each other's.
+        prefs.set_keybinding("normal-mark", "T".to_owned());
+        assert_eq!(prefs.keybinding_problems().len(), 1);
+        prefs.set_keybinding("normal-truncate", "Shift+T".to_owned());
+        assert!(prefs.keybinding_problems().is_empty());
+
+        // Controls can be bound to keyboard actions, and to a few actions of their own.
+        let controls = Arc::make_mut(&mut prefs.controller_bindings);
+        controls.insert("midi-note-36".to_owned(), "mark".to_owned());
+        controls.insert("midi-cc-64".to_owned(), "toggle-talk".to_owned());
+        assert!(prefs.keybinding_problems().is_empty());
+        Arc::make_mut(&mut prefs.controller_bindings)
+            .insert("pedal-4".to_owned(), "dance".to_owned());
+        assert_eq!(prefs.keybinding_problems().len(), 1);
+    }
+
+    #[test]
+    fn mouse_bindings() {
+        let parsed = |s| MouseBinding::parse(s).unwrap();
+        let mut mods = KeyModifiers::default();
+        assert!(parsed("Right").matches(MouseButton::Right, &mods));
+        assert!(!parsed("Right").matches(MouseButton::Middle, &mods));
+        assert!(!parsed("Shift+Right").matches(MouseButton::Right, &mods));
+        mods.shift = true;
+        assert!(parsed("shift + right").matches(MouseButton::Right, &mods));
+        assert!(!parsed("Right").matches(MouseButton::Right, &mods));
+        assert!(parsed("Back").matches(MouseButton::X1, &KeyModifiers::default()));
+        assert!(MouseBinding::parse("Shift+").is_err());
+        assert!(MouseBinding::parse("Wheel").is_err());
+        assert!(MouseBinding::parse("Hyper+Left").is_err());
+
+        let mut prefs = Preferences::default();
+        assert!(prefs.keybinding_problems().is_empty());
+        let buttons = Arc::make_mut(&mut prefs.mouse_bindings);
+        buttons.insert("Middle".to_owned(), "play-pause".to_owned());
+        buttons.insert("Alt+Left".to_owned(), "erase".to_owned());
+        assert!(prefs.keybinding_problems().is_empty());
+        let buttons = Arc::make_mut(&mut prefs.mouse_bindings);
+        buttons.insert("Left".to_owned(), "undo".to_owned());
+        buttons.insert("right".to_owned(), "dance".to_owned());
+        // The left button is taken, "right" is the same as "Right", and "dance" isn't an action.
+        assert_eq!(prefs.keybinding_problems().len(), 3);
+
+        let mut prefs = Preferences::default();
+        assert_eq!(prefs.mouse_binding("Right"), "erase");
+        prefs
+            .set_mouse_binding("Right", "next-color".to_owned())
+            .unwrap();
+        assert_eq!(prefs.mouse_binding("right"), "next-color");
+        assert_eq!(prefs.mouse_bindings.len(), 3);
+        prefs
+            .set_mouse_binding("Middle", "undo".to_owned())
+            .unwrap();
+        prefs.set_mouse_binding("Back", String::new()).unwrap();
+        assert_eq!(prefs.mouse_binding("Back"), "");
+        assert_eq!(prefs.mouse_bindings.len(), 3);
+        assert!(prefs.keybinding_problems().is_empty());
+
+        // A button that doesn't parse is an error, and it leaves the other bindings alone.
+        Arc::make_mut(&mut prefs.mouse_bindings).insert("Shift+".to_owned(), "undo".to_owned());
+        assert!(prefs.set_mouse_binding("Wheel", "undo".to_owned()).is_err());
+        assert_eq!(prefs.mouse_binding("Wheel"), "");
+        assert_eq!(prefs.mouse_bindings.len(), 4);
+    }
+
+    #[test]
+    fn replace_preset() {
+        let prefs = Preferences::default();
+        let n = prefs.export_presets.len();
+        let prefs = prefs
+            .with_export_preset("Mine".to_owned(), ExportSettings::default())
+            .with_export_preset("Small GIF".to_owned(), ExportSettings::default());
+        assert_eq!(prefs.export_presets.len(), n + 1);
+        assert_eq!(prefs.export_presets[1].settings, ExportSettings::default());
+    }
+
+    #[test]
+    fn recent_files() {
+        let mut prefs = Preferences::default();
+        for i in 0..20 {
+            prefs = prefs.with_recent_file(Path::new(&format!("{}.scb", i)));
+        }
+        prefs = prefs.with_recent_file(Path::new("15.scb"));
+        assert_eq!(prefs.recent_files.len(), MAX_RECENT_FILES);
+        assert_eq!(prefs.recent_files[0], Path::new("15.scb"));
+        assert_eq!(prefs.recent_files[1], Path::new("19.scb"));
+        assert_eq!(prefs.recent_files[5], Path::new("14.scb"));
+
+        // Make sure that the serialization doesn't trip over the order of the fields.
+        let de: Preferences = toml::from_str(&toml::to_string(&prefs).unwrap()).unwrap();
+        assert_eq!(prefs, de);
+    }
+}
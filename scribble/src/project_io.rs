@@ -0,0 +1,435 @@
+//! Projects are saved and loaded on a background thread, so that big projects don't freeze the
+//! UI.
+
+use druid::Data;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use scribble_core::save_file::{SaveFileData, SaveFormat, SAVE_FILE_VERSION};
+
+use crate::data::{AppState, Dialog, ScribbleState};
+
+/// A save or load that is currently running.
+#[derive(Clone, Data, Debug, PartialEq)]
+pub struct FileOp {
+    /// True if we're saving, false if we're loading.
+    pub saving: bool,
+    /// The name of the file being saved or loaded.
+    pub name: String,
+    /// How much of the work has been done, between 0.0 and 1.0.
+    pub progress: f64,
+}
+
+impl FileOp {
+    pub fn new(saving: bool, path: &Path) -> FileOp {
+        FileOp {
+            saving,
+            name: path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            progress: 0.0,
+        }
+    }
+
+    pub fn description(&self) -> String {
+        if self.saving {
+            format!("Saving {}: ", self.name)
+        } else {
+            format!("Loading {}: ", self.name)
+        }
+    }
+}
+
+enum Job {
+    Save {
+        path: PathBuf,
+        data: SaveFileData,
+        format: SaveFormat,
+        backups: u32,
+        /// The undo history to save next to the project, if the user wants it saved.
+        history: Option<Vec<SaveFileData>>,
+    },
+    Load {
+        path: PathBuf,
+        with_history: bool,
+    },
+    /// Something that doesn't report back, like writing an autosave.
+    Background(Box<dyn FnOnce() + Send>),
+}
+
+/// Status updates from the background thread. Every save or load ends with either `Saved`,
+/// `Loaded`, or `Failed`.
+pub enum Status {
+    Progress(f64),
+    /// The project was saved. This comes with a copy of what was saved.
+    Saved(PathBuf, Box<SaveFileData>),
+    /// The project was loaded. This comes with its saved undo history (most recent first), if
+    /// there was one.
+    Loaded(PathBuf, Box<SaveFileData>, Vec<SaveFileData>),
+    Failed(String),
+}
+
+/// The handle to the background thread that saves and loads projects (and autosaves them).
+pub struct ProjectIo {
+    jobs: Sender<Job>,
+    pub status: Receiver<Status>,
+}
+
+// We only send progress updates when the progress has changed by at least this much, so that we
+// don't flood the UI thread.
+const PROGRESS_STEP: f64 = 0.01;
+
+impl ProjectIo {
+    pub fn new() -> ProjectIo {
+        let (jobs_tx, jobs_rx) = channel::<Job>();
+        let (status_tx, status_rx) = channel();
+        std::thread::spawn(move || {
+            for job in jobs_rx {
+                if let Some(status) = run(job, &status_tx) {
+                    let _ = status_tx.send(status);
+                }
+            }
+        });
+        ProjectIo {
+            jobs: jobs_tx,
+            status: status_rx,
+        }
+    }
+
+    /// Saves `data` to `path`, keeping `backups` previous versions of the file. If `history` is
+    /// given, it gets saved next to the project; otherwise, any previously saved history is
+    /// removed (since it would no longer match the project).
+    pub fn save(
+        &self,
+        path: PathBuf,
+        data: SaveFileData,
+        format: SaveFormat,
+        backups: u32,
+        history: Option<Vec<SaveFileData>>,
+    ) {
+        self.push(Job::Save {
+            path,
+            data,
+            format,
+            backups,
+            history,
+        });
+    }
+
+    /// Loads the project at `path`, together with its saved undo history if `with_history` is
+    /// true.
+    pub fn load(&self, path: PathBuf, with_history: bool) {
+        self.push(Job::Load { path, with_history });
+    }
+
+    /// Runs `f` on the background thread, after all the jobs that were queued before it.
+    pub fn run_in_background(&self, f: impl FnOnce() + Send + 'static) {
+        self.push(Job::Background(Box::new(f)));
+    }
+
+    fn push(&self, job: Job) {
+        if self.jobs.send(job).is_err() {
+            log::error!("the save/load thread has stopped");
+        }
+    }
+}
+
+fn run(job: Job, status: &Sender<Status>) -> Option<Status> {
+    let mut last_progress = 0.0;
+    let progress = |p: f64| {
+        if p - last_progress >= PROGRESS_STEP {
+            last_progress = p;
+            let _ = status.send(Status::Progress(p));
+        }
+    };
+    let status = match job {
+        Job::Save {
+            path,
+            data,
+            format,
+            backups,
+            history,
+        } => {
+            if let Err(e) = rotate_backups(&path, backups) {
+                // It's better to save without a backup than to not save at all.
+                log::error!("error backing up {:?}: {}", path, e);
+            }
+            match data.save_to_path_with_progress(&path, format, progress) {
+                Ok(()) => {
+                    // The project itself is safe, so a problem with the history isn't worth
+                    // failing the save over.
+                    if let Err(e) = save_history(&path, history.as_deref()) {
+                        log::error!("error saving the undo history for {:?}: {}", path, e);
+                    }
+                    Status::Saved(path, Box::new(data))
+                }
+                Err(e) => Status::Failed(format!("error saving {:?}: {}", path, e)),
+            }
+        }
+        Job::Load { path, with_history } => match load(&path, progress) {
+            Ok(data) => {
+                let history = if with_history {
+                    load_history(&path, &data).unwrap_or_else(|e| {
+                        log::warn!("couldn't load the undo history for {:?}: {}", path, e);
+                        Vec::new()
+                    })
+                } else {
+                    Vec::new()
+                };
+                Status::Loaded(path, Box::new(data), history)
+            }
+            Err(e) => Status::Failed(format!("error loading {:?}: {}", path, e)),
+        },
+        Job::Background(f) => {
+            f();
+            return None;
+        }
+    };
+    Some(status)
+}
+
+fn backup_path(path: &Path, n: u32) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(format!(".bak{}", n));
+    path.with_file_name(name)
+}
+
+// Before overwriting `path`, copies it to `path.bak1` (after moving `path.bak1` to `path.bak2`,
+// and so on). We copy instead of moving so that if the save fails, the original is still there.
+fn rotate_backups(path: &Path, count: u32) -> std::io::Result<()> {
+    if count == 0 || !path.exists() {
+        return Ok(());
+    }
+    let oldest = backup_path(path, count);
+    if oldest.exists() {
+        std::fs::remove_file(oldest)?;
+    }
+    for n in (1..count).rev() {
+        let backup = backup_path(path, n);
+        if backup.exists() {
+            std::fs::rename(backup, backup_path(path, n + 1))?;
+        }
+    }
+    std::fs::copy(path, backup_path(path, 1))?;
+    Ok(())
+}
+
+fn load(path: &Path, progress: impl FnMut(f64)) -> anyhow::Result<SaveFileData> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let data = SaveFileData::load_from(ProgressReader {
+        inner: file,
+        read: 0,
+        len,
+        progress,
+    })?;
+    Ok(data.with_links_resolved(path).with_audio_loaded())
+}
+
+// Where the undo history of the project at `path` gets saved.
+fn history_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".undo");
+    path.with_file_name(name)
+}
+
+// Saves the undo history (or removes the old one, if there's nothing to save). This should happen
+// just after the project is saved, because we use the modification times to check that the
+// history belongs to the project.
+fn save_history(path: &Path, history: Option<&[SaveFileData]>) -> anyhow::Result<()> {
+    let history_path = history_path(path);
+    match history {
+        Some(history) if !history.is_empty() => {
+            let history: Vec<_> = history
+                .iter()
+                .map(|state| state.with_links_relative_to(path))
+                .collect();
+            let write = BufWriter::new(File::create(history_path)?);
+            let mut compress = zstd::Encoder::new(write, 3)?;
+            // Like the binary save format, this goes through json so that it has the same
+            // structure as the json.
+            serde_cbor::to_writer(&mut compress, &serde_json::to_value(&history)?)?;
+            compress.finish()?;
+        }
+        _ => {
+            if history_path.exists() {
+                std::fs::remove_file(history_path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Loads the undo history (most recent first) for the project at `path`, whose contents are
+/// `current`. Returns an empty history if there isn't one, or if it's out of date.
+pub fn load_history(path: &Path, current: &SaveFileData) -> anyhow::Result<Vec<SaveFileData>> {
+    let history_path = history_path(path);
+    if !history_path.exists() {
+        return Ok(Vec::new());
+    }
+    // If the project was saved (by something that doesn't save the history) after the history
+    // was, the history doesn't lead up to it anymore.
+    if std::fs::metadata(&history_path)?.modified()? < std::fs::metadata(path)?.modified()? {
+        log::info!("ignoring the out-of-date undo history for {:?}", path);
+        return Ok(Vec::new());
+    }
+    let read = BufReader::new(File::open(history_path)?);
+    let value: serde_json::Value = serde_cbor::from_reader(zstd::Decoder::with_buffer(read)?)?;
+    // We don't migrate undo histories, so ones saved by other versions are just dropped. This gets
+    // checked before deserializing them, because their format might be different.
+    let states = value
+        .as_array()
+        .map(|states| states.as_slice())
+        .unwrap_or_default();
+    if states
+        .iter()
+        .any(|state| state.get("version").and_then(|v| v.as_u64()) != Some(SAVE_FILE_VERSION))
+    {
+        log::info!(
+            "ignoring the undo history for {:?} from another version",
+            path
+        );
+        return Ok(Vec::new());
+    }
+    let history: Vec<SaveFileData> = serde_json::from_value(value)?;
+    Ok(history
+        .into_iter()
+        .map(|mut state| {
+            state = state.with_links_resolved(path);
+            state.audio_snippets = state
+                .audio_snippets
+                .with_links_shared(&current.audio_snippets);
+            state.with_audio_loaded()
+        })
+        .collect())
+}
+
+// Reports the fraction of the file that has been read so far. For loading, this is a good
+// enough measure of progress because the file gets decompressed and parsed as it's read.
+struct ProgressReader<R, F> {
+    inner: R,
+    read: u64,
+    len: u64,
+    progress: F,
+}
+
+impl<R: Read, F: FnMut(f64)> Read for ProgressReader<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        if self.len > 0 {
+            (self.progress)(self.read as f64 / self.len as f64);
+        }
+        Ok(n)
+    }
+}
+
+/// Updates the app state when we hear back from the background thread.
+pub fn apply_status(data: &mut AppState, status: Status) {
+    match status {
+        Status::Progress(p) => {
+            if let Some(op) = data.file_op.as_mut() {
+                op.progress = p;
+            }
+        }
+        Status::Saved(path, save_data) => {
+            data.file_op = None;
+            // The user might have kept editing while we were saving, so we compare against what
+            // was actually saved.
+            data.saved = ScribbleState::from_save_file(*save_data);
+            // Once it's saved, the autosave (either for the old path or, if this is the first
+            // save, for the untitled project) is obsolete.
+            let old_path = data.save_path.replace(path.clone());
+            crate::autosave::remove(old_path.as_deref());
+            crate::autosave::remove(Some(&path));
+            remember_recent_file(data, &path);
+        }
+        Status::Loaded(path, save_data, history) => {
+            data.file_op = None;
+            let scribble = ScribbleState::from_save_file(*save_data);
+            let history = history
+                .into_iter()
+                .map(ScribbleState::from_save_file)
+                .collect();
+            crate::tabs::open(data, scribble, history, path.clone());
+            remember_recent_file(data, &path);
+            if crate::autosave::has_recovery_data(data.save_path.as_deref()) {
+                data.dialog = Some(Dialog::RestoreAutosave);
+            } else if data.scribble.audio_snippets.first_missing_link().is_some() {
+                data.dialog = Some(Dialog::RelinkAudio);
+            }
+        }
+        Status::Failed(e) => {
+            log::error!("{}", e);
+            data.file_op = None;
+            // Whatever was waiting for the save shouldn't happen now.
+            data.after_save = None;
+        }
+    }
+}
+
+fn remember_recent_file(data: &mut AppState, path: &Path) {
+    data.prefs = data.prefs.with_recent_file(path);
+    if let Err(e) = data.prefs.save() {
+        log::error!("error saving preferences: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_reader() {
+        let data = [0u8; 100];
+        let mut reported = Vec::new();
+        let mut reader = ProgressReader {
+            inner: &data[..],
+            read: 0,
+            len: data.len() as u64,
+            progress: |p| reported.push(p),
+        };
+        let mut buf = [0u8; 40];
+        while reader.read(&mut buf).unwrap() > 0 {}
+        assert_eq!(reported, vec![0.4, 0.8, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn backups() {
+        let dir = std::env::temp_dir().join(format!("scribble-backups-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("project.scb");
+        let contents = |n| std::fs::read_to_string(backup_path(&path, n)).ok();
+
+        for version in &["1", "2", "3", "4"] {
+            rotate_backups(&path, 2).unwrap();
+            std::fs::write(&path, version).unwrap();
+        }
+        assert_eq!(contents(1).as_deref(), Some("3"));
+        assert_eq!(contents(2).as_deref(), Some("2"));
+        assert_eq!(contents(3), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn history() {
+        let dir = std::env::temp_dir().join(format!("scribble-history-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("project.scb");
+        let state = || ScribbleState::default().to_save_file();
+        let current = state();
+        current.save_to_path(&path, SaveFormat::Json).unwrap();
+
+        save_history(&path, Some(&[state(), state()])).unwrap();
+        assert_eq!(load_history(&path, &current).unwrap().len(), 2);
+
+        // Saving without a history removes the old one.
+        save_history(&path, None).unwrap();
+        assert!(!history_path(&path).exists());
+        assert!(load_history(&path, &current).unwrap().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,489 @@
+//! A remote-control server, so that scripts, stream decks and test harnesses can drive scribble.
+//!
+//! When `Preferences::remote_control_port` isn't zero, we listen on that port (on localhost
+//! only). Each line that a client sends is a JSON request, and each request gets a single line of
+//! JSON in response. The requests look like
+//!
+//! - `{"request": "action", "action": "play"}` runs an action. The actions are the same ones that
+//!   MIDI controllers and foot pedals can be bound to (see `command_palette::command_for`).
+//! - `{"request": "export", "path": "/tmp/lecture.webm"}` exports the current project.
+//! - `{"request": "drop", "paths": ["/tmp/intro.ogg"]}` does the same as dropping those files onto
+//!   the window (see `cmd::DROP_FILES`).
+//! - `{"request": "state"}` asks what scribble is up to.
+//! - `{"request": "input", "input": {"event": "down", "x": 0.5, "y": 0.5}}` pretends that a pen
+//!   did something (see `crate::remote_pen` for the events).
+//! - `{"request": "frame", "time": 2.5}` renders the frame at that time (or at the cursor, if
+//!   there's no time) at the export size, and reports a hash of its pixels.
+//!
+//! The responses look like `{"ok": true}`, `{"ok": false, "error": "..."}`, or (for state
+//! and frame requests) `{"ok": true, "state": {...}}` and `{"ok": true, "frame": {...}}`.
+//!
+//! Tests can also talk to us over a Unix socket (see `RemoteControl::listen_on_socket`), which
+//! uses the same protocol. Together, input and frame requests let a test record a drawing, play
+//! it back, and check that the right pixels come out.
+
+use druid::{Command, ExtEventSink};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use scribble_curves::Time;
+
+use crate::cmd;
+use crate::data::{AppState, CurrentAction};
+use crate::encode::{ExportFormat, FrameRenderer};
+use crate::prefs::Preferences;
+use crate::remote_pen::PenEvent;
+
+/// How often the listening thread checks whether it should stop.
+const ACCEPT_POLL: Duration = Duration::from_millis(200);
+
+/// How long a client waits for the UI thread to answer a request.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "request", rename_all = "kebab-case")]
+pub enum Request {
+    Action { action: String },
+    Export { path: PathBuf },
+    Drop { paths: Vec<PathBuf> },
+    State,
+    Input { input: PenEvent },
+    Frame { time: Option<f64> },
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<StateReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frame: Option<FrameReport>,
+}
+
+impl Response {
+    fn ok() -> Response {
+        Response {
+            ok: true,
+            ..Response::default()
+        }
+    }
+
+    fn error(msg: impl Into<String>) -> Response {
+        Response {
+            ok: false,
+            error: Some(msg.into()),
+            ..Response::default()
+        }
+    }
+}
+
+/// What gets reported in response to a state request.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct StateReport {
+    /// One of "idle", "playing", "recording", "recording-audio" or "scanning".
+    pub action: &'static str,
+    /// The current time, in seconds.
+    pub time: f64,
+    /// The time of the mark, in seconds, if there is one.
+    pub mark: Option<f64>,
+    pub project: Option<PathBuf>,
+    pub unsaved_changes: bool,
+    /// The number of exports that haven't finished yet.
+    pub exports_running: usize,
+}
+
+/// What gets reported in response to a frame request.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FrameReport {
+    /// The time of the frame, in seconds.
+    pub time: f64,
+    pub width: u32,
+    pub height: u32,
+    /// The 64-bit FNV-1a hash of the frame's RGBA pixels, in hex. This only depends on the
+    /// pixels, so it's the same every time (on the same machine, at least: different versions
+    /// of cairo might antialias a little differently).
+    pub hash: String,
+}
+
+/// A request on its way from a client's thread to the UI thread. The UI thread answers on
+/// `reply`.
+pub struct RemoteRequest {
+    pub request: Request,
+    pub reply: Sender<Response>,
+}
+
+pub struct RemoteControl {
+    sink: ExtEventSink,
+    port: u16,
+    server: Option<(Arc<AtomicBool>, JoinHandle<()>)>,
+    socket: Option<PathBuf>,
+}
+
+impl RemoteControl {
+    /// Sets up the remote control. The server doesn't start until the first `update`.
+    pub fn new(sink: ExtEventSink) -> RemoteControl {
+        RemoteControl {
+            sink,
+            port: 0,
+            server: None,
+            socket: None,
+        }
+    }
+
+    /// Also listens on a Unix socket at `path`, until scribble exits. This is meant for tests:
+    /// unlike a port, a socket in a temporary directory can't clash with anything else.
+    #[cfg(unix)]
+    pub fn listen_on_socket(&mut self, path: PathBuf) -> anyhow::Result<()> {
+        use std::os::unix::fs::FileTypeExt;
+
+        // A socket that's left over from a crash would stop us from binding, but we don't want to
+        // delete anything else.
+        if let Ok(meta) = std::fs::symlink_metadata(&path) {
+            if meta.file_type().is_socket() {
+                std::fs::remove_file(&path)?;
+            }
+        }
+        let listener = UnixListener::bind(&path)?;
+        log::info!("listening for remote control on {:?}", path);
+        let sink = self.sink.clone();
+        std::thread::spawn(move || listen_on_socket(listener, sink));
+        self.socket = Some(path);
+        Ok(())
+    }
+
+    /// Starts (or stops, or moves) the server according to `prefs`.
+    pub fn update(&mut self, prefs: &Preferences) {
+        if prefs.remote_control_port == self.port {
+            return;
+        }
+        self.port = prefs.remote_control_port;
+        self.stop();
+
+        if self.port == 0 {
+            return;
+        }
+        let listener = match TcpListener::bind((Ipv4Addr::LOCALHOST, self.port)) {
+            Ok(listener) => listener,
+            Err(e) => {
+                log::error!(
+                    "failed to start remote control on port {}: {}",
+                    self.port,
+                    e
+                );
+                return;
+            }
+        };
+        log::info!("listening for remote control on port {}", self.port);
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+        let sink = self.sink.clone();
+        let handle = std::thread::spawn(move || listen(listener, sink, thread_stop));
+        self.server = Some((stop, handle));
+    }
+
+    fn stop(&mut self) {
+        if let Some((stop, handle)) = self.server.take() {
+            stop.store(true, Ordering::SeqCst);
+            if handle.join().is_err() {
+                log::error!("the remote control thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for RemoteControl {
+    fn drop(&mut self) {
+        self.stop();
+        if let Some(path) = self.socket.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+// The listener is non-blocking, so that we notice when we're asked to stop. Each client gets its
+// own thread; those ones just stop when the client disconnects.
+fn listen(listener: TcpListener, sink: ExtEventSink, stop: Arc<AtomicBool>) {
+    if let Err(e) = listener.set_nonblocking(true) {
+        log::error!("failed to set up the remote control: {}", e);
+        return;
+    }
+    while !stop.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, addr)) => {
+                log::info!("remote control client connected from {}", addr);
+                let sink = sink.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = serve(stream, sink) {
+                        log::warn!("remote control client error: {}", e);
+                    }
+                });
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                std::thread::sleep(ACCEPT_POLL);
+            }
+            Err(e) => {
+                log::error!("remote control error: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+// Unlike the TCP listener, this one never gets asked to stop, so it can just block.
+#[cfg(unix)]
+fn listen_on_socket(listener: UnixListener, sink: ExtEventSink) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                log::info!("remote control client connected to the socket");
+                let sink = sink.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = serve_socket(stream, sink) {
+                        log::warn!("remote control client error: {}", e);
+                    }
+                });
+            }
+            Err(e) => {
+                log::error!("remote control socket error: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+fn serve(stream: TcpStream, sink: ExtEventSink) -> anyhow::Result<()> {
+    stream.set_nonblocking(false)?;
+    let out = stream.try_clone()?;
+    answer(stream, out, &sink)
+}
+
+#[cfg(unix)]
+fn serve_socket(stream: UnixStream, sink: ExtEventSink) -> anyhow::Result<()> {
+    let out = stream.try_clone()?;
+    answer(stream, out, &sink)
+}
+
+// Answers each line of `input` with a line of `out`, until the client hangs up.
+fn answer(input: impl Read, mut out: impl Write, sink: &ExtEventSink) -> anyhow::Result<()> {
+    for line in BufReader::new(input).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => forward(sink, request),
+            Err(e) => Response::error(format!("bad request: {}", e)),
+        };
+        serde_json::to_writer(&mut out, &response)?;
+        out.write_all(b"\n")?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+// Sends a request to the UI thread, and waits for the answer.
+fn forward(sink: &ExtEventSink, request: Request) -> Response {
+    let (tx, rx) = channel();
+    let req = RemoteRequest { request, reply: tx };
+    if let Err(e) = sink.submit_command(cmd::REMOTE_REQUEST.selector(), Box::new(req), None) {
+        return Response::error(format!("scribble isn't listening: {}", e));
+    }
+    rx.recv_timeout(REPLY_TIMEOUT)
+        .unwrap_or_else(|_| Response::error("timed out waiting for scribble"))
+}
+
+/// Figures out how to answer a request: the command to run (if any), and the response.
+pub fn handle(request: &Request, data: &AppState) -> (Option<Command>, Response) {
+    match request {
+        Request::Action { action } => match crate::command_palette::command_for(action, data) {
+            Some(command) => (Some(command), Response::ok()),
+            None => (
+                None,
+                Response::error(format!("\"{}\" can't be done right now", action)),
+            ),
+        },
+        Request::Export { path } => {
+            let known = ExportFormat::from_path(path).is_some()
+                || crate::plugins::registry().exporter_for(path).is_some();
+            if known {
+                let export = data.export_cmd(path.clone());
+                (Some(cmd::EXPORT.with(export)), Response::ok())
+            } else {
+                (None, Response::error(format!("can't export to {:?}", path)))
+            }
+        }
+        Request::Drop { paths } => (Some(cmd::DROP_FILES.with(paths.clone())), Response::ok()),
+        Request::State => {
+            let state = StateReport {
+                action: action_name(&data.action),
+                time: data.time().as_micros() as f64 / 1e6,
+                mark: data.scribble.mark.map(|t| t.as_micros() as f64 / 1e6),
+                project: data.save_path.clone(),
+                unsaved_changes: data.is_dirty(),
+                exports_running: data
+                    .export_jobs
+                    .jobs
+                    .iter()
+                    .filter(|j| !j.is_done())
+                    .count(),
+            };
+            let response = Response {
+                state: Some(state),
+                ..Response::ok()
+            };
+            (None, response)
+        }
+        Request::Input { input } => (Some(cmd::REMOTE_PEN.with(input.clone())), Response::ok()),
+        Request::Frame { time } => {
+            let time = match time {
+                Some(secs) => Time::from_micros((secs * 1e6).round() as i64),
+                None => data.time(),
+            };
+            match render_frame(data, time) {
+                Ok(frame) => {
+                    let response = Response {
+                        frame: Some(frame),
+                        ..Response::ok()
+                    };
+                    (None, response)
+                }
+                Err(e) => (None, Response::error(format!("couldn't render: {}", e))),
+            }
+        }
+    }
+}
+
+fn render_frame(data: &AppState, time: Time) -> anyhow::Result<FrameReport> {
+    let (width, height) = data.export.video_size(data.scribble.settings.aspect_ratio);
+    let pixels = FrameRenderer::new(
+        data.scribble.snippets.clone(),
+        data.scribble.images.clone(),
+        &data.scribble.settings,
+        data.show_pen,
+        width,
+        height,
+    )?
+    .render(time)?;
+    Ok(FrameReport {
+        time: time.as_micros() as f64 / 1e6,
+        width,
+        height,
+        hash: format!("{:016x}", fnv1a(&pixels)),
+    })
+}
+
+// A simple hash that (unlike the ones in std) is guaranteed not to change, so that tests can
+// compare against hashes that they saved earlier.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+fn action_name(action: &CurrentAction) -> &'static str {
+    match action {
+        CurrentAction::Idle => "idle",
+        CurrentAction::Playing => "playing",
+        CurrentAction::WaitingToRecord(_) | CurrentAction::Recording(_) => "recording",
+        CurrentAction::RecordingAudio(_) => "recording-audio",
+        CurrentAction::Scanning(_) => "scanning",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_requests() {
+        let parse = |s: &str| serde_json::from_str::<Request>(s).unwrap();
+        assert_eq!(
+            parse(r#"{"request": "action", "action": "play"}"#),
+            Request::Action {
+                action: "play".to_owned()
+            }
+        );
+        assert_eq!(
+            parse(r#"{"request": "export", "path": "a.webm"}"#),
+            Request::Export {
+                path: "a.webm".into()
+            }
+        );
+        assert_eq!(
+            parse(r#"{"request": "drop", "paths": ["a.ogg", "b.png"]}"#),
+            Request::Drop {
+                paths: vec!["a.ogg".into(), "b.png".into()]
+            }
+        );
+        assert_eq!(parse(r#"{"request": "state"}"#), Request::State);
+        assert_eq!(
+            parse(r#"{"request": "input", "input": {"event": "move", "x": 0.5, "y": 0.25}}"#),
+            Request::Input {
+                input: PenEvent::Move { x: 0.5, y: 0.25 }
+            }
+        );
+        assert_eq!(
+            parse(r#"{"request": "frame", "time": 2.5}"#),
+            Request::Frame { time: Some(2.5) }
+        );
+        assert_eq!(
+            parse(r#"{"request": "frame"}"#),
+            Request::Frame { time: None }
+        );
+        assert!(serde_json::from_str::<Request>(r#"{"request": "dance"}"#).is_err());
+    }
+
+    #[test]
+    fn responses() {
+        let data = AppState::with_prefs(Preferences::default());
+        let (command, response) = handle(
+            &Request::Action {
+                action: "play".to_owned(),
+            },
+            &data,
+        );
+        assert!(command.is_some());
+        assert_eq!(serde_json::to_string(&response).unwrap(), r#"{"ok":true}"#);
+
+        let (command, response) = handle(
+            &Request::Export {
+                path: "a.doc".into(),
+            },
+            &data,
+        );
+        assert!(command.is_none());
+        assert!(!response.ok);
+
+        let (_, response) = handle(&Request::State, &data);
+        let state = response.state.unwrap();
+        assert_eq!(state.action, "idle");
+        assert_eq!(state.exports_running, 0);
+
+        let (command, response) = handle(
+            &Request::Input {
+                input: PenEvent::Up,
+            },
+            &data,
+        );
+        assert!(command.is_some());
+        assert!(response.ok);
+    }
+
+    #[test]
+    fn frame_hash() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+}
@@ -0,0 +1,230 @@
+//! Drawing with a pen on another device, so that (for example) a tablet on the couch can draw
+//! while the computer with the microphone records.
+//!
+//! When `Preferences::pen_server_port` isn't zero, we listen on that port (on every network
+//! interface, since the tablet is somewhere else). A thin client on the tablet starts by sending
+//! `{"password": "..."}` with `Preferences::pen_server_password` (and we hang up if it's wrong).
+//! Then it sends one line of JSON for each pen event:
+//!
+//! - `{"event": "down", "x": 0.25, "y": 0.5}` when the pen touches the tablet,
+//! - `{"event": "move", "x": 0.26, "y": 0.5}` when it moves (touching or not),
+//! - `{"event": "up"}` when it lifts off, and
+//! - `{"event": "action", "action": "toggle-draw"}` to run an action, like starting or stopping
+//!   a recording. Only the actions in [`PEN_ACTIONS`] (and choosing a color, like "color-2") are
+//!   allowed, so that whoever holds the tablet can't save over or export the project.
+//!
+//! The coordinates are fractions of the width and the height of the canvas, with (0, 0) at the
+//! top left. (So the drawing area on the tablet should have the same shape as the canvas, or the
+//! drawings get stretched.) The events go through the same recording pipeline as the mouse, and
+//! they get their times when they arrive; on a decent network the delay is a few milliseconds.
+//! Nothing gets sent back.
+
+use druid::ExtEventSink;
+use serde::Deserialize;
+use std::io::{BufRead, BufReader};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::cmd;
+use crate::collab::authenticate;
+use crate::prefs::Preferences;
+
+/// How often the listening thread checks whether it should stop.
+const ACCEPT_POLL: Duration = Duration::from_millis(200);
+
+/// The actions that a remote pen may run: the ones that a drawing client needs.
+pub const PEN_ACTIONS: &[&str] = &[
+    "toggle-draw",
+    "toggle-talk",
+    "play-pause",
+    "stop",
+    "undo",
+    "redo",
+    "next-color",
+];
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum PenEvent {
+    Down { x: f64, y: f64 },
+    Move { x: f64, y: f64 },
+    Up,
+    Action { action: String },
+}
+
+pub struct PenServer {
+    sink: ExtEventSink,
+    port: u16,
+    password: String,
+    server: Option<(Arc<AtomicBool>, JoinHandle<()>)>,
+}
+
+impl PenServer {
+    /// Sets up the pen server. It doesn't start listening until the first `update`.
+    pub fn new(sink: ExtEventSink) -> PenServer {
+        PenServer {
+            sink,
+            port: 0,
+            password: String::new(),
+            server: None,
+        }
+    }
+
+    /// Starts (or stops, or moves) the server according to `prefs`.
+    pub fn update(&mut self, prefs: &Preferences) {
+        if prefs.pen_server_port == self.port && prefs.pen_server_password == self.password {
+            return;
+        }
+        self.port = prefs.pen_server_port;
+        self.password = prefs.pen_server_password.clone();
+        self.stop();
+
+        if self.port == 0 {
+            return;
+        }
+        if self.password.is_empty() {
+            log::error!("not listening for remote pens, because there's no password for them");
+            return;
+        }
+        let listener = match TcpListener::bind((Ipv4Addr::UNSPECIFIED, self.port)) {
+            Ok(listener) => listener,
+            Err(e) => {
+                log::error!(
+                    "failed to listen for remote pens on port {}: {}",
+                    self.port,
+                    e
+                );
+                return;
+            }
+        };
+        log::info!("listening for remote pens on port {}", self.port);
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+        let sink = self.sink.clone();
+        let password = Arc::new(self.password.clone());
+        let handle = std::thread::spawn(move || listen(listener, password, sink, thread_stop));
+        self.server = Some((stop, handle));
+    }
+
+    fn stop(&mut self) {
+        if let Some((stop, handle)) = self.server.take() {
+            stop.store(true, Ordering::SeqCst);
+            if handle.join().is_err() {
+                log::error!("the remote pen thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for PenServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+// The listener is non-blocking, so that we notice when we're asked to stop. Each pen gets its own
+// thread, which stops when the pen disconnects.
+fn listen(listener: TcpListener, password: Arc<String>, sink: ExtEventSink, stop: Arc<AtomicBool>) {
+    if let Err(e) = listener.set_nonblocking(true) {
+        log::error!("failed to set up the pen server: {}", e);
+        return;
+    }
+    while !stop.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, addr)) => {
+                let sink = sink.clone();
+                let password = Arc::clone(&password);
+                std::thread::spawn(move || {
+                    if let Err(e) = authenticate(&stream, &password) {
+                        log::warn!("turned away a remote pen from {}: {}", addr, e);
+                        return;
+                    }
+                    log::info!("remote pen connected from {}", addr);
+                    if let Err(e) = serve(stream, sink) {
+                        log::warn!("remote pen error: {}", e);
+                    }
+                });
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                std::thread::sleep(ACCEPT_POLL);
+            }
+            Err(e) => {
+                log::error!("pen server error: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Is a remote pen allowed to run `action`?
+pub fn is_pen_action(action: &str) -> bool {
+    PEN_ACTIONS.contains(&action) || action.starts_with("color-")
+}
+
+fn serve(stream: TcpStream, sink: ExtEventSink) -> anyhow::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut pen_down = false;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<PenEvent>(&line) {
+            Ok(event) => {
+                match &event {
+                    PenEvent::Down { .. } => pen_down = true,
+                    PenEvent::Up => pen_down = false,
+                    PenEvent::Action { action } if !is_pen_action(action) => {
+                        log::warn!("a remote pen isn't allowed to {:?}", action);
+                        continue;
+                    }
+                    _ => {}
+                }
+                sink.submit_command(cmd::REMOTE_PEN.selector(), Box::new(event), None)?;
+            }
+            Err(e) => log::warn!("bad pen event {:?}: {}", line, e),
+        }
+    }
+    // If the connection drops in the middle of a stroke, finish the stroke.
+    if pen_down {
+        sink.submit_command(cmd::REMOTE_PEN.selector(), Box::new(PenEvent::Up), None)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_events() {
+        let parse = |s: &str| serde_json::from_str::<PenEvent>(s).unwrap();
+        assert_eq!(
+            parse(r#"{"event": "down", "x": 0.25, "y": 0.5}"#),
+            PenEvent::Down { x: 0.25, y: 0.5 }
+        );
+        assert_eq!(
+            parse(r#"{"event": "move", "x": 1, "y": 0}"#),
+            PenEvent::Move { x: 1.0, y: 0.0 }
+        );
+        assert_eq!(parse(r#"{"event": "up"}"#), PenEvent::Up);
+        assert_eq!(
+            parse(r#"{"event": "action", "action": "toggle-draw"}"#),
+            PenEvent::Action {
+                action: "toggle-draw".to_owned()
+            }
+        );
+        assert!(serde_json::from_str::<PenEvent>(r#"{"event": "down"}"#).is_err());
+    }
+
+    #[test]
+    fn pen_actions() {
+        assert!(is_pen_action("toggle-draw"));
+        assert!(is_pen_action("color-3"));
+        assert!(!is_pen_action("save"));
+        assert!(!is_pen_action("export"));
+    }
+}
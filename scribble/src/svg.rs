@@ -0,0 +1,389 @@
+//! Exporting to animated SVG (using SMIL animations). Since everything we draw is made of Bezier
+//! curves, this lets animations be embedded on the web at any resolution.
+//!
+//! Each stroke is drawn using the usual trick of animating its dash offset. This is exact as far
+//! as the timing of the path's control points goes, but in between control points the pen moves
+//! at constant speed. Fade effects are supported (except for reversed snippets), but the pen
+//! position isn't shown.
+
+use druid::kurbo::{CubicBez, Line, ParamCurve, ParamCurveArclen, PathEl, PathSeg, Point, QuadBez};
+use druid::Color;
+use std::fmt::Write;
+
+use scribble_curves::curve::Segment;
+use scribble_curves::{Lerp, SnippetData, SnippetsData, Time};
+
+use crate::data::ProjectSettings;
+use crate::images::{ImageSnippetData, ImageSnippetsData};
+
+// The accuracy (in image coordinates) that we use for computing arc lengths.
+const ARCLEN_ACCURACY: f64 = 1e-5;
+
+fn secs(t: Time) -> f64 {
+    t.as_micros() as f64 / 1e6
+}
+
+// Writes (for example) `stroke="#ff0000" stroke-opacity="1.000"`.
+fn color_attrs(attr: &str, c: &Color) -> String {
+    let rgba = c.as_rgba_u32();
+    format!(
+        "{}=\"#{:06x}\" {}-opacity=\"{:.3}\"",
+        attr,
+        rgba >> 8,
+        attr,
+        (rgba & 0xff) as f64 / 255.0
+    )
+}
+
+fn path_data(elements: &[PathEl]) -> String {
+    let mut ret = String::new();
+    for el in elements {
+        // Writing to a string can't fail.
+        let _ = match el {
+            PathEl::MoveTo(p) => write!(ret, "M{:.5} {:.5}", p.x, p.y),
+            PathEl::LineTo(p) => write!(ret, "L{:.5} {:.5}", p.x, p.y),
+            PathEl::QuadTo(p1, p2) => {
+                write!(ret, "Q{:.5} {:.5} {:.5} {:.5}", p1.x, p1.y, p2.x, p2.y)
+            }
+            PathEl::CurveTo(p1, p2, p3) => write!(
+                ret,
+                "C{:.5} {:.5} {:.5} {:.5} {:.5} {:.5}",
+                p1.x, p1.y, p2.x, p2.y, p3.x, p3.y
+            ),
+            PathEl::ClosePath => write!(ret, "Z"),
+        };
+    }
+    ret
+}
+
+// Returns the arc length of the path up to (and including) each of its elements.
+fn cumulative_lengths(elements: &[PathEl]) -> Vec<f64> {
+    let mut ret = Vec::with_capacity(elements.len());
+    let mut start = Point::ZERO;
+    let mut cur = Point::ZERO;
+    let mut len = 0.0;
+    for el in elements {
+        let seg = match *el {
+            PathEl::MoveTo(p) => {
+                start = p;
+                cur = p;
+                None
+            }
+            PathEl::LineTo(p) => Some(PathSeg::Line(Line::new(cur, p))),
+            PathEl::QuadTo(p1, p2) => Some(PathSeg::Quad(QuadBez::new(cur, p1, p2))),
+            PathEl::CurveTo(p1, p2, p3) => Some(PathSeg::Cubic(CubicBez::new(cur, p1, p2, p3))),
+            PathEl::ClosePath => Some(PathSeg::Line(Line::new(cur, start))),
+        };
+        if let Some(seg) = seg {
+            len += seg.arclen(ARCLEN_ACCURACY);
+            cur = seg.end();
+        }
+        ret.push(len);
+    }
+    ret
+}
+
+// Converts a time in a snippet's local coordinates to global time. Like `Lerp::unlerp_extended`
+// (but in the other direction), times outside the snippet are extended without scaling.
+fn global_time(lerp: &Lerp, t: Time) -> Time {
+    if t > lerp.original_last() {
+        lerp.last() + (t - lerp.original_last())
+    } else if t < lerp.original_first() {
+        lerp.first() - (lerp.original_first() - t)
+    } else {
+        lerp.lerp_clamped(t)
+    }
+}
+
+// Writes an `<animate>` element that runs over the whole animation (from time zero until
+// `duration`), passing through the given keyframes. Before the first keyframe and after the last
+// one, the value stays constant.
+fn write_animate(out: &mut String, attr: &str, keyframes: &[(Time, f64)], duration: Time) {
+    let mut keyframes = keyframes.to_owned();
+    keyframes.sort_by_key(|&(t, _)| t);
+    let (first, last) = match (keyframes.first(), keyframes.last()) {
+        (Some(&first), Some(&last)) => (first, last),
+        _ => return,
+    };
+    keyframes.insert(0, (Time::from_micros(0), first.1));
+    keyframes.push((duration, last.1));
+
+    let total = secs(duration).max(1e-6);
+    let key_times: Vec<String> = keyframes
+        .iter()
+        .map(|&(t, _)| format!("{:.5}", (secs(t) / total).max(0.0).min(1.0)))
+        .collect();
+    let values: Vec<String> = keyframes
+        .iter()
+        .map(|&(_, v)| format!("{:.5}", v))
+        .collect();
+    let _ = writeln!(
+        out,
+        "<animate attributeName=\"{}\" begin=\"0s\" dur=\"{:.3}s\" fill=\"freeze\" keyTimes=\"{}\" values=\"{}\"/>",
+        attr,
+        total,
+        key_times.join(";"),
+        values.join(";"),
+    );
+}
+
+// Writes the elements that make something visible at `start` and (optionally) invisible again
+// at `end`. The thing should start out with `visibility="hidden"`.
+fn write_visibility(out: &mut String, start: Time, end: Option<Time>) {
+    let _ = writeln!(
+        out,
+        "<set attributeName=\"visibility\" to=\"visible\" begin=\"{:.3}s\"/>",
+        secs(start)
+    );
+    if let Some(end) = end {
+        let _ = writeln!(
+            out,
+            "<set attributeName=\"visibility\" to=\"hidden\" begin=\"{:.3}s\"/>",
+            secs(end)
+        );
+    }
+}
+
+fn write_segment(out: &mut String, snip: &SnippetData, seg: &Segment, duration: Time) {
+    let lengths = cumulative_lengths(seg.elements);
+    let total = lengths.last().cloned().unwrap_or(0.0);
+    let lerp = &snip.lerp;
+
+    // The dash pattern has one dash the length of the whole path, and then a longer gap. By
+    // changing the offset from 1 to 0, we draw the path from start to end.
+    let _ = writeln!(
+        out,
+        "<path d=\"{}\" fill=\"none\" {} stroke-width=\"{:.5}\" stroke-linecap=\"round\" stroke-linejoin=\"round\" pathLength=\"1\" stroke-dasharray=\"1 2\" stroke-dashoffset=\"1\">",
+        path_data(seg.elements),
+        color_attrs("stroke", &seg.style.color),
+        seg.style.thickness,
+    );
+
+    let keyframes: Vec<(Time, f64)> = seg
+        .times
+        .iter()
+        .zip(&lengths)
+        .map(|(&t, &len)| {
+            // When the snippet is reversed, the drawing time runs backwards.
+            let local = if snip.reversed {
+                lerp.original_first() + (lerp.original_last() - t)
+            } else {
+                t
+            };
+            let drawn = if total > 0.0 { len / total } else { 1.0 };
+            (global_time(lerp, local), 1.0 - drawn)
+        })
+        .collect();
+    write_animate(out, "stroke-dashoffset", &keyframes, duration);
+
+    // We don't try to fade reversed snippets, because they get un-drawn anyway.
+    if let (Some(fade), Some(&last)) = (seg.effects.fade(), seg.times.last()) {
+        if !snip.reversed {
+            let fade_start = global_time(lerp, last + fade.pause);
+            let fade_end = global_time(lerp, last + fade.pause + fade.fade);
+            write_animate(
+                out,
+                "opacity",
+                &[(fade_start, 1.0), (fade_end, 0.0)],
+                duration,
+            );
+        }
+    }
+    let _ = writeln!(out, "</path>");
+}
+
+fn write_image(out: &mut String, image: &ImageSnippetData, duration: Time) {
+    let mime = match image::guess_format(image.encoded()) {
+        Ok(image::ImageFormat::Png) => "image/png",
+        Ok(image::ImageFormat::Jpeg) => "image/jpeg",
+        Ok(image::ImageFormat::Gif) => "image/gif",
+        Ok(image::ImageFormat::Bmp) => "image/bmp",
+        _ => "application/octet-stream",
+    };
+    let rect = image.rect;
+    let _ = writeln!(
+        out,
+        "<image x=\"{:.5}\" y=\"{:.5}\" width=\"{:.5}\" height=\"{:.5}\" preserveAspectRatio=\"none\" visibility=\"hidden\" xlink:href=\"data:{};base64,{}\">",
+        rect.x0,
+        rect.y0,
+        rect.width(),
+        rect.height(),
+        mime,
+        base64::encode(image.encoded()),
+    );
+    write_visibility(out, image.start_time, image.end_time);
+    if image.fade_in.as_micros() > 0 {
+        write_animate(
+            out,
+            "opacity",
+            &[
+                (image.start_time, 0.0),
+                (image.start_time + image.fade_in, 1.0),
+            ],
+            duration,
+        );
+    }
+    let _ = writeln!(out, "</image>");
+}
+
+/// Escapes the characters that are special in XML (and HTML).
+pub fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Formats a time (in seconds since the Unix epoch) as an ISO 8601 date, like "2020-05-17".
+fn iso_date(secs: u64) -> String {
+    // This is Howard Hinnant's `civil_from_days` algorithm.
+    let z = (secs / 86400) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+// Writes the project's title, author and creation date, along with the version of scribble that
+// made the file.
+fn write_metadata(out: &mut String, settings: &ProjectSettings) {
+    if !settings.title.is_empty() {
+        let _ = writeln!(out, "<title>{}</title>", escape(&settings.title));
+    }
+    let mut desc = Vec::new();
+    if !settings.author.is_empty() {
+        desc.push(format!("Author: {}", escape(&settings.author)));
+    }
+    if let Some(created) = settings.created {
+        desc.push(format!("Created: {}", iso_date(created)));
+    }
+    desc.push(format!("Exported by {}", crate::encode::SCRIBBLE_VERSION));
+    let _ = writeln!(out, "<desc>{}</desc>", desc.join(". "));
+}
+
+/// Renders the animation as an SVG document.
+///
+/// `width` and `height` are the nominal size (in pixels) of the SVG, and `duration` is the time
+/// at which the animation ends.
+pub fn to_svg(
+    snippets: &SnippetsData,
+    images: &ImageSnippetsData,
+    settings: &ProjectSettings,
+    width: u32,
+    height: u32,
+    duration: Time,
+) -> String {
+    let drawing_height = settings.drawing_height();
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" xmlns:xlink=\"http://www.w3.org/1999/xlink\" width=\"{}\" height=\"{}\" viewBox=\"0 0 1 {:.5}\">",
+        width, height, drawing_height
+    );
+    write_metadata(&mut out, settings);
+    let _ = writeln!(
+        out,
+        "<rect x=\"0\" y=\"0\" width=\"1\" height=\"{:.5}\" {}/>",
+        drawing_height,
+        color_attrs("fill", &settings.background)
+    );
+
+    for image in images.snippets() {
+        write_image(&mut out, image, duration);
+    }
+    for (_, snip) in snippets.snippets() {
+        let _ = writeln!(out, "<g visibility=\"hidden\">");
+        write_visibility(&mut out, snip.start_time(), snip.end_time());
+        for seg in snip.curve.segments() {
+            write_segment(&mut out, snip, &seg, duration);
+        }
+        let _ = writeln!(out, "</g>");
+    }
+    let _ = writeln!(out, "</svg>");
+    out
+}
+
+/// Writes the animation in `cmd` to an SVG file.
+pub fn write_svg(cmd: &crate::cmd::ExportCmd, duration: Time) -> anyhow::Result<()> {
+    let (width, height) = cmd.export.video_size(cmd.scribble.settings.aspect_ratio);
+    let svg = to_svg(
+        &cmd.scribble.snippets,
+        &cmd.scribble.images,
+        &cmd.scribble.settings,
+        width,
+        height,
+        duration,
+    );
+    std::fs::write(&cmd.filename, svg)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scribble_curves::{Curve, Effects, LineStyle};
+
+    #[test]
+    fn lengths() {
+        let elements = [
+            PathEl::MoveTo(Point::new(0.0, 0.0)),
+            PathEl::LineTo(Point::new(3.0, 4.0)),
+            PathEl::LineTo(Point::new(3.0, 5.0)),
+        ];
+        assert_eq!(cumulative_lengths(&elements), vec![0.0, 5.0, 6.0]);
+    }
+
+    #[test]
+    fn metadata() {
+        assert_eq!(iso_date(0), "1970-01-01");
+        assert_eq!(iso_date(951_825_600), "2000-02-29");
+
+        let settings = ProjectSettings {
+            title: "Fish & <chips>".to_owned(),
+            author: "Me".to_owned(),
+            created: Some(1_589_673_600),
+            ..ProjectSettings::default()
+        };
+        let mut out = String::new();
+        write_metadata(&mut out, &settings);
+        assert!(out.contains("<title>Fish &amp; &lt;chips&gt;</title>"));
+        assert!(out.contains("<desc>Author: Me. Created: 2020-05-17. Exported by scribble "));
+    }
+
+    #[test]
+    fn stroke() {
+        let style = LineStyle {
+            color: Color::rgb8(255, 0, 0),
+            thickness: 0.01,
+        };
+        let mut curve = Curve::new();
+        curve.move_to(
+            Point::new(0.0, 0.0),
+            Time::from_micros(0),
+            style,
+            Effects::default(),
+        );
+        curve.line_to(Point::new(0.5, 0.0), Time::from_micros(1_000_000));
+        curve.line_to(Point::new(1.0, 0.0), Time::from_micros(3_000_000));
+        let (snippets, _) = SnippetsData::default().with_new_snippet(SnippetData::new(curve));
+
+        let svg = to_svg(
+            &snippets,
+            &ImageSnippetsData::default(),
+            &ProjectSettings::default(),
+            800,
+            600,
+            Time::from_micros(4_000_000),
+        );
+        assert!(svg.contains("d=\"M0.00000 0.00000L0.50000 0.00000L1.00000 0.00000\""));
+        assert!(svg.contains("stroke=\"#ff0000\""));
+        assert!(svg.contains(
+            "keyTimes=\"0.00000;0.00000;0.25000;0.75000;1.00000\" values=\"1.00000;1.00000;0.50000;0.00000;0.00000\""
+        ));
+    }
+}
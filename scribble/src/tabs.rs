@@ -0,0 +1,252 @@
+//! Having several projects open at once, each in its own tab. The project in the current tab
+//! lives in the usual `AppState` fields, and the others get stashed in `AppState::tabs` until
+//! they are switched to. Everything that isn't part of a project (the audio device, the
+//! preferences, the snippet clipboard, and so on) is shared between the tabs.
+
+use druid::Data;
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use scribble_core::undo::UndoStack;
+use scribble_curves::{time, Time};
+
+use crate::data::{AppState, ScribbleState};
+
+/// A project that is open, but not in the current tab.
+#[derive(Clone, Data)]
+pub struct Tab {
+    scribble: ScribbleState,
+    saved: ScribbleState,
+    undo: Arc<RefCell<UndoStack>>,
+    time: Time,
+    #[data(ignore)]
+    save_path: Option<PathBuf>,
+}
+
+impl Tab {
+    fn blank() -> Tab {
+        let scribble = ScribbleState::default();
+        Tab {
+            undo: Arc::new(RefCell::new(UndoStack::new(scribble.clone()))),
+            saved: scribble.clone(),
+            scribble,
+            time: time::ZERO,
+            save_path: None,
+        }
+    }
+
+    // Takes the current project out of `data`, leaving `replacement` in its place.
+    fn swap(data: &mut AppState, replacement: Tab) -> Tab {
+        let ret = Tab {
+            scribble: std::mem::replace(&mut data.scribble, replacement.scribble),
+            saved: std::mem::replace(&mut data.saved, replacement.saved),
+            undo: std::mem::replace(&mut data.undo, replacement.undo),
+            time: data.time(),
+            save_path: std::mem::replace(&mut data.save_path, replacement.save_path),
+        };
+        data.warp_time_to(replacement.time);
+        data.restore_project_defaults();
+        // Dialogs (and anything they were holding up) belong to the project they were opened
+        // for.
+        data.dialog = None;
+        data.unsaved_action = None;
+        ret
+    }
+
+    /// The name to show on this tab: the name of the project, with a "*" if it has unsaved
+    /// changes.
+    pub fn name(&self) -> String {
+        project_name(
+            self.save_path.as_ref(),
+            is_dirty(&self.scribble, &self.saved),
+        )
+    }
+
+    pub fn save_path(&self) -> Option<&PathBuf> {
+        self.save_path.as_ref()
+    }
+}
+
+pub(crate) fn project_name(save_path: Option<&PathBuf>, dirty: bool) -> String {
+    let name = save_path
+        .and_then(|p| p.file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "Untitled".to_owned());
+    if dirty {
+        format!("{}*", name)
+    } else {
+        name
+    }
+}
+
+/// Returns true if `scribble` has changed compared to `saved`. Changes to things that don't get
+/// saved (like the selection) don't count.
+pub(crate) fn is_dirty(scribble: &ScribbleState, saved: &ScribbleState) -> bool {
+    !(scribble.snippets.same(&saved.snippets)
+        && scribble.audio_snippets.same(&saved.audio_snippets)
+        && scribble.images.same(&saved.images)
+        && scribble.captions.same(&saved.captions)
+        && scribble.settings.same(&saved.settings))
+}
+
+/// The names of all the open tabs, in order (including the current one).
+pub fn tab_names(data: &AppState) -> Vec<String> {
+    let mut ret: Vec<String> = data.tabs.iter().map(Tab::name).collect();
+    ret.insert(
+        data.current_tab,
+        project_name(data.save_path.as_ref(), data.is_dirty()),
+    );
+    ret
+}
+
+/// The number of open tabs (including the current one).
+pub fn tab_count(data: &AppState) -> usize {
+    data.tabs.len() + 1
+}
+
+/// Returns true if it's ok to switch tabs right now. We don't allow it while something is being
+/// recorded or played, or while a project is being saved or loaded (because then the result
+/// would end up in the wrong tab).
+pub fn can_switch(data: &AppState) -> bool {
+    data.action.is_idle() && data.file_op.is_none()
+}
+
+/// Switches to the tab at index `idx`.
+pub fn switch_to(data: &mut AppState, idx: usize) {
+    if idx == data.current_tab || idx >= tab_count(data) {
+        return;
+    }
+    let mut tabs = data.tabs.as_ref().clone();
+    let tab = tabs.remove(if idx < data.current_tab { idx } else { idx - 1 });
+    let old = Tab::swap(data, tab);
+    tabs.insert(
+        if idx < data.current_tab {
+            data.current_tab - 1
+        } else {
+            data.current_tab
+        },
+        old,
+    );
+    data.tabs = Arc::new(tabs);
+    data.current_tab = idx;
+}
+
+/// Opens a new tab with an empty project, just after the current one.
+pub fn new_tab(data: &mut AppState) {
+    let old = Tab::swap(data, Tab::blank());
+    let mut tabs = data.tabs.as_ref().clone();
+    tabs.insert(data.current_tab, old);
+    data.tabs = Arc::new(tabs);
+    data.current_tab += 1;
+}
+
+/// Closes the current tab, throwing away any unsaved changes. If it was the last tab, it gets
+/// replaced by an empty project.
+pub fn close_tab(data: &mut AppState) {
+    if data.tabs.is_empty() {
+        Tab::swap(data, Tab::blank());
+        return;
+    }
+    let mut tabs = data.tabs.as_ref().clone();
+    // Switch to the tab on the right, if there is one, and otherwise to the one on the left.
+    let idx = data.current_tab.min(tabs.len() - 1);
+    Tab::swap(data, tabs.remove(idx));
+    data.tabs = Arc::new(tabs);
+    data.current_tab = idx;
+}
+
+/// Puts a project that was just loaded (along with its saved undo history, most recent first)
+/// into a tab. If the current tab is an untouched empty project it gets replaced; otherwise, the
+/// project goes in a new tab.
+pub fn open(
+    data: &mut AppState,
+    scribble: ScribbleState,
+    history: Vec<ScribbleState>,
+    save_path: PathBuf,
+) {
+    let blank = data.save_path.is_none()
+        && !data.is_dirty()
+        && data.scribble.snippets.snippets().next().is_none()
+        && data.scribble.audio_snippets.snippets().next().is_none();
+    if !blank {
+        new_tab(data);
+    }
+    let tab = Tab {
+        undo: Arc::new(RefCell::new(UndoStack::with_history(
+            scribble.clone(),
+            history,
+        ))),
+        saved: scribble.clone(),
+        scribble,
+        time: time::ZERO,
+        save_path: Some(save_path),
+    };
+    Tab::swap(data, tab);
+}
+
+/// The index of some tab (other than the current one) that has unsaved changes.
+pub fn dirty_tab(data: &AppState) -> Option<usize> {
+    data.tabs
+        .iter()
+        .position(|tab| is_dirty(&tab.scribble, &tab.saved))
+        .map(|i| if i < data.current_tab { i } else { i + 1 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::{AudioSnippetData, AudioSnippetsData};
+    use crate::prefs::Preferences;
+
+    fn with_audio(data: &mut AppState, buf: Vec<i16>) {
+        let snip = AudioSnippetData::new(buf, time::ZERO);
+        data.scribble.audio_snippets = AudioSnippetsData::default().with_new_snippet(snip).0;
+    }
+
+    #[test]
+    fn switching() {
+        let mut data = AppState::with_prefs(Preferences::default());
+        with_audio(&mut data, vec![1]);
+        new_tab(&mut data);
+        with_audio(&mut data, vec![2]);
+        new_tab(&mut data);
+        assert_eq!(data.current_tab, 2);
+        assert_eq!(tab_names(&data), vec!["Untitled*", "Untitled*", "Untitled"]);
+        assert_eq!(dirty_tab(&data), Some(0));
+
+        let first_sample = |data: &AppState| {
+            data.scribble
+                .audio_snippets
+                .snippets()
+                .next()
+                .map(|(_, s)| s.buf()[0])
+        };
+        switch_to(&mut data, 0);
+        assert_eq!(first_sample(&data), Some(1));
+        switch_to(&mut data, 1);
+        assert_eq!(first_sample(&data), Some(2));
+
+        // Closing the middle tab switches to the one on its right.
+        close_tab(&mut data);
+        assert_eq!(data.current_tab, 1);
+        assert_eq!(first_sample(&data), None);
+        assert_eq!(tab_count(&data), 2);
+
+        // Opening a project replaces the empty tab, but not the other one.
+        open(
+            &mut data,
+            ScribbleState::default(),
+            Vec::new(),
+            "a.scb".into(),
+        );
+        assert_eq!(tab_count(&data), 2);
+        open(
+            &mut data,
+            ScribbleState::default(),
+            Vec::new(),
+            "b.scb".into(),
+        );
+        assert_eq!(tab_names(&data), vec!["Untitled*", "a.scb", "b.scb"]);
+    }
+}
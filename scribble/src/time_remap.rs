@@ -0,0 +1,299 @@
+//! Exporting a condensed version of an animation, by speeding it up (either everywhere, or just in
+//! the parts where nothing is being said).
+
+use phase_vocoder::PhaseVocoder;
+
+use scribble_curves::time::TimeSpan;
+use scribble_curves::{time, Diff, Time};
+
+use crate::audio::{AudioSnippetData, AudioSnippetsData, Cursor, SAMPLE_RATE};
+use crate::encode::ExportSettings;
+
+// When looking for silence, we look at the audio in windows of this many samples (10ms).
+const SILENCE_WINDOW: usize = SAMPLE_RATE as usize / 100;
+
+// A window is silent if none of its samples are louder than this (about -36dB).
+const SILENCE_THRESHOLD: i16 = 500;
+
+// Silences that are shorter than this are just pauses in speech, so we leave them alone.
+const MIN_SILENCE: Diff = Diff::from_micros(1_000_000);
+
+// We leave this much of each silence at normal speed, so that we don't clip the ends of words.
+const SILENCE_PADDING: Diff = Diff::from_micros(150_000);
+
+// The number of samples that we feed into the phase vocoder at a time.
+const VOCODER_CHUNK: usize = 2048;
+
+// A part of the animation that plays at a constant speed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Piece {
+    /// Where this piece starts, in the exported video.
+    out_start: Time,
+    /// The part of the animation that this piece covers.
+    src_start: Time,
+    src_end: Time,
+    /// How many times faster than normal this piece plays.
+    speed: f64,
+}
+
+impl Piece {
+    fn out_end(&self) -> Time {
+        let len = (self.src_end - self.src_start).as_micros() as f64 / self.speed;
+        self.out_start + Diff::from_micros(len.round() as i64)
+    }
+}
+
+/// A map from times in the exported video to times in the animation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimeRemap {
+    // The pieces, in order. They cover the exported part of the animation, without gaps.
+    pieces: Vec<Piece>,
+}
+
+impl TimeRemap {
+    /// Plays the part of the animation between `src_start` and `src_end` at `speed` times the
+    /// normal speed, except for the parts in `silences`, which play at `silence_speed` times the
+    /// normal speed.
+    ///
+    /// The silences must be sorted and non-overlapping.
+    pub fn new(
+        src_start: Time,
+        src_end: Time,
+        speed: f64,
+        silences: &[(Time, Time)],
+        silence_speed: f64,
+    ) -> TimeRemap {
+        let mut ret = TimeRemap { pieces: Vec::new() };
+        let mut src = src_start;
+        for &(start, end) in silences {
+            let start = start.max(src).min(src_end);
+            let end = end.max(start).min(src_end);
+            ret.push(src, start, speed);
+            ret.push(start, end, silence_speed);
+            src = end;
+        }
+        ret.push(src, src_end, speed);
+        ret
+    }
+
+    fn push(&mut self, src_start: Time, src_end: Time, speed: f64) {
+        if src_end <= src_start {
+            return;
+        }
+        let out_start = self.out_end();
+        self.pieces.push(Piece {
+            out_start,
+            src_start,
+            src_end,
+            speed,
+        });
+    }
+
+    /// Is this remapping the one that leaves everything alone?
+    pub fn is_identity(&self) -> bool {
+        let starts_at_zero = self
+            .pieces
+            .first()
+            .map(|p| p.src_start == time::ZERO)
+            .unwrap_or(true);
+        starts_at_zero && self.pieces.iter().all(|p| p.speed == 1.0)
+    }
+
+    /// The length of the exported video.
+    pub fn out_end(&self) -> Time {
+        self.pieces
+            .last()
+            .map(|p| p.out_end())
+            .unwrap_or(time::ZERO)
+    }
+
+    /// The time in the animation that should be shown at time `out` of the exported video.
+    pub fn src_time(&self, out: Time) -> Time {
+        let piece = match self.pieces.iter().rev().find(|p| p.out_start <= out) {
+            Some(p) => p,
+            None => return time::ZERO,
+        };
+        let offset = (out - piece.out_start).as_micros() as f64 * piece.speed;
+        (piece.src_start + Diff::from_micros(offset.round() as i64)).min(piece.src_end)
+    }
+
+    /// The time in the exported video at which time `src` of the animation gets shown. Times
+    /// outside the exported part of the animation get clamped to the start or end of the video.
+    pub fn out_time(&self, src: Time) -> Time {
+        let piece = match self.pieces.iter().rev().find(|p| p.src_start <= src) {
+            Some(p) => p,
+            None => return time::ZERO,
+        };
+        let offset = (src.min(piece.src_end) - piece.src_start).as_micros() as f64 / piece.speed;
+        piece.out_start + Diff::from_micros(offset.round() as i64)
+    }
+
+    /// The times in the animation that should be shown in each frame of the exported video.
+    pub fn frame_times(&self, fps: f64) -> Vec<Time> {
+        (0..self.out_end().as_video_frame(fps))
+            .map(|frame_idx| self.src_time(Time::from_video_frame(frame_idx, fps)))
+            .collect()
+    }
+
+    /// Applies the remapping to the (mixed) audio of the animation. The pitch is preserved.
+    pub fn remap_samples(&self, samples: &[i16]) -> Vec<i16> {
+        let mut ret = Vec::with_capacity(self.out_end().as_audio_idx(SAMPLE_RATE));
+        for piece in &self.pieces {
+            let src_start = piece.src_start.as_audio_idx(SAMPLE_RATE).min(samples.len());
+            let src_end = piece.src_end.as_audio_idx(SAMPLE_RATE).min(samples.len());
+            let out_len = piece.out_end().as_audio_idx(SAMPLE_RATE)
+                - piece.out_start.as_audio_idx(SAMPLE_RATE);
+            ret.extend(stretch(&samples[src_start..src_end], piece.speed, out_len));
+        }
+        ret
+    }
+}
+
+// Speeds up some audio by a factor of `speed`, producing exactly `out_len` samples.
+fn stretch(samples: &[i16], speed: f64, out_len: usize) -> Vec<i16> {
+    let mut out = vec![0; out_len];
+    if speed == 1.0 {
+        let len = samples.len().min(out_len);
+        out[..len].copy_from_slice(&samples[..len]);
+        return out;
+    }
+
+    let mut pvoc = PhaseVocoder::new(speed as f32);
+    let mut input = samples.chunks(VOCODER_CHUNK);
+    let silence = [0i16; VOCODER_CHUNK];
+    let mut written = 0;
+    while written < out_len {
+        // The vocoder lags behind its input a little, so once the input runs out we push it along
+        // with silence.
+        pvoc.input(input.next().unwrap_or(&silence[..]));
+        let len = pvoc.samples_available().min(out_len - written);
+        pvoc.consume_output(&mut out[written..(written + len)]);
+        written += len;
+    }
+    out
+}
+
+/// Mixes all the audio in the animation into a single buffer, up until `end`.
+fn mix(audio: &AudioSnippetsData, end: Time) -> Vec<i16> {
+    let mut buf = vec![0; end.as_audio_idx(SAMPLE_RATE)];
+    let mut cursor = Cursor::new(audio, time::ZERO, SAMPLE_RATE, true);
+    cursor.mix_to_buffer(audio, &mut buf[..]);
+    buf
+}
+
+/// Finds the parts of `samples` that are silent for at least `MIN_SILENCE` (not including
+/// `SILENCE_PADDING` at each end).
+pub fn find_silences(samples: &[i16]) -> Vec<(Time, Time)> {
+    let to_time = |idx: usize| time::ZERO + Diff::from_audio_idx(idx as i64, SAMPLE_RATE);
+    let mut ret = Vec::new();
+    let mut push = |start: usize, end: usize| {
+        let (start, end) = (to_time(start), to_time(end));
+        if end - start >= MIN_SILENCE {
+            ret.push((start + SILENCE_PADDING, end - SILENCE_PADDING));
+        }
+    };
+
+    let mut silence_start = None;
+    for (i, window) in samples.chunks(SILENCE_WINDOW).enumerate() {
+        let silent = window
+            .iter()
+            .all(|&s| (s as i32).abs() < SILENCE_THRESHOLD as i32);
+        match (silent, silence_start) {
+            (true, None) => silence_start = Some(i * SILENCE_WINDOW),
+            (false, Some(start)) => {
+                push(start, i * SILENCE_WINDOW);
+                silence_start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(start) = silence_start {
+        push(start, samples.len());
+    }
+    ret
+}
+
+/// Figures out how to remap the times of the part of an animation covered by `range`, according
+/// to the export settings. Returns the remapping and the remapped audio.
+pub fn for_export(
+    audio: &AudioSnippetsData,
+    export: &ExportSettings,
+    range: TimeSpan,
+) -> (TimeRemap, AudioSnippetsData) {
+    let (start, end) = (range.start(), range.end());
+    let speed = export.speed.max(0.01);
+    if !export.skip_silence && speed == 1.0 && start == time::ZERO {
+        return (TimeRemap::new(start, end, 1.0, &[], 1.0), audio.clone());
+    }
+
+    let samples = mix(audio, end);
+    let silences = if export.skip_silence {
+        find_silences(&samples)
+    } else {
+        Vec::new()
+    };
+    let silence_speed = export.silence_speed.max(speed);
+    let remap = TimeRemap::new(start, end, speed, &silences, silence_speed);
+    let remapped = AudioSnippetData::new(remap.remap_samples(&samples), time::ZERO);
+    (
+        remap,
+        AudioSnippetsData::default().with_new_snippet(remapped).0,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn t(secs: f64) -> Time {
+        Time::from_micros((secs * 1e6).round() as i64)
+    }
+
+    #[test]
+    fn remap() {
+        let remap = TimeRemap::new(t(0.0), t(10.0), 2.0, &[(t(2.0), t(6.0))], 4.0);
+        assert!(!remap.is_identity());
+        assert_eq!(remap.out_end(), t(4.0));
+        assert_eq!(remap.src_time(t(0.5)), t(1.0));
+        assert_eq!(remap.src_time(t(1.5)), t(4.0));
+        assert_eq!(remap.src_time(t(2.5)), t(7.0));
+        assert_eq!(remap.src_time(t(100.0)), t(10.0));
+        assert_eq!(remap.out_time(t(1.0)), t(0.5));
+        assert_eq!(remap.out_time(t(4.0)), t(1.5));
+        assert_eq!(remap.out_time(t(7.0)), t(2.5));
+        assert_eq!(remap.out_time(t(100.0)), t(4.0));
+
+        let remap = TimeRemap::new(t(0.0), t(10.0), 1.0, &[], 1.0);
+        assert!(remap.is_identity());
+        assert_eq!(remap.frame_times(2.0)[3], t(1.5));
+    }
+
+    #[test]
+    fn remap_range() {
+        let remap = TimeRemap::new(t(3.0), t(10.0), 1.0, &[(t(2.0), t(5.0))], 2.0);
+        assert!(!remap.is_identity());
+        assert_eq!(remap.out_end(), t(6.0));
+        assert_eq!(remap.src_time(t(0.0)), t(3.0));
+        assert_eq!(remap.src_time(t(2.0)), t(6.0));
+        assert_eq!(remap.out_time(t(1.0)), t(0.0));
+        assert_eq!(remap.out_time(t(6.0)), t(2.0));
+        assert_eq!(remap.frame_times(1.0).len(), 6);
+    }
+
+    #[test]
+    fn silences() {
+        let second = SAMPLE_RATE as usize;
+        let mut samples = vec![1000i16; second];
+        samples.extend(vec![0; 2 * second]);
+        samples.extend(vec![-1000; second / 2]);
+        samples.extend(vec![10; second / 2]);
+        assert_eq!(find_silences(&samples), vec![(t(1.15), t(2.85))]);
+    }
+
+    #[test]
+    fn stretched_length() {
+        let samples = vec![1000i16; 10000];
+        assert_eq!(stretch(&samples, 2.0, 5000).len(), 5000);
+        assert_eq!(stretch(&samples, 1.0, 12000)[10000..], [0; 2000][..]);
+    }
+}
@@ -0,0 +1,293 @@
+//! Telling fingers, pens and mice apart. Druid reports all of them as mouse events, so on Linux we
+//! also watch the touchscreens and pens ourselves (through evdev), and whenever a button goes down
+//! the drawing pane asks us what is touching the screen. This needs read access to
+//! `/dev/input/event*` (usually by being in the `input` group); without it, or on other platforms,
+//! everything counts as the mouse.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// What a mouse event (probably) came from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pointer {
+    Mouse,
+    Pen,
+    /// This many fingers are on a touchscreen, and there's no pen nearby.
+    Fingers(usize),
+    /// Something touched a touchscreen while a pen was nearby, which is most likely the hand that
+    /// holds the pen.
+    Palm,
+}
+
+impl Pointer {
+    /// The number of fingers that are touching (and not being rejected as a palm).
+    pub fn fingers(self) -> usize {
+        match self {
+            Pointer::Fingers(n) => n,
+            _ => 0,
+        }
+    }
+}
+
+static FINGERS: AtomicUsize = AtomicUsize::new(0);
+static PEN_NEAR: AtomicBool = AtomicBool::new(false);
+static PEN_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Starts watching the touchscreens and pens, if we can.
+pub fn start() {
+    #[cfg(target_os = "linux")]
+    evdev::start();
+}
+
+/// What the current mouse event (probably) came from.
+pub fn pointer() -> Pointer {
+    classify(
+        FINGERS.load(Ordering::SeqCst),
+        PEN_NEAR.load(Ordering::SeqCst),
+        PEN_DOWN.load(Ordering::SeqCst),
+    )
+}
+
+fn classify(fingers: usize, pen_near: bool, pen_down: bool) -> Pointer {
+    if pen_down || (pen_near && fingers == 0) {
+        Pointer::Pen
+    } else if pen_near {
+        Pointer::Palm
+    } else if fingers > 0 {
+        Pointer::Fingers(fingers)
+    } else {
+        Pointer::Mouse
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod evdev {
+    use std::fs::{self, File};
+    use std::io::Read;
+    use std::path::Path;
+    use std::sync::atomic::Ordering;
+
+    use super::{FINGERS, PEN_DOWN, PEN_NEAR};
+
+    const EV_SYN: u16 = 0x00;
+    const EV_KEY: u16 = 0x01;
+    const EV_ABS: u16 = 0x03;
+    const SYN_REPORT: u16 = 0x00;
+    const BTN_TOOL_PEN: u16 = 0x140;
+    const BTN_TOOL_RUBBER: u16 = 0x141;
+    const BTN_TOUCH: u16 = 0x14a;
+    const ABS_MT_SLOT: u16 = 0x2f;
+    const ABS_MT_TOOL_TYPE: u16 = 0x37;
+    const ABS_MT_TRACKING_ID: u16 = 0x39;
+    const MT_TOOL_FINGER: i32 = 0;
+    const MT_TOOL_PEN: i32 = 1;
+    const INPUT_PROP_DIRECT: usize = 0x01;
+
+    // Touchscreens don't have anywhere near this many contacts, but a confused one shouldn't make
+    // us allocate a lot.
+    const MAX_SLOTS: usize = 64;
+
+    // A `struct input_event` is a `struct timeval` (two `long`s) followed by the type, the code
+    // and the value.
+    const TIME_LEN: usize = 2 * std::mem::size_of::<usize>();
+    const EVENT_LEN: usize = TIME_LEN + 8;
+
+    pub fn start() {
+        let dir = match fs::read_dir("/dev/input") {
+            Ok(dir) => dir,
+            Err(e) => {
+                log::warn!("couldn't look for touchscreens: {}", e);
+                return;
+            }
+        };
+        for entry in dir.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with("event") || !is_interesting(&name) {
+                continue;
+            }
+            let path = entry.path();
+            match File::open(&path) {
+                Ok(file) => {
+                    std::thread::spawn(move || watch(file, &name));
+                }
+                Err(e) => log::warn!("couldn't watch {} for touches: {}", path.display(), e),
+            }
+        }
+    }
+
+    /// Is the device `/dev/input/<name>` a pen, or a touchscreen? (Touchpads and the like report
+    /// touches too, but they aren't "direct": they move a pointer instead of touching the canvas.)
+    fn is_interesting(name: &str) -> bool {
+        let caps = Path::new("/sys/class/input").join(name).join("device");
+        let read = |file: &str| fs::read_to_string(caps.join(file)).unwrap_or_default();
+        let keys = read("capabilities/key");
+        let abs = read("capabilities/abs");
+        let props = read("properties");
+        has_bit(&keys, BTN_TOOL_PEN as usize)
+            || (has_bit(&props, INPUT_PROP_DIRECT) && has_bit(&abs, ABS_MT_TRACKING_ID as usize))
+    }
+
+    /// Whether bit number `bit` is set in a bitmask from sysfs, which is written as hexadecimal
+    /// words (each as wide as a `long`), most significant first.
+    pub(super) fn has_bit(mask: &str, bit: usize) -> bool {
+        let word_bits = 8 * std::mem::size_of::<usize>();
+        mask.split_whitespace()
+            .rev()
+            .nth(bit / word_bits)
+            .and_then(|word| u64::from_str_radix(word, 16).ok())
+            .map_or(false, |word| word & (1 << (bit % word_bits)) != 0)
+    }
+
+    fn watch(mut file: File, name: &str) {
+        let mut device = Device::default();
+        let mut published = Report::default();
+        let mut buf = [0u8; EVENT_LEN];
+        while file.read_exact(&mut buf).is_ok() {
+            let ty = u16::from_ne_bytes([buf[TIME_LEN], buf[TIME_LEN + 1]]);
+            let code = u16::from_ne_bytes([buf[TIME_LEN + 2], buf[TIME_LEN + 3]]);
+            let mut value = [0u8; 4];
+            value.copy_from_slice(&buf[TIME_LEN + 4..]);
+            if let Some(report) = device.handle(ty, code, i32::from_ne_bytes(value)) {
+                publish(&published, &report);
+                published = report;
+            }
+        }
+        log::info!("stopped watching {} for touches", name);
+        publish(&published, &Report::default());
+    }
+
+    fn publish(old: &Report, new: &Report) {
+        // Several devices might have fingers on them, so each one only adds its own.
+        FINGERS.fetch_add(new.fingers, Ordering::SeqCst);
+        FINGERS.fetch_sub(old.fingers, Ordering::SeqCst);
+        if old.pen_near != new.pen_near {
+            PEN_NEAR.store(new.pen_near, Ordering::SeqCst);
+        }
+        if old.pen_down != new.pen_down {
+            PEN_DOWN.store(new.pen_down, Ordering::SeqCst);
+        }
+    }
+
+    /// What is touching one device, as of its last report.
+    #[derive(Clone, Debug, Default, PartialEq)]
+    pub(super) struct Report {
+        pub fingers: usize,
+        pub pen_near: bool,
+        pub pen_down: bool,
+    }
+
+    #[derive(Clone, Copy, Default)]
+    struct Slot {
+        active: bool,
+        tool: i32,
+    }
+
+    /// The state of one device, put together from its events. Touchscreens report their contacts
+    /// in slots (this is multi-touch protocol B); pens report whether they're nearby with a tool
+    /// button, and whether they're touching with `BTN_TOUCH`.
+    #[derive(Default)]
+    pub(super) struct Device {
+        slots: Vec<Slot>,
+        slot: usize,
+        pen_near: bool,
+        pen_down: bool,
+    }
+
+    impl Device {
+        /// Handles one event, returning the new state of the device whenever it finishes a report.
+        pub fn handle(&mut self, ty: u16, code: u16, value: i32) -> Option<Report> {
+            match (ty, code) {
+                (EV_KEY, BTN_TOOL_PEN) | (EV_KEY, BTN_TOOL_RUBBER) => self.pen_near = value != 0,
+                (EV_KEY, BTN_TOUCH) if self.pen_near => self.pen_down = value != 0,
+                (EV_ABS, ABS_MT_SLOT) => self.slot = (value.max(0) as usize).min(MAX_SLOTS - 1),
+                (EV_ABS, ABS_MT_TRACKING_ID) => self.cur_slot().active = value != -1,
+                (EV_ABS, ABS_MT_TOOL_TYPE) => self.cur_slot().tool = value,
+                (EV_SYN, SYN_REPORT) => return Some(self.report()),
+                _ => {}
+            }
+            None
+        }
+
+        fn cur_slot(&mut self) -> &mut Slot {
+            if self.slots.len() <= self.slot {
+                self.slots.resize(self.slot + 1, Slot::default());
+            }
+            &mut self.slots[self.slot]
+        }
+
+        fn report(&self) -> Report {
+            let active = || self.slots.iter().filter(|s| s.active);
+            // Some touchscreens report their pen as one of the contacts.
+            let pen = active().any(|s| s.tool == MT_TOOL_PEN);
+            Report {
+                fingers: active().filter(|s| s.tool == MT_TOOL_FINGER).count(),
+                pen_near: self.pen_near || pen,
+                pen_down: self.pen_down || pen,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classification() {
+        assert_eq!(classify(0, false, false), Pointer::Mouse);
+        assert_eq!(classify(2, false, false), Pointer::Fingers(2));
+        assert_eq!(classify(0, true, false), Pointer::Pen);
+        assert_eq!(classify(1, true, true), Pointer::Pen);
+        assert_eq!(classify(1, true, false), Pointer::Palm);
+    }
+
+    #[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+    #[test]
+    fn bitmasks() {
+        use evdev::has_bit;
+
+        assert!(has_bit("2", 1));
+        assert!(!has_bit("2", 0));
+        assert!(has_bit("1 0", 64));
+        assert!(!has_bit("1 0", 0));
+        assert!(!has_bit("", 3));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn touchscreen_events() {
+        use evdev::{Device, Report};
+
+        let mut dev = Device::default();
+        // Two fingers go down, then one of them turns out to be a palm.
+        assert_eq!(dev.handle(3, 0x2f, 0), None);
+        dev.handle(3, 0x39, 10);
+        dev.handle(3, 0x2f, 1);
+        dev.handle(3, 0x39, 11);
+        let report = dev.handle(0, 0, 0).unwrap();
+        assert_eq!(report.fingers, 2);
+        dev.handle(3, 0x37, 2);
+        assert_eq!(dev.handle(0, 0, 0).unwrap().fingers, 1);
+
+        // A pen comes near, and touches.
+        dev.handle(1, 0x140, 1);
+        dev.handle(1, 0x14a, 1);
+        let report = dev.handle(0, 0, 0).unwrap();
+        assert_eq!(
+            report,
+            Report {
+                fingers: 1,
+                pen_near: true,
+                pen_down: true,
+            }
+        );
+
+        // Everything lifts.
+        dev.handle(1, 0x14a, 0);
+        dev.handle(1, 0x140, 0);
+        dev.handle(3, 0x2f, 0);
+        dev.handle(3, 0x39, -1);
+        dev.handle(3, 0x2f, 1);
+        dev.handle(3, 0x39, -1);
+        assert_eq!(dev.handle(0, 0, 0).unwrap(), Report::default());
+    }
+}
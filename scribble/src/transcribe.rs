@@ -0,0 +1,204 @@
+//! Turning narration into captions with speech-to-text.
+//!
+//! The recognition is done offline by whisper.cpp, which is only compiled in with the `whisper`
+//! feature. It needs a model file (one of the `ggml-*.bin` files that whisper.cpp distributes),
+//! which is set in `Preferences::whisper_model`. Transcription is slow, so it runs on a
+//! background thread; like `crate::project_io`, the results get picked up by `Root` on its timer.
+
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use scribble_curves::{Diff, Time};
+
+use crate::audio::{AudioSnippetData, SAMPLE_RATE};
+use crate::captions::Caption;
+
+/// The sample rate that whisper wants.
+const WHISPER_SAMPLE_RATE: u32 = 16000;
+
+struct Job {
+    snippets: Vec<AudioSnippetData>,
+    model: PathBuf,
+    language: String,
+}
+
+/// The result of transcribing some audio snippets.
+pub enum Status {
+    /// The audio was transcribed. This comes with the times that the transcribed snippets
+    /// covered (so that old captions there can be replaced), and the new captions.
+    Finished {
+        spans: Vec<(Time, Time)>,
+        captions: Vec<Caption>,
+    },
+    Failed(String),
+}
+
+/// The handle to the background thread that does speech-to-text.
+pub struct Transcriber {
+    jobs: Sender<Job>,
+    pub status: Receiver<Status>,
+}
+
+impl Transcriber {
+    pub fn new() -> Transcriber {
+        let (jobs_tx, jobs_rx) = channel::<Job>();
+        let (status_tx, status_rx) = channel();
+        std::thread::spawn(move || {
+            for job in jobs_rx {
+                let spans = job
+                    .snippets
+                    .iter()
+                    .map(|s| (s.start_time(), s.end_time()))
+                    .collect();
+                let status = match transcribe(&job) {
+                    Ok(captions) => Status::Finished { spans, captions },
+                    Err(e) => Status::Failed(e.to_string()),
+                };
+                let _ = status_tx.send(status);
+            }
+        });
+        Transcriber {
+            jobs: jobs_tx,
+            status: status_rx,
+        }
+    }
+
+    /// Starts transcribing some audio snippets, using the whisper model in the file `model`. If
+    /// `language` is empty, whisper guesses the language.
+    pub fn transcribe(&self, snippets: Vec<AudioSnippetData>, model: PathBuf, language: String) {
+        let job = Job {
+            snippets,
+            model,
+            language,
+        };
+        if self.jobs.send(job).is_err() {
+            log::error!("the speech-to-text thread has stopped");
+        }
+    }
+}
+
+fn transcribe(job: &Job) -> anyhow::Result<Vec<Caption>> {
+    let mut ret = Vec::new();
+    for snip in &job.snippets {
+        // Linked audio that's gone missing has no samples.
+        if snip.buf().is_empty() {
+            continue;
+        }
+        log::info!("transcribing the audio at {:?}", snip.start_time());
+        let samples = to_whisper_samples(snip.buf());
+        for (start, end, text) in recognize(&job.model, &job.language, &samples)? {
+            let text = text.trim();
+            if is_speech(text) {
+                ret.push(Caption {
+                    start: snip.start_time() + start,
+                    end: snip.start_time() + end,
+                    text: text.to_owned(),
+                });
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// Converts our audio to whisper's: 16kHz mono, as floats between -1 and 1. We average each
+/// group of three samples, which is a (crude) low-pass filter.
+pub fn to_whisper_samples(buf: &[i16]) -> Vec<f32> {
+    let ratio = (SAMPLE_RATE / WHISPER_SAMPLE_RATE) as usize;
+    buf.chunks(ratio)
+        .map(|c| c.iter().map(|&x| x as f32).sum::<f32>() / (c.len() as f32 * 32768.0))
+        .collect()
+}
+
+// Whisper marks some things that aren't speech with brackets, like "[BLANK_AUDIO]" or
+// "(music)". We don't want those as captions.
+fn is_speech(text: &str) -> bool {
+    let bracketed = (text.starts_with('[') && text.ends_with(']'))
+        || (text.starts_with('(') && text.ends_with(')'));
+    !text.is_empty() && !bracketed
+}
+
+/// Runs whisper on some audio, returning the times (relative to the start of the audio) and
+/// texts of the segments that it found.
+#[cfg(feature = "whisper")]
+fn recognize(
+    model: &std::path::Path,
+    language: &str,
+    samples: &[f32],
+) -> anyhow::Result<Vec<(Diff, Diff, String)>> {
+    use anyhow::anyhow;
+    use whisper_rs::{FullParams, SamplingStrategy, WhisperContext, WhisperContextParameters};
+
+    let model_name = model
+        .to_str()
+        .ok_or_else(|| anyhow!("the model path {:?} isn't valid unicode", model))?;
+    let ctx = WhisperContext::new_with_params(model_name, WhisperContextParameters::default())
+        .map_err(|e| {
+            anyhow!(
+                "couldn't load the speech-to-text model {:?}: {:?}",
+                model,
+                e
+            )
+        })?;
+    let mut state = ctx
+        .create_state()
+        .map_err(|e| anyhow!("couldn't start speech-to-text: {:?}", e))?;
+
+    let mut params = FullParams::new(SamplingStrategy::Greedy { best_of: 1 });
+    params.set_language(Some(if language.is_empty() {
+        "auto"
+    } else {
+        language
+    }));
+    params.set_print_progress(false);
+    params.set_print_realtime(false);
+    params.set_print_special(false);
+    params.set_print_timestamps(false);
+    state
+        .full(params, samples)
+        .map_err(|e| anyhow!("speech-to-text failed: {:?}", e))?;
+
+    // Whisper's timestamps are in hundredths of a second.
+    let time = |t: i64| Diff::from_micros(t * 10_000);
+    let err = |e| anyhow!("speech-to-text failed: {:?}", e);
+    let mut ret = Vec::new();
+    for i in 0..state.full_n_segments().map_err(err)? {
+        let text = state.full_get_segment_text(i).map_err(err)?;
+        let start = state.full_get_segment_t0(i).map_err(err)?;
+        let end = state.full_get_segment_t1(i).map_err(err)?;
+        ret.push((time(start), time(end), text));
+    }
+    Ok(ret)
+}
+
+#[cfg(not(feature = "whisper"))]
+fn recognize(
+    _model: &std::path::Path,
+    _language: &str,
+    _samples: &[f32],
+) -> anyhow::Result<Vec<(Diff, Diff, String)>> {
+    Err(anyhow::anyhow!(
+        "this copy of scribble was built without speech-to-text (it needs the `whisper` feature)"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn whisper_samples() {
+        let samples = to_whisper_samples(&[0, 3, 6, -16384, -16384, -16384, 32767]);
+        assert_eq!(samples.len(), 3);
+        assert_eq!(samples[0], 9.0 / 3.0 / 32768.0);
+        assert_eq!(samples[1], -0.5);
+        assert!(samples[2] > 0.99);
+    }
+
+    #[test]
+    fn not_speech() {
+        assert!(is_speech("Hello."));
+        assert!(!is_speech("[BLANK_AUDIO]"));
+        assert!(!is_speech("(music)"));
+        assert!(!is_speech(""));
+    }
+}
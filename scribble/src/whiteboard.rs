@@ -0,0 +1,430 @@
+//! Importing drawings from the Excalidraw (`.excalidraw`) and tldraw (`.tldr`) whiteboards, so
+//! that existing diagrams can be animated and narrated.
+//!
+//! Both formats are JSON. We turn each shape into one or more polylines, scale the whole drawing
+//! to fit on the canvas, and then make a single snippet out of it. Text and images are skipped.
+
+use anyhow::anyhow;
+use druid::kurbo::Vec2;
+use druid::{Color, Point};
+use serde_json::Value;
+use std::path::Path;
+
+use scribble_curves::{Curve, Diff, Effects, LineStyle, SnippetData, Time};
+
+use crate::widgets::DRAWING_WIDTH;
+
+/// How much of the canvas (on each side) to leave empty around an imported drawing.
+const MARGIN: f64 = 0.05;
+
+/// When drawing an import stroke by stroke, this is how fast the pen moves (in drawing widths
+/// per second).
+const DRAW_ON_SPEED: f64 = 0.5;
+
+/// When drawing an import stroke by stroke, this is how long the pen rests between strokes.
+const DRAW_ON_PAUSE: Diff = Diff::from_micros(100_000);
+
+/// Ellipses are approximated by polygons with this many sides.
+const ELLIPSE_SIDES: usize = 64;
+
+/// Does this path look like a whiteboard drawing that we can import?
+pub fn is_whiteboard_path(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| matches!(e.to_lowercase().as_str(), "excalidraw" | "tldr"))
+        .unwrap_or(false)
+}
+
+/// A single polyline from an imported drawing.
+#[derive(Clone, Debug)]
+pub struct Stroke {
+    pub points: Vec<Point>,
+    pub color: Color,
+    /// The thickness of the line, in the same units as `points`.
+    pub width: f64,
+}
+
+/// Loads the strokes from an Excalidraw or tldraw file, scaled to fit on a canvas of height
+/// `drawing_height`.
+pub fn load(path: &Path, drawing_height: f64) -> anyhow::Result<Vec<Stroke>> {
+    let scene: Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    let mut strokes = if scene.get("elements").is_some() {
+        excalidraw_strokes(&scene)?
+    } else if scene.get("document").is_some() {
+        tldraw_strokes(&scene)?
+    } else {
+        return Err(anyhow!("{:?} isn't an Excalidraw or tldraw drawing", path));
+    };
+    if strokes.is_empty() {
+        return Err(anyhow!("there's nothing to import in {:?}", path));
+    }
+    fit(&mut strokes, drawing_height);
+    Ok(strokes)
+}
+
+/// Makes a snippet out of some strokes, starting at `start`. If `draw_on` is true, the strokes
+/// get drawn one after the other (at a steady speed); otherwise they all appear at once.
+pub fn to_snippet(strokes: &[Stroke], start: Time, draw_on: bool) -> Option<SnippetData> {
+    let mut curve = Curve::new();
+    let mut time = start;
+    for stroke in strokes.iter().filter(|s| !s.points.is_empty()) {
+        let style = LineStyle {
+            color: stroke.color.clone(),
+            thickness: stroke.width,
+        };
+        curve.move_to(stroke.points[0], time, style, Effects::default());
+        for pair in stroke.points.windows(2) {
+            if draw_on {
+                let secs = (pair[1] - pair[0]).hypot() / DRAW_ON_SPEED;
+                time += Diff::from_micros((secs * 1e6).round() as i64);
+            }
+            curve.line_to(pair[1], time);
+        }
+        if draw_on {
+            time += DRAW_ON_PAUSE;
+        }
+    }
+    if curve.times.is_empty() {
+        None
+    } else {
+        Some(SnippetData::new(curve))
+    }
+}
+
+// Scales and translates the strokes so that they're centered on the canvas, with a margin.
+fn fit(strokes: &mut [Stroke], drawing_height: f64) {
+    let mut points = strokes.iter().flat_map(|s| s.points.iter());
+    let first = match points.next() {
+        Some(p) => *p,
+        None => return,
+    };
+    let (min, max) = points.fold((first, first), |(min, max), p| {
+        (
+            Point::new(min.x.min(p.x), min.y.min(p.y)),
+            Point::new(max.x.max(p.x), max.y.max(p.y)),
+        )
+    });
+
+    let avail_w = DRAWING_WIDTH * (1.0 - 2.0 * MARGIN);
+    let avail_h = drawing_height - 2.0 * MARGIN * DRAWING_WIDTH;
+    let (w, h) = (max.x - min.x, max.y - min.y);
+    let scale = match (w > 0.0, h > 0.0) {
+        (true, true) => (avail_w / w).min(avail_h / h),
+        (true, false) => avail_w / w,
+        (false, true) => avail_h / h,
+        (false, false) => 1.0,
+    };
+    let center = Vec2::new(DRAWING_WIDTH / 2.0, drawing_height / 2.0);
+    let offset = center - (min.to_vec2() + max.to_vec2()) * (scale / 2.0);
+    for stroke in strokes {
+        for p in &mut stroke.points {
+            *p = (p.to_vec2() * scale + offset).to_point();
+        }
+        // Don't let thin lines vanish entirely.
+        stroke.width = (stroke.width * scale).max(0.001);
+    }
+}
+
+fn num(v: &Value, key: &str) -> f64 {
+    v.get(key).and_then(Value::as_f64).unwrap_or(0.0)
+}
+
+// Reads a point that's stored as an array (like `[x, y]`, or `[x, y, pressure]`).
+fn array_point(v: &Value) -> Option<Point> {
+    let arr = v.as_array()?;
+    Some(Point::new(arr.get(0)?.as_f64()?, arr.get(1)?.as_f64()?))
+}
+
+fn rotated(points: Vec<Point>, center: Point, angle: f64) -> Vec<Point> {
+    if angle == 0.0 {
+        return points;
+    }
+    let (sin, cos) = angle.sin_cos();
+    points
+        .into_iter()
+        .map(|p| {
+            let d = p - center;
+            center + Vec2::new(d.x * cos - d.y * sin, d.x * sin + d.y * cos)
+        })
+        .collect()
+}
+
+fn rectangle(origin: Point, w: f64, h: f64) -> Vec<Point> {
+    vec![
+        origin,
+        origin + (w, 0.0),
+        origin + (w, h),
+        origin + (0.0, h),
+        origin,
+    ]
+}
+
+fn ellipse(center: Point, rx: f64, ry: f64) -> Vec<Point> {
+    (0..=ELLIPSE_SIDES)
+        .map(|i| {
+            let theta = i as f64 / ELLIPSE_SIDES as f64 * 2.0 * std::f64::consts::PI;
+            center + (rx * theta.cos(), ry * theta.sin())
+        })
+        .collect()
+}
+
+// The two barbs of an arrow pointing from `from` to `to`.
+fn arrow_head(from: Point, to: Point, len: f64) -> Vec<Point> {
+    let dir = to - from;
+    if dir.hypot() == 0.0 {
+        return Vec::new();
+    }
+    let back = -dir * (len / dir.hypot());
+    let barb = |angle: f64| {
+        let (sin, cos) = angle.sin_cos();
+        to + Vec2::new(back.x * cos - back.y * sin, back.x * sin + back.y * cos)
+    };
+    vec![barb(0.5), to, barb(-0.5)]
+}
+
+fn parse_hex_color(s: &str) -> Option<Color> {
+    if !s.starts_with('#') {
+        return None;
+    }
+    let hex = &s[1..];
+    let rgb = u32::from_str_radix(hex, 16).ok()?;
+    match hex.len() {
+        6 => Some(Color::from_rgba32_u32((rgb << 8) | 0xff)),
+        3 => {
+            let (r, g, b) = ((rgb >> 8) & 0xf, (rgb >> 4) & 0xf, rgb & 0xf);
+            Some(Color::rgb8((r * 17) as u8, (g * 17) as u8, (b * 17) as u8))
+        }
+        _ => None,
+    }
+}
+
+fn excalidraw_strokes(scene: &Value) -> anyhow::Result<Vec<Stroke>> {
+    let elements = scene["elements"]
+        .as_array()
+        .ok_or_else(|| anyhow!("the Excalidraw elements should be a list"))?;
+    let mut ret = Vec::new();
+    for el in elements {
+        if el["isDeleted"].as_bool() == Some(true) {
+            continue;
+        }
+        let origin = Point::new(num(el, "x"), num(el, "y"));
+        let (w, h) = (num(el, "width"), num(el, "height"));
+        let relative_points = || -> Vec<Point> {
+            el["points"]
+                .as_array()
+                .map(|ps| ps.iter().filter_map(array_point).collect::<Vec<_>>())
+                .unwrap_or_default()
+                .into_iter()
+                .map(|p| origin + p.to_vec2())
+                .collect()
+        };
+        let shapes = match el["type"].as_str() {
+            Some("freedraw") | Some("line") => vec![relative_points()],
+            Some("arrow") => {
+                let line = relative_points();
+                let head = match line[..] {
+                    [.., a, b] => arrow_head(a, b, 15.0),
+                    _ => Vec::new(),
+                };
+                vec![line, head]
+            }
+            Some("rectangle") => vec![rectangle(origin, w, h)],
+            Some("diamond") => vec![vec![
+                origin + (w / 2.0, 0.0),
+                origin + (w, h / 2.0),
+                origin + (w / 2.0, h),
+                origin + (0.0, h / 2.0),
+                origin + (w / 2.0, 0.0),
+            ]],
+            Some("ellipse") => vec![ellipse(origin + (w / 2.0, h / 2.0), w / 2.0, h / 2.0)],
+            _ => continue,
+        };
+        let color = el["strokeColor"]
+            .as_str()
+            .and_then(parse_hex_color)
+            .unwrap_or(Color::BLACK);
+        let width = el["strokeWidth"].as_f64().unwrap_or(1.0);
+        let center = origin + (w / 2.0, h / 2.0);
+        for points in shapes.into_iter().filter(|ps| !ps.is_empty()) {
+            ret.push(Stroke {
+                points: rotated(points, center, num(el, "angle")),
+                color: color.clone(),
+                width,
+            });
+        }
+    }
+    Ok(ret)
+}
+
+// tldraw's colors are named, and the actual colors depend on the theme. These are the ones from
+// the light theme.
+fn tldraw_color(name: &str) -> Color {
+    let hex = match name {
+        "white" => "#f0f1f3",
+        "lightGray" => "#c6cbd1",
+        "gray" => "#788492",
+        "green" => "#36b24d",
+        "cyan" => "#0e98ad",
+        "blue" => "#1c7ed6",
+        "indigo" => "#4263eb",
+        "violet" => "#7746f1",
+        "red" => "#ff2133",
+        "orange" => "#ff9433",
+        "yellow" => "#ffc936",
+        _ => "#1d1d1d",
+    };
+    parse_hex_color(hex).unwrap_or(Color::BLACK)
+}
+
+fn tldraw_width(size: &str) -> f64 {
+    match size {
+        "small" => 2.0,
+        "large" => 5.0,
+        _ => 3.5,
+    }
+}
+
+fn tldraw_strokes(scene: &Value) -> anyhow::Result<Vec<Stroke>> {
+    let pages = scene["document"]["pages"]
+        .as_object()
+        .ok_or_else(|| anyhow!("the tldraw document has no pages"))?;
+    // We only import the first page.
+    let shapes = match pages.values().next().and_then(|p| p["shapes"].as_object()) {
+        Some(shapes) => shapes,
+        None => return Ok(Vec::new()),
+    };
+    let mut shapes: Vec<&Value> = shapes.values().collect();
+    shapes.sort_by(|a, b| {
+        num(a, "childIndex")
+            .partial_cmp(&num(b, "childIndex"))
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    let mut ret = Vec::new();
+    for shape in shapes {
+        let origin = array_point(&shape["point"]).unwrap_or(Point::ORIGIN);
+        let size = array_point(&shape["size"]).unwrap_or(Point::ORIGIN);
+        let (w, h) = (size.x, size.y);
+        let mut center = origin + (w / 2.0, h / 2.0);
+        let lines = match shape["type"].as_str() {
+            Some("draw") => vec![shape["points"]
+                .as_array()
+                .map(|ps| ps.iter().filter_map(array_point).collect::<Vec<_>>())
+                .unwrap_or_default()
+                .into_iter()
+                .map(|p| origin + p.to_vec2())
+                .collect()],
+            Some("rectangle") => vec![rectangle(origin, w, h)],
+            Some("triangle") => vec![vec![
+                origin + (w / 2.0, 0.0),
+                origin + (w, h),
+                origin + (0.0, h),
+                origin + (w / 2.0, 0.0),
+            ]],
+            Some("ellipse") => {
+                let radius = array_point(&shape["radius"]).unwrap_or(Point::ORIGIN);
+                center = origin + radius.to_vec2();
+                vec![ellipse(center, radius.x, radius.y)]
+            }
+            Some("arrow") => {
+                let handle = |name: &str| array_point(&shape["handles"][name]["point"]);
+                match (handle("start"), handle("end")) {
+                    (Some(start), Some(end)) => {
+                        let (start, end) = (origin + start.to_vec2(), origin + end.to_vec2());
+                        vec![vec![start, end], arrow_head(start, end, 15.0)]
+                    }
+                    _ => continue,
+                }
+            }
+            _ => continue,
+        };
+        let style = &shape["style"];
+        let color = tldraw_color(style["color"].as_str().unwrap_or("black"));
+        let width = tldraw_width(style["size"].as_str().unwrap_or("medium"));
+        for points in lines.into_iter().filter(|ps| !ps.is_empty()) {
+            ret.push(Stroke {
+                points: rotated(points, center, num(shape, "rotation")),
+                color: color.clone(),
+                width,
+            });
+        }
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn excalidraw() {
+        let scene = json!({
+            "type": "excalidraw",
+            "elements": [
+                {"type": "rectangle", "x": 0, "y": 0, "width": 100, "height": 50,
+                 "strokeColor": "#ff0000", "strokeWidth": 2},
+                {"type": "freedraw", "x": 10, "y": 10, "width": 20, "height": 20,
+                 "points": [[0, 0], [10, 10], [20, 20]], "strokeColor": "#000"},
+                {"type": "text", "x": 0, "y": 0, "text": "hi"},
+                {"type": "line", "x": 0, "y": 0, "isDeleted": true, "points": [[0, 0], [1, 1]]}
+            ]
+        });
+        let strokes = excalidraw_strokes(&scene).unwrap();
+        assert_eq!(strokes.len(), 2);
+        assert_eq!(strokes[0].points.len(), 5);
+        assert_eq!(strokes[0].color.as_rgba_u32(), 0xff0000ff);
+        assert_eq!(strokes[1].points[2], Point::new(30.0, 30.0));
+        assert_eq!(strokes[1].color.as_rgba_u32(), 0x000000ff);
+    }
+
+    #[test]
+    fn tldraw() {
+        let scene = json!({
+            "document": {"pages": {"page": {"shapes": {
+                "b": {"type": "ellipse", "point": [0, 0], "radius": [10, 5], "childIndex": 2,
+                      "style": {"color": "blue", "size": "large"}},
+                "a": {"type": "arrow", "point": [0, 0], "childIndex": 1,
+                      "handles": {"start": {"point": [0, 0]}, "end": {"point": [100, 0]}},
+                      "style": {"color": "black", "size": "small"}}
+            }}}}
+        });
+        let strokes = tldraw_strokes(&scene).unwrap();
+        // The arrow comes first, and it has a head.
+        assert_eq!(strokes.len(), 3);
+        assert_eq!(
+            strokes[0].points,
+            vec![Point::ORIGIN, Point::new(100.0, 0.0)]
+        );
+        assert_eq!(strokes[1].points[1], Point::new(100.0, 0.0));
+        assert_eq!(strokes[2].points.len(), ELLIPSE_SIDES + 1);
+        assert_eq!(strokes[2].width, 5.0);
+    }
+
+    #[test]
+    fn fitting_and_timing() {
+        let mut strokes = vec![Stroke {
+            points: vec![Point::new(0.0, 0.0), Point::new(200.0, 0.0)],
+            color: Color::BLACK,
+            width: 2.0,
+        }];
+        fit(&mut strokes, 0.75);
+        let p = &strokes[0].points;
+        assert!((p[0].x - MARGIN).abs() < 1e-9);
+        assert!((p[1].x - (1.0 - MARGIN)).abs() < 1e-9);
+        assert!((p[0].y - 0.375).abs() < 1e-9);
+
+        let start = Time::from_micros(1_000_000);
+        let instant = to_snippet(&strokes, start, false).unwrap();
+        assert_eq!(instant.last_draw_time(), start);
+        let drawn = to_snippet(&strokes, start, true).unwrap();
+        assert_eq!(drawn.last_draw_time(), Time::from_micros(2_800_000));
+    }
+
+    #[test]
+    fn paths() {
+        assert!(is_whiteboard_path(Path::new("diagram.excalidraw")));
+        assert!(is_whiteboard_path(Path::new("diagram.TLDR")));
+        assert!(!is_whiteboard_path(Path::new("diagram.json")));
+    }
+}
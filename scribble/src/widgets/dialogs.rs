@@ -0,0 +1,1160 @@
+//! Druid doesn't (yet) support modal dialogs, so our "dialogs" are panels that get shown
+//! between the button row and the drawing. Which one (if any) is shown is controlled by
+//! `AppState::dialog`.
+
+use druid::widget::prelude::*;
+use druid::widget::{
+    Button, Checkbox, CrossAxisAlignment, Flex, Label, RadioGroup, Scroll, SizedBox, Stepper,
+    TextBox, ViewSwitcher, WidgetExt,
+};
+use druid::{Color, Command, FileDialogOptions, FileSpec, Lens, LensExt};
+
+use scribble_core::save_file::SaveFormat;
+use scribble_curves::Time;
+use scribble_project::watermark::{Corner, Watermark};
+
+use crate::captions::CaptionsData;
+use crate::cmd;
+use crate::command_palette;
+use crate::data::{AppState, AspectRatio, Dialog, ProjectSettings, RecordingSpeed, ScribbleState};
+use crate::encode::{
+    parse_encoder_args, ExportFormat, ExportSettings, FrameRate, Poster, RateControl, Resolution,
+    VideoCodec,
+};
+use crate::prefs::{Keymap, Preferences, DEFAULT_KEYBINDINGS, MODAL_KEYBINDINGS};
+use crate::widgets::LabelledContainer;
+
+pub fn make_dialog() -> impl Widget<AppState> {
+    ViewSwitcher::new(
+        // The export dialog shows the presets, so it needs to be rebuilt when they change.
+        |data: &AppState, _env| (data.dialog, data.prefs.clone()),
+        |(dialog, prefs): &(Option<Dialog>, Preferences),
+         _data: &AppState,
+         _env: &Env|
+         -> Box<dyn Widget<AppState>> {
+            match dialog {
+                None => Box::new(SizedBox::empty()),
+                Some(Dialog::ChangeSpeed) => Box::new(make_speed_dialog()),
+                Some(Dialog::Export) => Box::new(make_export_dialog(prefs)),
+                Some(Dialog::RestoreAutosave) => Box::new(make_restore_dialog()),
+                Some(Dialog::ProjectProperties) => Box::new(make_project_dialog()),
+                Some(Dialog::ImportProject) => Box::new(make_import_dialog()),
+                Some(Dialog::UnsavedChanges) => Box::new(make_unsaved_dialog()),
+                Some(Dialog::RelinkAudio) => Box::new(make_relink_dialog()),
+                Some(Dialog::Preferences) => Box::new(make_prefs_dialog(prefs)),
+                Some(Dialog::RestoreSnippet) => Box::new(make_trash_dialog()),
+                Some(Dialog::HistoryBranches) => Box::new(make_branches_dialog()),
+                Some(Dialog::CommandPalette) => Box::new(make_command_palette()),
+                Some(Dialog::Captions) => Box::new(make_captions_dialog()),
+            }
+        },
+    )
+}
+
+/// Wraps the body of a dialog in a labelled frame, together with "cancel" and "ok" buttons.
+fn dialog_frame<W: Widget<AppState> + 'static>(
+    title: &'static str,
+    body: W,
+    ok_label: &'static str,
+    on_ok: impl Fn(&mut EventCtx, &mut AppState, &Env) + 'static,
+) -> impl Widget<AppState> {
+    let ok = Button::new(ok_label).on_click(move |ctx, data: &mut AppState, env| {
+        on_ok(ctx, data, env);
+        ctx.submit_command(cmd::CLOSE_DIALOG, None);
+    });
+    let cancel = Button::new("Cancel").on_click(|ctx, _data: &mut AppState, _env| {
+        ctx.submit_command(cmd::CLOSE_DIALOG, None);
+    });
+
+    let row = Flex::row()
+        .with_child(body)
+        .with_flex_spacer(1.0)
+        .with_child(cancel)
+        .with_spacer(5.0)
+        .with_child(ok)
+        .padding(5.0);
+    LabelledContainer::new(row, title)
+        .border_color(Color::WHITE)
+        .corner_radius(druid::theme::BUTTON_BORDER_RADIUS)
+        .padding(5.0)
+}
+
+fn make_project_dialog() -> impl Widget<AppState> {
+    let settings = || AppState::scribble.then(ScribbleState::settings);
+    let aspect_ratio = RadioGroup::new(
+        AspectRatio::ALL
+            .iter()
+            .map(|&ratio| (ratio.name(), ratio))
+            .collect::<Vec<_>>(),
+    )
+    .lens(settings().then(ProjectSettings::aspect_ratio));
+    let frame_rate = RadioGroup::new(vec![
+        ("24 fps", FrameRate::Fps24),
+        ("30 fps", FrameRate::Fps30),
+        ("60 fps", FrameRate::Fps60),
+    ])
+    .lens(settings().then(ProjectSettings::frame_rate));
+    let recording_speed = RadioGroup::new(vec![
+        ("Slower", RecordingSpeed::Slower),
+        ("Slow", RecordingSpeed::Slow),
+        ("Normal", RecordingSpeed::Normal),
+    ])
+    .lens(settings().then(ProjectSettings::recording_speed));
+    let body = Flex::row()
+        .with_child(
+            Flex::column()
+                .with_child(Label::new("Canvas shape:"))
+                .with_spacer(5.0)
+                .with_child(aspect_ratio),
+        )
+        .with_spacer(10.0)
+        .with_child(
+            Flex::column()
+                .with_child(Label::new("Frame rate:"))
+                .with_spacer(5.0)
+                .with_child(frame_rate),
+        )
+        .with_spacer(10.0)
+        .with_child(
+            Flex::column()
+                .with_child(Label::new("Recording speed:"))
+                .with_spacer(5.0)
+                .with_child(recording_speed),
+        )
+        .with_spacer(10.0)
+        .with_child(make_metadata_options());
+
+    // The settings get edited in place, so all that's left is to apply the ones that are only
+    // defaults for other things.
+    dialog_frame("Project properties", body, "OK", |_ctx, data, _env| {
+        data.recording_speed = data.scribble.settings.recording_speed;
+        data.export.frame_rate = data.scribble.settings.frame_rate;
+        data.push_undo("Change Project Properties");
+    })
+}
+
+fn make_import_dialog() -> impl Widget<AppState> {
+    let label = Label::new(|data: &AppState, _env: &Env| match &data.import_path {
+        Some(path) => format!(
+            "Project: {}",
+            path.file_name().unwrap_or_default().to_string_lossy()
+        ),
+        None => "Project: none".to_owned(),
+    });
+    let choose = Button::new("Choose...").on_click(|ctx, _data: &mut AppState, _env| {
+        // The app delegate knows that projects opened while the import dialog is showing are
+        // meant to be imported.
+        ctx.submit_command(
+            Command::new(
+                druid::commands::SHOW_OPEN_PANEL,
+                FileDialogOptions::new().allowed_types(vec![crate::menus::SCRIBBLE_FILE_TYPE]),
+            ),
+            None,
+        );
+    });
+    let offset_label = Label::new(|data: &AppState, _env: &Env| {
+        format!("Start {:.1}s after the cursor", data.import_offset)
+    });
+    let offset_stepper = Stepper::new()
+        .with_range(0.0, 600.0)
+        .with_step(0.5)
+        .lens(AppState::import_offset);
+    let body = Flex::column()
+        .with_child(
+            Flex::row()
+                .with_child(label)
+                .with_spacer(5.0)
+                .with_child(choose),
+        )
+        .with_spacer(5.0)
+        .with_child(
+            Flex::row()
+                .with_child(offset_label)
+                .with_spacer(5.0)
+                .with_child(offset_stepper),
+        );
+
+    dialog_frame("Import project", body, "Import", |ctx, data, _env| {
+        if let Some(path) = data.import_path.take() {
+            ctx.submit_command(cmd::IMPORT_PROJECT.with((*path).clone()), None);
+        }
+    })
+}
+
+fn make_unsaved_dialog() -> impl Widget<AppState> {
+    let discard = Button::new("Don't save").on_click(|ctx, _data: &mut AppState, _env| {
+        ctx.submit_command(cmd::DISCARD_CHANGES, None);
+        ctx.submit_command(cmd::CLOSE_DIALOG, None);
+    });
+    let body = Flex::row()
+        .with_child(Label::new("This project has unsaved changes."))
+        .with_spacer(10.0)
+        .with_child(discard);
+    dialog_frame("Unsaved changes", body, "Save", |ctx, _data, _env| {
+        ctx.submit_command(cmd::SAVE_CHANGES, None);
+    })
+}
+
+fn make_relink_dialog() -> impl Widget<AppState> {
+    let label = Label::new(|data: &AppState, _env: &Env| {
+        match data.scribble.audio_snippets.first_missing_link() {
+            Some(path) => format!("Couldn't find the audio file {}", path.display()),
+            None => "All the audio files were found.".to_owned(),
+        }
+    });
+    let choose = Button::new("Find it...").on_click(|ctx, _data: &mut AppState, _env| {
+        // The app delegate knows that audio files opened while this dialog is showing are meant
+        // to replace the missing one.
+        ctx.submit_command(
+            Command::new(
+                druid::commands::SHOW_OPEN_PANEL,
+                FileDialogOptions::new().allowed_types(vec![crate::menus::AUDIO_FILE_TYPE]),
+            ),
+            None,
+        );
+    });
+    let body = Flex::row()
+        .with_child(label)
+        .with_spacer(5.0)
+        .with_child(choose);
+    // Closing the dialog without finding the file leaves the snippets silent.
+    dialog_frame("Missing audio", body, "Skip", |_ctx, _data, _env| {})
+}
+
+fn make_trash_dialog() -> impl Widget<AppState> {
+    let list = ViewSwitcher::new(
+        |data: &AppState, _env| data.scribble.trash_labels(),
+        |labels: &Vec<String>, _data: &AppState, _env: &Env| -> Box<dyn Widget<AppState>> {
+            if labels.is_empty() {
+                return Box::new(Label::new("There are no deleted snippets."));
+            }
+            let mut col = Flex::column().cross_axis_alignment(CrossAxisAlignment::End);
+            for (idx, label) in labels.iter().enumerate() {
+                let restore =
+                    Button::new("Restore").on_click(move |ctx, _data: &mut AppState, _env| {
+                        ctx.submit_command(cmd::RESTORE_SNIPPET.with(idx), None);
+                    });
+                col.add_child(
+                    Flex::row()
+                        .with_child(Label::new(label.as_str()))
+                        .with_spacer(5.0)
+                        .with_child(restore),
+                );
+            }
+            Box::new(Scroll::new(col).vertical().fix_height(150.0))
+        },
+    );
+    dialog_frame("Deleted snippets", list, "Done", |_ctx, _data, _env| {})
+}
+
+fn make_branches_dialog() -> impl Widget<AppState> {
+    let list = ViewSwitcher::new(
+        |data: &AppState, _env| {
+            data.undo
+                .borrow()
+                .branches()
+                .iter()
+                .map(|b| {
+                    let edits = if b.edits == 1 { "edit" } else { "edits" };
+                    let mins = b.abandoned.elapsed().as_secs() / 60;
+                    if mins == 0 {
+                        format!("{} {}, abandoned just now", b.edits, edits)
+                    } else {
+                        format!("{} {}, abandoned {} min ago", b.edits, edits, mins)
+                    }
+                })
+                .collect::<Vec<_>>()
+        },
+        |labels: &Vec<String>, _data: &AppState, _env: &Env| -> Box<dyn Widget<AppState>> {
+            if labels.is_empty() {
+                return Box::new(Label::new("There are no other branches."));
+            }
+            let mut col = Flex::column().cross_axis_alignment(CrossAxisAlignment::End);
+            for (idx, label) in labels.iter().enumerate() {
+                let switch =
+                    Button::new("Switch").on_click(move |ctx, _data: &mut AppState, _env| {
+                        ctx.submit_command(cmd::SWITCH_BRANCH.with(idx), None);
+                    });
+                col.add_child(
+                    Flex::row()
+                        .with_child(Label::new(label.as_str()))
+                        .with_spacer(5.0)
+                        .with_child(switch),
+                );
+            }
+            Box::new(Scroll::new(col).vertical().fix_height(150.0))
+        },
+    );
+    dialog_frame("History branches", list, "Done", |_ctx, _data, _env| {})
+}
+
+fn make_captions_dialog() -> impl Widget<AppState> {
+    let list = ViewSwitcher::new(
+        |data: &AppState, _env| data.edited_captions.captions().len(),
+        |&len: &usize, _data: &AppState, _env: &Env| -> Box<dyn Widget<AppState>> {
+            if len == 0 {
+                return Box::new(Label::new(
+                    "There are no captions. You can import them, or transcribe the narration.",
+                ));
+            }
+            let mut col = Flex::column().cross_axis_alignment(CrossAxisAlignment::End);
+            for idx in 0..len {
+                col.add_child(make_caption_row(idx));
+                col.add_spacer(5.0);
+            }
+            Box::new(Scroll::new(col).vertical().fix_height(150.0))
+        },
+    );
+    let transcribe =
+        Button::new("Transcribe narration").on_click(|ctx, _data: &mut AppState, _env| {
+            ctx.submit_command(cmd::TRANSCRIBE, None);
+        });
+    let status = Label::new(|data: &AppState, _env: &Env| {
+        if data.transcribing {
+            "Transcribing...".to_owned()
+        } else if data.prefs.whisper_model.is_empty() {
+            "(Transcribing needs a speech-to-text model in the preferences.)".to_owned()
+        } else {
+            String::new()
+        }
+    });
+    let body = Flex::column()
+        .cross_axis_alignment(CrossAxisAlignment::Start)
+        .with_child(list)
+        .with_spacer(5.0)
+        .with_child(
+            Flex::row()
+                .with_child(transcribe)
+                .with_spacer(5.0)
+                .with_child(status),
+        );
+
+    // The captions get edited in a copy, which only replaces the project's captions here.
+    dialog_frame("Captions", body, "OK", |_ctx, data, _env| {
+        if !data.edited_captions.same(&data.scribble.captions) {
+            data.scribble.captions = data.edited_captions.clone();
+            data.push_undo("Edit Captions");
+        }
+    })
+}
+
+// One line of the captions dialog, for editing the `idx`th caption.
+fn make_caption_row(idx: usize) -> impl Widget<AppState> {
+    let times = Label::new(move |data: &AppState, _env: &Env| {
+        let secs = |t: Time| t.as_micros() as f64 / 1e6;
+        match data.edited_captions.captions().get(idx) {
+            Some(c) => format!("{:.1}s to {:.1}s", secs(c.start), secs(c.end)),
+            None => String::new(),
+        }
+    });
+    let text = TextBox::new()
+        .lens(AppState::edited_captions.map(
+            move |c: &CaptionsData| {
+                c.captions()
+                    .get(idx)
+                    .map(|c| c.text.clone())
+                    .unwrap_or_default()
+            },
+            move |c: &mut CaptionsData, text: String| c.set_text(idx, text),
+        ))
+        .fix_width(300.0);
+    let go = Button::new("Go").on_click(move |ctx, data: &mut AppState, _env| {
+        if let Some(c) = data.edited_captions.captions().get(idx) {
+            ctx.submit_command(cmd::WARP_TIME_TO.with(c.start), None);
+        }
+    });
+    let start = Button::new("Start here").on_click(move |_ctx, data: &mut AppState, _env| {
+        let time = data.time();
+        data.edited_captions.set_start(idx, time);
+    });
+    let end = Button::new("End here").on_click(move |_ctx, data: &mut AppState, _env| {
+        let time = data.time();
+        data.edited_captions.set_end(idx, time);
+    });
+    let delete = Button::new("Delete").on_click(move |_ctx, data: &mut AppState, _env| {
+        data.edited_captions.remove(idx);
+    });
+    Flex::row()
+        .with_child(times)
+        .with_spacer(5.0)
+        .with_child(text)
+        .with_spacer(5.0)
+        .with_child(go)
+        .with_spacer(5.0)
+        .with_child(start)
+        .with_spacer(5.0)
+        .with_child(end)
+        .with_spacer(5.0)
+        .with_child(delete)
+}
+
+fn make_command_palette() -> impl Widget<AppState> {
+    let query = TextBox::new()
+        .lens(AppState::command_query)
+        .fix_width(300.0);
+    let list = ViewSwitcher::new(
+        |data: &AppState, _env| {
+            command_palette::matching(&data.command_query, data)
+                .iter()
+                .map(|c| c.label(data))
+                .collect::<Vec<_>>()
+        },
+        |labels: &Vec<String>, data: &AppState, _env: &Env| -> Box<dyn Widget<AppState>> {
+            if labels.is_empty() {
+                return Box::new(Label::new("No commands match."));
+            }
+            let mut col = Flex::column().cross_axis_alignment(CrossAxisAlignment::Start);
+            for (c, label) in command_palette::matching(&data.command_query, data)
+                .into_iter()
+                .zip(labels)
+            {
+                let command = c.command;
+                let run =
+                    Button::new(label.as_str()).on_click(move |ctx, _data: &mut AppState, _env| {
+                        ctx.submit_command(cmd::CLOSE_DIALOG, None);
+                        ctx.submit_command(command.clone(), None);
+                    });
+                col.add_child(run);
+            }
+            Box::new(Scroll::new(col).vertical().fix_height(150.0))
+        },
+    );
+    let body = Flex::column()
+        .cross_axis_alignment(CrossAxisAlignment::Start)
+        .with_child(query)
+        .with_spacer(5.0)
+        .with_child(list);
+
+    // "Run" runs the best match.
+    dialog_frame("Command palette", body, "Run", |ctx, data, _env| {
+        if let Some(c) = command_palette::matching(&data.command_query, data)
+            .into_iter()
+            .next()
+        {
+            ctx.submit_command(c.command, None);
+        }
+    })
+}
+
+fn make_prefs_dialog(prefs: &Preferences) -> impl Widget<AppState> {
+    let edited = || AppState::edited_prefs;
+    let autosave_label = Label::new(|data: &AppState, _env: &Env| {
+        let secs = data.edited_prefs.autosave_secs;
+        if secs <= 0.0 {
+            "Autosave: off".to_owned()
+        } else {
+            format!("Autosave every {:.0}s", secs)
+        }
+    });
+    let autosave_stepper = Stepper::new()
+        .with_range(0.0, 3600.0)
+        .with_step(10.0)
+        .lens(edited().then(Preferences::autosave_secs));
+    let backups_label = Label::new(|data: &AppState, _env: &Env| {
+        format!("Keep {} backups", data.edited_prefs.backups)
+    });
+    let backups_stepper = Stepper::new().with_range(0.0, 20.0).with_step(1.0).lens(
+        edited()
+            .then(Preferences::backups)
+            .map(|b| *b as f64, |b, x| *b = x.round() as u32),
+    );
+    let binary_format = Checkbox::new("Save in compact binary format").lens(
+        edited().then(Preferences::save_format).map(
+            |f| *f == SaveFormat::Binary,
+            |f, binary| {
+                *f = if binary {
+                    SaveFormat::Binary
+                } else {
+                    SaveFormat::Json
+                }
+            },
+        ),
+    );
+    let link_audio =
+        Checkbox::new("Link imported audio files").lens(edited().then(Preferences::link_audio));
+    let draw_on = Checkbox::new("Draw imported drawings stroke by stroke")
+        .lens(edited().then(Preferences::draw_on_imported_drawings));
+    let save_undo_history = Checkbox::new("Save the undo history with projects")
+        .lens(edited().then(Preferences::save_undo_history));
+    let fade_in_images =
+        Checkbox::new("Fade in new images").lens(edited().then(Preferences::fade_in_images));
+    let show_pen =
+        Checkbox::new("Show the pen position").lens(edited().then(Preferences::show_pen));
+    let touch_pans = Checkbox::new("Pan with one finger on a touchscreen")
+        .lens(edited().then(Preferences::touch_pans));
+    let general = Flex::column()
+        .with_child(
+            Flex::row()
+                .with_child(autosave_label)
+                .with_spacer(5.0)
+                .with_child(autosave_stepper),
+        )
+        .with_spacer(5.0)
+        .with_child(
+            Flex::row()
+                .with_child(backups_label)
+                .with_spacer(5.0)
+                .with_child(backups_stepper),
+        )
+        .with_spacer(5.0)
+        .with_child(binary_format)
+        .with_spacer(5.0)
+        .with_child(link_audio)
+        .with_spacer(5.0)
+        .with_child(draw_on)
+        .with_spacer(5.0)
+        .with_child(save_undo_history)
+        .with_spacer(5.0)
+        .with_child(fade_in_images)
+        .with_spacer(5.0)
+        .with_child(show_pen)
+        .with_spacer(5.0)
+        .with_child(touch_pans);
+
+    // An empty name means the system's default device (or the built-in export settings).
+    let choices = |default: &str, names: Vec<String>| {
+        let mut ret = vec![(default.to_owned(), String::new())];
+        ret.extend(names.into_iter().map(|name| (name.clone(), name)));
+        ret
+    };
+    let input = RadioGroup::new(choices(
+        "System default",
+        crate::audio::input_device_names(),
+    ))
+    .lens(edited().then(Preferences::input_device));
+    let output = RadioGroup::new(choices(
+        "System default",
+        crate::audio::output_device_names(),
+    ))
+    .lens(edited().then(Preferences::output_device));
+    let preset_names = prefs
+        .export_presets
+        .iter()
+        .map(|p| p.name.clone())
+        .collect();
+    let preset = RadioGroup::new(choices("Built-in settings", preset_names))
+        .lens(edited().then(Preferences::default_export_preset));
+
+    let keymap = RadioGroup::new(vec![
+        ("Standard", Keymap::Standard),
+        ("Vim-style (modal)", Keymap::Modal),
+    ])
+    .lens(edited().then(Preferences::keymap));
+
+    // Shortcuts are edited as text, like "Cmd+Shift+O". An empty one means no shortcut. The
+    // "normal-" ones only matter in the modal keymap.
+    let mut shortcuts = Flex::column().cross_axis_alignment(CrossAxisAlignment::End);
+    for (action, _) in DEFAULT_KEYBINDINGS.iter().chain(MODAL_KEYBINDINGS) {
+        let key = TextBox::new().lens(edited().map(
+            move |p| p.keybinding(action),
+            move |p, key| p.set_keybinding(action, key),
+        ));
+        shortcuts.add_child(
+            Flex::row()
+                .with_child(Label::new(*action))
+                .with_spacer(5.0)
+                .with_child(key.fix_width(120.0)),
+        );
+    }
+    // Tablet drivers report the buttons on the pen's barrel as mouse buttons.
+    let mut buttons = Flex::column().cross_axis_alignment(CrossAxisAlignment::End);
+    for &(label, button) in &[
+        ("Right button / lower pen button", "Right"),
+        ("Middle button / upper pen button", "Middle"),
+        ("Back button", "Back"),
+        ("Forward button", "Forward"),
+    ] {
+        buttons.add_child(labelled_text_box(
+            label,
+            edited().map(
+                move |p| p.mouse_binding(button),
+                move |p, action| {
+                    if let Err(e) = p.set_mouse_binding(button, action) {
+                        log::error!("couldn't bind {:?}: {}", button, e);
+                    }
+                },
+            ),
+        ));
+    }
+    let problems = Label::new(|data: &AppState, _env: &Env| {
+        data.edited_prefs.keybinding_problems().join("\n")
+    });
+    let keybindings = Flex::column()
+        .with_child(Label::new("Keyboard shortcuts:"))
+        .with_spacer(5.0)
+        .with_child(keymap)
+        .with_spacer(5.0)
+        .with_child(Scroll::new(shortcuts).vertical().fix_height(200.0))
+        .with_spacer(5.0)
+        .with_child(Label::new("Pen and mouse buttons:"))
+        .with_spacer(5.0)
+        .with_child(buttons)
+        .with_spacer(5.0)
+        .with_child(problems)
+        .with_spacer(5.0)
+        .with_child(labelled_text_box(
+            "Global draw key",
+            edited().then(Preferences::global_draw_key),
+        ))
+        .with_spacer(5.0)
+        .with_child(labelled_text_box(
+            "Global talk key",
+            edited().then(Preferences::global_talk_key),
+        ))
+        .with_spacer(5.0)
+        .with_child(labelled_text_box(
+            "Foot pedal (USB id)",
+            edited().then(Preferences::pedal_device),
+        ))
+        .with_spacer(5.0)
+        .with_child(labelled_text_box(
+            "Remote control port",
+            edited().then(Preferences::remote_control_port).map(
+                |port| match port {
+                    0 => String::new(),
+                    p => p.to_string(),
+                },
+                |port, s| *port = s.trim().parse().unwrap_or(0),
+            ),
+        ))
+        .with_spacer(5.0)
+        .with_child(labelled_text_box(
+            "Drawing tablet port",
+            edited().then(Preferences::pen_server_port).map(
+                |port| match port {
+                    0 => String::new(),
+                    p => p.to_string(),
+                },
+                |port, s| *port = s.trim().parse().unwrap_or(0),
+            ),
+        ))
+        .with_spacer(5.0)
+        .with_child(labelled_text_box(
+            "Drawing tablet password",
+            edited().then(Preferences::pen_server_password),
+        ))
+        .with_spacer(5.0)
+        .with_child(labelled_text_box(
+            "Live output device",
+            edited().then(Preferences::live_output_target),
+        ))
+        .with_spacer(5.0)
+        .with_child(labelled_text_box(
+            "Collaboration port",
+            edited().then(Preferences::collab_port).map(
+                |port| match port {
+                    0 => String::new(),
+                    p => p.to_string(),
+                },
+                |port, s| *port = s.trim().parse().unwrap_or(0),
+            ),
+        ))
+        .with_spacer(5.0)
+        .with_child(labelled_text_box(
+            "Host collaboration on",
+            edited().then(Preferences::collab_interface),
+        ))
+        .with_spacer(5.0)
+        .with_child(labelled_text_box(
+            "Join collaboration at",
+            edited().then(Preferences::collab_address),
+        ))
+        .with_spacer(5.0)
+        .with_child(labelled_text_box(
+            "Collaboration password",
+            edited().then(Preferences::collab_password),
+        ))
+        .with_spacer(5.0)
+        .with_child(
+            Checkbox::new("Follow MIDI timecode")
+                .lens(edited().then(Preferences::follow_midi_sync)),
+        )
+        .with_spacer(5.0)
+        .with_child(labelled_text_box(
+            "Send MIDI timecode to",
+            edited().then(Preferences::midi_sync_output),
+        ))
+        .with_spacer(5.0)
+        .with_child(labelled_text_box(
+            "Speech-to-text model",
+            edited().then(Preferences::whisper_model),
+        ))
+        .with_spacer(5.0)
+        .with_child(labelled_text_box(
+            "Narration language",
+            edited().then(Preferences::transcription_language),
+        ));
+
+    let body = Flex::row()
+        .with_child(general)
+        .with_spacer(10.0)
+        .with_child(
+            Flex::column()
+                .with_child(Label::new("Record from:"))
+                .with_spacer(5.0)
+                .with_child(input),
+        )
+        .with_spacer(10.0)
+        .with_child(
+            Flex::column()
+                .with_child(Label::new("Play to:"))
+                .with_spacer(5.0)
+                .with_child(output),
+        )
+        .with_spacer(10.0)
+        .with_child(
+            Flex::column()
+                .with_child(Label::new("Export preset to start with:"))
+                .with_spacer(5.0)
+                .with_child(preset),
+        )
+        .with_spacer(10.0)
+        .with_child(keybindings);
+
+    dialog_frame("Preferences", body, "Apply", |ctx, data, _env| {
+        ctx.submit_command(cmd::SET_PREFERENCES.with(data.edited_prefs.clone()), None);
+    })
+}
+
+/// A text box with a label in front of it.
+fn labelled_text_box(
+    label: &str,
+    lens: impl Lens<AppState, String> + 'static,
+) -> impl Widget<AppState> {
+    Flex::row()
+        .with_child(Label::new(label.to_owned()))
+        .with_spacer(5.0)
+        .with_child(TextBox::new().lens(lens).fix_width(120.0))
+}
+
+fn make_restore_dialog() -> impl Widget<AppState> {
+    let body = Label::new(
+        "It looks like scribble didn't shut down properly. \
+         Do you want to restore the autosaved version of this project?",
+    );
+    dialog_frame("Restore autosave", body, "Restore", |ctx, _data, _env| {
+        ctx.submit_command(cmd::RESTORE_AUTOSAVE, None);
+    })
+}
+
+fn make_speed_dialog() -> impl Widget<AppState> {
+    let label = Label::new(|data: &AppState, _env: &Env| {
+        format!("Play the snippet {:.2} times as fast", data.speed_factor)
+    });
+    let stepper = Stepper::new()
+        .with_range(0.25, 8.0)
+        .with_step(0.25)
+        .lens(AppState::speed_factor);
+    let body = Flex::row()
+        .with_child(label)
+        .with_spacer(5.0)
+        .with_child(stepper);
+
+    dialog_frame("Change speed", body, "Apply", |ctx, data, _env| {
+        ctx.submit_command(cmd::CHANGE_SPEED.with(data.speed_factor), None);
+    })
+}
+
+fn make_export_dialog(prefs: &Preferences) -> impl Widget<AppState> {
+    let mut presets = Flex::column().with_child(Label::new("Presets:"));
+    for preset in prefs.export_presets.iter() {
+        let settings = preset.settings.clone();
+        presets.add_spacer(5.0);
+        presets.add_child(Button::new(preset.name.as_str()).on_click(
+            move |_ctx, data: &mut AppState, _env| {
+                data.export = settings.clone();
+            },
+        ));
+    }
+    let save_preset = Button::new("Save preset").on_click(|_ctx, data: &mut AppState, _env| {
+        let name = data.preset_name.trim();
+        if name.is_empty() {
+            return;
+        }
+        data.prefs = data
+            .prefs
+            .with_export_preset(name.to_owned(), data.export.clone());
+        if let Err(e) = data.prefs.save() {
+            log::error!("error saving preferences: {}", e);
+        }
+    });
+    presets.add_spacer(5.0);
+    presets.add_child(
+        Flex::row()
+            .with_child(TextBox::new().lens(AppState::preset_name))
+            .with_spacer(5.0)
+            .with_child(save_preset),
+    );
+
+    let format = RadioGroup::new(vec![
+        ("Video (mp4)", ExportFormat::Video),
+        ("WebM", ExportFormat::WebM),
+        ("Animated GIF", ExportFormat::Gif),
+        ("PNG sequence", ExportFormat::PngSequence),
+        ("Animated SVG", ExportFormat::Svg),
+        ("AV1, built-in encoder (no audio)", ExportFormat::Ivf),
+        ("Narration audio only", ExportFormat::Audio),
+        ("PDF handout of key frames", ExportFormat::Pdf),
+        ("Web page with a vector player", ExportFormat::Html),
+    ])
+    .lens(AppState::export.then(ExportSettings::format));
+    let resolution = RadioGroup::new(vec![
+        ("720p", Resolution::P720),
+        ("1080p", Resolution::P1080),
+        ("4K", Resolution::P2160),
+        ("Custom", Resolution::Custom),
+    ])
+    .lens(AppState::export.then(ExportSettings::resolution));
+    let format_options = ViewSwitcher::new(
+        |data: &AppState, _env| data.export.format,
+        |format: &ExportFormat, _data: &AppState, _env: &Env| -> Box<dyn Widget<AppState>> {
+            match format {
+                ExportFormat::Video => Box::new(
+                    Flex::row()
+                        .with_child(make_frame_rate_options())
+                        .with_spacer(10.0)
+                        .with_child(make_quality_options())
+                        .with_spacer(10.0)
+                        .with_child(make_encoder_args_options()),
+                ),
+                // The built-in encoder doesn't use gstreamer, so it doesn't take extra properties.
+                ExportFormat::Ivf => Box::new(
+                    Flex::row()
+                        .with_child(make_frame_rate_options())
+                        .with_spacer(10.0)
+                        .with_child(make_quality_options()),
+                ),
+                ExportFormat::PngSequence => Box::new(make_frame_rate_options()),
+                ExportFormat::WebM => Box::new(make_webm_options()),
+                ExportFormat::Gif => Box::new(make_gif_options()),
+                ExportFormat::Svg
+                | ExportFormat::Audio
+                | ExportFormat::Pdf
+                | ExportFormat::Html => Box::new(SizedBox::empty()),
+            }
+        },
+    );
+    let custom_label = Label::new(|data: &AppState, _env: &Env| {
+        format!("Custom: {:.0}p", data.export.custom_resolution)
+    });
+    let custom_stepper = Stepper::new()
+        .with_range(16.0, 4320.0)
+        .with_step(16.0)
+        .lens(AppState::export.then(ExportSettings::custom_resolution));
+    let selection_only = Checkbox::new("Only the selected snippet")
+        .lens(AppState::export.then(ExportSettings::selection_only));
+    let size_label = Label::new(|data: &AppState, _env: &Env| {
+        let (width, height) = data.export.video_size(data.scribble.settings.aspect_ratio);
+        format!("Output size: {}x{}", width, height)
+    });
+    let body = Flex::row()
+        .with_child(presets)
+        .with_spacer(10.0)
+        .with_child(format)
+        .with_spacer(10.0)
+        .with_child(resolution)
+        .with_spacer(10.0)
+        .with_child(format_options)
+        .with_spacer(10.0)
+        .with_child(
+            Flex::column()
+                .with_child(
+                    Flex::row()
+                        .with_child(custom_label)
+                        .with_spacer(5.0)
+                        .with_child(custom_stepper),
+                )
+                .with_spacer(5.0)
+                .with_child(size_label)
+                .with_spacer(5.0)
+                .with_child(selection_only),
+        )
+        .with_spacer(10.0)
+        .with_child(make_speed_options())
+        .with_spacer(10.0)
+        .with_child(make_poster_options())
+        .with_spacer(10.0)
+        .with_child(make_watermark_options())
+        .with_spacer(10.0)
+        .with_child(make_metadata_options());
+
+    // Note that we're reusing the SHOW_SAVE_PANEL command for exporting. There doesn't appear to
+    // be another way to get the system file dialog.
+    dialog_frame("Export", body, "Export...", |ctx, data, _env| {
+        ctx.submit_command(
+            Command::new(
+                druid::commands::SHOW_SAVE_PANEL,
+                FileDialogOptions::new().allowed_types(vec![data.export.format.file_spec()]),
+            ),
+            None,
+        );
+    })
+}
+
+fn make_poster_options() -> impl Widget<AppState> {
+    let poster = RadioGroup::new(vec![
+        ("No thumbnail", Poster::Off),
+        ("Thumbnail of the last frame", Poster::End),
+        ("Thumbnail at the cursor", Poster::Cursor),
+    ])
+    .lens(AppState::export.then(ExportSettings::poster));
+    let captions = Checkbox::new("Save the captions (.srt)")
+        .lens(AppState::export.then(ExportSettings::captions));
+    Flex::column()
+        .with_child(Label::new("Thumbnail (.png):"))
+        .with_spacer(5.0)
+        .with_child(poster)
+        .with_spacer(10.0)
+        .with_child(captions)
+}
+
+fn make_speed_options() -> impl Widget<AppState> {
+    let speed_label =
+        Label::new(|data: &AppState, _env: &Env| format!("Speed: {:.2}x", data.export.speed));
+    let speed_stepper = Stepper::new()
+        .with_range(0.25, 8.0)
+        .with_step(0.25)
+        .lens(AppState::export.then(ExportSettings::speed));
+    let skip_silence = Checkbox::new("Speed up silences")
+        .lens(AppState::export.then(ExportSettings::skip_silence));
+    let silence_label = Label::new(|data: &AppState, _env: &Env| {
+        format!("Silences: {:.0}x", data.export.silence_speed)
+    });
+    let silence_stepper = Stepper::new()
+        .with_range(1.0, 16.0)
+        .with_step(1.0)
+        .lens(AppState::export.then(ExportSettings::silence_speed));
+    Flex::column()
+        .with_child(
+            Flex::row()
+                .with_child(speed_label)
+                .with_spacer(5.0)
+                .with_child(speed_stepper),
+        )
+        .with_spacer(5.0)
+        .with_child(skip_silence)
+        .with_spacer(5.0)
+        .with_child(
+            Flex::row()
+                .with_child(silence_label)
+                .with_spacer(5.0)
+                .with_child(silence_stepper),
+        )
+}
+
+// The watermark is part of the project settings (not the export settings), because it's more
+// likely to belong with the project than with the choice of output format.
+fn make_watermark_options() -> impl Widget<AppState> {
+    let watermark = || {
+        AppState::scribble
+            .then(ScribbleState::settings)
+            .then(ProjectSettings::watermark)
+    };
+    let choose = Button::new("Choose...").on_click(|ctx, _data: &mut AppState, _env| {
+        // The app delegate knows that images opened while the export dialog is showing are
+        // meant to be watermarks.
+        let image_type = FileSpec::new("Image", crate::images::IMAGE_EXTENSIONS);
+        ctx.submit_command(
+            Command::new(
+                druid::commands::SHOW_OPEN_PANEL,
+                FileDialogOptions::new().allowed_types(vec![image_type]),
+            ),
+            None,
+        );
+    });
+    let remove = Button::new("Remove").on_click(|ctx, _data: &mut AppState, _env| {
+        ctx.submit_command(cmd::SET_WATERMARK.with(None), None);
+    });
+    let label = Label::new(|data: &Watermark, _env: &Env| {
+        if data.encoded.is_some() {
+            "Watermark:".to_owned()
+        } else {
+            "Watermark: none".to_owned()
+        }
+    })
+    .lens(watermark());
+    let corner = RadioGroup::new(vec![
+        ("Top left", Corner::TopLeft),
+        ("Top right", Corner::TopRight),
+        ("Bottom left", Corner::BottomLeft),
+        ("Bottom right", Corner::BottomRight),
+    ])
+    .lens(watermark().then(Watermark::corner));
+    let scale_label =
+        Label::new(|data: &Watermark, _env: &Env| format!("Size: {:.0}%", data.scale * 100.0))
+            .lens(watermark());
+    let scale_stepper = Stepper::new()
+        .with_range(0.05, 1.0)
+        .with_step(0.05)
+        .lens(watermark().then(Watermark::scale));
+    let opacity_label =
+        Label::new(|data: &Watermark, _env: &Env| format!("Opacity: {:.0}%", data.opacity * 100.0))
+            .lens(watermark());
+    let opacity_stepper = Stepper::new()
+        .with_range(0.05, 1.0)
+        .with_step(0.05)
+        .lens(watermark().then(Watermark::opacity));
+    Flex::column()
+        .with_child(
+            Flex::row()
+                .with_child(label)
+                .with_spacer(5.0)
+                .with_child(choose)
+                .with_spacer(5.0)
+                .with_child(remove),
+        )
+        .with_spacer(5.0)
+        .with_child(corner)
+        .with_spacer(5.0)
+        .with_child(
+            Flex::row()
+                .with_child(scale_label)
+                .with_spacer(5.0)
+                .with_child(scale_stepper),
+        )
+        .with_spacer(5.0)
+        .with_child(
+            Flex::row()
+                .with_child(opacity_label)
+                .with_spacer(5.0)
+                .with_child(opacity_stepper),
+        )
+}
+
+// The title and author are project settings, but they're only used when exporting.
+fn make_metadata_options() -> impl Widget<AppState> {
+    let settings = || AppState::scribble.then(ScribbleState::settings);
+    Flex::column()
+        .with_child(Label::new("Title:"))
+        .with_spacer(5.0)
+        .with_child(TextBox::new().lens(settings().then(ProjectSettings::title)))
+        .with_spacer(5.0)
+        .with_child(Label::new("Author:"))
+        .with_spacer(5.0)
+        .with_child(TextBox::new().lens(settings().then(ProjectSettings::author)))
+}
+
+fn make_frame_rate_options() -> impl Widget<AppState> {
+    RadioGroup::new(vec![
+        ("24 fps", FrameRate::Fps24),
+        ("30 fps", FrameRate::Fps30),
+        ("60 fps", FrameRate::Fps60),
+    ])
+    .lens(AppState::export.then(ExportSettings::frame_rate))
+}
+
+fn make_quality_options() -> impl Widget<AppState> {
+    let rate_control = RadioGroup::new(vec![
+        ("Constant quality", RateControl::Quality),
+        ("Target bitrate", RateControl::Bitrate),
+    ])
+    .lens(AppState::export.then(ExportSettings::rate_control));
+    let amount = ViewSwitcher::new(
+        |data: &AppState, _env| data.export.rate_control,
+        |rate_control: &RateControl, _data: &AppState, _env: &Env| -> Box<dyn Widget<AppState>> {
+            match rate_control {
+                RateControl::Quality => {
+                    let label = Label::new(|data: &AppState, _env: &Env| {
+                        format!("Quality: {:.0} (lower is better)", data.export.quality)
+                    });
+                    let stepper = Stepper::new()
+                        .with_range(0.0, 63.0)
+                        .with_step(1.0)
+                        .lens(AppState::export.then(ExportSettings::quality));
+                    Box::new(
+                        Flex::row()
+                            .with_child(label)
+                            .with_spacer(5.0)
+                            .with_child(stepper),
+                    )
+                }
+                RateControl::Bitrate => {
+                    let label = Label::new(|data: &AppState, _env: &Env| {
+                        format!("{:.0} kbit/s", data.export.bitrate_kbps)
+                    });
+                    let stepper = Stepper::new()
+                        .with_range(100.0, 50000.0)
+                        .with_step(100.0)
+                        .lens(AppState::export.then(ExportSettings::bitrate_kbps));
+                    Box::new(
+                        Flex::row()
+                            .with_child(label)
+                            .with_spacer(5.0)
+                            .with_child(stepper),
+                    )
+                }
+            }
+        },
+    );
+    let two_pass =
+        Checkbox::new("Two-pass (VP9 only)").lens(AppState::export.then(ExportSettings::two_pass));
+    Flex::column()
+        .with_child(rate_control)
+        .with_spacer(5.0)
+        .with_child(amount)
+        .with_spacer(5.0)
+        .with_child(two_pass)
+}
+
+fn make_encoder_args_options() -> impl Widget<AppState> {
+    let args = TextBox::new().lens(AppState::export.then(ExportSettings::encoder_args));
+    let error = Label::new(|data: &AppState, _env: &Env| {
+        match parse_encoder_args(&data.export.encoder_args) {
+            Ok(_) => String::new(),
+            Err(e) => e.to_string(),
+        }
+    });
+    Flex::column()
+        .with_child(Label::new("Extra encoder properties:"))
+        .with_spacer(5.0)
+        .with_child(args)
+        .with_spacer(5.0)
+        .with_child(error)
+}
+
+fn make_webm_options() -> impl Widget<AppState> {
+    let codec = RadioGroup::new(vec![("VP9", VideoCodec::Vp9), ("AV1", VideoCodec::Av1)])
+        .lens(AppState::export.then(ExportSettings::codec));
+    Flex::row()
+        .with_child(make_frame_rate_options())
+        .with_spacer(10.0)
+        .with_child(codec)
+        .with_spacer(10.0)
+        .with_child(make_quality_options())
+        .with_spacer(10.0)
+        .with_child(make_encoder_args_options())
+}
+
+fn make_gif_options() -> impl Widget<AppState> {
+    let fps_label =
+        Label::new(|data: &AppState, _env: &Env| format!("{:.0} fps", data.export.gif_fps));
+    let fps_stepper = Stepper::new()
+        .with_range(1.0, 50.0)
+        .with_step(1.0)
+        .lens(AppState::export.then(ExportSettings::gif_fps));
+    let repeat_label = Label::new(|data: &AppState, _env: &Env| {
+        if data.export.gif_repeats == 0.0 {
+            "Repeat forever".to_owned()
+        } else {
+            format!("Repeat {:.0} times", data.export.gif_repeats)
+        }
+    });
+    let repeat_stepper = Stepper::new()
+        .with_range(0.0, 100.0)
+        .with_step(1.0)
+        .lens(AppState::export.then(ExportSettings::gif_repeats));
+    Flex::column()
+        .with_child(
+            Flex::row()
+                .with_child(fps_label)
+                .with_spacer(5.0)
+                .with_child(fps_stepper),
+        )
+        .with_spacer(5.0)
+        .with_child(
+            Flex::row()
+                .with_child(repeat_label)
+                .with_spacer(5.0)
+                .with_child(repeat_stepper),
+        )
+}
@@ -1,27 +1,126 @@
+use druid::kurbo::{Line, Shape};
+use druid::piet::{FontBuilder, Piet, StrokeStyle, Text, TextLayout, TextLayoutBuilder};
 use druid::{
-    Affine, BoxConstraints, Color, Command, Data, Env, Event, EventCtx, LayoutCtx, LifeCycle,
-    LifeCycleCtx, PaintCtx, Point, Rect, RenderContext, Size, UpdateCtx, Vec2, Widget,
+    Affine, BoxConstraints, Color, Data, Env, Event, EventCtx, LayoutCtx, LifeCycle, LifeCycleCtx,
+    PaintCtx, Point, Rect, RenderContext, Size, UpdateCtx, Vec2, Widget,
 };
 
 use scribble_curves::SnippetsCursor;
 
+use super::stroke_cache::StrokeCache;
 use crate::cmd;
-use crate::data::{AppState, CurrentAction};
+use crate::data::{AppState, CurrentAction, MirrorMode};
+use crate::images::ImageCache;
+use crate::touch::{self, Pointer};
 
-// The drawing coordinates are chosen so that the width of the image is always
-// 1.0. For now we also fix the height, but eventually we will support other aspect
-// ratios.
-pub const DRAWING_WIDTH: f64 = 1.0;
-pub const DRAWING_HEIGHT: f64 = 0.75;
+pub use scribble_project::settings::DRAWING_WIDTH;
 
-const ASPECT_RATIO: f64 = DRAWING_WIDTH / DRAWING_HEIGHT;
-const PAPER_COLOR: Color = Color::rgb8(0xff, 0xff, 0xff);
 const PAPER_BDY_COLOR: Color = Color::rgb8(0x00, 0x00, 0x00);
 const PAPER_BDY_THICKNESS: f64 = 1.0;
+const MIRROR_AXIS_COLOR: Color = Color::rgb8(0x99, 0x99, 0xdd);
+const MIRROR_AXIS_THICKNESS: f64 = 1.0;
+// How close (in pixels) the mouse needs to be to the mirror axis in order to drag it.
+const MIRROR_AXIS_GRAB_DISTANCE: f64 = 5.0;
+// How close (in pixels) the mouse needs to be to a drawing in order to erase it.
+const ERASE_DISTANCE: f64 = 5.0;
+const SELECTION_COLOR: Color = Color::rgb8(0x99, 0x99, 0xdd);
+const SELECTION_THICKNESS: f64 = 1.0;
+// The size (in pixels) of the handle for scaling the selected snippet.
+const SCALE_HANDLE_SIZE: f64 = 8.0;
+// Don't let the user scale a snippet down to nothing.
+const MIN_SCALE: f64 = 0.05;
+const MEASURE_COLOR: Color = Color::rgb8(0xdd, 0x55, 0x55);
+const MEASURE_TEXT_SIZE: f64 = 11.0;
+// How far (in pixels) from the mouse cursor to draw the measurement text.
+const MEASURE_TEXT_OFFSET: (f64, f64) = (10.0, -6.0);
+const CAPTION_COLOR: Color = Color::WHITE;
+const CAPTION_BACKGROUND: Color = Color::rgba8(0x00, 0x00, 0x00, 0xaa);
+// The size of the caption text, as a fraction of the canvas height.
+const CAPTION_TEXT_SIZE: f64 = 0.045;
+// How far (as a fraction of the text size) the caption box extends around the text.
+const CAPTION_PADDING: f64 = 0.3;
+const MIN_ZOOM: f64 = 0.25;
+const MAX_ZOOM: f64 = 16.0;
+// How much to zoom for each pixel of scroll-wheel movement.
+const WHEEL_ZOOM_SPEED: f64 = 0.002;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TransformKind {
+    Move,
+    Scale,
+}
+
+// The state of a drag that moves or scales the selected snippet. All the points and rectangles
+// are in image coordinates.
+struct TransformDrag {
+    kind: TransformKind,
+    // The bounding box of the snippet when the drag started.
+    bbox: Rect,
+    start: Point,
+    current: Point,
+}
+
+impl TransformDrag {
+    fn transform(&self) -> Affine {
+        match self.kind {
+            TransformKind::Move => Affine::translate(self.current - self.start),
+            TransformKind::Scale => {
+                // We scale uniformly around the top-left corner, by projecting the mouse position
+                // onto the diagonal of the bounding box.
+                let origin = self.bbox.origin().to_vec2();
+                let diag = Vec2::new(self.bbox.width(), self.bbox.height());
+                let scale = if diag.hypot2() > 0.0 {
+                    (self.current.to_vec2() - origin).dot(diag) / diag.hypot2()
+                } else {
+                    1.0
+                };
+                Affine::translate(origin)
+                    * Affine::scale(scale.max(MIN_SCALE))
+                    * Affine::translate(-origin)
+            }
+        }
+    }
+}
+
+// Transforms a rectangle, assuming that the transformation doesn't involve any rotation.
+fn transform_rect(transform: Affine, rect: Rect) -> Rect {
+    Rect::from_points(
+        transform * rect.origin(),
+        transform * Point::new(rect.x1, rect.y1),
+    )
+}
 
 pub struct DrawingPane {
+    // The largest rectangle with the right aspect ratio that fits in the widget. This is where the
+    // paper goes if the canvas isn't zoomed or panned.
+    base_rect: Rect,
+    // Where the paper actually goes, taking into account the zoom and pan.
     paper_rect: Rect,
     cursor: Option<SnippetsCursor>,
+    // The snippets that aren't changing any more, already drawn.
+    stroke_cache: StrokeCache,
+    // The images on the canvas, already uploaded.
+    image_cache: ImageCache<<Piet<'static> as RenderContext>::Image>,
+    // True if the user is currently dragging the mirror axis around.
+    dragging_mirror_axis: bool,
+    // If the user is currently moving or scaling the selected snippet, this is the state of that
+    // operation.
+    transform_drag: Option<TransformDrag>,
+    // If the user is currently panning the canvas by dragging, this is the previous mouse
+    // position (in widget coordinates).
+    pan_drag: Option<Point>,
+    // If the measure overlay is on, this is the position of the mouse (in image coordinates).
+    hover: Option<Point>,
+    // If the user is measuring something, this is the start and end of the measurement (in image
+    // coordinates).
+    measure: Option<(Point, Point)>,
+    // If the user is erasing by dragging, this is whether they've erased anything yet.
+    erase_drag: Option<bool>,
+    // True if the current drag (whether it draws or pans) was started by fingers on a
+    // touchscreen.
+    touch_drag: bool,
+    // True if we're ignoring a palm that rests on the touchscreen while the pen is in use.
+    rejecting_palm: bool,
 }
 
 impl DrawingPane {
@@ -36,13 +135,217 @@ impl DrawingPane {
         let size_ratio = DRAWING_WIDTH / self.paper_rect.width();
         Affine::translate(top_left) * Affine::scale(1.0 / size_ratio)
     }
+
+    // Recomputes `paper_rect` from `base_rect` and the zoom and pan settings.
+    fn update_paper_rect(&mut self, data: &AppState) {
+        let size = self.base_rect.size() * data.canvas_zoom;
+        let scale = size.width / DRAWING_WIDTH;
+        let origin = self.base_rect.center() - size.to_vec2() / 2.0 - data.canvas_pan * scale;
+        self.paper_rect = Rect::from_origin_size(origin, size);
+    }
+
+    // Zooms by `factor`, keeping the point `anchor` (in widget coordinates) fixed.
+    fn zoom_about(&self, data: &mut AppState, factor: f64, anchor: Point) {
+        let p = self.to_image_coords() * anchor;
+        let zoom = (data.canvas_zoom * factor).max(MIN_ZOOM).min(MAX_ZOOM);
+        let size = self.base_rect.size() * zoom;
+        let scale = size.width / DRAWING_WIDTH;
+        // We want `anchor = origin + p * scale`, where `origin` is as in `update_paper_rect`.
+        let origin = anchor - p.to_vec2() * scale;
+        data.canvas_pan = (self.base_rect.center() - size.to_vec2() / 2.0 - origin) / scale;
+        data.canvas_zoom = zoom;
+    }
+
+    // Moves the canvas by `delta` (in widget coordinates).
+    fn pan_by(&self, data: &mut AppState, delta: Vec2) {
+        let scale = self.paper_rect.width() / DRAWING_WIDTH;
+        data.canvas_pan -= delta / scale;
+    }
+
+    // Draws the coordinates of the mouse, and the current measurement (if there is one).
+    fn paint_measure(&self, ctx: &mut PaintCtx, env: &Env) {
+        let from_image = self.from_image_coords();
+        let (text, text_pos) = if let Some((start, end)) = self.measure {
+            let line = Line::new(from_image * start, from_image * end);
+            ctx.stroke(line, &MEASURE_COLOR, 1.0);
+            let d = end - start;
+            // The y coordinate increases downwards, but people expect angles to go
+            // counter-clockwise.
+            let angle = (-d.y).atan2(d.x).to_degrees();
+            (format!("{:.3}, {:.1}°", d.hypot(), angle), line.p1)
+        } else if let Some(p) = self.hover {
+            (format!("({:.3}, {:.3})", p.x, p.y), from_image * p)
+        } else {
+            return;
+        };
+
+        let font_name = env.get(druid::theme::FONT_NAME);
+        let font = match ctx
+            .text()
+            .new_font_by_name(font_name, MEASURE_TEXT_SIZE)
+            .build()
+        {
+            Ok(font) => font,
+            Err(e) => {
+                log::error!("failed to load font: {}", e);
+                return;
+            }
+        };
+        match ctx
+            .text()
+            .new_text_layout(&font, &text, std::f64::INFINITY)
+            .build()
+        {
+            Ok(layout) => {
+                let pos = text_pos + Vec2::from(MEASURE_TEXT_OFFSET);
+                ctx.draw_text(&layout, pos, &MEASURE_COLOR)
+            }
+            Err(e) => log::error!("failed to lay out text: {}", e),
+        }
+    }
+
+    // Draws the caption that's showing right now (if there is one) at the bottom of the canvas.
+    // Captions don't zoom with the canvas, so that they're always readable.
+    fn paint_caption(&self, ctx: &mut PaintCtx, data: &AppState, env: &Env) {
+        let caption = match data.scribble.captions.at(data.time()) {
+            Some(c) => c,
+            None => return,
+        };
+        let size = (self.base_rect.height() * CAPTION_TEXT_SIZE).max(MEASURE_TEXT_SIZE);
+        let font_name = env.get(druid::theme::FONT_NAME);
+        let font = match ctx.text().new_font_by_name(font_name, size).build() {
+            Ok(font) => font,
+            Err(e) => {
+                log::error!("failed to load font: {}", e);
+                return;
+            }
+        };
+        let mut layouts = Vec::new();
+        for line in caption.text.lines() {
+            match ctx
+                .text()
+                .new_text_layout(&font, line, std::f64::INFINITY)
+                .build()
+            {
+                Ok(layout) => layouts.push(layout),
+                Err(e) => log::error!("failed to lay out text: {}", e),
+            }
+        }
+
+        // The text positions are for the baselines, so the descenders of the last line go a bit
+        // below it.
+        let pad = size * CAPTION_PADDING;
+        let line_height = size * 1.2;
+        let mut baseline =
+            self.base_rect.y1 - 2.0 * pad - line_height * (layouts.len() as f64 - 1.0);
+        for layout in &layouts {
+            let left = self.base_rect.center().x - layout.width() / 2.0;
+            let bg = Rect::new(
+                left - pad,
+                baseline - size - pad / 2.0,
+                left + layout.width() + pad,
+                baseline + size * 0.2 + pad / 2.0,
+            );
+            ctx.fill(bg, &CAPTION_BACKGROUND);
+            ctx.draw_text(layout, (left, baseline), &CAPTION_COLOR);
+            baseline += line_height;
+        }
+    }
+
+    /// The mirror axis, in image coordinates.
+    fn mirror_axis(&self, mirror: MirrorMode, drawing_height: f64) -> Option<Line> {
+        match mirror {
+            MirrorMode::Off => None,
+            MirrorMode::Vertical(x) => Some(Line::new((x, 0.0), (x, drawing_height))),
+            MirrorMode::Horizontal(y) => Some(Line::new((0.0, y), (DRAWING_WIDTH, y))),
+        }
+    }
+
+    // Erases the drawing under `pos` (in widget coordinates), if there is one. Returns true if
+    // something was erased.
+    fn erase_at(&self, state: &mut AppState, pos: Point) -> bool {
+        let pos = self.to_image_coords() * pos;
+        let radius = ERASE_DISTANCE * DRAWING_WIDTH / self.paper_rect.width();
+        match state.scribble.snippet_near(pos, state.time(), radius) {
+            Some(id) => {
+                state.scribble.delete_snippet(id.into());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Is the point `pos` (in widget coordinates) close enough to the mirror axis to grab it?
+    fn is_near_mirror_axis(&self, mirror: MirrorMode, pos: Point) -> bool {
+        let p = self.to_image_coords() * pos;
+        let grab_distance = MIRROR_AXIS_GRAB_DISTANCE * DRAWING_WIDTH / self.paper_rect.width();
+        match mirror {
+            MirrorMode::Off => false,
+            MirrorMode::Vertical(x) => (p.x - x).abs() <= grab_distance,
+            MirrorMode::Horizontal(y) => (p.y - y).abs() <= grab_distance,
+        }
+    }
+
+    /// The bounding box (in image coordinates) of the selected snippet, if it can be transformed.
+    fn selection_bbox(&self, data: &AppState) -> Option<Rect> {
+        if !data.action.is_idle() {
+            return None;
+        }
+        let id = data.scribble.selected_snippet.as_draw()?;
+        let snip = data.scribble.snippets.snippet(id);
+        if snip.curve.path.elements().is_empty() {
+            None
+        } else {
+            Some(snip.curve.path.bounding_box())
+        }
+    }
+
+    /// The handle (in widget coordinates) for scaling a snippet with bounding box `bbox`.
+    fn scale_handle(&self, bbox: Rect) -> Rect {
+        let corner = self.from_image_coords() * Point::new(bbox.x1, bbox.y1);
+        Rect::from_center_size(corner, (SCALE_HANDLE_SIZE, SCALE_HANDLE_SIZE))
+    }
+
+    /// Finishes the segment that's being drawn, if there is one.
+    fn finish_stroke(&self, ctx: &mut EventCtx, state: &mut AppState) {
+        if state.action.is_recording() {
+            state.mouse_down = false;
+            if let Some(seg) = state.finish_cur_segment() {
+                ctx.submit_command(cmd::APPEND_NEW_SEGMENT.with(seg), None);
+            }
+        }
+    }
+
+    /// If the point `pos` (in widget coordinates) is in a position to start moving or scaling
+    /// the selected snippet, returns the kind of transformation.
+    fn transform_kind_at(&self, data: &AppState, pos: Point) -> Option<TransformKind> {
+        let bbox = self.selection_bbox(data)?;
+        if self.scale_handle(bbox).contains(pos) {
+            Some(TransformKind::Scale)
+        } else if transform_rect(self.from_image_coords(), bbox).contains(pos) {
+            Some(TransformKind::Move)
+        } else {
+            None
+        }
+    }
 }
 
 impl Default for DrawingPane {
     fn default() -> DrawingPane {
         DrawingPane {
+            base_rect: Rect::ZERO,
             paper_rect: Rect::ZERO,
             cursor: None,
+            stroke_cache: StrokeCache::default(),
+            image_cache: ImageCache::default(),
+            dragging_mirror_axis: false,
+            transform_drag: None,
+            pan_drag: None,
+            hover: None,
+            measure: None,
+            erase_drag: None,
+            touch_drag: false,
+            rejecting_palm: false,
         }
     }
 }
@@ -51,31 +354,164 @@ impl Default for DrawingPane {
 impl Widget<AppState> for DrawingPane {
     fn event(&mut self, ctx: &mut EventCtx, event: &Event, state: &mut AppState, _env: &Env) {
         match event {
+            Event::MouseMove(_) if self.rejecting_palm => {}
             Event::MouseMove(ev) => {
-                if state.mouse_down && state.action.is_recording() {
-                    let time = state.accurate_time();
-                    state.add_to_cur_snippet(self.to_image_coords() * ev.pos, time);
+                let pos = self.to_image_coords() * ev.pos;
+                if state.show_measure {
+                    self.hover = Some(pos);
+                    ctx.request_paint();
+                }
+                if self.touch_drag && self.pan_drag.is_none() && touch::pointer().fingers() >= 2 {
+                    // A second finger landed after the first one started drawing, so this is
+                    // a pan (or a pinch) after all. The little bit that got drawn stays, but
+                    // it can be undone.
+                    self.finish_stroke(ctx, state);
+                    self.pan_drag = Some(ev.pos);
+                }
+                if let Some(prev) = self.pan_drag {
+                    self.pan_by(state, ev.pos - prev);
+                    self.pan_drag = Some(ev.pos);
+                } else if let Some(erased) = self.erase_drag {
+                    self.erase_drag = Some(self.erase_at(state, ev.pos) || erased);
+                } else if let Some((_, end)) = self.measure.as_mut() {
+                    *end = pos;
+                } else if let Some(drag) = self.transform_drag.as_mut() {
+                    drag.current = pos;
                     ctx.request_paint();
+                } else if self.dragging_mirror_axis {
+                    state.mirror = state.mirror.with_axis_through(pos);
+                    ctx.request_paint();
+                } else if state.action.is_recording() {
+                    let time = state.accurate_time();
+                    state.record_pen_position(pos, time);
+                    if state.mouse_down {
+                        state.add_to_cur_snippet(pos, time);
+                        ctx.request_paint();
+                    }
+                }
+            }
+            Event::MouseDown(ev) if ev.button.is_left() && touch::pointer() == Pointer::Palm => {
+                self.rejecting_palm = true;
+                ctx.set_active(true);
+                ctx.set_handled();
+            }
+            Event::MouseUp(ev) if ev.button.is_left() && self.rejecting_palm => {
+                self.rejecting_palm = false;
+                ctx.set_active(false);
+                ctx.set_handled();
+            }
+            // Two fingers always pan (and pinch, below); one finger pans if the preferences say so.
+            Event::MouseDown(ev)
+                if ev.button.is_left()
+                    && (touch::pointer().fingers() >= 2
+                        || (touch::pointer().fingers() == 1 && state.prefs.touch_pans)) =>
+            {
+                self.touch_drag = true;
+                self.pan_drag = Some(ev.pos);
+                ctx.set_active(true);
+            }
+            Event::MouseUp(ev)
+                if ev.button.is_left() && self.touch_drag && self.pan_drag.is_some() =>
+            {
+                self.touch_drag = false;
+                self.pan_drag = None;
+                ctx.set_active(false);
+            }
+            // Any other bound buttons are taken care of by `Root`.
+            Event::MouseDown(ev) if state.prefs.mouse_action(ev) == Some("erase") => {
+                if state.action.is_idle() {
+                    self.erase_drag = Some(self.erase_at(state, ev.pos));
+                    ctx.set_active(true);
+                }
+            }
+            Event::MouseUp(_) if self.erase_drag.is_some() => {
+                if self.erase_drag.take() == Some(true) {
+                    state.push_undo("Erase");
+                }
+                ctx.set_active(false);
+            }
+            // Dragging with the middle button pans the canvas, even in the middle of a recording.
+            Event::MouseDown(ev) if ev.button.is_middle() => {
+                self.pan_drag = Some(ev.pos);
+                ctx.set_active(true);
+            }
+            Event::MouseUp(ev) if ev.button.is_middle() && self.pan_drag.is_some() => {
+                self.pan_drag = None;
+                ctx.set_active(false);
+            }
+            Event::Wheel(ev) => {
+                if ev.mods.ctrl || ev.mods.meta {
+                    let factor = (-ev.wheel_delta.y * WHEEL_ZOOM_SPEED).exp();
+                    self.zoom_about(state, factor, ev.pos);
+                } else {
+                    self.pan_by(state, -ev.wheel_delta);
+                }
+                ctx.set_handled();
+            }
+            // This comes from pinching on a trackpad or touchscreen.
+            Event::Zoom(delta) => {
+                self.zoom_about(state, 1.0 + delta, self.base_rect.center());
+                ctx.set_handled();
+            }
+            Event::MouseDown(ev)
+                if ev.button.is_left()
+                    && state.action.is_idle()
+                    && self.is_near_mirror_axis(state.mirror, ev.pos) =>
+            {
+                self.dragging_mirror_axis = true;
+                ctx.set_active(true);
+            }
+            Event::MouseDown(ev)
+                if ev.button.is_left() && state.action.is_idle() && state.show_measure =>
+            {
+                let pos = self.to_image_coords() * ev.pos;
+                self.measure = Some((pos, pos));
+                ctx.set_active(true);
+            }
+            Event::MouseDown(ev) if ev.button.is_left() && state.action.is_idle() => {
+                if let Some(kind) = self.transform_kind_at(state, ev.pos) {
+                    let bbox = self.selection_bbox(state).unwrap();
+                    let pos = self.to_image_coords() * ev.pos;
+                    self.transform_drag = Some(TransformDrag {
+                        kind,
+                        bbox,
+                        start: pos,
+                        current: pos,
+                    });
+                    ctx.set_active(true);
                 }
             }
             Event::MouseDown(ev) if ev.button.is_left() => {
+                self.touch_drag = touch::pointer().fingers() > 0;
                 if let CurrentAction::WaitingToRecord(_) = state.action {
                     state.start_actually_recording();
                 }
                 if state.action.is_recording() {
                     let time = state.accurate_time();
-                    state.add_to_cur_snippet(self.to_image_coords() * ev.pos, time);
+                    let pos = self.to_image_coords() * ev.pos;
+                    state.record_pen_position(pos, time);
+                    state.add_to_cur_snippet(pos, time);
 
                     state.mouse_down = true;
                     ctx.request_paint();
                 }
             }
             Event::MouseUp(ev) => {
-                if ev.button.is_left() && state.action.is_recording() {
-                    state.mouse_down = false;
-                    if let Some(seg) = state.finish_cur_segment() {
-                        ctx.submit_command(Command::new(cmd::APPEND_NEW_SEGMENT, seg), None);
+                if self.measure.take().is_some() {
+                    ctx.set_active(false);
+                    ctx.request_paint();
+                } else if let Some(drag) = self.transform_drag.take() {
+                    ctx.set_active(false);
+                    if drag.current != drag.start {
+                        let cmd = cmd::TRANSFORM_SNIPPET.with(drag.transform());
+                        ctx.submit_command(cmd, None);
                     }
+                } else if self.dragging_mirror_axis {
+                    self.dragging_mirror_axis = false;
+                    ctx.set_active(false);
+                } else if ev.button.is_left() {
+                    self.touch_drag = false;
+                    self.finish_stroke(ctx, state);
                 }
             }
             Event::WindowConnected => {
@@ -86,7 +522,26 @@ impl Widget<AppState> for DrawingPane {
     }
 
     fn update(&mut self, ctx: &mut UpdateCtx, old_data: &AppState, data: &AppState, _env: &Env) {
-        if old_data.time() != data.time() {
+        if old_data.time() != data.time()
+            || old_data.mirror != data.mirror
+            || old_data.scribble.selected_snippet != data.scribble.selected_snippet
+            || old_data.action != data.action
+            || old_data.show_pen != data.show_pen
+            || old_data.show_measure != data.show_measure
+            || old_data.show_captions != data.show_captions
+            || !old_data.scribble.captions.same(&data.scribble.captions)
+            || !old_data.scribble.settings.same(&data.scribble.settings)
+            || !old_data.scribble.images.same(&data.scribble.images)
+        {
+            ctx.request_paint();
+        }
+
+        if old_data.scribble.settings.aspect_ratio != data.scribble.settings.aspect_ratio {
+            ctx.request_layout();
+        }
+
+        if old_data.canvas_zoom != data.canvas_zoom || old_data.canvas_pan != data.canvas_pan {
+            self.update_paper_rect(data);
             ctx.request_paint();
         }
 
@@ -96,45 +551,131 @@ impl Widget<AppState> for DrawingPane {
         }
     }
 
-    fn lifecycle(&mut self, _ctx: &mut LifeCycleCtx, _: &LifeCycle, _state: &AppState, _env: &Env) {
+    fn lifecycle(&mut self, ctx: &mut LifeCycleCtx, ev: &LifeCycle, _state: &AppState, _env: &Env) {
+        if let LifeCycle::HotChanged(false) = ev {
+            if self.hover.take().is_some() {
+                ctx.request_paint();
+            }
+        }
     }
 
     fn layout(
         &mut self,
         _ctx: &mut LayoutCtx,
         bc: &BoxConstraints,
-        _data: &AppState,
+        data: &AppState,
         _env: &Env,
     ) -> Size {
         let size = bc.max();
 
         // Find the largest rectangle of the correct aspect ratio that will fit in the box.
-        let paper_width = size.width.min(ASPECT_RATIO * size.height);
-        let paper_height = paper_width / ASPECT_RATIO;
-        self.paper_rect = Rect::from_origin_size(Point::ZERO, (paper_width, paper_height));
-        self.paper_rect =
-            self.paper_rect + size.to_vec2() / 2.0 - self.paper_rect.center().to_vec2();
-        self.paper_rect = self.paper_rect.inset(PAPER_BDY_THICKNESS).round();
+        let aspect_ratio = data.scribble.settings.aspect_ratio.ratio();
+        let paper_width = size.width.min(aspect_ratio * size.height);
+        let paper_height = paper_width / aspect_ratio;
+        self.base_rect = Rect::from_origin_size(Point::ZERO, (paper_width, paper_height));
+        self.base_rect = self.base_rect + size.to_vec2() / 2.0 - self.base_rect.center().to_vec2();
+        self.base_rect = self.base_rect.inset(PAPER_BDY_THICKNESS).round();
+        self.update_paper_rect(data);
 
         size
     }
 
-    fn paint(&mut self, ctx: &mut PaintCtx, data: &AppState, _env: &Env) {
+    fn paint(&mut self, ctx: &mut PaintCtx, data: &AppState, env: &Env) {
+        // Widgets only get translated, so this is the number of pixels per unit.
+        let scale = ctx.current_transform().as_coeffs()[0];
+        let selected = data.scribble.selected_snippet.as_draw();
+        self.stroke_cache.update(
+            &data.scribble.snippets,
+            data.time(),
+            self.transform_drag.as_ref().and(selected),
+            self.paper_rect.intersect(ctx.size().to_rect()),
+            self.from_image_coords(),
+            scale,
+        );
+
+        // If the canvas is zoomed in, it might not fit inside the widget.
+        ctx.clip(ctx.size().to_rect());
         ctx.stroke(&self.paper_rect, &PAPER_BDY_COLOR, PAPER_BDY_THICKNESS);
-        ctx.fill(&self.paper_rect, &PAPER_COLOR);
+        ctx.fill(&self.paper_rect, &data.scribble.settings.background);
 
         ctx.with_save(|ctx| {
             ctx.transform(self.from_image_coords());
+            data.scribble.images.render(
+                ctx.render_ctx,
+                &mut self.image_cache,
+                data.time(),
+                &data.scribble.settings.background,
+            );
+            self.stroke_cache
+                .render(ctx.render_ctx, self.to_image_coords());
             if let Some(path_in_progress) = data.new_snippet_as_curve() {
                 path_in_progress.render(ctx.render_ctx, data.time());
             }
             if let Some(curve) = data.scribble.new_curve.as_ref() {
                 curve.render(ctx.render_ctx, data.time());
             }
+            // Collaborators might be drawing at some other time, so we show all of their strokes.
+            for curve in data.peer_strokes.iter() {
+                if let Some(&end) = curve.times.iter().max() {
+                    curve.render(ctx.render_ctx, end);
+                }
+            }
+
+            for (id, snip) in data.scribble.snippets.snippets() {
+                if self.stroke_cache.contains(id) {
+                    continue;
+                }
+                match &self.transform_drag {
+                    // While the selected snippet is being moved, draw it in its new position.
+                    Some(drag) if Some(id) == selected => ctx.with_save(|ctx| {
+                        ctx.transform(drag.transform());
+                        snip.render(ctx.render_ctx, data.time());
+                    }),
+                    _ => snip.render(ctx.render_ctx, data.time()),
+                }
+            }
 
-            for (_, snip) in data.scribble.snippets.snippets() {
-                snip.render(ctx.render_ctx, data.time());
+            // While recording, the real mouse cursor shows where the pen is.
+            if data.show_pen && !data.action.is_recording() {
+                data.scribble
+                    .snippets
+                    .render_pen(ctx.render_ctx, data.time());
             }
         });
+
+        if let Some(bbox) = self.selection_bbox(data) {
+            let transform = self
+                .transform_drag
+                .as_ref()
+                .map(|drag| drag.transform())
+                .unwrap_or_default();
+            let bbox = transform_rect(transform, bbox);
+            let rect = transform_rect(self.from_image_coords(), bbox);
+            let style = StrokeStyle {
+                dash: Some((vec![4.0, 4.0], 0.0)),
+                ..StrokeStyle::new()
+            };
+            ctx.stroke_styled(rect, &SELECTION_COLOR, SELECTION_THICKNESS, &style);
+            ctx.fill(self.scale_handle(bbox), &SELECTION_COLOR);
+        }
+
+        let drawing_height = data.scribble.settings.drawing_height();
+        if let Some(axis) = self.mirror_axis(data.mirror, drawing_height) {
+            // We draw the axis in widget coordinates, so that the dashes don't scale.
+            let from_image = self.from_image_coords();
+            let axis = Line::new(from_image * axis.p0, from_image * axis.p1);
+            let style = StrokeStyle {
+                dash: Some((vec![4.0, 4.0], 0.0)),
+                ..StrokeStyle::new()
+            };
+            ctx.stroke_styled(axis, &MIRROR_AXIS_COLOR, MIRROR_AXIS_THICKNESS, &style);
+        }
+
+        if data.show_captions {
+            self.paint_caption(ctx, data, env);
+        }
+        if data.show_measure {
+            self.paint_measure(ctx, env);
+        }
     }
 }
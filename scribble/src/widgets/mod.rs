@@ -1,3 +1,4 @@
+mod dialogs;
 mod drawing_pane;
 mod icons;
 mod labelled_container;
@@ -5,14 +6,18 @@ mod palette;
 pub mod radio_icon;
 mod root;
 mod status;
+mod stroke_cache;
+mod tab_bar;
 mod timeline;
 mod toggle_button;
 
-pub use drawing_pane::DrawingPane;
+pub use dialogs::make_dialog;
+pub use drawing_pane::{DrawingPane, DRAWING_WIDTH};
 pub use icons::Icon;
 pub use labelled_container::LabelledContainer;
 pub use palette::{Palette, PaletteData};
 pub use root::Root;
 pub use status::make_status_bar;
+pub use tab_bar::make_tab_bar;
 pub use timeline::make_timeline;
 pub use toggle_button::{ToggleButton, ToggleButtonState};
@@ -1,6 +1,6 @@
 use druid::kurbo::Circle;
 use druid::widget::prelude::*;
-use druid::{Color, Command, Data, Lens, Point, Rect, RenderContext, WidgetPod};
+use druid::{Color, Data, Lens, Point, Rect, RenderContext, WidgetPod};
 use std::sync::Arc;
 
 use crate::cmd;
@@ -45,6 +45,21 @@ impl PaletteData {
     pub fn select(&mut self, color: &Color) {
         self.selected = color.clone();
     }
+
+    pub fn colors(&self) -> &[Color] {
+        &self.colors
+    }
+
+    /// Replaces the colors in the palette. If the selected color isn't one of the new colors,
+    /// the first color gets selected.
+    pub fn set_colors(&mut self, colors: Vec<Color>) {
+        assert!(!colors.is_empty());
+        let selected = self.selected.as_rgba_u32();
+        if !colors.iter().any(|c| c.as_rgba_u32() == selected) {
+            self.selected = colors[0].clone();
+        }
+        self.colors = Arc::new(colors);
+    }
 }
 
 #[derive(Default)]
@@ -67,7 +82,7 @@ impl Widget<Color> for PaletteElement {
             Event::MouseUp(_) => {
                 if ctx.is_active() {
                     ctx.set_active(false);
-                    ctx.submit_command(Command::new(cmd::CHOOSE_COLOR, self.color.clone()), None);
+                    ctx.submit_command(cmd::CHOOSE_COLOR.with(self.color.clone()), None);
                 }
             }
             _ => {}
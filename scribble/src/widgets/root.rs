@@ -1,31 +1,133 @@
 use druid::widget::{Align, Flex};
 use druid::{
-    BoxConstraints, Color, Command, Env, Event, EventCtx, KeyCode, KeyEvent, LayoutCtx, LifeCycle,
-    LifeCycleCtx, PaintCtx, Size, TimerToken, UpdateCtx, Widget, WidgetExt, WidgetId,
+    Application, BoxConstraints, Color, Command, Env, Event, EventCtx, FileDialogOptions, KeyEvent,
+    LayoutCtx, LifeCycle, LifeCycleCtx, PaintCtx, Point, Selector, Size, TimerToken, UpdateCtx,
+    Vec2, Widget, WidgetExt, WidgetId,
 };
-use std::sync::mpsc::{channel, Receiver};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use scribble_curves::{SnippetData, SnippetId, Time};
+use scribble_core::save_file::SaveFileData;
+use scribble_core::undo::{COALESCE_WINDOW, MAX_SAVED_UNDO};
+use scribble_curves::{time, Diff};
 
-use crate::audio::{AudioSnippetData, AudioSnippetId};
+use crate::audio::AudioSnippetData;
+use crate::autosave::Autosave;
 use crate::cmd;
-use crate::data::{AppState, CurrentAction, MaybeSnippetId, RecordingSpeed, SegmentInProgress};
-use crate::encode::EncodingStatus;
+use crate::collab::Collab;
+use crate::command_palette::command_for;
+use crate::data::{AppState, CurrentAction, Dialog, MaybeSnippetId, RecordingSpeed, ScribbleState};
+use crate::export_queue::ExportQueue;
+use crate::images::{self, ImageSnippetData};
+use crate::live_output::LiveOutput;
+use crate::menus::MenuState;
+use crate::midi_sync::{SyncEvent, SyncOutput};
+use crate::modal::ModalKeys;
+use crate::prefs::Keymap;
+use crate::project_io::{FileOp, ProjectIo};
+use crate::remote_pen::PenEvent;
+use crate::transcribe::{Status as TranscribeStatus, Transcriber};
 use crate::widgets::{
-    icons, make_status_bar, make_timeline, DrawingPane, LabelledContainer, Palette, ToggleButton,
+    icons, make_dialog, make_status_bar, make_tab_bar, make_timeline, DrawingPane,
+    LabelledContainer, Palette, ToggleButton, DRAWING_WIDTH,
 };
 use crate::FRAME_TIME;
 
+// The snippet edits that can be repeated with `REPEAT_LAST_EDIT`. They all apply to the selected
+// drawing snippet, and they all make sense to do to a different snippet.
+const REPEATABLE_EDITS: [Selector; 6] = [
+    cmd::TRUNCATE_SNIPPET,
+    cmd::RECOLOR_SNIPPET,
+    cmd::TRANSFORM_SNIPPET.selector(),
+    cmd::CHANGE_SPEED.selector(),
+    cmd::REVERSE_SNIPPET,
+    cmd::LERP_SNIPPET,
+];
+
+// The clipboard formats that we check (in order) when pasting an image. Different platforms have
+// different names for the same thing.
+// Screenshots on mac are TIFF; most other things offer PNG.
+const CLIPBOARD_IMAGE_FORMATS: &[&str] = &[
+    "image/png",
+    "public.png",
+    "PNG",
+    "image/jpeg",
+    "image/bmp",
+    "image/gif",
+    "public.tiff",
+];
+// When an image file gets copied in a file manager, the clipboard has its URI.
+const CLIPBOARD_FILE_FORMATS: &[&str] = &["text/uri-list", "public.file-url"];
+const IMAGE_FADE_IN: Diff = Diff::from_micros(500_000);
+const TOAST_DURATION: Duration = Duration::from_secs(4);
+
 pub struct Root {
     timer_id: TimerToken,
 
-    // While we're encoding a file, this receives status updates from the encoder. Each update
-    // is a number between 0.0 and 1.0 (where 1.0 means finished).
-    encoder_progress: Option<Receiver<EncodingStatus>>,
+    // The background thread that runs exports. It gets started the first time we export
+    // something.
+    export_queue: Option<ExportQueue>,
+
+    autosave: Autosave,
+
+    // The background thread that saves and loads projects. It gets started the first time we
+    // save or load something.
+    project_io: Option<ProjectIo>,
+
+    // The background thread that does speech-to-text. It gets started the first time we
+    // transcribe something.
+    transcriber: Option<Transcriber>,
+
+    // What the menus showed the last time we rebuilt them.
+    menu_state: Option<MenuState>,
+
+    // The most recent of the `REPEATABLE_EDITS`.
+    last_edit: Option<Command>,
+
+    modal: ModalKeys,
+
+    // Where we're publishing the canvas, if live output is on.
+    live_output: Option<LiveOutput>,
+
+    // When the current `AppState::toast` was shown.
+    toast_shown: Option<Instant>,
+
+    // Where we're sending MIDI timecode, and the name that it was found by.
+    sync_output: Option<SyncOutput>,
+    sync_output_port: String,
+
+    // When we last heard MIDI timecode, while following it.
+    last_timecode: Option<Instant>,
+
+    // The collaboration that we're hosting or have joined, if any.
+    collab: Option<Collab>,
 
     inner: Box<dyn Widget<AppState>>,
 }
 
+// The (encoded) image on the clipboard. This is either the image itself (like a screenshot), or
+// an image file that was copied.
+fn clipboard_image() -> std::io::Result<Option<Vec<u8>>> {
+    let clipboard = Application::global().clipboard();
+    if let Some(bytes) = clipboard
+        .preferred_format(CLIPBOARD_IMAGE_FORMATS)
+        .and_then(|format| clipboard.get_format(format))
+    {
+        return Ok(Some(bytes));
+    }
+    let text = clipboard
+        .preferred_format(CLIPBOARD_FILE_FORMATS)
+        .and_then(|format| clipboard.get_format(format))
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .or_else(|| clipboard.get_string());
+    match text.as_deref().and_then(images::image_path_from_clipboard) {
+        Some(path) => std::fs::read(path).map(Some),
+        None => Ok(None),
+    }
+}
+
 fn make_draw_button_group() -> impl Widget<AppState> {
     let rec_button: ToggleButton<AppState> = ToggleButton::new(
         &icons::VIDEO,
@@ -47,8 +149,8 @@ fn make_draw_button_group() -> impl Widget<AppState> {
         &icons::FADE_OUT,
         20.0,
         |&b: &bool| b.into(),
-        |_, data, _| *data = true,
-        |_, data, _| *data = false,
+        |ctx, _, _| ctx.submit_command(cmd::SET_FADE.with(true), None),
+        |ctx, _, _| ctx.submit_command(cmd::SET_FADE.with(false), None),
     )
     .lens(AppState::fade_enabled);
 
@@ -116,20 +218,201 @@ impl Root {
             .draggable(true).debug_paint_layout();
         */
         let column = Flex::column()
+            .with_child(make_tab_bar())
             .with_child(button_row)
+            .with_child(make_dialog())
             .with_flex_child(drawing.padding(10.0), 1.0)
             .with_child(timeline)
             .with_child(make_status_bar());
 
         Root {
             inner: Box::new(Align::centered(column)),
-            encoder_progress: None,
+            export_queue: None,
+            autosave: Autosave::new(),
+            project_io: None,
+            transcriber: None,
+            menu_state: None,
+            last_edit: None,
+            modal: ModalKeys::default(),
+            live_output: None,
+            toast_shown: None,
+            sync_output: None,
+            sync_output_port: String::new(),
+            last_timecode: None,
+            collab: None,
             timer_id: TimerToken::INVALID,
         }
     }
 }
 
 impl Root {
+    fn add_image(&mut self, bytes: Vec<u8>, data: &mut AppState) {
+        let height = data.scribble.settings.drawing_height();
+        match ImageSnippetData::from_encoded(bytes, data.time(), height) {
+            Ok(mut image) => {
+                if data.fade_in_images {
+                    image.fade_in = IMAGE_FADE_IN;
+                }
+                data.scribble.images = data.scribble.images.with_new_snippet(image);
+                data.push_undo("Add Image");
+            }
+            Err(e) => log::error!("failed to load image: {}", e),
+        }
+    }
+
+    fn add_background(&mut self, path: &Path, data: &mut AppState) -> anyhow::Result<()> {
+        let height = data.scribble.settings.drawing_height();
+        let bytes = std::fs::read(path)?;
+        let mut image = ImageSnippetData::from_encoded(bytes, data.time(), height)?;
+        image = image.as_background(height);
+        if data.fade_in_images {
+            image.fade_in = IMAGE_FADE_IN;
+        }
+        data.scribble.images = data.scribble.images.with_new_snippet(image);
+        data.push_undo("Add Background");
+        Ok(())
+    }
+
+    // Stops playing if the timecode that we were following has stopped, and sends our own
+    // timecode (connecting to the output from the preferences first, if it changed).
+    fn update_midi_sync(&mut self, data: &mut AppState) {
+        if crate::midi_sync::timecode_stopped(self.last_timecode) {
+            self.last_timecode = None;
+            if data.action == CurrentAction::Playing {
+                data.stop_playing();
+            }
+        }
+        if data.prefs.midi_sync_output != self.sync_output_port {
+            self.sync_output_port = data.prefs.midi_sync_output.clone();
+            self.sync_output = None;
+            if !self.sync_output_port.is_empty() {
+                match SyncOutput::connect(&self.sync_output_port) {
+                    Ok(output) => self.sync_output = Some(output),
+                    Err(e) => log::error!("failed to open MIDI output: {}", e),
+                }
+            }
+        }
+        if let Some(output) = &mut self.sync_output {
+            output.update(data);
+        }
+    }
+
+    // Trades edits with our collaborators, and stops collaborating if the connection is gone.
+    fn update_collab(&mut self, data: &mut AppState) {
+        let result = match &mut self.collab {
+            Some(collab) => collab.update(data),
+            None => return,
+        };
+        match result {
+            Ok(notices) => {
+                if !notices.is_empty() {
+                    self.show_toast(notices.join(" · "), data);
+                }
+            }
+            Err(e) => {
+                self.collab = None;
+                data.collab = None;
+                data.peer_strokes = Arc::new(Vec::new());
+                self.show_toast(e.to_string(), data);
+            }
+        }
+    }
+
+    // Feeds a pen on another device into the recording, the same way that `DrawingPane` does with
+    // the mouse.
+    fn remote_pen(&mut self, ctx: &mut EventCtx, event: &PenEvent, data: &mut AppState) {
+        let height = data.scribble.settings.drawing_height();
+        let to_pos = |x: f64, y: f64| Point::new(x * DRAWING_WIDTH, y * height);
+        match *event {
+            PenEvent::Down { x, y } => {
+                if let CurrentAction::WaitingToRecord(_) = data.action {
+                    data.start_actually_recording();
+                }
+                if data.action.is_recording() {
+                    let time = data.accurate_time();
+                    data.record_pen_position(to_pos(x, y), time);
+                    data.add_to_cur_snippet(to_pos(x, y), time);
+                    data.mouse_down = true;
+                }
+            }
+            PenEvent::Move { x, y } => {
+                if data.action.is_recording() {
+                    let time = data.accurate_time();
+                    data.record_pen_position(to_pos(x, y), time);
+                    if data.mouse_down {
+                        data.add_to_cur_snippet(to_pos(x, y), time);
+                    }
+                }
+            }
+            PenEvent::Up => {
+                if data.action.is_recording() {
+                    data.mouse_down = false;
+                    if let Some(seg) = data.finish_cur_segment() {
+                        ctx.submit_command(cmd::APPEND_NEW_SEGMENT.with(seg), None);
+                    }
+                }
+            }
+            PenEvent::Action { ref action } => match command_for(action, data) {
+                Some(command) => ctx.submit_command(command, None),
+                None => log::warn!("nothing to do for the remote pen's {}", action),
+            },
+        }
+    }
+
+    // Shows a message in the status bar for a few seconds.
+    fn show_toast(&mut self, msg: String, data: &mut AppState) {
+        data.toast = Some(Arc::new(msg));
+        self.toast_shown = Some(Instant::now());
+    }
+
+    fn drop_files(&mut self, ctx: &mut EventCtx, paths: &[PathBuf], data: &mut AppState) {
+        let mut msgs = Vec::new();
+        for path in paths {
+            let name = path
+                .file_name()
+                .unwrap_or_else(|| path.as_os_str())
+                .to_string_lossy();
+            let ext = path
+                .extension()
+                .and_then(OsStr::to_str)
+                .map(str::to_lowercase);
+            let msg = if ext.as_deref() == Some("scb") {
+                ctx.submit_command(cmd::OPEN_PROJECT.with(path.clone()), None);
+                format!("Opening {}", name)
+            } else if scribble_core::audio_file::is_audio_path(path) {
+                match AudioSnippetData::from_file(path, data.time(), data.prefs.link_audio) {
+                    Ok(snip) => {
+                        data.scribble.audio_snippets =
+                            data.scribble.audio_snippets.with_new_snippet(snip).0;
+                        data.push_undo("Import Audio");
+                        format!("Added {} at the cursor", name)
+                    }
+                    Err(e) => {
+                        log::error!("error importing audio from {:?}: {}", path, e);
+                        format!("Couldn't import {}", name)
+                    }
+                }
+            } else if images::is_image_path(path) {
+                match self.add_background(path, data) {
+                    Ok(()) => format!("Added {} as a background", name),
+                    Err(e) => {
+                        log::error!("error adding a background from {:?}: {}", path, e);
+                        format!("Couldn't add {}", name)
+                    }
+                }
+            } else if ext.as_deref() == Some("pdf") {
+                format!("Couldn't add {}: PDFs aren't supported yet", name)
+            } else {
+                format!("Don't know how to open {}", name)
+            };
+            log::info!("{}", msg);
+            msgs.push(msg);
+        }
+        if !msgs.is_empty() {
+            self.show_toast(msgs.join(" · "), data);
+        }
+    }
+
     fn handle_key_down(
         &mut self,
         ctx: &mut EventCtx,
@@ -137,37 +420,45 @@ impl Root {
         data: &mut AppState,
         _env: &Env,
     ) {
-        // If they push another key while holding down the arrow, cancel the scanning.
+        let forward = data.prefs.is_key_for(ev, "scan-forward");
+        let backward = data.prefs.is_key_for(ev, "scan-backward");
+
+        // If they push another key while holding down the scanning key, cancel the scanning.
         if let CurrentAction::Scanning(speed) = data.action {
-            let direction = if speed > 0.0 {
-                KeyCode::ArrowRight
-            } else {
-                KeyCode::ArrowLeft
-            };
-            if ev.key_code != direction {
+            let same_direction = if speed > 0.0 { forward } else { backward };
+            if !same_direction {
                 data.stop_scanning();
             }
             ctx.set_handled();
-            if ev.key_code == KeyCode::ArrowRight || ev.key_code == KeyCode::ArrowLeft {
+            if forward || backward {
                 return;
             }
         }
 
-        match ev.key_code {
-            KeyCode::ArrowRight | KeyCode::ArrowLeft => {
-                let speed = if ev.mods.shift { 2.0 } else { 1.0 };
-                let dir = if ev.key_code == KeyCode::ArrowRight {
-                    1.0
-                } else {
-                    -1.0
-                };
-                let velocity = speed * dir;
-                if data.action.is_idle() || data.action.is_scanning() {
-                    data.scan(velocity);
-                }
-                ctx.set_handled();
+        // Text boxes only appear in dialogs, and there the keys are for typing.
+        if data.prefs.keymap == Keymap::Modal
+            && data.dialog.is_none()
+            && self.modal.key_down(ctx, ev, data)
+        {
+            ctx.set_handled();
+            return;
+        }
+        if data.dialog.is_none() && data.prefs.is_shortcut_for(ev, "play-pause") {
+            if let Some(command) = command_for("play-pause", data) {
+                ctx.submit_command(command, None);
+            }
+            ctx.set_handled();
+            return;
+        }
+
+        if forward || backward {
+            let speed = if ev.mods.shift { 2.0 } else { 1.0 };
+            let dir = if forward { 1.0 } else { -1.0 };
+            let velocity = speed * dir;
+            if data.action.is_idle() || data.action.is_scanning() {
+                data.scan(velocity);
             }
-            _ => {}
+            ctx.set_handled();
         }
     }
 
@@ -178,14 +469,11 @@ impl Root {
         data: &mut AppState,
         _env: &Env,
     ) {
-        match ev.key_code {
-            KeyCode::ArrowRight | KeyCode::ArrowLeft => {
-                if data.action.is_scanning() {
-                    data.stop_scanning();
-                }
-                ctx.set_handled();
+        if data.prefs.is_key_for(ev, "scan-forward") || data.prefs.is_key_for(ev, "scan-backward") {
+            if data.action.is_scanning() {
+                data.stop_scanning();
             }
-            _ => {}
+            ctx.set_handled();
         }
     }
 
@@ -196,208 +484,599 @@ impl Root {
         data: &mut AppState,
         _env: &Env,
     ) -> bool {
-        let ret = match cmd.selector {
-            cmd::ADD_SNIPPET => {
-                let snip = cmd.get_object::<SnippetData>().expect("no snippet");
-                let (new_snippets, new_id) = data.scribble.snippets.with_new_snippet(snip.clone());
-                data.scribble.snippets = new_snippets;
-                data.scribble.selected_snippet = new_id.into();
-                data.undo.borrow_mut().push(&data.scribble);
-                true
-            }
-            cmd::DELETE_SNIPPET => {
-                if let Some(id) = cmd
-                    .get_object::<SnippetId>()
-                    .ok()
-                    .cloned()
-                    .or(data.scribble.selected_snippet.as_draw())
-                {
-                    let new_snippets = data.scribble.snippets.without_snippet(id);
-                    data.scribble.snippets = new_snippets;
-                    if data.scribble.selected_snippet == id.into() {
-                        data.scribble.selected_snippet = MaybeSnippetId::None;
-                    }
-                    data.undo.borrow_mut().push(&data.scribble);
-                } else if let Some(id) = cmd
-                    .get_object::<AudioSnippetId>()
-                    .ok()
-                    .cloned()
-                    .or(data.scribble.selected_snippet.as_audio())
-                {
-                    let new_snippets = data.scribble.audio_snippets.without_snippet(id);
-                    data.scribble.audio_snippets = new_snippets;
-                    if data.scribble.selected_snippet == id.into() {
-                        data.scribble.selected_snippet = MaybeSnippetId::None;
+        if REPEATABLE_EDITS.contains(&cmd.selector)
+            && data.scribble.selected_snippet.as_draw().is_some()
+        {
+            self.last_edit = Some(cmd.clone());
+        }
+
+        if let Some(snip) = cmd::ADD_SNIPPET.get(cmd) {
+            let (new_snippets, new_id) = data.scribble.snippets.with_new_snippet(snip.clone());
+            data.scribble.snippets = new_snippets;
+            data.scribble.selected_snippet = new_id.into();
+            data.push_undo("Add Snippet");
+            true
+        } else if cmd.selector == cmd::DELETE_SNIPPET {
+            if data.scribble.selected_snippet.is_none() {
+                log::error!("No snippet id to delete");
+            } else {
+                data.scribble.delete_snippet(data.scribble.selected_snippet);
+                data.push_undo("Delete Snippet");
+            }
+            true
+        } else if let Some(&idx) = cmd::RESTORE_SNIPPET.get(cmd) {
+            data.scribble.restore_snippet(idx);
+            data.push_undo("Restore Snippet");
+            true
+        } else if let Some(snip) = cmd::ADD_AUDIO_SNIPPET.get(cmd) {
+            let (audio_snippets, _) = data.scribble.audio_snippets.with_new_snippet(snip.clone());
+            data.scribble.audio_snippets = audio_snippets;
+            data.push_undo("Add Audio");
+            true
+        } else if let Some(seg) = cmd::APPEND_NEW_SEGMENT.get(cmd) {
+            data.add_segment_to_snippet(seg.clone());
+            data.push_transient_undo();
+            true
+        } else if let Some(color) = cmd::CHOOSE_COLOR.get(cmd) {
+            data.palette.select(color);
+            true
+        } else if let Some(&fade) = cmd::SET_FADE.get(cmd) {
+            data.fade_enabled = fade;
+            true
+        } else if cmd.selector == cmd::NEXT_RECORDING_SPEED {
+            data.recording_speed = data.recording_speed.next();
+            true
+        } else if let Some(&mirror) = cmd::SET_MIRROR.get(cmd) {
+            data.mirror = mirror;
+            ctx.request_paint();
+            true
+        } else if let Some(tool) = cmd::SET_DRAWING_TOOL.get(cmd) {
+            data.drawing_tool = tool.clone();
+            true
+        } else if let Some(export) = cmd::EXPORT.get(cmd) {
+            let name = export
+                .filename
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+
+            // Encoder progress will be read whenever the timer ticks.
+            data.export_jobs = data.export_jobs.with_new_job(name);
+            self.export_queue
+                .get_or_insert_with(ExportQueue::new)
+                .push(export.clone());
+
+            true
+        } else if cmd.selector == cmd::SET_MARK {
+            data.scribble.mark = Some(data.time());
+            data.push_coalescing_undo("Set Mark", COALESCE_WINDOW);
+            true
+        } else if cmd.selector == cmd::TRUNCATE_SNIPPET {
+            if let Some(id) = data.scribble.selected_snippet.as_draw() {
+                data.scribble.snippets = data
+                    .scribble
+                    .snippets
+                    .with_truncated_snippet(id, data.time());
+                data.push_undo("Truncate Snippet");
+            } else {
+                log::error!("cannot truncate, nothing selected");
+            }
+            true
+        } else if cmd.selector == cmd::RECOLOR_SNIPPET {
+            if let Some(id) = data.scribble.selected_snippet.as_draw() {
+                let color = data.palette.selected_color();
+                data.scribble.snippets = data.scribble.snippets.with_recolored_snippet(id, color);
+                data.push_undo("Recolor Snippet");
+            } else {
+                log::error!("cannot recolor, nothing selected");
+            }
+            true
+        } else if let Some(&transform) = cmd::TRANSFORM_SNIPPET.get(cmd) {
+            if let Some(id) = data.scribble.selected_snippet.as_draw() {
+                data.scribble.snippets = data
+                    .scribble
+                    .snippets
+                    .with_transformed_snippet(id, transform);
+                data.push_coalescing_undo("Transform Snippet", COALESCE_WINDOW);
+            } else {
+                log::error!("cannot transform, nothing selected");
+            }
+            true
+        } else if let Some(&factor) = cmd::CHANGE_SPEED.get(cmd) {
+            if let Some(id) = data.scribble.selected_snippet.as_draw() {
+                data.scribble.snippets = data.scribble.snippets.with_speed_factor(id, factor);
+                data.push_coalescing_undo("Change Speed", COALESCE_WINDOW);
+            } else {
+                log::error!("cannot change speed, nothing selected");
+            }
+            true
+        } else if let Some(bytes) = cmd::ADD_IMAGE.get(cmd) {
+            self.add_image(bytes.clone(), data);
+            true
+        } else if let Some(bytes) = cmd::SET_WATERMARK.get(cmd) {
+            let mut watermark = data.scribble.settings.watermark.clone();
+            watermark.encoded = bytes.clone().map(Arc::new);
+            // Check that we can actually decode the image before accepting it.
+            if let Err(e) = watermark.image() {
+                log::error!("failed to load watermark: {}", e);
+            } else {
+                data.scribble.settings.watermark = watermark;
+                data.push_undo("Set Watermark");
+            }
+            true
+        } else if cmd.selector == cmd::PASTE_IMAGE {
+            match clipboard_image() {
+                Ok(Some(bytes)) => self.add_image(bytes, data),
+                Ok(None) => log::warn!("there is no image on the clipboard"),
+                Err(e) => log::error!("failed to read the copied image: {}", e),
+            }
+            true
+        } else if cmd.selector == cmd::END_IMAGES {
+            data.scribble.images = data
+                .scribble
+                .images
+                .with_visible_snippets_ended(data.time());
+            data.push_undo("End Images");
+            true
+        } else if let Some(&fade_in) = cmd::SET_FADE_IN_IMAGES.get(cmd) {
+            data.fade_in_images = fade_in;
+            true
+        } else if let Some(&show) = cmd::SET_SHOW_MEASURE.get(cmd) {
+            data.show_measure = show;
+            true
+        } else if cmd.selector == cmd::RESET_ZOOM {
+            data.canvas_zoom = 1.0;
+            data.canvas_pan = Vec2::ZERO;
+            true
+        } else if let Some(&aspect_ratio) = cmd::SET_ASPECT_RATIO.get(cmd) {
+            data.scribble.settings.aspect_ratio = aspect_ratio;
+            data.push_undo("Change Aspect Ratio");
+            true
+        } else if let Some(color) = cmd::SET_BACKGROUND.get(cmd) {
+            data.scribble.settings.background = color.clone();
+            data.push_undo("Change Background");
+            true
+        } else if let Some(&show) = cmd::SET_SHOW_PEN.get(cmd) {
+            data.show_pen = show;
+            true
+        } else if let Some(&show) = cmd::SET_SHOW_CAPTIONS.get(cmd) {
+            data.show_captions = show;
+            true
+        } else if let Some(&on) = cmd::SET_LIVE_OUTPUT.get(cmd) {
+            self.live_output = None;
+            if on {
+                let target = Path::new(&data.prefs.live_output_target);
+                self.live_output = Some(LiveOutput::start(target, data));
+            }
+            data.live_output = self.live_output.is_some();
+            true
+        } else if let Some(&role) = cmd::SET_COLLAB.get(cmd) {
+            self.collab = None;
+            data.peer_strokes = Arc::new(Vec::new());
+            if let Some(role) = role {
+                match Collab::start(role, data) {
+                    Ok(collab) => self.collab = Some(collab),
+                    Err(e) => {
+                        log::error!("failed to start collaborating: {}", e);
+                        self.show_toast(format!("Couldn't start collaborating: {}", e), data);
                     }
-                    data.undo.borrow_mut().push(&data.scribble);
-                } else {
-                    log::error!("No snippet id to delete");
-                }
-                true
-            }
-            cmd::ADD_AUDIO_SNIPPET => {
-                let snip = cmd
-                    .get_object::<AudioSnippetData>()
-                    .expect("no audio snippet");
-                data.scribble.audio_snippets =
-                    data.scribble.audio_snippets.with_new_snippet(snip.clone());
-                data.undo.borrow_mut().push(&data.scribble);
-                true
-            }
-            cmd::APPEND_NEW_SEGMENT => {
-                let seg = cmd.get_object::<SegmentInProgress>().expect("no segment");
-                data.add_segment_to_snippet(seg.clone());
-                data.undo.borrow_mut().push_transient(&data.scribble);
-                true
-            }
-            cmd::CHOOSE_COLOR => {
-                let color = cmd.get_object::<Color>().expect("API violation");
-                data.palette.select(color);
-                true
-            }
-            cmd::EXPORT => {
-                let export = cmd.get_object::<cmd::ExportCmd>().expect("API violation");
-
-                if self.encoder_progress.is_some() {
-                    log::warn!("already encoding, not doing another one");
-                } else {
-                    let (tx, rx) = channel();
-                    let export = export.clone();
-                    // Encoder progress will be read whenever the timer ticks, and when encoding
-                    // is done this will be set back to `None`.
-                    self.encoder_progress = Some(rx);
-                    data.encoding_status = None;
-                    std::thread::spawn(move || crate::encode::encode_blocking(export, tx));
                 }
-
-                true
             }
-            cmd::SET_MARK => {
-                let time = *cmd.get_object::<Time>().unwrap_or(&data.time());
-                data.scribble.mark = Some(time);
-                data.undo.borrow_mut().push(&data.scribble);
-                true
+            data.collab = self.collab.as_ref().map(|c| c.role());
+            true
+        } else if cmd.selector == cmd::REPEAT_LAST_EDIT {
+            if let Some(edit) = self.last_edit.clone() {
+                ctx.submit_command(edit, None);
+            } else {
+                log::warn!("no edit to repeat");
+            }
+            true
+        } else if cmd.selector == cmd::REVERSE_SNIPPET {
+            if let Some(id) = data.scribble.selected_snippet.as_draw() {
+                data.scribble.snippets = data.scribble.snippets.with_reversed_snippet(id);
+                data.push_undo("Reverse Snippet");
+            } else {
+                log::error!("cannot reverse, nothing selected");
             }
-            cmd::TRUNCATE_SNIPPET => {
-                if let Some(id) = data.scribble.selected_snippet.as_draw() {
-                    data.scribble.snippets = data
-                        .scribble
-                        .snippets
-                        .with_truncated_snippet(id, data.time());
-                    data.undo.borrow_mut().push(&data.scribble);
-                } else {
-                    log::error!("cannot truncate, nothing selected");
+            true
+        } else if let Some(&dialog) = cmd::SHOW_DIALOG.get(cmd) {
+            if dialog == Dialog::Preferences {
+                data.edited_prefs = data.prefs.clone();
+            }
+            if dialog == Dialog::Captions {
+                data.edited_captions = data.scribble.captions.clone();
+            }
+            if dialog == Dialog::CommandPalette {
+                data.command_query.clear();
+            }
+            data.dialog = Some(dialog);
+            true
+        } else if let Some(prefs) = cmd::SET_PREFERENCES.get(cmd) {
+            if prefs.input_device != data.prefs.input_device
+                || prefs.output_device != data.prefs.output_device
+            {
+                data.audio
+                    .borrow_mut()
+                    .set_devices(&prefs.input_device, &prefs.output_device);
+            }
+            data.fade_in_images = prefs.fade_in_images;
+            data.show_pen = prefs.show_pen;
+            data.prefs = prefs.clone();
+            if let Err(e) = data.prefs.save() {
+                log::error!("error saving preferences: {}", e);
+            }
+            true
+        } else if let Some(&format) = cmd::SET_SAVE_FORMAT.get(cmd) {
+            data.prefs.save_format = format;
+            if let Err(e) = data.prefs.save() {
+                log::error!("error saving preferences: {}", e);
+            }
+            true
+        } else if cmd.selector == cmd::RESTORE_AUTOSAVE {
+            match crate::autosave::recover(data.save_path.as_deref()) {
+                Ok(scribble) => {
+                    data.scribble = scribble;
+                    data.push_undo("Restore Autosave");
                 }
-                true
-            }
-            cmd::LERP_SNIPPET => {
-                if let (Some(mark_time), Some(id)) =
-                    (data.scribble.mark, data.scribble.selected_snippet.as_draw())
-                {
-                    data.scribble.snippets =
-                        data.scribble
-                            .snippets
-                            .with_new_lerp(id, data.time(), mark_time);
-                    data.undo.borrow_mut().push(&data.scribble);
-                    ctx.submit_command(Command::new(cmd::WARP_TIME_TO, mark_time), None);
+                Err(e) => log::error!("error restoring autosave: {}", e),
+            }
+            true
+        } else if let Some(path) = cmd::SAVE_PROJECT
+            .get(cmd)
+            .or_else(|| cmd::OPEN_PROJECT.get(cmd))
+        {
+            if data.file_op.is_some() {
+                log::warn!("already saving or loading, ignoring {:?}", path);
+                return true;
+            }
+            let saving = cmd::SAVE_PROJECT.get(cmd).is_some();
+            data.file_op = Some(FileOp::new(saving, path));
+            let io = self.project_io.get_or_insert_with(ProjectIo::new);
+            if saving {
+                // The animation is behind `Arc`s, so this snapshot is cheap. Edits made while
+                // the save is running won't be included.
+                let history = if data.prefs.save_undo_history {
+                    let history = data.undo.borrow().history(MAX_SAVED_UNDO);
+                    Some(history.iter().map(|s| s.to_save_file()).collect())
                 } else {
-                    log::error!(
-                        "cannot lerp, mark time {:?}, selected {:?}",
-                        data.scribble.mark,
-                        data.scribble.selected_snippet
-                    );
+                    None
+                };
+                io.save(
+                    path.clone(),
+                    data.scribble.to_save_file(),
+                    data.prefs.save_format,
+                    data.prefs.backups,
+                    history,
+                );
+            } else {
+                io.load(path.clone(), data.prefs.save_undo_history);
+            }
+            true
+        } else if cmd.selector == cmd::SAVE_CHANGES {
+            let action = data.unsaved_action.take();
+            if let Some(path) = data.save_path.clone() {
+                data.after_save = action;
+                ctx.submit_command(cmd::SAVE_PROJECT.with(path), None);
+            } else {
+                // There's no way to tell whether the user cancels the save panel, so we
+                // don't try to go ahead afterwards. They'll have to try again once it's
+                // saved.
+                ctx.submit_command(
+                    Command::new(
+                        druid::commands::SHOW_SAVE_PANEL,
+                        FileDialogOptions::new()
+                            .allowed_types(vec![crate::menus::SCRIBBLE_FILE_TYPE]),
+                    ),
+                    None,
+                );
+            }
+            true
+        } else if cmd.selector == cmd::DISCARD_CHANGES {
+            if let Some((action, target)) = data.unsaved_action.take() {
+                // Pretend that everything is saved, so that the action doesn't get held up
+                // again.
+                data.saved = data.scribble.clone();
+                ctx.submit_command(action, target);
+            }
+            true
+        } else if let Some(path) = cmd::IMPORT_PROJECT.get(cmd) {
+            match SaveFileData::load_from_path(path) {
+                Ok(save_file) => {
+                    let imported = ScribbleState::from_save_file(save_file.with_audio_loaded());
+                    let offset = (data.time() - time::ZERO)
+                        + Diff::from_micros((data.import_offset * 1e6) as i64);
+                    data.scribble = data.scribble.with_imported(&imported, offset);
+                    data.push_undo("Import Project");
                 }
-                true
-            }
-            druid::commands::UNDO => {
-                let undone_state = data.undo.borrow_mut().undo();
-                if let Some(undone_state) = undone_state {
-                    data.scribble = undone_state;
-                    ctx.request_paint();
-
-                    // This is a bit of a special-case hack. If there get to be
-                    // more of these, it might be worth storing some
-                    // metadata in the undo state.
-                    //
-                    // In case the undo resets us to a mid-recording state, we
-                    // ensure that the state is waiting-to-record (i.e.,
-                    // recording but paused).
-                    if let Some(ref new_curve) = data.scribble.new_curve {
-                        let time = *new_curve.times.last().unwrap();
-                        data.warp_time_to(time);
-                        data.ensure_recording();
-                    }
+                Err(e) => log::error!("error importing {:?}: {}", path, e),
+            }
+            true
+        } else if let Some(path) = cmd::IMPORT_DRAWING.get(cmd) {
+            let height = data.scribble.settings.drawing_height();
+            let draw_on = data.prefs.draw_on_imported_drawings;
+            let snip = crate::whiteboard::load(path, height)
+                .map(|strokes| crate::whiteboard::to_snippet(&strokes, data.time(), draw_on));
+            match snip {
+                Ok(Some(snip)) => {
+                    let (new_snippets, new_id) = data.scribble.snippets.with_new_snippet(snip);
+                    data.scribble.snippets = new_snippets;
+                    data.scribble.selected_snippet = new_id.into();
+                    data.push_undo("Import Drawing");
                 }
-                true
+                Ok(None) => {}
+                Err(e) => log::error!("error importing drawing from {:?}: {}", path, e),
             }
-            druid::commands::REDO => {
-                if let Some(redone_state) = data.undo.borrow_mut().redo() {
-                    data.scribble = redone_state;
-                    ctx.request_paint();
+            true
+        } else if let Some(path) = cmd::IMPORT_CAPTIONS.get(cmd) {
+            match crate::captions::load(path, &data.scribble.audio_snippets, data.time()) {
+                Ok(captions) => {
+                    data.scribble.captions = captions;
+                    data.show_captions = true;
+                    data.push_undo("Import Captions");
                 }
-                true
+                Err(e) => log::error!("error importing captions from {:?}: {}", path, e),
             }
-            cmd::PLAY => {
-                if data.action.is_idle() {
-                    data.start_playing();
-                } else {
-                    log::error!("can't play, current action is {:?}", data.action);
+            true
+        } else if cmd.selector == cmd::TRANSCRIBE {
+            let snippets: Vec<_> = match data.scribble.selected_snippet {
+                MaybeSnippetId::Audio(id) => vec![data.scribble.audio_snippets.snippet(id).clone()],
+                _ => data
+                    .scribble
+                    .audio_snippets
+                    .snippets()
+                    .map(|(_, snip)| snip.clone())
+                    .collect(),
+            };
+            if data.prefs.whisper_model.is_empty() {
+                log::error!("can't transcribe: there's no speech-to-text model in the preferences");
+            } else if snippets.is_empty() {
+                log::error!("can't transcribe: there's no audio");
+            } else if !data.transcribing {
+                data.transcribing = true;
+                let model = data.prefs.whisper_model.clone().into();
+                let language = data.prefs.transcription_language.clone();
+                self.transcriber
+                    .get_or_insert_with(Transcriber::new)
+                    .transcribe(snippets, model, language);
+            }
+            true
+        } else if let Some(path) = cmd::ADD_AUDIO_FILE.get(cmd) {
+            match AudioSnippetData::from_file(path, data.time(), data.prefs.link_audio) {
+                Ok(snip) => {
+                    data.scribble.audio_snippets =
+                        data.scribble.audio_snippets.with_new_snippet(snip).0;
+                    data.push_undo("Import Audio");
                 }
-                true
+                Err(e) => log::error!("error importing audio from {:?}: {}", path, e),
             }
-            cmd::DRAW => {
-                if data.action.is_idle() {
-                    data.start_recording(data.recording_speed.factor());
-                } else {
-                    log::error!("can't draw, current action is {:?}", data.action);
+            true
+        } else if let Some(paths) = cmd::DROP_FILES.get(cmd) {
+            self.drop_files(ctx, paths, data);
+            true
+        } else if let Some(path) = cmd::RELINK_AUDIO.get(cmd) {
+            let missing = data
+                .scribble
+                .audio_snippets
+                .first_missing_link()
+                .map(|p| p.to_owned());
+            if let Some(missing) = missing {
+                match scribble_core::audio_file::decode(path) {
+                    Ok(buf) => {
+                        data.scribble.audio_snippets = data
+                            .scribble
+                            .audio_snippets
+                            .with_relinked(&missing, path, buf)
+                            .spilled();
+                        data.push_undo("Relink Audio");
+                    }
+                    Err(e) => log::error!("error loading audio from {:?}: {}", path, e),
                 }
-                true
             }
-            cmd::TALK => {
-                if data.action.is_idle() {
-                    data.start_recording_audio();
-                } else {
-                    log::error!("can't talk, current action is {:?}", data.action);
+            if data.scribble.audio_snippets.first_missing_link().is_none() {
+                data.dialog = None;
+            }
+            true
+        } else if cmd.selector == cmd::NEW_TAB
+            || cmd.selector == cmd::CLOSE_TAB
+            || cmd::SWITCH_TAB.get(cmd).is_some()
+        {
+            if !crate::tabs::can_switch(data) {
+                log::warn!("can't switch tabs right now");
+                return true;
+            }
+            if let Some(&idx) = cmd::SWITCH_TAB.get(cmd) {
+                crate::tabs::switch_to(data, idx);
+            } else if cmd.selector == cmd::NEW_TAB {
+                crate::tabs::new_tab(data);
+            } else {
+                crate::tabs::close_tab(data);
+            }
+            true
+        } else if cmd.selector == cmd::COPY_SNIPPET {
+            data.copy_snippet();
+            true
+        } else if cmd.selector == cmd::PASTE_SNIPPET {
+            if data.snippet_clipboard.is_some() {
+                data.paste_snippet();
+                data.push_undo("Paste Snippet");
+            }
+            true
+        } else if let Some(path) = cmd::LOAD_PALETTE.get(cmd) {
+            match crate::palette_file::load(path) {
+                Ok(colors) => data.palette.set_colors(colors),
+                Err(e) => log::error!("error loading palette {:?}: {}", path, e),
+            }
+            true
+        } else if let Some(path) = cmd::SAVE_PALETTE.get(cmd) {
+            if let Err(e) = crate::palette_file::save(path, data.palette.colors()) {
+                log::error!("error saving palette {:?}: {}", path, e);
+            }
+            true
+        } else if let Some(&link) = cmd::SET_LINK_AUDIO.get(cmd) {
+            data.prefs.link_audio = link;
+            if let Err(e) = data.prefs.save() {
+                log::error!("error saving preferences: {}", e);
+            }
+            true
+        } else if cmd.selector == cmd::CLOSE_DIALOG {
+            data.dialog = None;
+            // The dialog might have taken the focus, so take it back.
+            ctx.request_focus();
+            true
+        } else if cmd.selector == cmd::LERP_SNIPPET {
+            if let (Some(mark_time), Some(id)) =
+                (data.scribble.mark, data.scribble.selected_snippet.as_draw())
+            {
+                data.scribble.snippets =
+                    data.scribble
+                        .snippets
+                        .with_new_lerp(id, data.time(), mark_time);
+                data.push_undo("Add Lerp");
+                ctx.submit_command(cmd::WARP_TIME_TO.with(mark_time), None);
+            } else {
+                log::error!(
+                    "cannot lerp, mark time {:?}, selected {:?}",
+                    data.scribble.mark,
+                    data.scribble.selected_snippet
+                );
+            }
+            true
+        } else if cmd.selector == cmd::BEGIN_UNDO_GROUP {
+            data.undo.borrow_mut().begin_group();
+            true
+        } else if cmd.selector == cmd::END_UNDO_GROUP {
+            data.undo.borrow_mut().end_group();
+            true
+        } else if let Some(&idx) = cmd::SWITCH_BRANCH.get(cmd) {
+            let state = data.undo.borrow_mut().switch_branch(idx);
+            if let Some(state) = state {
+                data.restore_undo_state(state);
+                ctx.request_paint();
+            }
+            true
+        } else if cmd.selector == druid::commands::UNDO {
+            let undone_state = data.undo.borrow_mut().undo();
+            if let Some(undone_state) = undone_state {
+                data.restore_undo_state(undone_state);
+                ctx.request_paint();
+            }
+            true
+        } else if cmd.selector == druid::commands::REDO {
+            let redone_state = data.undo.borrow_mut().redo();
+            if let Some(redone_state) = redone_state {
+                data.restore_undo_state(redone_state);
+                ctx.request_paint();
+            }
+            true
+        } else if cmd.selector == cmd::PLAY {
+            if data.action.is_idle() {
+                data.start_playing();
+            } else {
+                log::error!("can't play, current action is {:?}", data.action);
+            }
+            true
+        } else if cmd.selector == cmd::DRAW {
+            if data.action.is_idle() {
+                data.start_recording(data.recording_speed.factor());
+            } else {
+                log::error!("can't draw, current action is {:?}", data.action);
+            }
+            true
+        } else if cmd.selector == cmd::TALK {
+            if data.action.is_idle() {
+                data.start_recording_audio();
+            } else {
+                log::error!("can't talk, current action is {:?}", data.action);
+            }
+            true
+        } else if cmd.selector == cmd::TOGGLE_DRAW || cmd.selector == cmd::TOGGLE_TALK {
+            if !data.action.is_idle() {
+                ctx.submit_command(cmd::STOP, None);
+            } else if cmd.selector == cmd::TOGGLE_DRAW {
+                ctx.submit_command(cmd::DRAW, None);
+            } else {
+                ctx.submit_command(cmd::TALK, None);
+            }
+            true
+        } else if let Some(req) = cmd::REMOTE_REQUEST.get(cmd) {
+            let (command, response) = crate::remote::handle(&req.request, data);
+            if let Some(command) = command {
+                ctx.submit_command(command, None);
+            }
+            // If the client gave up waiting, there's nobody to tell.
+            let _ = req.reply.send(response);
+            true
+        } else if let Some(event) = cmd::REMOTE_PEN.get(cmd) {
+            self.remote_pen(ctx, event, data);
+            true
+        } else if let Some(&event) = cmd::MIDI_SYNC.get(cmd) {
+            if data.prefs.follow_midi_sync {
+                if let SyncEvent::Timecode(_) = event {
+                    self.last_timecode = Some(Instant::now());
                 }
-                true
-            }
-            cmd::STOP => {
-                match data.action {
-                    CurrentAction::Idle => {}
-                    CurrentAction::Scanning(_) => {}
-                    CurrentAction::Playing => data.stop_playing(),
-                    CurrentAction::WaitingToRecord(_) | CurrentAction::Recording(_) => {
-                        if let Some(new_snippet) = data.stop_recording() {
-                            ctx.submit_command(Command::new(cmd::ADD_SNIPPET, new_snippet), None);
-                        }
-                    }
-                    CurrentAction::RecordingAudio(_) => {
-                        let snip = data.stop_recording_audio();
-                        ctx.submit_command(Command::new(cmd::ADD_AUDIO_SNIPPET, snip), None);
+                crate::midi_sync::follow(data, event);
+            }
+            true
+        } else if let Some(control) = cmd::CONTROLLER_INPUT.get(cmd) {
+            match data.prefs.controller_bindings.get(control) {
+                Some(action) => match command_for(action, data) {
+                    Some(command) => ctx.submit_command(command, None),
+                    None => log::warn!("nothing to do for {} ({})", control, action),
+                },
+                None => log::info!("{} isn't bound to anything", control),
+            }
+            true
+        } else if cmd.selector == cmd::STOP {
+            match data.action {
+                CurrentAction::Idle => {}
+                CurrentAction::Scanning(_) => {}
+                CurrentAction::Playing => data.stop_playing(),
+                CurrentAction::WaitingToRecord(_) | CurrentAction::Recording(_) => {
+                    if let Some(new_snippet) = data.stop_recording() {
+                        ctx.submit_command(cmd::ADD_SNIPPET.with(new_snippet), None);
                     }
                 }
-                true
-            }
-            cmd::WARP_TIME_TO => {
-                if data.action.is_idle() {
-                    data.warp_time_to(*cmd.get_object::<Time>().expect("API violation"));
-                } else {
-                    log::warn!("not warping: state is {:?}", data.action)
+                CurrentAction::RecordingAudio(_) => {
+                    let snip = data.stop_recording_audio();
+                    ctx.submit_command(cmd::ADD_AUDIO_SNIPPET.with(snip), None);
                 }
-                true
             }
-            _ => false,
-        };
-        // This might be a little conservative, but there are lots of state
-        // changes that cause the menus to change, so the easiest thing is just
-        // to rebuild the menus on every command.
-        ctx.submit_command(cmd::REBUILD_MENUS, None);
-        ret
+            true
+        } else if let Some(jump) = cmd::JUMP.get(cmd) {
+            if let Some(time) = jump.target(&data.scribble, data.time()) {
+                ctx.submit_command(cmd::WARP_TIME_TO.with(time), None);
+            }
+            true
+        } else if let Some(&time) = cmd::WARP_TIME_TO.get(cmd) {
+            if data.action.is_idle() {
+                data.warp_time_to(time);
+            } else {
+                log::warn!("not warping: state is {:?}", data.action)
+            }
+            true
+        } else {
+            false
+        }
     }
 }
 
 impl Widget<AppState> for Root {
     fn event(&mut self, ctx: &mut EventCtx, event: &Event, data: &mut AppState, env: &Env) {
+        // Anything the user does while a project is loading would get thrown away when it
+        // finishes, so we ignore their input until then.
+        if data.is_loading() {
+            match event {
+                Event::MouseDown(_)
+                | Event::MouseUp(_)
+                | Event::MouseMove(_)
+                | Event::Wheel(_)
+                | Event::KeyDown(_)
+                | Event::KeyUp(_)
+                | Event::Command(_) => return,
+                _ => {}
+            }
+        }
+
         match event {
             Event::WindowConnected => {
                 ctx.request_focus();
@@ -415,15 +1094,45 @@ impl Widget<AppState> for Root {
             Event::Timer(tok) => {
                 if tok == &self.timer_id {
                     // Handle any status reports from the encoder.
-                    if let Some(ref rx) = self.encoder_progress {
-                        if let Some(status) = rx.try_iter().last() {
+                    if let Some(queue) = &self.export_queue {
+                        for status in queue.status.try_iter() {
+                            data.export_jobs = data.export_jobs.with_status(status.clone());
                             data.encoding_status = Some(status);
                         }
-                        match data.encoding_status {
-                            Some(EncodingStatus::Finished) | Some(EncodingStatus::Error(_)) => {
-                                self.encoder_progress = None;
+                    }
+
+                    // Handle any status reports from saving or loading.
+                    if let Some(io) = &self.project_io {
+                        for status in io.status.try_iter() {
+                            crate::project_io::apply_status(data, status);
+                        }
+                        if data.file_op.is_none() {
+                            if let Some((action, target)) = data.after_save.take() {
+                                ctx.submit_command(action, target);
+                            }
+                        }
+                    }
+
+                    // Handle any finished transcriptions.
+                    if let Some(transcriber) = &self.transcriber {
+                        for status in transcriber.status.try_iter() {
+                            data.transcribing = false;
+                            match status {
+                                TranscribeStatus::Finished { spans, captions } => {
+                                    // The captions dialog might be open, and its copy should
+                                    // get the new captions too.
+                                    data.edited_captions = data
+                                        .edited_captions
+                                        .with_replaced(&spans, captions.clone());
+                                    data.scribble.captions =
+                                        data.scribble.captions.with_replaced(&spans, captions);
+                                    data.show_captions = true;
+                                    data.push_undo("Transcribe Narration");
+                                }
+                                TranscribeStatus::Failed(e) => {
+                                    log::error!("error transcribing narration: {}", e)
+                                }
                             }
-                            _ => {}
                         }
                     }
 
@@ -432,14 +1141,51 @@ impl Widget<AppState> for Root {
 
                     // Update the current time, if necessary.
                     data.update_time();
+                    let io = self.project_io.get_or_insert_with(ProjectIo::new);
+                    self.autosave.tick(data, io);
+                    if self
+                        .toast_shown
+                        .map_or(false, |t| t.elapsed() > TOAST_DURATION)
+                    {
+                        self.toast_shown = None;
+                        data.toast = None;
+                    }
+                    if let Some(live) = &mut self.live_output {
+                        live.update(data);
+                        if let Some(e) = live.failure() {
+                            ctx.submit_command(cmd::SET_LIVE_OUTPUT.with(false), None);
+                            self.show_toast(format!("Live output stopped: {}", e), data);
+                        }
+                    }
+                    self.update_midi_sync(data);
+                    self.update_collab(data);
                     self.timer_id = ctx.request_timer(FRAME_TIME);
                     ctx.set_handled();
                 }
             }
+            // Mouse buttons that are bound to actions work anywhere in the window. (Except for
+            // erasing, which only makes sense in the drawing pane.)
+            Event::MouseDown(ev) => match data.prefs.mouse_action(ev) {
+                Some(action) if action != "erase" => {
+                    if let Some(command) = command_for(action, data) {
+                        ctx.submit_command(command, None);
+                    }
+                    ctx.set_handled();
+                }
+                _ => self.inner.event(ctx, event, data, env),
+            },
             _ => {
                 self.inner.event(ctx, event, data, env);
             }
         }
+
+        // Lots of things (not just commands) can change what the menus show, so we check after
+        // every event.
+        let menu_state = MenuState::new(data);
+        if self.menu_state.as_ref() != Some(&menu_state) {
+            self.menu_state = Some(menu_state);
+            ctx.submit_command(cmd::REBUILD_MENUS, None);
+        }
     }
 
     fn update(&mut self, ctx: &mut UpdateCtx, old_data: &AppState, data: &AppState, env: &Env) {
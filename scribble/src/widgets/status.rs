@@ -1,10 +1,14 @@
 use druid::lens;
 use druid::widget::prelude::*;
-use druid::widget::{Align, Either, Flex, Label, ProgressBar, WidgetExt};
+use druid::widget::{Align, Button, Either, Flex, Label, List, ProgressBar, SizedBox, WidgetExt};
 use druid::LensExt;
+use std::sync::Arc;
 
+use crate::command_palette::shortcut_hints;
 use crate::data::AppState;
 use crate::encode::EncodingStatus;
+use crate::export_queue::{ExportJob, ExportJobs};
+use crate::project_io::FileOp;
 
 pub fn make_status_bar() -> impl Widget<AppState> {
     let time_label = Label::new(|data: &AppState, _env: &Env| {
@@ -28,8 +32,8 @@ pub fn make_status_bar() -> impl Widget<AppState> {
 
     let progress = ProgressBar::new().lens(lens::Id.map(
         |s| {
-            if let Some(EncodingStatus::Encoding(x)) = s {
-                *x
+            if let Some(EncodingStatus::Encoding(p)) = s {
+                p.fraction()
             } else {
                 0.0
             }
@@ -37,19 +41,72 @@ pub fn make_status_bar() -> impl Widget<AppState> {
         |_, _| {},
     ));
 
+    let progress_detail = Label::new(|data: &Option<EncodingStatus>, _env: &Env| match data {
+        Some(EncodingStatus::Encoding(p)) => p.to_string(),
+        _ => String::new(),
+    });
+
     let status_label = Either::new(
         |data: &Option<EncodingStatus>, _env| matches!(data, Some(EncodingStatus::Encoding(_))),
         Flex::row()
             .with_child(status_label_encoding)
             .with_child(progress)
+            .with_spacer(5.0)
+            .with_child(progress_detail)
             .with_flex_spacer(1.0),
         status_label_not_encoding,
     )
-    .fix_width(250.0); // TODO: can we make this depend on the text width?
+    .fix_width(450.0); // TODO: can we make this depend on the text width?
+
+    let hints = Label::new(|data: &AppState, _env: &Env| shortcut_hints(data));
+    let toast = Label::new(|data: &Option<Arc<String>>, _env: &Env| {
+        data.as_deref().cloned().unwrap_or_default()
+    });
 
     let row = Flex::row()
         .with_child(time_label)
+        .with_spacer(10.0)
+        .with_child(hints)
         .with_flex_spacer(1.0)
+        .with_child(toast.lens(AppState::toast))
+        .with_spacer(5.0)
+        .with_child(make_file_op_status().lens(AppState::file_op))
+        .with_spacer(5.0)
         .with_child(status_label.lens(AppState::encoding_status));
-    Align::centered(row)
+    Flex::column()
+        .with_child(Align::centered(row))
+        .with_child(make_export_queue().lens(AppState::export_jobs))
+}
+
+// The progress of saving or loading (which is only shown while one is running).
+fn make_file_op_status() -> impl Widget<Option<FileOp>> {
+    let label = Label::new(|data: &Option<FileOp>, _env: &Env| {
+        data.as_ref().map(|op| op.description()).unwrap_or_default()
+    });
+    let progress = ProgressBar::new().lens(lens::Id.map(
+        |op: &Option<FileOp>| op.as_ref().map_or(0.0, |op| op.progress),
+        |_, _| {},
+    ));
+    Either::new(
+        |data: &Option<FileOp>, _env| data.is_some(),
+        Flex::row().with_child(label).with_child(progress),
+        SizedBox::empty(),
+    )
+}
+
+// A list of all the queued exports (which is only shown if there are any).
+fn make_export_queue() -> impl Widget<ExportJobs> {
+    let list = List::new(|| Label::new(|job: &ExportJob, _env: &Env| job.description()))
+        .lens(ExportJobs::jobs);
+    let clear = Button::new("Clear finished").on_click(|_ctx, data: &mut ExportJobs, _env| {
+        *data = data.without_done_jobs();
+    });
+    Either::new(
+        |data: &ExportJobs, _env| data.is_empty(),
+        SizedBox::empty(),
+        Flex::row()
+            .with_child(list)
+            .with_flex_spacer(1.0)
+            .with_child(clear),
+    )
 }
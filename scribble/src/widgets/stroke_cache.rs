@@ -0,0 +1,182 @@
+//! A cache for the drawing pane, so that it doesn't have to draw every stroke in a long project on
+//! every frame.
+//!
+//! Most of the snippets on the canvas are usually "settled" (see `SnippetData::is_settled_at`):
+//! they've finished drawing and fading, so they'll look the same until they disappear. We draw
+//! those ones into an off-screen bitmap, and then each frame only needs to draw the bitmap and the
+//! few snippets that are still changing. The bitmap gets redrawn when the snippets or the view
+//! change, when a cached snippet disappears (after seeking backwards, say), or when too many
+//! settled snippets are missing from it.
+//!
+//! The cached snippets get drawn underneath all the others, but snippets are supposed to be
+//! drawn in order of their ids. So only the settled snippets that come before every visible,
+//! unsettled one get cached: an old snippet that's still fading (or un-drawing itself) keeps the
+//! ones after it out of the bitmap until it settles.
+//!
+//! Uploading the bitmap to the GPU isn't free either, so that only happens the first time it gets
+//! drawn after being redrawn.
+
+use anyhow::anyhow;
+use druid::piet::{Device, ImageFormat, InterpolationMode, Piet};
+use druid::{Affine, Color, Data, Point, Rect, RenderContext};
+use std::collections::BTreeSet;
+
+use scribble_curves::{SnippetId, SnippetsData, Time};
+
+type Image = <Piet<'static> as RenderContext>::Image;
+
+/// If this many settled snippets aren't in the bitmap, it's time to redraw it.
+const MAX_UNCACHED: usize = 16;
+
+#[derive(Default)]
+pub struct StrokeCache {
+    layer: Option<Layer>,
+}
+
+// The settled snippets, drawn into a bitmap.
+struct Layer {
+    snippets: SnippetsData,
+    ids: BTreeSet<SnippetId>,
+    // The part of the widget (in widget coordinates) that the bitmap covers.
+    rect: Rect,
+    // The transformation from image coordinates to widget coordinates.
+    transform: Affine,
+    // The size of the bitmap (in pixels) and its contents, as premultiplied RGBA.
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+    // The bitmap as an image for the render context, created the first time it's needed.
+    image: Option<Image>,
+}
+
+impl StrokeCache {
+    /// Makes sure that the cache is usable for drawing `snippets` at `time`, redrawing it if
+    /// necessary.
+    ///
+    /// `rect` is the visible part of the canvas and `transform` goes from image coordinates to
+    /// widget coordinates. `scale` is the number of pixels per unit of widget coordinates. The
+    /// snippet `skip` (if there is one) doesn't get cached, because it's about to get drawn
+    /// somewhere else (and so neither do the ones after it).
+    pub fn update(
+        &mut self,
+        snippets: &SnippetsData,
+        time: Time,
+        skip: Option<SnippetId>,
+        rect: Rect,
+        transform: Affine,
+        scale: f64,
+    ) {
+        let rect = rect.expand();
+        let mut settled = BTreeSet::new();
+        for (id, snip) in snippets.snippets() {
+            if !snip.visible_at(time) {
+                continue;
+            }
+            if Some(id) == skip || !snip.is_settled_at(time) {
+                break;
+            }
+            settled.insert(id);
+        }
+
+        if let Some(layer) = &self.layer {
+            if layer.snippets.same(snippets)
+                && layer.rect == rect
+                && layer.transform == transform
+                && layer.ids.is_subset(&settled)
+                && settled.len() - layer.ids.len() < MAX_UNCACHED
+            {
+                return;
+            }
+        }
+
+        self.layer = None;
+        if settled.is_empty() || rect.area() <= 0.0 {
+            return;
+        }
+        match Layer::new(snippets, settled, rect, transform, scale, time) {
+            Ok(layer) => self.layer = Some(layer),
+            Err(e) => log::error!("failed to cache the drawing: {}", e),
+        }
+    }
+
+    /// Is this snippet in the cache? If so, it doesn't need to be drawn.
+    pub fn contains(&self, id: SnippetId) -> bool {
+        self.layer
+            .as_ref()
+            .map(|layer| layer.ids.contains(&id))
+            .unwrap_or(false)
+    }
+
+    /// Draws the cached snippets. `to_image` is the transformation from widget coordinates to
+    /// the coordinates of `ctx`.
+    pub fn render(&mut self, ctx: &mut Piet, to_image: Affine) {
+        let layer = match &mut self.layer {
+            Some(layer) => layer,
+            None => return,
+        };
+        let image = match layer.image.take() {
+            Some(image) => image,
+            None => match ctx.make_image(
+                layer.width,
+                layer.height,
+                &layer.pixels,
+                ImageFormat::RgbaPremul,
+            ) {
+                Ok(image) => image,
+                Err(e) => {
+                    log::error!("failed to create image: {}", e);
+                    return;
+                }
+            },
+        };
+        // There's no rotation, so transforming the corners is enough.
+        let dest = Rect::from_points(
+            to_image * layer.rect.origin(),
+            to_image * Point::new(layer.rect.x1, layer.rect.y1),
+        );
+        ctx.draw_image(&image, dest, InterpolationMode::NearestNeighbor);
+        layer.image = Some(image);
+    }
+}
+
+impl Layer {
+    fn new(
+        snippets: &SnippetsData,
+        ids: BTreeSet<SnippetId>,
+        rect: Rect,
+        transform: Affine,
+        scale: f64,
+        time: Time,
+    ) -> anyhow::Result<Layer> {
+        let width = (rect.width() * scale).ceil() as usize;
+        let height = (rect.height() * scale).ceil() as usize;
+        let mut device = Device::new().map_err(|_| anyhow!("couldn't open Device"))?;
+        let mut bitmap = device
+            .bitmap_target(width, height, scale)
+            .map_err(|_| anyhow!("couldn't create bitmap"))?;
+        {
+            let mut ctx = bitmap.render_context();
+            ctx.clear(Color::rgba8(0, 0, 0, 0));
+            ctx.transform(Affine::translate(-rect.origin().to_vec2()) * transform);
+            for &id in &ids {
+                snippets.snippet(id).render(&mut ctx, time);
+            }
+            ctx.finish()
+                .map_err(|_| anyhow!("error finishing render"))?;
+        }
+        let pixels = bitmap
+            .into_raw_pixels(ImageFormat::RgbaPremul)
+            .map_err(|_| anyhow!("couldn't get pixels"))?;
+
+        Ok(Layer {
+            snippets: snippets.clone(),
+            ids,
+            rect,
+            transform,
+            width,
+            height,
+            pixels,
+            image: None,
+        })
+    }
+}
@@ -0,0 +1,39 @@
+use druid::theme;
+use druid::widget::prelude::*;
+use druid::widget::{Button, Flex, Label, SizedBox, ViewSwitcher, WidgetExt};
+
+use crate::cmd;
+use crate::data::AppState;
+
+/// A row of tabs, one for each open project (which is only shown if there's more than one).
+pub fn make_tab_bar() -> impl Widget<AppState> {
+    ViewSwitcher::new(
+        |data: &AppState, _env| (crate::tabs::tab_names(data), data.current_tab),
+        |(names, current): &(Vec<String>, usize),
+         _data: &AppState,
+         _env: &Env|
+         -> Box<dyn Widget<AppState>> {
+            if names.len() <= 1 {
+                return Box::new(SizedBox::empty());
+            }
+            let mut row = Flex::row();
+            for (idx, name) in names.iter().enumerate() {
+                if idx == *current {
+                    row.add_child(
+                        Label::new(name.as_str())
+                            .padding(5.0)
+                            .border(theme::BORDER_LIGHT, theme::BUTTON_BORDER_WIDTH),
+                    );
+                } else {
+                    row.add_child(Button::new(name.as_str()).on_click(
+                        move |ctx, _data: &mut AppState, _env| {
+                            ctx.submit_command(cmd::SWITCH_TAB.with(idx), None);
+                        },
+                    ));
+                }
+                row.add_spacer(5.0);
+            }
+            Box::new(row.padding(5.0))
+        },
+    )
+}
@@ -2,9 +2,8 @@ use druid::kurbo::{BezPath, Line, Vec2};
 use druid::theme;
 use druid::widget::{Controller, Scroll};
 use druid::{
-    Affine, BoxConstraints, Color, Command, Data, Env, Event, EventCtx, LayoutCtx, LifeCycle,
-    LifeCycleCtx, PaintCtx, Point, Rect, RenderContext, Size, UpdateCtx, Widget, WidgetExt,
-    WidgetPod,
+    Affine, BoxConstraints, Color, Data, Env, Event, EventCtx, LayoutCtx, LifeCycle, LifeCycleCtx,
+    PaintCtx, Point, Rect, RenderContext, Size, UpdateCtx, Widget, WidgetExt, WidgetPod,
 };
 use std::collections::HashMap;
 
@@ -157,6 +156,16 @@ impl Snip {
     }
 }
 
+/// Holding shift while clicking (or dragging) on the timeline snaps the cursor to the nearest
+/// snippet boundary, which makes it easier to truncate or lerp at exactly the right time.
+fn snapped(time: Time, shift: bool, data: &AppState) -> Time {
+    if shift {
+        data.scribble.nearest_boundary(time).unwrap_or(time)
+    } else {
+        time
+    }
+}
+
 /// The main timeline widget.
 struct TimelineInner {
     // The timeline is organized in rows, and this map associates each id to a
@@ -164,6 +173,10 @@ struct TimelineInner {
     snippet_offsets: HashMap<Id, usize>,
     num_rows: usize,
     children: HashMap<Id, WidgetPod<AppState, TimelineSnippet>>,
+    // Where the cursor has been dragged to, if we haven't moved the time there yet. Mouse moves
+    // can come much faster than frames, and moving the time means repainting the drawing, so we
+    // only do it once per frame.
+    scrub_to: Option<Time>,
 }
 
 pub fn make_timeline() -> impl Widget<AppState> {
@@ -211,7 +224,13 @@ impl<W: Widget<AppState>> Controller<AppState, Scroll<AppState, W>> for Timeline
                 0.0
             };
 
+            let old_offset = child.offset();
             child.scroll(Vec2 { x: delta_x, y: 0.0 }, size);
+            // The timeline only repaints around the cursor when the time changes, so if we
+            // scrolled then everything needs repainting.
+            if child.offset() != old_offset {
+                ctx.request_paint();
+            }
         }
         child.update(ctx, old_data, data, env);
     }
@@ -223,37 +242,53 @@ impl Default for TimelineInner {
             snippet_offsets: HashMap::new(),
             num_rows: MIN_NUM_ROWS,
             children: HashMap::new(),
+            scrub_to: None,
         }
     }
 }
 
 impl TimelineInner {
-    // Recreates the child widgets, and organizes them into rows so that they don't overlap.
-    fn recreate_children(&mut self, snippets: &SnippetsData, audio: &AudioSnippetsData) {
+    // The part of the timeline that the cursor covers when it's at `time`.
+    fn cursor_rect(&self, time: Time) -> Rect {
+        let x = pix_x(time);
+        let height = SNIPPET_HEIGHT * self.num_rows as f64;
+        Rect::new(x - CURSOR_THICKNESS, 0.0, x + CURSOR_THICKNESS, height)
+    }
+
+    // Organizes the snippets into rows so that they don't overlap, and makes sure that there's a
+    // child widget for each one. The children of snippets that are still around get kept (along
+    // with their waveforms and hover state), so that changing one snippet doesn't cost as much as
+    // changing all of them. Returns true if any children were added or removed.
+    fn update_children(&mut self, snippets: &SnippetsData, audio: &AudioSnippetsData) -> bool {
         let draw_offsets = snippet_layout::layout(snippets.snippets());
         let audio_offsets = snippet_layout::layout(audio.snippets());
         self.num_rows = (draw_offsets.num_rows + audio_offsets.num_rows).max(MIN_NUM_ROWS);
 
         self.snippet_offsets.clear();
-        self.children.clear();
         for (&id, &offset) in &draw_offsets.positions {
-            let id = Id::Drawing(id);
-            self.snippet_offsets.insert(id, offset);
-            self.children
-                .insert(id, WidgetPod::new(TimelineSnippet { id, wave: None }));
+            self.snippet_offsets.insert(Id::Drawing(id), offset);
         }
         for (&id, &offset) in &audio_offsets.positions {
-            let audio_data = audio.snippet(id);
-            let id = Id::Audio(id);
-            self.snippet_offsets.insert(id, self.num_rows - offset - 1);
-            self.children.insert(
-                id,
-                WidgetPod::new(TimelineSnippet {
-                    id,
-                    wave: Some(AudioWaveform::from_audio(audio_data.clone())),
-                }),
-            );
+            self.snippet_offsets
+                .insert(Id::Audio(id), self.num_rows - offset - 1);
+        }
+
+        let offsets = &self.snippet_offsets;
+        let old_len = self.children.len();
+        self.children.retain(|id, _| offsets.contains_key(id));
+        let mut changed = self.children.len() != old_len;
+        for &id in self.snippet_offsets.keys() {
+            if !self.children.contains_key(&id) {
+                let wave = match id {
+                    Id::Drawing(_) => None,
+                    Id::Audio(id) => Some(AudioWaveform::from_audio(audio.snippet(id).clone())),
+                };
+                self.children
+                    .insert(id, WidgetPod::new(TimelineSnippet { id, wave }));
+                changed = true;
+            }
         }
+        changed
     }
 }
 
@@ -374,8 +409,13 @@ impl Widget<AppState> for TimelineSnippet {
         let snip = self.snip(data);
         let old_snip = self.snip(old_data);
         if !snip.same(&old_snip) {
-            if let Snip::Audio(data) = snip {
-                self.wave = Some(AudioWaveform::from_audio(data));
+            if let (Snip::Audio(data), Snip::Audio(old)) = (snip, old_snip) {
+                // The waveform only depends on the samples, so moving the snippet doesn't
+                // change it.
+                if data.buf().as_ptr() != old.buf().as_ptr() || data.buf().len() != old.buf().len()
+                {
+                    self.wave = Some(AudioWaveform::from_audio(data));
+                }
             }
             ctx.request_paint();
         }
@@ -454,21 +494,38 @@ impl Widget<AppState> for TimelineInner {
             }
             Event::MouseDown(ev) => {
                 let time = Time::from_micros((ev.pos.x / PIXELS_PER_USEC) as i64);
-                ctx.submit_command(Command::new(cmd::WARP_TIME_TO, time), None);
-                ctx.set_active(true);
-                ctx.request_paint();
+                let time = snapped(time, ev.mods.shift, data);
+                self.scrub_to = None;
+                ctx.submit_command(cmd::WARP_TIME_TO.with(time), None);
+                // Whatever gets edited during the drag should be undone all at once.
+                if !ctx.is_active() {
+                    ctx.submit_command(cmd::BEGIN_UNDO_GROUP, None);
+                    ctx.set_active(true);
+                }
             }
             Event::MouseMove(ev) => {
-                // On click-and-drag, we change the time with the drag.
+                // On click-and-drag, we change the time with the drag (on the next frame).
                 if ctx.is_active() {
                     let time = Time::from_micros((ev.pos.x.max(0.0) / PIXELS_PER_USEC) as i64);
-                    ctx.submit_command(Command::new(cmd::WARP_TIME_TO, time), None);
-                    ctx.request_paint();
+                    let time = snapped(time, ev.mods.shift, data);
+                    if self.scrub_to.replace(time).is_none() {
+                        ctx.request_anim_frame();
+                    }
+                }
+            }
+            Event::AnimFrame(_) => {
+                if let Some(time) = self.scrub_to.take() {
+                    ctx.submit_command(cmd::WARP_TIME_TO.with(time), None);
                 }
             }
             Event::MouseUp(_) => {
                 if ctx.is_active() {
+                    // Make sure that the drag ends where the mouse does.
+                    if let Some(time) = self.scrub_to.take() {
+                        ctx.submit_command(cmd::WARP_TIME_TO.with(time), None);
+                    }
                     ctx.set_active(false);
+                    ctx.submit_command(cmd::END_UNDO_GROUP, None);
                 }
             }
             _ => {}
@@ -487,11 +544,16 @@ impl Widget<AppState> for TimelineInner {
                 .same(&old_data.scribble.audio_snippets)
         {
             ctx.request_layout();
-            self.recreate_children(&data.scribble.snippets, &data.scribble.audio_snippets);
-            ctx.children_changed();
+            if self.update_children(&data.scribble.snippets, &data.scribble.audio_snippets) {
+                ctx.children_changed();
+            }
         }
-        if old_data.time() != data.time() || old_data.scribble.mark != data.scribble.mark {
+        if old_data.scribble.mark != data.scribble.mark {
             ctx.request_paint();
+        } else if old_data.time() != data.time() {
+            // Only the cursor moved, so only the places where it was and is need repainting.
+            ctx.request_paint_rect(self.cursor_rect(old_data.time()));
+            ctx.request_paint_rect(self.cursor_rect(data.time()));
         }
         for child in self.children.values_mut() {
             child.update(ctx, data, env);
@@ -502,7 +564,7 @@ impl Widget<AppState> for TimelineInner {
         match event {
             LifeCycle::WidgetAdded => {
                 ctx.request_layout();
-                self.recreate_children(&data.scribble.snippets, &data.scribble.audio_snippets);
+                self.update_children(&data.scribble.snippets, &data.scribble.audio_snippets);
                 ctx.children_changed();
             }
             _ => {}